@@ -16,6 +16,17 @@
 // relating to use of the SAFE Network Software.
 
 //! Simple service example.
+//!
+//! Pass the path of a configuration file to set the server's limits, echo policy and advertised
+//! addresses. Each line is `key = value`, with keys `memory_budget`, `max_requests_per_poll`,
+//! `max_shed_per_poll`, `require_cookies`, `ignore` (a subnet of clients to ignore), `withhold`
+//! (a subnet of addresses not to reflect) and `advertise` (an address to hand out in place of
+//! the mapped ones). The last three may be repeated. Type `reload` to re-read the file without
+//! dropping the server's socket, `ignore <subnet>` to stop answering a subnet of clients on top
+//! of those in the file, or `stats` to see how many requests have been served and ignored.
+//!
+//! Under systemd socket activation the server serves on the udp socket passed in by systemd, so
+//! that it can listen on port 3478 without running as root.
 
 // For explanation of lint checks, run `rustc -W help` or see
 // https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
@@ -36,13 +47,108 @@
 #![cfg_attr(feature="clippy", deny(clippy, clippy_pedantic))]
 
 extern crate nat_traversal;
+extern crate socket_addr;
 extern crate w_result;
 
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::UdpSocket;
 use std::time::{Instant, Duration};
 
-use nat_traversal::{MappingContext, SimpleUdpHolePunchServer};
+use nat_traversal::{EchoPolicy, IpSubnet, MappingContext, SimpleUdpHolePunchServer,
+                    SimpleUdpServerLimits};
+#[cfg(target_family = "unix")]
+use nat_traversal::ActivatedSocket;
+use socket_addr::SocketAddr;
 use w_result::{WOk, WErr};
 
+struct Config {
+    limits: SimpleUdpServerLimits,
+    echo_policy: EchoPolicy,
+    advertised: Vec<SocketAddr>,
+}
+
+fn read_config(path: &str) -> Result<Config, String> {
+    let file = try!(File::open(path).map_err(|e| format!("Error opening {}: {}", path, e)));
+    let mut config = Config {
+        limits: SimpleUdpServerLimits::default(),
+        echo_policy: EchoPolicy::new(),
+        advertised: Vec::new(),
+    };
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = try!(line.map_err(|e| format!("Error reading {}: {}", path, e)));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad_line = || format!("{}:{}: can't make sense of \"{}\"", path, i + 1, line);
+        let mut key_value = line.splitn(2, '=').map(|s| s.trim());
+        let (key, value) = match (key_value.next(), key_value.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => return Err(bad_line()),
+        };
+        match key {
+            "memory_budget" => {
+                config.limits.memory_budget = try!(value.parse().map_err(|_| bad_line()));
+            }
+            "max_requests_per_poll" => {
+                config.limits.max_requests_per_poll = try!(value.parse().map_err(|_| bad_line()));
+            }
+            "max_shed_per_poll" => {
+                config.limits.max_shed_per_poll = try!(value.parse().map_err(|_| bad_line()));
+            }
+            "require_cookies" => {
+                config.limits.require_cookies = try!(value.parse().map_err(|_| bad_line()));
+            }
+            "ignore" => {
+                let subnet = try!(value.parse().map_err(|_| bad_line()));
+                config.echo_policy.ignored_clients.insert(subnet);
+            }
+            "withhold" => {
+                let subnet = try!(value.parse().map_err(|_| bad_line()));
+                config.echo_policy.withheld_addrs.insert(subnet);
+            }
+            "advertise" => {
+                let addr = try!(value.parse().map_err(|_| bad_line()));
+                config.advertised.push(SocketAddr(addr));
+            }
+            _ => return Err(bad_line()),
+        }
+    }
+    Ok(config)
+}
+
+fn apply_config<T: AsRef<MappingContext>>(server: &SimpleUdpHolePunchServer<T>,
+                                          config: Config,
+                                          mapped_addresses: &[SocketAddr]) {
+    server.set_limits(config.limits);
+    server.set_echo_policy(config.echo_policy);
+    if config.advertised.is_empty() {
+        server.set_addresses(mapped_addresses.to_vec());
+    } else {
+        server.set_addresses(config.advertised);
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn activated_udp_socket() -> io::Result<Option<UdpSocket>> {
+    let sockets = try!(nat_traversal::activated_sockets());
+    Ok(sockets.into_iter()
+              .filter_map(|socket| {
+                  match socket {
+                      ActivatedSocket::Udp(socket) => Some(socket),
+                      ActivatedSocket::Tcp(..) => None,
+                  }
+              })
+              .next())
+}
+
+#[cfg(not(target_family = "unix"))]
+fn activated_udp_socket() -> io::Result<Option<UdpSocket>> {
+    Ok(None)
+}
+
 fn main() {
     println!("The example runs a simple rendezvous server that peers can use to connect to each other with");
 
@@ -61,9 +167,22 @@ fn main() {
         }
     };
 
-    // Now we create the server.
+    // Now we create the server, on the socket we were handed if we were socket activated.
     let deadline = Instant::now() + Duration::from_secs(3);
-    let simple_server = match SimpleUdpHolePunchServer::new(Box::new(mapping_context), deadline) {
+    let activated_socket = match activated_udp_socket() {
+        Ok(activated_socket) => activated_socket,
+        Err(e) => {
+            println!("Error taking the activated socket: {}", e);
+            println!("Exiting.");
+            return;
+        }
+    };
+    let simple_server = match activated_socket {
+        Some(socket) => SimpleUdpHolePunchServer::from_socket(Box::new(mapping_context), socket,
+                                                              deadline),
+        None => SimpleUdpHolePunchServer::new(Box::new(mapping_context), deadline),
+    };
+    let simple_server = match simple_server {
         WOk(simple_server, warnings) => {
             for warning in warnings {
                 println!("Warning when creating simple server: {}", warning);
@@ -77,10 +196,74 @@ fn main() {
         }
     };
 
+    // Apply the configuration, if there is one.
+    let mapped_addresses = simple_server.addresses();
+    let config_path = env::args().nth(1);
+    if let Some(ref config_path) = config_path {
+        match read_config(config_path) {
+            Ok(config) => apply_config(&simple_server, config, &mapped_addresses),
+            Err(e) => {
+                println!("{}", e);
+                println!("Exiting.");
+                return;
+            }
+        }
+    }
+
     // Now we print the servers known addresses
     let addresses = simple_server.addresses();
     println!("Server addresses: {:#?}", addresses);
 
+    // Reload the configuration whenever asked to. The server keeps serving throughout, and keeps
+    // its old configuration if the new one is broken.
+    // Subnets ignored from the command line are kept across reloads.
+    let mut ignored_subnets: Vec<IpSubnet> = Vec::new();
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let line = line.trim();
+        if line.starts_with("ignore ") {
+            match line["ignore ".len()..].trim().parse() {
+                Ok(subnet) => {
+                    simple_server.ignore_clients(subnet);
+                    ignored_subnets.push(subnet);
+                    println!("Ignoring {}.", subnet);
+                }
+                Err(e) => println!("Invalid subnet: {}", e),
+            }
+            continue;
+        }
+        if line == "stats" {
+            println!("{:#?}", simple_server.stats());
+            continue;
+        }
+        if line != "reload" {
+            println!("Unknown command. Type \"reload\", \"ignore <subnet>\" or \"stats\".");
+            continue;
+        }
+        let config_path = match config_path {
+            Some(ref config_path) => config_path,
+            None => {
+                println!("No configuration file was given.");
+                continue;
+            }
+        };
+        match read_config(config_path) {
+            Ok(config) => {
+                apply_config(&simple_server, config, &mapped_addresses);
+                for subnet in &ignored_subnets {
+                    simple_server.ignore_clients(*subnet);
+                }
+                println!("Reloaded {}. Server addresses: {:#?}", config_path,
+                         simple_server.addresses());
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+
     std::thread::park();
 }
 
@@ -0,0 +1,257 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+// Sending and receiving several datagrams per system call. On Linux, with the `mmsg` feature,
+// this uses `sendmmsg` and `recvmmsg`. Elsewhere it falls back to one call per datagram so that
+// callers don't need to care which they're getting.
+
+use std::io;
+use std::net::{self, UdpSocket};
+
+/// Send each of `datagrams` to its address. Returns the outcome of each send, in order.
+#[cfg(not(all(feature = "mmsg", target_os = "linux")))]
+pub fn send_batch(socket: &UdpSocket, datagrams: &[(&[u8], net::SocketAddr)])
+                  -> Vec<io::Result<usize>> {
+    datagrams.iter().map(|&(buf, ref addr)| socket.send_to(buf, addr)).collect()
+}
+
+/// Receive up to one datagram per buffer in `bufs`, where a buffer's length is the largest
+/// datagram it can hold. This blocks, as the socket's own timeout and blocking mode allow, for
+/// the first datagram only. Returns the length and sender of each datagram received, in the
+/// order of the buffers they were written to. The fallback receives one datagram per call.
+#[cfg(not(all(feature = "mmsg", target_os = "linux")))]
+pub fn recv_batch(socket: &UdpSocket, bufs: &mut [Vec<u8>])
+                  -> io::Result<Vec<(usize, net::SocketAddr)>> {
+    match bufs.first_mut() {
+        Some(buf) => Ok(vec![try!(socket.recv_from(buf))]),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(all(feature = "mmsg", target_os = "linux"))]
+pub use self::mmsg::{recv_batch, send_batch};
+
+#[cfg(all(feature = "mmsg", target_os = "linux"))]
+#[allow(unsafe_code)]
+mod mmsg {
+    use std::io;
+    use std::mem;
+    use std::net::{self, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    use libc;
+
+    fn to_sockaddr(addr: &net::SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        match *addr {
+            net::SocketAddr::V4(ref addr) => {
+                let sin = &mut storage as *mut _ as *mut libc::sockaddr_in;
+                unsafe {
+                    (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                    (*sin).sin_port = addr.port().to_be();
+                    (*sin).sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                }
+                (storage, mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+            },
+            net::SocketAddr::V6(ref addr) => {
+                let sin6 = &mut storage as *mut _ as *mut libc::sockaddr_in6;
+                let segments = addr.ip().segments();
+                unsafe {
+                    (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    (*sin6).sin6_port = addr.port().to_be();
+                    (*sin6).sin6_flowinfo = addr.flowinfo();
+                    (*sin6).sin6_scope_id = addr.scope_id();
+                    for (i, segment) in segments.iter().enumerate() {
+                        (*sin6).sin6_addr.s6_addr[2 * i] = (segment >> 8) as u8;
+                        (*sin6).sin6_addr.s6_addr[2 * i + 1] = *segment as u8;
+                    }
+                }
+                (storage, mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t)
+            },
+        }
+    }
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<net::SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                Ok(net::SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+            },
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                Ok(net::SocketAddr::V6(SocketAddrV6::new(ip,
+                                                         u16::from_be(sin6.sin6_port),
+                                                         sin6.sin6_flowinfo,
+                                                         sin6.sin6_scope_id)))
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown address family")),
+        }
+    }
+
+    /// Send each of `datagrams` to its address. Returns the outcome of each send, in order.
+    pub fn send_batch(socket: &UdpSocket, datagrams: &[(&[u8], net::SocketAddr)])
+                      -> Vec<io::Result<usize>> {
+        let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> =
+            datagrams.iter().map(|&(_, ref addr)| to_sockaddr(addr)).collect();
+        let mut iovecs: Vec<libc::iovec> = datagrams.iter()
+                                                    .map(|&(buf, _)| {
+                                                        libc::iovec {
+                                                            iov_base: buf.as_ptr() as *mut _,
+                                                            iov_len: buf.len(),
+                                                        }
+                                                    })
+                                                    .collect();
+        let mut msgs = Vec::with_capacity(datagrams.len());
+        for (addr, iovec) in addrs.iter_mut().zip(iovecs.iter_mut()) {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = &mut addr.0 as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = addr.1;
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msgs.push(msg);
+        }
+
+        // `sendmmsg` stops at the first datagram it fails to send. Record the error against that
+        // datagram and carry on from the next one.
+        let mut results = Vec::with_capacity(msgs.len());
+        while results.len() < msgs.len() {
+            let start = results.len();
+            let sent = unsafe {
+                libc::sendmmsg(socket.as_raw_fd(),
+                               msgs[start..].as_mut_ptr(),
+                               (msgs.len() - start) as libc::c_uint,
+                               0)
+            };
+            if sent < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    results.push(Err(err));
+                }
+            } else if sent == 0 {
+                results.push(Err(io::Error::new(io::ErrorKind::WriteZero,
+                                                "sendmmsg sent no datagrams")));
+            } else {
+                for msg in &msgs[start..start + sent as usize] {
+                    results.push(Ok(msg.msg_len as usize));
+                }
+            }
+        }
+        results
+    }
+
+    /// Receive up to one datagram per buffer in `bufs`, where a buffer's length is the largest
+    /// datagram it can hold. This blocks, as the socket's own timeout and blocking mode allow,
+    /// for the first datagram only. Returns the length and sender of each datagram received, in
+    /// the order of the buffers they were written to. Datagrams from senders whose address
+    /// family isn't IPv4 or IPv6 are dropped, and the buffers reordered so that the nth result's
+    /// datagram is still in the nth buffer.
+    pub fn recv_batch(socket: &UdpSocket, bufs: &mut [Vec<u8>])
+                      -> io::Result<Vec<(usize, net::SocketAddr)>> {
+        if bufs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut addrs: Vec<libc::sockaddr_storage> =
+            bufs.iter().map(|_| unsafe { mem::zeroed() }).collect();
+        let mut iovecs: Vec<libc::iovec> = bufs.iter_mut()
+                                               .map(|buf| {
+                                                   libc::iovec {
+                                                       iov_base: buf.as_mut_ptr() as *mut _,
+                                                       iov_len: buf.len(),
+                                                   }
+                                               })
+                                               .collect();
+        let mut msgs = Vec::with_capacity(bufs.len());
+        for (addr, iovec) in addrs.iter_mut().zip(iovecs.iter_mut()) {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msgs.push(msg);
+        }
+
+        let mut received;
+        loop {
+            received = unsafe {
+                libc::recvmmsg(socket.as_raw_fd(),
+                               msgs.as_mut_ptr(),
+                               msgs.len() as libc::c_uint,
+                               libc::MSG_WAITFORONE,
+                               ptr::null_mut())
+            };
+            if received >= 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        let received = received as usize;
+        let mut results = Vec::with_capacity(received);
+        for (i, (msg, addr)) in msgs[..received].iter().zip(addrs.iter()).enumerate() {
+            // A sender in an address family we don't know couldn't be replied to anyway, so drop
+            // its datagram rather than the whole batch. Later datagrams move up a buffer.
+            if let Ok(from) = from_sockaddr(addr) {
+                bufs.swap(results.len(), i);
+                results.push((msg.msg_len as usize, from));
+            }
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    #[test]
+    fn batches_round_trip_over_loopback() {
+        let sender = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let receiver = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let receiver_addr = unwrap_result!(receiver.local_addr());
+        unwrap_result!(receiver.set_read_timeout(Some(Duration::from_secs(5))));
+
+        let datagrams = [(&b"zero"[..], receiver_addr),
+                         (&b"one"[..], receiver_addr),
+                         (&b"two"[..], receiver_addr)];
+        for result in send_batch(&sender, &datagrams) {
+            let _ = unwrap_result!(result);
+        }
+
+        let mut bufs = vec![vec![0u8; 64]; 4];
+        let mut received = Vec::new();
+        while received.len() < datagrams.len() {
+            for (i, (len, from)) in unwrap_result!(recv_batch(&receiver, &mut bufs))
+                                        .into_iter()
+                                        .enumerate() {
+                assert_eq!(from, unwrap_result!(sender.local_addr()));
+                received.push(bufs[i][..len].to_vec());
+            }
+        }
+        assert_eq!(received, vec![b"zero".to_vec(), b"one".to_vec(), b"two".to_vec()]);
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! nat-probe: diagnose the network this host is on, through the same code paths as the library.
+//!
+//! ```text
+//! nat-probe probe [--server <addr>]...
+//! nat-probe map-udp [--server <addr>]...
+//! nat-probe punch [--server <addr>]... [--info <blob>]
+//! nat-probe serve
+//! ```
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(bad_style, exceeding_bitshifts, mutable_transmutes, no_mangle_const_items,
+          unknown_crate_types, warnings)]
+#![deny(deprecated, drop_with_repr_extern, improper_ctypes, missing_docs,
+        non_shorthand_field_patterns, overflowing_literals, plugin_as_library,
+        private_no_mangle_fns, private_no_mangle_statics, stable_features, unconditional_recursion,
+        unknown_lints, unsafe_code, unused, unused_allocation, unused_attributes,
+        unused_comparisons, unused_features, unused_parens, while_true)]
+#![warn(trivial_casts, trivial_numeric_casts, unused_extern_crates, unused_import_braces,
+        unused_qualifications, unused_results)]
+#![allow(box_pointers, fat_ptr_transmutes, missing_copy_implementations,
+         missing_debug_implementations, variant_size_differences)]
+
+#![cfg_attr(feature="clippy", feature(plugin))]
+#![cfg_attr(feature="clippy", plugin(clippy))]
+#![cfg_attr(feature="clippy", deny(clippy, clippy_pedantic))]
+
+extern crate nat_traversal;
+extern crate rustc_serialize;
+extern crate socket_addr;
+extern crate w_result;
+
+use std::env;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nat_traversal::{gen_rendezvous_info, probe_nat, MappedUdpSocket, MappingContext,
+                    PunchedUdpSocket, SimpleUdpHolePunchServer};
+use rustc_serialize::json;
+use socket_addr::SocketAddr;
+use w_result::{WErr, WOk};
+
+const USAGE: &'static str = "\
+Usage:
+    nat-probe probe [--server <addr>]...
+    nat-probe map-udp [--server <addr>]...
+    nat-probe punch [--server <addr>]... [--info <blob>]
+    nat-probe serve
+
+Subcommands:
+    probe      Report our endpoints, the NAT's mapping behaviour and whether it hairpins.
+    map-udp    Map a udp socket and print its endpoints and our public rendezvous info.
+    punch      Punch a udp hole to a peer. Their public rendezvous info is read from --info or,
+               if not given, from stdin.
+    serve      Run a simple udp hole punch server for other peers to map against.
+
+Options:
+    --server <addr>    A simple udp hole punch server to map against. May be given more than once.
+    --info <blob>      The peer's public rendezvous info, as printed by their map-udp or punch.";
+
+struct Args {
+    command: String,
+    servers: Vec<SocketAddr>,
+    info: Option<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = env::args().skip(1);
+    let command = match args.next() {
+        Some(command) => command,
+        None => return Err(String::from("No subcommand given")),
+    };
+    let mut parsed = Args {
+        command: command,
+        servers: Vec::new(),
+        info: None,
+    };
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--server" => {
+                let value = try!(args.next().ok_or("--server needs an address"));
+                let mut addrs = try!(value.to_socket_addrs()
+                                          .map_err(|e| format!("Bad address {}: {}", value, e)));
+                let addr = try!(addrs.next().ok_or(format!("{} didn't resolve", value)));
+                parsed.servers.push(SocketAddr(addr));
+            }
+            "--info" => {
+                parsed.info = Some(try!(args.next().ok_or("--info needs a blob")));
+            }
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    Ok(parsed)
+}
+
+fn fail(msg: &str) -> ! {
+    println!("{}", msg);
+    println!("Exiting.");
+    process::exit(1);
+}
+
+fn mapping_context(servers: Vec<SocketAddr>) -> MappingContext {
+    let mapping_context = match MappingContext::new() {
+        WOk(mapping_context, warnings) => {
+            for warning in warnings {
+                println!("Warning when creating mapping context: {}", warning);
+            }
+            mapping_context
+        }
+        WErr(e) => fail(&format!("Error creating mapping context: {}", e)),
+    };
+    mapping_context.add_simple_udp_servers(servers);
+    mapping_context
+}
+
+fn map_udp(mapping_context: &MappingContext) -> MappedUdpSocket {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    match MappedUdpSocket::new(mapping_context, deadline) {
+        WOk(mapped_socket, warnings) => {
+            for warning in warnings {
+                println!("Warning when mapping socket: {}", warning);
+            }
+            mapped_socket
+        }
+        WErr(e) => fail(&format!("Error mapping udp socket: {}", e)),
+    }
+}
+
+fn probe(args: Args) {
+    let mapping_context = mapping_context(args.servers);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let report = match probe_nat(&mapping_context, deadline) {
+        WOk(report, warnings) => {
+            for warning in warnings {
+                println!("Warning when mapping socket: {}", warning);
+            }
+            report
+        }
+        WErr(e) => fail(&format!("Error probing the NAT: {}", e)),
+    };
+    println!("{}", json::as_pretty_json(&report));
+}
+
+fn map(args: Args) {
+    let mapping_context = mapping_context(args.servers);
+    let MappedUdpSocket { socket, endpoints } = map_udp(&mapping_context);
+    match socket.local_addr() {
+        Ok(addr) => println!("Local address: {}", addr),
+        Err(e) => println!("Error getting the socket's local address: {}", e),
+    }
+    println!("Endpoints: {:#?}", endpoints);
+    let (_, our_pub_info) = gen_rendezvous_info(endpoints);
+    println!("Our public rendezvous info:");
+    println!("{}", json::as_json(&our_pub_info));
+}
+
+fn punch(args: Args) {
+    let mapping_context = mapping_context(args.servers);
+    let MappedUdpSocket { socket, endpoints } = map_udp(&mapping_context);
+    let (our_priv_info, our_pub_info) = gen_rendezvous_info(endpoints);
+    println!("Our public rendezvous info:");
+    println!("{}", json::as_json(&our_pub_info));
+
+    let info_str = match args.info {
+        Some(info_str) => info_str,
+        None => {
+            println!("Paste the peer's public rendezvous info on one line and hit return.");
+            let mut info_str = String::new();
+            if let Err(e) = io::stdin().read_line(&mut info_str) {
+                fail(&format!("IO error reading stdin: {}", e));
+            }
+            info_str
+        }
+    };
+    let their_pub_info = match json::decode(info_str.trim()) {
+        Ok(info) => info,
+        Err(e) => fail(&format!("Error decoding their public rendezvous info: {}", e)),
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    match PunchedUdpSocket::punch_hole(socket, our_priv_info, their_pub_info, deadline) {
+        WOk(punched_socket, warnings) => {
+            for warning in warnings {
+                println!("Warning when punching hole: {}", warning);
+            }
+            println!("Punched a hole to {}", punched_socket.peer_addr.0);
+        }
+        WErr(e) => fail(&format!("Error punching udp hole: {}", e)),
+    }
+}
+
+fn serve() {
+    let mapping_context = mapping_context(Vec::new());
+    let deadline = Instant::now() + Duration::from_secs(3);
+    let simple_server = match SimpleUdpHolePunchServer::new(Box::new(mapping_context), deadline) {
+        WOk(simple_server, warnings) => {
+            for warning in warnings {
+                println!("Warning when creating simple server: {}", warning);
+            }
+            simple_server
+        }
+        WErr(e) => fail(&format!("Error creating simple server: {}", e)),
+    };
+    println!("Serving on: {:#?}", simple_server.addresses());
+    println!("^C to exit.");
+    loop {
+        thread::park();
+    }
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            println!("{}", e);
+            println!("");
+            println!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    match &args.command[..] {
+        "probe" => probe(args),
+        "map-udp" => map(args),
+        "punch" => punch(args),
+        "serve" => serve(),
+        _ => {
+            println!("Unknown subcommand: {}", args.command);
+            println!("");
+            println!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
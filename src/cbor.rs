@@ -0,0 +1,242 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+// Just enough CBOR (RFC 7049) to encode and decode the punch and simple server messages without
+// allocating. The output matches what `maidsafe_utilities::serialisation::serialise` produces for
+// the same messages: structs are maps from field name to value, arrays of bytes are arrays of
+// integers and integers use their shortest encoding. Decoding is strict; callers fall back to
+// `deserialise` for anything these readers don't accept.
+
+use std::io;
+use std::str;
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+
+/// Writes CBOR into a caller-provided buffer.
+pub struct CborWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> CborWriter<'a> {
+    /// Write into the start of `buf`.
+    pub fn new(buf: &'a mut [u8]) -> CborWriter<'a> {
+        CborWriter {
+            buf: buf,
+            len: 0,
+        }
+    }
+
+    /// The number of bytes written so far.
+    pub fn position(&self) -> usize {
+        self.len
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.buf.len() - self.len < bytes.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "CBOR buffer too small"));
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    fn head(&mut self, major: u8, value: u64) -> io::Result<()> {
+        let major = major << 5;
+        if value < 24 {
+            self.bytes(&[major | value as u8])
+        } else if value <= 0xff {
+            self.bytes(&[major | 24, value as u8])
+        } else if value <= 0xffff {
+            self.bytes(&[major | 25, (value >> 8) as u8, value as u8])
+        } else if value <= 0xffff_ffff {
+            self.bytes(&[major | 26,
+                         (value >> 24) as u8,
+                         (value >> 16) as u8,
+                         (value >> 8) as u8,
+                         value as u8])
+        } else {
+            self.bytes(&[major | 27,
+                         (value >> 56) as u8,
+                         (value >> 48) as u8,
+                         (value >> 40) as u8,
+                         (value >> 32) as u8,
+                         (value >> 24) as u8,
+                         (value >> 16) as u8,
+                         (value >> 8) as u8,
+                         value as u8])
+        }
+    }
+
+    /// Start a map of `len` key-value pairs.
+    pub fn map(&mut self, len: usize) -> io::Result<()> {
+        self.head(MAJOR_MAP, len as u64)
+    }
+
+    /// Start an array of `len` items.
+    pub fn array(&mut self, len: usize) -> io::Result<()> {
+        self.head(MAJOR_ARRAY, len as u64)
+    }
+
+    /// Write an unsigned integer.
+    pub fn uint(&mut self, value: u64) -> io::Result<()> {
+        self.head(MAJOR_UNSIGNED, value)
+    }
+
+    /// Write a text string.
+    pub fn text(&mut self, text: &str) -> io::Result<()> {
+        try!(self.head(MAJOR_TEXT, text.len() as u64));
+        self.bytes(text.as_bytes())
+    }
+
+    /// Write a boolean.
+    pub fn bool(&mut self, value: bool) -> io::Result<()> {
+        self.bytes(&[if value { TRUE } else { FALSE }])
+    }
+}
+
+/// Reads CBOR by borrowing from a receive buffer. Every method returns `None` if the next item
+/// isn't what was asked for.
+pub struct CborReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> CborReader<'a> {
+    /// Read from the start of `data`.
+    pub fn new(data: &'a [u8]) -> CborReader<'a> {
+        CborReader { data: data }
+    }
+
+    /// Whether everything has been read.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn head(&mut self, major: u8) -> Option<u64> {
+        let initial = match self.take(1) {
+            Some(initial) => initial[0],
+            None => return None,
+        };
+        if initial >> 5 != major {
+            return None;
+        }
+        let len = match initial & 0x1f {
+            info @ 0...23 => return Some(info as u64),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return None,
+        };
+        self.take(len).map(|bytes| bytes.iter().fold(0, |value, b| (value << 8) | *b as u64))
+    }
+
+    /// Start a map, returning its number of key-value pairs.
+    pub fn map(&mut self) -> Option<u64> {
+        self.head(MAJOR_MAP)
+    }
+
+    /// Start an array, returning its number of items.
+    pub fn array(&mut self) -> Option<u64> {
+        self.head(MAJOR_ARRAY)
+    }
+
+    /// Read an unsigned integer.
+    pub fn uint(&mut self) -> Option<u64> {
+        self.head(MAJOR_UNSIGNED)
+    }
+
+    /// Read a text string.
+    pub fn text(&mut self) -> Option<&'a str> {
+        let len = match self.head(MAJOR_TEXT) {
+            Some(len) => len as usize,
+            None => return None,
+        };
+        self.take(len).and_then(|bytes| str::from_utf8(bytes).ok())
+    }
+
+    /// Read a text string and check that it's `expected`, eg. a struct field's name.
+    pub fn key(&mut self, expected: &str) -> Option<()> {
+        match self.text() {
+            Some(text) if text == expected => Some(()),
+            _ => None,
+        }
+    }
+
+    /// Read a boolean.
+    pub fn bool(&mut self) -> Option<bool> {
+        match self.take(1) {
+            Some(bytes) if bytes[0] == FALSE => Some(false),
+            Some(bytes) if bytes[0] == TRUE => Some(true),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut buf = [0u8; 64];
+        let len = {
+            let mut writer = CborWriter::new(&mut buf);
+            unwrap_result!(writer.map(2));
+            unwrap_result!(writer.text("a"));
+            unwrap_result!(writer.uint(1_000_000));
+            unwrap_result!(writer.text("b"));
+            unwrap_result!(writer.bool(true));
+            writer.position()
+        };
+        // RFC 7049 appendix A encodes 1000000 as 0x1a000f4240.
+        assert_eq!(&buf[..len],
+                   &[0xa2, 0x61, b'a', 0x1a, 0x00, 0x0f, 0x42, 0x40, 0x61, b'b', 0xf5][..]);
+
+        let mut reader = CborReader::new(&buf[..len]);
+        assert_eq!(reader.map(), Some(2));
+        assert_eq!(reader.key("a"), Some(()));
+        assert_eq!(reader.uint(), Some(1_000_000));
+        assert_eq!(reader.key("b"), Some(()));
+        assert_eq!(reader.bool(), Some(true));
+        assert!(reader.is_empty());
+
+        let mut reader = CborReader::new(&buf[..len]);
+        assert_eq!(reader.map(), Some(2));
+        assert_eq!(reader.key("b"), None);
+
+        let mut small = [0u8; 2];
+        assert!(CborWriter::new(&mut small).text("abc").is_err());
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! ICE style checklists: pairs of our and the peer's endpoints, checked in priority order so
+//! that the best pair that works is chosen rather than whichever answered first.
+
+use std::cmp;
+use std::net;
+use std::time::Duration;
+
+use mapped_socket_addr::{CandidateClass, MappedSocketAddr};
+
+/// The type preference of an endpoint the peer reached us from but never told us about.
+const PEER_REFLEXIVE_PREFERENCE: u32 = 110;
+
+/// The state of the check of a `CandidatePair`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
+pub enum PairState {
+    /// Not checked yet.
+    Waiting,
+    /// Packets have been sent but nothing has come back.
+    InProgress,
+    /// The peer answered on this pair.
+    Succeeded,
+    /// The check gave up.
+    Failed,
+}
+
+/// One of our endpoints paired with one of the peer's.
+#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct CandidatePair {
+    /// Our endpoint.
+    pub local: MappedSocketAddr,
+    /// The peer's endpoint.
+    pub remote: MappedSocketAddr,
+    /// The pair's priority, computed from both endpoints' priorities with `pair_priority`.
+    pub priority: u64,
+    /// How far its check has got.
+    pub state: PairState,
+    /// The round trip time of the check, if it succeeded and was measured.
+    pub rtt: Option<Duration>,
+}
+
+/// The priority of an endpoint as an ICE candidate priority (RFC 8445 section 5.1.2): directly
+/// reachable endpoints first, then reflexive ones, then those whose port is a guess. Endpoints
+/// that a server couldn't confirm come after confirmed ones of the same kind.
+pub fn candidate_priority(endpoint: &MappedSocketAddr) -> u32 {
+    let type_preference = match endpoint.candidate_class() {
+        CandidateClass::Host => 126,
+        CandidateClass::Reflexive => 100,
+        CandidateClass::Predicted => 50,
+    };
+    priority(type_preference, endpoint.unverified)
+}
+
+fn priority(type_preference: u32, unverified: bool) -> u32 {
+    let local_preference: u32 = if unverified { 0x7fff } else { 0xffff };
+    (type_preference << 24) + (local_preference << 8) + 255
+}
+
+/// The priority of a pair whose controlling side's endpoint has priority `controlling` and whose
+/// controlled side's endpoint has priority `controlled` (RFC 8445 section 6.1.2.3). Both peers
+/// compute the same value for the same pair.
+pub fn pair_priority(controlling: u32, controlled: u32) -> u64 {
+    let (g, d) = (controlling as u64, controlled as u64);
+    (cmp::min(g, d) << 32) + 2 * cmp::max(g, d) + if g > d { 1 } else { 0 }
+}
+
+/// The candidate pairs to check with a peer, kept in priority order.
+#[derive(Debug, Clone)]
+pub struct Checklist {
+    pairs: Vec<CandidatePair>,
+    controlling: bool,
+}
+
+impl Checklist {
+    /// Pair each of our endpoints in `local` with each of the peer's in `remote` of the same
+    /// address family. `controlling` says which side's priorities go first in `pair_priority`;
+    /// exactly one of the peers should pass `true`, eg. the one with the greater secret.
+    pub fn new(local: &[MappedSocketAddr], remote: &[MappedSocketAddr], controlling: bool)
+               -> Checklist {
+        let mut checklist = Checklist {
+            pairs: Vec::new(),
+            controlling: controlling,
+        };
+        for l in local {
+            for r in remote {
+                if same_family(&l.addr, &r.addr) {
+                    let priority = checklist.priority_of(candidate_priority(l),
+                                                         candidate_priority(r));
+                    checklist.add(l.clone(), r.clone(), priority);
+                }
+            }
+        }
+        checklist
+    }
+
+    /// The pairs, highest priority first.
+    pub fn pairs(&self) -> &[CandidatePair] {
+        &self.pairs[..]
+    }
+
+    /// The highest priority pair still waiting to be checked, marking it in progress.
+    pub fn next_check(&mut self) -> Option<CandidatePair> {
+        match self.pairs.iter_mut().find(|pair| pair.state == PairState::Waiting) {
+            Some(pair) => {
+                pair.state = PairState::InProgress;
+                Some(pair.clone())
+            },
+            None => None,
+        }
+    }
+
+    /// Record that the peer answered from `remote`, taking `rtt` to do so if it's known. An
+    /// address that isn't one of the peer's endpoints is added as a peer reflexive one, paired
+    /// with our first endpoint of its family.
+    pub fn record_success(&mut self, remote: net::SocketAddr, rtt: Option<Duration>) {
+        let mut found = false;
+        for pair in self.pairs.iter_mut().filter(|pair| *pair.remote.addr == remote) {
+            pair.state = PairState::Succeeded;
+            pair.rtt = rtt;
+            found = true;
+        }
+        if found {
+            return;
+        }
+        let local = match self.pairs.iter().find(|pair| same_family(&pair.local.addr, &remote)) {
+            Some(pair) => pair.local.clone(),
+            None => return,
+        };
+        let mut endpoint = MappedSocketAddr::direct(remote);
+        endpoint.nat_restricted = true;
+        let priority = self.priority_of(candidate_priority(&local),
+                                        priority(PEER_REFLEXIVE_PREFERENCE, false));
+        self.add(local, endpoint, priority);
+        for pair in self.pairs.iter_mut().filter(|pair| *pair.remote.addr == remote) {
+            pair.state = PairState::Succeeded;
+            pair.rtt = rtt;
+        }
+    }
+
+    /// Record that the checks to `remote` gave up.
+    pub fn record_failure(&mut self, remote: net::SocketAddr) {
+        for pair in self.pairs.iter_mut().filter(|pair| *pair.remote.addr == remote) {
+            if pair.state != PairState::Succeeded {
+                pair.state = PairState::Failed;
+            }
+        }
+    }
+
+    /// The pair to nominate: the highest priority one that succeeded, or among those of equal
+    /// priority the quickest.
+    pub fn nominated(&self) -> Option<&CandidatePair> {
+        let mut best: Option<&CandidatePair> = None;
+        for pair in self.pairs.iter().filter(|pair| pair.state == PairState::Succeeded) {
+            best = match best {
+                Some(b) if b.priority > pair.priority => Some(b),
+                Some(b) if b.priority == pair.priority && !quicker(pair.rtt, b.rtt) => Some(b),
+                _ => Some(pair),
+            };
+        }
+        best
+    }
+
+    /// Whether waiting on the remaining checks can't turn up a better pair than `nominated`.
+    pub fn is_settled(&self) -> bool {
+        match self.nominated() {
+            Some(best) => {
+                !self.pairs.iter().any(|pair| {
+                    pair.priority > best.priority &&
+                    (pair.state == PairState::Waiting || pair.state == PairState::InProgress)
+                })
+            },
+            None => false,
+        }
+    }
+
+    fn priority_of(&self, local: u32, remote: u32) -> u64 {
+        if self.controlling {
+            pair_priority(local, remote)
+        } else {
+            pair_priority(remote, local)
+        }
+    }
+
+    fn add(&mut self, local: MappedSocketAddr, remote: MappedSocketAddr, priority: u64) {
+        if self.pairs.iter().any(|pair| pair.local == local && pair.remote == remote) {
+            return;
+        }
+        let pos = self.pairs
+                      .iter()
+                      .position(|pair| pair.priority < priority)
+                      .unwrap_or(self.pairs.len());
+        self.pairs.insert(pos, CandidatePair {
+            local: local,
+            remote: remote,
+            priority: priority,
+            state: PairState::Waiting,
+            rtt: None,
+        });
+    }
+}
+
+fn same_family(a: &net::SocketAddr, b: &net::SocketAddr) -> bool {
+    match (*a, *b) {
+        (net::SocketAddr::V4(..), net::SocketAddr::V4(..)) |
+        (net::SocketAddr::V6(..), net::SocketAddr::V6(..)) => true,
+        _ => false,
+    }
+}
+
+fn quicker(a: Option<Duration>, b: Option<Duration>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a < b,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapped_socket_addr::MappedSocketAddr;
+
+    fn endpoint(addr: &str, nat_restricted: bool) -> MappedSocketAddr {
+        let mut endpoint = MappedSocketAddr::direct(unwrap_result!(addr.parse()));
+        endpoint.nat_restricted = nat_restricted;
+        endpoint
+    }
+
+    #[test]
+    fn best_validated_pair_is_nominated_not_the_first() {
+        let local = [endpoint("192.168.1.2:5000", false)];
+        let remote = [endpoint("203.0.113.7:6000", true), endpoint("10.0.0.9:6000", false)];
+        let mut ours = Checklist::new(&local, &remote, true);
+        let theirs = Checklist::new(&remote[..1], &local, false);
+        assert_eq!(ours.pairs()[1].priority, theirs.pairs()[0].priority);
+
+        // Checks go out best first.
+        let first = ours.next_check().expect("no checks");
+        assert_eq!(first.remote, remote[1]);
+
+        // The reflexive pair answers first, but the host pair might still.
+        ours.record_success(*remote[0].addr, None);
+        assert_eq!(ours.nominated().expect("nothing nominated").remote, remote[0]);
+        assert!(!ours.is_settled());
+
+        ours.record_success(*remote[1].addr, None);
+        assert_eq!(ours.nominated().expect("nothing nominated").remote, remote[1]);
+        assert!(ours.is_settled());
+    }
+
+    #[test]
+    fn unknown_source_becomes_a_peer_reflexive_pair() {
+        let local = [endpoint("192.168.1.2:5000", false)];
+        let remote = [endpoint("203.0.113.7:6000", true)];
+        let mut checklist = Checklist::new(&local, &remote, false);
+        checklist.record_failure(*remote[0].addr);
+        checklist.record_success(unwrap_result!("203.0.113.7:6001".parse()), None);
+        let nominated = checklist.nominated().expect("nothing nominated");
+        assert_eq!(nominated.remote.socket_addr(), unwrap_result!("203.0.113.7:6001".parse()));
+        assert!(nominated.priority > checklist.pairs()[1].priority);
+        assert!(checklist.is_settled());
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Instant, Duration};
+
+/// A source of time for timeout, backoff and keepalive logic.
+///
+/// Code which waits on deadlines takes a `&Clock` rather than calling `Instant::now` and
+/// `thread::sleep` directly so that tests can substitute a `MockClock` and control the passage of
+/// time without real sleeps.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+    /// Block for `dur`.
+    fn sleep(&self, dur: Duration);
+}
+
+/// The real system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        thread::sleep(dur)
+    }
+}
+
+/// A clock which only moves when told to. Sleeping on a `MockClock` returns immediately after
+/// advancing the clock by the requested amount. Cloning a `MockClock` produces a handle to the
+/// same clock.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    /// Create a mock clock starting at the current system time.
+    pub fn new() -> MockClock {
+        MockClock { now: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    /// Move the clock forward by `dur`.
+    pub fn advance(&self, dur: Duration) {
+        let mut now = unwrap_result!(self.now.lock());
+        *now = *now + dur;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *unwrap_result!(self.now.lock())
+    }
+
+    fn sleep(&self, dur: Duration) {
+        self.advance(dur)
+    }
+}
+
+/// Convert a deadline measured on `clock` into one measured on the system clock, for passing to
+/// blocking socket operations. Deadlines which have already passed on `clock` map to the current
+/// system time.
+pub fn system_deadline(clock: &Clock, deadline: Instant) -> Instant {
+    let now = clock.now();
+    let system_now = Instant::now();
+    if deadline <= now {
+        system_now
+    } else {
+        system_now + (deadline - now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Instant, Duration};
+
+    #[test]
+    fn mock_clock_only_moves_when_told() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        let before_sleep = Instant::now();
+        clock.sleep(Duration::from_secs(60));
+        assert!(before_sleep.elapsed() < Duration::from_secs(1));
+        assert_eq!(clock.now(), start + Duration::from_secs(60));
+
+        let handle = clock.clone();
+        handle.advance(Duration::from_secs(1));
+        assert_eq!(clock.now(), start + Duration::from_secs(61));
+
+        let system_now = Instant::now();
+        assert!(system_deadline(&clock, start) <= Instant::now());
+        assert!(system_deadline(&clock, clock.now() + Duration::from_secs(5)) >=
+                system_now + Duration::from_secs(5));
+    }
+}
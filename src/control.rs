@@ -0,0 +1,322 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! A control socket for operating a running `SimpleUdpHolePunchServer`.
+
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{self, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use mapping_context::MappingContext;
+use runtime::{RuntimeHandle, Sockets};
+use simple_udp_hole_punch_server::SimpleUdpHolePunchServer;
+use socket_utils;
+use subnetting::IpSubnet;
+
+/// How long a connection may sit without sending a command before it's closed, so that one
+/// forgotten connection doesn't lock everyone else out.
+const IDLE_TIMEOUT_SECS: u64 = 60;
+/// The longest command accepted.
+const MAX_COMMAND_LEN: usize = 1024;
+
+/// Lets operators control a running `SimpleUdpHolePunchServer` through a tcp socket on a loopback
+/// address, eg. with `nc 127.0.0.1 <port>`. Each command is a line, answered with a line:
+///
+/// * `status`: whether the server is draining, its addresses and the subnets it ignores.
+/// * `stats`: the server's stats for this run and over all runs.
+/// * `drain`: answer every request with "try later", so that clients move to other servers.
+/// * `resume`: stop draining.
+/// * `ignore <subnet>`: stop answering clients in the subnet.
+/// * `unignore <subnet>`: answer clients in the subnet again.
+///
+/// Commands which change something are answered with `ok`, and failed commands with `error: `
+/// and the reason. Connections are served one at a time, on the runtime of the server's mapping
+/// context. The socket isn't authenticated, so it may only be bound to a loopback address. Stops
+/// when dropped.
+pub struct ControlServer {
+    local_addr: net::SocketAddr,
+    stop_flag: Arc<AtomicBool>,
+    runtime: RuntimeHandle,
+}
+
+impl fmt::Debug for ControlServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ControlServer")
+         .field("local_addr", &self.local_addr)
+         .finish()
+    }
+}
+
+impl ControlServer {
+    /// Start accepting commands for `server` on `addr`, which must be a loopback address. Use
+    /// port 0 to have one picked.
+    pub fn new<T>(server: Arc<SimpleUdpHolePunchServer<T>>,
+                  addr: &net::SocketAddr)
+                  -> io::Result<ControlServer>
+        where T: AsRef<MappingContext> + Send + Sync + 'static
+    {
+        if !socket_utils::is_loopback(&addr.ip()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "The control socket must be bound to a loopback address"));
+        }
+        let listener = try!(TcpListener::bind(addr));
+        let local_addr = try!(listener.local_addr());
+        try!(listener.set_nonblocking(true));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let cloned_stop_flag = stop_flag.clone();
+        let runtime = server.runtime();
+        let cloned_runtime = runtime.clone();
+        runtime.spawn(move || accept(listener, server, cloned_stop_flag, cloned_runtime));
+        Ok(ControlServer {
+            local_addr: local_addr,
+            stop_flag: stop_flag,
+            runtime: runtime,
+        })
+    }
+
+    /// The address the control socket is bound to.
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.runtime.stop(&self.stop_flag);
+    }
+}
+
+// A connection being served, and the command read from it so far.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    line: Vec<u8>,
+    last_command: Instant,
+}
+
+// Take the next connection waiting on the listener, or wait for one. Once the control server has
+// been stopped the listener is dropped instead.
+fn accept<T>(listener: TcpListener,
+             server: Arc<SimpleUdpHolePunchServer<T>>,
+             stop_flag: Arc<AtomicBool>,
+             runtime: RuntimeHandle)
+    where T: AsRef<MappingContext> + Send + Sync + 'static
+{
+    if stop_flag.load(Ordering::SeqCst) {
+        return;
+    }
+    let connection = listener.accept().and_then(|(stream, _)| {
+        // Accepted sockets inherit non-blocking mode on some platforms but not others.
+        try!(stream.set_nonblocking(true));
+        let writer = try!(stream.try_clone());
+        Ok(Connection {
+            reader: BufReader::new(stream),
+            writer: writer,
+            line: Vec::new(),
+            last_command: Instant::now(),
+        })
+    });
+    let cloned_runtime = runtime.clone();
+    match connection {
+        Ok(connection) => {
+            runtime.spawn(move || {
+                serve_connection(listener, connection, server, stop_flag, cloned_runtime);
+            });
+        },
+        Err(_) => {
+            let sockets = Sockets::new(&[&listener]).or_stopped(&stop_flag);
+            runtime.when_readable(sockets, None, move || {
+                accept(listener, server, stop_flag, cloned_runtime);
+            });
+        },
+    }
+}
+
+// Answer the commands waiting on the connection then wait for more. Once it's closed, goes idle or
+// misbehaves, go back to accepting connections.
+fn serve_connection<T>(listener: TcpListener,
+                       mut connection: Connection,
+                       server: Arc<SimpleUdpHolePunchServer<T>>,
+                       stop_flag: Arc<AtomicBool>,
+                       runtime: RuntimeHandle)
+    where T: AsRef<MappingContext> + Send + Sync + 'static
+{
+    if stop_flag.load(Ordering::SeqCst) {
+        return;
+    }
+    let cloned_runtime = runtime.clone();
+    match answer_commands(&mut connection, &*server) {
+        // A misbehaving connection is simply closed.
+        Ok(None) | Err(_) => {
+            runtime.spawn(move || accept(listener, server, stop_flag, cloned_runtime));
+        },
+        Ok(Some(_)) => {
+            let sockets = Sockets::new(&[&connection.writer]).or_stopped(&stop_flag);
+            let idle_at = connection.last_command + Duration::from_secs(IDLE_TIMEOUT_SECS);
+            runtime.when_readable(sockets, Some(idle_at), move || {
+                serve_connection(listener, connection, server, stop_flag, cloned_runtime);
+            });
+        },
+    }
+}
+
+// Read and answer the complete commands waiting on the connection. Returns how many were answered,
+// or `None` once the connection has been closed.
+fn answer_commands<T: AsRef<MappingContext>>(connection: &mut Connection,
+                                             server: &SimpleUdpHolePunchServer<T>)
+                                             -> io::Result<Option<usize>> {
+    let mut answered = 0;
+    loop {
+        match connection.reader.read_until(b'\n', &mut connection.line) {
+            Ok(0) => return Ok(None),
+            Ok(_) if connection.line.ends_with(b"\n") => {
+                let response = match String::from_utf8(connection.line.clone()) {
+                    Ok(command) => execute(server, command.trim()),
+                    Err(_) => String::from("error: commands must be utf-8"),
+                };
+                try!(connection.writer.write_all(format!("{}\n", response).as_bytes()));
+                connection.line.clear();
+                connection.last_command = Instant::now();
+                answered += 1;
+            },
+            // The connection closed part way through a command.
+            Ok(_) => return Ok(None),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    if connection.line.len() > MAX_COMMAND_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Command too long"));
+    }
+    if connection.last_command.elapsed() >= Duration::from_secs(IDLE_TIMEOUT_SECS) {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "Connection idle"));
+    }
+    Ok(Some(answered))
+}
+
+// Carry out a command and describe the outcome.
+fn execute<T: AsRef<MappingContext>>(server: &SimpleUdpHolePunchServer<T>,
+                                     command: &str)
+                                     -> String {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("status"), None, None) => {
+            let addresses: Vec<String> =
+                server.addresses().iter().map(|addr| format!("{}", **addr)).collect();
+            let ignored: Vec<String> =
+                server.ignored_clients().iter().map(|subnet| format!("{}", subnet)).collect();
+            format!("draining={} addresses={} ignored={}",
+                    server.is_draining(),
+                    addresses.join(","),
+                    ignored.join(","))
+        },
+        (Some("stats"), None, None) => {
+            let stats = server.stats();
+            let cumulative = server.cumulative_stats();
+            format!("served_requests={} shed_requests={} ignored_requests={} \
+                     cumulative_served_requests={} cumulative_shed_requests={} \
+                     cumulative_ignored_requests={}",
+                    stats.served_requests,
+                    stats.shed_requests,
+                    stats.ignored_requests,
+                    cumulative.served_requests,
+                    cumulative.shed_requests,
+                    cumulative.ignored_requests)
+        },
+        (Some("drain"), None, None) => {
+            server.set_draining(true);
+            String::from("ok")
+        },
+        (Some("resume"), None, None) => {
+            server.set_draining(false);
+            String::from("ok")
+        },
+        (Some("ignore"), Some(subnet), None) => {
+            match subnet.parse::<IpSubnet>() {
+                Ok(subnet) => {
+                    server.ignore_clients(subnet);
+                    String::from("ok")
+                },
+                Err(e) => format!("error: {}", e),
+            }
+        },
+        (Some("unignore"), Some(subnet), None) => {
+            match subnet.parse::<IpSubnet>() {
+                Ok(ref subnet) if server.unignore_clients(subnet) => String::from("ok"),
+                Ok(subnet) => format!("error: {} isn't ignored", subnet),
+                Err(e) => format!("error: {}", e),
+            }
+        },
+        _ => format!("error: unknown command \"{}\"", command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use mapping_context::MappingContext;
+    use simple_udp_hole_punch_server::SimpleUdpHolePunchServer;
+
+    #[test]
+    fn commands_change_the_running_server() {
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new(Box::new(mapping_context),
+                                                                  deadline)
+                                        .result_discard());
+        let server = Arc::new(server);
+        let control = unwrap_result!(ControlServer::new(server.clone(),
+                                                        &unwrap_result!("127.0.0.1:0".parse())));
+        assert!(ControlServer::new(server.clone(), &unwrap_result!("0.0.0.0:0".parse())).is_err());
+
+        let stream = unwrap_result!(TcpStream::connect(control.local_addr()));
+        let mut writer = unwrap_result!(stream.try_clone());
+        let mut reader = BufReader::new(stream);
+        let mut command = |command: &str| {
+            unwrap_result!(writer.write_all(format!("{}\n", command).as_bytes()));
+            let mut response = String::new();
+            let _ = unwrap_result!(reader.read_line(&mut response));
+            response.trim().to_owned()
+        };
+
+        assert_eq!(command("drain"), "ok");
+        assert!(server.is_draining());
+        assert!(command("status").starts_with("draining=true "));
+        assert_eq!(command("resume"), "ok");
+        assert!(!server.is_draining());
+
+        assert_eq!(command("ignore 203.0.113.0/24"), "ok");
+        assert!(command("status").ends_with("ignored=203.0.113.0/24"));
+        assert_eq!(command("unignore 203.0.113.0/24"), "ok");
+        assert!(server.ignored_clients().is_empty());
+        assert!(command("unignore 203.0.113.0/24").starts_with("error: "));
+        assert!(command("ignore nonsense").starts_with("error: "));
+
+        assert!(command("stats").starts_with("served_requests="));
+        assert!(command("reboot").starts_with("error: "));
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Exchanging rendezvous info with nodes running the crust releases built on `nat_traversal`
+//! 0.3, so that a network can move to this crate a node at a time.
+//!
+//! Those nodes serialise `PubRendezvousInfo` as just its endpoints, each an address and whether
+//! it's NAT restricted, and the secret. Their udp hole punch packets and tcp secret exchange are
+//! the ones this crate still uses, so once the info is swapped in a format both understand,
+//! punching with the default options works. Sealed punching, synchronized starts and secure
+//! channels need both nodes to be current.
+//!
+//! There's no room in the old format for a version field, so the format itself is the flag: a
+//! node that hasn't heard from a peer sends `RendezvousWireVersion::Crust`, which both old and
+//! current nodes read, and answers a peer in whichever version the peer used.
+
+use maidsafe_utilities::serialisation::{deserialise, serialise, SerialisationError};
+use socket_addr::SocketAddr;
+
+use cbor::CborReader;
+use mapped_socket_addr::MappedSocketAddr;
+use rendezvous_info::{self, PubRendezvousInfo};
+
+/// The number of fields in the old `PubRendezvousInfo`, which is serialised as a map from field
+/// name to value.
+const CRUST_INFO_FIELDS: u64 = 2;
+
+/// The formats serialised `PubRendezvousInfo` comes in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
+pub enum RendezvousWireVersion {
+    /// The format of `nat_traversal` 0.3, used by existing crust deployments. Only endpoints and
+    /// the secret are carried.
+    Crust,
+    /// The format of this crate, as `serialise` produces it.
+    Current,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct CrustMappedSocketAddr {
+    addr: SocketAddr,
+    nat_restricted: bool,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct CrustPubRendezvousInfo {
+    endpoints: Vec<CrustMappedSocketAddr>,
+    secret: [u8; 4],
+}
+
+/// Serialise `info` in `version`. For `RendezvousWireVersion::Crust`, endpoints whose port is a
+/// guess are left out, since old nodes would take them for real addresses, and so is everything
+/// besides endpoints and the secret, including any signature.
+pub fn serialise_rendezvous_info(info: &PubRendezvousInfo, version: RendezvousWireVersion)
+                                 -> Result<Vec<u8>, SerialisationError> {
+    match version {
+        RendezvousWireVersion::Current => serialise(info),
+        RendezvousWireVersion::Crust => {
+            let endpoints = info.endpoints()
+                                .iter()
+                                .filter(|endpoint| !endpoint.port_unknown)
+                                .map(|endpoint| {
+                                    CrustMappedSocketAddr {
+                                        addr: endpoint.addr,
+                                        nat_restricted: endpoint.nat_restricted,
+                                    }
+                                })
+                                .collect();
+            serialise(&CrustPubRendezvousInfo {
+                endpoints: endpoints,
+                secret: rendezvous_info::get_pub_secret(info),
+            })
+        },
+    }
+}
+
+/// Deserialise info in either version, returning the version it was in so that the answer can be
+/// sent in the same one.
+pub fn deserialise_rendezvous_info(data: &[u8])
+                                   -> Result<(PubRendezvousInfo, RendezvousWireVersion),
+                                             SerialisationError> {
+    if CborReader::new(data).map() != Some(CRUST_INFO_FIELDS) {
+        let info = try!(deserialise(data));
+        return Ok((info, RendezvousWireVersion::Current));
+    }
+    let crust_info: CrustPubRendezvousInfo = try!(deserialise(data));
+    let endpoints = crust_info.endpoints
+                              .into_iter()
+                              .map(|endpoint| {
+                                  MappedSocketAddr {
+                                      addr: endpoint.addr,
+                                      nat_restricted: endpoint.nat_restricted,
+                                      port_unknown: false,
+                                      unverified: false,
+                                  }
+                              })
+                              .collect();
+    let info = rendezvous_info::compose(endpoints, crust_info.secret);
+    Ok((info, RendezvousWireVersion::Crust))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{CrustMappedSocketAddr, CrustPubRendezvousInfo};
+
+    use maidsafe_utilities::serialisation::serialise;
+    use socket_addr::SocketAddr;
+
+    use mapped_socket_addr::MappedSocketAddr;
+    use rendezvous_info::{self, gen_rendezvous_info};
+
+    fn endpoint(addr: &str, port_unknown: bool) -> MappedSocketAddr {
+        MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(addr.parse())),
+            nat_restricted: true,
+            port_unknown: port_unknown,
+            unverified: false,
+        }
+    }
+
+    #[test]
+    fn info_is_swapped_with_old_and_current_nodes() {
+        // What an old node sends.
+        let old = unwrap_result!(serialise(&CrustPubRendezvousInfo {
+            endpoints: vec![CrustMappedSocketAddr {
+                                addr: SocketAddr(unwrap_result!("192.0.2.1:4000".parse())),
+                                nat_restricted: true,
+                            }],
+            secret: [1, 2, 3, 4],
+        }));
+        let (info, version) = unwrap_result!(deserialise_rendezvous_info(&old));
+        assert_eq!(version, RendezvousWireVersion::Crust);
+        assert_eq!(info.endpoints(), &[endpoint("192.0.2.1:4000", false)][..]);
+        assert_eq!(rendezvous_info::get_pub_secret(&info), [1, 2, 3, 4]);
+
+        // Answering it in the old format drops the guessed port and reads back the same.
+        let (_, mut ours) = gen_rendezvous_info(vec![endpoint("198.51.100.1:5000", false),
+                                                     endpoint("198.51.100.1:5002", true)]);
+        let answer = unwrap_result!(serialise_rendezvous_info(&ours, version));
+        assert_eq!(answer,
+                   unwrap_result!(serialise(&CrustPubRendezvousInfo {
+                       endpoints: vec![CrustMappedSocketAddr {
+                                           addr: ours.endpoints()[0].addr,
+                                           nat_restricted: true,
+                                       }],
+                       secret: rendezvous_info::get_pub_secret(&ours),
+                   })));
+
+        // Current nodes get everything.
+        ours.schedule_punch(::std::time::Duration::from_secs(1));
+        let current = unwrap_result!(serialise_rendezvous_info(&ours,
+                                                               RendezvousWireVersion::Current));
+        let (info, version) = unwrap_result!(deserialise_rendezvous_info(&current));
+        assert_eq!(version, RendezvousWireVersion::Current);
+        assert_eq!(info, ours);
+    }
+}
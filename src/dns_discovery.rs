@@ -0,0 +1,662 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::io;
+use std::net;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs, UdpSocket};
+use std::time::{Instant, Duration};
+
+use byteorder::{BigEndian, ByteOrder};
+use rand;
+use socket_addr::SocketAddr;
+
+use error_code::{ErrorCategory, ErrorCode};
+use socket_utils::RecvUntil;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_RD: u16 = 0x0100;
+const RCODE_NXDOMAIN: u16 = 3;
+const MAX_MESSAGE_SIZE: usize = 512;
+const MAX_COMPRESSION_JUMPS: usize = 16;
+
+quick_error! {
+    /// Errors returned when resolving hole punch servers through DNS.
+    #[derive(Debug)]
+    pub enum DnsDiscoveryError {
+        /// No name server is configured on this machine.
+        NoNameServer {
+            description("No DNS name server is configured on this machine")
+        }
+        /// The domain name can't be encoded in a DNS query.
+        InvalidName { name: String } {
+            description("Invalid DNS name")
+            display("\"{}\" is not a valid DNS name", name)
+        }
+        /// IO error talking to the name server.
+        Io { err: io::Error } {
+            description("IO error talking to the DNS name server")
+            display("IO error talking to the DNS name server: {}", err)
+            cause(err)
+        }
+        /// The name server did not respond before the deadline.
+        TimedOut {
+            description("The DNS name server did not respond before the deadline")
+        }
+        /// The name server's response could not be parsed.
+        Malformed {
+            description("The DNS name server sent a malformed response")
+        }
+        /// The name server failed to answer the query.
+        ServerFailure { rcode: u16 } {
+            description("The DNS name server failed to answer the query")
+            display("The DNS name server failed to answer the query. Response code: {}", rcode)
+        }
+    }
+}
+
+impl From<DnsDiscoveryError> for io::Error {
+    fn from(e: DnsDiscoveryError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            DnsDiscoveryError::NoNameServer => io::ErrorKind::NotFound,
+            DnsDiscoveryError::InvalidName { .. } => io::ErrorKind::InvalidInput,
+            DnsDiscoveryError::Io { err } => err.kind(),
+            DnsDiscoveryError::TimedOut => io::ErrorKind::TimedOut,
+            DnsDiscoveryError::Malformed => io::ErrorKind::InvalidData,
+            DnsDiscoveryError::ServerFailure { .. } => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for DnsDiscoveryError {
+    fn code(&self) -> u32 {
+        match *self {
+            DnsDiscoveryError::NoNameServer => 2101,
+            DnsDiscoveryError::InvalidName { .. } => 2102,
+            DnsDiscoveryError::Io { .. } => 2103,
+            DnsDiscoveryError::TimedOut => 2104,
+            DnsDiscoveryError::Malformed => 2105,
+            DnsDiscoveryError::ServerFailure { .. } => 2106,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            DnsDiscoveryError::NoNameServer => ErrorCategory::Configuration,
+            DnsDiscoveryError::InvalidName { .. } => ErrorCategory::Configuration,
+            DnsDiscoveryError::Io { .. } => ErrorCategory::Network,
+            DnsDiscoveryError::TimedOut => ErrorCategory::Network,
+            DnsDiscoveryError::Malformed => ErrorCategory::Protocol,
+            DnsDiscoveryError::ServerFailure { .. } => ErrorCategory::Network,
+        }
+    }
+}
+
+/// A DNS SRV record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// Lower priorities should be tried first.
+    pub priority: u16,
+    /// Relative weight of records with the same priority.
+    pub weight: u16,
+    /// The port the service is listening on.
+    pub port: u16,
+    /// The host name of the server.
+    pub target: String,
+    /// How many seconds this record may be cached for.
+    pub ttl: u32,
+}
+
+/// A resource record of one of the types we understand. Names never have a trailing dot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Txt(String),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Other,
+}
+
+/// Resolve the SRV records for `name`, eg. `_nat-punch._udp.example.com`, sorted by priority and
+/// then by descending weight. A non-existent name resolves to no records.
+pub fn resolve_srv(name: &str, deadline: Instant) -> Result<Vec<SrvRecord>, DnsDiscoveryError> {
+    let answers = try!(query(name, TYPE_SRV, deadline));
+    let mut records: Vec<SrvRecord> = answers.into_iter().filter_map(|r| match r.data {
+        RecordData::Srv { priority, weight, port, target } => Some(SrvRecord {
+            priority: priority,
+            weight: weight,
+            port: port,
+            target: target,
+            ttl: r.ttl,
+        }),
+        _ => None,
+    }).collect();
+    records.sort_by(|a, b| (a.priority, !a.weight).cmp(&(b.priority, !b.weight)));
+    Ok(records)
+}
+
+/// Resolve the TXT records for `name`. The character strings of each record are concatenated.
+pub fn resolve_txt(name: &str, deadline: Instant) -> Result<Vec<String>, DnsDiscoveryError> {
+    let answers = try!(query(name, TYPE_TXT, deadline));
+    Ok(answers.into_iter().filter_map(|r| match r.data {
+        RecordData::Txt(txt) => Some(txt),
+        _ => None,
+    }).collect())
+}
+
+/// Resolve the ipv6 addresses of `name`.
+pub fn resolve_aaaa(name: &str, deadline: Instant) -> Result<Vec<Ipv6Addr>, DnsDiscoveryError> {
+    let answers = try!(query(name, TYPE_AAAA, deadline));
+    Ok(answers.into_iter().filter_map(|r| match r.data {
+        RecordData::Aaaa(ip) => Some(ip),
+        _ => None,
+    }).collect())
+}
+
+/// Resolve the servers advertised under `_nat-punch._<proto>.<domain>`. Returns the server
+/// addresses along with the smallest ttl of the records they came from.
+pub fn discover_servers(domain: &str, proto: &str, deadline: Instant)
+                        -> Result<(Vec<SocketAddr>, u32), DnsDiscoveryError>
+{
+    let name = format!("_nat-punch._{}.{}", proto, domain);
+    let records = try!(resolve_srv(&name, deadline));
+    let mut ttl = records.iter().map(|r| r.ttl).min().unwrap_or(0);
+    let mut servers = Vec::new();
+    for record in records {
+        // RFC 2782: a target of "." means the service is decidedly not available.
+        if record.target.is_empty() {
+            continue;
+        }
+        let addrs = match (&record.target[..], record.port).to_socket_addrs() {
+            Ok(addrs) => addrs,
+            // Skip targets which don't resolve and try again when the records expire.
+            Err(_) => {
+                ttl = 0;
+                continue;
+            },
+        };
+        for addr in addrs {
+            let addr = SocketAddr(addr);
+            if !servers.contains(&addr) {
+                servers.push(addr);
+            }
+        }
+    }
+    Ok((servers, ttl))
+}
+
+fn query(name: &str, qtype: u16, deadline: Instant) -> Result<Vec<Record>, DnsDiscoveryError> {
+    let servers = name_servers();
+    if servers.is_empty() {
+        return Err(DnsDiscoveryError::NoNameServer);
+    }
+    let mut last_err = DnsDiscoveryError::TimedOut;
+    for server in servers {
+        match query_server(server, name, qtype, deadline) {
+            Ok(answers) => return Ok(answers),
+            Err(e) => last_err = e,
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+    }
+    Err(last_err)
+}
+
+#[cfg(unix)]
+fn name_servers() -> Vec<net::SocketAddr> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut conf = String::new();
+    match File::open("/etc/resolv.conf").and_then(|mut f| f.read_to_string(&mut conf)) {
+        Ok(_) => (),
+        Err(_) => return Vec::new(),
+    };
+    conf.lines().filter_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("nameserver"), Some(ip)) => ip.parse::<IpAddr>().ok(),
+            _ => None,
+        }
+    }).map(|ip| net::SocketAddr::new(ip, 53)).collect()
+}
+
+// TODO(canndrew): Read the name servers from the registry on windows.
+#[cfg(not(unix))]
+fn name_servers() -> Vec<net::SocketAddr> {
+    Vec::new()
+}
+
+fn query_server(server: net::SocketAddr, name: &str, qtype: u16, deadline: Instant)
+                -> Result<Vec<Record>, DnsDiscoveryError>
+{
+    let id = rand::random::<u16>();
+    let request = try!(encode_query(id, name, qtype));
+    let bind_addr = match server {
+        net::SocketAddr::V4(..) => "0.0.0.0:0",
+        net::SocketAddr::V6(..) => "[::]:0",
+    };
+    let socket = match UdpSocket::bind(bind_addr) {
+        Ok(socket) => socket,
+        Err(e) => return Err(DnsDiscoveryError::Io { err: e }),
+    };
+
+    // Resend once a second in case the query or the response was lost.
+    let mut recv_deadline = Instant::now();
+    while recv_deadline < deadline {
+        recv_deadline = recv_deadline + Duration::from_secs(1);
+        if recv_deadline > deadline {
+            recv_deadline = deadline;
+        }
+        let _ = match socket.send_to(&request[..], server) {
+            Ok(n) => n,
+            Err(e) => return Err(DnsDiscoveryError::Io { err: e }),
+        };
+        let mut response = [0u8; MAX_MESSAGE_SIZE];
+        loop {
+            let (len, addr) = match socket.recv_until(&mut response[..], recv_deadline) {
+                Ok(Some(res)) => res,
+                Ok(None) => break,
+                Err(e) => return Err(DnsDiscoveryError::Io { err: e }),
+            };
+            if *addr != server || len < 2 || BigEndian::read_u16(&response[..2]) != id {
+                continue;
+            }
+            let records = try!(decode_response(&response[..len]));
+            // Only keep the records which answer the question.
+            let name = name.trim_right_matches('.').to_lowercase();
+            return Ok(records.into_iter().filter(|r| r.name.to_lowercase() == name).collect());
+        }
+    }
+    Err(DnsDiscoveryError::TimedOut)
+}
+
+fn write_name(msg: &mut Vec<u8>, name: &str) -> Result<(), DnsDiscoveryError> {
+    let start = msg.len();
+    for label in name.trim_right_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsDiscoveryError::InvalidName { name: name.to_owned() });
+        }
+        msg.push(label.len() as u8);
+        msg.extend(label.as_bytes());
+    }
+    msg.push(0);
+    if msg.len() - start > 255 {
+        return Err(DnsDiscoveryError::InvalidName { name: name.to_owned() });
+    }
+    Ok(())
+}
+
+fn write_u16(msg: &mut Vec<u8>, n: u16) {
+    let mut buf = [0u8; 2];
+    BigEndian::write_u16(&mut buf, n);
+    msg.extend(&buf[..]);
+}
+
+fn write_header(id: u16, flags: u16, qdcount: u16, ancount: u16) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(MAX_MESSAGE_SIZE);
+    write_u16(&mut msg, id);
+    write_u16(&mut msg, flags);
+    write_u16(&mut msg, qdcount);
+    write_u16(&mut msg, ancount);
+    write_u16(&mut msg, 0);
+    write_u16(&mut msg, 0);
+    msg
+}
+
+/// Encode a recursive query for `name`.
+pub fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, DnsDiscoveryError> {
+    let mut msg = write_header(id, FLAG_RD, 1, 0);
+    try!(write_name(&mut msg, name));
+    write_u16(&mut msg, qtype);
+    write_u16(&mut msg, CLASS_IN);
+    Ok(msg)
+}
+
+/// Encode an authoritative response holding `records`. Names are not compressed.
+#[cfg_attr(not(feature = "lan"), allow(dead_code))]
+pub fn encode_response(id: u16, records: &[Record]) -> Result<Vec<u8>, DnsDiscoveryError> {
+    let mut msg = write_header(id, FLAG_QR | FLAG_AA, 0, records.len() as u16);
+    for record in records {
+        try!(write_name(&mut msg, &record.name));
+        let mut rdata = Vec::new();
+        let rtype = match record.data {
+            RecordData::A(ip) => {
+                rdata.extend(&ip.octets()[..]);
+                TYPE_A
+            },
+            RecordData::Aaaa(ip) => {
+                for segment in &ip.segments() {
+                    write_u16(&mut rdata, *segment);
+                }
+                TYPE_AAAA
+            },
+            RecordData::Ptr(ref target) => {
+                try!(write_name(&mut rdata, target));
+                TYPE_PTR
+            },
+            RecordData::Txt(ref txt) => {
+                for chunk in txt.as_bytes().chunks(255) {
+                    rdata.push(chunk.len() as u8);
+                    rdata.extend(chunk);
+                }
+                TYPE_TXT
+            },
+            RecordData::Srv { priority, weight, port, ref target } => {
+                write_u16(&mut rdata, priority);
+                write_u16(&mut rdata, weight);
+                write_u16(&mut rdata, port);
+                try!(write_name(&mut rdata, target));
+                TYPE_SRV
+            },
+            RecordData::Other => continue,
+        };
+        write_u16(&mut msg, rtype);
+        write_u16(&mut msg, CLASS_IN);
+        let mut ttl = [0u8; 4];
+        BigEndian::write_u32(&mut ttl, record.ttl);
+        msg.extend(&ttl[..]);
+        write_u16(&mut msg, rdata.len() as u16);
+        msg.extend(rdata);
+    }
+    Ok(msg)
+}
+
+// Read a possibly-compressed name starting at `pos`. Returns the name, without a trailing dot,
+// and the position just after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize), DnsDiscoveryError> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = match msg.get(pos) {
+            Some(&len) => len as usize,
+            None => return Err(DnsDiscoveryError::Malformed),
+        };
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+        if len & 0xc0 == 0xc0 {
+            if pos + 2 > msg.len() || jumps == MAX_COMPRESSION_JUMPS {
+                return Err(DnsDiscoveryError::Malformed);
+            }
+            jumps += 1;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            pos = (BigEndian::read_u16(&msg[pos..pos + 2]) & 0x3fff) as usize;
+            continue;
+        }
+        if pos + 1 + len > msg.len() {
+            return Err(DnsDiscoveryError::Malformed);
+        }
+        labels.push(String::from_utf8_lossy(&msg[pos + 1..pos + 1 + len]).into_owned());
+        pos += 1 + len;
+    }
+    Ok((labels.join("."), end.unwrap_or(pos)))
+}
+
+// Check the header of a message and skip its questions. Returns the questions as `(name, type)`
+// and the position of the first record.
+fn read_questions(msg: &[u8]) -> Result<(Vec<(String, u16)>, usize), DnsDiscoveryError> {
+    if msg.len() < 12 {
+        return Err(DnsDiscoveryError::Malformed);
+    }
+    let qdcount = BigEndian::read_u16(&msg[4..6]);
+    let mut questions = Vec::new();
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        let (name, next) = try!(read_name(msg, pos));
+        if next + 4 > msg.len() {
+            return Err(DnsDiscoveryError::Malformed);
+        }
+        questions.push((name, BigEndian::read_u16(&msg[next..next + 2])));
+        pos = next + 4;
+    }
+    Ok((questions, pos))
+}
+
+/// Decode a query. Returns its id and its questions as `(name, type)`.
+#[cfg_attr(not(feature = "lan"), allow(dead_code))]
+pub fn decode_query(msg: &[u8]) -> Result<(u16, Vec<(String, u16)>), DnsDiscoveryError> {
+    let (questions, _) = try!(read_questions(msg));
+    if BigEndian::read_u16(&msg[2..4]) & FLAG_QR != 0 {
+        return Err(DnsDiscoveryError::Malformed);
+    }
+    Ok((BigEndian::read_u16(&msg[0..2]), questions))
+}
+
+/// Decode the records of every section of a response. Records of types we don't understand are
+/// returned as `RecordData::Other`. A non-existent name is treated as having no records.
+pub fn decode_response(msg: &[u8]) -> Result<Vec<Record>, DnsDiscoveryError> {
+    let (_, mut pos) = try!(read_questions(msg));
+    let flags = BigEndian::read_u16(&msg[2..4]);
+    if flags & FLAG_QR == 0 {
+        return Err(DnsDiscoveryError::Malformed);
+    }
+    match flags & 0x000f {
+        0 => (),
+        RCODE_NXDOMAIN => return Ok(Vec::new()),
+        rcode => return Err(DnsDiscoveryError::ServerFailure { rcode: rcode }),
+    }
+    let record_count = BigEndian::read_u16(&msg[6..8]) as usize +
+                       BigEndian::read_u16(&msg[8..10]) as usize +
+                       BigEndian::read_u16(&msg[10..12]) as usize;
+
+    let mut records = Vec::new();
+    for _ in 0..record_count {
+        let (name, next) = try!(read_name(msg, pos));
+        if next + 10 > msg.len() {
+            return Err(DnsDiscoveryError::Malformed);
+        }
+        let rtype = BigEndian::read_u16(&msg[next..next + 2]);
+        let ttl = BigEndian::read_u32(&msg[next + 4..next + 8]);
+        let rdlength = BigEndian::read_u16(&msg[next + 8..next + 10]) as usize;
+        let rdata_start = next + 10;
+        pos = rdata_start + rdlength;
+        if pos > msg.len() {
+            return Err(DnsDiscoveryError::Malformed);
+        }
+        let rdata = &msg[rdata_start..pos];
+        let data = match rtype {
+            TYPE_A => {
+                if rdata.len() != 4 {
+                    return Err(DnsDiscoveryError::Malformed);
+                }
+                RecordData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+            },
+            TYPE_AAAA => {
+                if rdata.len() != 16 {
+                    return Err(DnsDiscoveryError::Malformed);
+                }
+                let mut segments = [0u16; 8];
+                for (i, segment) in segments.iter_mut().enumerate() {
+                    *segment = BigEndian::read_u16(&rdata[2 * i..2 * i + 2]);
+                }
+                RecordData::Aaaa(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                               segments[4], segments[5], segments[6], segments[7]))
+            },
+            TYPE_PTR => {
+                let (target, _) = try!(read_name(msg, rdata_start));
+                RecordData::Ptr(target)
+            },
+            TYPE_SRV => {
+                if rdata.len() < 7 {
+                    return Err(DnsDiscoveryError::Malformed);
+                }
+                let (target, _) = try!(read_name(msg, rdata_start + 6));
+                RecordData::Srv {
+                    priority: BigEndian::read_u16(&rdata[0..2]),
+                    weight: BigEndian::read_u16(&rdata[2..4]),
+                    port: BigEndian::read_u16(&rdata[4..6]),
+                    target: target,
+                }
+            },
+            TYPE_TXT => {
+                let mut txt = Vec::new();
+                let mut i = 0;
+                while i < rdata.len() {
+                    let len = rdata[i] as usize;
+                    if i + 1 + len > rdata.len() {
+                        return Err(DnsDiscoveryError::Malformed);
+                    }
+                    txt.extend(&rdata[i + 1..i + 1 + len]);
+                    i += 1 + len;
+                }
+                RecordData::Txt(String::from_utf8_lossy(&txt).into_owned())
+            },
+            // Eg. CNAME records preceding the answer.
+            _ => RecordData::Other,
+        };
+        records.push(Record {
+            name: name,
+            ttl: ttl,
+            data: data,
+        });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_query, decode_response, encode_query, encode_response, query_server,
+                Record, RecordData, TYPE_PTR, TYPE_SRV, TYPE_TXT};
+
+    use std::net::UdpSocket;
+    use std::time::{Instant, Duration};
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    // Answer every query with the question followed by the given records. The records' names
+    // point back at the question's name.
+    fn fake_name_server(records: Vec<(u16, Vec<u8>)>) -> ::std::net::SocketAddr {
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let addr = unwrap_result!(socket.local_addr());
+        let _ = thread!("fake_name_server", move || {
+            let mut buf = [0u8; 512];
+            let (len, peer) = unwrap_result!(socket.recv_from(&mut buf));
+            let mut response = buf[..len].to_vec();
+            // Set QR and the answer count.
+            response[2] |= 0x80;
+            BigEndian::write_u16(&mut response[6..8], records.len() as u16);
+            for (rtype, rdata) in records {
+                let mut header = [0u8; 12];
+                BigEndian::write_u16(&mut header[0..2], 0xc00c);
+                BigEndian::write_u16(&mut header[2..4], rtype);
+                BigEndian::write_u16(&mut header[4..6], 1);
+                BigEndian::write_u32(&mut header[6..10], 300);
+                BigEndian::write_u16(&mut header[10..12], rdata.len() as u16);
+                response.extend(&header[..]);
+                response.extend(rdata);
+            }
+            let _ = unwrap_result!(socket.send_to(&response, peer));
+        });
+        addr
+    }
+
+    #[test]
+    fn srv_records_are_decoded() {
+        let mut low = vec![0, 20, 0, 5, 0x1f, 0x90];
+        low.extend(b"\x04punch\x07example\x03com\x00".iter());
+        let mut high = vec![0, 10, 0, 1, 0x1f, 0x91];
+        // Compressed pointer back into the question name: "_udp.example.com".
+        high.extend(b"\x03two\xc0\x17".iter());
+        let server = fake_name_server(vec![(TYPE_SRV, low), (TYPE_SRV, high)]);
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let answers = unwrap_result!(query_server(server, "_nat-punch._udp.example.com",
+                                                  TYPE_SRV, deadline));
+        let targets: Vec<(u16, String)> = answers.into_iter().filter_map(|r| match r.data {
+            RecordData::Srv { port, target, .. } => Some((port, target)),
+            _ => None,
+        }).collect();
+        assert_eq!(targets, vec![(8080, "punch.example.com".to_owned()),
+                                 (8081, "two._udp.example.com".to_owned())]);
+    }
+
+    #[test]
+    fn txt_records_are_decoded() {
+        let server = fake_name_server(vec![(TYPE_TXT, b"\x08caps=udp\x04,tcp".to_vec())]);
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let answers = unwrap_result!(query_server(server, "_nat-punch._udp.example.com",
+                                                  TYPE_TXT, deadline));
+        assert_eq!(answers, vec![Record {
+            name: "_nat-punch._udp.example.com".to_owned(),
+            ttl: 300,
+            data: RecordData::Txt("caps=udp,tcp".to_owned()),
+        }]);
+    }
+
+    #[test]
+    fn encoded_messages_decode() {
+        let query = unwrap_result!(encode_query(7, "_nat-punch._udp.local", TYPE_PTR));
+        assert_eq!(unwrap_result!(decode_query(&query)),
+                   (7, vec![("_nat-punch._udp.local".to_owned(), TYPE_PTR)]));
+
+        let records = vec![
+            Record {
+                name: "_nat-punch._udp.local".to_owned(),
+                ttl: 120,
+                data: RecordData::Ptr("peer._nat-punch._udp.local".to_owned()),
+            },
+            Record {
+                name: "peer._nat-punch._udp.local".to_owned(),
+                ttl: 120,
+                data: RecordData::Srv {
+                    priority: 0,
+                    weight: 0,
+                    port: 5483,
+                    target: "peer.local".to_owned(),
+                },
+            },
+            Record {
+                name: "peer.local".to_owned(),
+                ttl: 120,
+                data: RecordData::A(unwrap_result!("192.168.1.7".parse())),
+            },
+        ];
+        let response = unwrap_result!(encode_response(7, &records));
+        assert_eq!(unwrap_result!(decode_response(&response)), records);
+        assert!(decode_query(&response).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::net::IpAddr;
+
+use socket_utils::unmap_ipv4;
+use subnetting::SubnetList;
+
+/// Which clients a hole punch server answers and which of the addresses it observes it reflects
+/// back to them. By default every client is answered and every address reflected.
+#[derive(Debug, Clone, Default, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct EchoPolicy {
+    /// Requests from clients in these subnets are ignored, pings included.
+    pub ignored_clients: SubnetList,
+    /// Observed addresses in these subnets are never reflected back. Clients there can still
+    /// ping the server.
+    pub withheld_addrs: SubnetList,
+}
+
+impl EchoPolicy {
+    /// A policy which answers every client and reflects every address.
+    pub fn new() -> EchoPolicy {
+        EchoPolicy::default()
+    }
+
+    /// A policy which doesn't reflect private, link-local, shared or site-local addresses, for
+    /// servers which mustn't reveal internal addressing to the clients they serve.
+    pub fn public_only() -> EchoPolicy {
+        let mut withheld_addrs = SubnetList::private();
+        withheld_addrs.extend(&SubnetList::shared());
+        withheld_addrs.extend(&SubnetList::site_local());
+        EchoPolicy {
+            ignored_clients: SubnetList::new(),
+            withheld_addrs: withheld_addrs,
+        }
+    }
+
+    /// Whether requests from `client` are answered at all.
+    pub fn answers(&self, client: &IpAddr) -> bool {
+        !self.ignored_clients.contains(&unmap_ipv4(client))
+    }
+
+    /// Whether `addr` may be reflected back to the client seen at it.
+    pub fn echoes(&self, addr: &IpAddr) -> bool {
+        let addr = unmap_ipv4(addr);
+        self.answers(&addr) && !self.withheld_addrs.contains(&addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        unwrap_result!(s.parse())
+    }
+
+    #[test]
+    fn public_only_withholds_internal_addresses() {
+        let mut policy = EchoPolicy::public_only();
+        for addr in &["10.1.2.3", "100.70.0.1", "fec0::1", "fd00::1", "::ffff:192.168.1.1"] {
+            assert!(policy.answers(&ip(addr)));
+            assert!(!policy.echoes(&ip(addr)));
+        }
+        assert!(policy.echoes(&ip("203.0.113.7")));
+        assert!(policy.echoes(&ip("2001:db8::1")));
+
+        policy.ignored_clients.insert(unwrap_result!("203.0.113.0/24".parse()));
+        assert!(!policy.answers(&ip("::ffff:203.0.113.7")));
+        assert!(!policy.echoes(&ip("203.0.113.7")));
+        assert!(EchoPolicy::new().echoes(&ip("10.1.2.3")));
+    }
+}
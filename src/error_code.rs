@@ -0,0 +1,125 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::fmt;
+
+/// Broad classification of an error or warning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCategory {
+    /// Something went wrong talking to the network or to the local networking stack. Retrying
+    /// later may help.
+    Network,
+    /// A remote host sent us something we didn't understand or didn't expect.
+    Protocol,
+    /// The arguments or environment supplied by the caller are not usable.
+    Configuration,
+    /// The platform doesn't support something we need.
+    Unsupported,
+}
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            ErrorCategory::Network => "network",
+            ErrorCategory::Protocol => "protocol",
+            ErrorCategory::Configuration => "configuration",
+            ErrorCategory::Unsupported => "unsupported",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Implemented by every error and warning type in this crate.
+///
+/// Each variant of each type has a code which will never be changed or reused, so that
+/// consumers can handle errors without matching on strings. Codes are grouped into blocks of one
+/// hundred per type:
+///
+/// | Codes  | Type                               |
+/// |--------|------------------------------------|
+/// | `1xx`  | `MappingContextNewError`           |
+/// | `2xx`  | `MappingContextNewWarning`         |
+/// | `3xx`  | `MappedUdpSocketMapError`          |
+/// | `4xx`  | `MappedUdpSocketMapWarning`        |
+/// | `5xx`  | `MappedUdpSocketNewError`          |
+/// | `6xx`  | `UdpPunchHoleWarning`              |
+/// | `7xx`  | `UdpPunchHoleError`                |
+/// | `8xx`  | `MappedTcpSocketMapError`          |
+/// | `9xx`  | `MappedTcpSocketMapWarning`        |
+/// | `10xx` | `MappedTcpSocketNewError`          |
+/// | `11xx` | `NewReusablyBoundTcpSocketError`   |
+/// | `12xx` | `TcpPunchHoleWarning`              |
+/// | `13xx` | `TcpPunchHoleError`                |
+/// | `14xx` | `SimpleUdpHolePunchServerNewError` |
+/// | `15xx` | `SimpleTcpHolePunchServerNewError` |
+/// | `16xx` | `PingServerError`                  |
+/// | `17xx` | `LoopbackRendezvousError`          |
+/// | `18xx` | `SubnetError`                      |
+/// | `19xx` | `ffi` argument errors and panics   |
+/// | `20xx` | `HttpDiscoveryError`               |
+/// | `21xx` | `DnsDiscoveryError`                |
+/// | `22xx` | `LanDiscoveryError`                |
+/// | `23xx` | `Nat64Error`                       |
+/// | `24xx` | `Socks5Error`                      |
+/// | `25xx` | `StunError`                        |
+/// | `26xx` | `IceError`                         |
+/// | `27xx` | `StunQueryError`                   |
+/// | `28xx` | `TurnError`                        |
+/// | `29xx` | `TurnServerNewError`               |
+/// | `30xx` | `NoiseError`                       |
+/// | `31xx` | `IdentityError`                    |
+/// | `32xx` | `FirewallError`                    |
+/// | `33xx` | `ParseMappedSocketAddrError`       |
+/// | `34xx` | `ParseRendezvousInfoError`         |
+/// | `35xx` | `MappingBehaviourError`            |
+/// | `36xx` | `TcpSpliceError`                   |
+/// | `37xx` | `TcpSpliceServerNewError`          |
+/// | `38xx` | `TcpRaceError`                     |
+///
+/// Where an error wraps another error from this crate, its category is the category of the
+/// wrapped error.
+pub trait ErrorCode {
+    /// The stable numeric code of this error.
+    fn code(&self) -> u32;
+    /// The category of this error.
+    fn category(&self) -> ErrorCategory;
+}
+
+#[cfg(all(test, feature = "tcp"))]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    use mapped_tcp_socket::{MappedTcpSocketNewError, NewReusablyBoundTcpSocketError};
+
+    #[test]
+    fn wrapping_errors_keep_their_code_but_take_inner_category() {
+        let inner = NewReusablyBoundTcpSocketError::EnableReusePort {
+            err: io::Error::new(io::ErrorKind::Other, "not supported"),
+        };
+        assert_eq!(inner.code(), 1103);
+        assert_eq!(inner.category(), ErrorCategory::Unsupported);
+
+        let outer = MappedTcpSocketNewError::NewReusablyBoundTcpSocket { err: inner };
+        assert_eq!(outer.code(), 1001);
+        assert_eq!(outer.category(), ErrorCategory::Unsupported);
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::fmt;
+
+use socket_addr::SocketAddr;
+
+use mapped_socket_addr::MappedSocketAddr;
+use mapping_context::{self, MappingContext};
+
+/// The technique used to obtain an endpoint or a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
+pub enum Strategy {
+    /// Using the addresses of the local network interfaces directly.
+    LocalInterface,
+    /// Requesting a port mapping from an IGD (UPnP) gateway.
+    Igd,
+    /// Asking simple hole punch servers for our external address.
+    SimpleServer,
+    /// Udp hole punching.
+    UdpHolePunch,
+    /// Tcp hole punching.
+    TcpHolePunch,
+    /// Asking http echo servers for our external ip address. Only the ip of the resulting
+    /// endpoint is known.
+    HttpEcho,
+    /// Relaying udp through a SOCKS5 proxy's UDP ASSOCIATE.
+    Socks5,
+    /// Asking public STUN servers for our external address.
+    Stun,
+}
+
+/// Events raised over the lifetime of a traversal.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub enum Event {
+    /// We've started gathering endpoints for the socket bound to `local_addr`.
+    GatheringStarted {
+        /// The local address of the socket being mapped.
+        local_addr: SocketAddr,
+    },
+    /// A new endpoint was found for a socket.
+    CandidateFound {
+        /// How the endpoint was found.
+        strategy: Strategy,
+        /// The endpoint.
+        endpoint: MappedSocketAddr,
+    },
+    /// We've started trying to reach one of the peer's endpoints.
+    CheckStarted {
+        /// The endpoint being checked.
+        peer_addr: SocketAddr,
+    },
+    /// We've exchanged secrets with the peer through `peer_addr`.
+    CheckSucceeded {
+        /// The endpoint that was checked.
+        peer_addr: SocketAddr,
+    },
+    /// We failed to reach the peer through `peer_addr`.
+    CheckFailed {
+        /// The endpoint that was checked.
+        peer_addr: SocketAddr,
+        /// Why the check failed.
+        reason: String,
+    },
+    /// The operation has moved on to using a different strategy.
+    StrategyChanged {
+        /// The strategy now being used.
+        strategy: Strategy,
+    },
+    /// A connection to the peer has been established.
+    Connected {
+        /// The strategy that produced the connection.
+        strategy: Strategy,
+        /// The address of the peer.
+        peer_addr: SocketAddr,
+    },
+    /// A long-running operation (such as a hole punch server) has shut down.
+    Closed {
+        /// The local address that the operation was using.
+        local_addr: SocketAddr,
+    },
+    /// The machine's network changed and the `MappingContext` has re-gathered its interfaces.
+    /// Endpoints found before the change may no longer work, so sockets should be mapped and
+    /// punched again.
+    NetworkChanged,
+    /// A `FailoverUdpSocket` stopped hearing from the peer on the path it was using and moved
+    /// over to another, or moved back to a path it prefers once that recovered.
+    PathChanged {
+        /// The peer's address on the path now being used.
+        peer_addr: SocketAddr,
+    },
+}
+
+/// Delivers `Event`s to any number of subscribers. Cloning an `EventSender` produces a handle to
+/// the same set of subscribers.
+#[derive(Clone, Default)]
+pub struct EventSender {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl fmt::Debug for EventSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let subscribers = unwrap_result!(self.subscribers.lock()).len();
+        f.debug_struct("EventSender").field("subscribers", &subscribers).finish()
+    }
+}
+
+impl EventSender {
+    /// Create an `EventSender` with no subscribers.
+    pub fn new() -> EventSender {
+        EventSender::default()
+    }
+
+    /// Subscribe to the events sent through this sender. Events are delivered until the returned
+    /// receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        let mut subscribers = unwrap_result!(self.subscribers.lock());
+        subscribers.push(tx);
+        rx
+    }
+
+    /// Send an event to all subscribers, forgetting any subscribers that have hung up.
+    pub fn send(&self, event: Event) {
+        let mut subscribers = unwrap_result!(self.subscribers.lock());
+        subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+/// Somewhere to report endpoints as soon as they're found.
+pub trait CandidateSink {
+    /// `endpoint` was just found using `strategy`.
+    fn candidate_found(&self, strategy: Strategy, endpoint: &MappedSocketAddr);
+}
+
+impl CandidateSink for EventSender {
+    fn candidate_found(&self, strategy: Strategy, endpoint: &MappedSocketAddr) {
+        self.send(Event::CandidateFound {
+            strategy: strategy,
+            endpoint: endpoint.clone(),
+        });
+    }
+}
+
+/// Reports candidates to a context's subscribers and, if the application is willing to share
+/// them, to a callback for trickling to the peer while mapping goes on.
+pub struct Trickle<'a> {
+    mc: &'a MappingContext,
+    on_candidate: Option<&'a Fn(&MappedSocketAddr)>,
+}
+
+impl<'a> Trickle<'a> {
+    /// Report candidates found while mapping with `mc` to `on_candidate`, if given.
+    pub fn new(mc: &'a MappingContext, on_candidate: Option<&'a Fn(&MappedSocketAddr)>)
+               -> Trickle<'a> {
+        Trickle {
+            mc: mc,
+            on_candidate: on_candidate,
+        }
+    }
+}
+
+impl<'a> CandidateSink for Trickle<'a> {
+    fn candidate_found(&self, strategy: Strategy, endpoint: &MappedSocketAddr) {
+        mapping_context::events(self.mc).candidate_found(strategy, endpoint);
+        if let Some(on_candidate) = self.on_candidate {
+            // The same policies as for the final list, so nothing is trickled that the finished
+            // socket wouldn't advertise.
+            for endpoint in mapping_context::exposed_endpoints(self.mc, vec![endpoint.clone()]) {
+                on_candidate(&endpoint);
+            }
+        }
+    }
+}
+
+/// Record a newly found endpoint and tell `sink` about it.
+pub fn push_endpoint(endpoints: &mut Vec<MappedSocketAddr>,
+                     sink: &CandidateSink,
+                     strategy: Strategy,
+                     endpoint: MappedSocketAddr) {
+    sink.candidate_found(strategy, &endpoint);
+    endpoints.push(endpoint);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net;
+    use socket_addr::SocketAddr;
+
+    #[test]
+    fn events_reach_live_subscribers_only() {
+        let events = EventSender::new();
+        let rx_0 = events.subscribe();
+        let rx_1 = events.subscribe();
+        drop(rx_1);
+
+        let addr = SocketAddr(unwrap_result!("127.0.0.1:1234".parse::<net::SocketAddr>()));
+        events.send(Event::CheckStarted { peer_addr: addr });
+
+        match unwrap_result!(rx_0.try_recv()) {
+            Event::CheckStarted { peer_addr } => assert_eq!(peer_addr, addr),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        assert_eq!(unwrap_result!(events.subscribers.lock()).len(), 1);
+    }
+}
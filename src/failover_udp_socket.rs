@@ -0,0 +1,270 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Keeping backup paths to a peer alongside the punched one and failing over between them.
+
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use socket_addr::SocketAddr;
+
+use event::{Event, EventSender};
+use listener_message::{PATH_KEEPALIVE_MAGIC_CONSTANT, PATH_PING, PATH_PONG};
+use punched_udp_socket::{filter_udp_hole_punch_packet, is_peer_unreachable, PunchedUdpSocket};
+use transport::DatagramTransport;
+
+/// How often a keepalive is sent on each path by default.
+const DEFAULT_KEEPALIVE_INTERVAL_MS: u64 = 1000;
+/// How long a path can go without hearing from the peer, by default, before it's taken to have
+/// failed.
+const DEFAULT_FAILURE_TIMEOUT_MS: u64 = 5000;
+/// How long each path is listened to at a time while receiving.
+const POLL_SLICE_MS: u64 = 10;
+
+struct Path {
+    socket: Box<DatagramTransport>,
+    peer_addr: SocketAddr,
+    last_heard: Instant,
+    last_keepalive: Option<Instant>,
+}
+
+/// A connection to a peer over any of several paths which have already been validated, for
+/// instance a punched socket, a socket punched over the other address family and a TURN
+/// allocation. Keepalives are sent on every path, and sending uses the first path added which
+/// the peer is still answering on, so a NAT dropping one mapping doesn't end the session. Data
+/// arriving on any path is received, as the peer may fail over before we do. Both peers must
+/// use a `FailoverUdpSocket`, so that keepalives get answered.
+pub struct FailoverUdpSocket {
+    paths: Vec<Path>,
+    active: usize,
+    events: Option<EventSender>,
+    keepalive_interval: Duration,
+    failure_timeout: Duration,
+}
+
+impl fmt::Debug for FailoverUdpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let peer_addrs: Vec<SocketAddr> = self.paths.iter().map(|path| path.peer_addr).collect();
+        f.debug_struct("FailoverUdpSocket")
+         .field("peer_addrs", &peer_addrs)
+         .field("active", &self.active)
+         .field("keepalive_interval", &self.keepalive_interval)
+         .field("failure_timeout", &self.failure_timeout)
+         .finish()
+    }
+}
+
+impl FailoverUdpSocket {
+    /// Use `primary` for as long as the peer answers on it.
+    pub fn new<S>(primary: PunchedUdpSocket<S>) -> FailoverUdpSocket
+        where S: DatagramTransport + 'static
+    {
+        let mut socket = FailoverUdpSocket {
+            paths: Vec::new(),
+            active: 0,
+            events: None,
+            keepalive_interval: Duration::from_millis(DEFAULT_KEEPALIVE_INTERVAL_MS),
+            failure_timeout: Duration::from_millis(DEFAULT_FAILURE_TIMEOUT_MS),
+        };
+        socket.add_backup(primary);
+        socket
+    }
+
+    /// Keep `backup` ready to fall back on, behind every path added before it.
+    pub fn add_backup<S>(&mut self, backup: PunchedUdpSocket<S>)
+        where S: DatagramTransport + 'static
+    {
+        self.paths.push(Path {
+            socket: Box::new(backup.socket),
+            peer_addr: backup.peer_addr,
+            last_heard: Instant::now(),
+            last_keepalive: None,
+        });
+    }
+
+    /// Raise `Event::PathChanged` through `events` whenever the path being used changes.
+    pub fn events(mut self, events: &EventSender) -> FailoverUdpSocket {
+        self.events = Some(events.clone());
+        self
+    }
+
+    /// Send keepalives on each path every `interval`, and give up on a path once nothing has
+    /// been heard on it for `failure_timeout`.
+    pub fn keepalive(mut self, interval: Duration, failure_timeout: Duration)
+                     -> FailoverUdpSocket {
+        self.keepalive_interval = interval;
+        self.failure_timeout = failure_timeout;
+        self
+    }
+
+    /// The peer's address on the path being used.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.paths[self.active].peer_addr
+    }
+
+    /// The index, in the order they were added, of the path being used.
+    pub fn active_path(&self) -> usize {
+        self.active
+    }
+
+    /// Send a datagram to the peer over the path being used.
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.maintain();
+        let path = &self.paths[self.active];
+        path.socket.send_to(buf, &path.peer_addr)
+    }
+
+    /// Receive a datagram from the peer on any path, blocking until one arrives or `deadline`
+    /// passes, and keeping the paths alive meanwhile. Returns `None` on timeout. Only receiving
+    /// answers the peer's keepalives, so this should be called regularly.
+    pub fn recv_until(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<Option<usize>> {
+        loop {
+            self.maintain();
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            for i in 0..self.paths.len() {
+                let mut slice_deadline = Instant::now() + Duration::from_millis(POLL_SLICE_MS);
+                if slice_deadline > deadline {
+                    slice_deadline = deadline;
+                }
+                let (len, addr) = match self.paths[i].socket.recv_until(buf, slice_deadline) {
+                    Ok(Some(received)) => received,
+                    Ok(None) => continue,
+                    Err(ref e) if is_peer_unreachable(e) => continue,
+                    Err(e) => return Err(e),
+                };
+                if addr != self.paths[i].peer_addr {
+                    continue;
+                }
+                self.paths[i].last_heard = Instant::now();
+                if len == 5 && buf[..4] == PATH_KEEPALIVE_MAGIC_CONSTANT {
+                    if buf[4] == PATH_PING {
+                        self.send_keepalive(i, PATH_PONG);
+                    }
+                    continue;
+                }
+                if filter_udp_hole_punch_packet(&buf[..len]).is_none() {
+                    continue;
+                }
+                return Ok(Some(len));
+            }
+        }
+    }
+
+    fn send_keepalive(&self, i: usize, kind: u8) {
+        let path = &self.paths[i];
+        let mut keepalive = [0u8; 5];
+        keepalive[..4].copy_from_slice(&PATH_KEEPALIVE_MAGIC_CONSTANT[..]);
+        keepalive[4] = kind;
+        let _ = path.socket.send_to(&keepalive[..], &path.peer_addr);
+    }
+
+    // Send any keepalives that are due, then move to the first path that's still alive.
+    fn maintain(&mut self) {
+        let now = Instant::now();
+        for i in 0..self.paths.len() {
+            let due = match self.paths[i].last_keepalive {
+                Some(last_keepalive) => now - last_keepalive >= self.keepalive_interval,
+                None => true,
+            };
+            if due {
+                self.send_keepalive(i, PATH_PING);
+                self.paths[i].last_keepalive = Some(now);
+            }
+        }
+        let failure_timeout = self.failure_timeout;
+        let alive = self.paths
+                        .iter()
+                        .position(|path| now - path.last_heard < failure_timeout);
+        if let Some(alive) = alive {
+            if alive != self.active {
+                self.active = alive;
+                if let Some(ref events) = self.events {
+                    events.send(Event::PathChanged { peer_addr: self.paths[alive].peer_addr });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use socket_addr::SocketAddr;
+
+    use event::{Event, EventSender};
+    use punched_udp_socket::PunchedUdpSocket;
+
+    fn punched(socket: UdpSocket, peer: &UdpSocket) -> PunchedUdpSocket {
+        PunchedUdpSocket {
+            socket: socket,
+            peer_addr: SocketAddr(unwrap_result!(peer.local_addr())),
+            local_ip: None,
+        }
+    }
+
+    #[test]
+    fn fails_over_when_the_peer_stops_answering_on_the_primary_path() {
+        let a_primary = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let a_backup = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        // Nothing reads from here, as though the peer's NAT dropped the mapping.
+        let b_dead = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let b_backup = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let backup_addr = SocketAddr(unwrap_result!(b_backup.local_addr()));
+
+        let events = EventSender::new();
+        let rx = events.subscribe();
+        let interval = Duration::from_millis(50);
+        let failure_timeout = Duration::from_millis(300);
+        let mut a = FailoverUdpSocket::new(punched(a_primary, &b_dead))
+                        .events(&events)
+                        .keepalive(interval, failure_timeout);
+        a.add_backup(punched(unwrap_result!(a_backup.try_clone()), &b_backup));
+        let mut b = FailoverUdpSocket::new(punched(b_backup, &a_backup))
+                        .keepalive(interval, failure_timeout);
+
+        let peer = thread!("fails_over_peer", move || {
+            let mut buf = [0u8; 64];
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let res = unwrap_result!(b.recv_until(&mut buf, deadline));
+            let len = res.expect("Timed out waiting for data over the backup path");
+            assert_eq!(&buf[..len], b"over the backup");
+        });
+
+        assert_eq!(a.active_path(), 0);
+        let mut buf = [0u8; 64];
+        let res = unwrap_result!(a.recv_until(&mut buf, Instant::now() + Duration::from_secs(1)));
+        assert!(res.is_none());
+        assert_eq!(a.active_path(), 1);
+        assert_eq!(a.peer_addr(), backup_addr);
+        let _ = unwrap_result!(a.send(b"over the backup"));
+        unwrap_result!(peer.join());
+
+        match unwrap_result!(rx.try_recv()) {
+            Event::PathChanged { peer_addr } => assert_eq!(peer_addr, backup_addr),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        drop(b_dead);
+    }
+}
@@ -0,0 +1,958 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+// C bindings for the udp traversal pipeline.
+//
+// Every object is handed to C as an opaque pointer created with `Box::into_raw` and must be
+// released with the matching `_free` function. Functions which can fail return `0` on success or
+// the stable code of the error (see `ErrorCode`) on failure. Argument errors detected at the FFI
+// boundary have their own codes in the `19xx` block. A panic never unwinds into C: it's caught at
+// the boundary and reported as `NAT_ERR_PANIC`, or as the function's failure value if it has no
+// error code.
+//
+// Mapping, the IGD gateway search and the blocking `nat_punch_udp_hole` run on the calling
+// thread, and events are delivered from a library thread. Embedders that can't have the library
+// block or spawn threads punch with `nat_punch_udp_hole_start` instead, waiting on
+// `nat_punch_fd` and `nat_punch_timeout_ms` in their own loop and calling `nat_drive` whenever
+// either fires, and take events from a `NatEventQueue` on a thread of their choosing.
+
+#![allow(unsafe_code)]
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::net::{self, IpAddr, UdpSocket};
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::mpsc::Receiver;
+use std::time::{Instant, Duration};
+
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use socket_addr::SocketAddr;
+use w_result::{WOk, WErr};
+
+use error_code::ErrorCode;
+use event::{Event, Strategy};
+use mapped_socket_addr::MappedSocketAddr;
+use mapping_context::MappingContext;
+use mapped_udp_socket::MappedUdpSocket;
+use punch_state_machine::{UdpPunchStateMachine, UdpPunchStatus};
+use punched_udp_socket::{is_peer_unreachable, PunchedUdpSocket, UdpPunchHoleError};
+use rendezvous_info::{self, PrivRendezvousInfo, PubRendezvousInfo};
+
+/// A required pointer argument was null.
+pub const NAT_ERR_NULL_POINTER: u32 = 1901;
+/// A string argument was not valid UTF-8 or not a valid socket address.
+pub const NAT_ERR_INVALID_ADDRESS: u32 = 1902;
+/// Serialised rendezvous info could not be serialised or deserialised.
+pub const NAT_ERR_SERIALISATION: u32 = 1903;
+/// `nat_punch_finish` was called on a punch that's still in progress.
+pub const NAT_ERR_PUNCH_IN_PROGRESS: u32 = 1904;
+/// The library panicked. Whatever the call was doing was abandoned, and the objects it was given
+/// should be freed rather than used again.
+pub const NAT_ERR_PANIC: u32 = 1905;
+
+/// `nat_drive` has more to do.
+pub const NAT_PUNCH_IN_PROGRESS: u32 = 0;
+/// The hole is punched. Call `nat_punch_finish`.
+pub const NAT_PUNCH_CONNECTED: u32 = 1;
+/// The punch timed out. Call `nat_punch_finish` or `nat_punch_free`.
+pub const NAT_PUNCH_TIMED_OUT: u32 = 2;
+
+/// No strategy.
+pub const NAT_STRATEGY_NONE: i32 = -1;
+/// `Strategy::LocalInterface`.
+pub const NAT_STRATEGY_LOCAL_INTERFACE: i32 = 0;
+/// `Strategy::Igd`.
+pub const NAT_STRATEGY_IGD: i32 = 1;
+/// `Strategy::SimpleServer`.
+pub const NAT_STRATEGY_SIMPLE_SERVER: i32 = 2;
+/// `Strategy::UdpHolePunch`.
+pub const NAT_STRATEGY_UDP_HOLE_PUNCH: i32 = 3;
+/// `Strategy::TcpHolePunch`.
+pub const NAT_STRATEGY_TCP_HOLE_PUNCH: i32 = 4;
+/// `Strategy::HttpEcho`.
+pub const NAT_STRATEGY_HTTP_ECHO: i32 = 5;
+/// `Strategy::Socks5`.
+pub const NAT_STRATEGY_SOCKS5: i32 = 6;
+/// `Strategy::Stun`.
+pub const NAT_STRATEGY_STUN: i32 = 7;
+
+/// `Event::GatheringStarted`.
+pub const NAT_EVENT_GATHERING_STARTED: u32 = 0;
+/// `Event::CandidateFound`.
+pub const NAT_EVENT_CANDIDATE_FOUND: u32 = 1;
+/// `Event::CheckStarted`.
+pub const NAT_EVENT_CHECK_STARTED: u32 = 2;
+/// `Event::CheckSucceeded`.
+pub const NAT_EVENT_CHECK_SUCCEEDED: u32 = 3;
+/// `Event::CheckFailed`.
+pub const NAT_EVENT_CHECK_FAILED: u32 = 4;
+/// `Event::StrategyChanged`.
+pub const NAT_EVENT_STRATEGY_CHANGED: u32 = 5;
+/// `Event::Connected`.
+pub const NAT_EVENT_CONNECTED: u32 = 6;
+/// `Event::Closed`.
+pub const NAT_EVENT_CLOSED: u32 = 7;
+/// `Event::NetworkChanged`.
+pub const NAT_EVENT_NETWORK_CHANGED: u32 = 8;
+/// `Event::PathChanged`.
+pub const NAT_EVENT_PATH_CHANGED: u32 = 9;
+
+fn strategy_code(strategy: Strategy) -> i32 {
+    match strategy {
+        Strategy::LocalInterface => NAT_STRATEGY_LOCAL_INTERFACE,
+        Strategy::Igd => NAT_STRATEGY_IGD,
+        Strategy::SimpleServer => NAT_STRATEGY_SIMPLE_SERVER,
+        Strategy::UdpHolePunch => NAT_STRATEGY_UDP_HOLE_PUNCH,
+        Strategy::TcpHolePunch => NAT_STRATEGY_TCP_HOLE_PUNCH,
+        Strategy::HttpEcho => NAT_STRATEGY_HTTP_ECHO,
+        Strategy::Socks5 => NAT_STRATEGY_SOCKS5,
+        Strategy::Stun => NAT_STRATEGY_STUN,
+    }
+}
+
+/// A socket address. IPv4 addresses are stored in the first four bytes of `ip`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatEndpoint {
+    /// The address bytes in network order.
+    pub ip: [u8; 16],
+    /// `1` if `ip` holds an IPv6 address, `0` for IPv4.
+    pub is_ipv6: u8,
+    /// `1` if hole punching is needed to reach this endpoint. Always `0` for endpoints which
+    /// aren't mapped socket addresses.
+    pub nat_restricted: u8,
+    /// The port.
+    pub port: u16,
+}
+
+impl NatEndpoint {
+    fn from_addr(addr: &net::SocketAddr, nat_restricted: bool) -> NatEndpoint {
+        let mut ip = [0u8; 16];
+        let is_ipv6 = match addr.ip() {
+            IpAddr::V4(addr_v4) => {
+                ip[..4].copy_from_slice(&addr_v4.octets());
+                0
+            },
+            IpAddr::V6(addr_v6) => {
+                for (i, segment) in addr_v6.segments().iter().enumerate() {
+                    ip[2 * i] = (*segment >> 8) as u8;
+                    ip[2 * i + 1] = *segment as u8;
+                }
+                1
+            },
+        };
+        NatEndpoint {
+            ip: ip,
+            is_ipv6: is_ipv6,
+            nat_restricted: nat_restricted as u8,
+            port: addr.port(),
+        }
+    }
+
+    fn from_mapped(endpoint: &MappedSocketAddr) -> NatEndpoint {
+        NatEndpoint::from_addr(&endpoint.addr, endpoint.nat_restricted)
+    }
+}
+
+/// A summary of what we know about the NAT in front of a mapped socket.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatInfo {
+    /// The number of endpoints found for the socket.
+    pub endpoint_count: u32,
+    /// How many of those endpoints can be reached without hole punching.
+    pub unrestricted_count: u32,
+    /// The strategy that has worked best on this network, one of the `NAT_STRATEGY_` constants.
+    pub recommended_strategy: i32,
+    /// 1 if the network is behind two layers of NAT, so UPnP mappings are of no use, or 0 if not
+    /// or not known.
+    pub double_nat: u8,
+}
+
+/// A traversal event, as delivered to a `NatEventCallback`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NatEvent {
+    /// One of the `NAT_EVENT_` constants.
+    pub kind: u32,
+    /// The strategy involved, or `NAT_STRATEGY_NONE`.
+    pub strategy: i32,
+    /// The local address, candidate endpoint or peer address the event is about. Zeroed for
+    /// `NAT_EVENT_STRATEGY_CHANGED` and `NAT_EVENT_NETWORK_CHANGED`.
+    pub endpoint: NatEndpoint,
+    /// For `NAT_EVENT_CHECK_FAILED`, a nul-terminated description of the failure. Otherwise null.
+    /// Only valid for the duration of the callback.
+    pub reason: *const c_char,
+}
+
+/// A function called with each traversal event. `user_data` is the pointer given when the
+/// callback was registered.
+pub type NatEventCallback = extern "C" fn(user_data: *mut c_void, event: *const NatEvent);
+
+// The user data pointer is only ever handed back to the C caller, who is responsible for making it
+// safe to use from the callback thread.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+fn deliver_event(callback: NatEventCallback, user_data: &UserData, event: &Event) {
+    let zeroed = NatEndpoint {
+        ip: [0u8; 16],
+        is_ipv6: 0,
+        nat_restricted: 0,
+        port: 0,
+    };
+    let mut reason = None;
+    let (kind, strategy, endpoint) = match *event {
+        Event::GatheringStarted { ref local_addr } => {
+            (NAT_EVENT_GATHERING_STARTED, NAT_STRATEGY_NONE,
+             NatEndpoint::from_addr(local_addr, false))
+        },
+        Event::CandidateFound { strategy, ref endpoint } => {
+            (NAT_EVENT_CANDIDATE_FOUND, strategy_code(strategy), NatEndpoint::from_mapped(endpoint))
+        },
+        Event::CheckStarted { ref peer_addr } => {
+            (NAT_EVENT_CHECK_STARTED, NAT_STRATEGY_NONE, NatEndpoint::from_addr(peer_addr, false))
+        },
+        Event::CheckSucceeded { ref peer_addr } => {
+            (NAT_EVENT_CHECK_SUCCEEDED, NAT_STRATEGY_NONE,
+             NatEndpoint::from_addr(peer_addr, false))
+        },
+        Event::CheckFailed { ref peer_addr, reason: ref r } => {
+            // Interior nuls can't be represented, so drop the reason rather than truncate it.
+            reason = CString::new(r.clone()).ok();
+            (NAT_EVENT_CHECK_FAILED, NAT_STRATEGY_NONE, NatEndpoint::from_addr(peer_addr, false))
+        },
+        Event::StrategyChanged { strategy } => {
+            (NAT_EVENT_STRATEGY_CHANGED, strategy_code(strategy), zeroed)
+        },
+        Event::Connected { strategy, ref peer_addr } => {
+            (NAT_EVENT_CONNECTED, strategy_code(strategy), NatEndpoint::from_addr(peer_addr, false))
+        },
+        Event::Closed { ref local_addr } => {
+            (NAT_EVENT_CLOSED, NAT_STRATEGY_NONE, NatEndpoint::from_addr(local_addr, false))
+        },
+        Event::NetworkChanged => (NAT_EVENT_NETWORK_CHANGED, NAT_STRATEGY_NONE, zeroed),
+        Event::PathChanged { ref peer_addr } => {
+            (NAT_EVENT_PATH_CHANGED, NAT_STRATEGY_NONE, NatEndpoint::from_addr(peer_addr, false))
+        },
+    };
+    let nat_event = NatEvent {
+        kind: kind,
+        strategy: strategy,
+        endpoint: endpoint,
+        reason: reason.as_ref().map_or(ptr::null(), |r| r.as_ptr()),
+    };
+    callback(user_data.0, &nat_event);
+}
+
+// Run `f`, returning `on_panic` instead if it panics, as unwinding into C is undefined behaviour.
+fn catch_panic<T, F: FnOnce() -> T>(on_panic: T, f: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+
+fn deadline_from_ms(timeout_ms: u64) -> Instant {
+    Instant::now() + Duration::from_millis(timeout_ms)
+}
+
+fn punch_status_code(status: UdpPunchStatus) -> u32 {
+    match status {
+        UdpPunchStatus::InProgress => NAT_PUNCH_IN_PROGRESS,
+        UdpPunchStatus::Connected(..) => NAT_PUNCH_CONNECTED,
+        UdpPunchStatus::TimedOut => NAT_PUNCH_TIMED_OUT,
+    }
+}
+
+/// A udp hole punch driven from the caller's event loop. See `nat_punch_udp_hole_start`.
+pub struct NatUdpPunch {
+    socket: UdpSocket,
+    machine: UdpPunchStateMachine,
+}
+
+impl NatUdpPunch {
+    // Handle whatever has arrived, then send whatever is due. Never blocks.
+    fn drive(&mut self, now: Instant) -> io::Result<UdpPunchStatus> {
+        let mut buf = [0u8; 1024];
+        // Once the punch is over, anything further is the peer's data and is left for the caller.
+        while self.machine.status() == UdpPunchStatus::InProgress {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) => self.machine.handle_packet(from, &buf[..len], now),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Windows reports ICMP errors about earlier sends on the next receive.
+                Err(ref e) if is_peer_unreachable(e) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        self.machine.handle_timeout(now);
+        while let Some((addr, packet)) = self.machine.next_outgoing() {
+            match self.socket.send_to(&packet, addr) {
+                Ok(_) => (),
+                // Lost like any other udp packet. Hole punch packets are resent.
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.machine.status())
+    }
+}
+
+/// Queued events for callers that won't have them delivered from a library thread. See
+/// `nat_event_queue_new`.
+pub struct NatEventQueue {
+    rx: Receiver<Event>,
+}
+
+/// Create a `MappingContext` without spawning any threads. On success `*out` is set to a handle
+/// which must be released with `nat_mapping_context_free`. No IGD gateways are known until
+/// `nat_mapping_context_search_gateways` is called.
+#[no_mangle]
+pub unsafe extern "C" fn nat_mapping_context_new(out: *mut *mut MappingContext) -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if out.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        match MappingContext::without_gateway_search() {
+            Ok(mc) => {
+                *out = Box::into_raw(Box::new(mc));
+                0
+            },
+            Err(e) => e.code(),
+        }
+    })
+}
+
+/// Search for IGD gateways on the calling thread, blocking for up to a second per network
+/// interface. Call this after `nat_mapping_context_new` and again after
+/// `nat_mapping_context_network_changed`. See `MappingContext::search_gateways`.
+#[no_mangle]
+pub unsafe extern "C" fn nat_mapping_context_search_gateways(mc: *const MappingContext) -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if mc.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let _ = (*mc).search_gateways();
+        0
+    })
+}
+
+/// Release a `MappingContext`.
+#[no_mangle]
+pub unsafe extern "C" fn nat_mapping_context_free(mc: *mut MappingContext) {
+    catch_panic((), || {
+        if !mc.is_null() {
+            drop(Box::from_raw(mc));
+        }
+    })
+}
+
+/// Tell a `MappingContext` that the network changed. Mobile apps call this from the platform's
+/// path or connectivity callbacks, eg. `NWPathMonitor` on iOS or `ConnectivityManager` on
+/// Android. See `MappingContext::network_changed`.
+#[no_mangle]
+pub unsafe extern "C" fn nat_mapping_context_network_changed(mc: *const MappingContext) -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if mc.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        match (*mc).network_changed() {
+            WOk((), _) => 0,
+            WErr(e) => e.code(),
+        }
+    })
+}
+
+/// Tell a `MappingContext` about a simple udp hole punch server at `addr`, a nul-terminated
+/// string such as `"203.0.113.7:5483"`.
+#[no_mangle]
+pub unsafe extern "C" fn nat_mapping_context_add_simple_udp_server(mc: *const MappingContext,
+                                                                   addr: *const c_char)
+                                                                   -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if mc.is_null() || addr.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let addr = match CStr::from_ptr(addr).to_str().ok().and_then(|s| s.parse().ok()) {
+            Some(addr) => SocketAddr(addr),
+            None => return NAT_ERR_INVALID_ADDRESS,
+        };
+        (*mc).add_simple_udp_servers(Some(addr));
+        0
+    })
+}
+
+/// Register `callback` to be called with every event raised through `mc`. The callback is invoked
+/// from a background thread, in the order the events happened, until the context is freed.
+#[no_mangle]
+pub unsafe extern "C" fn nat_mapping_context_set_event_callback(mc: *const MappingContext,
+                                                                callback: NatEventCallback,
+                                                                user_data: *mut c_void)
+                                                                -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if mc.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let rx = (*mc).subscribe();
+        let user_data = UserData(user_data);
+        let _ = thread!("nat_traversal ffi events", move || {
+            for event in rx.iter() {
+                // Keep delivering the events that follow one whose conversion panicked.
+                catch_panic((), || deliver_event(callback, &user_data, &event));
+            }
+        });
+        0
+    })
+}
+
+/// Create and map a udp socket, taking at most `timeout_ms` milliseconds. On success `*out` is
+/// set to a handle which must be released with `nat_mapped_udp_socket_free` or consumed by
+/// `nat_punch_udp_hole`.
+#[no_mangle]
+pub unsafe extern "C" fn nat_mapped_udp_socket_new(mc: *const MappingContext,
+                                                   timeout_ms: u64,
+                                                   out: *mut *mut MappedUdpSocket)
+                                                   -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if mc.is_null() || out.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        match MappedUdpSocket::new(&*mc, deadline_from_ms(timeout_ms)) {
+            WOk(socket, _) => {
+                *out = Box::into_raw(Box::new(socket));
+                0
+            },
+            WErr(e) => e.code(),
+        }
+    })
+}
+
+/// Copy up to `capacity` endpoints of a mapped socket into `out`. `*out_len` is set to the total
+/// number of endpoints, which may be more than `capacity`.
+#[no_mangle]
+pub unsafe extern "C" fn nat_mapped_udp_socket_endpoints(socket: *const MappedUdpSocket,
+                                                         out: *mut NatEndpoint,
+                                                         capacity: usize,
+                                                         out_len: *mut usize)
+                                                         -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if socket.is_null() || out_len.is_null() || (out.is_null() && capacity > 0) {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let endpoints = &(*socket).endpoints;
+        for (i, endpoint) in endpoints.iter().take(capacity).enumerate() {
+            *out.offset(i as isize) = NatEndpoint::from_mapped(endpoint);
+        }
+        *out_len = endpoints.len();
+        0
+    })
+}
+
+/// Summarise what is known about the NAT in front of a mapped socket.
+#[no_mangle]
+pub unsafe extern "C" fn nat_mapped_udp_socket_info(mc: *const MappingContext,
+                                                    socket: *const MappedUdpSocket,
+                                                    out: *mut NatInfo)
+                                                    -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if mc.is_null() || socket.is_null() || out.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let endpoints = &(*socket).endpoints;
+        *out = NatInfo {
+            endpoint_count: endpoints.len() as u32,
+            unrestricted_count: endpoints.iter().filter(|e| !e.nat_restricted).count() as u32,
+            recommended_strategy: (*mc).recommended_strategy()
+                                       .map_or(NAT_STRATEGY_NONE, strategy_code),
+            double_nat: ((*mc).double_nat() == Some(true)) as u8,
+        };
+        0
+    })
+}
+
+/// Release a mapped udp socket.
+#[no_mangle]
+pub unsafe extern "C" fn nat_mapped_udp_socket_free(socket: *mut MappedUdpSocket) {
+    catch_panic((), || {
+        if !socket.is_null() {
+            drop(Box::from_raw(socket));
+        }
+    })
+}
+
+/// Generate rendezvous info for a mapped socket. `*out_priv` is set to our private info, which
+/// must be released with `nat_priv_rendezvous_info_free` or consumed by `nat_punch_udp_hole`.
+/// `*out_pub` and `*out_pub_len` are set to the serialised public info, which should be sent to
+/// the peer and then released with `nat_bytes_free`.
+#[no_mangle]
+pub unsafe extern "C" fn nat_gen_rendezvous_info(socket: *const MappedUdpSocket,
+                                                 out_priv: *mut *mut PrivRendezvousInfo,
+                                                 out_pub: *mut *mut u8,
+                                                 out_pub_len: *mut usize)
+                                                 -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if socket.is_null() || out_priv.is_null() || out_pub.is_null() || out_pub_len.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let endpoints = (*socket).endpoints.clone();
+        let (priv_info, pub_info) = rendezvous_info::gen_rendezvous_info(endpoints);
+        let data = match serialise(&pub_info) {
+            Ok(data) => data,
+            Err(_) => return NAT_ERR_SERIALISATION,
+        };
+        let mut data = data.into_boxed_slice();
+        *out_pub_len = data.len();
+        *out_pub = data.as_mut_ptr();
+        let _ = Box::into_raw(data);
+        *out_priv = Box::into_raw(Box::new(priv_info));
+        0
+    })
+}
+
+/// Release private rendezvous info.
+#[no_mangle]
+pub unsafe extern "C" fn nat_priv_rendezvous_info_free(info: *mut PrivRendezvousInfo) {
+    catch_panic((), || {
+        if !info.is_null() {
+            drop(Box::from_raw(info));
+        }
+    })
+}
+
+/// Release a buffer returned by this library.
+#[no_mangle]
+pub unsafe extern "C" fn nat_bytes_free(data: *mut u8, len: usize) {
+    catch_panic((), || {
+        if !data.is_null() {
+            let data: *mut [u8] = slice::from_raw_parts_mut(data, len);
+            drop(Box::from_raw(data));
+        }
+    })
+}
+
+/// Punch a hole to the peer whose serialised public rendezvous info is `their_pub`, taking at
+/// most `timeout_ms` milliseconds. `socket` and `our_priv` are consumed whether or not this
+/// succeeds. On success `*out` is set to a handle which must be released with
+/// `nat_punched_udp_socket_free`.
+#[no_mangle]
+pub unsafe extern "C" fn nat_punch_udp_hole(socket: *mut MappedUdpSocket,
+                                            our_priv: *mut PrivRendezvousInfo,
+                                            their_pub: *const u8,
+                                            their_pub_len: usize,
+                                            timeout_ms: u64,
+                                            out: *mut *mut PunchedUdpSocket)
+                                            -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if socket.is_null() || our_priv.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let socket = Box::from_raw(socket);
+        let our_priv = Box::from_raw(our_priv);
+        if their_pub.is_null() || out.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let their_pub = slice::from_raw_parts(their_pub, their_pub_len);
+        let their_pub: PubRendezvousInfo = match deserialise(their_pub) {
+            Ok(info) => info,
+            Err(_) => return NAT_ERR_SERIALISATION,
+        };
+        match PunchedUdpSocket::punch_hole(socket.socket,
+                                           *our_priv,
+                                           their_pub,
+                                           deadline_from_ms(timeout_ms)) {
+            WOk(punched, _) => {
+                *out = Box::into_raw(Box::new(punched));
+                0
+            },
+            WErr(e) => e.code(),
+        }
+    })
+}
+
+/// Start punching a hole to the peer whose serialised public rendezvous info is `their_pub`,
+/// taking at most `timeout_ms` milliseconds, without blocking or spawning threads. `socket` and
+/// `our_priv` are consumed whether or not this succeeds. On success `*out` is set to a handle to
+/// drive with `nat_drive`, which must be released with `nat_punch_finish` or `nat_punch_free`.
+#[no_mangle]
+pub unsafe extern "C" fn nat_punch_udp_hole_start(socket: *mut MappedUdpSocket,
+                                                  our_priv: *mut PrivRendezvousInfo,
+                                                  their_pub: *const u8,
+                                                  their_pub_len: usize,
+                                                  timeout_ms: u64,
+                                                  out: *mut *mut NatUdpPunch)
+                                                  -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if socket.is_null() || our_priv.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let socket = Box::from_raw(socket);
+        let our_priv = Box::from_raw(our_priv);
+        if their_pub.is_null() || out.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let their_pub = slice::from_raw_parts(their_pub, their_pub_len);
+        let their_pub: PubRendezvousInfo = match deserialise(their_pub) {
+            Ok(info) => info,
+            Err(_) => return NAT_ERR_SERIALISATION,
+        };
+        if let Err(e) = socket.socket.set_nonblocking(true) {
+            return UdpPunchHoleError::Io { err: e }.code();
+        }
+        let now = Instant::now();
+        let machine = UdpPunchStateMachine::new(*our_priv,
+                                                their_pub,
+                                                now,
+                                                now + Duration::from_millis(timeout_ms));
+        *out = Box::into_raw(Box::new(NatUdpPunch {
+            socket: socket.socket,
+            machine: machine,
+        }));
+        0
+    })
+}
+
+/// The file descriptor to wait on for a punch to be readable. It stays owned by the punch.
+/// Returns `-1` if `punch` is null.
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn nat_punch_fd(punch: *const NatUdpPunch) -> ::std::os::raw::c_int {
+    catch_panic(-1, || {
+        use std::os::unix::io::AsRawFd;
+        if punch.is_null() {
+            return -1;
+        }
+        (*punch).socket.as_raw_fd()
+    })
+}
+
+/// The socket to wait on for a punch to be readable. It stays owned by the punch. Returns
+/// `INVALID_SOCKET` if `punch` is null.
+#[cfg(windows)]
+#[no_mangle]
+pub unsafe extern "C" fn nat_punch_socket(punch: *const NatUdpPunch) -> u64 {
+    catch_panic(!0, || {
+        use std::os::windows::io::AsRawSocket;
+        if punch.is_null() {
+            return !0;
+        }
+        (*punch).socket.as_raw_socket() as u64
+    })
+}
+
+/// How many milliseconds until `nat_drive` must be called even if the socket hasn't become
+/// readable, rounded up. Returns `-1` once there's nothing left to wait for, or if `punch` is
+/// null.
+#[no_mangle]
+pub unsafe extern "C" fn nat_punch_timeout_ms(punch: *const NatUdpPunch) -> i64 {
+    catch_panic(-1, || {
+        if punch.is_null() {
+            return -1;
+        }
+        let timeout = match (*punch).machine.poll_timeout() {
+            Some(timeout) => timeout,
+            None => return -1,
+        };
+        let now = Instant::now();
+        if timeout <= now {
+            return 0;
+        }
+        let wait = timeout - now;
+        (wait.as_secs() * 1000 + (wait.subsec_nanos() as u64 + 999_999) / 1_000_000) as i64
+    })
+}
+
+/// Make progress on a punch: handle the packets that have arrived and send any that are due.
+/// Never blocks. `*out_status` is set to one of the `NAT_PUNCH_` constants.
+#[no_mangle]
+pub unsafe extern "C" fn nat_drive(punch: *mut NatUdpPunch, out_status: *mut u32) -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if punch.is_null() || out_status.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        match (*punch).drive(Instant::now()) {
+            Ok(status) => {
+                *out_status = punch_status_code(status);
+                0
+            },
+            Err(e) => UdpPunchHoleError::Io { err: e }.code(),
+        }
+    })
+}
+
+/// Finish a punch that `nat_drive` reported as over, consuming it. On success `*out` is set to a
+/// handle to the punched socket, back in blocking mode, which must be released with
+/// `nat_punched_udp_socket_free`. Fails with `NAT_ERR_PUNCH_IN_PROGRESS`, leaving the punch
+/// untouched, if it isn't over yet.
+#[no_mangle]
+pub unsafe extern "C" fn nat_punch_finish(punch: *mut NatUdpPunch,
+                                          out: *mut *mut PunchedUdpSocket)
+                                          -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if punch.is_null() || out.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let peer_addr = match (*punch).machine.status() {
+            UdpPunchStatus::InProgress => return NAT_ERR_PUNCH_IN_PROGRESS,
+            UdpPunchStatus::Connected(peer_addr) => peer_addr,
+            UdpPunchStatus::TimedOut => {
+                drop(Box::from_raw(punch));
+                return UdpPunchHoleError::TimedOut.code();
+            },
+        };
+        let punch = Box::from_raw(punch);
+        if let Err(e) = punch.socket.set_nonblocking(false) {
+            return UdpPunchHoleError::Io { err: e }.code();
+        }
+        *out = Box::into_raw(Box::new(PunchedUdpSocket {
+            socket: punch.socket,
+            peer_addr: SocketAddr(peer_addr),
+            local_ip: None,
+        }));
+        0
+    })
+}
+
+/// Release a punch, closing its socket.
+#[no_mangle]
+pub unsafe extern "C" fn nat_punch_free(punch: *mut NatUdpPunch) {
+    catch_panic((), || {
+        if !punch.is_null() {
+            drop(Box::from_raw(punch));
+        }
+    })
+}
+
+/// Queue the events raised through `mc` from now on, to be taken with `nat_event_queue_drain`
+/// rather than delivered from a library thread. On success `*out` is set to a handle which must
+/// be released with `nat_event_queue_free`.
+#[no_mangle]
+pub unsafe extern "C" fn nat_event_queue_new(mc: *const MappingContext,
+                                             out: *mut *mut NatEventQueue)
+                                             -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if mc.is_null() || out.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        *out = Box::into_raw(Box::new(NatEventQueue { rx: (*mc).subscribe() }));
+        0
+    })
+}
+
+/// Call `callback`, on the calling thread and in the order they happened, with each event queued
+/// so far. Never blocks.
+#[no_mangle]
+pub unsafe extern "C" fn nat_event_queue_drain(queue: *const NatEventQueue,
+                                               callback: NatEventCallback,
+                                               user_data: *mut c_void)
+                                               -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if queue.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let user_data = UserData(user_data);
+        while let Ok(event) = (*queue).rx.try_recv() {
+            catch_panic((), || deliver_event(callback, &user_data, &event));
+        }
+        0
+    })
+}
+
+/// Release an event queue.
+#[no_mangle]
+pub unsafe extern "C" fn nat_event_queue_free(queue: *mut NatEventQueue) {
+    catch_panic((), || {
+        if !queue.is_null() {
+            drop(Box::from_raw(queue));
+        }
+    })
+}
+
+/// Write the peer address of a punched socket into `buf` as a nul-terminated string. Fails with
+/// `NAT_ERR_INVALID_ADDRESS` if `buf_len` is too small.
+#[no_mangle]
+pub unsafe extern "C" fn nat_punched_udp_socket_peer_addr(socket: *const PunchedUdpSocket,
+                                                          buf: *mut c_char,
+                                                          buf_len: usize)
+                                                          -> u32 {
+    catch_panic(NAT_ERR_PANIC, || {
+        if socket.is_null() || buf.is_null() {
+            return NAT_ERR_NULL_POINTER;
+        }
+        let addr = format!("{}", *(*socket).peer_addr);
+        if addr.len() >= buf_len {
+            return NAT_ERR_INVALID_ADDRESS;
+        }
+        ptr::copy_nonoverlapping(addr.as_ptr() as *const c_char, buf, addr.len());
+        *buf.offset(addr.len() as isize) = 0;
+        0
+    })
+}
+
+/// Take ownership of the file descriptor of a punched socket, releasing the handle. Returns `-1`
+/// if `socket` is null.
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn nat_punched_udp_socket_into_raw_fd(socket: *mut PunchedUdpSocket)
+                                                            -> ::std::os::raw::c_int {
+    catch_panic(-1, || {
+        use std::os::unix::io::IntoRawFd;
+        if socket.is_null() {
+            return -1;
+        }
+        Box::from_raw(socket).socket.into_raw_fd()
+    })
+}
+
+/// Release a punched socket, closing it.
+#[no_mangle]
+pub unsafe extern "C" fn nat_punched_udp_socket_free(socket: *mut PunchedUdpSocket) {
+    catch_panic((), || {
+        if !socket.is_null() {
+            drop(Box::from_raw(socket));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{UserData, catch_panic, deliver_event};
+
+    use std::net::UdpSocket;
+    use std::os::raw::c_void;
+    use std::ptr;
+    use std::thread;
+    use std::time::Duration;
+
+    use maidsafe_utilities::serialisation::serialise;
+    use socket_addr::SocketAddr;
+
+    use event::{Event, Strategy};
+    use mapped_socket_addr::MappedSocketAddr;
+    use mapped_udp_socket::MappedUdpSocket;
+    use punched_udp_socket::PunchedUdpSocket;
+    use rendezvous_info::gen_rendezvous_info;
+
+    extern "C" fn record_event(user_data: *mut c_void, event: *const NatEvent) {
+        unsafe {
+            let events = &mut *(user_data as *mut Vec<(u32, i32, u16)>);
+            let event = &*event;
+            events.push((event.kind, event.strategy, event.endpoint.port));
+        }
+    }
+
+    #[test]
+    fn events_are_converted_for_c() {
+        let mut events: Vec<(u32, i32, u16)> = Vec::new();
+        let events_ptr: *mut Vec<(u32, i32, u16)> = &mut events;
+        let user_data = UserData(events_ptr as *mut c_void);
+        let peer_addr = SocketAddr(unwrap_result!("192.0.2.1:4000".parse()));
+        deliver_event(record_event, &user_data, &Event::Connected {
+            strategy: Strategy::UdpHolePunch,
+            peer_addr: peer_addr,
+        });
+        deliver_event(record_event, &user_data, &Event::StrategyChanged {
+            strategy: Strategy::Igd,
+        });
+        assert_eq!(events, vec![(NAT_EVENT_CONNECTED, NAT_STRATEGY_UDP_HOLE_PUNCH, 4000),
+                                (NAT_EVENT_STRATEGY_CHANGED, NAT_STRATEGY_IGD, 0)]);
+
+        let endpoint = NatEndpoint::from_addr(&unwrap_result!("192.0.2.1:4000".parse()), true);
+        assert_eq!(&endpoint.ip[..4], &[192, 0, 2, 1]);
+        assert_eq!(endpoint.is_ipv6, 0);
+        assert_eq!(endpoint.nat_restricted, 1);
+    }
+
+    // Start a punch from `socket` towards the peer whose serialised public info is `their_pub`.
+    unsafe fn start(socket: UdpSocket,
+                    their_pub: &[u8],
+                    priv_info: PrivRendezvousInfo)
+                    -> *mut NatUdpPunch {
+        let socket = Box::into_raw(Box::new(MappedUdpSocket {
+            socket: socket,
+            endpoints: Vec::new(),
+        }));
+        let mut punch = ptr::null_mut();
+        assert_eq!(nat_punch_udp_hole_start(socket,
+                                            Box::into_raw(Box::new(priv_info)),
+                                            their_pub.as_ptr(),
+                                            their_pub.len(),
+                                            5000,
+                                            &mut punch),
+                   0);
+        punch
+    }
+
+    #[test]
+    fn punches_are_driven_from_the_callers_loop() {
+        let socket_0 = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let socket_1 = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let endpoint = |socket: &UdpSocket| {
+            MappedSocketAddr {
+                addr: SocketAddr(unwrap_result!(socket.local_addr())),
+                nat_restricted: false,
+                port_unknown: false,
+                unverified: false,
+            }
+        };
+        let (priv_0, pub_0) = gen_rendezvous_info(vec![endpoint(&socket_0)]);
+        let (priv_1, pub_1) = gen_rendezvous_info(vec![endpoint(&socket_1)]);
+        let pub_0 = unwrap_result!(serialise(&pub_0));
+        let pub_1 = unwrap_result!(serialise(&pub_1));
+
+        unsafe {
+            let punches = [start(socket_0, &pub_1, priv_0), start(socket_1, &pub_0, priv_1)];
+            assert!(nat_punch_timeout_ms(punches[0]) >= 0);
+            let mut statuses = [NAT_PUNCH_IN_PROGRESS; 2];
+            while statuses.contains(&NAT_PUNCH_IN_PROGRESS) {
+                for i in 0..2 {
+                    assert_eq!(nat_drive(punches[i], &mut statuses[i]), 0);
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(statuses, [NAT_PUNCH_CONNECTED; 2]);
+            for punch in &punches {
+                let mut punched: *mut PunchedUdpSocket = ptr::null_mut();
+                assert_eq!(nat_punch_finish(*punch, &mut punched), 0);
+                nat_punched_udp_socket_free(punched);
+            }
+        }
+    }
+
+    #[test]
+    fn null_arguments_are_rejected() {
+        unsafe {
+            assert_eq!(nat_mapping_context_new(ptr::null_mut()), NAT_ERR_NULL_POINTER);
+            assert_eq!(nat_mapping_context_search_gateways(ptr::null()), NAT_ERR_NULL_POINTER);
+            assert_eq!(nat_mapped_udp_socket_new(ptr::null(), 0, ptr::null_mut()),
+                       NAT_ERR_NULL_POINTER);
+            nat_mapping_context_free(ptr::null_mut());
+            nat_bytes_free(ptr::null_mut(), 0);
+        }
+    }
+
+    #[test]
+    fn panics_stop_at_the_boundary() {
+        let code = catch_panic(NAT_ERR_PANIC, || -> u32 { panic!("Deliberate panic") });
+        assert_eq!(code, NAT_ERR_PANIC);
+        assert_eq!(catch_panic(NAT_ERR_PANIC, || 0), 0);
+    }
+}
@@ -0,0 +1,496 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+// Checking Windows Firewall, which silently drops inbound hole punch packets for programs it
+// hasn't been told to allow. Everything goes through netsh rather than the firewall's COM API.
+// netsh's output is localised, so only English installations can be checked, and elsewhere
+// `check_firewall` fails with `UnrecognisedOutput`. On other platforms it fails with
+// `Unsupported`.
+
+use std::io;
+
+use error_code::{ErrorCategory, ErrorCode};
+
+quick_error! {
+    /// Errors returned by `check_firewall` and `add_firewall_rule`.
+    #[derive(Debug)]
+    pub enum FirewallError {
+        /// Windows Firewall only exists on Windows.
+        Unsupported {
+            description("Windows Firewall can only be checked on Windows")
+        }
+        /// Error finding the path of this program, which firewall rules are keyed by.
+        CurrentExe { err: io::Error } {
+            description("Error finding the path of this program")
+            display("Error finding the path of this program: {}", err)
+            cause(err)
+        }
+        /// Error running netsh.
+        RunNetsh { err: io::Error } {
+            description("Error running netsh")
+            display("Error running netsh: {}", err)
+            cause(err)
+        }
+        /// netsh failed. Adding a rule fails like this when the process isn't elevated.
+        NetshFailed { output: String } {
+            description("netsh failed")
+            display("netsh failed: {}", output)
+        }
+        /// netsh's output couldn't be read, probably because it's in a language other than
+        /// English.
+        UnrecognisedOutput {
+            description("netsh's output wasn't recognised")
+        }
+    }
+}
+
+impl From<FirewallError> for io::Error {
+    fn from(e: FirewallError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            FirewallError::Unsupported => io::ErrorKind::Other,
+            FirewallError::CurrentExe { err } => err.kind(),
+            FirewallError::RunNetsh { err } => err.kind(),
+            FirewallError::NetshFailed { .. } => io::ErrorKind::Other,
+            FirewallError::UnrecognisedOutput => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for FirewallError {
+    fn code(&self) -> u32 {
+        match *self {
+            FirewallError::Unsupported => 3201,
+            FirewallError::CurrentExe { .. } => 3202,
+            FirewallError::RunNetsh { .. } => 3203,
+            FirewallError::NetshFailed { .. } => 3204,
+            FirewallError::UnrecognisedOutput => 3205,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            FirewallError::Unsupported => ErrorCategory::Unsupported,
+            FirewallError::CurrentExe { .. } => ErrorCategory::Configuration,
+            FirewallError::RunNetsh { .. } => ErrorCategory::Configuration,
+            FirewallError::NetshFailed { .. } => ErrorCategory::Configuration,
+            FirewallError::UnrecognisedOutput => ErrorCategory::Unsupported,
+        }
+    }
+}
+
+/// The protocol of a port that the firewall is checked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirewallProtocol {
+    /// Udp, for `MappedUdpSocket`s.
+    Udp,
+    /// Tcp, for `MappedTcpSocket`s.
+    Tcp,
+}
+
+/// What Windows Firewall does with inbound packets to one of this program's ports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirewallVerdict {
+    /// The firewall is off, or lets inbound packets through by default, for the active network
+    /// profiles.
+    Allowed,
+    /// The enabled inbound rule named `rule` allows the packets.
+    AllowedByRule {
+        /// The rule's name.
+        rule: String,
+    },
+    /// The enabled inbound rule named `rule` blocks the packets. Block rules take precedence over
+    /// allow rules so adding one won't help. The rule needs disabling.
+    BlockedByRule {
+        /// The rule's name.
+        rule: String,
+    },
+    /// No rule allows the packets so the default policy of the active network profile `profile`
+    /// drops them. `add_firewall_rule` fixes this.
+    BlockedByDefault {
+        /// The profile's name, eg. `"Public"`.
+        profile: String,
+    },
+    /// The active network profile `profile` is set to block all inbound packets, whatever the
+    /// rules say. Only the user can change this.
+    BlockedAlways {
+        /// The profile's name, eg. `"Public"`.
+        profile: String,
+    },
+}
+
+/// Check whether Windows Firewall lets inbound packets through to `port` for this program.
+#[cfg(target_family = "windows")]
+pub fn check_firewall(protocol: FirewallProtocol, port: u16)
+                      -> Result<FirewallVerdict, FirewallError> {
+    let program = try!(current_exe());
+    let profiles = match parse_profiles(&try!(netsh(&["advfirewall", "show", "currentprofile"]))) {
+        Some(profiles) => profiles,
+        None => return Err(FirewallError::UnrecognisedOutput),
+    };
+    let rules = match parse_rules(&try!(netsh(&["advfirewall", "firewall", "show", "rule",
+                                                "name=all", "dir=in", "verbose"]))) {
+        Some(rules) => rules,
+        None => return Err(FirewallError::UnrecognisedOutput),
+    };
+    Ok(verdict(&profiles, &rules, protocol, port, &program))
+}
+
+/// Check whether Windows Firewall lets inbound packets through to `port` for this program. Always
+/// fails as there's no Windows Firewall on this platform.
+#[cfg(not(target_family = "windows"))]
+pub fn check_firewall(_protocol: FirewallProtocol, _port: u16)
+                      -> Result<FirewallVerdict, FirewallError> {
+    Err(FirewallError::Unsupported)
+}
+
+/// Add an inbound Windows Firewall rule named `name` allowing packets to `port` for this program.
+/// This changes the machine's security policy so only call it with the user's consent. It needs
+/// an elevated process and fails with `NetshFailed` otherwise.
+#[cfg(target_family = "windows")]
+pub fn add_firewall_rule(name: &str, protocol: FirewallProtocol, port: u16)
+                         -> Result<(), FirewallError> {
+    let program = try!(current_exe());
+    let protocol = match protocol {
+        FirewallProtocol::Udp => "protocol=UDP",
+        FirewallProtocol::Tcp => "protocol=TCP",
+    };
+    let _ = try!(netsh(&["advfirewall", "firewall", "add", "rule",
+                         &format!("name={}", name),
+                         "dir=in",
+                         "action=allow",
+                         protocol,
+                         &format!("localport={}", port),
+                         &format!("program={}", program),
+                         "enable=yes"]));
+    Ok(())
+}
+
+/// Add an inbound Windows Firewall rule named `name` allowing packets to `port` for this program.
+/// Always fails as there's no Windows Firewall on this platform.
+#[cfg(not(target_family = "windows"))]
+pub fn add_firewall_rule(_name: &str, _protocol: FirewallProtocol, _port: u16)
+                         -> Result<(), FirewallError> {
+    Err(FirewallError::Unsupported)
+}
+
+#[cfg(target_family = "windows")]
+fn current_exe() -> Result<String, FirewallError> {
+    match ::std::env::current_exe() {
+        Ok(path) => Ok(path.to_string_lossy().into_owned()),
+        Err(e) => Err(FirewallError::CurrentExe { err: e }),
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn netsh(args: &[&str]) -> Result<String, FirewallError> {
+    use std::process::Command;
+
+    let output = match Command::new("netsh").args(args).output() {
+        Ok(output) => output,
+        Err(e) => return Err(FirewallError::RunNetsh { err: e }),
+    };
+    // netsh reports errors on stdout.
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if !output.status.success() {
+        return Err(FirewallError::NetshFailed { output: stdout.trim().to_owned() });
+    }
+    Ok(stdout)
+}
+
+// A network profile that's active on some interface.
+#[cfg(any(target_family = "windows", test))]
+#[derive(Debug)]
+struct Profile {
+    name: String,
+    enabled: bool,
+    // The first half of the "Firewall Policy" setting, eg. "BlockInbound".
+    inbound_policy: String,
+}
+
+// An inbound rule. Fields netsh doesn't print are "Any".
+#[cfg(any(target_family = "windows", test))]
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    enabled: bool,
+    profiles: String,
+    protocol: String,
+    local_port: String,
+    program: String,
+    service: String,
+    allow: bool,
+}
+
+// Name/value lines with the name ending at a colon, or for profile settings at a run of spaces.
+#[cfg(any(target_family = "windows", test))]
+fn split_line(line: &str) -> Option<(&str, &str)> {
+    if let Some(i) = line.find(':') {
+        return Some((line[..i].trim(), line[i + 1..].trim()));
+    }
+    line.find("  ").map(|i| (line[..i].trim(), line[i..].trim()))
+}
+
+// `netsh advfirewall show currentprofile` prints a block starting "Private Profile Settings:"
+// for each active profile, with lines like "State   ON" and
+// "Firewall Policy   BlockInbound,AllowOutbound". Returns `None` unless there's at least one
+// profile and each has both of those lines, as there won't be if the output is localised.
+#[cfg(any(target_family = "windows", test))]
+fn parse_profiles(output: &str) -> Option<Vec<Profile>> {
+    // Each profile, with whether its state and its policy have been seen.
+    let mut profiles: Vec<(Profile, bool, bool)> = Vec::new();
+    for line in output.lines() {
+        let line = line.trim();
+        if line.ends_with(" Profile Settings:") {
+            profiles.push((Profile {
+                name: line[..line.len() - " Profile Settings:".len()].to_owned(),
+                enabled: true,
+                inbound_policy: String::from("BlockInbound"),
+            }, false, false));
+            continue;
+        }
+        let &mut (ref mut profile, ref mut seen_state, ref mut seen_policy) =
+            match profiles.last_mut() {
+                Some(profile) => profile,
+                None => continue,
+            };
+        match split_line(line) {
+            Some(("State", value)) => {
+                profile.enabled = value == "ON";
+                *seen_state = true;
+            },
+            Some(("Firewall Policy", value)) => {
+                profile.inbound_policy = value.split(',').next().unwrap_or("").to_owned();
+                *seen_policy = true;
+            },
+            _ => (),
+        }
+    }
+    if profiles.is_empty() || profiles.iter().any(|&(_, state, policy)| !state || !policy) {
+        return None;
+    }
+    Some(profiles.into_iter().map(|(profile, _, _)| profile).collect())
+}
+
+// `netsh advfirewall firewall show rule name=all dir=in verbose` prints a block starting
+// "Rule Name:   ..." for each rule, with lines like "Enabled:   Yes" and "LocalPort:   4000".
+// With no inbound rules it prints a single sentence. Returns `None` if there are name/value lines
+// but no rules, or a rule without its "Enabled" and "Action" lines, as when the output is
+// localised.
+#[cfg(any(target_family = "windows", test))]
+fn parse_rules(output: &str) -> Option<Vec<Rule>> {
+    // Each rule, with whether it's enabled and its action have been seen.
+    let mut rules: Vec<(Rule, bool, bool)> = Vec::new();
+    let mut labelled = false;
+    for line in output.lines() {
+        let (name, value) = match split_line(line) {
+            Some((name, value)) => (name, value.to_owned()),
+            None => continue,
+        };
+        labelled = true;
+        if name == "Rule Name" {
+            rules.push((Rule {
+                name: value,
+                enabled: false,
+                profiles: String::from("Any"),
+                protocol: String::from("Any"),
+                local_port: String::from("Any"),
+                program: String::from("Any"),
+                service: String::from("Any"),
+                allow: false,
+            }, false, false));
+            continue;
+        }
+        let &mut (ref mut rule, ref mut seen_enabled, ref mut seen_action) =
+            match rules.last_mut() {
+                Some(rule) => rule,
+                None => continue,
+            };
+        match name {
+            "Enabled" => {
+                rule.enabled = value == "Yes";
+                *seen_enabled = true;
+            },
+            "Profiles" => rule.profiles = value,
+            "Protocol" => rule.protocol = value,
+            "LocalPort" => rule.local_port = value,
+            "Program" => rule.program = value,
+            "Service" => rule.service = value,
+            "Action" => {
+                rule.allow = value == "Allow";
+                *seen_action = true;
+            },
+            _ => (),
+        }
+    }
+    if (labelled && rules.is_empty()) ||
+       rules.iter().any(|&(_, enabled, action)| !enabled || !action) {
+        return None;
+    }
+    Some(rules.into_iter().map(|(rule, _, _)| rule).collect())
+}
+
+#[cfg(any(target_family = "windows", test))]
+fn rule_applies(rule: &Rule,
+                profile: &Profile,
+                protocol: FirewallProtocol,
+                port: u16,
+                program: &str)
+                -> bool {
+    let profile_matches = rule.profiles == "Any" ||
+                          rule.profiles.split(',').any(|p| p.trim() == profile.name);
+    let protocol_matches = match (&rule.protocol[..], protocol) {
+        ("Any", _) |
+        ("UDP", FirewallProtocol::Udp) |
+        ("17", FirewallProtocol::Udp) |
+        ("TCP", FirewallProtocol::Tcp) |
+        ("6", FirewallProtocol::Tcp) => true,
+        _ => false,
+    };
+    // Ports may be listed and ranged, eg. "4000,5000-5010". Keywords such as "RPC" never match.
+    let port_matches = rule.local_port.split(',').any(|ports| {
+        let ports = ports.trim();
+        if ports == "Any" {
+            return true;
+        }
+        let mut bounds = ports.splitn(2, '-').map(|p| p.trim().parse::<u16>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(first)), None) => first == port,
+            (Some(Ok(first)), Some(Ok(last))) => first <= port && port <= last,
+            _ => false,
+        }
+    });
+    let program_matches = rule.program == "Any" ||
+                          rule.program.to_lowercase() == program.to_lowercase();
+    rule.enabled && rule.service == "Any" && profile_matches && protocol_matches &&
+    port_matches && program_matches
+}
+
+// Block rules win over allow rules, and a profile set to block everything ignores both.
+#[cfg(any(target_family = "windows", test))]
+fn verdict(profiles: &[Profile],
+           rules: &[Rule],
+           protocol: FirewallProtocol,
+           port: u16,
+           program: &str)
+           -> FirewallVerdict {
+    let mut allowed_by = None;
+    for profile in profiles {
+        if !profile.enabled || profile.inbound_policy == "AllowInbound" {
+            continue;
+        }
+        if profile.inbound_policy == "BlockInboundAlways" {
+            return FirewallVerdict::BlockedAlways { profile: profile.name.clone() };
+        }
+        let applicable: Vec<&Rule> = rules.iter()
+                                          .filter(|r| rule_applies(r, profile, protocol, port,
+                                                                   program))
+                                          .collect();
+        if let Some(rule) = applicable.iter().find(|r| !r.allow) {
+            return FirewallVerdict::BlockedByRule { rule: rule.name.clone() };
+        }
+        match applicable.first() {
+            Some(rule) => allowed_by = Some(rule.name.clone()),
+            None => return FirewallVerdict::BlockedByDefault { profile: profile.name.clone() },
+        }
+    }
+    match allowed_by {
+        Some(rule) => FirewallVerdict::AllowedByRule { rule: rule },
+        None => FirewallVerdict::Allowed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{parse_profiles, parse_rules, verdict};
+
+    const PROFILES: &'static str = "
+Public Profile Settings:
+----------------------------------------------------------------------
+State                                 ON
+Firewall Policy                       BlockInbound,AllowOutbound
+LocalFirewallRules                    N/A (GPO-store only)
+
+Ok.
+";
+
+    const RULES: &'static str = "
+Rule Name:                            My App
+----------------------------------------------------------------------
+Enabled:                              Yes
+Direction:                            In
+Profiles:                             Private,Public
+Protocol:                             UDP
+LocalPort:                            4000,5000-5010
+Program:                              C:\\Program Files\\MyApp\\myapp.exe
+Action:                               Allow
+
+Rule Name:                            Block 5005
+----------------------------------------------------------------------
+Enabled:                              Yes
+Direction:                            In
+Profiles:                             Public
+Protocol:                             Any
+LocalPort:                            5005
+Action:                               Block
+Ok.
+";
+
+    #[test]
+    fn verdicts_follow_profiles_and_rules() {
+        let program = "c:\\program files\\myapp\\MYAPP.EXE";
+        let profiles = parse_profiles(PROFILES).expect("unrecognised profiles");
+        let rules = parse_rules(RULES).expect("unrecognised rules");
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(rules.len(), 2);
+
+        let check = |protocol, port| verdict(&profiles, &rules, protocol, port, program);
+        assert_eq!(check(FirewallProtocol::Udp, 4000),
+                   FirewallVerdict::AllowedByRule { rule: String::from("My App") });
+        assert_eq!(check(FirewallProtocol::Udp, 5005),
+                   FirewallVerdict::BlockedByRule { rule: String::from("Block 5005") });
+        assert_eq!(check(FirewallProtocol::Tcp, 4000),
+                   FirewallVerdict::BlockedByDefault { profile: String::from("Public") });
+
+        let off = parse_profiles(&PROFILES.replace("ON", "OFF")).expect("unrecognised profiles");
+        assert_eq!(verdict(&off, &rules, FirewallProtocol::Tcp, 4000, program),
+                   FirewallVerdict::Allowed);
+        let always = PROFILES.replace("BlockInbound,", "BlockInboundAlways,");
+        let always = parse_profiles(&always).expect("unrecognised profiles");
+        assert_eq!(verdict(&always, &rules, FirewallProtocol::Udp, 4000, program),
+                   FirewallVerdict::BlockedAlways { profile: String::from("Public") });
+    }
+
+    #[test]
+    fn localised_output_is_unrecognised() {
+        let profiles = PROFILES.replace("State", "Status")
+                               .replace("Firewall Policy", "Firewallrichtlinie");
+        assert!(parse_profiles(&profiles).is_none());
+        assert!(parse_profiles("").is_none());
+
+        let rules = RULES.replace("Rule Name", "Regelname");
+        assert!(parse_rules(&rules).is_none());
+        assert!(parse_rules(&RULES.replace("Action", "Aktion")).is_none());
+        let no_rules = parse_rules("\nNo rules match the specified criteria.\n");
+        assert_eq!(no_rules.map(|rules| rules.len()), Some(0));
+    }
+}
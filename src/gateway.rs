@@ -0,0 +1,98 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+// The parts of `igd` used by this crate. When the `upnp` feature is disabled these are replaced
+// with stand-ins that never find a gateway, so that the rest of the crate, including the public
+// error types, is the same with or without the feature.
+
+#[cfg(feature = "upnp")]
+pub use igd::{AddAnyPortError, Gateway, PortMappingProtocol, SearchError,
+              search_gateway_from_timeout};
+
+#[cfg(not(feature = "upnp"))]
+pub use self::disabled::{AddAnyPortError, Gateway, PortMappingProtocol, SearchError,
+                         search_gateway_from_timeout};
+
+#[cfg(not(feature = "upnp"))]
+mod disabled {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::Duration;
+
+    use void::Void;
+
+    /// Stand-in for `igd::PortMappingProtocol`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PortMappingProtocol {
+        /// TCP.
+        TCP,
+        /// UDP.
+        UDP,
+    }
+
+    quick_error! {
+        /// Stand-in for `igd::SearchError`.
+        #[derive(Debug)]
+        pub enum SearchError {
+            /// Gateway search is not available in this build.
+            Disabled {
+                description("UPnP support was disabled at compile time.")
+            }
+        }
+    }
+
+    quick_error! {
+        /// Stand-in for `igd::AddAnyPortError`.
+        #[derive(Debug)]
+        pub enum AddAnyPortError {
+            /// The gateway can't map the requested port.
+            ExternalPortInUse {
+                description("The external port is in use.")
+            }
+            /// Port mapping is not available in this build.
+            Disabled {
+                description("UPnP support was disabled at compile time.")
+            }
+        }
+    }
+
+    /// Stand-in for `igd::Gateway`. Can never be constructed.
+    #[derive(Debug, Clone)]
+    pub struct Gateway {
+        /// The address of the gateway.
+        pub addr: SocketAddrV4,
+        never: Void,
+    }
+
+    impl Gateway {
+        pub fn get_any_address(&self,
+                               _protocol: PortMappingProtocol,
+                               _local_addr: SocketAddrV4,
+                               _lease_duration: u32,
+                               _description: &str)
+                               -> Result<SocketAddrV4, AddAnyPortError> {
+            match self.never {}
+        }
+    }
+
+    pub fn search_gateway_from_timeout(_ip: Ipv4Addr, _timeout: Duration)
+                                       -> Result<Gateway, SearchError> {
+        Err(SearchError::Disabled)
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Coordinated shutdown of punched tcp streams.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+
+use net2::TcpStreamExt;
+
+use event::{Event, EventSender};
+use gateway as igd;
+use mapped_tcp_socket::PunchedTcpStream;
+use mapping_context::{self, MappingContext};
+
+/// A punched tcp stream that is shut down in step with the peer. Each side half-closes with
+/// `shutdown_write` once it has nothing more to send and reads until the peer does the same, so
+/// neither is left waiting on a timeout to learn that the connection is over.
+///
+/// Closing reports `Event::Closed` for the stream's local address. If the stream was dropped, or
+/// the peer didn't finish in time, the NAT may still hold the mapping for the dead connection, so
+/// a `MappingContext` given with `in_context` stops reusing the stream's port for new sockets.
+pub struct GracefulTcpStream<'a> {
+    punched: PunchedTcpStream,
+    mc: Option<&'a MappingContext>,
+    events: Option<EventSender>,
+    write_closed: bool,
+    peer_write_closed: bool,
+    closed: bool,
+}
+
+impl<'a> fmt::Debug for GracefulTcpStream<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GracefulTcpStream")
+         .field("punched", &self.punched)
+         .field("write_closed", &self.write_closed)
+         .field("peer_write_closed", &self.peer_write_closed)
+         .finish()
+    }
+}
+
+impl<'a> GracefulTcpStream<'a> {
+    /// Wrap a punched stream.
+    pub fn new(punched: PunchedTcpStream) -> GracefulTcpStream<'a> {
+        GracefulTcpStream {
+            punched: punched,
+            mc: None,
+            events: None,
+            write_closed: false,
+            peer_write_closed: false,
+            closed: false,
+        }
+    }
+
+    /// Report the close through the events of `mc`, and stop it reusing the stream's port if the
+    /// connection dies.
+    pub fn in_context(mut self, mc: &'a MappingContext) -> GracefulTcpStream<'a> {
+        self.mc = Some(mc);
+        self.events = Some(mapping_context::events(mc).clone());
+        self
+    }
+
+    /// Report the close through `events`.
+    pub fn events(mut self, events: &EventSender) -> GracefulTcpStream<'a> {
+        self.events = Some(events.clone());
+        self
+    }
+
+    /// The wrapped stream.
+    pub fn punched(&self) -> &PunchedTcpStream {
+        &self.punched
+    }
+
+    /// How long closing the socket waits for unsent data to go out. `None`, the default, sends it
+    /// in the background. `Some(Duration::new(0, 0))` discards it and resets the connection.
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        self.punched.stream.set_linger(linger)
+    }
+
+    /// Flush anything written and tell the peer we won't send any more. Reading still works.
+    pub fn shutdown_write(&mut self) -> io::Result<()> {
+        if self.write_closed {
+            return Ok(());
+        }
+        try!(self.punched.stream.flush());
+        try!(self.punched.stream.shutdown(Shutdown::Write));
+        self.write_closed = true;
+        Ok(())
+    }
+
+    /// Whether the peer has told us it won't send any more.
+    pub fn peer_write_closed(&self) -> bool {
+        self.peer_write_closed
+    }
+
+    /// Half-close our side and discard whatever the peer still sends until it half-closes its
+    /// side too, giving up at `deadline`.
+    pub fn close(mut self, deadline: Instant) -> io::Result<()> {
+        try!(self.shutdown_write());
+        let mut buf = [0u8; 1024];
+        while !self.peer_write_closed {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          "Timed out waiting for the peer to close"));
+            }
+            try!(self.punched.stream.set_read_timeout(Some(deadline - now)));
+            match self.read(&mut buf) {
+                Ok(..) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) => return Err(e),
+            }
+        }
+        self.closed = true;
+        Ok(())
+    }
+}
+
+impl<'a> Read for GracefulTcpStream<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.punched.stream.read(buf));
+        if n == 0 && !buf.is_empty() {
+            self.peer_write_closed = true;
+        }
+        Ok(n)
+    }
+}
+
+impl<'a> Write for GracefulTcpStream<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.punched.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.punched.stream.flush()
+    }
+}
+
+impl<'a> Drop for GracefulTcpStream<'a> {
+    fn drop(&mut self) {
+        let local_addr = self.punched.local_addr;
+        if let Some(ref events) = self.events {
+            events.send(Event::Closed { local_addr: local_addr });
+        }
+        if !self.closed {
+            if let Some(mc) = self.mc {
+                mapping_context::forget_mapped_port(mc, igd::PortMappingProtocol::TCP,
+                                                    local_addr.port());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::{Duration, Instant};
+
+    use event::Event;
+    use gateway as igd;
+    use mapped_tcp_socket::{PunchedTcpStream, TcpPunchTechnique};
+    use mapping_context::{self, MappingContext};
+    use socket_addr::SocketAddr;
+
+    fn stream_pair() -> (PunchedTcpStream, PunchedTcpStream) {
+        let listener = unwrap_result!(TcpListener::bind("127.0.0.1:0"));
+        let connected = unwrap_result!(TcpStream::connect(unwrap_result!(listener.local_addr())));
+        let (accepted, _) = unwrap_result!(listener.accept());
+        let punched = |stream: TcpStream, technique| {
+            PunchedTcpStream {
+                local_addr: SocketAddr(unwrap_result!(stream.local_addr())),
+                peer_addr: SocketAddr(unwrap_result!(stream.peer_addr())),
+                stream: stream,
+                technique: technique,
+                mptcp: false,
+            }
+        };
+        (punched(connected, TcpPunchTechnique::Connected),
+         punched(accepted, TcpPunchTechnique::Accepted))
+    }
+
+    #[test]
+    fn half_closed_stream_still_reads_until_the_peer_closes() {
+        let (punched_0, punched_1) = stream_pair();
+        let mut stream_0 = GracefulTcpStream::new(punched_0);
+        let mut stream_1 = GracefulTcpStream::new(punched_1);
+
+        unwrap_result!(stream_0.write_all(b"request"));
+        unwrap_result!(stream_0.shutdown_write());
+        let mut request = Vec::new();
+        let _ = unwrap_result!(stream_1.read_to_end(&mut request));
+        assert_eq!(request, b"request");
+        assert!(stream_1.peer_write_closed());
+
+        // The peer can still answer over its half of the connection.
+        unwrap_result!(stream_1.write_all(b"response"));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        unwrap_result!(stream_1.close(deadline));
+        let mut response = [0u8; 8];
+        unwrap_result!(stream_0.read_exact(&mut response));
+        assert_eq!(&response, b"response");
+        unwrap_result!(stream_0.close(deadline));
+    }
+
+    #[test]
+    fn dropped_stream_port_is_not_reused() {
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        mc.set_port_reuse(true);
+        let (punched_0, _punched_1) = stream_pair();
+        let port = punched_0.local_addr.port();
+        mapping_context::record_mapped_port(&mc, igd::PortMappingProtocol::TCP, port);
+
+        let events = mc.subscribe();
+        drop(GracefulTcpStream::new(punched_0).in_context(&mc));
+        assert_eq!(mapping_context::port_hint(&mc, igd::PortMappingProtocol::TCP), None);
+        match unwrap_result!(events.try_recv()) {
+            Event::Closed { local_addr } => assert_eq!(local_addr.port(), port),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+}
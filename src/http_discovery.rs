@@ -0,0 +1,269 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::str;
+use std::time::Instant;
+
+use error_code::{ErrorCategory, ErrorCode};
+
+/// Responses larger than this are rejected. An echo service only ever needs to send an address.
+const MAX_RESPONSE_SIZE: u64 = 4096;
+
+quick_error! {
+    /// Errors returned by `query_http_echo_server`.
+    #[derive(Debug)]
+    pub enum HttpDiscoveryError {
+        /// The url is not of the form `http://host[:port]/path`.
+        InvalidUrl { url: String } {
+            description("The url is not of the form http://host[:port]/path.")
+            display("The url \"{}\" is not of the form http://host[:port]/path.", url)
+        }
+        /// Only plain http is supported.
+        UnsupportedScheme { url: String } {
+            description("Only plain http echo servers are supported.")
+            display("Only plain http echo servers are supported, not \"{}\".", url)
+        }
+        /// Error connecting to the server.
+        Connect { err: io::Error } {
+            description("Error connecting to the http echo server.")
+            display("Error connecting to the http echo server: {}", err)
+            cause(err)
+        }
+        /// IO error talking to the server.
+        Io { err: io::Error } {
+            description("IO error talking to the http echo server.")
+            display("IO error talking to the http echo server: {}", err)
+            cause(err)
+        }
+        /// The deadline passed before the server responded.
+        TimedOut {
+            description("The http echo server did not respond before the deadline.")
+        }
+        /// The server responded with something other than `200 OK` and an ip address.
+        BadResponse { response: String } {
+            description("The http echo server sent an unexpected response.")
+            display("The http echo server sent an unexpected response: {:?}", response)
+        }
+    }
+}
+
+impl From<HttpDiscoveryError> for io::Error {
+    fn from(e: HttpDiscoveryError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            HttpDiscoveryError::InvalidUrl { .. } => io::ErrorKind::InvalidInput,
+            HttpDiscoveryError::UnsupportedScheme { .. } => io::ErrorKind::InvalidInput,
+            HttpDiscoveryError::Connect { err } => err.kind(),
+            HttpDiscoveryError::Io { err } => err.kind(),
+            HttpDiscoveryError::TimedOut => io::ErrorKind::TimedOut,
+            HttpDiscoveryError::BadResponse { .. } => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for HttpDiscoveryError {
+    fn code(&self) -> u32 {
+        match *self {
+            HttpDiscoveryError::InvalidUrl { .. } => 2001,
+            HttpDiscoveryError::UnsupportedScheme { .. } => 2002,
+            HttpDiscoveryError::Connect { .. } => 2003,
+            HttpDiscoveryError::Io { .. } => 2004,
+            HttpDiscoveryError::TimedOut => 2005,
+            HttpDiscoveryError::BadResponse { .. } => 2006,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            HttpDiscoveryError::InvalidUrl { .. } => ErrorCategory::Configuration,
+            HttpDiscoveryError::UnsupportedScheme { .. } => ErrorCategory::Unsupported,
+            HttpDiscoveryError::Connect { .. } => ErrorCategory::Network,
+            HttpDiscoveryError::Io { .. } => ErrorCategory::Network,
+            HttpDiscoveryError::TimedOut => ErrorCategory::Network,
+            HttpDiscoveryError::BadResponse { .. } => ErrorCategory::Protocol,
+        }
+    }
+}
+
+// Split `http://host[:port]/path` into `(host, port, path)`.
+fn parse_url(url: &str) -> Result<(&str, u16, &str), HttpDiscoveryError> {
+    let rest = if url.starts_with("http://") {
+        &url["http://".len()..]
+    } else if url.contains("://") {
+        return Err(HttpDiscoveryError::UnsupportedScheme { url: url.to_owned() });
+    } else {
+        return Err(HttpDiscoveryError::InvalidUrl { url: url.to_owned() });
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rfind(':') {
+        // Leave bracketed ipv6 literals without a port alone.
+        Some(i) if !authority[i..].contains(']') => {
+            match authority[i + 1..].parse() {
+                Ok(port) => (&authority[..i], port),
+                Err(_) => return Err(HttpDiscoveryError::InvalidUrl { url: url.to_owned() }),
+            }
+        },
+        _ => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(HttpDiscoveryError::InvalidUrl { url: url.to_owned() });
+    }
+    Ok((host, port, path))
+}
+
+fn parse_response(response: &[u8]) -> Result<IpAddr, HttpDiscoveryError> {
+    let bad_response = || {
+        HttpDiscoveryError::BadResponse {
+            response: String::from_utf8_lossy(response).into_owned(),
+        }
+    };
+    let text = match str::from_utf8(response) {
+        Ok(text) => text,
+        Err(_) => return Err(bad_response()),
+    };
+    let (head, body) = match text.find("\r\n\r\n") {
+        Some(i) => (&text[..i], &text[i + 4..]),
+        None => return Err(bad_response()),
+    };
+    let status_ok = head.lines().next().map_or(false, |status| {
+        status.starts_with("HTTP/") && status.split(' ').nth(1) == Some("200")
+    });
+    if !status_ok {
+        return Err(bad_response());
+    }
+    match body.trim().trim_matches(|c| c == '[' || c == ']').parse() {
+        Ok(ip) => Ok(ip),
+        Err(_) => Err(bad_response()),
+    }
+}
+
+/// Ask an http "what is my ip" service at `url` for our external ip address. The service must
+/// respond to a `GET` with the address as the whole body of the response. Only plain `http://`
+/// urls are supported.
+///
+/// This says nothing about which port a NAT would map a udp socket to, so it is only a fallback
+/// for networks where udp servers can't be reached.
+pub fn query_http_echo_server(url: &str, deadline: Instant) -> Result<IpAddr, HttpDiscoveryError> {
+    let (host, port, path) = try!(parse_url(url));
+    let host = host.trim_matches(|c| c == '[' || c == ']');
+    let addrs = match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => return Err(HttpDiscoveryError::Connect { err: e }),
+    };
+
+    let mut last_err = None;
+    let mut stream = None;
+    for addr in addrs {
+        if Instant::now() >= deadline {
+            return Err(HttpDiscoveryError::TimedOut);
+        }
+        match TcpStream::connect(addr) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            },
+            Err(e) => last_err = Some(e),
+        }
+    }
+    let mut stream = match (stream, last_err) {
+        (Some(stream), _) => stream,
+        (None, Some(e)) => return Err(HttpDiscoveryError::Connect { err: e }),
+        (None, None) => return Err(HttpDiscoveryError::Connect {
+            err: io::Error::new(io::ErrorKind::NotFound, "host has no addresses"),
+        }),
+    };
+
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(HttpDiscoveryError::TimedOut);
+    }
+    let timeout = Some(deadline - now);
+    match stream.set_read_timeout(timeout).and_then(|()| stream.set_write_timeout(timeout)) {
+        Ok(()) => (),
+        Err(e) => return Err(HttpDiscoveryError::Io { err: e }),
+    };
+
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: nat_traversal\r\n\
+                           Connection: close\r\n\r\n", path, host);
+    match stream.write_all(request.as_bytes()) {
+        Ok(()) => (),
+        Err(e) => return Err(HttpDiscoveryError::Io { err: e }),
+    };
+    let mut response = Vec::new();
+    match stream.take(MAX_RESPONSE_SIZE).read_to_end(&mut response) {
+        Ok(_) => (),
+        Err(e) => {
+            return match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Err(HttpDiscoveryError::TimedOut),
+                _ => Err(HttpDiscoveryError::Io { err: e }),
+            };
+        },
+    };
+    parse_response(&response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{parse_response, parse_url};
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::{Instant, Duration};
+
+    #[test]
+    fn urls_and_responses_are_parsed() {
+        assert_eq!(unwrap_result!(parse_url("http://example.com/ip")), ("example.com", 80, "/ip"));
+        assert_eq!(unwrap_result!(parse_url("http://[::1]:8080")), ("[::1]", 8080, "/"));
+        assert!(parse_url("https://example.com/").is_err());
+        assert!(parse_url("example.com").is_err());
+
+        let ip = unwrap_result!(parse_response(b"HTTP/1.1 200 OK\r\nServer: x\r\n\r\n203.0.113.9\n"));
+        assert_eq!(ip, unwrap_result!("203.0.113.9".parse()));
+        assert!(parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n203.0.113.9").is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n\r\n<html>").is_err());
+    }
+
+    #[test]
+    fn query_local_echo_server() {
+        let listener = unwrap_result!(TcpListener::bind("127.0.0.1:0"));
+        let port = unwrap_result!(listener.local_addr()).port();
+        let jh = thread!("query_local_echo_server", move || {
+            let (mut stream, addr) = unwrap_result!(listener.accept());
+            let mut request = [0u8; 1024];
+            let _ = unwrap_result!(stream.read(&mut request));
+            let response = format!("HTTP/1.0 200 OK\r\n\r\n{}", addr.ip());
+            unwrap_result!(stream.write_all(response.as_bytes()));
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let url = format!("http://127.0.0.1:{}/", port);
+        let ip = unwrap_result!(query_http_echo_server(&url, deadline));
+        assert_eq!(ip, unwrap_result!("127.0.0.1".parse()));
+        unwrap_result!(jh.join());
+    }
+}
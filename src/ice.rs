@@ -0,0 +1,497 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::fmt;
+use std::io;
+use std::net;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use rand;
+use rand::Rng;
+use socket_addr::SocketAddr;
+
+use error_code::{ErrorCategory, ErrorCode};
+use mapped_socket_addr::MappedSocketAddr;
+use punched_udp_socket::PunchedUdpSocket;
+use socket_utils;
+use stun;
+use stun::{StunAttribute, StunClass, StunMessage, METHOD_BINDING};
+use subnetting::SubnetList;
+use transport::DatagramTransport;
+
+/// How often a connectivity check is sent (RFC 5245's `Ta`).
+const CHECK_INTERVAL_MS: u64 = 50;
+const ICE_CHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const SOFTWARE: &'static str = "nat_traversal";
+
+quick_error! {
+    /// Errors returned by the ICE interop functions.
+    #[derive(Debug)]
+    pub enum IceError {
+        /// The remote end didn't give us any candidates to check.
+        NoCandidates {
+            description("No remote candidates to check")
+        }
+        /// A candidate line couldn't be parsed.
+        InvalidCandidate { line: String } {
+            description("Invalid ICE candidate")
+            display("Invalid ICE candidate: {:?}", line)
+        }
+        /// IO error sending or receiving connectivity checks.
+        Io { err: io::Error } {
+            description("IO error performing ICE connectivity checks")
+            display("IO error performing ICE connectivity checks: {}", err)
+            cause(err)
+        }
+        /// The remote end rejected our connectivity checks.
+        Rejected { code: u16, reason: String } {
+            description("The remote ICE agent rejected our connectivity check")
+            display("The remote ICE agent rejected our connectivity check: {} {}", code, reason)
+        }
+        /// None of the candidates answered before the deadline.
+        TimedOut {
+            description("Timed out waiting for a successful connectivity check")
+        }
+    }
+}
+
+impl From<IceError> for io::Error {
+    fn from(e: IceError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            IceError::NoCandidates |
+            IceError::InvalidCandidate { .. } => io::ErrorKind::InvalidInput,
+            IceError::Io { err } => err.kind(),
+            IceError::Rejected { .. } => io::ErrorKind::ConnectionRefused,
+            IceError::TimedOut => io::ErrorKind::TimedOut,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for IceError {
+    fn code(&self) -> u32 {
+        match *self {
+            IceError::NoCandidates => 2601,
+            IceError::InvalidCandidate { .. } => 2602,
+            IceError::Io { .. } => 2603,
+            IceError::Rejected { .. } => 2604,
+            IceError::TimedOut => 2605,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            IceError::NoCandidates => ErrorCategory::Configuration,
+            IceError::InvalidCandidate { .. } |
+            IceError::Rejected { .. } => ErrorCategory::Protocol,
+            IceError::Io { .. } |
+            IceError::TimedOut => ErrorCategory::Network,
+        }
+    }
+}
+
+/// The type of an ICE candidate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IceCandidateType {
+    /// An address of a local interface.
+    Host,
+    /// An address a server saw our traffic come from.
+    ServerReflexive,
+    /// An address learnt from a connectivity check.
+    PeerReflexive,
+    /// An address on a relay.
+    Relayed,
+}
+
+impl IceCandidateType {
+    /// The RFC 5245 recommended type preference.
+    pub fn preference(&self) -> u32 {
+        match *self {
+            IceCandidateType::Host => 126,
+            IceCandidateType::PeerReflexive => 110,
+            IceCandidateType::ServerReflexive => 100,
+            IceCandidateType::Relayed => 0,
+        }
+    }
+
+    fn sdp_name(&self) -> &'static str {
+        match *self {
+            IceCandidateType::Host => "host",
+            IceCandidateType::ServerReflexive => "srflx",
+            IceCandidateType::PeerReflexive => "prflx",
+            IceCandidateType::Relayed => "relay",
+        }
+    }
+}
+
+/// Compute an ICE candidate priority as recommended by RFC 5245.
+pub fn ice_priority(typ: IceCandidateType, local_preference: u16, component: u8) -> u32 {
+    (typ.preference() << 24) + ((local_preference as u32) << 8) + (256 - component as u32)
+}
+
+/// An ICE candidate. Formats as, and parses from, the SDP `candidate` attribute, eg.
+/// `candidate:1 1 udp 2130706431 192.168.1.2 5000 typ host`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IceCandidate {
+    /// Identifies candidates with the same type and base.
+    pub foundation: String,
+    /// The component id. Always 1 for our purposes.
+    pub component: u8,
+    /// The candidate's priority.
+    pub priority: u32,
+    /// The transport address.
+    pub addr: SocketAddr,
+    /// The candidate type.
+    pub typ: IceCandidateType,
+}
+
+impl fmt::Display for IceCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "candidate:{} {} udp {} {} {} typ {}",
+               self.foundation,
+               self.component,
+               self.priority,
+               self.addr.ip(),
+               self.addr.port(),
+               self.typ.sdp_name())
+    }
+}
+
+impl FromStr for IceCandidate {
+    type Err = IceError;
+
+    fn from_str(s: &str) -> Result<IceCandidate, IceError> {
+        let invalid = || IceError::InvalidCandidate { line: s.to_owned() };
+        let line = s.trim();
+        let line = if line.starts_with("a=") { &line[2..] } else { line };
+        if !line.starts_with("candidate:") {
+            return Err(invalid());
+        }
+        let fields: Vec<&str> = line["candidate:".len()..].split_whitespace().collect();
+        // Anything after the type (raddr, rport, generation, ...) is ignored.
+        if fields.len() < 8 || fields[2].to_lowercase() != "udp" || fields[6] != "typ" {
+            return Err(invalid());
+        }
+        let component = match fields[1].parse() {
+            Ok(component) => component,
+            Err(_) => return Err(invalid()),
+        };
+        let priority = match fields[3].parse() {
+            Ok(priority) => priority,
+            Err(_) => return Err(invalid()),
+        };
+        let ip: IpAddr = match fields[4].parse() {
+            Ok(ip) => ip,
+            Err(_) => return Err(invalid()),
+        };
+        let port = match fields[5].parse() {
+            Ok(port) => port,
+            Err(_) => return Err(invalid()),
+        };
+        let typ = match fields[7] {
+            "host" => IceCandidateType::Host,
+            "srflx" => IceCandidateType::ServerReflexive,
+            "prflx" => IceCandidateType::PeerReflexive,
+            "relay" => IceCandidateType::Relayed,
+            _ => return Err(invalid()),
+        };
+        Ok(IceCandidate {
+            foundation: fields[0].to_owned(),
+            component: component,
+            priority: priority,
+            addr: SocketAddr(net::SocketAddr::new(ip, port)),
+            typ: typ,
+        })
+    }
+}
+
+/// Describe our endpoints as ICE candidates for component 1. Endpoints in private or loopback
+/// ranges become host candidates and everything else becomes server reflexive. Earlier endpoints
+/// get a higher local preference so the order of `endpoints` is preserved within each type.
+pub fn ice_candidates(endpoints: &[MappedSocketAddr]) -> Vec<IceCandidate> {
+    let private = SubnetList::private();
+    endpoints.iter()
+             .enumerate()
+             .map(|(i, endpoint)| {
+                 let ip = endpoint.addr.ip();
+                 let typ = if private.contains(&ip) || socket_utils::is_loopback(&ip) {
+                     IceCandidateType::Host
+                 } else {
+                     IceCandidateType::ServerReflexive
+                 };
+                 let local_preference = 0xffff - ::std::cmp::min(i, 0xffff) as u16;
+                 let foundation = format!("{} {}", typ.sdp_name(), ip);
+                 IceCandidate {
+                     foundation: format!("{:x}", stun::crc32(foundation.as_bytes())),
+                     component: 1,
+                     priority: ice_priority(typ, local_preference, 1),
+                     addr: endpoint.addr,
+                     typ: typ,
+                 }
+             })
+             .collect()
+}
+
+/// An ICE username fragment and password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IceCredentials {
+    /// The username fragment.
+    pub ufrag: String,
+    /// The password, used as the MESSAGE-INTEGRITY key.
+    pub pwd: String,
+}
+
+impl IceCredentials {
+    /// Generate random credentials of the lengths RFC 5245 asks for.
+    pub fn generate() -> IceCredentials {
+        let mut rng = rand::thread_rng();
+        let mut ice_string = |len| {
+            (0..len)
+                .map(|_| ICE_CHARS[rng.gen_range(0, ICE_CHARS.len())] as char)
+                .collect::<String>()
+        };
+        let ufrag = ice_string(8);
+        let pwd = ice_string(24);
+        IceCredentials {
+            ufrag: ufrag,
+            pwd: pwd,
+        }
+    }
+}
+
+/// Connect to an ICE-lite endpoint. We take the controlling role and send STUN Binding requests
+/// with USE-CANDIDATE (aggressive nomination) to each of `remote_candidates` in priority order
+/// until one of them sends back an authenticated success response. That candidate becomes the
+/// peer address of the returned socket.
+///
+/// Binding requests from the remote end are answered too, so this also works against a full ICE
+/// agent which happens to check the same pair.
+pub fn ice_lite_connect<S>(socket: S,
+                           local: &IceCredentials,
+                           remote: &IceCredentials,
+                           remote_candidates: &[IceCandidate],
+                           deadline: Instant)
+                           -> Result<PunchedUdpSocket<S>, IceError>
+    where S: DatagramTransport
+{
+    if remote_candidates.is_empty() {
+        return Err(IceError::NoCandidates);
+    }
+    let mut candidates = remote_candidates.to_vec();
+    candidates.sort_by(|a, b| b.priority.cmp(&a.priority));
+    let transaction_ids: Vec<[u8; 12]> = candidates.iter().map(|_| rand::random()).collect();
+
+    let username = format!("{}:{}", remote.ufrag, local.ufrag);
+    let tie_breaker = rand::random();
+    let priority = ice_priority(IceCandidateType::PeerReflexive, 0xffff, 1);
+
+    let mut next_check = 0;
+    let mut next_check_time = Instant::now();
+    let mut recv_buf = [0u8; 1500];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(IceError::TimedOut);
+        }
+        if now >= next_check_time {
+            let candidate = &candidates[next_check];
+            let mut request = StunMessage::new(StunClass::Request,
+                                               METHOD_BINDING,
+                                               transaction_ids[next_check]);
+            request.attributes.push(StunAttribute::Username(username.clone()));
+            request.attributes.push(StunAttribute::Priority(priority));
+            request.attributes.push(StunAttribute::IceControlling(tie_breaker));
+            request.attributes.push(StunAttribute::UseCandidate);
+            let encoded = request.encode(Some(remote.pwd.as_bytes()), true);
+            // A candidate we can't reach (eg. of the wrong address family) shouldn't stop us
+            // checking the others.
+            let _ = socket.send_to(&encoded, &candidate.addr);
+            next_check = (next_check + 1) % candidates.len();
+            next_check_time = now + Duration::from_millis(CHECK_INTERVAL_MS);
+        }
+
+        let recv_deadline = ::std::cmp::min(next_check_time, deadline);
+        let (len, from, local_ip) = match socket.recv_until_with_local_ip(&mut recv_buf,
+                                                                          recv_deadline) {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(err) => return Err(IceError::Io { err: err }),
+        };
+        let decoded = match StunMessage::decode(&recv_buf[..len]) {
+            Ok(decoded) => decoded,
+            Err(_) => continue,
+        };
+        let message = &decoded.message;
+        if message.method != METHOD_BINDING {
+            continue;
+        }
+        match message.class {
+            StunClass::Request => {
+                let expected_username = format!("{}:{}", local.ufrag, remote.ufrag);
+                let username_matches = message.find(|attr| match *attr {
+                    StunAttribute::Username(ref username) => Some(*username == expected_username),
+                    _ => None,
+                });
+                if username_matches != Some(true) ||
+                   !decoded.verify_integrity(local.pwd.as_bytes()) {
+                    continue;
+                }
+                let mut response = StunMessage::new(StunClass::SuccessResponse,
+                                                    METHOD_BINDING,
+                                                    message.transaction_id);
+                response.attributes.push(StunAttribute::XorMappedAddress(*from));
+                response.attributes.push(StunAttribute::Software(SOFTWARE.to_owned()));
+                let encoded = response.encode(Some(local.pwd.as_bytes()), true);
+                let _ = socket.send_to(&encoded, &from);
+            },
+            StunClass::SuccessResponse |
+            StunClass::ErrorResponse => {
+                let i = match transaction_ids.iter().position(|id| *id == message.transaction_id) {
+                    Some(i) => i,
+                    None => continue,
+                };
+                // ICE requires the response to come from where the request was sent and to be
+                // authenticated with the remote password.
+                if candidates[i].addr != from || !decoded.verify_integrity(remote.pwd.as_bytes()) {
+                    continue;
+                }
+                if message.class == StunClass::ErrorResponse {
+                    let rejection = message.find(|attr| match *attr {
+                        StunAttribute::ErrorCode { code, ref reason } => {
+                            Some((code, reason.clone()))
+                        },
+                        _ => None,
+                    });
+                    let (code, reason) = rejection.unwrap_or((0, String::new()));
+                    return Err(IceError::Rejected {
+                        code: code,
+                        reason: reason,
+                    });
+                }
+                return Ok(PunchedUdpSocket {
+                    socket: socket,
+                    peer_addr: from,
+                    local_ip: local_ip,
+                });
+            },
+            StunClass::Indication => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net;
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use socket_addr::SocketAddr;
+
+    use mapped_socket_addr::MappedSocketAddr;
+    use stun::{StunAttribute, StunClass, StunMessage, METHOD_BINDING};
+
+    #[test]
+    fn candidates_format_as_sdp() {
+        let endpoints = vec![MappedSocketAddr {
+                                 addr: SocketAddr(unwrap_result!("192.168.1.2:5000".parse())),
+                                 nat_restricted: false,
+                                 port_unknown: false,
+                                 unverified: false,
+                             },
+                             MappedSocketAddr {
+                                 addr: SocketAddr(unwrap_result!("203.0.113.7:6000".parse())),
+                                 nat_restricted: true,
+                                 port_unknown: false,
+                                 unverified: false,
+                             }];
+        let candidates = ice_candidates(&endpoints);
+        assert_eq!(candidates[0].typ, IceCandidateType::Host);
+        assert_eq!(candidates[0].priority, 2130706431);
+        assert_eq!(candidates[1].typ, IceCandidateType::ServerReflexive);
+        assert!(candidates[1].priority < candidates[0].priority);
+
+        for candidate in &candidates {
+            let line = format!("{}", candidate);
+            assert_eq!(unwrap_result!(line.parse::<IceCandidate>()), *candidate);
+        }
+        let parsed: IceCandidate = unwrap_result!(
+            "a=candidate:842163049 1 UDP 1677729535 198.51.100.3 61665 typ srflx raddr 0.0.0.0 \
+             rport 0 generation 0".parse());
+        assert_eq!(parsed.typ, IceCandidateType::ServerReflexive);
+        assert_eq!(parsed.priority, 1677729535);
+        assert!("candidate:1 1 tcp 1 192.0.2.1 9 typ host".parse::<IceCandidate>().is_err());
+    }
+
+    #[test]
+    fn connects_to_ice_lite_endpoint() {
+        let local = IceCredentials::generate();
+        let remote = IceCredentials::generate();
+        assert_eq!(local.pwd.len(), 24);
+
+        let lite = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let lite_addr = unwrap_result!(lite.local_addr());
+        let expected_username = format!("{}:{}", remote.ufrag, local.ufrag);
+        let remote_pwd = remote.pwd.clone();
+        let _ = thread!("fake_ice_lite", move || {
+            let mut buf = [0u8; 1500];
+            let (len, from) = unwrap_result!(lite.recv_from(&mut buf));
+            let decoded = unwrap_result!(StunMessage::decode(&buf[..len]));
+            assert!(decoded.verify_integrity(remote_pwd.as_bytes()));
+            let request = decoded.message;
+            assert_eq!(request.class, StunClass::Request);
+            assert!(request.attributes.contains(&StunAttribute::UseCandidate));
+            assert!(request.attributes.contains(&StunAttribute::Username(expected_username)));
+
+            let mut response = StunMessage::new(StunClass::SuccessResponse,
+                                                METHOD_BINDING,
+                                                request.transaction_id);
+            response.attributes.push(StunAttribute::XorMappedAddress(from));
+            let encoded = response.encode(Some(remote_pwd.as_bytes()), true);
+            let _ = unwrap_result!(lite.send_to(&encoded, from));
+        });
+
+        // An unreachable, lower priority candidate shouldn't get in the way.
+        let candidates = vec![IceCandidate {
+                                  foundation: "1".to_owned(),
+                                  component: 1,
+                                  priority: 2130706431,
+                                  addr: SocketAddr(lite_addr),
+                                  typ: IceCandidateType::Host,
+                              },
+                              IceCandidate {
+                                  foundation: "2".to_owned(),
+                                  component: 1,
+                                  priority: 1694498815,
+                                  addr: SocketAddr(net::SocketAddr::new(lite_addr.ip(), 9)),
+                                  typ: IceCandidateType::ServerReflexive,
+                              }];
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let punched = unwrap_result!(ice_lite_connect(socket, &local, &remote, &candidates,
+                                                      deadline));
+        assert_eq!(*punched.peer_addr, lite_addr);
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+// Watching for the ICMP errors that routers and hosts send back about our hole punch packets,
+// which tell apart failures that otherwise all look like a timeout. Reading ICMP needs a raw
+// socket and so root or CAP_NET_RAW. Without the `icmp` feature, or on platforms other than unix,
+// `IcmpObserver::new` always fails. Only ipv4 is observed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{self, IpAddr, SocketAddrV4};
+#[cfg(any(all(feature = "icmp", target_family = "unix"), test))]
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(any(all(feature = "icmp", target_family = "unix"), test))]
+use byteorder::{BigEndian, ByteOrder};
+use maidsafe_utilities::thread::RaiiThreadJoiner;
+
+use privacy::Redacted;
+
+/// The most failures remembered at once. The oldest aren't tracked, so all are forgotten when
+/// this is reached.
+#[cfg(all(feature = "icmp", target_family = "unix"))]
+const MAX_FAILURES: usize = 1024;
+
+/// What an ICMP error said about the packets sent to an endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpFailure {
+    /// A firewall rejected the packets (destination unreachable, administratively prohibited).
+    Filtered,
+    /// There's no route to the endpoint's network or host.
+    NoRoute,
+    /// The endpoint's host rejected the packets because nothing is listening on the port. When
+    /// the endpoint is a NAT, it has no mapping for the port.
+    PortUnreachable,
+    /// The packets ran out of hops at `router`. Expected when sending with a low ttl.
+    TtlExceeded {
+        /// The router that dropped the packets.
+        router: IpAddr,
+    },
+}
+
+impl fmt::Display for IcmpFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IcmpFailure::Filtered => write!(f, "a firewall rejected the packets"),
+            IcmpFailure::NoRoute => write!(f, "there is no route to the endpoint"),
+            IcmpFailure::PortUnreachable => {
+                write!(f, "nothing is listening on the port, or the NAT has no mapping for it")
+            },
+            IcmpFailure::TtlExceeded { router } => {
+                write!(f, "the packets expired in transit at {}", Redacted(router))
+            },
+        }
+    }
+}
+
+/// Watches from a background thread for ICMP errors about the udp packets this host sends, until
+/// it's dropped. Pass it to `PunchedUdpSocket::punch_hole_observed` to have peer endpoints that
+/// time out reported with the reason an ICMP error gave.
+pub struct IcmpObserver {
+    failures: Arc<Mutex<HashMap<(u16, SocketAddrV4), IcmpFailure>>>,
+    stop_flag: Arc<AtomicBool>,
+    _raii_joiner: RaiiThreadJoiner,
+}
+
+impl fmt::Debug for IcmpObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let failures = unwrap_result!(self.failures.lock()).len();
+        f.debug_struct("IcmpObserver").field("failures", &failures).finish()
+    }
+}
+
+impl IcmpObserver {
+    /// Start observing. Fails with `PermissionDenied` without the privileges to open a raw socket.
+    #[cfg(all(feature = "icmp", target_family = "unix"))]
+    pub fn new() -> io::Result<IcmpObserver> {
+        use std::time::Duration;
+
+        let socket = try!(raw::RawIcmpSocket::new(Duration::from_secs(1)));
+        let failures = Arc::new(Mutex::new(HashMap::new()));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let cloned_failures = failures.clone();
+        let cloned_stop_flag = stop_flag.clone();
+        let raii_joiner = RaiiThreadJoiner::new(thread!("IcmpObserver", move || {
+            let mut buf = [0u8; 1500];
+            while !cloned_stop_flag.load(Ordering::SeqCst) {
+                let len = match socket.recv(&mut buf) {
+                    Ok(Some(len)) => len,
+                    Ok(None) => continue,
+                    Err(_) => break,
+                };
+                if let Some((local_port, dest, failure)) = parse_icmpv4(&buf[..len]) {
+                    let mut failures = unwrap_result!(cloned_failures.lock());
+                    if failures.len() >= MAX_FAILURES {
+                        failures.clear();
+                    }
+                    let _ = failures.insert((local_port, dest), failure);
+                }
+            }
+        }));
+        Ok(IcmpObserver {
+            failures: failures,
+            stop_flag: stop_flag,
+            _raii_joiner: raii_joiner,
+        })
+    }
+
+    /// Start observing. Always fails, as ICMP observation was disabled at compile time or isn't
+    /// supported on this platform.
+    #[cfg(not(all(feature = "icmp", target_family = "unix")))]
+    pub fn new() -> io::Result<IcmpObserver> {
+        Err(io::Error::new(io::ErrorKind::Other,
+                           "ICMP observation was disabled at compile time or is unsupported"))
+    }
+
+    /// The latest ICMP error seen about packets sent from local port `local_port` to `dest`.
+    pub fn failure(&self, local_port: u16, dest: &net::SocketAddr) -> Option<IcmpFailure> {
+        let dest = match *dest {
+            net::SocketAddr::V4(dest) => dest,
+            net::SocketAddr::V6(..) => return None,
+        };
+        unwrap_result!(self.failures.lock()).get(&(local_port, dest)).cloned()
+    }
+}
+
+impl Drop for IcmpObserver {
+    fn drop(&mut self) {
+        self.stop_flag.store(true, Ordering::SeqCst);
+    }
+}
+
+// Parse an ICMP packet, including its ip header as raw sockets deliver it. Errors quote the ip
+// header and first 8 bytes of the packet that caused them, which for udp covers the ports.
+// Returns the local port and destination of the quoted packet and what went wrong with it.
+#[cfg(any(all(feature = "icmp", target_family = "unix"), test))]
+fn parse_icmpv4(packet: &[u8]) -> Option<(u16, SocketAddrV4, IcmpFailure)> {
+    const IPPROTO_ICMP: u8 = 1;
+    const IPPROTO_UDP: u8 = 17;
+    const DEST_UNREACHABLE: u8 = 3;
+    const TIME_EXCEEDED: u8 = 11;
+
+    if packet.len() < 20 || packet[9] != IPPROTO_ICMP {
+        return None;
+    }
+    let router = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    // The ICMP header, then at least the quoted packet's ip header.
+    if packet.len() < header_len + 8 + 20 {
+        return None;
+    }
+    let icmp = &packet[header_len..];
+    let failure = match (icmp[0], icmp[1]) {
+        (TIME_EXCEEDED, 0) => IcmpFailure::TtlExceeded { router: IpAddr::V4(router) },
+        (DEST_UNREACHABLE, 3) => IcmpFailure::PortUnreachable,
+        (DEST_UNREACHABLE, 0) | (DEST_UNREACHABLE, 1) |
+        (DEST_UNREACHABLE, 6) | (DEST_UNREACHABLE, 7) => IcmpFailure::NoRoute,
+        (DEST_UNREACHABLE, 9) | (DEST_UNREACHABLE, 10) |
+        (DEST_UNREACHABLE, 13) => IcmpFailure::Filtered,
+        _ => return None,
+    };
+    let quoted = &icmp[8..];
+    let quoted_header_len = (quoted[0] & 0x0f) as usize * 4;
+    if quoted[9] != IPPROTO_UDP || quoted.len() < quoted_header_len + 4 {
+        return None;
+    }
+    let dest_ip = Ipv4Addr::new(quoted[16], quoted[17], quoted[18], quoted[19]);
+    let udp = &quoted[quoted_header_len..];
+    let local_port = BigEndian::read_u16(&udp[0..]);
+    let dest_port = BigEndian::read_u16(&udp[2..]);
+    Some((local_port, SocketAddrV4::new(dest_ip, dest_port), failure))
+}
+
+#[cfg(all(feature = "icmp", target_family = "unix"))]
+#[allow(unsafe_code)]
+mod raw {
+    use std::io;
+    use std::mem;
+    use std::time::Duration;
+
+    use libc;
+
+    /// A raw socket receiving all the ICMP packets sent to this host.
+    pub struct RawIcmpSocket {
+        fd: libc::c_int,
+    }
+
+    impl RawIcmpSocket {
+        /// Open the socket. `recv` gives up after `read_timeout`.
+        pub fn new(read_timeout: Duration) -> io::Result<RawIcmpSocket> {
+            let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_ICMP) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = RawIcmpSocket { fd: fd };
+            let timeout = libc::timeval {
+                tv_sec: read_timeout.as_secs() as libc::time_t,
+                tv_usec: (read_timeout.subsec_nanos() / 1000) as libc::suseconds_t,
+            };
+            let timeout_ptr: *const libc::timeval = &timeout;
+            let ret = unsafe {
+                libc::setsockopt(fd,
+                                 libc::SOL_SOCKET,
+                                 libc::SO_RCVTIMEO,
+                                 timeout_ptr as *const libc::c_void,
+                                 mem::size_of::<libc::timeval>() as libc::socklen_t)
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(socket)
+        }
+
+        /// Read a packet into `buf`. Returns `None` if none arrived before the read timeout.
+        pub fn recv(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+            let ret = unsafe {
+                libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+            };
+            if ret >= 0 {
+                return Ok(Some(ret as usize));
+            }
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock |
+                io::ErrorKind::TimedOut |
+                io::ErrorKind::Interrupted => Ok(None),
+                _ => Err(err),
+            }
+        }
+    }
+
+    impl Drop for RawIcmpSocket {
+        fn drop(&mut self) {
+            let _ = unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+
+    // An ICMP error from `router` quoting a udp packet from port 4000 to 198.51.100.7:5000.
+    fn icmp_error(router: [u8; 4], icmp_type: u8, code: u8) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 56, 0, 0, 0, 0, 64, 1, 0, 0];
+        packet.extend(&router);
+        packet.extend(&[192, 168, 1, 2]);
+        packet.extend(&[icmp_type, code, 0, 0, 0, 0, 0, 0]);
+        packet.extend(&[0x45, 0, 0, 28, 0, 0, 0, 0, 1, 17, 0, 0, 192, 168, 1, 2, 198, 51, 100, 7]);
+        packet.extend(&[0x0f, 0xa0, 0x13, 0x88, 0, 8, 0, 0]);
+        packet
+    }
+
+    #[test]
+    fn icmp_errors_are_classified() {
+        let dest = SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 7), 5000);
+        assert_eq!(parse_icmpv4(&icmp_error([198, 51, 100, 7], 3, 3)),
+                   Some((4000, dest, IcmpFailure::PortUnreachable)));
+        assert_eq!(parse_icmpv4(&icmp_error([10, 0, 0, 1], 3, 13)),
+                   Some((4000, dest, IcmpFailure::Filtered)));
+        assert_eq!(parse_icmpv4(&icmp_error([10, 0, 0, 1], 3, 1)),
+                   Some((4000, dest, IcmpFailure::NoRoute)));
+        let router = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(parse_icmpv4(&icmp_error([10, 0, 0, 1], 11, 0)),
+                   Some((4000, dest, IcmpFailure::TtlExceeded { router: router })));
+        // Echo replies aren't errors.
+        assert_eq!(parse_icmpv4(&icmp_error([10, 0, 0, 1], 0, 0)), None);
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::io;
+use std::fmt;
+
+use sodiumoxide;
+use sodiumoxide::crypto::sign;
+
+use error_code::{ErrorCategory, ErrorCode};
+
+/// A long-term Ed25519 signing key pair identifying a peer across sessions. Peers sign their
+/// rendezvous info with it so that whoever relays the info, eg. a signalling server, can't swap
+/// in someone else's endpoints or keys. The secret key is zeroed when this is dropped.
+#[derive(Clone)]
+pub struct Identity {
+    public: sign::PublicKey,
+    secret: sign::SecretKey,
+}
+
+// The secret key is deliberately left out so that it can't end up in logs.
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Identity").field("public", &self.public.0).finish()
+    }
+}
+
+impl Identity {
+    /// Generate a new random identity.
+    pub fn generate() -> Identity {
+        let _ = sodiumoxide::init();
+        let (public, secret) = sign::gen_keypair();
+        Identity {
+            public: public,
+            secret: secret,
+        }
+    }
+
+    /// The public key peers use to recognise this identity.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public.0
+    }
+}
+
+quick_error! {
+    /// Errors returned when checking the identity that signed a `PubRendezvousInfo`.
+    #[derive(Debug)]
+    pub enum IdentityError {
+        /// The rendezvous info isn't signed.
+        Unsigned {
+            description("The rendezvous info isn't signed.")
+        }
+        /// The rendezvous info was signed by a different identity than the one expected.
+        WrongIdentity {
+            description("The rendezvous info was signed by a different identity than the one \
+                         expected.")
+        }
+        /// The signature over the rendezvous info is invalid. The info has been tampered with.
+        BadSignature {
+            description("The signature over the rendezvous info is invalid. The info has been \
+                         tampered with.")
+        }
+    }
+}
+
+impl From<IdentityError> for io::Error {
+    fn from(e: IdentityError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            IdentityError::Unsigned => io::ErrorKind::InvalidInput,
+            IdentityError::WrongIdentity => io::ErrorKind::PermissionDenied,
+            IdentityError::BadSignature => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for IdentityError {
+    fn code(&self) -> u32 {
+        match *self {
+            IdentityError::Unsigned => 3101,
+            IdentityError::WrongIdentity => 3102,
+            IdentityError::BadSignature => 3103,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            IdentityError::Unsigned => ErrorCategory::Configuration,
+            IdentityError::WrongIdentity => ErrorCategory::Protocol,
+            IdentityError::BadSignature => ErrorCategory::Protocol,
+        }
+    }
+}
+
+/// Sign `data` with `identity`.
+pub fn sign(identity: &Identity, data: &[u8]) -> Vec<u8> {
+    let sign::Signature(signature) = sign::sign_detached(data, &identity.secret);
+    signature.to_vec()
+}
+
+/// Check that `signature` is `public_key`'s signature over `data`.
+pub fn verify(public_key: &[u8; 32], data: &[u8], signature: &[u8]) -> Result<(), IdentityError> {
+    let signature = match sign::Signature::from_slice(signature) {
+        Some(signature) => signature,
+        None => return Err(IdentityError::BadSignature),
+    };
+    if sign::verify_detached(&signature, data, &sign::PublicKey(*public_key)) {
+        Ok(())
+    } else {
+        Err(IdentityError::BadSignature)
+    }
+}
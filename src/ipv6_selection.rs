@@ -0,0 +1,203 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+#[cfg(any(target_os = "linux", test))]
+use std::str::FromStr;
+
+use mapping_context::InterfaceV6;
+use socket_utils;
+
+/// Which global ipv6 addresses to bind to and advertise when a host has both stable addresses and
+/// temporary (RFC 4941 privacy) ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub enum Ipv6AddrPreference {
+    /// Use stable addresses, falling back to temporary ones only when there are none. Temporary
+    /// addresses are deprecated after a day or so, breaking long-lived connections made to them.
+    Stable,
+    /// Use temporary addresses, falling back to stable ones only when there are none, so that
+    /// peers can't link sessions through the stable address. Suits short-lived connections.
+    Temporary,
+}
+
+impl Default for Ipv6AddrPreference {
+    fn default() -> Ipv6AddrPreference {
+        Ipv6AddrPreference::Stable
+    }
+}
+
+/// What the OS knows about an address beyond the address itself.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct AddrState {
+    /// A temporary address, see RFC 4941.
+    pub temporary: bool,
+    /// The address's preferred lifetime has passed. It keeps working for existing connections
+    /// until its valid lifetime passes too, but shouldn't be used for new ones.
+    pub deprecated: bool,
+}
+
+/// The state of the host's ipv6 addresses, where the OS exposes it. Addresses missing from the
+/// map are treated as stable and preferred.
+#[cfg(target_os = "linux")]
+pub fn addr_states() -> HashMap<Ipv6Addr, AddrState> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut table = String::new();
+    match File::open("/proc/net/if_inet6").and_then(|mut f| f.read_to_string(&mut table)) {
+        Ok(..) => parse_if_inet6(&table),
+        Err(..) => HashMap::new(),
+    }
+}
+
+/// The state of the host's ipv6 addresses, where the OS exposes it. Addresses missing from the
+/// map are treated as stable and preferred.
+#[cfg(not(target_os = "linux"))]
+pub fn addr_states() -> HashMap<Ipv6Addr, AddrState> {
+    HashMap::new()
+}
+
+// Lines of /proc/net/if_inet6 are "address ifindex prefixlen scope flags name", all but the name
+// in hex and the address without colons. The flags are the low byte of the IFA_F_ flags.
+#[cfg(any(target_os = "linux", test))]
+fn parse_if_inet6(table: &str) -> HashMap<Ipv6Addr, AddrState> {
+    const IFA_F_TEMPORARY: u8 = 0x01;
+    const IFA_F_DEPRECATED: u8 = 0x20;
+
+    let mut states = HashMap::new();
+    for line in table.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 5 || fields[0].len() != 32 {
+            continue;
+        }
+        let groups: Vec<&str> = (0..8).map(|i| &fields[0][4 * i..4 * i + 4]).collect();
+        let addr = match Ipv6Addr::from_str(&groups.join(":")) {
+            Ok(addr) => addr,
+            Err(..) => continue,
+        };
+        let flags = match u8::from_str_radix(fields[4], 16) {
+            Ok(flags) => flags,
+            Err(..) => continue,
+        };
+        let _ = states.insert(addr, AddrState {
+            temporary: flags & IFA_F_TEMPORARY != 0,
+            deprecated: flags & IFA_F_DEPRECATED != 0,
+        });
+    }
+    states
+}
+
+// Roughly the RFC 6724 precedence of an address's kind, without knowing the destination.
+fn scope_rank(addr: &Ipv6Addr) -> u8 {
+    let first = addr.segments()[0];
+    if socket_utils::ipv6_is_loopback(addr) {
+        0
+    } else if first & 0xffc0 == 0xfe80 {
+        1
+    } else if first & 0xfe00 == 0xfc00 {
+        2
+    } else {
+        3
+    }
+}
+
+/// Whether `addr` is a global address, rather than a link-local, unique local or loopback one.
+pub fn is_global(addr: &Ipv6Addr) -> bool {
+    scope_rank(addr) == 3
+}
+
+/// Pick the addresses to advertise, best first, following the relevant rules of RFC 6724's
+/// source address selection. Deprecated global addresses are dropped whenever a preferred one
+/// exists, as are global addresses of the kind `preference` doesn't ask for. Link-local, unique
+/// local and loopback addresses are kept, after the global ones.
+pub fn select(interfaces: &[InterfaceV6], preference: Ipv6AddrPreference) -> Vec<InterfaceV6> {
+    let wants_temporary = preference == Ipv6AddrPreference::Temporary;
+    let have_preferred = interfaces.iter().any(|i| is_global(&i.addr) && !i.state.deprecated);
+    let have_wanted = interfaces.iter().any(|i| {
+        is_global(&i.addr) && !i.state.deprecated && i.state.temporary == wants_temporary
+    });
+    let mut selected: Vec<InterfaceV6> = interfaces.iter().filter(|i| {
+        if !is_global(&i.addr) {
+            return true;
+        }
+        if have_preferred && i.state.deprecated {
+            return false;
+        }
+        !have_wanted || i.state.temporary == wants_temporary
+    }).cloned().collect();
+    selected.sort_by(|a, b| {
+        let key = |i: &InterfaceV6| {
+            (i.state.deprecated, 3 - scope_rank(&i.addr), i.state.temporary != wants_temporary)
+        };
+        key(a).cmp(&key(b))
+    });
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv6Addr;
+    use std::str::FromStr;
+
+    use mapping_context::InterfaceV6;
+
+    fn interface(addr: &str, temporary: bool, deprecated: bool) -> InterfaceV6 {
+        InterfaceV6 {
+            addr: unwrap_result!(Ipv6Addr::from_str(addr)),
+            state: AddrState {
+                temporary: temporary,
+                deprecated: deprecated,
+            },
+            name: String::from("eth0"),
+            is_virtual: false,
+        }
+    }
+
+    #[test]
+    fn selection_follows_preference_and_avoids_deprecated() {
+        let table = "\
+fe80000000000000021122fffe334455 02 40 20 80     eth0
+20010db800000000021122fffe334455 02 40 00 00     eth0
+20010db80000000055aa1234abcd0001 02 40 00 01     eth0
+20010db800000000aa55432100ff0002 02 40 00 21     eth0
+";
+        let states = parse_if_inet6(table);
+        let temporary = unwrap_result!(Ipv6Addr::from_str("2001:db8::55aa:1234:abcd:1"));
+        assert_eq!(states[&temporary], AddrState { temporary: true, deprecated: false });
+
+        let interfaces = vec![interface("fe80::211:22ff:fe33:4455", false, false),
+                              interface("2001:db8::211:22ff:fe33:4455", false, false),
+                              interface("2001:db8::55aa:1234:abcd:1", true, false),
+                              interface("2001:db8::aa55:4321:ff:2", true, true)];
+        let addrs = |selected: Vec<InterfaceV6>| -> Vec<String> {
+            selected.iter().map(|i| format!("{}", i.addr)).collect()
+        };
+        assert_eq!(addrs(select(&interfaces, Ipv6AddrPreference::Stable)),
+                   vec!["2001:db8::211:22ff:fe33:4455", "fe80::211:22ff:fe33:4455"]);
+        assert_eq!(addrs(select(&interfaces, Ipv6AddrPreference::Temporary)),
+                   vec!["2001:db8::55aa:1234:abcd:1", "fe80::211:22ff:fe33:4455"]);
+        // A deprecated address is still better than nothing.
+        assert_eq!(addrs(select(&interfaces[3..], Ipv6AddrPreference::Stable)),
+                   vec!["2001:db8::aa55:4321:ff:2"]);
+    }
+}
@@ -0,0 +1,496 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::io;
+use std::net;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::{Instant, Duration};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::fmt;
+
+use get_if_addrs;
+use net2::UdpBuilder;
+use rand;
+use socket_addr::SocketAddr;
+
+use dns_discovery;
+use dns_discovery::{DnsDiscoveryError, Record, RecordData, TYPE_PTR};
+use error_code::{ErrorCategory, ErrorCode};
+use mapping_context::MappingContext;
+use runtime::{RuntimeHandle, Sockets};
+use socket_utils::RecvUntil;
+
+/// The mDNS service under which peers advertise their rendezvous listeners.
+pub const LAN_RENDEZVOUS_SERVICE: &'static str = "_nat-rendezvous._udp.local";
+/// The mDNS service under which simple udp hole punch servers are advertised.
+pub const LAN_PUNCH_SERVER_SERVICE: &'static str = "_nat-punch._udp.local";
+
+const MDNS_PORT: u16 = 5353;
+const RECORD_TTL_SECS: u32 = 120;
+const TYPE_ANY: u16 = 255;
+
+fn mdns_group() -> Ipv4Addr {
+    Ipv4Addr::new(224, 0, 0, 251)
+}
+
+quick_error! {
+    /// Errors raised while advertising or browsing for services on the local network.
+    #[derive(Debug)]
+    pub enum LanDiscoveryError {
+        /// Error binding a socket for mDNS.
+        Bind { err: io::Error } {
+            description("Error binding a socket for mDNS")
+            display("Error binding a socket for mDNS: {}", err)
+            cause(err)
+        }
+        /// Error joining the mDNS multicast group.
+        JoinMulticast { err: io::Error } {
+            description("Error joining the mDNS multicast group")
+            display("Error joining the mDNS multicast group: {}", err)
+            cause(err)
+        }
+        /// Error putting the mDNS socket into non-blocking mode.
+        SetNonblocking { err: io::Error } {
+            description("Error putting the mDNS socket into non-blocking mode")
+            display("Error putting the mDNS socket into non-blocking mode: {}", err)
+            cause(err)
+        }
+        /// Error listing the local machine's network interfaces.
+        ListInterfaces { err: io::Error } {
+            description("Error listing the local machine's network interfaces")
+            display("Error listing the local machine's network interfaces: {}", err)
+            cause(err)
+        }
+        /// The service or instance name can't be encoded in DNS.
+        InvalidName { err: DnsDiscoveryError } {
+            description("Invalid mDNS service or instance name")
+            display("Invalid mDNS service or instance name: {}", err)
+            cause(err)
+        }
+        /// IO error sending an mDNS query.
+        Send { err: io::Error } {
+            description("IO error sending an mDNS query")
+            display("IO error sending an mDNS query: {}", err)
+            cause(err)
+        }
+        /// IO error receiving mDNS responses.
+        Recv { err: io::Error } {
+            description("IO error receiving mDNS responses")
+            display("IO error receiving mDNS responses: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl From<LanDiscoveryError> for io::Error {
+    fn from(e: LanDiscoveryError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            LanDiscoveryError::Bind { err } => err.kind(),
+            LanDiscoveryError::JoinMulticast { err } => err.kind(),
+            LanDiscoveryError::SetNonblocking { err } => err.kind(),
+            LanDiscoveryError::ListInterfaces { err } => err.kind(),
+            LanDiscoveryError::InvalidName { .. } => io::ErrorKind::InvalidInput,
+            LanDiscoveryError::Send { err } => err.kind(),
+            LanDiscoveryError::Recv { err } => err.kind(),
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for LanDiscoveryError {
+    fn code(&self) -> u32 {
+        match *self {
+            LanDiscoveryError::Bind { .. } => 2201,
+            LanDiscoveryError::JoinMulticast { .. } => 2202,
+            // 2203 was `SetSocketTimeout`, from when the advertiser blocked in `recv_from` on a
+            // thread of its own. It stays retired.
+            LanDiscoveryError::ListInterfaces { .. } => 2204,
+            LanDiscoveryError::InvalidName { .. } => 2205,
+            LanDiscoveryError::Send { .. } => 2206,
+            LanDiscoveryError::Recv { .. } => 2207,
+            LanDiscoveryError::SetNonblocking { .. } => 2208,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            LanDiscoveryError::Bind { .. } => ErrorCategory::Network,
+            LanDiscoveryError::JoinMulticast { .. } => ErrorCategory::Network,
+            LanDiscoveryError::SetNonblocking { .. } => ErrorCategory::Network,
+            LanDiscoveryError::ListInterfaces { .. } => ErrorCategory::Network,
+            LanDiscoveryError::InvalidName { ref err, .. } => err.category(),
+            LanDiscoveryError::Send { .. } => ErrorCategory::Network,
+            LanDiscoveryError::Recv { .. } => ErrorCategory::Network,
+        }
+    }
+}
+
+/// A service instance found on the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanService {
+    /// The name the instance was advertised under.
+    pub instance: String,
+    /// The addresses of the instance.
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// RAII type which answers mDNS queries for a service on the local network until it is dropped.
+pub struct LanAdvertiser {
+    stop_flag: Arc<AtomicBool>,
+    runtime: RuntimeHandle,
+}
+
+impl fmt::Debug for LanAdvertiser {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LanAdvertiser")
+    }
+}
+
+impl LanAdvertiser {
+    /// Advertise `port` on this machine as the instance `instance` of `service`, eg.
+    /// `LAN_RENDEZVOUS_SERVICE`. Queries are answered on every ipv4 interface, from `mc`'s
+    /// runtime.
+    pub fn new(mc: &MappingContext, service: &str, instance: &str, port: u16)
+               -> Result<LanAdvertiser, LanDiscoveryError>
+    {
+        let interfaces = match get_if_addrs::get_if_addrs() {
+            Ok(interfaces) => interfaces,
+            Err(e) => return Err(LanDiscoveryError::ListInterfaces { err: e }),
+        };
+        let ips: Vec<Ipv4Addr> = interfaces.into_iter().filter_map(|interface| {
+            match interface.addr {
+                get_if_addrs::IfAddr::V4(ref v4_addr) if !v4_addr.ip.is_loopback() => {
+                    Some(v4_addr.ip)
+                },
+                _ => None,
+            }
+        }).collect();
+        let records = service_records(service, instance, port, &ips);
+        // Make sure the names are valid before spawning the responder.
+        match dns_discovery::encode_response(0, &records) {
+            Ok(_) => (),
+            Err(e) => return Err(LanDiscoveryError::InvalidName { err: e }),
+        };
+
+        let socket = try!(bind_mdns_socket());
+        match socket.join_multicast_v4(&mdns_group(), &Ipv4Addr::new(0, 0, 0, 0)) {
+            Ok(()) => (),
+            Err(e) => return Err(LanDiscoveryError::JoinMulticast { err: e }),
+        };
+        match socket.set_nonblocking(true) {
+            Ok(()) => (),
+            Err(e) => return Err(LanDiscoveryError::SetNonblocking { err: e }),
+        };
+
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let responder = Responder {
+            names: records.iter().map(|r| r.name.to_lowercase()).collect(),
+            socket: socket,
+            records: records,
+            stop_flag: stop_flag.clone(),
+        };
+        let runtime = mc.runtime();
+        let cloned_runtime = runtime.clone();
+        runtime.spawn(move || responder.answer(cloned_runtime));
+        Ok(LanAdvertiser {
+            stop_flag: stop_flag,
+            runtime: runtime,
+        })
+    }
+}
+
+impl Drop for LanAdvertiser {
+    fn drop(&mut self) {
+        self.runtime.stop(&self.stop_flag);
+    }
+}
+
+// Everything the advertiser needs from one wakeup to the next.
+struct Responder {
+    socket: UdpSocket,
+    records: Vec<Record>,
+    // The lowercased names of `records`, which queries are matched against.
+    names: Vec<String>,
+    stop_flag: Arc<AtomicBool>,
+}
+
+impl Responder {
+    // Answer the queries waiting on the socket then wait for more. Once the advertiser has been
+    // dropped the socket is dropped instead.
+    fn answer(self, runtime: RuntimeHandle) {
+        if self.stop_flag.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut read_buf = [0u8; 1024];
+        // Stop at the first error, which is usually `WouldBlock` once the socket has been
+        // drained.
+        while let Ok((bytes_read, peer_addr)) = self.socket.recv_from(&mut read_buf) {
+            self.answer_query(&read_buf[..bytes_read], peer_addr);
+        }
+        let sockets = Sockets::new(&[&self.socket]).or_stopped(&self.stop_flag);
+        let cloned_runtime = runtime.clone();
+        runtime.when_readable(sockets, None, move || self.answer(cloned_runtime));
+    }
+
+    // Answer a query if it asks for one of our records.
+    fn answer_query(&self, query: &[u8], peer_addr: net::SocketAddr) {
+        let (id, questions) = match dns_discovery::decode_query(query) {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        let asks_for_us = questions.iter().any(|&(ref name, qtype)| {
+            (qtype == TYPE_PTR || qtype == TYPE_ANY) && self.names.contains(&name.to_lowercase())
+        });
+        if !asks_for_us {
+            return;
+        }
+        // Queries from port 5353 come from full mDNS responders which expect multicast answers.
+        // Anything else is a one-shot querier, such as `browse_lan`, which expects a unicast
+        // answer with the query's id.
+        let (id, dest) = if peer_addr.port() == MDNS_PORT {
+            (0, net::SocketAddr::V4(SocketAddrV4::new(mdns_group(), MDNS_PORT)))
+        } else {
+            (id, peer_addr)
+        };
+        let response = match dns_discovery::encode_response(id, &self.records) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+        let _ = self.socket.send_to(&response, dest);
+    }
+}
+
+/// Ask the local network for instances of `service`, eg. `LAN_PUNCH_SERVER_SERVICE`, collecting
+/// answers until `deadline`.
+pub fn browse_lan(service: &str, deadline: Instant) -> Result<Vec<LanService>, LanDiscoveryError> {
+    let id = rand::random::<u16>();
+    let query = match dns_discovery::encode_query(id, service, TYPE_PTR) {
+        Ok(query) => query,
+        Err(e) => return Err(LanDiscoveryError::InvalidName { err: e }),
+    };
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(e) => return Err(LanDiscoveryError::Bind { err: e }),
+    };
+    let group = net::SocketAddr::V4(SocketAddrV4::new(mdns_group(), MDNS_PORT));
+
+    let mut services = Vec::new();
+    let mut recv_deadline = Instant::now();
+    while recv_deadline < deadline {
+        recv_deadline = recv_deadline + Duration::from_secs(1);
+        if recv_deadline > deadline {
+            recv_deadline = deadline;
+        }
+        let _ = match socket.send_to(&query, group) {
+            Ok(n) => n,
+            Err(e) => return Err(LanDiscoveryError::Send { err: e }),
+        };
+        let mut response = [0u8; 1024];
+        loop {
+            let (len, addr) = match socket.recv_until(&mut response[..], recv_deadline) {
+                Ok(Some(res)) => res,
+                Ok(None) => break,
+                Err(e) => return Err(LanDiscoveryError::Recv { err: e }),
+            };
+            let records = match dns_discovery::decode_response(&response[..len]) {
+                Ok(records) => records,
+                Err(_) => continue,
+            };
+            merge_services(&mut services, services_from_records(service, &records, addr.ip()));
+        }
+    }
+    Ok(services)
+}
+
+/// Browse the local network for simple udp hole punch servers and add them to `mc`. Returns the
+/// number of servers found.
+pub fn add_lan_simple_servers(mc: &MappingContext, deadline: Instant)
+                              -> Result<usize, LanDiscoveryError>
+{
+    let services = try!(browse_lan(LAN_PUNCH_SERVER_SERVICE, deadline));
+    let servers: Vec<SocketAddr> = services.into_iter().flat_map(|s| s.addrs).collect();
+    let count = servers.len();
+    mc.add_simple_udp_servers(servers);
+    Ok(count)
+}
+
+fn bind_mdns_socket() -> Result<UdpSocket, LanDiscoveryError> {
+    let builder = match UdpBuilder::new_v4() {
+        Ok(builder) => builder,
+        Err(e) => return Err(LanDiscoveryError::Bind { err: e }),
+    };
+    // Share the port with any other mDNS responder on this machine.
+    match builder.reuse_address(true) {
+        Ok(_) => (),
+        Err(e) => return Err(LanDiscoveryError::Bind { err: e }),
+    };
+    match enable_so_reuseport(&builder) {
+        Ok(()) => (),
+        Err(e) => return Err(LanDiscoveryError::Bind { err: e }),
+    };
+    match builder.bind(("0.0.0.0", MDNS_PORT)) {
+        Ok(socket) => Ok(socket),
+        Err(e) => Err(LanDiscoveryError::Bind { err: e }),
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn enable_so_reuseport(builder: &UdpBuilder) -> io::Result<()> {
+    use net2::unix::UnixUdpBuilderExt;
+    let _ = try!(builder.reuse_port(true));
+    Ok(())
+}
+
+#[cfg(target_family = "windows")]
+fn enable_so_reuseport(_builder: &UdpBuilder) -> io::Result<()> {
+    Ok(())
+}
+
+// The PTR, SRV and A records describing an instance of a service.
+fn service_records(service: &str, instance: &str, port: u16, ips: &[Ipv4Addr]) -> Vec<Record> {
+    let service = service.trim_right_matches('.');
+    let instance_name = format!("{}.{}", instance, service);
+    let host = format!("{}.local", instance);
+    let mut records = vec![
+        Record {
+            name: service.to_owned(),
+            ttl: RECORD_TTL_SECS,
+            data: RecordData::Ptr(instance_name.clone()),
+        },
+        Record {
+            name: instance_name,
+            ttl: RECORD_TTL_SECS,
+            data: RecordData::Srv {
+                priority: 0,
+                weight: 0,
+                port: port,
+                target: host.clone(),
+            },
+        },
+    ];
+    records.extend(ips.iter().map(|ip| {
+        Record {
+            name: host.clone(),
+            ttl: RECORD_TTL_SECS,
+            data: RecordData::A(*ip),
+        }
+    }));
+    records
+}
+
+// Find the instances of `service` described by the records of one response. Instances without A
+// records are assumed to live at the address the response came from.
+fn services_from_records(service: &str, records: &[Record], sender: net::IpAddr)
+                         -> Vec<LanService>
+{
+    let service = service.trim_right_matches('.').to_lowercase();
+    let suffix = format!(".{}", service);
+    let mut services = Vec::new();
+    for record in records {
+        let instance_name = match record.data {
+            RecordData::Ptr(ref target) if record.name.to_lowercase() == service => target,
+            _ => continue,
+        };
+        let srv = records.iter().filter_map(|r| match r.data {
+            RecordData::Srv { port, ref target, .. } if r.name == *instance_name => {
+                Some((port, target))
+            },
+            _ => None,
+        }).next();
+        let (port, host) = match srv {
+            Some(srv) => srv,
+            None => continue,
+        };
+        let mut addrs: Vec<SocketAddr> = records.iter().filter_map(|r| match r.data {
+            RecordData::A(ip) if r.name == *host => {
+                Some(SocketAddr(net::SocketAddr::V4(SocketAddrV4::new(ip, port))))
+            },
+            _ => None,
+        }).collect();
+        if addrs.is_empty() {
+            addrs.push(SocketAddr(net::SocketAddr::new(sender, port)));
+        }
+        let instance = if instance_name.to_lowercase().ends_with(&suffix) {
+            instance_name[..instance_name.len() - suffix.len()].to_owned()
+        } else {
+            instance_name.clone()
+        };
+        services.push(LanService {
+            instance: instance,
+            addrs: addrs,
+        });
+    }
+    services
+}
+
+fn merge_services(services: &mut Vec<LanService>, found: Vec<LanService>) {
+    for service in found {
+        match services.iter_mut().find(|s| s.instance == service.instance) {
+            Some(existing) => {
+                for addr in service.addrs {
+                    if !existing.addrs.contains(&addr) {
+                        existing.addrs.push(addr);
+                    }
+                }
+                continue;
+            },
+            None => (),
+        }
+        services.push(service);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{service_records, services_from_records};
+
+    use std::net::Ipv4Addr;
+
+    use dns_discovery::{Record, RecordData};
+    use socket_addr::SocketAddr;
+
+    #[test]
+    fn advertised_records_are_browsed() {
+        let ips = [Ipv4Addr::new(192, 168, 1, 7), Ipv4Addr::new(10, 0, 0, 7)];
+        let records = service_records(LAN_PUNCH_SERVER_SERVICE, "office-server", 5483, &ips);
+        let sender = unwrap_result!("192.168.1.7".parse());
+
+        let services = services_from_records(LAN_PUNCH_SERVER_SERVICE, &records, sender);
+        assert_eq!(services, vec![LanService {
+            instance: "office-server".to_owned(),
+            addrs: vec![SocketAddr(unwrap_result!("192.168.1.7:5483".parse())),
+                        SocketAddr(unwrap_result!("10.0.0.7:5483".parse()))],
+        }]);
+
+        // Other services on the network are ignored.
+        assert!(services_from_records(LAN_RENDEZVOUS_SERVICE, &records, sender).is_empty());
+
+        // Without A records the sender's address is used.
+        let records: Vec<Record> = records.into_iter().filter(|r| match r.data {
+            RecordData::A(..) => false,
+            _ => true,
+        }).collect();
+        let services = services_from_records(LAN_PUNCH_SERVER_SERVICE, &records, sender);
+        assert_eq!(services[0].addrs, vec![SocketAddr(unwrap_result!("192.168.1.7:5483".parse()))]);
+    }
+}
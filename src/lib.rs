@@ -17,6 +17,20 @@
 
 //! # `nat_traversal`
 //! NAT traversal utilities.
+//!
+//! # Panics
+//!
+//! Failed network operations, and anything malformed received from the network, are reported as
+//! errors or warnings rather than panics, so the crate is safe to use in long-running daemons.
+//! The panics that remain guard invariants which can't be broken from outside, such as a lock
+//! being poisoned by a thread which has already panicked.
+//!
+//! # Layout
+//!
+//! Everything is re-exported at the crate root. It's also grouped by subsystem in the `mapping`,
+//! `punching::udp`, `punching::tcp`, `server`, `relay`, `signaling` and `subnetting` modules.
+//! `punching::tcp` needs the `tcp` feature and `relay` the `relay` feature. The `prelude` has
+//! what most applications need.
 
 #![doc(html_logo_url =
            "https://raw.githubusercontent.com/maidsafe/QA/master/Images/maidsafe_logo.png",
@@ -46,43 +60,217 @@
 #![allow(missing_docs)]
 
 extern crate byteorder;
+#[cfg(target_family = "unix")]
+extern crate libc;
+#[cfg(any(feature = "tcp", feature = "lan"))]
 extern crate net2;
 extern crate rand;
 extern crate rustc_serialize;
 extern crate void;
 #[macro_use]
 extern crate maidsafe_utilities;
+#[cfg(feature = "upnp")]
 extern crate igd;
 extern crate socket_addr;
+extern crate sodiumoxide;
+#[cfg(all(feature = "tcp", target_family = "windows"))]
+extern crate winapi;
+#[cfg(all(feature = "tcp", target_family = "windows"))]
+extern crate ws2_32;
 extern crate get_if_addrs;
 extern crate w_result;
 #[allow(unused_extern_crates)] // Needed because the crate is only used for macros
 #[macro_use]
 extern crate quick_error;
 
-pub use mapping_context::{MappingContext, MappingContextNewError, MappingContextNewWarning};
-pub use mapped_socket_addr::MappedSocketAddr;
-pub use rendezvous_info::{PrivRendezvousInfo, PubRendezvousInfo,
-                         gen_rendezvous_info};
+pub use checklist::{candidate_priority, pair_priority, CandidatePair, Checklist, PairState};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use control::ControlServer;
+#[cfg(feature = "crust_compat")]
+pub use crust_compat::{deserialise_rendezvous_info, serialise_rendezvous_info,
+                       RendezvousWireVersion};
+pub use dns_discovery::{resolve_srv, resolve_txt, DnsDiscoveryError, SrvRecord};
+pub use echo_policy::EchoPolicy;
+pub use error_code::{ErrorCategory, ErrorCode};
+pub use event::{Event, EventSender, Strategy};
+pub use failover_udp_socket::FailoverUdpSocket;
+pub use firewall::{add_firewall_rule, check_firewall, FirewallError, FirewallProtocol,
+                   FirewallVerdict};
+pub use http_discovery::{query_http_echo_server, HttpDiscoveryError};
+#[cfg(feature = "stun")]
+pub use ice::{ice_candidates, ice_lite_connect, ice_priority, IceCandidate, IceCandidateType,
+              IceCredentials, IceError};
+#[cfg(feature = "lan")]
+pub use lan_discovery::{add_lan_simple_servers, browse_lan, LanAdvertiser, LanDiscoveryError,
+                        LanService, LAN_PUNCH_SERVER_SERVICE, LAN_RENDEZVOUS_SERVICE};
+pub use icmp::{IcmpFailure, IcmpObserver};
+pub use identity::{Identity, IdentityError};
+pub use ipv6_selection::Ipv6AddrPreference;
+pub use loopback::{loopback_udp_rendezvous, LoopbackRendezvousError};
+pub use mapping_behaviour::{check_mapping_behaviour, MappingBehaviour, MappingBehaviourError};
+pub use mapping_context::{ConcurrencyLimits, MappingContext, MappingContextNewError,
+                          MappingContextNewWarning, DEFAULT_EXTERNAL_ADDR_TTL_SECS};
+pub use mapped_socket_addr::{CandidateClass, MappedSocketAddr, ParseMappedSocketAddrError};
+pub use metrics::{render_metrics, write_metrics_textfile, MetricsServer};
+pub use randomness::RngHandle;
+pub use rendezvous_info::{ParseRendezvousInfoError, PrivRendezvousInfo, PubRendezvousInfo,
+                         RotatingRendezvousInfo, gen_rendezvous_info, gen_rendezvous_info_with_rng,
+                         gen_rendezvous_info_with_identity, gen_rendezvous_info_with_static_key,
+                         synchronized_punch_start};
 pub use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketMapError,
                             MappedUdpSocketMapWarning, MappedUdpSocketNewError};
-pub use punched_udp_socket::{PunchedUdpSocket, filter_udp_hole_punch_packet};
-pub use mapped_tcp_socket::{new_reusably_bound_tcp_socket, MappedTcpSocket, tcp_punch_hole,
+pub use punch_crypto::PunchKey;
+pub use punch_state_machine::{UdpPunchStateMachine, UdpPunchStatus};
+#[cfg(feature = "quic")]
+pub use quic::{QuicBootstrap, QuicRole};
+pub use punched_udp_socket::{PunchedUdpSocket, ReplayOutcome, UdpPunchBuilder, UdpPunchHoleError,
+                             UdpPunchHoleWarning, filter_sealed_udp_hole_punch_packet,
+                             filter_udp_hole_punch_packet, is_peer_unreachable,
+                             replay_udp_punch};
+#[cfg(feature = "tcp")]
+pub use mapped_tcp_socket::{new_reusably_bound_mptcp_socket, new_reusably_bound_tcp_socket,
+                            MappedTcpSocket, tcp_punch_hole,
+                            tcp_punch_hole_with_events,
                             MappedTcpSocketMapError, MappedTcpSocketMapWarning,
                             MappedTcpSocketNewError, NewReusablyBoundTcpSocketError,
-                            TcpPunchHoleWarning, TcpPunchHoleError};
-pub use simple_udp_hole_punch_server::{SimpleUdpHolePunchServer, SimpleUdpHolePunchServerNewError};
+                            PunchedTcpStream, TcpPunchBuilder, TcpPunchHoleWarning,
+                            TcpPunchHoleError, TcpPunchTechnique};
+pub use network_monitor::NetworkMonitor;
+pub use nat64::{discover_nat64_prefixes, synthesize_nat64_candidates, Nat64Error, Nat64Prefix};
+pub use nat_probe::{check_hairpinning, probe_nat, NatReport};
+pub use noise::{noise_handshake, secure_channel, secure_channel_with_identity, NoiseError,
+                SecureChannel, StaticKeypair, MAX_SECURE_MESSAGE_LEN};
+#[cfg(any(test, feature = "nat_sim"))]
+pub use nat_sim::{NatBehaviour, NatConfig, NatSimSocket, PortAllocation, SimulatedNat};
+pub use pipeline::{ExternalAddrDiscovery, HolePuncher, PortMapper, UdpHolePuncher};
+#[cfg(feature = "tcp")]
+pub use graceful_tcp_stream::GracefulTcpStream;
+#[cfg(feature = "tcp")]
+pub use pipeline::TcpHolePuncher;
+pub use privacy::{redact_addrs, set_redact_addrs, ExposurePolicy};
+pub use ping::{is_server_keepalive, ping_server, probe_latency, request_keepalive, LatencyStats,
+               PingServerError, ServerKeepalive, ServerStatus};
+#[cfg(feature = "tcp")]
+pub use ping::ping_tcp_server;
+pub use runtime::{Pollable, Runtime, RuntimeHandle, Sockets, DEFAULT_RUNTIME_THREADS};
+pub use session_record::{Direction, RecordedPacket, SessionRecord, SessionRecorder};
+pub use simple_udp_hole_punch_server::{SimpleUdpHolePunchServer, SimpleUdpHolePunchServerNewError,
+                                       SimpleUdpServerLimits, SimpleUdpServerStats};
+#[cfg(feature = "tcp")]
 pub use simple_tcp_hole_punch_server::{SimpleTcpHolePunchServer, SimpleTcpHolePunchServerNewError};
+#[cfg(target_family = "unix")]
+pub use socket_activation::{activated_sockets, ActivatedSocket};
+pub use socks5::{map_socks5_udp, Socks5Error, Socks5Proxy, Socks5UdpSocket};
+pub use strategy_history::StrategyHistory;
+#[cfg(feature = "stun")]
+pub use stun::{crc32, decode_channel_data, encode_channel_data, is_stun, query_stun_server,
+               DecodedStunMessage, StunAttribute, StunClass, StunError, StunMessage,
+               StunQueryError, MAGIC_COOKIE, METHOD_ALLOCATE, METHOD_BINDING, METHOD_CHANNEL_BIND,
+               METHOD_CONNECT, METHOD_CONNECTION_ATTEMPT, METHOD_CONNECTION_BIND,
+               METHOD_CREATE_PERMISSION, METHOD_DATA, METHOD_REFRESH, METHOD_SEND};
+#[cfg(feature = "tcp")]
+pub use tcp_race::{RacedTcpStream, TcpRaceBuilder, TcpRaceError};
+#[cfg(feature = "tcp")]
+pub use tcp_splice::{tcp_splice, SpliceToken, TcpSpliceError, TcpSpliceServer,
+                     TcpSpliceServerNewError};
+pub use subnetting::{IpSubnet, Ipv4Subnet, Ipv6Subnet, Ipv6Tunnel, SubnetError, SubnetList};
+pub use timeouts::Timeouts;
+pub use transport::DatagramTransport;
+pub use virtual_interfaces::VirtualInterfacePolicy;
+#[cfg(feature = "relay")]
+pub use turn::{TurnAllocation, TurnCredentials, TurnError, TurnServer, TurnServerConfig,
+               TurnServerNewError, TurnUsage};
+#[cfg(feature = "relay")]
+pub use turn_tcp::TurnTcpAllocation;
 
+mod batch;
+mod cbor;
+mod checklist;
+mod clock;
+mod control;
+#[cfg(feature = "crust_compat")]
+mod crust_compat;
+mod dns_discovery;
+mod echo_policy;
+mod error_code;
+mod event;
+mod failover_udp_socket;
+pub mod ffi;
+mod firewall;
+mod gateway;
+#[cfg(feature = "tcp")]
+mod graceful_tcp_stream;
+mod http_discovery;
+mod icmp;
+mod identity;
+mod ipv6_selection;
+#[cfg(feature = "stun")]
+mod ice;
+#[cfg(feature = "lan")]
+mod lan_discovery;
+mod loopback;
+pub mod mapping;
+mod mapping_behaviour;
 mod mapping_context;
+mod metrics;
 mod mapped_socket_addr;
+#[cfg(feature = "relay")]
+mod md5;
+mod randomness;
+#[cfg(feature = "relay")]
+pub mod relay;
 mod rendezvous_info;
+mod routes;
+mod runtime;
 mod mapped_udp_socket;
+mod punch_crypto;
+mod punch_state_machine;
 mod punched_udp_socket;
+#[cfg(feature = "quic")]
+mod quic;
+pub mod punching;
+#[cfg(feature = "tcp")]
 mod mapped_tcp_socket;
+mod nat64;
+mod nat_probe;
+#[cfg(any(test, feature = "nat_sim"))]
+mod nat_sim;
+mod network_monitor;
+mod noise;
+mod pacer;
+mod ping;
+mod pipeline;
+mod pktinfo;
+pub mod prelude;
+mod privacy;
+pub mod server;
+mod session_record;
+pub mod signaling;
+#[cfg(feature = "stun")]
+mod sha1;
+mod snapshot;
 mod simple_udp_hole_punch_server;
+#[cfg(feature = "tcp")]
 mod simple_tcp_hole_punch_server;
+#[cfg(target_family = "unix")]
+mod socket_activation;
+mod strategy_history;
+pub mod subnetting;
+mod timeouts;
 mod socket_utils;
+mod socks5;
+#[cfg(feature = "stun")]
+mod stun;
+#[cfg(feature = "tcp")]
+mod tcp_race;
+#[cfg(feature = "tcp")]
+mod tcp_splice;
+mod transport;
+#[cfg(feature = "relay")]
+mod turn;
+#[cfg(feature = "relay")]
+mod turn_tcp;
 mod listener_message;
 mod utils;
+mod virtual_interfaces;
 
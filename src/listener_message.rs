@@ -15,11 +15,585 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use std::io;
+use std::io::Write;
+use std::net;
+use std::str;
+
+use byteorder::{BigEndian, ByteOrder};
+use maidsafe_utilities::serialisation::{deserialise, SerialisationError};
 use socket_addr::SocketAddr;
 
+use cbor::{CborReader, CborWriter};
+
 pub const REQUEST_MAGIC_CONSTANT: [u8; 4] = ['E' as u8, 'C' as u8, 'H' as u8, 'O' as u8];
+pub const PING_MAGIC_CONSTANT: [u8; 4] = ['P' as u8, 'I' as u8, 'N' as u8, 'G' as u8];
+/// Sent by an overloaded server in place of a response. The client should try again later or use
+/// another server.
+pub const TRY_LATER_MAGIC_CONSTANT: [u8; 4] = ['B' as u8, 'U' as u8, 'S' as u8, 'Y' as u8];
+
+/// Sent in reply to a padded request which lacks a valid cookie, followed by the cookie to echo.
+pub const COOKIE_MAGIC_CONSTANT: [u8; 4] = ['C' as u8, 'O' as u8, 'O' as u8, 'K' as u8];
+/// Prefixes a `ConfirmedExternalAddr`.
+pub const CONFIRMED_ADDR_MAGIC_CONSTANT: [u8; 4] = ['A' as u8, 'D' as u8, 'D' as u8, 'R' as u8];
+/// Prefixes a `LatencyProbe` and the server's reflection of it.
+pub const LATENCY_PROBE_MAGIC_CONSTANT: [u8; 4] = ['R' as u8, 'T' as u8, 'T' as u8, 'P' as u8];
+
+/// The length of a latency probe and of its reflection. The client leaves room for the server's
+/// timestamp so that the reflection is no larger than the probe.
+pub const LATENCY_PROBE_LEN: usize = 4 + 4 + 8 + 8;
+/// Prefixes a `MappingBehaviourRequest` and the `MappingBehaviourReport` answering it.
+pub const MAPPING_BEHAVIOUR_MAGIC_CONSTANT: [u8; 4] = ['M' as u8, 'A' as u8, 'P' as u8, 'B' as u8];
+/// Prefixes a padded request for keepalives and the `KeepaliveGranted` answering it. The
+/// keepalives themselves are just the magic constant.
+pub const KEEPALIVE_MAGIC_CONSTANT: [u8; 4] = ['K' as u8, 'E' as u8, 'E' as u8, 'P' as u8];
+/// Prefixes a tcp splice request, which is followed by the splice token, and is sent back alone
+/// once the connection has been spliced to the peer's.
+pub const SPLICE_MAGIC_CONSTANT: [u8; 4] = ['S' as u8, 'P' as u8, 'L' as u8, 'C' as u8];
+/// The length of a tcp splice token.
+pub const SPLICE_TOKEN_LEN: usize = 32;
+/// The length of a tcp splice request.
+pub const SPLICE_REQUEST_LEN: usize = 4 + SPLICE_TOKEN_LEN;
+/// Prefixes the keepalives peers send each other on every path of a `FailoverUdpSocket`. It's
+/// followed by `PATH_PING` or `PATH_PONG`.
+pub const PATH_KEEPALIVE_MAGIC_CONSTANT: [u8; 4] = ['P' as u8, 'A' as u8, 'T' as u8, 'H' as u8];
+/// Marks a path keepalive which should be answered.
+pub const PATH_PING: u8 = 0;
+/// Marks the answer to a path keepalive.
+pub const PATH_PONG: u8 = 1;
+
+/// The length of the cookies handed out by the udp server.
+pub const COOKIE_LEN: usize = 16;
+/// The length of the nonce a client puts in its padded requests.
+pub const NONCE_LEN: usize = 16;
+/// Requests which may carry a cookie are padded to this length so that no response, whether a
+/// cookie or an answer, is larger than the request which caused it.
+pub const PADDED_REQUEST_LEN: usize = 96;
+/// The length of a cookie reply.
+pub const COOKIE_REPLY_LEN: usize = 4 + COOKIE_LEN + NONCE_LEN;
+
+/// Version of the simple hole punch server protocol spoken by this crate.
+pub const PROTOCOL_VERSION: u32 = 1;
 
 #[derive(RustcEncodable, RustcDecodable)]
 pub struct EchoExternalAddr {
     pub external_addr: SocketAddr,
 }
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct Pong {
+    pub uptime_secs: u64,
+    pub protocol_version: u32,
+}
+
+/// The most bytes any encoded message takes.
+pub const MAX_MESSAGE_SIZE: usize = 128;
+
+impl EchoExternalAddr {
+    /// Encode into `buf`, returning the encoded length.
+    pub fn encode_into(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // The address is sent as text, which we format on the stack.
+        let mut text_buf = [0u8; 64];
+        let text_len = {
+            let mut cursor = io::Cursor::new(&mut text_buf[..]);
+            try!(write!(cursor, "{}", *self.external_addr));
+            cursor.position() as usize
+        };
+        let text = match str::from_utf8(&text_buf[..text_len]) {
+            Ok(text) => text,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad address")),
+        };
+        let mut writer = CborWriter::new(buf);
+        try!(writer.map(1));
+        try!(writer.text("external_addr"));
+        try!(writer.text(text));
+        Ok(writer.position())
+    }
+
+    /// Decode a message received from a server.
+    pub fn decode(data: &[u8]) -> Result<EchoExternalAddr, SerialisationError> {
+        match decode_echo_external_addr(data) {
+            Some(addr) => Ok(EchoExternalAddr { external_addr: SocketAddr(addr) }),
+            // Let the general decoder have a go, which also gives us a proper error.
+            None => deserialise(data),
+        }
+    }
+}
+
+fn decode_echo_external_addr(data: &[u8]) -> Option<net::SocketAddr> {
+    let mut reader = CborReader::new(data);
+    if reader.map() != Some(1) || reader.key("external_addr").is_none() {
+        return None;
+    }
+    let addr = match reader.text() {
+        Some(text) => text.parse().ok(),
+        None => None,
+    };
+    if reader.is_empty() { addr } else { None }
+}
+
+impl Pong {
+    /// Encode into `buf`, returning the encoded length.
+    pub fn encode_into(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut writer = CborWriter::new(buf);
+        try!(writer.map(2));
+        try!(writer.text("uptime_secs"));
+        try!(writer.uint(self.uptime_secs));
+        try!(writer.text("protocol_version"));
+        try!(writer.uint(self.protocol_version as u64));
+        Ok(writer.position())
+    }
+
+    /// Decode a message received from a server.
+    pub fn decode(data: &[u8]) -> Result<Pong, SerialisationError> {
+        match decode_pong(data) {
+            Some(pong) => Ok(pong),
+            None => deserialise(data),
+        }
+    }
+}
+
+/// A request padded to `PADDED_REQUEST_LEN`. Laid out as the magic constant, a flag saying whether
+/// a cookie follows, the cookie (or zeroes), the nonce and zero padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaddedRequest {
+    pub magic: [u8; 4],
+    /// The cookie the server handed us, if we have one yet.
+    pub cookie: Option<[u8; COOKIE_LEN]>,
+    /// Echoed in the server's replies so that we can tell them from spoofed or stale ones.
+    pub nonce: [u8; NONCE_LEN],
+}
+
+impl PaddedRequest {
+    pub fn encode(&self) -> [u8; PADDED_REQUEST_LEN] {
+        let mut buf = [0u8; PADDED_REQUEST_LEN];
+        buf[..4].copy_from_slice(&self.magic[..]);
+        if let Some(ref cookie) = self.cookie {
+            buf[4] = 1;
+            buf[5..5 + COOKIE_LEN].copy_from_slice(&cookie[..]);
+        }
+        buf[5 + COOKIE_LEN..5 + COOKIE_LEN + NONCE_LEN].copy_from_slice(&self.nonce[..]);
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<PaddedRequest> {
+        if data.len() != PADDED_REQUEST_LEN {
+            return None;
+        }
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(&data[..4]);
+        let mut cookie = [0u8; COOKIE_LEN];
+        cookie.copy_from_slice(&data[5..5 + COOKIE_LEN]);
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&data[5 + COOKIE_LEN..5 + COOKIE_LEN + NONCE_LEN]);
+        let cookie = match data[4] {
+            0 => None,
+            1 => Some(cookie),
+            _ => return None,
+        };
+        Some(PaddedRequest {
+            magic: magic,
+            cookie: cookie,
+            nonce: nonce,
+        })
+    }
+}
+
+/// Encode a cookie reply to a request carrying `nonce`.
+pub fn encode_cookie(cookie: &[u8; COOKIE_LEN], nonce: &[u8; NONCE_LEN])
+                     -> [u8; COOKIE_REPLY_LEN] {
+    let mut buf = [0u8; COOKIE_REPLY_LEN];
+    buf[..4].copy_from_slice(&COOKIE_MAGIC_CONSTANT[..]);
+    buf[4..4 + COOKIE_LEN].copy_from_slice(&cookie[..]);
+    buf[4 + COOKIE_LEN..].copy_from_slice(&nonce[..]);
+    buf
+}
+
+/// Decode a cookie reply received from a server into the cookie and the nonce it echoes.
+pub fn decode_cookie(data: &[u8]) -> Option<([u8; COOKIE_LEN], [u8; NONCE_LEN])> {
+    if data.len() != COOKIE_REPLY_LEN || data[..4] != COOKIE_MAGIC_CONSTANT {
+        return None;
+    }
+    let mut cookie = [0u8; COOKIE_LEN];
+    cookie.copy_from_slice(&data[4..4 + COOKIE_LEN]);
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(&data[4 + COOKIE_LEN..]);
+    Some((cookie, nonce))
+}
+
+/// The answer to a padded request which echoed a valid cookie. The cookie was sent to the address
+/// the server saw us at, so getting this back proves that the address reaches us, and the nonce
+/// proves it's an answer to our request rather than a spoofed or stale one.
+pub struct ConfirmedExternalAddr {
+    pub nonce: [u8; NONCE_LEN],
+    pub external_addr: SocketAddr,
+}
+
+impl ConfirmedExternalAddr {
+    /// Encode into `buf`, returning the encoded length.
+    pub fn encode_into(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let header_len = 4 + NONCE_LEN;
+        if buf.len() < header_len {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "Buffer too small"));
+        }
+        buf[..4].copy_from_slice(&CONFIRMED_ADDR_MAGIC_CONSTANT[..]);
+        buf[4..header_len].copy_from_slice(&self.nonce[..]);
+        let echo = EchoExternalAddr { external_addr: self.external_addr };
+        let len = try!(echo.encode_into(&mut buf[header_len..]));
+        Ok(header_len + len)
+    }
+
+    /// Decode a message received from a server.
+    pub fn decode(data: &[u8]) -> Option<ConfirmedExternalAddr> {
+        let header_len = 4 + NONCE_LEN;
+        if data.len() < header_len || data[..4] != CONFIRMED_ADDR_MAGIC_CONSTANT {
+            return None;
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&data[4..header_len]);
+        decode_echo_external_addr(&data[header_len..]).map(|addr| {
+            ConfirmedExternalAddr {
+                nonce: nonce,
+                external_addr: SocketAddr(addr),
+            }
+        })
+    }
+}
+
+/// A probe used to measure the latency to a server, which the server reflects back with its own
+/// timestamp filled in. Laid out as the magic constant, the sequence number, the client's
+/// timestamp and the server's timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyProbe {
+    /// Distinguishes the probes in a run from each other.
+    pub sequence: u32,
+    /// When the client sent the probe, in microseconds from a point of the client's choosing.
+    pub client_timestamp_micros: u64,
+    /// When the server received the probe, in microseconds since the server started. Zero in
+    /// probes sent by a client.
+    pub server_timestamp_micros: u64,
+}
+
+impl LatencyProbe {
+    pub fn encode(&self) -> [u8; LATENCY_PROBE_LEN] {
+        let mut buf = [0u8; LATENCY_PROBE_LEN];
+        buf[..4].copy_from_slice(&LATENCY_PROBE_MAGIC_CONSTANT[..]);
+        BigEndian::write_u32(&mut buf[4..8], self.sequence);
+        BigEndian::write_u64(&mut buf[8..16], self.client_timestamp_micros);
+        BigEndian::write_u64(&mut buf[16..], self.server_timestamp_micros);
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<LatencyProbe> {
+        if data.len() != LATENCY_PROBE_LEN || data[..4] != LATENCY_PROBE_MAGIC_CONSTANT {
+            return None;
+        }
+        Some(LatencyProbe {
+            sequence: BigEndian::read_u32(&data[4..8]),
+            client_timestamp_micros: BigEndian::read_u64(&data[8..16]),
+            server_timestamp_micros: BigEndian::read_u64(&data[16..]),
+        })
+    }
+}
+
+/// The length of an address encoded by `encode_addr`.
+const ENCODED_ADDR_LEN: usize = 1 + 16 + 2;
+
+// Encode `addr` as its family (4 or 6), the address bytes padded to 16 bytes and the port.
+fn encode_addr(addr: &net::SocketAddr, buf: &mut [u8]) {
+    match *addr {
+        net::SocketAddr::V4(ref addr) => {
+            buf[0] = 4;
+            buf[1..5].copy_from_slice(&addr.ip().octets()[..]);
+        },
+        net::SocketAddr::V6(ref addr) => {
+            buf[0] = 6;
+            buf[1..17].copy_from_slice(&addr.ip().octets()[..]);
+        },
+    }
+    BigEndian::write_u16(&mut buf[17..ENCODED_ADDR_LEN], addr.port());
+}
+
+fn decode_addr(data: &[u8]) -> Option<net::SocketAddr> {
+    let port = BigEndian::read_u16(&data[17..ENCODED_ADDR_LEN]);
+    match data[0] {
+        4 => {
+            let ip = net::Ipv4Addr::new(data[1], data[2], data[3], data[4]);
+            Some(net::SocketAddr::V4(net::SocketAddrV4::new(ip, port)))
+        },
+        6 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&data[1..17]);
+            let ip = net::Ipv6Addr::from(octets);
+            Some(net::SocketAddr::V6(net::SocketAddrV6::new(ip, port, 0, 0)))
+        },
+        _ => None,
+    }
+}
+
+/// Asks a server for the address it sees us at and how our NAT maps. Sent first to the server's
+/// main port, whose report says which secondary port to ask next, then to the secondary port with
+/// the address the first report gave, so that the server can compare the two mappings. Padded to
+/// `PADDED_REQUEST_LEN`, so the report is never larger than the request. Laid out as the magic
+/// constant, the nonce, a flag saying whether an address follows, the address and zero padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingBehaviourRequest {
+    /// Echoed in the report so that we can tell it from spoofed or stale ones.
+    pub nonce: [u8; NONCE_LEN],
+    /// The external address the server's main port reported, when asking its secondary port.
+    pub first_addr: Option<net::SocketAddr>,
+}
+
+impl MappingBehaviourRequest {
+    pub fn encode(&self) -> [u8; PADDED_REQUEST_LEN] {
+        let mut buf = [0u8; PADDED_REQUEST_LEN];
+        buf[..4].copy_from_slice(&MAPPING_BEHAVIOUR_MAGIC_CONSTANT[..]);
+        buf[4..4 + NONCE_LEN].copy_from_slice(&self.nonce[..]);
+        if let Some(ref first_addr) = self.first_addr {
+            buf[4 + NONCE_LEN] = 1;
+            encode_addr(first_addr, &mut buf[5 + NONCE_LEN..]);
+        }
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<MappingBehaviourRequest> {
+        if data.len() != PADDED_REQUEST_LEN || data[..4] != MAPPING_BEHAVIOUR_MAGIC_CONSTANT {
+            return None;
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&data[4..4 + NONCE_LEN]);
+        let first_addr = match data[4 + NONCE_LEN] {
+            0 => None,
+            1 => match decode_addr(&data[5 + NONCE_LEN..]) {
+                Some(addr) => Some(addr),
+                None => return None,
+            },
+            _ => return None,
+        };
+        Some(MappingBehaviourRequest {
+            nonce: nonce,
+            first_addr: first_addr,
+        })
+    }
+}
+
+// The verdicts of a `MappingBehaviourReport`: none, because the report came from the main port,
+// or whether the secondary port saw the same external address as the main port.
+pub const MAPPING_VERDICT_NONE: u8 = 0;
+pub const MAPPING_VERDICT_SAME: u8 = 1;
+pub const MAPPING_VERDICT_DIFFERENT: u8 = 2;
+
+/// The length of an encoded `MappingBehaviourReport`.
+pub const MAPPING_BEHAVIOUR_REPORT_LEN: usize = 4 + NONCE_LEN + ENCODED_ADDR_LEN + 2 + 1;
+
+/// A server's answer to a `MappingBehaviourRequest`. Laid out as the magic constant, the nonce,
+/// the external address, the secondary port and the verdict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingBehaviourReport {
+    pub nonce: [u8; NONCE_LEN],
+    /// The address the server saw the request come from.
+    pub external_addr: net::SocketAddr,
+    /// The port to send the second request to, or zero if the server has no secondary port.
+    pub secondary_port: u16,
+    /// One of the `MAPPING_VERDICT_` constants. Only reports from the secondary port have one.
+    pub verdict: u8,
+}
+
+impl MappingBehaviourReport {
+    pub fn encode(&self) -> [u8; MAPPING_BEHAVIOUR_REPORT_LEN] {
+        let mut buf = [0u8; MAPPING_BEHAVIOUR_REPORT_LEN];
+        buf[..4].copy_from_slice(&MAPPING_BEHAVIOUR_MAGIC_CONSTANT[..]);
+        buf[4..4 + NONCE_LEN].copy_from_slice(&self.nonce[..]);
+        let addr_end = 4 + NONCE_LEN + ENCODED_ADDR_LEN;
+        encode_addr(&self.external_addr, &mut buf[4 + NONCE_LEN..addr_end]);
+        BigEndian::write_u16(&mut buf[addr_end..addr_end + 2], self.secondary_port);
+        buf[addr_end + 2] = self.verdict;
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<MappingBehaviourReport> {
+        if data.len() != MAPPING_BEHAVIOUR_REPORT_LEN ||
+           data[..4] != MAPPING_BEHAVIOUR_MAGIC_CONSTANT {
+            return None;
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&data[4..4 + NONCE_LEN]);
+        let addr_end = 4 + NONCE_LEN + ENCODED_ADDR_LEN;
+        let external_addr = match decode_addr(&data[4 + NONCE_LEN..addr_end]) {
+            Some(addr) => addr,
+            None => return None,
+        };
+        Some(MappingBehaviourReport {
+            nonce: nonce,
+            external_addr: external_addr,
+            secondary_port: BigEndian::read_u16(&data[addr_end..addr_end + 2]),
+            verdict: data[addr_end + 2],
+        })
+    }
+}
+
+/// The length of an encoded `KeepaliveGranted`.
+pub const KEEPALIVE_GRANTED_LEN: usize = 4 + NONCE_LEN + ENCODED_ADDR_LEN + 4 + 4;
+
+/// A server's answer to a padded keepalive request which echoed a valid cookie. Laid out as the
+/// magic constant, the nonce, the external address, the interval and the lease.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveGranted {
+    pub nonce: [u8; NONCE_LEN],
+    /// The address the server saw the request come from, which the keepalives are sent to.
+    pub external_addr: net::SocketAddr,
+    /// How often the server sends a keepalive.
+    pub interval_secs: u32,
+    /// How long the server keeps sending them unless the request is repeated.
+    pub lease_secs: u32,
+}
+
+impl KeepaliveGranted {
+    pub fn encode(&self) -> [u8; KEEPALIVE_GRANTED_LEN] {
+        let mut buf = [0u8; KEEPALIVE_GRANTED_LEN];
+        buf[..4].copy_from_slice(&KEEPALIVE_MAGIC_CONSTANT[..]);
+        buf[4..4 + NONCE_LEN].copy_from_slice(&self.nonce[..]);
+        let addr_end = 4 + NONCE_LEN + ENCODED_ADDR_LEN;
+        encode_addr(&self.external_addr, &mut buf[4 + NONCE_LEN..addr_end]);
+        BigEndian::write_u32(&mut buf[addr_end..addr_end + 4], self.interval_secs);
+        BigEndian::write_u32(&mut buf[addr_end + 4..], self.lease_secs);
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<KeepaliveGranted> {
+        if data.len() != KEEPALIVE_GRANTED_LEN || data[..4] != KEEPALIVE_MAGIC_CONSTANT {
+            return None;
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&data[4..4 + NONCE_LEN]);
+        let addr_end = 4 + NONCE_LEN + ENCODED_ADDR_LEN;
+        let external_addr = match decode_addr(&data[4 + NONCE_LEN..addr_end]) {
+            Some(addr) => addr,
+            None => return None,
+        };
+        Some(KeepaliveGranted {
+            nonce: nonce,
+            external_addr: external_addr,
+            interval_secs: BigEndian::read_u32(&data[addr_end..addr_end + 4]),
+            lease_secs: BigEndian::read_u32(&data[addr_end + 4..]),
+        })
+    }
+}
+
+fn decode_pong(data: &[u8]) -> Option<Pong> {
+    let mut reader = CborReader::new(data);
+    if reader.map() != Some(2) || reader.key("uptime_secs").is_none() {
+        return None;
+    }
+    let uptime_secs = match reader.uint() {
+        Some(uptime_secs) => uptime_secs,
+        None => return None,
+    };
+    if reader.key("protocol_version").is_none() {
+        return None;
+    }
+    match reader.uint() {
+        Some(version) if version <= u32::max_value() as u64 && reader.is_empty() => {
+            Some(Pong {
+                uptime_secs: uptime_secs,
+                protocol_version: version as u32,
+            })
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use maidsafe_utilities::serialisation::serialise;
+    use socket_addr::SocketAddr;
+
+    #[test]
+    fn codecs_match_serialise() {
+        for addr in &["192.168.0.1:5483", "[2001:db8::1]:45000"] {
+            let echo = EchoExternalAddr {
+                external_addr: SocketAddr(unwrap_result!(addr.parse())),
+            };
+            let mut buf = [0u8; MAX_MESSAGE_SIZE];
+            let len = unwrap_result!(echo.encode_into(&mut buf));
+            assert_eq!(&buf[..len], &unwrap_result!(serialise(&echo))[..]);
+            let decoded = unwrap_result!(EchoExternalAddr::decode(&buf[..len]));
+            assert_eq!(decoded.external_addr, echo.external_addr);
+        }
+
+        let pong = Pong {
+            uptime_secs: 1 << 40,
+            protocol_version: PROTOCOL_VERSION,
+        };
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let len = unwrap_result!(pong.encode_into(&mut buf));
+        assert_eq!(&buf[..len], &unwrap_result!(serialise(&pong))[..]);
+        let decoded = unwrap_result!(Pong::decode(&buf[..len]));
+        assert_eq!(decoded.uptime_secs, pong.uptime_secs);
+        assert_eq!(decoded.protocol_version, pong.protocol_version);
+
+        assert!(Pong::decode(b"garbage").is_err());
+    }
+
+    #[test]
+    fn responses_fit_in_padded_request() {
+        let longest_addr = "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535";
+        let nonce = [3u8; NONCE_LEN];
+        let confirmed = ConfirmedExternalAddr {
+            nonce: nonce,
+            external_addr: SocketAddr(unwrap_result!(longest_addr.parse())),
+        };
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let len = unwrap_result!(confirmed.encode_into(&mut buf));
+        assert!(len <= PADDED_REQUEST_LEN);
+        let decoded = unwrap_result!(ConfirmedExternalAddr::decode(&buf[..len])
+                                                           .ok_or("Failed to decode"));
+        assert_eq!(decoded.nonce, nonce);
+        assert_eq!(decoded.external_addr, confirmed.external_addr);
+        let pong = Pong {
+            uptime_secs: u64::max_value(),
+            protocol_version: PROTOCOL_VERSION,
+        };
+        assert!(unwrap_result!(pong.encode_into(&mut buf)) <= PADDED_REQUEST_LEN);
+
+        let cookie = [7u8; COOKIE_LEN];
+        let request = PaddedRequest {
+            magic: REQUEST_MAGIC_CONSTANT,
+            cookie: Some(cookie),
+            nonce: nonce,
+        };
+        assert_eq!(PaddedRequest::decode(&request.encode()[..]), Some(request));
+        let request = PaddedRequest {
+            magic: PING_MAGIC_CONSTANT,
+            cookie: None,
+            nonce: nonce,
+        };
+        assert_eq!(PaddedRequest::decode(&request.encode()[..]), Some(request));
+        assert_eq!(PaddedRequest::decode(&request.encode()[..4]), None);
+        assert_eq!(decode_cookie(&encode_cookie(&cookie, &nonce)[..]), Some((cookie, nonce)));
+
+        let probe = LatencyProbe {
+            sequence: 7,
+            client_timestamp_micros: 1 << 40,
+            server_timestamp_micros: 0,
+        };
+        assert_eq!(LatencyProbe::decode(&probe.encode()[..]), Some(probe));
+        assert_eq!(LatencyProbe::decode(&probe.encode()[..16]), None);
+
+        let request = MappingBehaviourRequest {
+            nonce: nonce,
+            first_addr: Some(unwrap_result!(longest_addr.parse())),
+        };
+        assert_eq!(MappingBehaviourRequest::decode(&request.encode()[..]), Some(request));
+        let report = MappingBehaviourReport {
+            nonce: nonce,
+            external_addr: unwrap_result!("203.0.113.5:4000".parse()),
+            secondary_port: 5484,
+            verdict: MAPPING_VERDICT_SAME,
+        };
+        assert!(report.encode().len() <= PADDED_REQUEST_LEN);
+        assert_eq!(MappingBehaviourReport::decode(&report.encode()[..]), Some(report));
+    }
+}
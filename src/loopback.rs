@@ -0,0 +1,224 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::io;
+use std::sync::mpsc;
+use std::time::Instant;
+
+use socket_addr::SocketAddr;
+use w_result::{WOk, WErr};
+
+use error_code::{ErrorCategory, ErrorCode};
+use mapping_context::{MappingContext, MappingContextNewError};
+use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketNewError};
+use punched_udp_socket::{PunchedUdpSocket, UdpPunchHoleError};
+use rendezvous_info::gen_rendezvous_info;
+use simple_udp_hole_punch_server::{SimpleUdpHolePunchServer, SimpleUdpHolePunchServerNewError};
+use socket_utils;
+
+quick_error! {
+    /// Errors returned by `loopback_udp_rendezvous`.
+    #[derive(Debug)]
+    pub enum LoopbackRendezvousError {
+        /// Error creating a mapping context.
+        CreateMappingContext { err: MappingContextNewError } {
+            description("Error creating a mapping context.")
+            display("Error creating a mapping context: {}", err)
+            cause(err)
+        }
+        /// Error starting the hole punch server.
+        StartServer { err: SimpleUdpHolePunchServerNewError } {
+            description("Error starting the hole punch server.")
+            display("Error starting the hole punch server: {}", err)
+            cause(err)
+        }
+        /// The hole punch server is not reachable over loopback.
+        NoLoopbackAddress {
+            description("The hole punch server is not reachable over loopback.")
+        }
+        /// Error creating a mapped socket for one of the peers.
+        MapSocket { err: MappedUdpSocketNewError } {
+            description("Error creating a mapped socket for one of the peers.")
+            display("Error creating a mapped socket for one of the peers: {}", err)
+            cause(err)
+        }
+        /// Error punching a hole between the peers.
+        PunchHole { err: UdpPunchHoleError } {
+            description("Error punching a hole between the peers.")
+            display("Error punching a hole between the peers: {}", err)
+            cause(err)
+        }
+        /// The thread punching for the second peer died without reporting a result.
+        PunchThreadDied {
+            description("The thread punching for the second peer died without reporting a \
+                         result.")
+        }
+    }
+}
+
+impl From<LoopbackRendezvousError> for io::Error {
+    fn from(e: LoopbackRendezvousError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            LoopbackRendezvousError::CreateMappingContext { err } => {
+                let err: io::Error = From::from(err);
+                err.kind()
+            },
+            LoopbackRendezvousError::StartServer { err } => {
+                let err: io::Error = From::from(err);
+                err.kind()
+            },
+            LoopbackRendezvousError::NoLoopbackAddress => io::ErrorKind::AddrNotAvailable,
+            LoopbackRendezvousError::MapSocket { err } => {
+                let err: io::Error = From::from(err);
+                err.kind()
+            },
+            LoopbackRendezvousError::PunchHole { err } => {
+                let err: io::Error = From::from(err);
+                err.kind()
+            },
+            LoopbackRendezvousError::PunchThreadDied => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for LoopbackRendezvousError {
+    fn code(&self) -> u32 {
+        match *self {
+            LoopbackRendezvousError::CreateMappingContext { .. } => 1701,
+            LoopbackRendezvousError::StartServer { .. } => 1702,
+            LoopbackRendezvousError::NoLoopbackAddress => 1703,
+            LoopbackRendezvousError::MapSocket { .. } => 1704,
+            LoopbackRendezvousError::PunchHole { .. } => 1705,
+            LoopbackRendezvousError::PunchThreadDied => 1706,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            LoopbackRendezvousError::CreateMappingContext { ref err } => err.category(),
+            LoopbackRendezvousError::StartServer { ref err } => err.category(),
+            LoopbackRendezvousError::NoLoopbackAddress => ErrorCategory::Unsupported,
+            LoopbackRendezvousError::MapSocket { ref err } => err.category(),
+            LoopbackRendezvousError::PunchHole { ref err } => err.category(),
+            LoopbackRendezvousError::PunchThreadDied => ErrorCategory::Protocol,
+        }
+    }
+}
+
+/// Run a complete udp rendezvous over loopback.
+///
+/// This starts a `SimpleUdpHolePunchServer`, creates two `MappingContext`s which know about it,
+/// maps a socket with each and punches a hole between them. On success the two punched sockets
+/// are returned, each connected to the other. This is a one-call sanity check that the whole
+/// pipeline works on this machine. Warnings are discarded.
+pub fn loopback_udp_rendezvous(deadline: Instant)
+    -> Result<(PunchedUdpSocket, PunchedUdpSocket), LoopbackRendezvousError>
+{
+    let server_mc = match MappingContext::new().result_discard() {
+        Ok(mc) => mc,
+        Err(e) => return Err(LoopbackRendezvousError::CreateMappingContext { err: e }),
+    };
+    let server = match SimpleUdpHolePunchServer::new(Box::new(server_mc), deadline) {
+        WOk(server, _) => server,
+        WErr(e) => return Err(LoopbackRendezvousError::StartServer { err: e }),
+    };
+    let server_addr = match server.addresses().into_iter().find(|addr| {
+        socket_utils::is_loopback(&addr.ip())
+    }) {
+        Some(addr) => addr,
+        None => return Err(LoopbackRendezvousError::NoLoopbackAddress),
+    };
+
+    let mapped_socket_0 = try!(map_peer_socket(server_addr, deadline));
+    let mapped_socket_1 = try!(map_peer_socket(server_addr, deadline));
+
+    let (priv_info_0, pub_info_0) = gen_rendezvous_info(mapped_socket_0.endpoints);
+    let (priv_info_1, pub_info_1) = gen_rendezvous_info(mapped_socket_1.endpoints);
+
+    // Both ends have to punch at the same time so one of them runs on another thread.
+    let socket_1 = mapped_socket_1.socket;
+    let (tx, rx) = mpsc::channel();
+    let joiner = thread!("loopback_udp_rendezvous punch", move || {
+        let res = PunchedUdpSocket::punch_hole(socket_1, priv_info_1, pub_info_0, deadline);
+        let _ = tx.send(res.result_discard());
+    });
+    let res_0 = PunchedUdpSocket::punch_hole(mapped_socket_0.socket,
+                                             priv_info_0,
+                                             pub_info_1,
+                                             deadline).result_discard();
+    let res_1 = rx.recv();
+    let _ = joiner.join();
+    drop(server);
+    let res_1 = match res_1 {
+        Ok(res_1) => res_1,
+        Err(_) => return Err(LoopbackRendezvousError::PunchThreadDied),
+    };
+
+    match (res_0, res_1) {
+        (Ok(punched_0), Ok(punched_1)) => Ok((punched_0, punched_1)),
+        (Err(e), _) | (_, Err(e)) => Err(LoopbackRendezvousError::PunchHole { err: e }),
+    }
+}
+
+fn map_peer_socket(server_addr: SocketAddr, deadline: Instant)
+    -> Result<MappedUdpSocket, LoopbackRendezvousError>
+{
+    let mc = match MappingContext::new().result_discard() {
+        Ok(mc) => mc,
+        Err(e) => return Err(LoopbackRendezvousError::CreateMappingContext { err: e }),
+    };
+    mc.add_simple_udp_servers(Some(server_addr));
+    match MappedUdpSocket::new(&mc, deadline) {
+        WOk(mapped_socket, _) => Ok(mapped_socket),
+        WErr(e) => Err(LoopbackRendezvousError::MapSocket { err: e }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Instant, Duration};
+
+    use punched_udp_socket::filter_udp_hole_punch_packet;
+
+    #[test]
+    fn loopback_rendezvous_connects_both_peers() {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let (punched_0, punched_1) = unwrap_result!(loopback_udp_rendezvous(deadline));
+        assert_eq!(punched_0.peer_addr.port(), unwrap_result!(punched_1.socket.local_addr()).port());
+
+        let data = b"loopback";
+        let _ = unwrap_result!(punched_1.socket.send_to(&data[..], &*punched_1.peer_addr));
+        let mut buf = [0u8; 64];
+        loop {
+            let (n, _) = unwrap_result!(punched_0.socket.recv_from(&mut buf[..]));
+            match filter_udp_hole_punch_packet(&buf[..n]) {
+                Some(d) => {
+                    assert_eq!(d, &data[..]);
+                    break;
+                },
+                None => continue,
+            }
+        }
+    }
+}
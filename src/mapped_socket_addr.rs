@@ -18,8 +18,69 @@
 //! # `nat_traversal`
 //! NAT traversal utilities.
 
+use std::fmt;
+use std::io;
+use std::net::{self, IpAddr};
+use std::str::FromStr;
+
 use socket_addr::SocketAddr;
 
+use error_code::{ErrorCategory, ErrorCode};
+use subnetting::Ipv6Tunnel;
+
+// The suffixes of the text form which stand for the flags.
+const RESTRICTED_FLAG: &'static str = "restricted";
+const PORT_UNKNOWN_FLAG: &'static str = "port-unknown";
+const UNVERIFIED_FLAG: &'static str = "unverified";
+
+quick_error! {
+    /// Errors returned when parsing a `MappedSocketAddr`.
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum ParseMappedSocketAddrError {
+        /// The text doesn't start with a socket address.
+        Addr { s: String } {
+            description("The text doesn't start with a socket address.")
+            display("\"{}\" doesn't start with a socket address.", s)
+        }
+        /// One of the flags following the address isn't known.
+        Flag { flag: String } {
+            description("Unknown endpoint flag.")
+            display("Unknown endpoint flag \"{}\".", flag)
+        }
+    }
+}
+
+impl From<ParseMappedSocketAddrError> for io::Error {
+    fn from(e: ParseMappedSocketAddrError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e))
+    }
+}
+
+impl ErrorCode for ParseMappedSocketAddrError {
+    fn code(&self) -> u32 {
+        match *self {
+            ParseMappedSocketAddrError::Addr { .. } => 3301,
+            ParseMappedSocketAddrError::Flag { .. } => 3302,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        ErrorCategory::Configuration
+    }
+}
+
+/// The kinds of endpoint, in the terms of ICE candidate types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
+pub enum CandidateClass {
+    /// Reachable directly: an interface address or an address mapped through UPnP or a full-cone
+    /// NAT.
+    Host,
+    /// An address a server saw, which only lets packets through once a hole has been punched.
+    Reflexive,
+    /// An address whose port is a guess.
+    Predicted,
+}
+
 /// A socket address obtained through some mapping technique.
 #[derive(Debug, PartialEq, Eq, Clone, RustcEncodable, RustcDecodable)]
 pub struct MappedSocketAddr {
@@ -30,5 +91,199 @@ pub struct MappedSocketAddr {
     /// address. `nat_restricted` will not be set if this is a fully mapped address such as the
     /// external address of a full-cone NAT or one obtained through UPnP.
     pub nat_restricted: bool,
+
+    /// Indicates that only the ip address of this endpoint is known. The port is a guess, usually
+    /// the local port of the socket on the assumption that the NAT preserves ports.
+    pub port_unknown: bool,
+
+    /// Indicates that a server reported this address but couldn't confirm it by reaching us there
+    /// with a challenge we echoed back. The report may have been spoofed or stale, so peers should
+    /// prefer other endpoints.
+    pub unverified: bool,
+}
+
+impl MappedSocketAddr {
+    /// The kind of ipv4 tunnel this endpoint is reached through, if it's a Teredo or 6to4
+    /// address.
+    pub fn ipv6_tunnel(&self) -> Option<Ipv6Tunnel> {
+        match *self.addr {
+            net::SocketAddr::V6(ref addr_v6) => Ipv6Tunnel::classify(addr_v6.ip()),
+            net::SocketAddr::V4(..) => None,
+        }
+    }
+
+    /// An endpoint at `addr` that can be connected to directly, such as one of our own interface
+    /// addresses. Set the flags afterwards for endpoints learned some other way.
+    pub fn direct(addr: net::SocketAddr) -> MappedSocketAddr {
+        MappedSocketAddr {
+            addr: SocketAddr(addr),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        }
+    }
+
+    /// What kind of endpoint this is.
+    pub fn candidate_class(&self) -> CandidateClass {
+        if self.port_unknown {
+            CandidateClass::Predicted
+        } else if self.nat_restricted {
+            CandidateClass::Reflexive
+        } else {
+            CandidateClass::Host
+        }
+    }
+
+    /// The address as a std type.
+    pub fn socket_addr(&self) -> net::SocketAddr {
+        *self.addr
+    }
+
+    /// The ip address of the endpoint.
+    pub fn ip(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    /// The port of the endpoint. Only a guess if `port_unknown` is set.
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// The scope id of an ipv6 endpoint, which says which interface a link-local address belongs
+    /// to. `None` for ipv4 endpoints.
+    pub fn scope_id(&self) -> Option<u32> {
+        match *self.addr {
+            net::SocketAddr::V6(ref addr_v6) => Some(addr_v6.scope_id()),
+            net::SocketAddr::V4(..) => None,
+        }
+    }
+
+    /// Whether a peer can connect to this endpoint without hole punching or guessing, ie. none of
+    /// the flags are set.
+    pub fn is_direct(&self) -> bool {
+        !self.nat_restricted && !self.port_unknown && !self.unverified
+    }
 }
 
+impl From<net::SocketAddr> for MappedSocketAddr {
+    fn from(addr: net::SocketAddr) -> MappedSocketAddr {
+        MappedSocketAddr::direct(addr)
+    }
+}
+
+impl From<SocketAddr> for MappedSocketAddr {
+    fn from(addr: SocketAddr) -> MappedSocketAddr {
+        MappedSocketAddr::direct(*addr)
+    }
+}
+
+impl From<MappedSocketAddr> for net::SocketAddr {
+    fn from(addr: MappedSocketAddr) -> net::SocketAddr {
+        *addr.addr
+    }
+}
+
+impl From<MappedSocketAddr> for SocketAddr {
+    fn from(addr: MappedSocketAddr) -> SocketAddr {
+        addr.addr
+    }
+}
+
+// The address followed by a `!` and the name of each flag that's set, eg.
+// `203.0.113.5:4000!restricted`.
+impl fmt::Display for MappedSocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", *self.addr));
+        if self.nat_restricted {
+            try!(write!(f, "!{}", RESTRICTED_FLAG));
+        }
+        if self.port_unknown {
+            try!(write!(f, "!{}", PORT_UNKNOWN_FLAG));
+        }
+        if self.unverified {
+            try!(write!(f, "!{}", UNVERIFIED_FLAG));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for MappedSocketAddr {
+    type Err = ParseMappedSocketAddrError;
+
+    fn from_str(s: &str) -> Result<MappedSocketAddr, ParseMappedSocketAddrError> {
+        let mut parts = s.split('!');
+        let addr = match parts.next().and_then(|addr| addr.parse().ok()) {
+            Some(addr) => addr,
+            None => return Err(ParseMappedSocketAddrError::Addr { s: s.to_owned() }),
+        };
+        let mut mapped = MappedSocketAddr::direct(addr);
+        for flag in parts {
+            match flag {
+                RESTRICTED_FLAG => mapped.nat_restricted = true,
+                PORT_UNKNOWN_FLAG => mapped.port_unknown = true,
+                UNVERIFIED_FLAG => mapped.unverified = true,
+                _ => return Err(ParseMappedSocketAddrError::Flag { flag: flag.to_owned() }),
+            }
+        }
+        Ok(mapped)
+    }
+}
+
+// Comparisons with plain addresses ignore the flags.
+impl PartialEq<net::SocketAddr> for MappedSocketAddr {
+    fn eq(&self, other: &net::SocketAddr) -> bool {
+        *self.addr == *other
+    }
+}
+
+impl PartialEq<MappedSocketAddr> for net::SocketAddr {
+    fn eq(&self, other: &MappedSocketAddr) -> bool {
+        *self == *other.addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{self, SocketAddrV6};
+
+    #[test]
+    fn converts_to_and_from_std_addresses() {
+        let addr: net::SocketAddr = unwrap_result!("192.0.2.1:5483".parse());
+        let mut mapped = MappedSocketAddr::from(addr);
+        assert!(mapped.is_direct());
+        assert_eq!(mapped, addr);
+        assert_eq!(addr, mapped);
+
+        mapped.nat_restricted = true;
+        assert!(!mapped.is_direct());
+        assert_eq!(mapped, addr);
+        assert_eq!(mapped.scope_id(), None);
+        assert_eq!(net::SocketAddr::from(mapped), addr);
+
+        let link_local = unwrap_result!("fe80::1".parse());
+        let addr = net::SocketAddr::V6(SocketAddrV6::new(link_local, 5483, 0, 3));
+        let mapped = MappedSocketAddr::from(addr);
+        assert_eq!(mapped.scope_id(), Some(3));
+        assert_eq!(mapped.port(), 5483);
+    }
+
+    #[test]
+    fn text_form_round_trips() {
+        let mapped = MappedSocketAddr {
+            nat_restricted: true,
+            unverified: true,
+            ..MappedSocketAddr::direct(unwrap_result!("[2001:db8::1]:4000".parse()))
+        };
+        let text = format!("{}", mapped);
+        assert_eq!(text, "[2001:db8::1]:4000!restricted!unverified");
+        assert_eq!(unwrap_result!(text.parse::<MappedSocketAddr>()), mapped);
+
+        assert_eq!("192.0.2.1:80".parse::<MappedSocketAddr>(),
+                   Ok(MappedSocketAddr::direct(unwrap_result!("192.0.2.1:80".parse()))));
+        assert_eq!("192.0.2.1:80!sideways".parse::<MappedSocketAddr>(),
+                   Err(ParseMappedSocketAddrError::Flag { flag: "sideways".to_owned() }));
+        assert!("192.0.2.1".parse::<MappedSocketAddr>().is_err());
+    }
+}
@@ -18,6 +18,7 @@
 //! # `nat_traversal`
 //! NAT traversal utilities.
 
+use std::cmp;
 use std::net;
 use std::net::{IpAddr, Ipv4Addr, TcpStream};
 use std::io;
@@ -30,21 +31,26 @@ use std::fmt;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use igd;
+use gateway as igd;
 use net2;
 use socket_addr::SocketAddr;
 use w_result::{WResult, WErr, WOk};
-use maidsafe_utilities::serialisation::{deserialise, SerialisationError};
+use maidsafe_utilities::serialisation::SerialisationError;
 use rand::random;
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 
+use dns_discovery::DnsDiscoveryError;
+use error_code::{ErrorCategory, ErrorCode};
 use mapping_context::MappingContext;
 use mapped_socket_addr::MappedSocketAddr;
+use privacy::Redacted;
 use rendezvous_info::{PrivRendezvousInfo, PubRendezvousInfo};
 use rendezvous_info;
 use socket_utils;
 use mapping_context;
 use listener_message;
+use event::{Event, EventSender, Strategy, Trickle, push_endpoint};
+use timeouts::Timeouts;
 use utils::DisplaySlice;
 
 /// A tcp socket for which we know our external endpoints.
@@ -57,6 +63,15 @@ pub struct MappedTcpSocket {
     pub endpoints: Vec<MappedSocketAddr>,
 }
 
+impl fmt::Debug for MappedTcpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MappedTcpSocket")
+         .field("local_addr", &self.socket.local_addr().ok())
+         .field("endpoints", &self.endpoints)
+         .finish()
+    }
+}
+
 quick_error! {
     /// Errors returned by MappedTcpSocket::map
     #[derive(Debug)]
@@ -85,6 +100,20 @@ impl From<MappedTcpSocketMapError> for io::Error {
     }
 }
 
+impl ErrorCode for MappedTcpSocketMapError {
+    fn code(&self) -> u32 {
+        match *self {
+            MappedTcpSocketMapError::SocketLocalAddr { .. } => 801,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            MappedTcpSocketMapError::SocketLocalAddr { .. } => ErrorCategory::Configuration,
+        }
+    }
+}
+
 quick_error! {
     /// Warnings raised by MappedTcpSocket::map
     #[derive(Debug)]
@@ -108,7 +137,7 @@ quick_error! {
                          gateway")
             display("Error mapping external address and port through IGD \
                      gateway at address {}. igd::Gateway::get_any_address \
-                     returned an error: {}", gateway_addr, err)
+                     returned an error: {}", Redacted(gateway_addr), err)
             cause(err)
         }
         /// Error creating a reusably bound temporary socket for mapping.
@@ -124,7 +153,7 @@ quick_error! {
         } {
             description("Error connecting to a mapping server.")
             display("Error connecting to mapping server at address {}. connect() returned an \
-                     error: {}", addr, err)
+                     error: {}", Redacted(addr), err)
             cause(err)
         }
         /// Error writing to temporary socket.
@@ -145,13 +174,48 @@ quick_error! {
                          you've connected to a mapping server?")
             display("Error deserialising a response from mapping server at address {}: {}. \
                      Response: \"{}\". Are you sure you've connected to a mapping server?",
-                     addr, err, {
+                     Redacted(addr), err, {
                          match str::from_utf8(response) {
                              Ok(r) => r,
                              Err(e) => "<Response contains binary data>",
                          }
                      }
             )
+            cause(err)
+        }
+        /// Error resolving the simple servers of the discovery domain through DNS.
+        DiscoverServers { err: DnsDiscoveryError } {
+            description("Error resolving simple servers through DNS")
+            display("Error resolving simple servers through DNS: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl ErrorCode for MappedTcpSocketMapWarning {
+    fn code(&self) -> u32 {
+        match *self {
+            MappedTcpSocketMapWarning::FindGateway { .. } => 901,
+            MappedTcpSocketMapWarning::GetExternalPort { .. } => 902,
+            MappedTcpSocketMapWarning::NewReusablyBoundTcpSocket { .. } => 903,
+            MappedTcpSocketMapWarning::MappingSocketConnect { .. } => 904,
+            MappedTcpSocketMapWarning::MappingSocketWrite { .. } => 905,
+            MappedTcpSocketMapWarning::MappingSocketRead { .. } => 906,
+            MappedTcpSocketMapWarning::Deserialise { .. } => 907,
+            MappedTcpSocketMapWarning::DiscoverServers { .. } => 908,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            MappedTcpSocketMapWarning::FindGateway { .. } => ErrorCategory::Network,
+            MappedTcpSocketMapWarning::GetExternalPort { .. } => ErrorCategory::Network,
+            MappedTcpSocketMapWarning::NewReusablyBoundTcpSocket { ref err, .. } => err.category(),
+            MappedTcpSocketMapWarning::MappingSocketConnect { .. } => ErrorCategory::Network,
+            MappedTcpSocketMapWarning::MappingSocketWrite { .. } => ErrorCategory::Network,
+            MappedTcpSocketMapWarning::MappingSocketRead { .. } => ErrorCategory::Network,
+            MappedTcpSocketMapWarning::Deserialise { .. } => ErrorCategory::Protocol,
+            MappedTcpSocketMapWarning::DiscoverServers { ref err, .. } => err.category(),
         }
     }
 }
@@ -172,6 +236,11 @@ quick_error! {
             display("Error mapping new socket: {}", err)
             cause(err)
         }
+        /// There's no interface with the requested name, or it has no ipv4 address.
+        NoSuchInterface { if_name: String } {
+            description("No interface with the requested name and an ipv4 address")
+            display("There's no interface named {} with an ipv4 address", if_name)
+        }
     }
 }
 
@@ -187,11 +256,30 @@ impl From<MappedTcpSocketNewError> for io::Error {
                 let err: io::Error = From::from(err);
                 err.kind()
             },
+            MappedTcpSocketNewError::NoSuchInterface { .. } => io::ErrorKind::NotFound,
         };
         io::Error::new(kind, err_str)
     }
 }
 
+impl ErrorCode for MappedTcpSocketNewError {
+    fn code(&self) -> u32 {
+        match *self {
+            MappedTcpSocketNewError::NewReusablyBoundTcpSocket { .. } => 1001,
+            MappedTcpSocketNewError::Map { .. } => 1002,
+            MappedTcpSocketNewError::NoSuchInterface { .. } => 1003,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            MappedTcpSocketNewError::NewReusablyBoundTcpSocket { ref err, .. } => err.category(),
+            MappedTcpSocketNewError::Map { ref err, .. } => err.category(),
+            MappedTcpSocketNewError::NoSuchInterface { .. } => ErrorCategory::Configuration,
+        }
+    }
+}
+
 quick_error! {
     /// Errors returned by new_reusably_bound_tcp_socket
     #[derive(Debug)]
@@ -227,6 +315,22 @@ quick_error! {
                      set", err)
             cause(err)
         }
+        /// Another socket is bound to the provided address with SO_EXCLUSIVEADDRUSE set (on
+        /// Windows), so no other socket can share it.
+        ExclusivelyBound { err: io::Error } {
+            description("Another socket is bound to the provided address with \
+                         SO_EXCLUSIVEADDRUSE set")
+            display("Error binding new socket to the provided address: {}. Another socket is \
+                     bound to it with SO_EXCLUSIVEADDRUSE set so it can't be shared", err)
+            cause(err)
+        }
+        /// Error pinning the new socket to the interface of the socket it shares a port with.
+        BindToInterface { err: io::Error } {
+            description("Error pinning new socket to an interface")
+            display("Error pinning new socket to the interface of the socket it shares a port \
+                     with: {}", err)
+            cause(err)
+        }
     }
 }
 
@@ -238,15 +342,61 @@ impl From<NewReusablyBoundTcpSocketError> for io::Error {
             NewReusablyBoundTcpSocketError::EnableReuseAddr { err } => err.kind(),
             NewReusablyBoundTcpSocketError::EnableReusePort { err } => err.kind(),
             NewReusablyBoundTcpSocketError::Bind { err } => err.kind(),
+            NewReusablyBoundTcpSocketError::ExclusivelyBound { err } => err.kind(),
+            NewReusablyBoundTcpSocketError::BindToInterface { err } => err.kind(),
         };
         io::Error::new(kind, err_str)
     }
 }
 
+impl ErrorCode for NewReusablyBoundTcpSocketError {
+    fn code(&self) -> u32 {
+        match *self {
+            NewReusablyBoundTcpSocketError::Create { .. } => 1101,
+            NewReusablyBoundTcpSocketError::EnableReuseAddr { .. } => 1102,
+            NewReusablyBoundTcpSocketError::EnableReusePort { .. } => 1103,
+            NewReusablyBoundTcpSocketError::Bind { .. } => 1104,
+            NewReusablyBoundTcpSocketError::ExclusivelyBound { .. } => 1105,
+            NewReusablyBoundTcpSocketError::BindToInterface { .. } => 1106,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            NewReusablyBoundTcpSocketError::Create { .. } => ErrorCategory::Network,
+            NewReusablyBoundTcpSocketError::EnableReuseAddr { .. } => ErrorCategory::Unsupported,
+            NewReusablyBoundTcpSocketError::EnableReusePort { .. } => ErrorCategory::Unsupported,
+            NewReusablyBoundTcpSocketError::Bind { .. } => ErrorCategory::Configuration,
+            NewReusablyBoundTcpSocketError::ExclusivelyBound { .. } => ErrorCategory::Configuration,
+            NewReusablyBoundTcpSocketError::BindToInterface { .. } => ErrorCategory::Configuration,
+        }
+    }
+}
+
 pub fn new_reusably_bound_tcp_socket(local_addr: &net::SocketAddr) -> Result<net2::TcpBuilder, NewReusablyBoundTcpSocketError> {
-    let socket_res = match local_addr.ip() {
-        IpAddr::V4(..) => net2::TcpBuilder::new_v4(),
-        IpAddr::V6(..) => net2::TcpBuilder::new_v6(),
+    new_reusably_bound(local_addr, false)
+}
+
+/// Like `new_reusably_bound_tcp_socket` but the socket speaks MPTCP where the OS supports it, so
+/// that a punched stream accepted on it can later add subflows over other interfaces. Elsewhere
+/// it's a plain tcp socket.
+pub fn new_reusably_bound_mptcp_socket(local_addr: &net::SocketAddr)
+                                       -> Result<net2::TcpBuilder, NewReusablyBoundTcpSocketError> {
+    new_reusably_bound(local_addr, true)
+}
+
+fn new_reusably_bound(local_addr: &net::SocketAddr, mptcp: bool)
+                      -> Result<net2::TcpBuilder, NewReusablyBoundTcpSocketError> {
+    let ipv6 = socket_utils::is_ipv6(local_addr);
+    let mptcp_socket = if mptcp {
+        socket_utils::new_mptcp_socket(ipv6).ok()
+    } else {
+        None
+    };
+    let socket_res = match mptcp_socket {
+        Some(socket) => Ok(socket),
+        None if ipv6 => net2::TcpBuilder::new_v6(),
+        None => net2::TcpBuilder::new_v4(),
     };
     let socket = match socket_res {
         Ok(socket) => socket,
@@ -262,16 +412,61 @@ pub fn new_reusably_bound_tcp_socket(local_addr: &net::SocketAddr) -> Result<net
     };
     match socket.bind(local_addr) {
         Ok(..) => (),
+        Err(ref e) if socket_utils::is_exclusive_bind_error(e) => {
+            let err = io::Error::new(e.kind(), format!("{}", e));
+            return Err(NewReusablyBoundTcpSocketError::ExclusivelyBound { err: err });
+        },
         Err(e) => return Err(NewReusablyBoundTcpSocketError::Bind { err: e }),
     };
     Ok(socket)
 }
 
+// Like `new_reusably_bound_tcp_socket` but pins the socket to the interface `if_name`, if given,
+// and makes it an MPTCP socket if `mptcp` and the OS supports it. Sockets sharing a port with a
+// pinned socket must be pinned too for their connections to leave through the same interface.
+fn new_reusably_bound_tcp_socket_on(local_addr: &net::SocketAddr,
+                                    if_name: Option<&str>,
+                                    mptcp: bool)
+                                    -> Result<net2::TcpBuilder, NewReusablyBoundTcpSocketError> {
+    let socket = try!(new_reusably_bound(local_addr, mptcp));
+    if let Some(if_name) = if_name {
+        let ipv6 = socket_utils::is_ipv6(local_addr);
+        match socket_utils::bind_to_interface(&socket, if_name, ipv6) {
+            Ok(()) => (),
+            Err(e) => return Err(NewReusablyBoundTcpSocketError::BindToInterface { err: e }),
+        }
+    }
+    Ok(socket)
+}
+
 impl MappedTcpSocket {
     /// Map an existing tcp socket. The socket must bound but not connected. It must have been
-    /// bound with SO_REUSEADDR and SO_REUSEPORT options (or equivalent) set.
+    /// bound with SO_REUSEADDR and SO_REUSEPORT options set or, on Windows, with SO_REUSEADDR set
+    /// and SO_EXCLUSIVEADDRUSE clear. `new_reusably_bound_tcp_socket` binds sockets like this.
     pub fn map(socket: net2::TcpBuilder, mc: &MappingContext, deadline: Instant)
                -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketMapError>
+    {
+        Self::map_impl(socket, mc, deadline, None)
+    }
+
+    /// Like `map` but calls `on_candidate` with each endpoint as soon as it's found, so that it
+    /// can be trickled to the peer rather than waiting for the slowest server. Only endpoints the
+    /// context's policies allow to be shared are passed on. The returned socket lists all of
+    /// them, ordered best first.
+    pub fn map_trickle(socket: net2::TcpBuilder,
+                       mc: &MappingContext,
+                       deadline: Instant,
+                       on_candidate: &Fn(&MappedSocketAddr))
+        -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketMapError>
+    {
+        Self::map_impl(socket, mc, deadline, Some(on_candidate))
+    }
+
+    fn map_impl(socket: net2::TcpBuilder,
+                mc: &MappingContext,
+                deadline: Instant,
+                on_candidate: Option<&Fn(&MappedSocketAddr)>)
+        -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketMapError>
     {
         let mut endpoints = Vec::new();
         let mut warnings = Vec::new();
@@ -280,6 +475,17 @@ impl MappedTcpSocket {
             Ok(local_addr) => local_addr,
             Err(e) => return WErr(MappedTcpSocketMapError::SocketLocalAddr { err: e }),
         };
+        // If the socket is pinned to an interface, query the servers through it too.
+        let ipv6 = socket_utils::is_ipv6(&local_addr);
+        let if_name = socket_utils::bound_interface(&socket, ipv6).unwrap_or(None);
+        let events = mapping_context::events(mc);
+        let timeouts = mc.timeouts();
+        let trickle = Trickle::new(mc, on_candidate);
+        events.send(Event::GatheringStarted { local_addr: SocketAddr(local_addr) });
+
+        // Don't bother with IGD if it's compiled out or has kept failing on this network.
+        let use_igd = cfg!(feature = "upnp") &&
+                      !mapping_context::should_skip_strategy(mc, Strategy::Igd);
         match local_addr.ip() {
             IpAddr::V4(ipv4_addr) => {
                 if socket_utils::ipv4_is_unspecified(&ipv4_addr) {
@@ -288,22 +494,29 @@ impl MappedTcpSocket {
                     // an address.
                     for iface_v4 in mapping_context::interfaces_v4(&mc) {
                         let local_iface_addr = net::SocketAddrV4::new(iface_v4.addr, local_addr.port());
-                        endpoints.push(MappedSocketAddr {
+                        push_endpoint(&mut endpoints, &trickle, Strategy::LocalInterface, MappedSocketAddr {
                             addr: SocketAddr(net::SocketAddr::V4(local_iface_addr)),
                             nat_restricted: false,
+                            port_unknown: false,
+                            unverified: false,
                         });
-                        if let Some(gateway) = iface_v4.gateway {
+                        let gateway_opt = if use_igd { iface_v4.gateway } else { None };
+                        if let Some(gateway) = gateway_opt {
                             match gateway.get_any_address(igd::PortMappingProtocol::TCP,
                                                           local_iface_addr, 0,
                                                           "rust nat_traversal")
                             {
                                 Ok(external_addr) => {
-                                    endpoints.push(MappedSocketAddr {
+                                    mc.record_strategy_result(Strategy::Igd, true);
+                                    push_endpoint(&mut endpoints, &trickle, Strategy::Igd, MappedSocketAddr {
                                         addr: SocketAddr(net::SocketAddr::V4(external_addr)),
                                         nat_restricted: false,
+                                        port_unknown: false,
+                                        unverified: false,
                                     });
                                 },
                                 Err(e) => {
+                                    mc.record_strategy_result(Strategy::Igd, false);
                                     warnings.push(MappedTcpSocketMapWarning::GetExternalPort {
                                         gateway_addr: gateway.addr,
                                         err: e,
@@ -315,9 +528,11 @@ impl MappedTcpSocket {
                 }
                 else {
                     let local_addr_v4 = net::SocketAddrV4::new(ipv4_addr, local_addr.port());
-                    endpoints.push(MappedSocketAddr {
+                    push_endpoint(&mut endpoints, &trickle, Strategy::LocalInterface, MappedSocketAddr {
                         addr: SocketAddr(net::SocketAddr::V4(local_addr_v4)),
                         nat_restricted: false,
+                        port_unknown: false,
+                        unverified: false,
                     });
 
                     // If the local address is the address of an interface then we can avoid
@@ -331,11 +546,13 @@ impl MappedTcpSocket {
                         }
                     };
                     let gateway_opt = match gateway_opt_opt {
+                        _ if !use_igd => None,
                         Some(gateway_opt) => gateway_opt,
                         // We don't where this local address came from so search for an IGD gateway
                         // at it.
                         None => {
-                            match igd::search_gateway_from_timeout(ipv4_addr, Duration::from_secs(1)) {
+                            let timeout = timeouts.server_query;
+                            match igd::search_gateway_from_timeout(ipv4_addr, timeout) {
                                 Ok(gateway) => Some(gateway),
                                 Err(e) => {
                                     warnings.push(MappedTcpSocketMapWarning::FindGateway {
@@ -353,12 +570,16 @@ impl MappedTcpSocket {
                                                       "rust nat_traversal")
                         {
                             Ok(external_addr) => {
-                                endpoints.push(MappedSocketAddr {
+                                mc.record_strategy_result(Strategy::Igd, true);
+                                push_endpoint(&mut endpoints, &trickle, Strategy::Igd, MappedSocketAddr {
                                     addr: SocketAddr(net::SocketAddr::V4(external_addr)),
                                     nat_restricted: false,
+                                    port_unknown: false,
+                                    unverified: false,
                                 });
                             },
                             Err(e) => {
+                                mc.record_strategy_result(Strategy::Igd, false);
                                 warnings.push(MappedTcpSocketMapWarning::GetExternalPort {
                                     gateway_addr: gateway.addr,
                                     err: e,
@@ -373,16 +594,20 @@ impl MappedTcpSocket {
                     // If the socket address is unspecified add an address for every interface.
                     for iface_v6 in mapping_context::interfaces_v6(&mc) {
                         let local_iface_addr = net::SocketAddr::V6(net::SocketAddrV6::new(iface_v6.addr, local_addr.port(), 0, 0));
-                        endpoints.push(MappedSocketAddr {
+                        push_endpoint(&mut endpoints, &trickle, Strategy::LocalInterface, MappedSocketAddr {
                             addr: SocketAddr(local_iface_addr),
                             nat_restricted: false,
+                            port_unknown: false,
+                            unverified: false,
                         });
                     };
                 }
                 else {
-                    endpoints.push(MappedSocketAddr {
+                    push_endpoint(&mut endpoints, &trickle, Strategy::LocalInterface, MappedSocketAddr {
                         addr: SocketAddr(net::SocketAddr::V6(net::SocketAddrV6::new(ipv6_addr, local_addr.port(), 0, 0))),
                         nat_restricted: false,
+                        port_unknown: false,
+                        unverified: false,
                     });
                 }
             },
@@ -390,8 +615,21 @@ impl MappedTcpSocket {
         
         let (results_tx, results_rx) = mpsc::channel();
         let mut mapping_threads = Vec::new();
+        if let Err(e) = mapping_context::refresh_stale_discovered_servers(mc, deadline) {
+            warnings.push(MappedTcpSocketMapWarning::DiscoverServers { err: e });
+        }
         let simple_servers = mapping_context::simple_tcp_servers(&mc);
+        let mut simple_server_responded = false;
+        if !simple_servers.is_empty() {
+            events.send(Event::StrategyChanged { strategy: Strategy::SimpleServer });
+        }
+        // Each server gets its own connection, so only query as many as the limits allow.
+        let max_server_queries = mc.concurrency_limits().max_server_queries;
+        let mut queried = 0;
         for simple_server in simple_servers {
+            if queried >= max_server_queries {
+                break;
+            }
             // TODO(canndrew): Remove this. Ideally we should use servers that are on private
             // networks in case we're behind multiple private networks. This will require using
             // non-blocking IO however.
@@ -407,10 +645,16 @@ impl MappedTcpSocket {
                     };
                 },
             };
+            queried += 1;
             let results_tx = results_tx.clone();
+            let if_name = if_name.clone();
+            let server_query = timeouts.server_query;
             mapping_threads.push(thread::spawn(move || {
                 let map = move || {
-                    let mapping_socket = match new_reusably_bound_tcp_socket(&local_addr) {
+                    let if_name = if_name.as_ref().map(|if_name| &if_name[..]);
+                    let mapping_socket = match new_reusably_bound_tcp_socket_on(&local_addr,
+                                                                                if_name,
+                                                                                false) {
                         Ok(mapping_socket) => mapping_socket,
                         Err(e) => return Err(MappedTcpSocketMapWarning::NewReusablyBoundTcpSocket { err: e }),
                     };
@@ -421,6 +665,19 @@ impl MappedTcpSocket {
                             err: e
                         }),
                     };
+                    // Don't let a server which accepts but never answers hold this thread.
+                    match stream.set_write_timeout(Some(server_query)) {
+                        Ok(()) => (),
+                        Err(e) => {
+                            return Err(MappedTcpSocketMapWarning::MappingSocketWrite { err: e })
+                        },
+                    };
+                    match stream.set_read_timeout(Some(server_query)) {
+                        Ok(()) => (),
+                        Err(e) => {
+                            return Err(MappedTcpSocketMapWarning::MappingSocketRead { err: e })
+                        },
+                    };
                     let send_data = listener_message::REQUEST_MAGIC_CONSTANT;
                     // TODO(canndrew): What should we do if we get a partial write?
                     let _ = match stream.write(&send_data[..]) {
@@ -434,7 +691,7 @@ impl MappedTcpSocket {
                         Ok(n) => n,
                         Err(e) => return Err(MappedTcpSocketMapWarning::MappingSocketRead { err: e }),
                     };
-                    let listener_message::EchoExternalAddr { external_addr } = match deserialise::<listener_message::EchoExternalAddr>(&recv_data[..n]) {
+                    let listener_message::EchoExternalAddr { external_addr } = match listener_message::EchoExternalAddr::decode(&recv_data[..n]) {
                         Ok(msg) => msg,
                         Err(e) => return Err(MappedTcpSocketMapWarning::Deserialise {
                             addr: simple_server,
@@ -459,9 +716,12 @@ impl MappedTcpSocket {
         for result in results_rx {
             match result {
                 Some(Ok(external_addr)) => {
-                    endpoints.push(MappedSocketAddr {
+                    simple_server_responded = true;
+                    push_endpoint(&mut endpoints, &trickle, Strategy::SimpleServer, MappedSocketAddr {
                         addr: external_addr,
                         nat_restricted: true,
+                        port_unknown: false,
+                        unverified: false,
                     });
                 },
                 Some(Err(e)) => {
@@ -474,6 +734,12 @@ impl MappedTcpSocket {
         }
 
         timeout_thread.thread().unpark();
+        if !mapping_threads.is_empty() {
+            mc.record_strategy_result(Strategy::SimpleServer, simple_server_responded);
+        }
+        // Only report what the application is willing to share. Tunneled ipv6 endpoints often
+        // blackhole traffic so have peers try them last.
+        let endpoints = mapping_context::exposed_endpoints(mc, endpoints);
         WOk(MappedTcpSocket {
             socket: socket,
             endpoints: endpoints,
@@ -484,23 +750,74 @@ impl MappedTcpSocket {
     pub fn new(mc: &MappingContext, deadline: Instant)
             -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketNewError>
     {
-        let unspec_addr = net::SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
-        let socket = match new_reusably_bound_tcp_socket(&unspec_addr) {
+        Self::new_impl(mc, None, deadline)
+    }
+
+    /// Like `new` but pins the socket to the network interface `if_name`, eg. `"eth1"`, so that
+    /// the servers are queried, and holes punched, through that interface only. Only the
+    /// interface's own ipv4 address and IGD gateway are used. Pinning is supported on Linux,
+    /// where it needs CAP_NET_RAW before Linux 5.7, and on macOS and iOS.
+    pub fn new_on_interface(mc: &MappingContext, if_name: &str, deadline: Instant)
+            -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketNewError>
+    {
+        Self::new_impl(mc, Some(if_name), deadline)
+    }
+
+    fn new_impl(mc: &MappingContext, if_name: Option<&str>, deadline: Instant)
+            -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketNewError>
+    {
+        let bind_ip = match if_name {
+            Some(if_name) => match mapping_context::interface_bind_ip(mc, if_name, false) {
+                Some(ip) => ip,
+                None => return WErr(MappedTcpSocketNewError::NoSuchInterface {
+                    if_name: if_name.to_owned(),
+                }),
+            },
+            None => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+        };
+        // Try to keep the port of the last socket we mapped. The socket is bound with
+        // SO_REUSEADDR and SO_REUSEPORT so binding it would succeed even if something else is
+        // still using the port. Check that the port is free first.
+        let mut unspec_addr = net::SocketAddr::new(bind_ip, 0);
+        if let Some(port) = mapping_context::port_hint(mc, igd::PortMappingProtocol::TCP) {
+            let hinted_addr = net::SocketAddr::new(unspec_addr.ip(), port);
+            if port_is_free(&hinted_addr) {
+                unspec_addr = hinted_addr;
+            }
+        }
+        let socket = match new_reusably_bound_tcp_socket_on(&unspec_addr, if_name, false) {
             Ok(socket) => socket,
             Err(e) => return WErr(MappedTcpSocketNewError::NewReusablyBoundTcpSocket { err: e }),
         };
 
-        MappedTcpSocket::map(socket, mc, deadline).map_err(|e| MappedTcpSocketNewError::Map { err: e })
+        let res = MappedTcpSocket::map(socket, mc, deadline);
+        if let WOk(ref mapped, _) = res {
+            if let Ok(local_addr) = mapped.socket.local_addr() {
+                mapping_context::record_mapped_port(mc, igd::PortMappingProtocol::TCP,
+                                                    local_addr.port());
+            }
+        }
+        res.map_err(|e| MappedTcpSocketNewError::Map { err: e })
     }
 }
 
+// Whether a socket without SO_REUSEADDR could be bound to `addr`, ie. no other socket is using it.
+fn port_is_free(addr: &net::SocketAddr) -> bool {
+    let socket = match net2::TcpBuilder::new_v4() {
+        Ok(socket) => socket,
+        Err(..) => return false,
+    };
+    let free = socket.bind(addr).is_ok();
+    free
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum TcpPunchHoleWarning {
         /// Connecting to endpoint failed.
         Connect { peer_addr: SocketAddr, err: io::Error } {
             description("Connecting to endpoint failed.")
-            display("Connecting to endpoint {} failed: {}", peer_addr, err)
+            display("Connecting to endpoint {} failed: {}", Redacted(peer_addr), err)
             cause(err)
         }
         /// Error accepting an incoming connection.
@@ -518,17 +835,92 @@ quick_error! {
         /// IO error communicating with a connected host.
         StreamIo { peer_addr: SocketAddr, err: io::Error } {
             description("IO error communicating with a connected host.")
-            display("IO error communicating with connected host at {}: {}", peer_addr, err)
+            display("IO error communicating with connected host at {}: {}",
+                    Redacted(peer_addr), err)
             cause(err)
         }
         /// A connected host provided an invalid response to the handshake.
         InvalidResponse { peer_addr: SocketAddr, data: [u8; 4] } {
             description("A connected host provided an invalid response to the handshake.")
-            display("The connected host at {} provided an invalid response to the handshake: {:?}", peer_addr, data)
+            display("The connected host at {} provided an invalid response to the handshake: {:?}",
+                    Redacted(peer_addr), data)
+        }
+        /// Error turning on keepalives for the punched stream.
+        SetKeepalive { err: io::Error } {
+            description("Error turning on keepalives for the punched stream.")
+            display("Error turning on keepalives for the punched stream: {}", err)
+            cause(err)
+        }
+        /// Error setting the ttl of a low ttl SYN, or restoring it once connected.
+        SetTtl { err: io::Error } {
+            description("Error setting the ttl of a low ttl SYN.")
+            display("Error setting the ttl of a low ttl SYN: {}", err)
+            cause(err)
+        }
+        /// Most attempts to reach the peer were reset, suggesting a middlebox is killing
+        /// connections from our port.
+        ResetStorm { resets: u32, local_port: u16 } {
+            description("Most attempts to reach the peer were reset.")
+            display("{} attempts to reach the peer from local port {} were reset. A middlebox may \
+                     be interfering with connections from that port.", resets, local_port)
+        }
+        /// Error mapping a new socket to retry from after a reset storm.
+        Remap { err: MappedTcpSocketNewError } {
+            description("Error mapping a new socket to retry from after a reset storm.")
+            display("Error mapping a new socket to retry from after a reset storm: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl ErrorCode for TcpPunchHoleWarning {
+    fn code(&self) -> u32 {
+        match *self {
+            TcpPunchHoleWarning::Connect { .. } => 1201,
+            TcpPunchHoleWarning::Accept { .. } => 1202,
+            TcpPunchHoleWarning::StreamSetTimeout { .. } => 1203,
+            TcpPunchHoleWarning::StreamIo { .. } => 1204,
+            TcpPunchHoleWarning::InvalidResponse { .. } => 1205,
+            TcpPunchHoleWarning::SetKeepalive { .. } => 1206,
+            TcpPunchHoleWarning::SetTtl { .. } => 1207,
+            TcpPunchHoleWarning::ResetStorm { .. } => 1208,
+            TcpPunchHoleWarning::Remap { .. } => 1209,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            TcpPunchHoleWarning::Connect { .. } => ErrorCategory::Network,
+            TcpPunchHoleWarning::Accept { .. } => ErrorCategory::Network,
+            TcpPunchHoleWarning::StreamSetTimeout { .. } => ErrorCategory::Network,
+            TcpPunchHoleWarning::StreamIo { .. } => ErrorCategory::Network,
+            TcpPunchHoleWarning::InvalidResponse { .. } => ErrorCategory::Protocol,
+            TcpPunchHoleWarning::SetKeepalive { .. } => ErrorCategory::Network,
+            TcpPunchHoleWarning::SetTtl { .. } => ErrorCategory::Configuration,
+            TcpPunchHoleWarning::ResetStorm { .. } => ErrorCategory::Network,
+            TcpPunchHoleWarning::Remap { ref err } => err.category(),
         }
     }
 }
 
+impl TcpPunchHoleWarning {
+    /// The peer address that this warning relates to, if any.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            TcpPunchHoleWarning::Connect { peer_addr, .. } |
+            TcpPunchHoleWarning::StreamIo { peer_addr, .. } |
+            TcpPunchHoleWarning::InvalidResponse { peer_addr, .. } => Some(peer_addr),
+            TcpPunchHoleWarning::Accept { .. } |
+            TcpPunchHoleWarning::StreamSetTimeout { .. } |
+            TcpPunchHoleWarning::SetKeepalive { .. } |
+            TcpPunchHoleWarning::SetTtl { .. } |
+            TcpPunchHoleWarning::ResetStorm { .. } |
+            TcpPunchHoleWarning::Remap { .. } => None,
+        }
+    }
+}
+
+/// A stream that was punched to the peer but failed before it could be chosen.
 #[derive(Debug)]
 pub struct TcpPunchHoleBrokenStream {
     peer_addr: SocketAddr,
@@ -541,6 +933,16 @@ impl fmt::Display for TcpPunchHoleBrokenStream {
     }
 }
 
+impl ::std::error::Error for TcpPunchHoleBrokenStream {
+    fn description(&self) -> &str {
+        "Hole punched stream broke before it could be used"
+    }
+
+    fn cause(&self) -> Option<&::std::error::Error> {
+        Some(&self.error)
+    }
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum TcpPunchHoleError {
@@ -594,6 +996,256 @@ impl From<TcpPunchHoleError> for io::Error {
     }
 }
 
+impl ErrorCode for TcpPunchHoleError {
+    fn code(&self) -> u32 {
+        match *self {
+            TcpPunchHoleError::SocketLocalAddr { .. } => 1301,
+            TcpPunchHoleError::NewReusablyBoundTcpSocket { .. } => 1302,
+            TcpPunchHoleError::Listen { .. } => 1303,
+            TcpPunchHoleError::TimedOut { .. } => 1304,
+            TcpPunchHoleError::DecideStream { .. } => 1305,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            TcpPunchHoleError::SocketLocalAddr { .. } => ErrorCategory::Configuration,
+            TcpPunchHoleError::NewReusablyBoundTcpSocket { ref err, .. } => err.category(),
+            TcpPunchHoleError::Listen { .. } => ErrorCategory::Network,
+            TcpPunchHoleError::TimedOut { .. } => ErrorCategory::Network,
+            TcpPunchHoleError::DecideStream { .. } => ErrorCategory::Network,
+        }
+    }
+}
+
+/// How a punched tcp stream came about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
+pub enum TcpPunchTechnique {
+    /// We accepted the peer's connection.
+    Accepted,
+    /// Our connection to one of the peer's nat restricted endpoints got through.
+    Connected,
+    /// Our connection to one of the peer's endpoints that needs no hole punching, such as a port
+    /// forwarded with UPnP or a public address, got through.
+    Forwarded,
+    /// The stream goes through a relay rather than straight to the peer.
+    Relayed,
+}
+
+/// A stream obtained by tcp hole punching, along with how it was made.
+#[derive(Debug)]
+pub struct PunchedTcpStream {
+    /// The stream.
+    pub stream: TcpStream,
+    /// How the stream was made.
+    pub technique: TcpPunchTechnique,
+    /// Our end of the stream.
+    pub local_addr: SocketAddr,
+    /// The peer's end of the stream.
+    pub peer_addr: SocketAddr,
+    /// Whether the stream negotiated MPTCP, so that either peer may add subflows to it.
+    pub mptcp: bool,
+}
+
+impl PunchedTcpStream {
+    // Falls back on the address the stream was made with, and the address our socket was bound
+    // to, if the stream can't tell its ends.
+    fn new(stream: TcpStream,
+           peer_addr: SocketAddr,
+           technique: TcpPunchTechnique,
+           local_addr: net::SocketAddr)
+           -> PunchedTcpStream {
+        let local_addr = stream.local_addr().unwrap_or(local_addr);
+        let peer_addr = stream.peer_addr().map(SocketAddr).unwrap_or(peer_addr);
+        let mptcp = socket_utils::is_mptcp(&stream);
+        PunchedTcpStream {
+            stream: stream,
+            technique: technique,
+            local_addr: SocketAddr(local_addr),
+            peer_addr: peer_addr,
+            mptcp: mptcp,
+        }
+    }
+}
+
+/// How long to wait before reconnecting to an endpoint that refused us, by default.
+const DEFAULT_RETRY_INTERVAL_SECS: u64 = 1;
+/// With low ttl SYNs, how long the connecting side waits for the opening side's SYNs to open its
+/// NAT's mappings before connecting.
+const LOW_TTL_HEAD_START_MS: u64 = 500;
+/// With low ttl SYNs, how soon an attempt that was reset or dropped en route is made again.
+const LOW_TTL_RETRY_INTERVAL_MS: u64 = 100;
+/// The ttl a stream opened with a low ttl SYN is given once connected.
+const DEFAULT_TTL: u32 = 64;
+/// How many resets make a reset storm worth moving to another port over.
+const RESET_STORM_RESETS: u32 = 3;
+
+/// Options for tcp hole punching. Start with `new`, set whichever options are needed and finish
+/// with `punch_hole`. `tcp_punch_hole` and `tcp_punch_hole_with_events` are shorthands for it.
+#[derive(Clone)]
+pub struct TcpPunchBuilder<'a> {
+    deadline: Instant,
+    events: Option<&'a EventSender>,
+    retry_interval: Duration,
+    candidate_filter: Option<&'a Fn(&MappedSocketAddr) -> bool>,
+    keepalive: Option<Duration>,
+    check_timeout: Option<Duration>,
+    low_ttl_syn: Option<u32>,
+    mptcp: bool,
+    remap: Option<(&'a MappingContext, &'a Fn(PubRendezvousInfo) -> Option<PubRendezvousInfo>)>,
+}
+
+impl<'a> fmt::Debug for TcpPunchBuilder<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TcpPunchBuilder")
+         .field("deadline", &self.deadline)
+         .field("retry_interval", &self.retry_interval)
+         .field("candidate_filter", &self.candidate_filter.is_some())
+         .field("keepalive", &self.keepalive)
+         .field("check_timeout", &self.check_timeout)
+         .field("low_ttl_syn", &self.low_ttl_syn)
+         .field("mptcp", &self.mptcp)
+         .field("remap", &self.remap.is_some())
+         .finish()
+    }
+}
+
+impl<'a> TcpPunchBuilder<'a> {
+    /// Give up on hole punching at `deadline`. Every other option starts at its default.
+    pub fn new(deadline: Instant) -> TcpPunchBuilder<'a> {
+        TcpPunchBuilder {
+            deadline: deadline,
+            events: None,
+            retry_interval: Duration::from_secs(DEFAULT_RETRY_INTERVAL_SECS),
+            candidate_filter: None,
+            keepalive: None,
+            check_timeout: None,
+            low_ttl_syn: None,
+            mptcp: false,
+            remap: None,
+        }
+    }
+
+    /// Give up on hole punching once `timeouts.connect` has passed from now, and on each of the
+    /// peer's endpoints after `timeouts.check`. Keepalives are sent every `timeouts.keepalive`.
+    pub fn with_timeouts(timeouts: &Timeouts) -> TcpPunchBuilder<'a> {
+        TcpPunchBuilder::new(timeouts.connect_deadline())
+            .keepalive(timeouts.keepalive)
+            .check_timeout(timeouts.check)
+    }
+
+    /// Report the progress of the hole punching through `events`.
+    pub fn events(mut self, events: &'a EventSender) -> TcpPunchBuilder<'a> {
+        self.events = Some(events);
+        self
+    }
+
+    /// Wait `interval` before connecting again to an endpoint whose connection failed. Defaults
+    /// to a second.
+    pub fn retry_interval(mut self, interval: Duration) -> TcpPunchBuilder<'a> {
+        self.retry_interval = interval;
+        self
+    }
+
+    /// Only try the peer's endpoints for which `filter` returns `true`. Connections the peer makes
+    /// to us are accepted whatever their address.
+    pub fn candidate_filter(mut self, filter: &'a Fn(&MappedSocketAddr) -> bool)
+                            -> TcpPunchBuilder<'a> {
+        self.candidate_filter = Some(filter);
+        self
+    }
+
+    /// Stop connecting to the peer's endpoints once `timeout` has passed. Connections the peer
+    /// makes to us are accepted until the deadline. By default endpoints are tried until the
+    /// deadline.
+    pub fn check_timeout(mut self, timeout: Duration) -> TcpPunchBuilder<'a> {
+        self.check_timeout = Some(timeout);
+        self
+    }
+
+    /// Turn on tcp keepalives, sent after `interval` of idleness, on the punched stream so that
+    /// the NAT doesn't drop its mapping while the connection is quiet. Off by default.
+    pub fn keepalive(mut self, interval: Option<Duration>) -> TcpPunchBuilder<'a> {
+        self.keepalive = interval;
+        self
+    }
+
+    /// Punch sequentially with low ttl SYNs, for NATs where simultaneous open fails. One peer
+    /// sends its SYNs with a ttl of `ttl`, enough to open its own NAT's mappings but too little to
+    /// reach the peer's NAT and get reset, then waits for the other peer's SYNs to come through
+    /// them. The other peer connects as usual after giving it a head start, and retries quickly
+    /// when reset. The peer with the lower secret opens. Both peers must turn this on and call
+    /// `punch_hole` at about the same time, eg. at the instant from `synchronized_punch_start`
+    /// after swapping rendezvous info. Off by default.
+    pub fn low_ttl_syn(mut self, ttl: u32) -> TcpPunchBuilder<'a> {
+        self.low_ttl_syn = Some(cmp::max(ttl, 1));
+        self
+    }
+
+    /// Connect to the peer with MPTCP where the OS supports it, falling back to plain tcp where it
+    /// doesn't or the peer doesn't speak it. For the peer's connections to us to use it too,
+    /// `socket` should come from `new_reusably_bound_mptcp_socket`. `PunchedTcpStream::mptcp`
+    /// tells whether the stream ended up using it. Off by default.
+    pub fn mptcp(mut self, mptcp: bool) -> TcpPunchBuilder<'a> {
+        self.mptcp = mptcp;
+        self
+    }
+
+    /// If most attempts to reach the peer are reset, as some carrier middleboxes do to the first
+    /// port a host uses, retry once from a newly mapped socket with fresh rendezvous info. The
+    /// first attempt gets half the time until the deadline. `exchange` is called with our new
+    /// rendezvous info and must swap it for the peer's, or return `None` if the peer won't retry.
+    /// A `TcpPunchHoleWarning::ResetStorm` reports the interference. Off by default.
+    pub fn remap_on_resets(mut self,
+                           mc: &'a MappingContext,
+                           exchange: &'a Fn(PubRendezvousInfo) -> Option<PubRendezvousInfo>)
+                           -> TcpPunchBuilder<'a> {
+        self.remap = Some((mc, exchange));
+        self
+    }
+
+    /// Perform a tcp rendezvous connect. `socket` should have been obtained from a
+    /// `MappedTcpSocket`.
+    pub fn punch_hole(self,
+                      socket: net2::TcpBuilder,
+                      our_priv_rendezvous_info: PrivRendezvousInfo,
+                      their_pub_rendezvous_info: PubRendezvousInfo)
+                      -> WResult<TcpStream, TcpPunchHoleWarning, TcpPunchHoleError> {
+        match self.punch_hole_detailed(socket,
+                                       our_priv_rendezvous_info,
+                                       their_pub_rendezvous_info) {
+            WOk(punched, warnings) => WOk(punched.stream, warnings),
+            WErr(e) => WErr(e),
+        }
+    }
+
+    /// Like `punch_hole` but also tells how the stream was made and between which addresses.
+    pub fn punch_hole_detailed(self,
+                               socket: net2::TcpBuilder,
+                               our_priv_rendezvous_info: PrivRendezvousInfo,
+                               their_pub_rendezvous_info: PubRendezvousInfo)
+                               -> WResult<PunchedTcpStream,
+                                          TcpPunchHoleWarning,
+                                          TcpPunchHoleError> {
+        match self.remap {
+            Some((mc, exchange)) => {
+                tcp_punch_hole_remapping(socket,
+                                         our_priv_rendezvous_info,
+                                         their_pub_rendezvous_info,
+                                         self,
+                                         mc,
+                                         exchange)
+            },
+            None => {
+                tcp_punch_hole_impl(socket,
+                                    our_priv_rendezvous_info,
+                                    their_pub_rendezvous_info,
+                                    self)
+            },
+        }
+    }
+}
+
 /// Perform a tcp rendezvous connect. `socket` should have been obtained from a
 /// `MappedTcpSocket`.
 pub fn tcp_punch_hole(socket: net2::TcpBuilder,
@@ -601,6 +1253,27 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
                       their_pub_rendezvous_info: PubRendezvousInfo,
                       deadline: Instant)
                       -> WResult<TcpStream, TcpPunchHoleWarning, TcpPunchHoleError> {
+    TcpPunchBuilder::new(deadline)
+        .punch_hole(socket, our_priv_rendezvous_info, their_pub_rendezvous_info)
+}
+
+/// Like `tcp_punch_hole` but reports the progress of the hole punching through `events`.
+pub fn tcp_punch_hole_with_events(socket: net2::TcpBuilder,
+                                  our_priv_rendezvous_info: PrivRendezvousInfo,
+                                  their_pub_rendezvous_info: PubRendezvousInfo,
+                                  deadline: Instant,
+                                  events: &EventSender)
+                                  -> WResult<TcpStream, TcpPunchHoleWarning, TcpPunchHoleError> {
+    TcpPunchBuilder::new(deadline)
+        .events(events)
+        .punch_hole(socket, our_priv_rendezvous_info, their_pub_rendezvous_info)
+}
+
+fn tcp_punch_hole_impl(socket: net2::TcpBuilder,
+                       our_priv_rendezvous_info: PrivRendezvousInfo,
+                       their_pub_rendezvous_info: PubRendezvousInfo,
+                       options: TcpPunchBuilder)
+                       -> WResult<PunchedTcpStream, TcpPunchHoleWarning, TcpPunchHoleError> {
     // In order to do tcp hole punching we connect to all of their endpoints in parallel while
     // simultaneously listening. All the sockets we use must be bound to the same local address. As
     // soon as we successfully connect and exchange secrets, or accept and exchange secrets, we
@@ -612,33 +1285,89 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
     // timeouts should prevent the detached threads from leaking indefinitely.
 
     let mut warnings = Vec::new();
+    let deadline = options.deadline;
+    let no_events = EventSender::new();
+    let events = options.events.unwrap_or(&no_events);
+    let retry_interval = options.retry_interval;
 
     let shutdown = Arc::new(AtomicBool::new(false));
 
     // The channel we will use to collect the results from the many worker threads.
-    let (results_tx, results_rx) = mpsc::channel::<Option<Result<(TcpStream, SocketAddr), TcpPunchHoleWarning>>>();
+    let (results_tx, results_rx) = mpsc::channel::<Option<Result<(TcpStream,
+                                                                   SocketAddr,
+                                                                   TcpPunchTechnique),
+                                                                  TcpPunchHoleWarning>>>();
 
     let our_secret = rendezvous_info::get_priv_secret(our_priv_rendezvous_info);
-    let (their_endpoints, their_secret) = rendezvous_info::decompose(their_pub_rendezvous_info);
+    let (mut their_endpoints, their_secret) =
+        rendezvous_info::decompose(their_pub_rendezvous_info);
+    if let Some(filter) = options.candidate_filter {
+        their_endpoints.retain(|endpoint| filter(endpoint));
+    }
 
     let local_addr = match socket_utils::tcp_builder_local_addr(&socket) {
         Ok(local_addr) => local_addr,
         Err(e) => return WErr(TcpPunchHoleError::SocketLocalAddr { err: e }),
     };
+    // Connect through the interface the socket is pinned to, if any.
+    let ipv6 = socket_utils::is_ipv6(&local_addr);
+    let if_name = socket_utils::bound_interface(&socket, ipv6).unwrap_or(None);
+
+    // Each endpoint is only tried for as long as a check may take.
+    let check_deadline = match options.check_timeout {
+        Some(timeout) => cmp::min(Instant::now() + timeout, deadline),
+        None => deadline,
+    };
+
+    // With low ttl SYNs, the peer with the lower secret opens its NAT with SYNs that die before
+    // reaching the other NAT, and the other connects once they've had time to. Resets and
+    // unreachable errors are then expected while the holes open, so they're retried quickly
+    // without raising warnings.
+    let (opening_ttl, connect_delay, retry_interval) = match options.low_ttl_syn {
+        Some(ttl) if *our_secret < their_secret => {
+            (Some(ttl), None, Duration::from_millis(LOW_TTL_RETRY_INTERVAL_MS))
+        },
+        Some(..) => {
+            (None,
+             Some(Duration::from_millis(LOW_TTL_HEAD_START_MS)),
+             Duration::from_millis(LOW_TTL_RETRY_INTERVAL_MS))
+        },
+        None => (None, None, retry_interval),
+    };
+    let absorb_connect_errors = options.low_ttl_syn.is_some();
+    let mptcp = options.mptcp;
 
     // Try connecting to every potential endpoint in a seperate thread.
     for endpoint in their_endpoints {
         let addr = endpoint.addr;
+        let technique = if endpoint.nat_restricted {
+            TcpPunchTechnique::Connected
+        } else {
+            TcpPunchTechnique::Forwarded
+        };
+        events.send(Event::CheckStarted { peer_addr: addr });
         // Important to call new_reusably_bound_tcp_socket outside the inner thread so that it's called
         // before the listen() call below.
-        let mapping_socket = match new_reusably_bound_tcp_socket(&local_addr) {
+        let if_name = if_name.as_ref().map(|if_name| &if_name[..]);
+        let mapping_socket = match new_reusably_bound_tcp_socket_on(&local_addr, if_name, mptcp) {
             Ok(mapping_socket) => mapping_socket,
             Err(e) => return WErr(TcpPunchHoleError::NewReusablyBoundTcpSocket { err: e }),
         };
+        let thread_if_name = if_name.map(|if_name| if_name.to_owned());
+        let our_secret = our_secret.clone();
         let results_tx_clone = results_tx.clone();
         let shutdown_clone = shutdown.clone();
         let _ = thread!("tcp_punch_hole connect", move || {
-            let f = |timeout| {
+            if let Some(connect_delay) = connect_delay {
+                thread::sleep(connect_delay);
+            }
+            let mut mapping_socket = mapping_socket;
+            let f = |mapping_socket: &net2::TcpBuilder, timeout| {
+                if let Some(ttl) = opening_ttl {
+                    if let Err(e) = mapping_socket.ttl(ttl) {
+                        return Err(TcpPunchHoleWarning::SetTtl { err: e });
+                    }
+                }
                 let mut stream = match mapping_socket.connect(&*addr) {
                     Ok(stream) => stream,
                     Err(e) => return Err(TcpPunchHoleWarning::Connect {
@@ -646,6 +1375,12 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
                         err: e,
                     }),
                 };
+                // The peer's SYN came through the hole. Let our packets reach them again.
+                if opening_ttl.is_some() {
+                    if let Err(e) = stream.set_ttl(DEFAULT_TTL) {
+                        return Err(TcpPunchHoleWarning::SetTtl { err: e });
+                    }
+                }
                 match stream.set_write_timeout(Some(timeout)) {
                     Ok(()) => (),
                     Err(e) => return Err(TcpPunchHoleWarning::StreamSetTimeout { err: e }),
@@ -687,20 +1422,47 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
             };
             loop {
                 let now = Instant::now();
-                if now >= deadline || shutdown_clone.load(Ordering::SeqCst) {
+                if now >= check_deadline || shutdown_clone.load(Ordering::SeqCst) {
                     break;
                 }
                 else {
-                    let timeout = deadline - now;
-                    match f(timeout) {
+                    let timeout = check_deadline - now;
+                    match f(&mapping_socket, timeout) {
                         Ok(stream) => {
-                            let _ = results_tx_clone.send(Some(Ok((stream, addr))));
+                            let _ = results_tx_clone.send(Some(Ok((stream, addr, technique))));
                             break;
                         },
+                        // The peer's SYN reached our listener first, which now owns the
+                        // connection to this endpoint.
+                        Err(ref e) if is_lost_accept_race(e) => break,
                         Err(e) => {
-                            let _ = results_tx_clone.send(Some(Err(e)));
+                            let refused_slowly = is_slow_refusal(&e);
+                            if !(absorb_connect_errors && is_low_ttl_connect_error(&e)) {
+                                let _ = results_tx_clone.send(Some(Err(e)));
+                            }
+                            if RETRY_NEEDS_NEW_SOCKET {
+                                let if_name = thread_if_name.as_ref().map(|if_name| &if_name[..]);
+                                let res = new_reusably_bound_tcp_socket_on(&local_addr,
+                                                                           if_name,
+                                                                           mptcp);
+                                match res {
+                                    Ok(socket) => mapping_socket = socket,
+                                    Err(e) => {
+                                        let err = io::Error::from(e);
+                                        let _ = results_tx_clone.send(Some(Err(
+                                            TcpPunchHoleWarning::Connect {
+                                                peer_addr: addr,
+                                                err: err,
+                                            })));
+                                        break;
+                                    },
+                                }
+                            }
                             // So we don't continuously hammer an address we can't connect to.
-                            thread::sleep(Duration::from_secs(1));
+                            // A refusal that already took its time needs no further wait.
+                            if !refused_slowly {
+                                thread::sleep(retry_interval);
+                            }
                             continue;
                         },
                     }
@@ -739,6 +1501,7 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
             // Spawn a new thread here to prevent someone from connecting then not sending any data
             // and preventing us from accepting any more connections.
             let results_tx_clone = results_tx_clone.clone();
+            let our_secret = our_secret.clone();
             let now = Instant::now();
             if now >= deadline {
                 break;
@@ -801,7 +1564,8 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
                         return;
                     },
                 };
-                let _ = results_tx_clone.send(Some(Ok((stream, SocketAddr(addr)))));
+                let accepted = (stream, SocketAddr(addr), TcpPunchTechnique::Accepted);
+                let _ = results_tx_clone.send(Some(Ok(accepted)));
             });
         }
     });
@@ -825,12 +1589,9 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
     // Process the results that the worker threads send us.
     loop {
         match results_rx.recv() {
-            // All the senders have closed. This could only happen if all of the worker threads
-            // panicked. Propogate the panic.
-            Err(_) => panic!("In tcp_punch_hole results_rx.recv() returned Err"),
-
-            // We timed out.
-            Ok(None) => {
+            // We timed out. All the senders closing could only happen if every worker thread
+            // panicked, in which case nothing more can arrive either.
+            Ok(None) | Err(_) => {
                 timeout_thread_handle.unpark();
                 shutdown.store(true, Ordering::SeqCst);
                 let _ = TcpStream::connect(&acceptor_addr);
@@ -839,11 +1600,17 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
             
             // One of the worker threads raised a warning.
             Ok(Some(Err(e))) => {
+                if let Some(peer_addr) = e.peer_addr() {
+                    events.send(Event::CheckFailed {
+                        peer_addr: peer_addr,
+                        reason: format!("{}", e),
+                    });
+                }
                 warnings.push(e);
             },
 
             // Success!
-            Ok(Some(Ok((stream, stream_addr)))) => {
+            Ok(Some(Ok((stream, stream_addr, technique)))) => {
                 shutdown.store(true, Ordering::SeqCst);
                 // Cause the acceptor to shut down.
                 let _ = TcpStream::connect(&acceptor_addr);
@@ -859,14 +1626,19 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
                         // got a successful connection.
                         Some(Err(_)) => (),
                         // We made another connection.
-                        Some(Ok((stream, stream_addr))) => other_streams.push((stream, stream_addr)),
+                        Some(Ok((stream, stream_addr, technique))) => {
+                            other_streams.push((stream, stream_addr, technique))
+                        },
                     };
                 }
 
                 timeout_thread_handle.unpark();
 
                 if other_streams.len() == 0 {
-                    return WOk(stream, warnings);
+                    report_connected(events, stream_addr);
+                    set_keepalive(&stream, options.keepalive, &mut warnings);
+                    let punched = PunchedTcpStream::new(stream, stream_addr, technique, local_addr);
+                    return WOk(punched, warnings);
                 }
                 else {
                     // We have more than one stream. Both sides need to agree on which stream to
@@ -875,10 +1647,10 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
                     // the highest sum.
                     
                     let mut errors = Vec::new();
-                    other_streams.push((stream, stream_addr));
+                    other_streams.push((stream, stream_addr, technique));
 
                     // Write the random u64 to each stream.
-                    let streams: Vec<(TcpStream, SocketAddr, u64)> = other_streams.into_iter().filter_map(|(mut stream, stream_addr)| {
+                    let streams: Vec<(TcpStream, SocketAddr, TcpPunchTechnique, u64)> = other_streams.into_iter().filter_map(|(mut stream, stream_addr, technique)| {
                         let w = random();
                         match stream.write_u64::<BigEndian>(w) {
                             Ok(()) => (),
@@ -890,12 +1662,12 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
                                 return None;
                             },
                         };
-                        Some((stream, stream_addr, w))
+                        Some((stream, stream_addr, technique, w))
                     }).collect();
 
                     // Read the random u64 from each stream while keeping hold of the stream with
                     // the highest sum so far.
-                    let stream_opt = streams.into_iter().fold(None, |opt, (mut this_stream, this_stream_addr, w)| {
+                    let stream_opt = streams.into_iter().fold(None, |opt, (mut this_stream, this_stream_addr, this_technique, w)| {
                         // Calculate the sum for this stream.
                         let this_sum = match this_stream.read_u64::<BigEndian>() {
                             Ok(r) => r.wrapping_add(w),
@@ -910,29 +1682,38 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
                         // If the sum is greater than the current highest (or we don't have a
                         // current highest yet), replace the highest stream and sum with this
                         // stream and sum.
+                        let this = (this_stream, this_stream_addr, this_technique, this_sum);
                         match opt {
-                            Some((top_stream, top_sum)) => {
-                                if this_sum > top_sum {
-                                    Some((this_stream, this_sum))
+                            Some(top) => {
+                                if this_sum > top.3 {
+                                    Some(this)
                                 }
                                 else {
-                                    Some((top_stream, top_sum))
+                                    Some(top)
                                 }
                             },
-                            None => Some((this_stream, this_sum))
+                            None => Some(this)
                         }
                     });
 
                     match stream_opt {
                         // Return the chosen stream.
-                        Some((stream, _sum)) => {
+                        Some((stream, stream_addr, technique, _sum)) => {
+                            if let Ok(peer_addr) = stream.peer_addr() {
+                                report_connected(events, SocketAddr(peer_addr));
+                            }
                             warnings.extend(errors.into_iter().map(|bs| {
                                 TcpPunchHoleWarning::StreamIo {
                                     peer_addr: bs.peer_addr,
                                     err: bs.error,
                                 }
                             }));
-                            return WOk(stream, warnings);
+                            set_keepalive(&stream, options.keepalive, &mut warnings);
+                            let punched = PunchedTcpStream::new(stream,
+                                                                stream_addr,
+                                                                technique,
+                                                                local_addr);
+                            return WOk(punched, warnings);
                         },
                         // Every stream died while deciding which stream to use.
                         None => {
@@ -947,6 +1728,185 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
     }
 }
 
+// Punch with `options`, and if that's thwarted by resets retry from a newly mapped socket.
+fn tcp_punch_hole_remapping(socket: net2::TcpBuilder,
+                            our_priv_rendezvous_info: PrivRendezvousInfo,
+                            their_pub_rendezvous_info: PubRendezvousInfo,
+                            options: TcpPunchBuilder,
+                            mc: &MappingContext,
+                            exchange: &Fn(PubRendezvousInfo) -> Option<PubRendezvousInfo>)
+                            -> WResult<PunchedTcpStream, TcpPunchHoleWarning, TcpPunchHoleError> {
+    let deadline = options.deadline;
+    let local_port = match socket_utils::tcp_builder_local_addr(&socket) {
+        Ok(local_addr) => local_addr.port(),
+        Err(e) => return WErr(TcpPunchHoleError::SocketLocalAddr { err: e }),
+    };
+    let now = Instant::now();
+    let first_deadline = if deadline > now {
+        now + (deadline - now) / 2
+    } else {
+        deadline
+    };
+    let first_options = TcpPunchBuilder {
+        deadline: first_deadline,
+        remap: None,
+        ..options.clone()
+    };
+    let mut warnings = match tcp_punch_hole_impl(socket,
+                                                 our_priv_rendezvous_info,
+                                                 their_pub_rendezvous_info,
+                                                 first_options) {
+        WErr(TcpPunchHoleError::TimedOut { warnings }) => warnings,
+        res => return res,
+    };
+    let resets = count_resets(&warnings);
+    if resets < RESET_STORM_RESETS || 2 * resets < count_connect_failures(&warnings) {
+        return WErr(TcpPunchHoleError::TimedOut { warnings: warnings });
+    }
+    warnings.push(TcpPunchHoleWarning::ResetStorm {
+        resets: resets,
+        local_port: local_port,
+    });
+
+    // Don't let the new socket land on the port that's being interfered with.
+    mapping_context::forget_mapped_port(mc, igd::PortMappingProtocol::TCP, local_port);
+    let mapped = match MappedTcpSocket::new(mc, deadline) {
+        WOk(mapped, _) => mapped,
+        WErr(e) => {
+            warnings.push(TcpPunchHoleWarning::Remap { err: e });
+            return WErr(TcpPunchHoleError::TimedOut { warnings: warnings });
+        },
+    };
+    let (our_priv_rendezvous_info, our_pub_rendezvous_info)
+        = rendezvous_info::gen_rendezvous_info(mapped.endpoints);
+    let their_pub_rendezvous_info = match exchange(our_pub_rendezvous_info) {
+        Some(their_pub_rendezvous_info) => their_pub_rendezvous_info,
+        None => return WErr(TcpPunchHoleError::TimedOut { warnings: warnings }),
+    };
+    let retry_options = TcpPunchBuilder {
+        remap: None,
+        ..options
+    };
+    match tcp_punch_hole_impl(mapped.socket,
+                              our_priv_rendezvous_info,
+                              their_pub_rendezvous_info,
+                              retry_options) {
+        WOk(punched, more_warnings) => {
+            warnings.extend(more_warnings);
+            WOk(punched, warnings)
+        },
+        WErr(TcpPunchHoleError::TimedOut { warnings: more_warnings }) => {
+            warnings.extend(more_warnings);
+            WErr(TcpPunchHoleError::TimedOut { warnings: warnings })
+        },
+        WErr(e) => WErr(e),
+    }
+}
+
+// How many attempts to reach the peer were reset.
+fn count_resets(warnings: &[TcpPunchHoleWarning]) -> u32 {
+    warnings.iter()
+            .filter(|warning| {
+                match **warning {
+                    TcpPunchHoleWarning::Connect { ref err, .. } |
+                    TcpPunchHoleWarning::StreamIo { ref err, .. } => {
+                        err.kind() == io::ErrorKind::ConnectionRefused ||
+                        err.kind() == io::ErrorKind::ConnectionReset
+                    },
+                    _ => false,
+                }
+            })
+            .count() as u32
+}
+
+// How many attempts to reach the peer failed in any way.
+fn count_connect_failures(warnings: &[TcpPunchHoleWarning]) -> u32 {
+    warnings.iter().filter(|warning| warning.peer_addr().is_some()).count() as u32
+}
+
+// Windows can't reuse a socket whose connect failed, so each attempt needs a new one bound to the
+// same port. Elsewhere connect can simply be called again.
+#[cfg(target_family = "windows")]
+const RETRY_NEEDS_NEW_SOCKET: bool = true;
+#[cfg(not(target_family = "windows"))]
+const RETRY_NEEDS_NEW_SOCKET: bool = false;
+
+// Returned by connect on Windows when the connection's addresses are taken, as they are once the
+// peer's SYN has been accepted by the listener sharing our port.
+#[cfg(target_family = "windows")]
+const WSAEADDRINUSE: i32 = 10048;
+
+// Whether `warning` means a connect lost the race with our listener to the peer's SYN. Windows
+// hands a simultaneous open to the listener and fails the connect instead of completing it.
+#[cfg(target_family = "windows")]
+fn is_lost_accept_race(warning: &TcpPunchHoleWarning) -> bool {
+    match *warning {
+        TcpPunchHoleWarning::Connect { ref err, .. } => err.raw_os_error() == Some(WSAEADDRINUSE),
+        _ => false,
+    }
+}
+
+#[cfg(not(target_family = "windows"))]
+fn is_lost_accept_race(_warning: &TcpPunchHoleWarning) -> bool {
+    false
+}
+
+// Whether `warning` is a refusal that has already taken a while. Windows resends a SYN that was
+// reset a couple of times before giving up with WSAECONNREFUSED, so by then it's been about a
+// second and the next attempt can go straight away. Elsewhere a reset fails the connect at once.
+#[cfg(target_family = "windows")]
+fn is_slow_refusal(warning: &TcpPunchHoleWarning) -> bool {
+    match *warning {
+        TcpPunchHoleWarning::Connect { ref err, .. } => {
+            err.kind() == io::ErrorKind::ConnectionRefused
+        },
+        _ => false,
+    }
+}
+
+#[cfg(not(target_family = "windows"))]
+fn is_slow_refusal(_warning: &TcpPunchHoleWarning) -> bool {
+    false
+}
+
+// Whether `warning` is a reset or an ICMP error, as expected while low ttl SYNs open the holes.
+fn is_low_ttl_connect_error(warning: &TcpPunchHoleWarning) -> bool {
+    match *warning {
+        TcpPunchHoleWarning::Connect { ref err, .. } => {
+            match err.kind() {
+                io::ErrorKind::ConnectionRefused |
+                io::ErrorKind::ConnectionReset |
+                io::ErrorKind::TimedOut => true,
+                // Unreachable errors, from the ICMP time exceeded the low ttl SYN provokes, have
+                // no kind of their own.
+                io::ErrorKind::Other => true,
+                _ => false,
+            }
+        },
+        _ => false,
+    }
+}
+
+// Turn on keepalives for a punched stream if they were asked for.
+fn set_keepalive(stream: &TcpStream,
+                 keepalive: Option<Duration>,
+                 warnings: &mut Vec<TcpPunchHoleWarning>) {
+    if keepalive.is_none() {
+        return;
+    }
+    if let Err(e) = net2::TcpStreamExt::set_keepalive(stream, keepalive) {
+        warnings.push(TcpPunchHoleWarning::SetKeepalive { err: e });
+    }
+}
+
+fn report_connected(events: &EventSender, peer_addr: SocketAddr) {
+    events.send(Event::CheckSucceeded { peer_addr: peer_addr });
+    events.send(Event::Connected {
+        strategy: Strategy::TcpHolePunch,
+        peer_addr: peer_addr,
+    });
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -956,6 +1916,7 @@ mod test {
 
     use mapping_context::MappingContext;
     use rendezvous_info::gen_rendezvous_info;
+    use socket_utils;
 
     #[test]
     fn two_peers_tcp_hole_punch_over_loopback() {
@@ -996,5 +1957,230 @@ mod test {
         unwrap_result!(thread_0.join());
         unwrap_result!(thread_1.join());
     }
-}
 
+    #[test]
+    fn two_peers_punch_with_low_ttl_syns() {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mapping_context = unwrap_result!(MappingContext::new().result_log());
+
+        let mapped_socket_0 = unwrap_result!(MappedTcpSocket::new(&mapping_context, deadline)
+                                                  .result_log());
+        let (priv_info_0, pub_info_0) = gen_rendezvous_info(mapped_socket_0.endpoints);
+        let mapped_socket_1 = unwrap_result!(MappedTcpSocket::new(&mapping_context, deadline)
+                                                  .result_log());
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(mapped_socket_1.endpoints);
+
+        // Loopback never decrements the ttl, so the opening peer's SYNs arrive anyway.
+        let socket_0 = mapped_socket_0.socket;
+        let thread_0 = thread!("two_peers_punch_with_low_ttl_syns", move || {
+            TcpPunchBuilder::new(deadline)
+                .low_ttl_syn(2)
+                .punch_hole(socket_0, priv_info_0, pub_info_1)
+                .result_log()
+        });
+        let mut stream_1 = unwrap_result!(TcpPunchBuilder::new(deadline)
+                                              .low_ttl_syn(2)
+                                              .punch_hole(mapped_socket_1.socket,
+                                                          priv_info_1,
+                                                          pub_info_0)
+                                              .result_log());
+        let mut stream_0 = unwrap_result!(unwrap_result!(thread_0.join()));
+
+        unwrap_result!(stream_0.write_all(&[7u8; 4]));
+        let mut data = [0u8; 4];
+        unwrap_result!(stream_1.read_exact(&mut data));
+        assert_eq!(data, [7u8; 4]);
+    }
+
+    #[test]
+    fn punched_streams_tell_how_they_were_made() {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mapping_context = unwrap_result!(MappingContext::new().result_log());
+
+        let mapped_socket_0 = unwrap_result!(MappedTcpSocket::new(&mapping_context, deadline)
+                                                  .result_log());
+        let (priv_info_0, pub_info_0) = gen_rendezvous_info(mapped_socket_0.endpoints);
+        let mapped_socket_1 = unwrap_result!(MappedTcpSocket::new(&mapping_context, deadline)
+                                                  .result_log());
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(mapped_socket_1.endpoints);
+
+        let socket_0 = mapped_socket_0.socket;
+        let thread_0 = thread!("punched_streams_tell_how_they_were_made", move || {
+            TcpPunchBuilder::new(deadline)
+                .punch_hole_detailed(socket_0, priv_info_0, pub_info_1)
+                .result_log()
+        });
+        let punched_1 = unwrap_result!(TcpPunchBuilder::new(deadline)
+                                           .punch_hole_detailed(mapped_socket_1.socket,
+                                                                priv_info_1,
+                                                                pub_info_0)
+                                           .result_log());
+        let punched_0 = unwrap_result!(unwrap_result!(thread_0.join()));
+
+        assert_eq!(punched_0.local_addr, punched_1.peer_addr);
+        assert_eq!(punched_0.peer_addr, punched_1.local_addr);
+        // Our interface addresses need no hole punching, so the streams were either accepted or
+        // connected straight through.
+        for punched in &[punched_0, punched_1] {
+            assert!(punched.technique == TcpPunchTechnique::Accepted ||
+                    punched.technique == TcpPunchTechnique::Forwarded);
+        }
+    }
+
+    #[test]
+    fn mptcp_punching_agrees_on_whether_it_was_negotiated() {
+        use mapped_socket_addr::MappedSocketAddr;
+
+        let any_port = unwrap_result!("127.0.0.1:0".parse());
+        let socket_0 = unwrap_result!(new_reusably_bound_mptcp_socket(&any_port));
+        let addr_0 = unwrap_result!(socket_utils::tcp_builder_local_addr(&socket_0));
+        let socket_1 = unwrap_result!(new_reusably_bound_mptcp_socket(&any_port));
+        let addr_1 = unwrap_result!(socket_utils::tcp_builder_local_addr(&socket_1));
+        let (priv_info_0, pub_info_0) = gen_rendezvous_info(vec![MappedSocketAddr::direct(addr_0)]);
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(vec![MappedSocketAddr::direct(addr_1)]);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let thread_0 = thread!("mptcp_punching_agrees_on_whether_it_was_negotiated", move || {
+            TcpPunchBuilder::new(deadline)
+                .mptcp(true)
+                .punch_hole_detailed(socket_0, priv_info_0, pub_info_1)
+                .result_log()
+        });
+        let punched_1 = unwrap_result!(TcpPunchBuilder::new(deadline)
+                                           .mptcp(true)
+                                           .punch_hole_detailed(socket_1, priv_info_1, pub_info_0)
+                                           .result_log());
+        let punched_0 = unwrap_result!(unwrap_result!(thread_0.join()));
+        // Whether it was depends on the kernel, but both ends must see the same.
+        assert_eq!(punched_0.mptcp, punched_1.mptcp);
+        if cfg!(not(target_os = "linux")) {
+            assert!(!punched_0.mptcp);
+        }
+    }
+
+    #[test]
+    fn reset_storm_triggers_a_remapped_retry() {
+        use std::net::TcpListener;
+        use std::sync::Mutex;
+        use mapped_socket_addr::MappedSocketAddr;
+        use rendezvous_info::PubRendezvousInfo;
+        use w_result::{WErr, WOk};
+
+        let mc = unwrap_result!(MappingContext::new().result_log());
+        let deadline = Instant::now() + Duration::from_secs(8);
+        // Nothing listens here, so every connection to it is reset.
+        let dead_addr = unwrap_result!(unwrap_result!(TcpListener::bind("127.0.0.1:0"))
+                                           .local_addr());
+        let (_, dead_info) = gen_rendezvous_info(vec![MappedSocketAddr::direct(dead_addr)]);
+
+        let mapped_0 = unwrap_result!(MappedTcpSocket::new(&mc, deadline).result_log());
+        let local_port = unwrap_result!(socket_utils::tcp_builder_local_addr(&mapped_0.socket))
+                             .port();
+        let (priv_info_0, _) = gen_rendezvous_info(mapped_0.endpoints);
+        let mapped_1 = unwrap_result!(MappedTcpSocket::new(&mc, deadline).result_log());
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(mapped_1.endpoints);
+
+        // The peer only starts punching once it has our new rendezvous info.
+        let peer = Mutex::new(Some((mapped_1.socket, priv_info_1)));
+        let peer_thread = Mutex::new(None);
+        let exchange = |our_info: PubRendezvousInfo| {
+            let (socket, priv_info) = match unwrap_result!(peer.lock()).take() {
+                Some(peer) => peer,
+                None => return None,
+            };
+            *unwrap_result!(peer_thread.lock()) = Some(thread!("remapped peer", move || {
+                TcpPunchBuilder::new(deadline)
+                    .punch_hole(socket, priv_info, our_info)
+                    .result_log()
+            }));
+            Some(pub_info_1.clone())
+        };
+
+        match TcpPunchBuilder::new(deadline)
+                  .retry_interval(Duration::from_millis(100))
+                  .remap_on_resets(&mc, &exchange)
+                  .punch_hole_detailed(mapped_0.socket, priv_info_0, dead_info) {
+            WOk(punched, warnings) => {
+                assert!(punched.local_addr.port() != local_port);
+                assert!(warnings.iter().any(|warning| match *warning {
+                    TcpPunchHoleWarning::ResetStorm { local_port: port, .. } => port == local_port,
+                    _ => false,
+                }));
+            },
+            WErr(e) => panic!("Unexpected error: {}", e),
+        }
+        let peer_thread = unwrap_result!(peer_thread.lock()).take().expect("Peer never retried");
+        let _ = unwrap_result!(unwrap_result!(peer_thread.join()));
+    }
+
+    #[test]
+    fn pinning_to_an_unknown_interface_fails() {
+        use w_result::{WErr, WOk};
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mapping_context = unwrap_result!(MappingContext::new().result_log());
+        match MappedTcpSocket::new_on_interface(&mapping_context, "no-such-if0", deadline) {
+            WErr(MappedTcpSocketNewError::NoSuchInterface { ref if_name }) => {
+                assert_eq!(if_name, "no-such-if0")
+            },
+            WErr(e) => panic!("Unexpected error: {}", e),
+            WOk(..) => panic!("Mapped a socket on an interface that doesn't exist"),
+        }
+    }
+
+    #[test]
+    fn reusably_bound_sockets_share_a_port() {
+        let addr = unwrap_result!("127.0.0.1:0".parse());
+        let socket_0 = unwrap_result!(new_reusably_bound_tcp_socket(&addr));
+        let addr = unwrap_result!(socket_utils::tcp_builder_local_addr(&socket_0));
+        let socket_1 = unwrap_result!(new_reusably_bound_tcp_socket(&addr));
+        assert_eq!(unwrap_result!(socket_utils::tcp_builder_local_addr(&socket_1)), addr);
+    }
+
+    #[cfg(target_family = "windows")]
+    #[test]
+    fn exclusively_bound_port_is_not_shared() {
+        use net2;
+
+        let exclusive = unwrap_result!(net2::TcpBuilder::new_v4());
+        unwrap_result!(socket_utils::set_exclusive_addr_use(&exclusive, true));
+        let _ = unwrap_result!(exclusive.bind("127.0.0.1:0"));
+        let addr = unwrap_result!(socket_utils::tcp_builder_local_addr(&exclusive));
+        match new_reusably_bound_tcp_socket(&addr) {
+            Err(NewReusablyBoundTcpSocketError::ExclusivelyBound { .. }) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(..) => panic!("Bound to an exclusively bound address"),
+        }
+    }
+
+    // Retries after a failed connect bind new sockets once the listener is up.
+    #[cfg(target_family = "windows")]
+    #[test]
+    fn retry_socket_binds_beside_the_listener() {
+        let any_port = unwrap_result!("127.0.0.1:0".parse());
+        let socket = unwrap_result!(new_reusably_bound_tcp_socket(&any_port));
+        let addr = unwrap_result!(socket_utils::tcp_builder_local_addr(&socket));
+        let _listener = unwrap_result!(socket.listen(1));
+        let _ = unwrap_result!(new_reusably_bound_tcp_socket(&addr));
+    }
+
+    #[cfg(target_family = "windows")]
+    #[test]
+    fn windows_connect_failures_are_told_apart() {
+        use std::io;
+        use socket_addr::SocketAddr;
+
+        let warning = |code| {
+            TcpPunchHoleWarning::Connect {
+                peer_addr: SocketAddr(unwrap_result!("192.0.2.1:5000".parse())),
+                err: io::Error::from_raw_os_error(code),
+            }
+        };
+        // WSAEADDRINUSE, WSAECONNREFUSED and WSAETIMEDOUT.
+        assert!(is_lost_accept_race(&warning(10048)));
+        assert!(!is_slow_refusal(&warning(10048)));
+        assert!(is_slow_refusal(&warning(10061)));
+        assert!(!is_lost_accept_race(&warning(10061)));
+        assert!(!is_slow_refusal(&warning(10060)));
+    }
+}
@@ -18,29 +18,46 @@
 //! # `nat_traversal`
 //! NAT traversal utilities.
 
+use std::cmp;
 use std::io;
+use std::mem;
 use std::net::UdpSocket;
 use std::net;
 use std::net::IpAddr;
+use std::thread;
 use std::time::{Instant, Duration};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-use igd;
-use maidsafe_utilities::serialisation::deserialise;
+use gateway as igd;
+use rand;
 use socket_addr::SocketAddr;
 use w_result::{WResult, WOk, WErr};
 
+use clock::SystemClock;
+use dns_discovery::DnsDiscoveryError;
+use error_code::{ErrorCategory, ErrorCode};
+use event::{CandidateSink, Event, Strategy, Trickle, push_endpoint};
+use http_discovery;
+use http_discovery::HttpDiscoveryError;
 use listener_message;
 use mapping_context;
 use mapping_context::MappingContext;
 use mapped_socket_addr::MappedSocketAddr;
+use pktinfo;
+use privacy::Redacted;
 use socket_utils;
-use socket_utils::RecvUntil;
+#[cfg(feature = "stun")]
+use stun::{query_stun_server, StunQueryError};
+#[cfg(not(feature = "stun"))]
+use self::stun_disabled::{query_stun_server, StunQueryError};
+use subnetting::SubnetList;
+use transport::DatagramTransport;
 
 /// A bound udp socket for which we know our external endpoints.
-pub struct MappedUdpSocket {
+#[derive(Debug)]
+pub struct MappedUdpSocket<S = UdpSocket> {
     /// The socket.
-    pub socket: UdpSocket,
+    pub socket: S,
     /// The known endpoints of this socket.
     pub endpoints: Vec<MappedSocketAddr>
 }
@@ -92,6 +109,24 @@ impl From<MappedUdpSocketMapError> for io::Error {
     }
 }
 
+impl ErrorCode for MappedUdpSocketMapError {
+    fn code(&self) -> u32 {
+        match *self {
+            MappedUdpSocketMapError::SocketLocalAddr { .. } => 301,
+            MappedUdpSocketMapError::RecvError { .. } => 302,
+            MappedUdpSocketMapError::SendError { .. } => 303,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            MappedUdpSocketMapError::SocketLocalAddr { .. } => ErrorCategory::Configuration,
+            MappedUdpSocketMapError::RecvError { .. } => ErrorCategory::Network,
+            MappedUdpSocketMapError::SendError { .. } => ErrorCategory::Network,
+        }
+    }
+}
+
 quick_error! {
     /// Warnings raised by MappedUdpSocket::map
     #[derive(Debug)]
@@ -116,9 +151,71 @@ quick_error! {
                          gateway")
             display("Error mapping external address and port through IGD \
                      gateway at address {}. igd::Gateway::get_any_address \
-                     returned an error: {}", gateway_addr, err)
+                     returned an error: {}", Redacted(gateway_addr), err)
+            cause(err)
+        }
+        /// Error asking an http echo server for our external ip address.
+        HttpEcho {
+            url: String,
+            err: HttpDiscoveryError,
+        } {
+            description("Error asking an http echo server for our external ip address")
+            display("Error asking the http echo server at {} for our external ip address. \
+                     query_http_echo_server returned an error: {}", url, err)
             cause(err)
         }
+        /// Error resolving the simple servers of the discovery domain through DNS.
+        DiscoverServers {
+            err: DnsDiscoveryError,
+        } {
+            description("Error resolving simple servers through DNS")
+            display("Error resolving simple servers through DNS: {}", err)
+            cause(err)
+        }
+        /// Error asking a STUN server for our external address.
+        Stun {
+            server: SocketAddr,
+            err: StunQueryError,
+        } {
+            description("Error asking a STUN server for our external address")
+            display("Error asking the STUN server at {} for our external address. \
+                     query_stun_server returned an error: {}", Redacted(server), err)
+            cause(err)
+        }
+        /// The IGD gateway's external address isn't the address the servers saw us at, or is
+        /// itself private, so there's another NAT beyond the gateway. The gateway's mappings are
+        /// left out of the endpoints as peers couldn't reach them.
+        DoubleNat {
+            gateway_external_ip: IpAddr,
+        } {
+            description("The IGD gateway is behind another NAT")
+            display("The IGD gateway is behind another NAT. Its external address {} isn't \
+                     reachable from the internet.", Redacted(gateway_external_ip))
+        }
+    }
+}
+
+impl ErrorCode for MappedUdpSocketMapWarning {
+    fn code(&self) -> u32 {
+        match *self {
+            MappedUdpSocketMapWarning::FindGateway { .. } => 401,
+            MappedUdpSocketMapWarning::GetExternalPort { .. } => 402,
+            MappedUdpSocketMapWarning::HttpEcho { .. } => 403,
+            MappedUdpSocketMapWarning::DiscoverServers { .. } => 404,
+            MappedUdpSocketMapWarning::Stun { .. } => 405,
+            MappedUdpSocketMapWarning::DoubleNat { .. } => 406,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            MappedUdpSocketMapWarning::FindGateway { .. } => ErrorCategory::Network,
+            MappedUdpSocketMapWarning::GetExternalPort { .. } => ErrorCategory::Network,
+            MappedUdpSocketMapWarning::HttpEcho { ref err, .. } => err.category(),
+            MappedUdpSocketMapWarning::DiscoverServers { ref err, .. } => err.category(),
+            MappedUdpSocketMapWarning::Stun { ref err, .. } => err.category(),
+            MappedUdpSocketMapWarning::DoubleNat { .. } => ErrorCategory::Network,
+        }
     }
 }
 
@@ -126,12 +223,12 @@ quick_error! {
     /// Errors returned by MappedUdpSocket::new
     #[derive(Debug)]
     pub enum MappedUdpSocketNewError {
-        /// Error creating new udp socket bound to 0.0.0.0:0
+        /// Error creating new udp socket bound to an ephemeral port.
         CreateSocket {
             err: io::Error
         } {
-            description("Error creating a new udp socket bound to 0.0.0.0:0")
-            display("Error creating a new udp socket bound to 0.0.0.0:0. \
+            description("Error creating a new udp socket bound to an ephemeral port")
+            display("Error creating a new udp socket bound to an ephemeral port. \
                      UdpSocket::bind returned an IO error: {}", err)
             cause(err)
         }
@@ -144,6 +241,21 @@ quick_error! {
                      an error: {}", err)
             cause(err)
         }
+        /// There's no interface with the requested name, or it has no usable address.
+        NoSuchInterface {
+            if_name: String
+        } {
+            description("No interface with the requested name and a usable address")
+            display("There's no interface named {} with a usable address", if_name)
+        }
+        /// Error pinning the socket to the requested interface.
+        BindToInterface {
+            err: io::Error
+        } {
+            description("Error pinning the socket to the requested interface")
+            display("Error pinning the socket to the requested interface: {}", err)
+            cause(err)
+        }
     }
 }
 
@@ -156,15 +268,59 @@ impl From<MappedUdpSocketNewError> for io::Error {
                 let err: io::Error = From::from(err);
                 err.kind()
             },
+            MappedUdpSocketNewError::NoSuchInterface { .. } => io::ErrorKind::NotFound,
+            MappedUdpSocketNewError::BindToInterface { err } => err.kind(),
         };
         io::Error::new(kind, err_str)
     }
 }
 
-impl MappedUdpSocket {
-    /// Map an existing `UdpSocket`.
-    pub fn map(socket: UdpSocket, mc: &MappingContext, deadline: Instant)
-               -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketMapError>
+impl ErrorCode for MappedUdpSocketNewError {
+    fn code(&self) -> u32 {
+        match *self {
+            MappedUdpSocketNewError::CreateSocket { .. } => 501,
+            MappedUdpSocketNewError::MapSocket { .. } => 502,
+            MappedUdpSocketNewError::NoSuchInterface { .. } => 503,
+            MappedUdpSocketNewError::BindToInterface { .. } => 504,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            MappedUdpSocketNewError::CreateSocket { .. } => ErrorCategory::Network,
+            MappedUdpSocketNewError::MapSocket { ref err, .. } => err.category(),
+            MappedUdpSocketNewError::NoSuchInterface { .. } => ErrorCategory::Configuration,
+            MappedUdpSocketNewError::BindToInterface { .. } => ErrorCategory::Configuration,
+        }
+    }
+}
+
+impl<S: DatagramTransport> MappedUdpSocket<S> {
+    /// Map an existing `UdpSocket` or other `DatagramTransport`.
+    pub fn map(socket: S, mc: &MappingContext, deadline: Instant)
+               -> WResult<MappedUdpSocket<S>, MappedUdpSocketMapWarning, MappedUdpSocketMapError>
+    {
+        Self::map_impl(socket, mc, deadline, None)
+    }
+
+    /// Like `map` but calls `on_candidate` with each endpoint as soon as it's found, so that it
+    /// can be trickled to the peer rather than waiting for the slowest server. Only endpoints the
+    /// context's policies allow to be shared are passed on. The returned socket lists all of
+    /// them, ordered best first.
+    pub fn map_trickle(socket: S,
+                       mc: &MappingContext,
+                       deadline: Instant,
+                       on_candidate: &Fn(&MappedSocketAddr))
+        -> WResult<MappedUdpSocket<S>, MappedUdpSocketMapWarning, MappedUdpSocketMapError>
+    {
+        Self::map_impl(socket, mc, deadline, Some(on_candidate))
+    }
+
+    fn map_impl(socket: S,
+                mc: &MappingContext,
+                deadline: Instant,
+                on_candidate: Option<&Fn(&MappedSocketAddr)>)
+        -> WResult<MappedUdpSocket<S>, MappedUdpSocketMapWarning, MappedUdpSocketMapError>
     {
         let mut endpoints = Vec::new();
         let mut warnings = Vec::new();
@@ -175,6 +331,16 @@ impl MappedUdpSocket {
             Ok(local_addr) => local_addr,
             Err(e) => return WErr(MappedUdpSocketMapError::SocketLocalAddr { err: e })
         };
+        let events = mapping_context::events(mc);
+        let timeouts = mc.timeouts();
+        let trickle = Trickle::new(mc, on_candidate);
+        events.send(Event::GatheringStarted { local_addr: SocketAddr(local_addr) });
+
+        // Don't bother with IGD if it's compiled out or has kept failing on this network.
+        let use_igd = cfg!(feature = "upnp") &&
+                      !mapping_context::should_skip_strategy(mc, Strategy::Igd);
+        // The external addresses the IGD gateways gave us, to compare with the servers'.
+        let mut igd_ips = Vec::new();
         match local_addr.ip() {
             IpAddr::V4(ipv4_addr) => {
                 if socket_utils::ipv4_is_unspecified(&ipv4_addr) {
@@ -183,22 +349,30 @@ impl MappedUdpSocket {
                     // an address.
                     for iface_v4 in mapping_context::interfaces_v4(&mc) {
                         let local_iface_addr = net::SocketAddrV4::new(iface_v4.addr, local_addr.port());
-                        endpoints.push(MappedSocketAddr {
+                        push_endpoint(&mut endpoints, &trickle, Strategy::LocalInterface, MappedSocketAddr {
                             addr: SocketAddr(net::SocketAddr::V4(local_iface_addr)),
                             nat_restricted: false,
+                            port_unknown: false,
+                            unverified: false,
                         });
-                        if let Some(gateway) = iface_v4.gateway {
+                        let gateway_opt = if use_igd { iface_v4.gateway } else { None };
+                        if let Some(gateway) = gateway_opt {
                             match gateway.get_any_address(igd::PortMappingProtocol::UDP,
                                                           local_iface_addr, 0,
                                                           "rust nat_traversal")
                             {
                                 Ok(external_addr) => {
-                                    endpoints.push(MappedSocketAddr {
+                                    mc.record_strategy_result(Strategy::Igd, true);
+                                    igd_ips.push(IpAddr::V4(*external_addr.ip()));
+                                    push_endpoint(&mut endpoints, &trickle, Strategy::Igd, MappedSocketAddr {
                                         addr: SocketAddr(net::SocketAddr::V4(external_addr)),
                                         nat_restricted: false,
+                                        port_unknown: false,
+                                        unverified: false,
                                     });
                                 },
                                 Err(e) => {
+                                    mc.record_strategy_result(Strategy::Igd, false);
                                     warnings.push(MappedUdpSocketMapWarning::GetExternalPort {
                                         gateway_addr: gateway.addr,
                                         err: e,
@@ -210,9 +384,11 @@ impl MappedUdpSocket {
                 }
                 else {
                     let local_addr_v4 = net::SocketAddrV4::new(ipv4_addr, local_addr.port());
-                    endpoints.push(MappedSocketAddr {
+                    push_endpoint(&mut endpoints, &trickle, Strategy::LocalInterface, MappedSocketAddr {
                         addr: SocketAddr(net::SocketAddr::V4(local_addr_v4)),
                         nat_restricted: false,
+                        port_unknown: false,
+                        unverified: false,
                     });
 
                     // If the local address is the address of an interface then we can avoid
@@ -226,11 +402,13 @@ impl MappedUdpSocket {
                         }
                     };
                     let gateway_opt = match gateway_opt_opt {
+                        _ if !use_igd => None,
                         Some(gateway_opt) => gateway_opt,
                         // We don't where this local address came from so search for an IGD gateway
                         // at it.
                         None => {
-                            match igd::search_gateway_from_timeout(ipv4_addr, Duration::from_secs(1)) {
+                            let timeout = timeouts.server_query;
+                            match igd::search_gateway_from_timeout(ipv4_addr, timeout) {
                                 Ok(gateway) => Some(gateway),
                                 Err(e) => {
                                     warnings.push(MappedUdpSocketMapWarning::FindGateway {
@@ -248,12 +426,17 @@ impl MappedUdpSocket {
                                                       "rust nat_traversal")
                         {
                             Ok(external_addr) => {
-                                endpoints.push(MappedSocketAddr {
+                                mc.record_strategy_result(Strategy::Igd, true);
+                                igd_ips.push(IpAddr::V4(*external_addr.ip()));
+                                push_endpoint(&mut endpoints, &trickle, Strategy::Igd, MappedSocketAddr {
                                     addr: SocketAddr(net::SocketAddr::V4(external_addr)),
                                     nat_restricted: false,
+                                    port_unknown: false,
+                                    unverified: false,
                                 });
                             },
                             Err(e) => {
+                                mc.record_strategy_result(Strategy::Igd, false);
                                 warnings.push(MappedUdpSocketMapWarning::GetExternalPort {
                                     gateway_addr: gateway.addr,
                                     err: e,
@@ -268,16 +451,20 @@ impl MappedUdpSocket {
                     // If the socket address is unspecified add an address for every interface.
                     for iface_v6 in mapping_context::interfaces_v6(&mc) {
                         let local_iface_addr = net::SocketAddr::V6(net::SocketAddrV6::new(iface_v6.addr, local_addr.port(), 0, 0));
-                        endpoints.push(MappedSocketAddr {
+                        push_endpoint(&mut endpoints, &trickle, Strategy::LocalInterface, MappedSocketAddr {
                             addr: SocketAddr(local_iface_addr),
                             nat_restricted: false,
+                            port_unknown: false,
+                            unverified: false,
                         });
                     };
                 }
                 else {
-                    endpoints.push(MappedSocketAddr {
+                    push_endpoint(&mut endpoints, &trickle, Strategy::LocalInterface, MappedSocketAddr {
                         addr: SocketAddr(net::SocketAddr::V6(net::SocketAddrV6::new(ipv6_addr, local_addr.port(), 0, 0))),
                         nat_restricted: false,
+                        port_unknown: false,
+                        unverified: false,
                     });
                 }
             },
@@ -286,38 +473,154 @@ impl MappedUdpSocket {
         const MAX_DATAGRAM_SIZE: usize = 256;
 
         let send_data = listener_message::REQUEST_MAGIC_CONSTANT;
-        let mut simple_servers: HashSet<SocketAddr> = mapping_context::simple_udp_servers(&mc)
-                                                                      .into_iter().collect();
+        // Servers echo the nonce in their answers to padded requests, so we can tell their answers
+        // from spoofed ones and from answers to some earlier request.
+        let nonce: [u8; listener_message::NONCE_LEN] = rand::random();
+        let mut padded_request = listener_message::PaddedRequest {
+            magic: send_data,
+            cookie: None,
+            nonce: nonce,
+        };
+        // Cookies handed out by the servers. A server only answers a padded request once we've
+        // echoed the cookie it sent to the address it saw us at, which confirms that address.
+        let mut cookies: HashMap<SocketAddr, [u8; listener_message::COOKIE_LEN]> = HashMap::new();
+        if let Err(e) = mapping_context::refresh_stale_discovered_servers(mc, deadline) {
+            warnings.push(MappedUdpSocketMapWarning::DiscoverServers { err: e });
+        }
+        // Queried in this order, which `MappingContext::rank_simple_udp_servers` sets fastest
+        // first.
+        let mut server_order: Vec<SocketAddr> = Vec::new();
+        for server in mapping_context::simple_udp_servers(&mc) {
+            if !server_order.contains(&server) {
+                server_order.push(server);
+            }
+        }
+        let mut simple_servers: HashSet<SocketAddr> = server_order.iter().cloned().collect();
 
-        // Ping all the simple servers and waiting for a response.
+        // If a socket bound to the same ip was mapped recently and the NAT kept its port, assume
+        // it'll keep ours too rather than asking the servers again.
+        let mut simple_server_responded = false;
+        let cached_ip = mapping_context::cached_external_ip(mc, local_addr.ip());
+        if let Some(ip) = cached_ip {
+            simple_servers.clear();
+            simple_server_responded = true;
+            let external_addr = SocketAddr(net::SocketAddr::new(ip, local_addr.port()));
+            if endpoints.iter().all(|e| e.addr != external_addr) {
+                push_endpoint(&mut endpoints, &trickle, Strategy::SimpleServer, MappedSocketAddr {
+                    addr: external_addr,
+                    nat_restricted: true,
+                    port_unknown: false,
+                    // The mapping may have changed since the address was cached.
+                    unverified: true,
+                });
+            }
+        }
+        // The addresses the servers saw us at, for caching.
+        let mut external_addrs = Vec::new();
+        // Our addresses that the servers' responses arrived on.
+        let mut response_local_ips = Vec::new();
+
+        let limits = mc.concurrency_limits();
+        let shaper = mapping_context::traffic_shaper(mc);
+        let queried_simple_servers = !simple_servers.is_empty();
+        if queried_simple_servers {
+            events.send(Event::StrategyChanged { strategy: Strategy::SimpleServer });
+        }
+
+        let http_echo_servers = mapping_context::http_echo_servers(mc);
+        let stun_servers = if cfg!(feature = "stun") {
+            mapping_context::stun_servers(mc)
+        } else {
+            Vec::new()
+        };
+
+        // Ping all the simple servers and waiting for a response. If we may need to fall back to
+        // the STUN or http echo servers, leave them half the remaining time.
         let start_time = Instant::now();
         let mut recv_deadline = start_time;
-        let mut deadline = deadline;
-        while recv_deadline < deadline && simple_servers.len() > 0 {
+        let mut simple_deadline = deadline;
+        if (!http_echo_servers.is_empty() || !stun_servers.is_empty()) && deadline > start_time {
+            simple_deadline = start_time + (deadline - start_time) / 2;
+        }
+        while recv_deadline < simple_deadline && simple_servers.len() > 0 {
             recv_deadline = recv_deadline + Duration::from_millis(250);
 
-            // TODO(canndrew): We should limit the number of servers that we send to. If the user
-            // has added two thousand servers we really don't want to be pinging all of them. We
-            // should be smart about it though and try to ping servers that are on different
-            // networks, not just the first ten in the list or something.
-            for simple_server in &simple_servers {
-                // TODO(canndrew): What should we do if we get a partial write?
-                let _ = match socket.send_to(&send_data[..], &**simple_server) {
-                    Ok(n) => n,
-                    Err(e) => return WErr(MappedUdpSocketMapError::SendError { err: e }),
-                };
-            };
+            // Ping the servers in paced bursts so as not to trip flood protection on the router.
+            // TODO(canndrew): We should be smart about which servers go in the first burst and
+            // try to ping servers that are on different networks.
+            let servers: Vec<SocketAddr> = server_order.iter()
+                                                       .filter(|s| simple_servers.contains(s))
+                                                       .cloned()
+                                                       .collect();
+            let burst_size = cmp::max(limits.max_server_queries, 1);
+            for (i, burst) in servers.chunks(burst_size).enumerate() {
+                if i > 0 {
+                    thread::sleep(limits.check_pacing);
+                }
+                for simple_server in burst {
+                    // Servers from before cookies only understand the bare request and newer ones
+                    // may only answer padded requests, so until a server has given us a cookie
+                    // send both.
+                    // TODO(canndrew): What should we do if we get a partial write?
+                    shaper.take(&SystemClock, &**simple_server);
+                    padded_request.cookie = cookies.get(simple_server).cloned();
+                    let padded = padded_request.encode();
+                    let res = match padded_request.cookie {
+                        Some(..) => socket.send_to(&padded[..], &**simple_server),
+                        None => {
+                            socket.send_to(&send_data[..], &**simple_server)
+                                  .and_then(|_| socket.send_to(&padded[..], &**simple_server))
+                        },
+                    };
+                    if let Err(e) = res {
+                        return WErr(MappedUdpSocketMapError::SendError { err: e });
+                    }
+                }
+            }
             let mut recv_data = [0u8; MAX_DATAGRAM_SIZE];
             loop {
-                let (read_size, recv_addr) = match socket.recv_until(&mut recv_data[..], recv_deadline) {
+                let res = socket.recv_until_with_local_ip(&mut recv_data[..], recv_deadline);
+                let (read_size, recv_addr, local_ip) = match res {
                     Ok(Some(res)) => res,
                     Ok(None) => break,
                     Err(e) => return WErr(MappedUdpSocketMapError::RecvError { err: e }),
                 };
-                if let Ok(listener_message::EchoExternalAddr { external_addr }) =
-                       deserialise::<listener_message::EchoExternalAddr>(&recv_data[..read_size]) {
+                let response = &recv_data[..read_size];
+                if let Some((cookie, echoed_nonce)) = listener_message::decode_cookie(response) {
+                    if echoed_nonce == nonce && !cookies.contains_key(&recv_addr) {
+                        // Echo the cookie straight away rather than waiting for the next round.
+                        shaper.take(&SystemClock, &*recv_addr);
+                        padded_request.cookie = Some(cookie);
+                        let request = padded_request.encode();
+                        if let Err(e) = socket.send_to(&request[..], &*recv_addr) {
+                            return WErr(MappedUdpSocketMapError::SendError { err: e });
+                        }
+                        let _ = cookies.insert(recv_addr, cookie);
+                    }
+                    continue;
+                }
+                // Answers to the bare request can't be told from spoofed or stale ones.
+                let answer = if let Some(confirmed) =
+                                    listener_message::ConfirmedExternalAddr::decode(response) {
+                    if confirmed.nonce != nonce {
+                        continue;
+                    }
+                    Some((confirmed.external_addr, false))
+                } else if let Ok(echo) = listener_message::EchoExternalAddr::decode(response) {
+                    Some((echo.external_addr, true))
+                } else {
+                    None
+                };
+                if let Some((external_addr, unverified)) = answer {
                     // Don't ping this simple server again while mapping this socket.
                     simple_servers.remove(&recv_addr);
+                    simple_server_responded = true;
+                    external_addrs.push(*external_addr);
+                    if let Some(local_ip) = local_ip {
+                        if !response_local_ips.contains(&local_ip) {
+                            response_local_ips.push(local_ip);
+                        }
+                    }
 
                     // If the address that responded to us is global then drop max_attempts to exit
                     // the loop more quickly. The logic here is that global addresses are the ones
@@ -331,35 +634,227 @@ impl MappedUdpSocket {
                     let is_global = false;
                     if is_global {
                         let now = Instant::now();
-                        if deadline > now {
-                            deadline = now + (now - deadline) / 2;
+                        if simple_deadline > now {
+                            simple_deadline = now + (now - simple_deadline) / 2;
                         }
                     };
 
                     // Add this endpoint if we don't already know about it. We may have found it
-                    // through IGD or it may be a local interface.
-                    if endpoints.iter().all(|e| e.addr != external_addr) {
-                        endpoints.push(MappedSocketAddr {
-                            addr: external_addr,
-                            // TODO(canndrew): We should consider ways to determine whether this is
-                            // actually an restricted port. For now, just assume it's restricted. It
-                            // usually will be.
-                            nat_restricted: true,
-                        });
+                    // through IGD or it may be a local interface, or another server may have
+                    // reported it without confirming it.
+                    if let Some(endpoint) = endpoints.iter_mut().find(|e| e.addr == external_addr) {
+                        endpoint.unverified = endpoint.unverified && unverified;
+                        continue;
                     }
+                    push_endpoint(&mut endpoints, &trickle, Strategy::SimpleServer, MappedSocketAddr {
+                        addr: external_addr,
+                        // TODO(canndrew): We should consider ways to determine whether this is
+                        // actually an restricted port. For now, just assume it's restricted. It
+                        // usually will be.
+                        nat_restricted: true,
+                        port_unknown: false,
+                        unverified: unverified,
+                    });
+                }
+            }
+        }
+        if queried_simple_servers {
+            mc.record_strategy_result(Strategy::SimpleServer, simple_server_responded);
+        }
+        correct_host_candidates(&mut endpoints, &trickle, local_addr, &response_local_ips);
+
+        // If no simple server could be reached, try any public STUN servers we know of. If http
+        // echo servers are configured too, leave them half the remaining time.
+        let mut stun_responded = false;
+        if !simple_server_responded && !stun_servers.is_empty() &&
+           !mapping_context::should_skip_strategy(mc, Strategy::Stun) {
+            events.send(Event::StrategyChanged { strategy: Strategy::Stun });
+            let now = Instant::now();
+            let mut stun_deadline = deadline;
+            if !http_echo_servers.is_empty() && deadline > now {
+                stun_deadline = now + (deadline - now) / 2;
+            }
+            for server in stun_servers {
+                if Instant::now() >= stun_deadline {
+                    break;
+                }
+                let query_deadline = timeouts.server_query_deadline(stun_deadline);
+                let res = query_stun_server(&socket, &server, query_deadline);
+                let external_addr = match res {
+                    Ok(external_addr) => SocketAddr(external_addr),
+                    Err(e) => {
+                        warnings.push(MappedUdpSocketMapWarning::Stun {
+                            server: server,
+                            err: e,
+                        });
+                        continue;
+                    },
+                };
+                stun_responded = true;
+                external_addrs.push(*external_addr);
+                if endpoints.iter().all(|e| e.addr != external_addr) {
+                    push_endpoint(&mut endpoints, &trickle, Strategy::Stun, MappedSocketAddr {
+                        addr: external_addr,
+                        nat_restricted: true,
+                        port_unknown: false,
+                        unverified: false,
+                    });
+                }
+                break;
+            }
+            mc.record_strategy_result(Strategy::Stun, stun_responded);
+        }
+        if cached_ip.is_none() {
+            mapping_context::cache_external_addrs(mc, local_addr, &external_addrs);
+        }
+
+        // Mappings from a gateway behind another NAT can never be reached, so drop them and stop
+        // asking gateways on this network.
+        let reflexive_ips: Vec<IpAddr> = external_addrs.iter().map(|addr| addr.ip()).collect();
+        if let Some(double_nat) = detect_double_nat(&igd_ips, &reflexive_ips) {
+            mapping_context::record_double_nat(mc, double_nat);
+            if double_nat {
+                endpoints.retain(|endpoint| !igd_ips.contains(&endpoint.addr.ip()));
+                for ip in igd_ips {
+                    warnings.push(MappedUdpSocketMapWarning::DoubleNat {
+                        gateway_external_ip: ip,
+                    });
+                }
+            }
+        }
+
+        // If neither simple nor STUN servers could be reached, udp may be blocked on this
+        // network. Ask the http echo servers for our external ip instead. They can't tell us
+        // which port the NAT would map this socket to so guess that it preserves our local port.
+        if !simple_server_responded && !stun_responded && !http_echo_servers.is_empty() &&
+           !mapping_context::should_skip_strategy(mc, Strategy::HttpEcho) {
+            events.send(Event::StrategyChanged { strategy: Strategy::HttpEcho });
+            let mut http_echo_responded = false;
+            for url in http_echo_servers {
+                if Instant::now() >= deadline {
+                    break;
+                }
+                let query_deadline = timeouts.server_query_deadline(deadline);
+                let ip = match http_discovery::query_http_echo_server(&url, query_deadline) {
+                    Ok(ip) => ip,
+                    Err(e) => {
+                        warnings.push(MappedUdpSocketMapWarning::HttpEcho {
+                            url: url,
+                            err: e,
+                        });
+                        continue;
+                    },
+                };
+                http_echo_responded = true;
+                let addr = SocketAddr(net::SocketAddr::new(ip, local_addr.port()));
+                let same_family = match (ip, local_addr) {
+                    (IpAddr::V4(..), net::SocketAddr::V4(..)) => true,
+                    (IpAddr::V6(..), net::SocketAddr::V6(..)) => true,
+                    _ => false,
+                };
+                if same_family && endpoints.iter().all(|e| e.addr != addr) {
+                    push_endpoint(&mut endpoints, &trickle, Strategy::HttpEcho, MappedSocketAddr {
+                        addr: addr,
+                        nat_restricted: true,
+                        port_unknown: true,
+                        unverified: false,
+                    });
                 }
+                break;
             }
+            mc.record_strategy_result(Strategy::HttpEcho, http_echo_responded);
         }
 
+        // Only report what the application is willing to share. Tunneled ipv6 endpoints often
+        // blackhole traffic so have peers try them last.
+        let endpoints = mapping_context::exposed_endpoints(mc, endpoints);
         WOk(MappedUdpSocket {
             socket: socket,
             endpoints: endpoints,
         }, warnings)
     }
+}
+
+// Whether there's a NAT beyond the IGD gateways, going by the external addresses they gave us
+// and the ones the servers saw us at. `None` if it can't be told.
+fn detect_double_nat(igd_ips: &[IpAddr], reflexive_ips: &[IpAddr]) -> Option<bool> {
+    if igd_ips.is_empty() {
+        return None;
+    }
+    let mut unroutable = SubnetList::private();
+    unroutable.extend(&SubnetList::shared());
+    if igd_ips.iter().any(|ip| unroutable.contains(ip)) {
+        return Some(true);
+    }
+    // Gateways only map ipv4, so only the servers' ipv4 answers can be compared.
+    let mut compared = false;
+    for ip in reflexive_ips {
+        if let IpAddr::V4(..) = *ip {
+            if !igd_ips.contains(ip) {
+                return Some(true);
+            }
+            compared = true;
+        }
+    }
+    if compared { Some(false) } else { None }
+}
+
+// The interface list can be stale, or filtered by the ipv6 address preference, so for a socket
+// bound to the unspecified address add any address the servers' responses arrived on that we
+// don't advertise yet. Those addresses are the ones our routes actually use, so move them to the front
+// of the host candidates for peers to try first.
+fn correct_host_candidates(endpoints: &mut Vec<MappedSocketAddr>,
+                           sink: &CandidateSink,
+                           local_addr: net::SocketAddr,
+                           response_local_ips: &[IpAddr]) {
+    let unspecified = match local_addr.ip() {
+        IpAddr::V4(ip) => socket_utils::ipv4_is_unspecified(&ip),
+        IpAddr::V6(ip) => socket_utils::ipv6_is_unspecified(&ip),
+    };
+    if !unspecified {
+        return;
+    }
+    let mut learned = Vec::new();
+    for ip in response_local_ips {
+        let addr = SocketAddr(net::SocketAddr::new(*ip, local_addr.port()));
+        match endpoints.iter().position(|e| e.addr == addr) {
+            Some(i) => learned.push(endpoints.remove(i)),
+            None => {
+                // Announce it like the other host candidates, then take it back to reorder.
+                push_endpoint(endpoints, sink, Strategy::LocalInterface, MappedSocketAddr {
+                    addr: addr,
+                    nat_restricted: false,
+                    port_unknown: false,
+                    unverified: false,
+                });
+                learned.extend(endpoints.pop());
+            },
+        }
+    }
+    let rest = mem::replace(endpoints, learned);
+    endpoints.extend(rest);
+}
 
+impl MappedUdpSocket {
     /// Create a new `MappedUdpSocket`
     pub fn new(mc: &MappingContext, deadline: Instant)
             -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketNewError>
+    {
+        Self::new_impl(mc, None, deadline)
+    }
+
+    /// Like `new` but pins the socket to the network interface `if_name`, eg. `"eth1"`, so that
+    /// the servers are queried, and holes punched, through that interface only. Only the
+    /// interface's own addresses and IGD gateway are used. Pinning is supported on Linux, where
+    /// it needs CAP_NET_RAW before Linux 5.7, and on macOS and iOS.
+    pub fn new_on_interface(mc: &MappingContext, if_name: &str, deadline: Instant)
+            -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketNewError>
+    {
+        Self::new_impl(mc, Some(if_name), deadline)
+    }
+
+    fn new_impl(mc: &MappingContext, if_name: Option<&str>, deadline: Instant)
+            -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketNewError>
     {
         // Sometimes we might bind a socket to a random port then find that we have an IGD gateway
         // that could give us an unrestricted external port but that it can't map the random port
@@ -367,10 +862,46 @@ impl MappedUdpSocket {
         let mut attempt = 0;
         'attempt: loop {
             attempt += 1;
-            let socket = match UdpSocket::bind("0.0.0.0:0") {
-                Ok(socket) => socket,
-                Err(e) => return WErr(MappedUdpSocketNewError::CreateSocket { err: e }),
+            // Prefer ipv6 on ipv6-only networks, eg. some mobile carriers. Peers' ipv4 endpoints
+            // can then be reached through NAT64, see `synthesize_nat64_candidates`.
+            let ipv6 = mc.is_ipv6_only();
+            let bind_ip = match (if_name, ipv6) {
+                (Some(if_name), _) => {
+                    match mapping_context::interface_bind_ip(mc, if_name, ipv6) {
+                        Some(ip) => ip,
+                        None => return WErr(MappedUdpSocketNewError::NoSuchInterface {
+                            if_name: if_name.to_owned(),
+                        }),
+                    }
+                },
+                (None, true) => IpAddr::V6(mapping_context::ipv6_bind_addr(mc)),
+                (None, false) => IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0)),
             };
+            // Try to keep the port of the last socket we mapped. If something else has it now,
+            // bind to any port.
+            let port_hint = match attempt {
+                1 => mapping_context::port_hint(mc, igd::PortMappingProtocol::UDP),
+                _ => None,
+            };
+            let hinted_socket = match port_hint {
+                Some(port) => UdpSocket::bind(net::SocketAddr::new(bind_ip, port)).ok(),
+                None => None,
+            };
+            let socket = match hinted_socket {
+                Some(socket) => socket,
+                None => match UdpSocket::bind(net::SocketAddr::new(bind_ip, 0)) {
+                    Ok(socket) => socket,
+                    Err(e) => return WErr(MappedUdpSocketNewError::CreateSocket { err: e }),
+                },
+            };
+            if let Some(if_name) = if_name {
+                match socket_utils::bind_to_interface(&socket, if_name, ipv6) {
+                    Ok(()) => (),
+                    Err(e) => return WErr(MappedUdpSocketNewError::BindToInterface { err: e }),
+                }
+            }
+            // Best effort, so that `map` can tell which of our addresses the servers reach.
+            let _ = pktinfo::enable(&socket);
             let (socket, warnings) = match Self::map(socket, mc, deadline) {
                 WOk(s, ws) => (s, ws),
                 WErr(e) => return WErr(MappedUdpSocketNewError::MapSocket { err: e }),
@@ -387,8 +918,110 @@ impl MappedUdpSocket {
                     }
                 }
             }
+            if let Ok(local_addr) = socket.socket.local_addr() {
+                mapping_context::record_mapped_port(mc, igd::PortMappingProtocol::UDP,
+                                                    local_addr.port());
+            }
             return WOk(socket, warnings);
         }
     }
 }
 
+// The STUN client used when mapping. When the `stun` feature is disabled there are no STUN servers
+// to ask and these stand-ins are never called, so that `MappedUdpSocketMapWarning` is the same
+// with or without the feature.
+#[cfg(not(feature = "stun"))]
+mod stun_disabled {
+    use std::net::SocketAddr;
+    use std::time::Instant;
+
+    use error_code::{ErrorCategory, ErrorCode};
+
+    quick_error! {
+        /// Stand-in for `stun::StunQueryError`.
+        #[derive(Debug)]
+        pub enum StunQueryError {
+            /// STUN queries are not available in this build.
+            Disabled {
+                description("STUN support was disabled at compile time.")
+            }
+        }
+    }
+
+    impl ErrorCode for StunQueryError {
+        fn code(&self) -> u32 {
+            match *self {
+                StunQueryError::Disabled => 2707,
+            }
+        }
+
+        fn category(&self) -> ErrorCategory {
+            match *self {
+                StunQueryError::Disabled => ErrorCategory::Unsupported,
+            }
+        }
+    }
+
+    pub fn query_stun_server<S>(_socket: &S, _server: &SocketAddr, _deadline: Instant)
+                                -> Result<SocketAddr, StunQueryError> {
+        Err(StunQueryError::Disabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{IpAddr, UdpSocket};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use mapped_socket_addr::MappedSocketAddr;
+    use mapping_context::MappingContext;
+    use simple_udp_hole_punch_server::SimpleUdpHolePunchServer;
+    use socket_utils;
+
+    #[test]
+    fn double_nat_is_told_from_the_gateway_and_server_addresses() {
+        use super::detect_double_nat;
+
+        let ip = |s: &str| -> IpAddr { unwrap_result!(s.parse()) };
+        // Nothing to go on without a gateway, or without an ipv4 server answer.
+        assert_eq!(detect_double_nat(&[], &[ip("1.2.3.4")]), None);
+        assert_eq!(detect_double_nat(&[ip("1.2.3.4")], &[ip("2001:db8::1")]), None);
+        assert_eq!(detect_double_nat(&[ip("1.2.3.4")], &[ip("1.2.3.4")]), Some(false));
+        assert_eq!(detect_double_nat(&[ip("1.2.3.4")], &[ip("5.6.7.8")]), Some(true));
+        // A gateway with a private or carrier-grade NAT address is behind another NAT whatever
+        // the servers say.
+        assert_eq!(detect_double_nat(&[ip("192.168.1.10")], &[]), Some(true));
+        assert_eq!(detect_double_nat(&[ip("100.64.3.4")], &[]), Some(true));
+    }
+
+    #[test]
+    fn map_trickle_reports_every_endpoint() {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let server_mc = unwrap_result!(MappingContext::new().result_discard());
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new(Box::new(server_mc),
+                                                                  deadline).result_discard());
+        let server_addr = unwrap_result!(server.addresses().into_iter().find(|addr| {
+            socket_utils::is_loopback(&addr.ip())
+        }).ok_or("No loopback address"));
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        mc.add_simple_udp_servers(Some(server_addr));
+
+        let trickled = Mutex::new(Vec::new());
+        let on_candidate = |endpoint: &MappedSocketAddr| {
+            unwrap_result!(trickled.lock()).push(endpoint.clone());
+        };
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let mapped = unwrap_result!(MappedUdpSocket::map_trickle(socket, &mc, deadline,
+                                                                 &on_candidate)
+                                        .result_discard());
+
+        let trickled = unwrap_result!(trickled.lock());
+        assert!(!mapped.endpoints.is_empty());
+        for endpoint in &mapped.endpoints {
+            assert!(trickled.contains(endpoint));
+        }
+    }
+}
@@ -0,0 +1,44 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Finding the endpoints that peers can reach a socket on: through the local interfaces, IGD
+//! gateways, simple hole punch servers, STUN and http echo servers or a SOCKS5 proxy.
+
+pub use dns_discovery::{resolve_srv, resolve_txt, DnsDiscoveryError, SrvRecord};
+pub use http_discovery::{query_http_echo_server, HttpDiscoveryError};
+pub use ipv6_selection::Ipv6AddrPreference;
+pub use mapped_socket_addr::{CandidateClass, MappedSocketAddr, ParseMappedSocketAddrError};
+#[cfg(feature = "tcp")]
+pub use mapped_tcp_socket::{new_reusably_bound_mptcp_socket, new_reusably_bound_tcp_socket,
+                            MappedTcpSocket,
+                            MappedTcpSocketMapError, MappedTcpSocketMapWarning,
+                            MappedTcpSocketNewError, NewReusablyBoundTcpSocketError};
+pub use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketMapError,
+                            MappedUdpSocketMapWarning, MappedUdpSocketNewError};
+pub use mapping_behaviour::{check_mapping_behaviour, MappingBehaviour, MappingBehaviourError};
+pub use mapping_context::{ConcurrencyLimits, MappingContext, MappingContextNewError,
+                          MappingContextNewWarning, DEFAULT_EXTERNAL_ADDR_TTL_SECS};
+pub use nat64::{discover_nat64_prefixes, synthesize_nat64_candidates, Nat64Error, Nat64Prefix};
+pub use nat_probe::{check_hairpinning, probe_nat, NatReport};
+pub use network_monitor::NetworkMonitor;
+pub use privacy::ExposurePolicy;
+pub use socks5::{map_socks5_udp, Socks5Error, Socks5Proxy, Socks5UdpSocket};
+pub use strategy_history::StrategyHistory;
+#[cfg(feature = "stun")]
+pub use stun::{query_stun_server, StunQueryError};
+pub use timeouts::Timeouts;
+pub use virtual_interfaces::VirtualInterfacePolicy;
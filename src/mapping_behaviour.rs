@@ -0,0 +1,193 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Checking how a NAT maps a socket's traffic, with the help of a single hole punch server.
+
+use std::io;
+use std::net::{self, UdpSocket};
+use std::time::{Duration, Instant};
+
+use rand;
+use socket_addr::SocketAddr;
+
+use error_code::{ErrorCategory, ErrorCode};
+use listener_message::{self, MappingBehaviourReport, MappingBehaviourRequest};
+use socket_utils::RecvUntil;
+
+/// How often unanswered requests are resent.
+const RESEND_INTERVAL_MS: u64 = 500;
+
+/// How a NAT maps the traffic a socket sends to different destinations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub enum MappingBehaviour {
+    /// The socket appears at the same external address whatever the destination, so an address
+    /// learnt from one server can be handed to any peer.
+    EndpointIndependent,
+    /// The socket appears at a different external address for each destination, so peers can't
+    /// reach it at the address learnt from a server and must guess or be relayed.
+    EndpointDependent,
+}
+
+quick_error! {
+    /// Errors returned by `check_mapping_behaviour`.
+    #[derive(Debug)]
+    pub enum MappingBehaviourError {
+        /// IO error sending a request.
+        Send { err: io::Error } {
+            description("IO error sending a request.")
+            display("IO error sending a request: {}", err)
+            cause(err)
+        }
+        /// IO error receiving a report.
+        Recv { err: io::Error } {
+            description("IO error receiving a report.")
+            display("IO error receiving a report: {}", err)
+            cause(err)
+        }
+        /// The server did not report back before the deadline.
+        TimedOut {
+            description("The server did not report back before the deadline.")
+        }
+        /// The server has no secondary port to compare mappings with.
+        NoSecondaryPort {
+            description("The server has no secondary port to compare mappings with.")
+        }
+    }
+}
+
+impl From<MappingBehaviourError> for io::Error {
+    fn from(e: MappingBehaviourError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            MappingBehaviourError::Send { err } => err.kind(),
+            MappingBehaviourError::Recv { err } => err.kind(),
+            MappingBehaviourError::TimedOut => io::ErrorKind::TimedOut,
+            MappingBehaviourError::NoSecondaryPort => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for MappingBehaviourError {
+    fn code(&self) -> u32 {
+        match *self {
+            MappingBehaviourError::Send { .. } => 3501,
+            MappingBehaviourError::Recv { .. } => 3502,
+            MappingBehaviourError::TimedOut => 3503,
+            MappingBehaviourError::NoSecondaryPort => 3504,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            MappingBehaviourError::Send { .. } => ErrorCategory::Network,
+            MappingBehaviourError::Recv { .. } => ErrorCategory::Network,
+            MappingBehaviourError::TimedOut => ErrorCategory::Network,
+            MappingBehaviourError::NoSecondaryPort => ErrorCategory::Unsupported,
+        }
+    }
+}
+
+/// Find out how the NAT in front of `socket` maps its traffic, using the `SimpleUdpHolePunchServer`
+/// at `server_addr`. The server reports the address it sees `socket` at on its main port, then
+/// again on a secondary port, and says whether the two are the same. This only needs one server,
+/// though it can't tell a NAT whose mapping depends on the destination's address but not its port
+/// from an endpoint-independent one.
+pub fn check_mapping_behaviour(socket: &UdpSocket, server_addr: &SocketAddr, deadline: Instant)
+                               -> Result<MappingBehaviour, MappingBehaviourError> {
+    let first = try!(request_report(socket, &**server_addr, None, deadline));
+    if first.secondary_port == 0 {
+        return Err(MappingBehaviourError::NoSecondaryPort);
+    }
+    let mut secondary_addr = **server_addr;
+    secondary_addr.set_port(first.secondary_port);
+    let second = try!(request_report(socket,
+                                     &secondary_addr,
+                                     Some(first.external_addr),
+                                     deadline));
+    match second.verdict {
+        listener_message::MAPPING_VERDICT_SAME => Ok(MappingBehaviour::EndpointIndependent),
+        _ => Ok(MappingBehaviour::EndpointDependent),
+    }
+}
+
+// Send a request to `addr` until it answers or `deadline` passes.
+fn request_report(socket: &UdpSocket,
+                  addr: &net::SocketAddr,
+                  first_addr: Option<net::SocketAddr>,
+                  deadline: Instant)
+                  -> Result<MappingBehaviourReport, MappingBehaviourError> {
+    let request = MappingBehaviourRequest {
+        nonce: rand::random(),
+        first_addr: first_addr,
+    };
+    let mut recv_data = [0u8; listener_message::MAPPING_BEHAVIOUR_REPORT_LEN + 1];
+    let mut recv_deadline = Instant::now();
+    while recv_deadline < deadline {
+        recv_deadline = recv_deadline + Duration::from_millis(RESEND_INTERVAL_MS);
+        if recv_deadline > deadline {
+            recv_deadline = deadline;
+        }
+        if let Err(e) = socket.send_to(&request.encode()[..], addr) {
+            return Err(MappingBehaviourError::Send { err: e });
+        }
+        loop {
+            let res = socket.recv_until(&mut recv_data[..], recv_deadline);
+            let (read_size, recv_addr) = match res {
+                Ok(Some(res)) => res,
+                Ok(None) => break,
+                Err(e) => return Err(MappingBehaviourError::Recv { err: e }),
+            };
+            if *recv_addr != *addr {
+                continue;
+            }
+            match MappingBehaviourReport::decode(&recv_data[..read_size]) {
+                Some(report) if report.nonce == request.nonce => return Ok(report),
+                _ => continue,
+            }
+        }
+    }
+    Err(MappingBehaviourError::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use mapping_context::MappingContext;
+    use simple_udp_hole_punch_server::SimpleUdpHolePunchServer;
+    use socket_utils;
+
+    #[test]
+    fn loopback_mapping_is_endpoint_independent() {
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new(Box::new(mapping_context),
+                                                                  deadline).result_discard());
+        let addr = unwrap_result!(server.addresses().into_iter().find(|addr| {
+            socket_utils::is_loopback(&addr.ip())
+        }).ok_or("No loopback address"));
+
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let behaviour = unwrap_result!(check_mapping_behaviour(&socket, &addr, deadline));
+        assert_eq!(behaviour, MappingBehaviour::EndpointIndependent);
+    }
+}
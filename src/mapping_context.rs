@@ -18,41 +18,189 @@
 //! # `nat_traversal`
 //! NAT traversal utilities.
 
-use std::sync::RwLock;
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
 use std::io;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
-use std::time::Duration;
+use std::time::{Instant, Duration};
+use std::fmt;
 
-use igd;
+use gateway as igd;
 use socket_addr::SocketAddr;
 use w_result::{WResult, WOk, WErr};
 use get_if_addrs;
 use void::Void;
 
+use clock::SystemClock;
+use dns_discovery;
+use dns_discovery::DnsDiscoveryError;
+use error_code::{ErrorCategory, ErrorCode};
+use event::{Event, EventSender, Strategy};
+use mapped_socket_addr::MappedSocketAddr;
+use nat64;
+use ipv6_selection::{self, AddrState, Ipv6AddrPreference};
+use pacer::TrafficShaper;
+use ping::{self, LatencyStats, PingServerError};
+use privacy::{ExposurePolicy, Redacted};
+use routes;
+use runtime;
+use runtime::{Runtime, RuntimeHandle};
+use snapshot::Snapshot;
+use socks5::Socks5Proxy;
+use nat64::{Nat64Error, Nat64Prefix};
+use strategy_history::StrategyHistory;
+use timeouts::Timeouts;
 use socket_utils;
+use virtual_interfaces::{self, VirtualInterfacePolicy};
 
 /// You need to create a `MappingContext` before doing any socket mapping. This
 /// `MappingContext` should ideally be kept throughout the lifetime of the
 /// program. Internally it caches a addresses of UPnP servers and hole punching
 /// servers.
 pub struct MappingContext {
-    interfaces_v4: RwLock<Vec<InterfaceV4>>,
-    interfaces_v6: RwLock<Vec<InterfaceV6>>,
-    simple_udp_servers: RwLock<Vec<SocketAddr>>,
-    simple_tcp_servers: RwLock<Vec<SocketAddr>>,
+    // Every mapping reads these while writes are rare, so readers take snapshots rather than
+    // holding a lock for as long as they use them.
+    interfaces_v4: Snapshot<Vec<InterfaceV4>>,
+    interfaces_v6: Snapshot<Vec<InterfaceV6>>,
+    default_gateway_v4: RwLock<Option<Ipv4Addr>>,
+    simple_udp_servers: Snapshot<Vec<SocketAddr>>,
+    simple_tcp_servers: Snapshot<Vec<SocketAddr>>,
+    http_echo_servers: Snapshot<Vec<String>>,
+    stun_servers: Snapshot<Vec<SocketAddr>>,
+    dns_servers: Snapshot<Option<DnsServers>>,
+    nat64_prefixes: RwLock<Vec<Nat64Prefix>>,
+    suppress_tunneled_ipv6: AtomicBool,
+    exposure_policy: RwLock<ExposurePolicy>,
+    ipv6_addr_preference: RwLock<Ipv6AddrPreference>,
+    virtual_interface_policy: RwLock<VirtualInterfacePolicy>,
+    socks5_proxy: RwLock<Option<Socks5Proxy>>,
+    events: EventSender,
+    strategy_history: Mutex<StrategyHistory>,
+    // Started by the first call to `runtime`, so contexts which never run background work don't
+    // spawn its threads.
+    runtime: Mutex<Option<Runtime>>,
+    // Whether `new` and `network_changed` search for IGD gateways on threads of their own, rather
+    // than leaving it to the caller's `search_gateways`.
+    search_gateways_on_threads: bool,
+    concurrency_limits: RwLock<ConcurrencyLimits>,
+    timeouts: RwLock<Timeouts>,
+    external_addr_cache: Mutex<HashMap<IpAddr, CachedExternalAddr>>,
+    external_addr_ttl: RwLock<Duration>,
+    // Whether mapping found a NAT beyond the IGD gateway, if it could tell.
+    double_nat: RwLock<Option<bool>>,
+    port_reuse: AtomicBool,
+    last_udp_port: Mutex<Option<u16>>,
+    last_tcp_port: Mutex<Option<u16>>,
+    traffic_shaper: TrafficShaper,
 }
 
+impl fmt::Debug for MappingContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MappingContext")
+         .field("simple_udp_servers", &*self.simple_udp_servers.load())
+         .field("simple_tcp_servers", &*self.simple_tcp_servers.load())
+         .field("http_echo_servers", &*self.http_echo_servers.load())
+         .field("stun_servers", &*self.stun_servers.load())
+         .field("concurrency_limits", &*unwrap_result!(self.concurrency_limits.read()))
+         .field("timeouts", &*unwrap_result!(self.timeouts.read()))
+         .finish()
+    }
+}
+
+/// Limits on how hard traversal hits the network at once. Flooding a home router with packets to
+/// many destinations can trip its flood protection or rate limiting, so servers and peer
+/// endpoints are contacted in paced bursts rather than all at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct ConcurrencyLimits {
+    /// The most servers of one kind queried at once when mapping a socket. Udp servers beyond
+    /// this are queried in later bursts; tcp servers beyond this aren't queried at all.
+    pub max_server_queries: usize,
+    /// The most peer endpoints sent hole punch packets in one burst.
+    pub max_parallel_checks: usize,
+    /// The pause between one burst of server queries or hole punch packets and the next.
+    pub check_pacing: Duration,
+    /// The pause before hole punching starts towards each further peer endpoint, as ICE paces
+    /// new checks. Endpoints are started one at a time, taking host, server reflexive and
+    /// predicted endpoints in turn, so that the first packets of a punch don't fill a small NAT's
+    /// mapping table. Zero starts them all at once.
+    pub new_check_interval: Duration,
+    /// The average rate at which hole punch packets, including resends and acks, are sent. This
+    /// is halved, down to a floor, whenever sends fail or a round of packets goes unanswered.
+    pub max_packets_per_sec: u32,
+    /// The most hole punch packets sent back to back before `max_packets_per_sec` applies.
+    pub packet_burst: u32,
+    /// A cap on the probes sent each second by all the traversal attempts using a context put
+    /// together, including server queries and hole punch packets. Zero means no cap. Set this on
+    /// networks whose intrusion detection takes aggressive hole punching for a port scan.
+    pub max_probes_per_sec: u32,
+    /// The least time between the first probes to two different destinations, across all the
+    /// traversal attempts using a context. Destinations probed in the last minute don't count
+    /// as new.
+    pub new_port_spacing: Duration,
+}
+
+impl Default for ConcurrencyLimits {
+    fn default() -> ConcurrencyLimits {
+        ConcurrencyLimits {
+            max_server_queries: 8,
+            max_parallel_checks: 16,
+            check_pacing: Duration::from_millis(20),
+            new_check_interval: Duration::from_millis(50),
+            max_packets_per_sec: 400,
+            packet_burst: 32,
+            max_probes_per_sec: 0,
+            new_port_spacing: Duration::new(0, 0),
+        }
+    }
+}
+
+/// How long an external address learned from the servers is reused for by default.
+pub const DEFAULT_EXTERNAL_ADDR_TTL_SECS: u64 = 30;
+
+// The external ip that servers saw for sockets bound to some local ip. This is only cached when
+// the NAT kept the socket's local port, as otherwise the servers need asking which port a new
+// socket has been mapped to.
+struct CachedExternalAddr {
+    ip: IpAddr,
+    expires_at: Instant,
+}
+
+// Servers advertised through SRV records under the domain set with `set_discovery_domain`.
+#[derive(Clone)]
+struct DnsServers {
+    domain: String,
+    udp_servers: Vec<SocketAddr>,
+    #[cfg_attr(not(feature = "tcp"), allow(dead_code))]
+    tcp_servers: Vec<SocketAddr>,
+    hints: Vec<String>,
+    refresh_at: Instant,
+}
+
+/// Discovered servers are re-resolved at least this often, whatever their ttl.
+const MAX_DNS_TTL_SECS: u32 = 24 * 60 * 60;
+/// Discovered servers are not re-resolved more often than this, including after a failure.
+const MIN_DNS_TTL_SECS: u32 = 60;
+
 #[derive(Clone)]
 pub struct InterfaceV4 {
     pub gateway: Option<igd::Gateway>,
     pub addr: Ipv4Addr,
+    pub name: String,
+    pub is_virtual: bool,
 }
 
 // TODO(canndrew): Can we support IGD on ipv6?
 #[derive(Clone)]
 pub struct InterfaceV6 {
     pub addr: Ipv6Addr,
+    pub state: AddrState,
+    pub name: String,
+    pub is_virtual: bool,
 }
 
 quick_error! {
@@ -90,6 +238,22 @@ impl From<MappingContextNewError> for io::Error {
     }
 }
 
+impl ErrorCode for MappingContextNewError {
+    fn code(&self) -> u32 {
+        match *self {
+            MappingContextNewError::ListInterfaces { .. } => 101,
+            MappingContextNewError::SpawnThread { .. } => 102,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            MappingContextNewError::ListInterfaces { .. } => ErrorCategory::Network,
+            MappingContextNewError::SpawnThread { .. } => ErrorCategory::Network,
+        }
+    }
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum MappingContextNewWarning {
@@ -103,9 +267,35 @@ quick_error! {
             description("Failed to find IGD gateway")
             display("Failed to find an IGD gateway on network interface {} {}. \
                      igd::search_gateway_from_timeout returned an error: {}",
-                     if_name, if_addr, err)
+                     if_name, Redacted(if_addr), err)
             cause(err)
         }
+        /// The thread searching for an IGD gateway from a network interface panicked. The
+        /// interface is used without a gateway.
+        SearchGatewayPanicked {
+            if_name: String,
+            if_addr: Ipv4Addr,
+        } {
+            description("Thread searching for IGD gateway panicked")
+            display("The thread searching for an IGD gateway on network interface {} {} \
+                     panicked", if_name, Redacted(if_addr))
+        }
+    }
+}
+
+impl ErrorCode for MappingContextNewWarning {
+    fn code(&self) -> u32 {
+        match *self {
+            MappingContextNewWarning::SearchGateway { .. } => 201,
+            MappingContextNewWarning::SearchGatewayPanicked { .. } => 202,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            MappingContextNewWarning::SearchGateway { .. } => ErrorCategory::Network,
+            MappingContextNewWarning::SearchGatewayPanicked { .. } => ErrorCategory::Protocol,
+        }
     }
 }
 
@@ -113,77 +303,58 @@ impl MappingContext {
     /// Create a new mapping context. This will block breifly while it searches
     /// the network for UPnP servers.
     pub fn new() -> WResult<MappingContext, MappingContextNewWarning, MappingContextNewError> {
-        let interfaces = match get_if_addrs::get_if_addrs() {
-            Ok(if_addrs) => if_addrs,
-            Err(e) => return WErr(MappingContextNewError::ListInterfaces { err: e }),
-        };
-        let mut interfaces_v4 = Vec::new();
-        let mut interfaces_v6 = Vec::new();
-        let mut warnings = Vec::new();
-        let mut search_threads = Vec::new();
-        for interface in interfaces {
-            let addr_v4 = match interface.addr {
-                get_if_addrs::IfAddr::V4(v4_addr) => {
-                    v4_addr.ip
-                },
-                get_if_addrs::IfAddr::V6(v6_addr) => {
-                    interfaces_v6.push(InterfaceV6 {
-                        addr: v6_addr.ip,
-                    });
-                    continue;
-                },
-            };
-            if socket_utils::ipv4_is_loopback(&addr_v4) {
-                interfaces_v4.push(InterfaceV4 {
-                    gateway: None,
-                    addr: addr_v4,
-                });
-                continue;
-            };
-            let if_name = interface.name;
-            search_threads.push(thread::Builder::new()
-                                                .name(From::from("IGD search"))
-                                                .spawn(move || -> WResult<_, _, Void> {
-                let mut warnings = Vec::new();
-                let gateway = match igd::search_gateway_from_timeout(addr_v4, Duration::from_secs(1)) {
-                    Ok(gateway) => Some(gateway),
-                    Err(e) => {
-                        warnings.push(MappingContextNewWarning::SearchGateway {
-                            if_name: if_name,
-                            if_addr: addr_v4,
-                            err: e,
-                        });
-                        None
-                    },
-                };
-                WOk(InterfaceV4 {
-                    gateway: gateway,
-                    addr: addr_v4,
-                }, warnings)
-            }));
-        };
+        MappingContext::with_gateway_search(true)
+    }
 
-        for search_thread in search_threads {
-            match search_thread {
-                Err(e) => return WErr(MappingContextNewError::SpawnThread { err: e }),
-                Ok(jh) => {
-                    // If the child thread panicked, propogate the panic.
-                    let res = unwrap_result!(jh.join());
-                    match res {
-                        WErr(e) => match e {},
-                        WOk(interface, ws) => {
-                            interfaces_v4.push(interface);
-                            warnings.extend(ws);
-                        }
-                    }
-                }
-            }
+    /// Create a new mapping context without spawning any threads. Interfaces are listed but no
+    /// IGD gateways are searched for, here or by `network_changed`, until the caller calls
+    /// `search_gateways` on a thread of its choosing. This is for embedders which can't have the
+    /// library spawn threads; they shouldn't call `runtime` either.
+    pub fn without_gateway_search() -> Result<MappingContext, MappingContextNewError> {
+        match MappingContext::with_gateway_search(false) {
+            WOk(mc, _) => Ok(mc),
+            WErr(e) => Err(e),
         }
+    }
+
+    fn with_gateway_search(search: bool)
+            -> WResult<MappingContext, MappingContextNewWarning, MappingContextNewError> {
+        let (interfaces_v4, interfaces_v6, warnings) = match gather_interfaces(search) {
+            WOk((interfaces_v4, interfaces_v6), warnings) => {
+                (interfaces_v4, interfaces_v6, warnings)
+            },
+            WErr(e) => return WErr(e),
+        };
         let mc = MappingContext {
-            interfaces_v4: RwLock::new(interfaces_v4),
-            interfaces_v6: RwLock::new(interfaces_v6),
-            simple_udp_servers: RwLock::new(Vec::new()),
-            simple_tcp_servers: RwLock::new(Vec::new()),
+            interfaces_v4: Snapshot::new(interfaces_v4),
+            interfaces_v6: Snapshot::new(interfaces_v6),
+            // Not knowing the gateway only rules out asking it for mappings directly, so a failure
+            // to read the routing table isn't worth a warning.
+            default_gateway_v4: RwLock::new(routes::default_gateway_v4().unwrap_or(None)),
+            simple_udp_servers: Snapshot::new(Vec::new()),
+            simple_tcp_servers: Snapshot::new(Vec::new()),
+            http_echo_servers: Snapshot::new(Vec::new()),
+            stun_servers: Snapshot::new(Vec::new()),
+            dns_servers: Snapshot::new(None),
+            nat64_prefixes: RwLock::new(Vec::new()),
+            suppress_tunneled_ipv6: AtomicBool::new(false),
+            exposure_policy: RwLock::new(ExposurePolicy::default()),
+            ipv6_addr_preference: RwLock::new(Ipv6AddrPreference::default()),
+            virtual_interface_policy: RwLock::new(VirtualInterfacePolicy::default()),
+            socks5_proxy: RwLock::new(None),
+            events: EventSender::new(),
+            strategy_history: Mutex::new(StrategyHistory::new()),
+            runtime: Mutex::new(None),
+            search_gateways_on_threads: search,
+            concurrency_limits: RwLock::new(ConcurrencyLimits::default()),
+            timeouts: RwLock::new(Timeouts::default()),
+            external_addr_cache: Mutex::new(HashMap::new()),
+            external_addr_ttl: RwLock::new(Duration::from_secs(DEFAULT_EXTERNAL_ADDR_TTL_SECS)),
+            double_nat: RwLock::new(None),
+            port_reuse: AtomicBool::new(false),
+            last_udp_port: Mutex::new(None),
+            last_tcp_port: Mutex::new(None),
+            traffic_shaper: TrafficShaper::new(&SystemClock, 0, Duration::new(0, 0)),
         };
         WOk(mc, warnings)
     }
@@ -193,8 +364,43 @@ impl MappingContext {
     pub fn add_simple_udp_servers<S>(&self, servers: S)
         where S: IntoIterator<Item=SocketAddr>
     {
-        let mut s = unwrap_result!(self.simple_udp_servers.write());
-        s.extend(servers)
+        self.simple_udp_servers.update(|s| s.extend(servers))
+    }
+
+    /// Measure the latency to each server added with `add_simple_udp_servers` with
+    /// `probe_latency`, sending `probes` probes `interval` apart, and reorder them fastest first
+    /// so that mapping queries the fastest in its first burst. Servers which didn't answer go
+    /// last. The servers are probed one after another, each given an equal share of the time
+    /// left until `deadline`. Returns what was measured.
+    pub fn rank_simple_udp_servers(&self, probes: u32, interval: Duration, deadline: Instant)
+                                   -> Vec<(SocketAddr, Result<LatencyStats, PingServerError>)> {
+        let servers = (*self.simple_udp_servers.load()).clone();
+        let mut results = Vec::new();
+        for (i, server) in servers.iter().enumerate() {
+            let now = Instant::now();
+            let share = if deadline > now {
+                (deadline - now) / (servers.len() - i) as u32
+            } else {
+                Duration::new(0, 0)
+            };
+            results.push((*server, ping::probe_latency(server, probes, interval, now + share)));
+        }
+        let round_trip_times: HashMap<SocketAddr, Duration> =
+            results.iter()
+                   .filter_map(|&(server, ref res)| {
+                       res.as_ref().ok().map(|stats| (server, stats.mean_round_trip_time))
+                   })
+                   .collect();
+        // Servers added while probing weren't measured, so they go last too.
+        self.simple_udp_servers.update(|servers| {
+            servers.sort_by_key(|server| {
+                match round_trip_times.get(server) {
+                    Some(rtt) => (false, *rtt),
+                    None => (true, Duration::new(0, 0)),
+                }
+            })
+        });
+        results
     }
 
     /// Inform the context about external servers that speak the TCP simple hole punch server
@@ -202,25 +408,674 @@ impl MappingContext {
     pub fn add_simple_tcp_servers<S>(&self, servers: S)
         where S: IntoIterator<Item=SocketAddr>
     {
-        let mut s = unwrap_result!(self.simple_tcp_servers.write());
-        s.extend(servers)
+        self.simple_tcp_servers.update(|s| s.extend(servers))
+    }
+
+    /// Inform the context about http "what is my ip" services, eg. `http://example.com/ip`. These
+    /// are only queried when mapping a udp socket and none of the simple servers respond, such as
+    /// on networks which block udp. The resulting endpoint has `port_unknown` set.
+    pub fn add_http_echo_servers<S>(&self, urls: S)
+        where S: IntoIterator<Item=String>
+    {
+        self.http_echo_servers.update(|s| s.extend(urls))
+    }
+
+    /// Inform the context about public STUN servers, eg. `stun.example.com:3478` once resolved.
+    /// These are only queried when mapping a udp socket and none of the simple servers respond.
+    /// Unlike the http echo servers they see the socket's own traffic so the port they report is
+    /// the one the NAT actually mapped. Without the `stun` feature they are never queried.
+    pub fn add_stun_servers<S>(&self, servers: S)
+        where S: IntoIterator<Item=SocketAddr>
+    {
+        self.stun_servers.update(|s| s.extend(servers))
+    }
+
+    /// Discover hole punch servers through DNS. Simple udp servers are read from the SRV records of
+    /// `_nat-punch._udp.<domain>` and simple tcp servers from `_nat-punch._tcp.<domain>`. The
+    /// records are resolved the next time a socket is mapped and re-resolved whenever their ttl
+    /// expires. Servers added with `add_simple_udp_servers` and `add_simple_tcp_servers` are
+    /// still used.
+    pub fn set_discovery_domain(&self, domain: String) {
+        self.dns_servers.store(Some(DnsServers {
+            domain: domain,
+            udp_servers: Vec::new(),
+            tcp_servers: Vec::new(),
+            hints: Vec::new(),
+            refresh_at: Instant::now(),
+        }));
+    }
+
+    /// Resolve the servers of the discovery domain now rather than waiting for their ttl to
+    /// expire. Does nothing if no discovery domain has been set. On failure the previously
+    /// discovered servers are kept.
+    pub fn refresh_discovered_servers(&self, deadline: Instant) -> Result<(), DnsDiscoveryError> {
+        let domain = match *self.dns_servers.load() {
+            Some(ref dns_servers) => dns_servers.domain.clone(),
+            None => return Ok(()),
+        };
+
+        let res = dns_discovery::discover_servers(&domain, "udp", deadline)
+                                .and_then(|(udp_servers, udp_ttl)| {
+            let (tcp_servers, tcp_ttl) = try!(discover_tcp_servers(&domain, deadline));
+            Ok((udp_servers, tcp_servers, cmp::min(udp_ttl, tcp_ttl)))
+        });
+        let hints = dns_discovery::resolve_txt(&format!("_nat-punch._udp.{}", domain), deadline);
+
+        self.dns_servers.update(|dns_servers| {
+            let dns_servers = match *dns_servers {
+                // The domain was changed while we were resolving the old one.
+                Some(ref mut dns_servers) if dns_servers.domain == domain => dns_servers,
+                _ => return Ok(()),
+            };
+            let now = Instant::now();
+            dns_servers.refresh_at = now + Duration::from_secs(MIN_DNS_TTL_SECS as u64);
+            if let Ok(hints) = hints {
+                dns_servers.hints = hints;
+            }
+            let (udp_servers, tcp_servers, ttl) = try!(res);
+            let ttl = cmp::max(MIN_DNS_TTL_SECS, cmp::min(MAX_DNS_TTL_SECS, ttl));
+            dns_servers.refresh_at = now + Duration::from_secs(ttl as u64);
+            dns_servers.udp_servers = udp_servers;
+            dns_servers.tcp_servers = tcp_servers;
+            Ok(())
+        })
+    }
+
+    /// The TXT records of `_nat-punch._udp.<domain>` for the discovery domain. Deployments can use
+    /// these to advertise capabilities of their servers, eg. `caps=udp,tcp`.
+    pub fn discovery_hints(&self) -> Vec<String> {
+        match *self.dns_servers.load() {
+            Some(ref dns_servers) => dns_servers.hints.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Look for a NAT64 gateway on the local network (RFC 7050) and remember its prefixes. This
+    /// should be called on networks without ipv4 connectivity, see `is_ipv6_only`. Returns the
+    /// prefixes found, which are empty if there's no NAT64.
+    pub fn detect_nat64(&self, deadline: Instant) -> Result<Vec<Nat64Prefix>, Nat64Error> {
+        let prefixes = try!(nat64::discover_nat64_prefixes(deadline));
+        *unwrap_result!(self.nat64_prefixes.write()) = prefixes.clone();
+        Ok(prefixes)
+    }
+
+    /// Use `prefixes` for NAT64 rather than detecting them.
+    pub fn set_nat64_prefixes(&self, prefixes: Vec<Nat64Prefix>) {
+        *unwrap_result!(self.nat64_prefixes.write()) = prefixes;
+    }
+
+    /// The NAT64 prefixes of the local network. Pass these to `synthesize_nat64_candidates` to
+    /// reach peers which only have ipv4 endpoints.
+    pub fn nat64_prefixes(&self) -> Vec<Nat64Prefix> {
+        unwrap_result!(self.nat64_prefixes.read()).clone()
+    }
+
+    /// Whether this machine only has ipv6 connectivity, ie. it has no non-loopback ipv4
+    /// interfaces but does have ipv6 ones. Sockets used for traversal on such a network need to
+    /// be ipv6 sockets.
+    pub fn is_ipv6_only(&self) -> bool {
+        let interfaces_v4 = self.interfaces_v4.load();
+        let interfaces_v6 = self.interfaces_v6.load();
+        interfaces_v4.iter().all(|i| socket_utils::ipv4_is_loopback(&i.addr)) &&
+        interfaces_v6.iter().any(|i| !socket_utils::ipv6_is_loopback(&i.addr))
+    }
+
+    /// The ipv4 default gateway as found when the context was created or the network last
+    /// changed. This is read from the routing table on Linux, macOS and the BSDs and is always
+    /// `None` elsewhere.
+    pub fn default_gateway_v4(&self) -> Option<Ipv4Addr> {
+        *unwrap_result!(self.default_gateway_v4.read())
+    }
+
+    /// Drop Teredo and 6to4 endpoints when mapping sockets rather than just trying them last.
+    pub fn set_suppress_tunneled_ipv6(&self, suppress: bool) {
+        self.suppress_tunneled_ipv6.store(suppress, Ordering::SeqCst);
+    }
+
+    /// Set which kinds of endpoint are reported when mapping sockets, and so which are shared with
+    /// peers.
+    pub fn set_exposure_policy(&self, policy: ExposurePolicy) {
+        *unwrap_result!(self.exposure_policy.write()) = policy;
+    }
+
+    /// The policy set with `set_exposure_policy`, or the default which reports everything.
+    pub fn exposure_policy(&self) -> ExposurePolicy {
+        *unwrap_result!(self.exposure_policy.read())
+    }
+
+    /// Choose between stable and temporary global ipv6 addresses when binding and advertising
+    /// endpoints. Defaults to `Ipv6AddrPreference::Stable`. Whether an address is temporary or
+    /// deprecated is only known on Linux. Elsewhere all addresses are treated as stable.
+    pub fn set_ipv6_addr_preference(&self, preference: Ipv6AddrPreference) {
+        *unwrap_result!(self.ipv6_addr_preference.write()) = preference;
+    }
+
+    /// The preference set with `set_ipv6_addr_preference`.
+    pub fn ipv6_addr_preference(&self) -> Ipv6AddrPreference {
+        *unwrap_result!(self.ipv6_addr_preference.read())
+    }
+
+    /// Set what to do with endpoints on virtual interfaces, such as VPN tunnels and Docker
+    /// bridges, when mapping sockets. Defaults to `VirtualInterfacePolicy::Deprioritize`.
+    pub fn set_virtual_interface_policy(&self, policy: VirtualInterfacePolicy) {
+        *unwrap_result!(self.virtual_interface_policy.write()) = policy;
+    }
+
+    /// The policy set with `set_virtual_interface_policy`.
+    pub fn virtual_interface_policy(&self) -> VirtualInterfacePolicy {
+        *unwrap_result!(self.virtual_interface_policy.read())
+    }
+
+    /// Set the SOCKS5 proxy used by `map_socks5_udp`, for networks where udp can only leave
+    /// through a proxy.
+    pub fn set_socks5_proxy(&self, proxy: Option<Socks5Proxy>) {
+        *unwrap_result!(self.socks5_proxy.write()) = proxy;
+    }
+
+    /// Subscribe to the events raised by operations performed using this context.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.events.subscribe()
     }
+
+    /// Get a handle to this context's event subscribers. This can be passed to operations which
+    /// don't take a `MappingContext`, such as hole punching.
+    pub fn event_sender(&self) -> EventSender {
+        self.events.clone()
+    }
+
+    /// Get a copy of the history of which strategies have worked on this network. This can be
+    /// saved with `StrategyHistory::save` and restored in a later session with
+    /// `set_strategy_history`.
+    pub fn strategy_history(&self) -> StrategyHistory {
+        unwrap_result!(self.strategy_history.lock()).clone()
+    }
+
+    /// Replace this context's strategy history, eg. with one loaded from disk.
+    pub fn set_strategy_history(&self, history: StrategyHistory) {
+        *unwrap_result!(self.strategy_history.lock()) = history;
+    }
+
+    /// Record the outcome of using a strategy. Mapping records its own outcomes but applications
+    /// should report the outcome of hole punching through here.
+    pub fn record_strategy_result(&self, strategy: Strategy, succeeded: bool) {
+        unwrap_result!(self.strategy_history.lock()).record(strategy, succeeded)
+    }
+
+    /// The strategy which has worked best on this network so far. Strategies which have
+    /// consistently failed are skipped when mapping sockets.
+    pub fn recommended_strategy(&self) -> Option<Strategy> {
+        unwrap_result!(self.strategy_history.lock()).recommended_strategy()
+    }
+
+    /// Whether this network is behind two layers of NAT: an IGD gateway whose own external address
+    /// is private, or isn't the address the servers see us at. Once this is found the gateway's
+    /// mappings, which peers could never reach, are no longer asked for or handed out. `None`
+    /// until a socket has been mapped through a gateway, and again after `network_changed`.
+    pub fn double_nat(&self) -> Option<bool> {
+        *unwrap_result!(self.double_nat.read())
+    }
+
+    /// Set the limits on how many servers are queried and how many peer endpoints are checked at
+    /// once. Pass `concurrency_limits()` to `PunchedUdpSocket::punch_hole_with_limits` to apply
+    /// them to hole punching too, or punch with `PunchedUdpSocket::punch_hole_in_context` to
+    /// also share the cap on probes with the context's other traversal attempts.
+    pub fn set_concurrency_limits(&self, limits: ConcurrencyLimits) {
+        self.traffic_shaper.reconfigure(&SystemClock, limits.max_probes_per_sec,
+                                        limits.new_port_spacing);
+        *unwrap_result!(self.concurrency_limits.write()) = limits;
+    }
+
+    /// The limits set with `set_concurrency_limits`, or the defaults.
+    pub fn concurrency_limits(&self) -> ConcurrencyLimits {
+        *unwrap_result!(self.concurrency_limits.read())
+    }
+
+    /// Set the timeouts used when mapping sockets. Only `server_query` bounds anything here; the
+    /// deadline passed when mapping a socket should come from `gather_deadline`. The rest are
+    /// kept for the application to pass on to hole punching.
+    pub fn set_timeouts(&self, timeouts: Timeouts) {
+        *unwrap_result!(self.timeouts.write()) = timeouts;
+    }
+
+    /// The timeouts set with `set_timeouts`, or the defaults.
+    pub fn timeouts(&self) -> Timeouts {
+        *unwrap_result!(self.timeouts.read())
+    }
+
+    /// Set how long the external address that servers report for a socket is reused for when
+    /// mapping further sockets bound to the same local ip. Sockets mapped within the ttl skip
+    /// querying the simple and STUN servers. This only applies on networks where the NAT keeps
+    /// sockets' local ports. A ttl of zero disables caching.
+    pub fn set_external_addr_ttl(&self, ttl: Duration) {
+        *unwrap_result!(self.external_addr_ttl.write()) = ttl;
+        if ttl == Duration::from_secs(0) {
+            self.invalidate_external_addrs();
+        }
+    }
+
+    /// Forget all cached external addresses. This should be called whenever the network may have
+    /// changed, eg. when the machine moves to a different wifi network.
+    pub fn invalidate_external_addrs(&self) {
+        unwrap_result!(self.external_addr_cache.lock()).clear();
+    }
+
+    /// Tell the context that the machine's network has changed, eg. a phone moved from wifi to
+    /// cellular. This lists the interfaces and searches for IGD gateways again, blocking briefly
+    /// like `new`, forgets cached external addresses and then raises `Event::NetworkChanged`.
+    /// Sockets mapped or punched before the change keep their old endpoints, so subscribers
+    /// should map new sockets and punch again. A `NetworkMonitor` calls this automatically.
+    ///
+    /// A context made with `without_gateway_search` only lists the interfaces, so its caller
+    /// should call `search_gateways` again afterwards.
+    pub fn network_changed(&self) -> WResult<(), MappingContextNewWarning, MappingContextNewError> {
+        let search = self.search_gateways_on_threads;
+        let (interfaces_v4, interfaces_v6, warnings) = match gather_interfaces(search) {
+            WOk((interfaces_v4, interfaces_v6), warnings) => {
+                (interfaces_v4, interfaces_v6, warnings)
+            },
+            WErr(e) => return WErr(e),
+        };
+        self.interfaces_v4.store(interfaces_v4);
+        self.interfaces_v6.store(interfaces_v6);
+        *unwrap_result!(self.default_gateway_v4.write()) =
+            routes::default_gateway_v4().unwrap_or(None);
+        self.invalidate_external_addrs();
+        *unwrap_result!(self.double_nat.write()) = None;
+        self.events.send(Event::NetworkChanged);
+        WOk((), warnings)
+    }
+
+    /// Have `MappedUdpSocket::new` and `MappedTcpSocket::new` bind to the local port of the last
+    /// socket they mapped, if that port is free. IGD gateways and many NATs map a local port to
+    /// the same external port each time, so peers which cached our old endpoints can reach the
+    /// new socket there. Disabled by default.
+    pub fn set_port_reuse(&self, reuse: bool) {
+        self.port_reuse.store(reuse, Ordering::SeqCst);
+    }
+
+    /// Get a handle to the worker threads this context runs background work on: the simple hole
+    /// punch servers and their keepalives, the `NetworkMonitor`, the `LanAdvertiser`, the
+    /// `TurnServer` and the control and metrics servers. Applications can schedule their own
+    /// periodic work, eg. keepalives or refreshing TURN allocations, onto it rather than
+    /// spawning a thread for each. Jobs must not block for long.
+    ///
+    /// The worker threads are started by the first call.
+    pub fn runtime(&self) -> RuntimeHandle {
+        let mut runtime = unwrap_result!(self.runtime.lock());
+        if let Some(ref runtime) = *runtime {
+            return runtime.handle();
+        }
+        let started = Runtime::new(runtime::DEFAULT_RUNTIME_THREADS);
+        let handle = started.handle();
+        *runtime = Some(started);
+        handle
+    }
+
+    /// Search for an IGD gateway from each ipv4 interface which doesn't have one yet, one
+    /// interface after another on the calling thread. This blocks for up to a second per
+    /// interface. Contexts made with `new` search by themselves; this is for ones made with
+    /// `without_gateway_search`, and does nothing without the `upnp` feature.
+    pub fn search_gateways(&self) -> Vec<MappingContextNewWarning> {
+        let mut warnings = Vec::new();
+        if !cfg!(feature = "upnp") {
+            return warnings;
+        }
+        let mut searched_v4 = interfaces_v4(self);
+        for interface in &mut searched_v4 {
+            if interface.gateway.is_some() || socket_utils::ipv4_is_loopback(&interface.addr) {
+                continue;
+            }
+            let if_name = interface.name.clone();
+            let addr_v4 = interface.addr;
+            // As on the search threads, a panic in the igd crate only loses the gateway.
+            match panic::catch_unwind(AssertUnwindSafe(|| search_gateway(if_name, addr_v4))) {
+                Ok((searched, ws)) => {
+                    *interface = searched;
+                    warnings.extend(ws);
+                },
+                Err(_) => {
+                    warnings.push(MappingContextNewWarning::SearchGatewayPanicked {
+                        if_name: interface.name.clone(),
+                        if_addr: addr_v4,
+                    });
+                },
+            }
+        }
+        // Only fill in gateways, in case `network_changed` replaced the interfaces meanwhile.
+        let mut current = interfaces_v4(self);
+        for interface in &mut current {
+            if let Some(searched) = searched_v4.iter().find(|s| {
+                s.name == interface.name && s.addr == interface.addr
+            }) {
+                if interface.gateway.is_none() {
+                    interface.gateway = searched.gateway.clone();
+                }
+            }
+        }
+        self.interfaces_v4.store(current);
+        warnings
+    }
+}
+
+// Search for an IGD gateway from the interface `if_name` with address `addr_v4`.
+fn search_gateway(if_name: String, addr_v4: Ipv4Addr)
+                  -> (InterfaceV4, Vec<MappingContextNewWarning>) {
+    let mut warnings = Vec::new();
+    let gateway = match igd::search_gateway_from_timeout(addr_v4, Duration::from_secs(1)) {
+        Ok(gateway) => Some(gateway),
+        Err(e) => {
+            warnings.push(MappingContextNewWarning::SearchGateway {
+                if_name: if_name.clone(),
+                if_addr: addr_v4,
+                err: e,
+            });
+            None
+        },
+    };
+    (InterfaceV4 {
+        gateway: gateway,
+        addr: addr_v4,
+        is_virtual: virtual_interfaces::is_virtual(&if_name),
+        name: if_name,
+    }, warnings)
+}
+
+// List the local interfaces and, if `search`, search for an IGD gateway on each ipv4 one.
+fn gather_interfaces(search: bool) -> WResult<(Vec<InterfaceV4>, Vec<InterfaceV6>),
+                                              MappingContextNewWarning,
+                                              MappingContextNewError> {
+    let interfaces = match get_if_addrs::get_if_addrs() {
+        Ok(if_addrs) => if_addrs,
+        Err(e) => return WErr(MappingContextNewError::ListInterfaces { err: e }),
+    };
+    let addr_states = ipv6_selection::addr_states();
+    let mut interfaces_v4 = Vec::new();
+    let mut interfaces_v6 = Vec::new();
+    let mut warnings = Vec::new();
+    let mut search_threads = Vec::new();
+    for interface in interfaces {
+        let addr_v4 = match interface.addr {
+            get_if_addrs::IfAddr::V4(v4_addr) => {
+                v4_addr.ip
+            },
+            get_if_addrs::IfAddr::V6(v6_addr) => {
+                interfaces_v6.push(InterfaceV6 {
+                    addr: v6_addr.ip,
+                    state: addr_states.get(&v6_addr.ip).cloned().unwrap_or(AddrState::default()),
+                    is_virtual: virtual_interfaces::is_virtual(&interface.name),
+                    name: interface.name,
+                });
+                continue;
+            },
+        };
+        if socket_utils::ipv4_is_loopback(&addr_v4) || !cfg!(feature = "upnp") || !search {
+            interfaces_v4.push(InterfaceV4 {
+                gateway: None,
+                addr: addr_v4,
+                is_virtual: virtual_interfaces::is_virtual(&interface.name),
+                name: interface.name,
+            });
+            continue;
+        };
+        let if_name = interface.name;
+        let cloned_if_name = if_name.clone();
+        search_threads.push((cloned_if_name, addr_v4, thread::Builder::new()
+                                            .name(From::from("IGD search"))
+                                            .spawn(move || -> WResult<_, _, Void> {
+            let (interface, warnings) = search_gateway(if_name, addr_v4);
+            WOk(interface, warnings)
+        })));
+    };
+
+    for (if_name, addr_v4, search_thread) in search_threads {
+        match search_thread {
+            Err(e) => return WErr(MappingContextNewError::SpawnThread { err: e }),
+            Ok(jh) => {
+                match jh.join() {
+                    Ok(WErr(e)) => match e {},
+                    Ok(WOk(interface, ws)) => {
+                        interfaces_v4.push(interface);
+                        warnings.extend(ws);
+                    },
+                    // The igd crate choked on whatever the network sent it. That's no reason to
+                    // lose the interface.
+                    Err(_) => {
+                        warnings.push(MappingContextNewWarning::SearchGatewayPanicked {
+                            if_name: if_name.clone(),
+                            if_addr: addr_v4,
+                        });
+                        interfaces_v4.push(InterfaceV4 {
+                            gateway: None,
+                            addr: addr_v4,
+                            is_virtual: virtual_interfaces::is_virtual(&if_name),
+                            name: if_name,
+                        });
+                    },
+                }
+            }
+        }
+    }
+    WOk((interfaces_v4, interfaces_v6), warnings)
 }
 
 pub fn interfaces_v4(mc: &MappingContext) -> Vec<InterfaceV4> {
-    unwrap_result!(mc.interfaces_v4.read()).clone()
+    (*mc.interfaces_v4.load()).clone()
 }
 
+/// The ipv6 interfaces to advertise, best first, as chosen by the context's
+/// `Ipv6AddrPreference`.
 pub fn interfaces_v6(mc: &MappingContext) -> Vec<InterfaceV6> {
-    unwrap_result!(mc.interfaces_v6.read()).clone()
+    ipv6_selection::select(&mc.interfaces_v6.load(), mc.ipv6_addr_preference())
+}
+
+/// The address to bind sockets pinned to the interface `if_name` to, an ipv6 one if `ipv6`. For
+/// ipv6 this is the best of the interface's addresses by the context's `Ipv6AddrPreference`.
+pub fn interface_bind_ip(mc: &MappingContext, if_name: &str, ipv6: bool) -> Option<IpAddr> {
+    if ipv6 {
+        interfaces_v6(mc).into_iter()
+                         .find(|i| i.name == if_name && ipv6_selection::is_global(&i.addr))
+                         .map(|i| IpAddr::V6(i.addr))
+    } else {
+        mc.interfaces_v4.load()
+                        .iter()
+                        .find(|i| i.name == if_name)
+                        .map(|i| IpAddr::V4(i.addr))
+    }
+}
+
+/// The address to bind ipv6 sockets to: the best global address, so that servers and peers see
+/// the address we advertise rather than whichever one the OS picks, or the unspecified address
+/// if there's no global address.
+pub fn ipv6_bind_addr(mc: &MappingContext) -> Ipv6Addr {
+    interfaces_v6(mc).into_iter()
+                     .map(|i| i.addr)
+                     .find(|addr| ipv6_selection::is_global(addr))
+                     .unwrap_or(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0))
 }
 
 pub fn simple_udp_servers(mc: &MappingContext) -> Vec<SocketAddr> {
-    unwrap_result!(mc.simple_udp_servers.read()).clone()
+    let mut servers = (*mc.simple_udp_servers.load()).clone();
+    if let Some(ref dns_servers) = *mc.dns_servers.load() {
+        servers.extend(dns_servers.udp_servers.iter().cloned());
+    }
+    servers
 }
 
+#[cfg(feature = "tcp")]
 pub fn simple_tcp_servers(mc: &MappingContext) -> Vec<SocketAddr> {
-    unwrap_result!(mc.simple_tcp_servers.read()).clone()
+    let mut servers = (*mc.simple_tcp_servers.load()).clone();
+    if let Some(ref dns_servers) = *mc.dns_servers.load() {
+        servers.extend(dns_servers.tcp_servers.iter().cloned());
+    }
+    servers
+}
+
+#[cfg(feature = "tcp")]
+fn discover_tcp_servers(domain: &str, deadline: Instant)
+                        -> Result<(Vec<SocketAddr>, u32), DnsDiscoveryError>
+{
+    dns_discovery::discover_servers(domain, "tcp", deadline)
+}
+
+#[cfg(not(feature = "tcp"))]
+fn discover_tcp_servers(_domain: &str, _deadline: Instant)
+                        -> Result<(Vec<SocketAddr>, u32), DnsDiscoveryError>
+{
+    Ok((Vec::new(), MAX_DNS_TTL_SECS))
+}
+
+/// Re-resolve the servers of the discovery domain if their ttl has expired.
+pub fn refresh_stale_discovered_servers(mc: &MappingContext, deadline: Instant)
+                                        -> Result<(), DnsDiscoveryError>
+{
+    let stale = match *mc.dns_servers.load() {
+        Some(ref dns_servers) => dns_servers.refresh_at <= Instant::now(),
+        None => false,
+    };
+    if stale {
+        mc.refresh_discovered_servers(deadline)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn http_echo_servers(mc: &MappingContext) -> Vec<String> {
+    (*mc.http_echo_servers.load()).clone()
+}
+
+pub fn stun_servers(mc: &MappingContext) -> Vec<SocketAddr> {
+    (*mc.stun_servers.load()).clone()
+}
+
+/// The endpoints of a newly mapped socket that the context's exposure policy allows, ranked by
+/// `rank_tunneled_endpoints` and with the virtual interface policy applied.
+pub fn exposed_endpoints(mc: &MappingContext, endpoints: Vec<MappedSocketAddr>)
+                         -> Vec<MappedSocketAddr>
+{
+    let endpoints = mc.exposure_policy().filter(endpoints);
+    let endpoints = rank_tunneled_endpoints(mc, endpoints);
+    mc.virtual_interface_policy().apply(endpoints, &virtual_ips(mc))
+}
+
+// The addresses of the host's virtual interfaces.
+fn virtual_ips(mc: &MappingContext) -> Vec<IpAddr> {
+    let mut ips: Vec<IpAddr> = mc.interfaces_v4.load()
+                                 .iter()
+                                 .filter(|i| i.is_virtual)
+                                 .map(|i| IpAddr::V4(i.addr))
+                                 .collect();
+    ips.extend(mc.interfaces_v6.load()
+                 .iter()
+                 .filter(|i| i.is_virtual)
+                 .map(|i| IpAddr::V6(i.addr)));
+    ips
+}
+
+/// Move tunneled ipv6 endpoints behind the native ones, or drop them if the context is set to
+/// suppress them.
+pub fn rank_tunneled_endpoints(mc: &MappingContext, endpoints: Vec<MappedSocketAddr>)
+                               -> Vec<MappedSocketAddr>
+{
+    let (tunneled, mut native): (Vec<_>, Vec<_>) = endpoints.into_iter().partition(|e| {
+        e.ipv6_tunnel().is_some()
+    });
+    if !mc.suppress_tunneled_ipv6.load(Ordering::SeqCst) {
+        native.extend(tunneled);
+    }
+    native
+}
+
+pub fn socks5_proxy(mc: &MappingContext) -> Option<Socks5Proxy> {
+    unwrap_result!(mc.socks5_proxy.read()).clone()
+}
+
+/// The cached external ip of sockets bound to `local_ip`, if it hasn't expired. Sockets mapped to
+/// this ip keep their local port.
+pub fn cached_external_ip(mc: &MappingContext, local_ip: IpAddr) -> Option<IpAddr> {
+    let mut cache = unwrap_result!(mc.external_addr_cache.lock());
+    let fresh = match cache.get(&local_ip) {
+        Some(cached) if cached.expires_at > Instant::now() => Some(cached.ip),
+        _ => None,
+    };
+    if fresh.is_none() {
+        let _ = cache.remove(&local_ip);
+    }
+    fresh
+}
+
+/// Remember the external addresses that servers reported for a socket bound to `local_addr`. These
+/// are only cached if they all agree on the ip and kept the local port.
+pub fn cache_external_addrs(mc: &MappingContext,
+                            local_addr: net::SocketAddr,
+                            external_addrs: &[net::SocketAddr]) {
+    let ttl = *unwrap_result!(mc.external_addr_ttl.read());
+    let ip = match external_addrs.first() {
+        Some(addr) => addr.ip(),
+        None => return,
+    };
+    let mut cache = unwrap_result!(mc.external_addr_cache.lock());
+    let consistent = external_addrs.iter().all(|addr| {
+        addr.ip() == ip && addr.port() == local_addr.port()
+    });
+    if consistent && ttl > Duration::from_secs(0) {
+        let _ = cache.insert(local_addr.ip(), CachedExternalAddr {
+            ip: ip,
+            expires_at: Instant::now() + ttl,
+        });
+    } else {
+        let _ = cache.remove(&local_addr.ip());
+    }
+}
+
+/// The local port to try binding a new socket to, if port reuse is enabled.
+pub fn port_hint(mc: &MappingContext, protocol: igd::PortMappingProtocol) -> Option<u16> {
+    if !mc.port_reuse.load(Ordering::SeqCst) {
+        return None;
+    }
+    *unwrap_result!(last_mapped_port(mc, protocol).lock())
+}
+
+/// Remember the local port of a socket that was mapped successfully.
+pub fn record_mapped_port(mc: &MappingContext, protocol: igd::PortMappingProtocol, port: u16) {
+    *unwrap_result!(last_mapped_port(mc, protocol).lock()) = Some(port);
+}
+
+/// Stop reusing `port` for new sockets, eg. because a connection on it died and the NAT may still
+/// hold its mapping for the dead connection.
+pub fn forget_mapped_port(mc: &MappingContext, protocol: igd::PortMappingProtocol, port: u16) {
+    let mut last_port = unwrap_result!(last_mapped_port(mc, protocol).lock());
+    if *last_port == Some(port) {
+        *last_port = None;
+    }
+}
+
+fn last_mapped_port(mc: &MappingContext, protocol: igd::PortMappingProtocol)
+                    -> &Mutex<Option<u16>>
+{
+    match protocol {
+        igd::PortMappingProtocol::UDP => &mc.last_udp_port,
+        igd::PortMappingProtocol::TCP => &mc.last_tcp_port,
+    }
+}
+
+pub fn events(mc: &MappingContext) -> &EventSender {
+    &mc.events
+}
+
+pub fn traffic_shaper(mc: &MappingContext) -> &TrafficShaper {
+    &mc.traffic_shaper
+}
+
+pub fn should_skip_strategy(mc: &MappingContext, strategy: Strategy) -> bool {
+    if strategy == Strategy::Igd && mc.double_nat() == Some(true) {
+        return true;
+    }
+    unwrap_result!(mc.strategy_history.lock()).should_skip(strategy)
+}
+
+/// Remember whether mapping found a NAT beyond the IGD gateway.
+pub fn record_double_nat(mc: &MappingContext, double_nat: bool) {
+    *unwrap_result!(mc.double_nat.write()) = Some(double_nat);
 }
 
 #[cfg(test)]
@@ -231,5 +1086,54 @@ mod tests {
     fn create_mapping_context() {
         let _ = unwrap_result!(MappingContext::new().result_discard());
     }
+
+    #[test]
+    fn tunneled_endpoints_are_ranked_last() {
+        use socket_addr::SocketAddr;
+
+        use mapped_socket_addr::MappedSocketAddr;
+        use super::rank_tunneled_endpoints;
+
+        let endpoint = |addr: &str| MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(addr.parse())),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        };
+        let endpoints = vec![endpoint("[2002:c000:221::1]:5000"),
+                             endpoint("[2001:db8::1]:5000"),
+                             endpoint("192.0.2.33:5000")];
+
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        assert_eq!(rank_tunneled_endpoints(&mc, endpoints.clone()),
+                   vec![endpoints[1].clone(), endpoints[2].clone(), endpoints[0].clone()]);
+        mc.set_suppress_tunneled_ipv6(true);
+        assert_eq!(rank_tunneled_endpoints(&mc, endpoints.clone()),
+                   vec![endpoints[1].clone(), endpoints[2].clone()]);
+    }
+
+    #[test]
+    fn external_addrs_are_cached_only_when_ports_are_kept() {
+        use std::net;
+
+        use super::{cache_external_addrs, cached_external_ip};
+
+        let parse = |addr: &str| -> net::SocketAddr { unwrap_result!(addr.parse()) };
+        let local_addr = parse("0.0.0.0:5000");
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+
+        cache_external_addrs(&mc, local_addr, &[parse("192.0.2.1:5000"), parse("192.0.2.1:5000")]);
+        assert_eq!(cached_external_ip(&mc, local_addr.ip()), Some(parse("192.0.2.1:0").ip()));
+
+        mc.invalidate_external_addrs();
+        assert_eq!(cached_external_ip(&mc, local_addr.ip()), None);
+
+        cache_external_addrs(&mc, local_addr, &[parse("192.0.2.1:5000"), parse("192.0.2.1:6000")]);
+        assert_eq!(cached_external_ip(&mc, local_addr.ip()), None);
+
+        mc.set_external_addr_ttl(Duration::from_secs(0));
+        cache_external_addrs(&mc, local_addr, &[parse("192.0.2.1:5000")]);
+        assert_eq!(cached_external_ip(&mc, local_addr.ip()), None);
+    }
 }
 
@@ -0,0 +1,373 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Exporting a `SimpleUdpHolePunchServer`'s stats in the Prometheus text format, either over
+//! http or through a textfile collector.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{self, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use mapping_context::MappingContext;
+use runtime::{RuntimeHandle, Sockets};
+use simple_udp_hole_punch_server::SimpleUdpHolePunchServer;
+
+/// How long a scraper gets from connecting to having been sent the response, so that one stalled
+/// connection doesn't hold up the next scrape.
+const REQUEST_TIMEOUT_MS: u64 = 2000;
+/// The longest request head accepted.
+const MAX_REQUEST_LEN: usize = 4096;
+/// The content type of the Prometheus text format.
+const CONTENT_TYPE: &'static str = "text/plain; version=0.0.4";
+
+/// Render `server`'s stats and state in the Prometheus text exposition format:
+///
+/// * `nat_traversal_udp_server_requests_total{outcome}`: requests served, shed and ignored during
+///   this run.
+/// * `nat_traversal_udp_server_all_runs_requests_total{outcome}`: the same over all runs, if the
+///   server persists its stats.
+/// * `nat_traversal_udp_server_draining`: 1 while the server is draining, 0 otherwise.
+/// * `nat_traversal_udp_server_ignored_subnets`: how many subnets the server ignores.
+/// * `nat_traversal_udp_server_addresses`: how many addresses the server advertises.
+pub fn render_metrics<T: AsRef<MappingContext>>(server: &SimpleUdpHolePunchServer<T>) -> String {
+    let stats = server.stats();
+    let cumulative = server.cumulative_stats();
+    let mut out = String::new();
+    metric(&mut out,
+           "nat_traversal_udp_server_requests_total",
+           "counter",
+           "Requests received during this run, by what was done with them.",
+           &[("outcome=\"served\"", stats.served_requests),
+             ("outcome=\"shed\"", stats.shed_requests),
+             ("outcome=\"ignored\"", stats.ignored_requests)]);
+    metric(&mut out,
+           "nat_traversal_udp_server_all_runs_requests_total",
+           "counter",
+           "Requests received over all runs, by what was done with them.",
+           &[("outcome=\"served\"", cumulative.served_requests),
+             ("outcome=\"shed\"", cumulative.shed_requests),
+             ("outcome=\"ignored\"", cumulative.ignored_requests)]);
+    metric(&mut out,
+           "nat_traversal_udp_server_draining",
+           "gauge",
+           "Whether the server is answering every request with \"try later\".",
+           &[("", server.is_draining() as usize)]);
+    metric(&mut out,
+           "nat_traversal_udp_server_ignored_subnets",
+           "gauge",
+           "Subnets whose clients the server doesn't answer.",
+           &[("", server.ignored_clients().len())]);
+    metric(&mut out,
+           "nat_traversal_udp_server_addresses",
+           "gauge",
+           "Addresses the server advertises.",
+           &[("", server.addresses().len())]);
+    out
+}
+
+/// Write `server`'s metrics to `path`, for the textfile collector of the Prometheus node exporter.
+/// They're written to a temporary file first and moved into place, so that the collector never
+/// reads a partial file.
+pub fn write_metrics_textfile<T, P>(server: &SimpleUdpHolePunchServer<T>, path: P) -> io::Result<()>
+    where T: AsRef<MappingContext>,
+          P: AsRef<Path>
+{
+    let path = path.as_ref();
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    {
+        let mut file = try!(File::create(&tmp_path));
+        try!(file.write_all(render_metrics(server).as_bytes()));
+        try!(file.sync_all());
+    }
+    fs::rename(&tmp_path, path)
+}
+
+// Append one metric family, with its help and type lines, to `out`.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, usize)]) {
+    out.push_str(&format!("# HELP {} {}\n", name, help));
+    out.push_str(&format!("# TYPE {} {}\n", name, kind));
+    for &(labels, value) in samples {
+        if labels.is_empty() {
+            out.push_str(&format!("{} {}\n", name, value));
+        } else {
+            out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+        }
+    }
+}
+
+/// Serves a `SimpleUdpHolePunchServer`'s metrics to Prometheus at `GET /metrics` over http.
+/// Scrapes are served one at a time, on the runtime of the server's mapping context. The metrics
+/// are read only, so unlike the `ControlServer` this may be bound to any address. Stops when
+/// dropped.
+pub struct MetricsServer {
+    local_addr: net::SocketAddr,
+    stop_flag: Arc<AtomicBool>,
+    runtime: RuntimeHandle,
+}
+
+impl fmt::Debug for MetricsServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MetricsServer")
+         .field("local_addr", &self.local_addr)
+         .finish()
+    }
+}
+
+impl MetricsServer {
+    /// Start serving metrics for `server` on `addr`. Use port 0 to have one picked.
+    pub fn new<T>(server: Arc<SimpleUdpHolePunchServer<T>>,
+                  addr: &net::SocketAddr)
+                  -> io::Result<MetricsServer>
+        where T: AsRef<MappingContext> + Send + Sync + 'static
+    {
+        let listener = try!(TcpListener::bind(addr));
+        let local_addr = try!(listener.local_addr());
+        try!(listener.set_nonblocking(true));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let cloned_stop_flag = stop_flag.clone();
+        let runtime = server.runtime();
+        let cloned_runtime = runtime.clone();
+        runtime.spawn(move || accept(listener, server, cloned_stop_flag, cloned_runtime));
+        Ok(MetricsServer {
+            local_addr: local_addr,
+            stop_flag: stop_flag,
+            runtime: runtime,
+        })
+    }
+
+    /// The address metrics are served on.
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.runtime.stop(&self.stop_flag);
+    }
+}
+
+// A scrape being served, and the request head read from it so far.
+struct Scrape {
+    stream: TcpStream,
+    head: Vec<u8>,
+    deadline: Instant,
+}
+
+// Take the next scrape waiting on the listener, or wait for one. Once the metrics server has been
+// stopped the listener is dropped instead.
+fn accept<T>(listener: TcpListener,
+             server: Arc<SimpleUdpHolePunchServer<T>>,
+             stop_flag: Arc<AtomicBool>,
+             runtime: RuntimeHandle)
+    where T: AsRef<MappingContext> + Send + Sync + 'static
+{
+    if stop_flag.load(Ordering::SeqCst) {
+        return;
+    }
+    let scrape = listener.accept().and_then(|(stream, _)| {
+        // Accepted sockets inherit non-blocking mode on some platforms but not others.
+        try!(stream.set_nonblocking(true));
+        Ok(Scrape {
+            stream: stream,
+            head: Vec::new(),
+            deadline: Instant::now() + Duration::from_millis(REQUEST_TIMEOUT_MS),
+        })
+    });
+    let cloned_runtime = runtime.clone();
+    match scrape {
+        Ok(scrape) => {
+            runtime.spawn(move || {
+                serve_scrape(listener, scrape, server, stop_flag, cloned_runtime);
+            });
+        },
+        Err(_) => {
+            let sockets = Sockets::new(&[&listener]).or_stopped(&stop_flag);
+            runtime.when_readable(sockets, None, move || {
+                accept(listener, server, stop_flag, cloned_runtime);
+            });
+        },
+    }
+}
+
+// Read what the scraper has sent so far and wait for more, until the request head is complete and
+// answered or the deadline passes. Then go back to accepting scrapes.
+fn serve_scrape<T>(listener: TcpListener,
+                   mut scrape: Scrape,
+                   server: Arc<SimpleUdpHolePunchServer<T>>,
+                   stop_flag: Arc<AtomicBool>,
+                   runtime: RuntimeHandle)
+    where T: AsRef<MappingContext> + Send + Sync + 'static
+{
+    if stop_flag.load(Ordering::SeqCst) {
+        return;
+    }
+    let cloned_runtime = runtime.clone();
+    match answer_scrape(&mut scrape, &*server) {
+        // A misbehaving scraper is simply disconnected.
+        Ok(true) | Err(_) => {
+            runtime.spawn(move || accept(listener, server, stop_flag, cloned_runtime));
+        },
+        Ok(false) => {
+            let sockets = Sockets::new(&[&scrape.stream]).or_stopped(&stop_flag);
+            let deadline = scrape.deadline;
+            runtime.when_readable(sockets, Some(deadline), move || {
+                serve_scrape(listener, scrape, server, stop_flag, cloned_runtime);
+            });
+        },
+    }
+}
+
+// Read the part of the request head waiting on the connection and, once it's complete, send the
+// response. Returns whether the scrape is finished with.
+fn answer_scrape<T: AsRef<MappingContext>>(scrape: &mut Scrape,
+                                           server: &SimpleUdpHolePunchServer<T>)
+                                           -> io::Result<bool> {
+    let mut buf = [0u8; 512];
+    while !scrape.head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if Instant::now() >= scrape.deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Scrape took too long"));
+        }
+        let len = match scrape.stream.read(&mut buf[..]) {
+            Ok(0) => return Ok(true),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        scrape.head.extend_from_slice(&buf[..len]);
+        if scrape.head.len() > MAX_REQUEST_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Request too long"));
+        }
+    }
+    try!(respond(&mut scrape.stream, &scrape.head, server, scrape.deadline));
+    Ok(true)
+}
+
+fn respond<T: AsRef<MappingContext>>(stream: &mut TcpStream,
+                                     head: &[u8],
+                                     server: &SimpleUdpHolePunchServer<T>,
+                                     deadline: Instant)
+                                     -> io::Result<()> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "Scrape took too long"));
+    }
+    // The response is small enough that blocking on it is brief, and the write timeout keeps it
+    // within the deadline.
+    try!(stream.set_nonblocking(false));
+    try!(stream.set_write_timeout(Some(deadline - now)));
+
+    let request_line = String::from_utf8_lossy(head);
+    let mut words = request_line.lines().next().unwrap_or("").split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render_metrics(server)),
+        (Some("GET"), Some(_)) => ("404 Not Found", String::from("Metrics are at /metrics\n")),
+        _ => ("405 Method Not Allowed", String::from("Only GET is supported\n")),
+    };
+    let response = format!("HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                            Connection: close\r\n\r\n{}",
+                           status,
+                           CONTENT_TYPE,
+                           body.len(),
+                           body);
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::REQUEST_TIMEOUT_MS;
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use mapping_context::MappingContext;
+    use simple_udp_hole_punch_server::SimpleUdpHolePunchServer;
+
+    #[test]
+    fn metrics_are_scraped_over_http() {
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new(Box::new(mapping_context),
+                                                                  deadline)
+                                        .result_discard());
+        let server = Arc::new(server);
+        server.set_draining(true);
+        let metrics = unwrap_result!(MetricsServer::new(server.clone(),
+                                                        &unwrap_result!("127.0.0.1:0".parse())));
+
+        let get = |path: &str| {
+            let mut stream = unwrap_result!(TcpStream::connect(metrics.local_addr()));
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            unwrap_result!(stream.write_all(request.as_bytes()));
+            let mut response = String::new();
+            let _ = unwrap_result!(stream.read_to_string(&mut response));
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("# TYPE nat_traversal_udp_server_requests_total counter\n"));
+        let served = "nat_traversal_udp_server_requests_total{outcome=\"served\"} 0\n";
+        assert!(response.contains(served));
+        assert!(response.contains("\nnat_traversal_udp_server_draining 1\n"));
+        assert!(response.ends_with(&render_metrics(&*server)));
+
+        assert!(get("/").starts_with("HTTP/1.0 404 "));
+    }
+
+    #[test]
+    fn slow_scrapers_dont_hold_up_the_next_one() {
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new(Box::new(mapping_context),
+                                                                  deadline)
+                                        .result_discard());
+        let metrics = unwrap_result!(MetricsServer::new(Arc::new(server),
+                                                        &unwrap_result!("127.0.0.1:0".parse())));
+
+        // Trickles its request out a byte at a time, never finishing it within the deadline.
+        let mut slow = unwrap_result!(TcpStream::connect(metrics.local_addr()));
+        let _ = thread!("slow scraper", move || {
+            for _ in 0..20 {
+                if slow.write_all(b"G").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(500));
+            }
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let mut stream = unwrap_result!(TcpStream::connect(metrics.local_addr()));
+        unwrap_result!(stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n"));
+        let mut response = String::new();
+        let _ = unwrap_result!(stream.read_to_string(&mut response));
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(started.elapsed() < Duration::from_millis(REQUEST_TIMEOUT_MS + 1000));
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::fmt;
+use std::io;
+use std::net;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+use std::time::Instant;
+
+use socket_addr::SocketAddr;
+
+use dns_discovery;
+use dns_discovery::DnsDiscoveryError;
+use error_code::{ErrorCategory, ErrorCode};
+use mapped_socket_addr::MappedSocketAddr;
+use rendezvous_info;
+use rendezvous_info::PubRendezvousInfo;
+
+/// The name which DNS64 resolvers synthesize AAAA records for (RFC 7050).
+const IPV4ONLY_NAME: &'static str = "ipv4only.arpa";
+/// Prefix lengths allowed by RFC 6052, most common first.
+const PREFIX_LENS: [u8; 6] = [96, 64, 56, 48, 40, 32];
+
+fn ipv4only_addrs() -> [Ipv4Addr; 2] {
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)]
+}
+
+quick_error! {
+    /// Errors raised while working with NAT64 prefixes.
+    #[derive(Debug)]
+    pub enum Nat64Error {
+        /// RFC 6052 only allows prefixes of length 32, 40, 48, 56, 64 or 96.
+        InvalidPrefixLength { prefix_len: u8 } {
+            description("Invalid NAT64 prefix length")
+            display("Invalid NAT64 prefix length {}. Must be one of 32, 40, 48, 56, 64 or 96.",
+                    prefix_len)
+        }
+        /// Error resolving `ipv4only.arpa`.
+        Resolve { err: DnsDiscoveryError } {
+            description("Error resolving ipv4only.arpa")
+            display("Error resolving ipv4only.arpa: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl From<Nat64Error> for io::Error {
+    fn from(e: Nat64Error) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            Nat64Error::InvalidPrefixLength { .. } => io::ErrorKind::InvalidInput,
+            Nat64Error::Resolve { err } => {
+                let err: io::Error = From::from(err);
+                err.kind()
+            },
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for Nat64Error {
+    fn code(&self) -> u32 {
+        match *self {
+            Nat64Error::InvalidPrefixLength { .. } => 2301,
+            Nat64Error::Resolve { .. } => 2302,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            Nat64Error::InvalidPrefixLength { .. } => ErrorCategory::Configuration,
+            Nat64Error::Resolve { ref err, .. } => err.category(),
+        }
+    }
+}
+
+/// The prefix a NAT64 gateway uses to represent ipv4 hosts as ipv6 addresses (RFC 6052).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Nat64Prefix {
+    octets: [u8; 16],
+    prefix_len: u8,
+}
+
+impl Nat64Prefix {
+    /// Create a prefix. The bits of `prefix` after `prefix_len` are ignored.
+    pub fn new(prefix: Ipv6Addr, prefix_len: u8) -> Result<Nat64Prefix, Nat64Error> {
+        if !PREFIX_LENS.contains(&prefix_len) {
+            return Err(Nat64Error::InvalidPrefixLength { prefix_len: prefix_len });
+        }
+        let mut octets = ipv6_octets(prefix);
+        for octet in &mut octets[(prefix_len / 8) as usize..] {
+            *octet = 0;
+        }
+        Ok(Nat64Prefix {
+            octets: octets,
+            prefix_len: prefix_len,
+        })
+    }
+
+    /// The well-known prefix `64:ff9b::/96`.
+    pub fn well_known() -> Nat64Prefix {
+        unwrap_result!(Nat64Prefix::new(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0), 96))
+    }
+
+    /// The prefix as an address.
+    pub fn prefix(&self) -> Ipv6Addr {
+        ipv6_from_octets(self.octets)
+    }
+
+    /// The length of the prefix in bits.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// The ipv6 address through which `ip` can be reached via the NAT64 gateway.
+    pub fn synthesize(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.octets;
+        for (pos, octet) in embedded_positions(self.prefix_len).iter().zip(ip.octets().iter()) {
+            octets[*pos] = *octet;
+        }
+        ipv6_from_octets(octets)
+    }
+
+    /// The ipv4 address embedded in `ip`, if `ip` is under this prefix.
+    pub fn extract(&self, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+        let octets = ipv6_octets(ip);
+        if octets[..(self.prefix_len / 8) as usize] != self.octets[..(self.prefix_len / 8) as usize] {
+            return None;
+        }
+        let positions = embedded_positions(self.prefix_len);
+        Some(Ipv4Addr::new(octets[positions[0]], octets[positions[1]],
+                           octets[positions[2]], octets[positions[3]]))
+    }
+}
+
+impl fmt::Display for Nat64Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.prefix(), self.prefix_len)
+    }
+}
+
+// The positions of the four ipv4 octets in a synthesized address. Bits 64 to 71 are reserved so
+// octet 8 is skipped.
+fn embedded_positions(prefix_len: u8) -> [usize; 4] {
+    let mut positions = [0; 4];
+    let mut pos = (prefix_len / 8) as usize;
+    for position in &mut positions {
+        if pos == 8 {
+            pos += 1;
+        }
+        *position = pos;
+        pos += 1;
+    }
+    positions
+}
+
+fn ipv6_octets(ip: Ipv6Addr) -> [u8; 16] {
+    let mut octets = [0u8; 16];
+    for (i, segment) in ip.segments().iter().enumerate() {
+        octets[2 * i] = (*segment >> 8) as u8;
+        octets[2 * i + 1] = *segment as u8;
+    }
+    octets
+}
+
+fn ipv6_from_octets(octets: [u8; 16]) -> Ipv6Addr {
+    let mut segments = [0u16; 8];
+    for (i, segment) in segments.iter_mut().enumerate() {
+        *segment = ((octets[2 * i] as u16) << 8) | octets[2 * i + 1] as u16;
+    }
+    Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                  segments[4], segments[5], segments[6], segments[7])
+}
+
+/// Find the NAT64 prefixes of the local network by resolving `ipv4only.arpa` (RFC 7050). On a
+/// network without DNS64 this returns no prefixes.
+pub fn discover_nat64_prefixes(deadline: Instant) -> Result<Vec<Nat64Prefix>, Nat64Error> {
+    let addrs = match dns_discovery::resolve_aaaa(IPV4ONLY_NAME, deadline) {
+        Ok(addrs) => addrs,
+        Err(e) => return Err(Nat64Error::Resolve { err: e }),
+    };
+    Ok(prefixes_from_addrs(&addrs))
+}
+
+fn prefixes_from_addrs(addrs: &[Ipv6Addr]) -> Vec<Nat64Prefix> {
+    let mut prefixes = Vec::new();
+    for addr in addrs {
+        for prefix_len in &PREFIX_LENS {
+            let prefix = unwrap_result!(Nat64Prefix::new(*addr, *prefix_len));
+            let found = match prefix.extract(*addr) {
+                Some(ip) => ipv4only_addrs().contains(&ip),
+                None => false,
+            };
+            if found {
+                if !prefixes.contains(&prefix) {
+                    prefixes.push(prefix);
+                }
+                break;
+            }
+        }
+    }
+    prefixes
+}
+
+/// Add candidates for reaching the peer's ipv4 endpoints through each of the NAT64 `prefixes`.
+/// The peer's endpoints are reordered so that native ipv6 endpoints are tried first, then the
+/// synthesized ones and then ipv4.
+pub fn synthesize_nat64_candidates(info: PubRendezvousInfo, prefixes: &[Nat64Prefix])
+                                   -> PubRendezvousInfo
+{
+    let endpoints = info.endpoints().to_vec();
+    let mut native = Vec::new();
+    let mut synthesized = Vec::new();
+    let mut ipv4 = Vec::new();
+    for endpoint in endpoints {
+        let addr = *endpoint.addr;
+        match addr {
+            net::SocketAddr::V4(addr_v4) => {
+                for prefix in prefixes {
+                    let ip = prefix.synthesize(*addr_v4.ip());
+                    let addr = SocketAddr(net::SocketAddr::V6(SocketAddrV6::new(ip, addr_v4.port(),
+                                                                                0, 0)));
+                    if synthesized.iter().all(|e: &MappedSocketAddr| e.addr != addr) {
+                        synthesized.push(MappedSocketAddr {
+                            addr: addr,
+                            nat_restricted: endpoint.nat_restricted,
+                            port_unknown: endpoint.port_unknown,
+                            unverified: endpoint.unverified,
+                        });
+                    }
+                }
+                ipv4.push(endpoint);
+            },
+            net::SocketAddr::V6(..) => native.push(endpoint),
+        }
+    }
+    native.extend(synthesized);
+    native.extend(ipv4);
+    rendezvous_info::with_endpoints(info, native)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::prefixes_from_addrs;
+
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use socket_addr::SocketAddr;
+
+    use mapped_socket_addr::MappedSocketAddr;
+    use rendezvous_info;
+
+    #[test]
+    fn prefixes_embed_and_extract() {
+        let ip = Ipv4Addr::new(192, 0, 2, 33);
+        let well_known = Nat64Prefix::well_known();
+        assert_eq!(well_known.synthesize(ip), unwrap_result!("64:ff9b::c000:221".parse()));
+
+        // Examples from RFC 6052 section 2.4.
+        let cases = [(32, "2001:db8:c000:221::"),
+                     (40, "2001:db8:1c0:2:21::"),
+                     (48, "2001:db8:122:c000:2:2100::"),
+                     (56, "2001:db8:122:3c0:0:221::"),
+                     (64, "2001:db8:122:344:c0:2:2100:0"),
+                     (96, "2001:db8:122:344::192.0.2.33")];
+        let base: Ipv6Addr = unwrap_result!("2001:db8:122:344::".parse());
+        for &(prefix_len, expected) in &cases {
+            let prefix = unwrap_result!(Nat64Prefix::new(base, prefix_len));
+            let synthesized = prefix.synthesize(ip);
+            assert_eq!(synthesized, unwrap_result!(expected.parse()));
+            assert_eq!(prefix.extract(synthesized), Some(ip));
+        }
+        assert!(Nat64Prefix::new(base, 80).is_err());
+    }
+
+    #[test]
+    fn discover_prefix_from_ipv4only_arpa() {
+        let addrs: [Ipv6Addr; 2] = [unwrap_result!("64:ff9b::c000:aa".parse()),
+                                    unwrap_result!("64:ff9b::c000:ab".parse())];
+        assert_eq!(prefixes_from_addrs(&addrs), vec![Nat64Prefix::well_known()]);
+        // A real ipv6 address for ipv4only.arpa isn't a NAT64 prefix.
+        let global: Ipv6Addr = unwrap_result!("2001:db8::1".parse());
+        assert!(prefixes_from_addrs(&[global]).is_empty());
+    }
+
+    #[test]
+    fn candidates_prefer_native_ipv6() {
+        let endpoint = |addr: &str| MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(addr.parse())),
+            nat_restricted: true,
+            port_unknown: false,
+            unverified: false,
+        };
+        let (_, info) = rendezvous_info::gen_rendezvous_info(vec![endpoint("192.0.2.33:5000"),
+                                                                   endpoint("[2001:db8::1]:5000")]);
+        let info = synthesize_nat64_candidates(info, &[Nat64Prefix::well_known()]);
+        let (endpoints, _) = rendezvous_info::decompose(info);
+        assert_eq!(endpoints, vec![endpoint("[2001:db8::1]:5000"),
+                                   endpoint("[64:ff9b::c000:221]:5000"),
+                                   endpoint("192.0.2.33:5000")]);
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Diagnosing a network: everything that can be found out about the NAT in front of us, through
+//! the same code paths as mapping and punching, in one report.
+
+use std::cmp;
+use std::io;
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use rand;
+use socket_addr::SocketAddr;
+use w_result::{WErr, WOk, WResult};
+
+use event::Strategy;
+use mapped_socket_addr::MappedSocketAddr;
+use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketNewError};
+use mapping_behaviour::{check_mapping_behaviour, MappingBehaviour};
+use mapping_context::{self, MappingContext};
+use socket_utils::RecvUntil;
+
+/// How often a hairpinning probe is resent.
+const HAIRPIN_RESEND_INTERVAL_MS: u64 = 200;
+
+/// What `probe_nat` found out about the network.
+#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct NatReport {
+    /// The endpoints found for a fresh udp socket.
+    pub endpoints: Vec<MappedSocketAddr>,
+    /// How the NAT maps traffic to different destinations, if a simple udp server with a
+    /// secondary port answered.
+    pub mapping_behaviour: Option<MappingBehaviour>,
+    /// Whether the NAT forwards packets sent from behind it to its own external address back
+    /// inside, so that two peers behind the same NAT can reach each other at their external
+    /// endpoints. `None` if there was no external endpoint to test.
+    pub hairpinning: Option<bool>,
+    /// Whether we're behind two layers of NAT, if known.
+    pub double_nat: Option<bool>,
+    /// The strategy that has worked best on this network so far.
+    pub recommended_strategy: Option<Strategy>,
+}
+
+/// Map a fresh udp socket with `mc` and find out what we can about the NAT it's behind, giving
+/// up at `deadline`. Mapping takes up to half the time and the remaining checks share the rest.
+pub fn probe_nat(mc: &MappingContext, deadline: Instant)
+                 -> WResult<NatReport, MappedUdpSocketMapWarning, MappedUdpSocketNewError> {
+    let now = Instant::now();
+    let map_deadline = if deadline > now { now + (deadline - now) / 2 } else { deadline };
+    let (mapped, warnings) = match MappedUdpSocket::new(mc, map_deadline) {
+        WOk(mapped, warnings) => (mapped, warnings),
+        WErr(e) => return WErr(e),
+    };
+
+    let now = Instant::now();
+    let check_deadline = if deadline > now { now + (deadline - now) / 2 } else { deadline };
+    let mut mapping_behaviour = None;
+    for server in mapping_context::simple_udp_servers(mc) {
+        if let Ok(behaviour) = check_mapping_behaviour(&mapped.socket, &server, check_deadline) {
+            mapping_behaviour = Some(behaviour);
+            break;
+        }
+    }
+
+    let mut hairpinning = None;
+    for endpoint in mapped.endpoints.iter().filter(|e| e.nat_restricted && !e.port_unknown) {
+        match check_hairpinning(&mapped.socket, &endpoint.addr, deadline) {
+            Ok(true) => {
+                hairpinning = Some(true);
+                break;
+            },
+            Ok(false) => hairpinning = Some(false),
+            Err(_) => (),
+        }
+    }
+
+    let report = NatReport {
+        endpoints: mapped.endpoints,
+        mapping_behaviour: mapping_behaviour,
+        hairpinning: hairpinning,
+        double_nat: mc.double_nat(),
+        recommended_strategy: mc.recommended_strategy(),
+    };
+    WOk(report, warnings)
+}
+
+/// Whether a packet sent from another socket on this host to `external_addr`, the external
+/// endpoint of `socket`, makes it back through the NAT to `socket` before `deadline`.
+pub fn check_hairpinning(socket: &UdpSocket, external_addr: &SocketAddr, deadline: Instant)
+                         -> io::Result<bool> {
+    let unspecified = match external_addr.ip() {
+        IpAddr::V4(..) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+        IpAddr::V6(..) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+    };
+    let prober = try!(UdpSocket::bind(net::SocketAddr::new(unspecified, 0)));
+    let nonce: [u8; 16] = rand::random();
+    let mut buf = [0u8; 64];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        let _ = try!(prober.send_to(&nonce[..], &**external_addr));
+        let resend_at = cmp::min(now + Duration::from_millis(HAIRPIN_RESEND_INTERVAL_MS),
+                                 deadline);
+        while let Some((len, _)) = try!(socket.recv_until(&mut buf[..], resend_at)) {
+            if buf[..len] == nonce[..] {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use socket_addr::SocketAddr;
+
+    #[test]
+    fn hairpinning_is_seen_when_packets_come_back() {
+        // Without a NAT in the way, a socket's own address trivially hairpins.
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let addr = SocketAddr(unwrap_result!(socket.local_addr()));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        assert!(unwrap_result!(check_hairpinning(&socket, &addr, deadline)));
+
+        let unanswered = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let addr = SocketAddr(unwrap_result!(unanswered.local_addr()));
+        let deadline = Instant::now() + Duration::from_millis(300);
+        assert!(!unwrap_result!(check_hairpinning(&socket, &addr, deadline)));
+    }
+}
@@ -0,0 +1,571 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::io;
+use std::net::{self, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, Duration};
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+use maidsafe_utilities::thread::RaiiThreadJoiner;
+use rand::Rng;
+use socket_addr::SocketAddr;
+
+use randomness::RngHandle;
+use socket_utils::RecvUntil;
+use transport::DatagramTransport;
+
+/// How often the NAT's threads check whether they should shut down.
+const POLL_INTERVAL_MS: u64 = 100;
+
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// Random port allocation picks ports from the IANA ephemeral range.
+const EPHEMERAL_PORT_MIN: u16 = 49152;
+/// How many random ports are tried before a new mapping fails.
+const RANDOM_PORT_ATTEMPTS: usize = 16;
+
+/// How a simulated symmetric NAT chooses the external port for a new mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortAllocation {
+    /// Each new mapping uses the next free port after the previous mapping's.
+    Sequential,
+    /// Each new mapping uses a random port drawn from the NAT's random number generator.
+    Random,
+}
+
+/// The mapping and filtering behaviour of a simulated NAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatBehaviour {
+    /// One mapping per internal address. Anyone can send to the mapping.
+    FullCone,
+    /// One mapping per internal address. Only hosts that the internal address has sent to can
+    /// send to the mapping.
+    RestrictedCone,
+    /// One mapping per internal address. Only the exact addresses that the internal address has
+    /// sent to can send to the mapping.
+    PortRestrictedCone,
+    /// One mapping per internal address and destination. Only the destination can send to the
+    /// mapping.
+    Symmetric(PortAllocation),
+}
+
+/// Configuration for a `SimulatedNat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatConfig {
+    /// How the NAT maps and filters packets.
+    pub behaviour: NatBehaviour,
+    /// How long a mapping survives without outbound traffic.
+    pub mapping_lifetime: Duration,
+}
+
+impl NatConfig {
+    /// A config with the given behaviour and a two minute mapping lifetime.
+    pub fn new(behaviour: NatBehaviour) -> NatConfig {
+        NatConfig {
+            behaviour: behaviour,
+            mapping_lifetime: Duration::from_secs(120),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct MappingKey {
+    internal: net::SocketAddr,
+    // Only set for symmetric NATs.
+    destination: Option<net::SocketAddr>,
+}
+
+struct Mapping {
+    socket: Arc<UdpSocket>,
+    contacted: HashSet<net::SocketAddr>,
+    last_outbound: Instant,
+    expired: Arc<AtomicBool>,
+    _raii_joiner: RaiiThreadJoiner,
+}
+
+struct NatState {
+    config: NatConfig,
+    mappings: HashMap<MappingKey, Mapping>,
+    next_port: Option<u16>,
+    rng: RngHandle,
+}
+
+/// A simulated NAT. Packets are forwarded until the NAT is dropped.
+pub struct SimulatedNat {
+    inside_addr: net::SocketAddr,
+    stop_flag: Arc<AtomicBool>,
+    state: Arc<Mutex<NatState>>,
+    _raii_joiner: RaiiThreadJoiner,
+}
+
+impl fmt::Debug for SimulatedNat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimulatedNat").field("inside_addr", &self.inside_addr).finish()
+    }
+}
+
+impl SimulatedNat {
+    /// Start a new simulated NAT.
+    pub fn new(config: NatConfig) -> io::Result<SimulatedNat> {
+        SimulatedNat::with_rng(config, try!(RngHandle::os()))
+    }
+
+    /// Start a new simulated NAT which draws random ports from `rng`. With a seeded `rng` the
+    /// sequence of ports chosen is reproducible. Ports are only ever drawn from `rng`, so if a
+    /// drawn port is in use the next one is drawn, and the packet that needed the mapping is
+    /// dropped once 16 have been.
+    pub fn with_rng(config: NatConfig, rng: RngHandle) -> io::Result<SimulatedNat> {
+        let inside = try!(UdpSocket::bind("127.0.0.1:0"));
+        try!(inside.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS))));
+        let inside_addr = try!(inside.local_addr());
+        let inside = Arc::new(inside);
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let state = Arc::new(Mutex::new(NatState {
+            config: config,
+            mappings: HashMap::new(),
+            next_port: None,
+            rng: rng,
+        }));
+
+        let cloned_stop_flag = stop_flag.clone();
+        let cloned_state = state.clone();
+        let raii_joiner = RaiiThreadJoiner::new(thread!("SimulatedNat outbound", move || {
+            SimulatedNat::run_outbound(inside, cloned_state, cloned_stop_flag);
+        }));
+
+        Ok(SimulatedNat {
+            inside_addr: inside_addr,
+            stop_flag: stop_flag,
+            state: state,
+            _raii_joiner: raii_joiner,
+        })
+    }
+
+    /// Bind a new socket behind this NAT. All traffic sent and received with the socket passes
+    /// through the NAT.
+    pub fn bind(&self) -> io::Result<NatSimSocket> {
+        let socket = try!(UdpSocket::bind("127.0.0.1:0"));
+        Ok(NatSimSocket {
+            socket: socket,
+            nat_addr: self.inside_addr,
+        })
+    }
+
+    /// The number of mappings currently held by the NAT.
+    pub fn num_mappings(&self) -> usize {
+        let state = unwrap_result!(self.state.lock());
+        state.mappings.values().filter(|m| !m.expired.load(Ordering::SeqCst)).count()
+    }
+
+    fn run_outbound(inside: Arc<UdpSocket>,
+                    state: Arc<Mutex<NatState>>,
+                    stop_flag: Arc<AtomicBool>) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        while !stop_flag.load(Ordering::SeqCst) {
+            let (n, internal) = match inside.recv_from(&mut buf[..]) {
+                Ok(x) => x,
+                Err(..) => continue,
+            };
+            let (destination, payload) = match decode_frame(&buf[..n]) {
+                Some(x) => x,
+                None => continue,
+            };
+            let socket = match SimulatedNat::outbound_mapping(&inside, &state, internal, destination) {
+                Some(socket) => socket,
+                None => continue,
+            };
+            let _ = socket.send_to(payload, destination);
+        }
+    }
+
+    fn outbound_mapping(inside: &Arc<UdpSocket>,
+                        state: &Arc<Mutex<NatState>>,
+                        internal: net::SocketAddr,
+                        destination: net::SocketAddr)
+                        -> Option<Arc<UdpSocket>> {
+        // Declared before the guard so that an expired mapping, whose thread may be waiting on
+        // the lock, is only joined once the lock has been released.
+        let mut stale = Vec::new();
+        let mut guard = unwrap_result!(state.lock());
+        let config = guard.config;
+        let key = MappingKey {
+            internal: internal,
+            destination: match config.behaviour {
+                NatBehaviour::Symmetric(..) => Some(destination),
+                _ => None,
+            },
+        };
+
+        let now = Instant::now();
+        let live = match guard.mappings.get(&key) {
+            Some(mapping) => {
+                !mapping.expired.load(Ordering::SeqCst) &&
+                now - mapping.last_outbound < config.mapping_lifetime
+            },
+            None => false,
+        };
+        if !live {
+            if let Some(old) = guard.mappings.remove(&key) {
+                old.expired.store(true, Ordering::SeqCst);
+                stale.push(old);
+            }
+            let socket = match SimulatedNat::bind_external(&mut guard) {
+                Ok(socket) => Arc::new(socket),
+                Err(..) => return None,
+            };
+            let expired = Arc::new(AtomicBool::new(false));
+            let cloned_socket = socket.clone();
+            let cloned_inside = inside.clone();
+            let cloned_state = state.clone();
+            let cloned_expired = expired.clone();
+            let raii_joiner = RaiiThreadJoiner::new(thread!("SimulatedNat inbound", move || {
+                SimulatedNat::run_inbound(cloned_socket, cloned_inside, cloned_state, key, internal,
+                                  cloned_expired);
+            }));
+            let _ = guard.mappings.insert(key, Mapping {
+                socket: socket,
+                contacted: HashSet::new(),
+                last_outbound: now,
+                expired: expired,
+                _raii_joiner: raii_joiner,
+            });
+        }
+
+        let mapping = match guard.mappings.get_mut(&key) {
+            Some(mapping) => mapping,
+            None => return None,
+        };
+        mapping.last_outbound = now;
+        let _ = mapping.contacted.insert(destination);
+        Some(mapping.socket.clone())
+    }
+
+    fn bind_external(state: &mut NatState) -> io::Result<UdpSocket> {
+        let allocation = match state.config.behaviour {
+            NatBehaviour::Symmetric(allocation) => Some(allocation),
+            _ => None,
+        };
+        let socket = match (allocation, state.next_port) {
+            (Some(PortAllocation::Sequential), Some(port)) => {
+                let mut port = port;
+                loop {
+                    match UdpSocket::bind(("127.0.0.1", port)) {
+                        Ok(socket) => return SimulatedNat::finish_external(state, socket),
+                        Err(..) if port < 65535 => port += 1,
+                        Err(e) => return Err(e),
+                    }
+                }
+            },
+            (Some(PortAllocation::Random), _) => {
+                let mut attempts = 0;
+                loop {
+                    let port = state.rng.gen_range(EPHEMERAL_PORT_MIN, 65535);
+                    attempts += 1;
+                    match UdpSocket::bind(("127.0.0.1", port)) {
+                        Ok(socket) => return SimulatedNat::finish_external(state, socket),
+                        Err(..) if attempts < RANDOM_PORT_ATTEMPTS => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+            },
+            _ => try!(UdpSocket::bind("127.0.0.1:0")),
+        };
+        SimulatedNat::finish_external(state, socket)
+    }
+
+    fn finish_external(state: &mut NatState, socket: UdpSocket) -> io::Result<UdpSocket> {
+        try!(socket.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS))));
+        let port = try!(socket.local_addr()).port();
+        state.next_port = port.checked_add(1);
+        Ok(socket)
+    }
+
+    fn run_inbound(external: Arc<UdpSocket>,
+                   inside: Arc<UdpSocket>,
+                   state: Arc<Mutex<NatState>>,
+                   key: MappingKey,
+                   internal: net::SocketAddr,
+                   expired: Arc<AtomicBool>) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        while !expired.load(Ordering::SeqCst) {
+            let (n, source) = match external.recv_from(&mut buf[..]) {
+                Ok(x) => x,
+                Err(..) => continue,
+            };
+            let allowed = {
+                let guard = unwrap_result!(state.lock());
+                match guard.mappings.get(&key) {
+                    Some(mapping) => {
+                        Instant::now() - mapping.last_outbound < guard.config.mapping_lifetime &&
+                        filter_allows(guard.config.behaviour, &mapping.contacted, &source)
+                    },
+                    None => false,
+                }
+            };
+            if allowed {
+                let _ = inside.send_to(&encode_frame(&source, &buf[..n]), internal);
+            }
+        }
+    }
+}
+
+impl Drop for SimulatedNat {
+    fn drop(&mut self) {
+        self.stop_flag.store(true, Ordering::SeqCst);
+        let mappings: Vec<Mapping> = {
+            let mut state = unwrap_result!(self.state.lock());
+            state.mappings.drain().map(|(_, mapping)| mapping).collect()
+        };
+        for mapping in &mappings {
+            mapping.expired.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+fn filter_allows(behaviour: NatBehaviour,
+                 contacted: &HashSet<net::SocketAddr>,
+                 source: &net::SocketAddr) -> bool {
+    match behaviour {
+        NatBehaviour::FullCone => true,
+        NatBehaviour::RestrictedCone => contacted.iter().any(|addr| addr.ip() == source.ip()),
+        NatBehaviour::PortRestrictedCone |
+        NatBehaviour::Symmetric(..) => contacted.contains(source),
+    }
+}
+
+/// A udp socket bound behind a `SimulatedNat`.
+#[derive(Debug)]
+pub struct NatSimSocket {
+    socket: UdpSocket,
+    nat_addr: net::SocketAddr,
+}
+
+impl NatSimSocket {
+    /// Send a packet through the NAT to `addr`.
+    pub fn send_to(&self, buf: &[u8], addr: &net::SocketAddr) -> io::Result<usize> {
+        let frame = encode_frame(addr, buf);
+        let _ = try!(self.socket.send_to(&frame, self.nat_addr));
+        Ok(buf.len())
+    }
+
+    /// Receive a packet which has passed through the NAT.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, net::SocketAddr)> {
+        loop {
+            match try!(self.recv_frame(buf, None)) {
+                Some((n, addr)) => return Ok((n, *addr)),
+                None => continue,
+            }
+        }
+    }
+
+    /// The address of the socket on the inside of the NAT.
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn recv_frame(&self, buf: &mut [u8], deadline: Option<Instant>)
+                  -> io::Result<Option<(usize, SocketAddr)>> {
+        let mut frame = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (n, addr) = match deadline {
+                Some(deadline) => {
+                    match try!(RecvUntil::recv_until(&self.socket, &mut frame[..], deadline)) {
+                        Some(x) => x,
+                        None => return Ok(None),
+                    }
+                },
+                None => {
+                    let (n, addr) = try!(self.socket.recv_from(&mut frame[..]));
+                    (n, SocketAddr(addr))
+                },
+            };
+            if *addr != self.nat_addr {
+                continue;
+            }
+            if let Some((source, payload)) = decode_frame(&frame[..n]) {
+                let len = ::std::cmp::min(payload.len(), buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                return Ok(Some((len, SocketAddr(source))));
+            }
+        }
+    }
+}
+
+impl DatagramTransport for NatSimSocket {
+    fn send_to(&self, buf: &[u8], addr: &net::SocketAddr) -> io::Result<usize> {
+        NatSimSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_until(&self, buf: &mut [u8], deadline: Instant)
+                  -> io::Result<Option<(usize, SocketAddr)>> {
+        self.recv_frame(buf, Some(deadline))
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        NatSimSocket::local_addr(self)
+    }
+}
+
+// Frames passed between a `NatSimSocket` and its NAT are prefixed with the remote address: a
+// family byte (4 or 6), the ip address, then the port in big-endian order.
+fn encode_frame(addr: &net::SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(19 + payload.len());
+    match *addr {
+        net::SocketAddr::V4(ref addr_v4) => {
+            frame.push(4);
+            frame.extend_from_slice(&addr_v4.ip().octets());
+        },
+        net::SocketAddr::V6(ref addr_v6) => {
+            frame.push(6);
+            for segment in &addr_v6.ip().segments() {
+                let mut bytes = [0u8; 2];
+                BigEndian::write_u16(&mut bytes, *segment);
+                frame.extend_from_slice(&bytes);
+            }
+        },
+    }
+    let mut port = [0u8; 2];
+    BigEndian::write_u16(&mut port, addr.port());
+    frame.extend_from_slice(&port);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn decode_frame(frame: &[u8]) -> Option<(net::SocketAddr, &[u8])> {
+    match frame.first() {
+        Some(&4) if frame.len() >= 7 => {
+            let ip = Ipv4Addr::new(frame[1], frame[2], frame[3], frame[4]);
+            let port = BigEndian::read_u16(&frame[5..7]);
+            Some((net::SocketAddr::V4(SocketAddrV4::new(ip, port)), &frame[7..]))
+        },
+        Some(&6) if frame.len() >= 19 => {
+            let mut segments = [0u16; 8];
+            for (i, segment) in segments.iter_mut().enumerate() {
+                *segment = BigEndian::read_u16(&frame[1 + 2 * i..3 + 2 * i]);
+            }
+            let ip = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                   segments[4], segments[5], segments[6], segments[7]);
+            let port = BigEndian::read_u16(&frame[17..19]);
+            Some((net::SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)), &frame[19..]))
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::EPHEMERAL_PORT_MIN;
+
+    use std::net::{self, UdpSocket};
+    use std::time::{Instant, Duration};
+
+    use rand::Rng;
+
+    use randomness::RngHandle;
+    use transport::DatagramTransport;
+
+    fn external_addr_towards(socket: &NatSimSocket, server: &UdpSocket) -> net::SocketAddr {
+        let server_addr = unwrap_result!(server.local_addr());
+        let _ = unwrap_result!(socket.send_to(b"hello", &server_addr));
+        let mut buf = [0u8; 16];
+        let (n, addr) = unwrap_result!(server.recv_from(&mut buf));
+        assert_eq!(&buf[..n], b"hello");
+        addr
+    }
+
+    fn bind_server() -> UdpSocket {
+        let server = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        unwrap_result!(server.set_read_timeout(Some(Duration::from_secs(2))));
+        server
+    }
+
+    fn receives(socket: &NatSimSocket) -> bool {
+        let deadline = Instant::now() + Duration::from_millis(500);
+        let mut buf = [0u8; 16];
+        unwrap_result!(DatagramTransport::recv_until(socket, &mut buf, deadline)).is_some()
+    }
+
+    #[test]
+    fn port_restricted_cone_filters_unsolicited_packets() {
+        let nat = unwrap_result!(SimulatedNat::new(NatConfig::new(NatBehaviour::PortRestrictedCone)));
+        let socket = unwrap_result!(nat.bind());
+        let server_0 = bind_server();
+        let server_1 = bind_server();
+
+        let external_0 = external_addr_towards(&socket, &server_0);
+        let external_1 = external_addr_towards(&socket, &server_1);
+        assert_eq!(external_0, external_1);
+
+        let stranger = bind_server();
+        let _ = unwrap_result!(stranger.send_to(b"unsolicited", external_0));
+        assert!(!receives(&socket));
+
+        let _ = unwrap_result!(server_0.send_to(b"reply", external_0));
+        assert!(receives(&socket));
+    }
+
+    #[test]
+    fn symmetric_nat_allocates_sequential_ports_per_destination() {
+        let config = NatConfig::new(NatBehaviour::Symmetric(PortAllocation::Sequential));
+        let nat = unwrap_result!(SimulatedNat::new(config));
+        let socket = unwrap_result!(nat.bind());
+        let server_0 = bind_server();
+        let server_1 = bind_server();
+
+        let external_0 = external_addr_towards(&socket, &server_0);
+        let external_1 = external_addr_towards(&socket, &server_1);
+        assert!(external_1.port() > external_0.port());
+        assert_eq!(nat.num_mappings(), 2);
+    }
+
+    #[test]
+    fn seeded_random_allocation_is_reproducible() {
+        let seed = [4, 8, 15, 16];
+        let config = NatConfig::new(NatBehaviour::Symmetric(PortAllocation::Random));
+        let server = bind_server();
+        // The second NAT is only started once the first, and its mapping, are gone.
+        for _ in 0..2 {
+            let nat = unwrap_result!(SimulatedNat::with_rng(config, RngHandle::seeded(&seed)));
+            let socket = unwrap_result!(nat.bind());
+            let external = external_addr_towards(&socket, &server);
+            let mut expected = RngHandle::seeded(&seed);
+            assert_eq!(external.port(), expected.gen_range(EPHEMERAL_PORT_MIN, 65535));
+        }
+    }
+
+    #[test]
+    fn mappings_expire() {
+        let mut config = NatConfig::new(NatBehaviour::FullCone);
+        config.mapping_lifetime = Duration::from_millis(200);
+        let nat = unwrap_result!(SimulatedNat::new(config));
+        let socket = unwrap_result!(nat.bind());
+        let server = bind_server();
+
+        let external = external_addr_towards(&socket, &server);
+        ::std::thread::sleep(Duration::from_millis(300));
+        let _ = unwrap_result!(server.send_to(b"late", external));
+        assert!(!receives(&socket));
+    }
+}
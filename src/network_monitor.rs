@@ -0,0 +1,450 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+#[cfg(any(all(feature = "netlink", any(target_os = "linux", target_os = "android")), test))]
+use std::cmp;
+use std::net::IpAddr;
+#[cfg(any(all(feature = "netlink", any(target_os = "linux", target_os = "android")), test))]
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::fmt;
+
+#[cfg(any(all(feature = "netlink", any(target_os = "linux", target_os = "android")), test))]
+use byteorder::{BigEndian, ByteOrder, NativeEndian};
+use get_if_addrs;
+use w_result::WResult;
+
+use mapping_context::{MappingContext, MappingContextNewError, MappingContextNewWarning};
+use runtime::RuntimeHandle;
+#[cfg(all(feature = "netlink", any(target_os = "linux", target_os = "android")))]
+use runtime::Sockets;
+
+/// How often the interface list is compared against the last one when there's no better way to
+/// learn about changes.
+const POLL_INTERVAL_SECS: u64 = 5;
+/// How long a burst of netlink notifications must have been quiet before re-gathering.
+#[cfg(all(feature = "netlink", any(target_os = "linux", target_os = "android")))]
+const SETTLE_TIME_SECS: u64 = 1;
+
+/// Watches for the machine's network changing, eg. a phone moving between wifi and cellular, and
+/// calls `MappingContext::network_changed` when it does. Subscribers to the context's events are
+/// then told with `Event::NetworkChanged`. Watching stops when this is dropped.
+pub struct NetworkMonitor {
+    mc: Arc<MappingContext>,
+    stop_flag: Arc<AtomicBool>,
+}
+
+impl fmt::Debug for NetworkMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NetworkMonitor")
+    }
+}
+
+impl NetworkMonitor {
+    /// Watch from the context's runtime. With the `netlink` feature on Linux and Android this
+    /// listens for rtnetlink link, address and route notifications, falling back to polling the
+    /// interface list where netlink isn't available. Otherwise the interface list is polled every
+    /// few seconds, which misses changes that are undone in between.
+    pub fn new(mc: Arc<MappingContext>) -> NetworkMonitor {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let runtime = mc.runtime();
+        start(mc.clone(), stop_flag.clone(), runtime);
+        NetworkMonitor {
+            mc: mc,
+            stop_flag: stop_flag,
+        }
+    }
+
+    /// A monitor which doesn't watch by itself, for apps which learn of changes from the
+    /// platform, eg. `NWPathMonitor` or the SystemConfiguration reachability callbacks on iOS and
+    /// macOS or `ConnectivityManager` on Android. They call `path_changed` from those callbacks.
+    pub fn driven_by_host(mc: Arc<MappingContext>) -> NetworkMonitor {
+        NetworkMonitor {
+            mc: mc,
+            stop_flag: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Report that the network changed. This blocks briefly while the context re-gathers its
+    /// interfaces.
+    pub fn path_changed(&self) -> WResult<(), MappingContextNewWarning, MappingContextNewError> {
+        self.mc.network_changed()
+    }
+}
+
+impl Drop for NetworkMonitor {
+    fn drop(&mut self) {
+        self.mc.runtime().stop(&self.stop_flag);
+    }
+}
+
+#[cfg(all(feature = "netlink", any(target_os = "linux", target_os = "android")))]
+fn start(mc: Arc<MappingContext>, stop_flag: Arc<AtomicBool>, runtime: RuntimeHandle) {
+    // Sandboxes, eg. on some Android versions, don't allow netlink sockets.
+    let socket = match netlink::NetlinkSocket::new() {
+        Ok(socket) => socket,
+        Err(_) => return start_polling(mc, stop_flag, runtime),
+    };
+    let cloned_runtime = runtime.clone();
+    runtime.spawn(move || watch_netlink(socket, None, mc, stop_flag, cloned_runtime));
+}
+
+#[cfg(not(all(feature = "netlink", any(target_os = "linux", target_os = "android"))))]
+fn start(mc: Arc<MappingContext>, stop_flag: Arc<AtomicBool>, runtime: RuntimeHandle) {
+    start_polling(mc, stop_flag, runtime)
+}
+
+// Read the notifications waiting on the netlink socket then wait for more. A change arrives as a
+// burst of notifications, so `burst` holds when the last one arrived and whether any so far were
+// relevant. Re-gathering waits until the burst has settled.
+#[cfg(all(feature = "netlink", any(target_os = "linux", target_os = "android")))]
+fn watch_netlink(socket: netlink::NetlinkSocket,
+                 mut burst: Option<(Instant, bool)>,
+                 mc: Arc<MappingContext>,
+                 stop_flag: Arc<AtomicBool>,
+                 runtime: RuntimeHandle) {
+    if stop_flag.load(Ordering::SeqCst) {
+        return;
+    }
+    let mut buf = [0u8; 8192];
+    loop {
+        match socket.recv(&mut buf) {
+            Ok(Some(len)) => {
+                let relevant = burst.map_or(false, |(_, relevant)| relevant);
+                burst = Some((Instant::now(), relevant || is_relevant(&buf[..len])));
+            },
+            Ok(None) => break,
+            Err(_) => return start_polling(mc, stop_flag, runtime),
+        }
+    }
+    if let Some((last_received, relevant)) = burst {
+        if last_received.elapsed() >= Duration::from_secs(SETTLE_TIME_SECS) {
+            burst = None;
+            if relevant {
+                report_change(&mc);
+            }
+        }
+    }
+    let sockets = Sockets::new(&[&socket]).or_stopped(&stop_flag);
+    let settled_at = burst.map(|(last_received, _)| {
+        last_received + Duration::from_secs(SETTLE_TIME_SECS)
+    });
+    let cloned_runtime = runtime.clone();
+    runtime.when_readable(sockets, settled_at, move || {
+        watch_netlink(socket, burst, mc, stop_flag, cloned_runtime);
+    });
+}
+
+fn start_polling(mc: Arc<MappingContext>, stop_flag: Arc<AtomicBool>, runtime: RuntimeHandle) {
+    let addrs = interface_addrs();
+    let cloned_runtime = runtime.clone();
+    runtime.schedule(Instant::now() + Duration::from_secs(POLL_INTERVAL_SECS), move || {
+        poll(addrs, mc, stop_flag, cloned_runtime);
+    });
+}
+
+// Compare the interface list against the one from the last poll, then poll again after
+// `POLL_INTERVAL_SECS`.
+fn poll(last_addrs: Vec<IpAddr>,
+        mc: Arc<MappingContext>,
+        stop_flag: Arc<AtomicBool>,
+        runtime: RuntimeHandle) {
+    if stop_flag.load(Ordering::SeqCst) {
+        return;
+    }
+    let addrs = interface_addrs();
+    if addrs != last_addrs {
+        report_change(&mc);
+    }
+    let cloned_runtime = runtime.clone();
+    runtime.schedule(Instant::now() + Duration::from_secs(POLL_INTERVAL_SECS), move || {
+        poll(addrs, mc, stop_flag, cloned_runtime);
+    });
+}
+
+fn interface_addrs() -> Vec<IpAddr> {
+    match get_if_addrs::get_if_addrs() {
+        Ok(interfaces) => {
+            interfaces.into_iter()
+                      .map(|interface| {
+                          match interface.addr {
+                              get_if_addrs::IfAddr::V4(v4_addr) => IpAddr::V4(v4_addr.ip),
+                              get_if_addrs::IfAddr::V6(v6_addr) => IpAddr::V6(v6_addr.ip),
+                          }
+                      })
+                      .collect()
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+// Which rtnetlink notifications warrant re-gathering. Anything touching loopback, addresses
+// still undergoing duplicate address detection and routes other than the main table's default
+// routes don't change the candidates, gateway or external addresses, and on busy hosts (eg. ones
+// running containers) they far outnumber the changes that do.
+#[cfg(any(all(feature = "netlink", any(target_os = "linux", target_os = "android")), test))]
+fn is_relevant(buf: &[u8]) -> bool {
+    const NLMSG_HDRLEN: usize = 16;
+    const RTM_NEWLINK: u16 = 16;
+    const RTM_DELLINK: u16 = 17;
+    const RTM_NEWADDR: u16 = 20;
+    const RTM_DELADDR: u16 = 21;
+    const RTM_NEWROUTE: u16 = 24;
+    const RTM_DELROUTE: u16 = 25;
+    const IFF_LOOPBACK: u32 = 0x8;
+    const IFA_F_TENTATIVE: u8 = 0x40;
+    const RT_TABLE_MAIN: u8 = 254;
+
+    let mut offset = 0;
+    while offset + NLMSG_HDRLEN <= buf.len() {
+        let len = NativeEndian::read_u32(&buf[offset..]) as usize;
+        if len < NLMSG_HDRLEN || offset + len > buf.len() {
+            break;
+        }
+        let msg_type = NativeEndian::read_u16(&buf[offset + 4..]);
+        let payload = &buf[offset + NLMSG_HDRLEN..offset + len];
+        let relevant = match msg_type {
+            // struct ifinfomsg: family, pad, type, index, flags, change.
+            RTM_NEWLINK | RTM_DELLINK if payload.len() >= 16 => {
+                NativeEndian::read_u32(&payload[8..]) & IFF_LOOPBACK == 0
+            },
+            // struct ifaddrmsg: family, prefixlen, flags, scope, index. Then attributes.
+            RTM_NEWADDR | RTM_DELADDR if payload.len() >= 8 => {
+                payload[2] & IFA_F_TENTATIVE == 0 &&
+                addr_attribute(payload[0], &payload[8..]).map_or(true, |ip| !ip.is_loopback())
+            },
+            // struct rtmsg: family, dst_len, src_len, tos, table, ...
+            RTM_NEWROUTE | RTM_DELROUTE if payload.len() >= 12 => {
+                payload[1] == 0 && payload[4] == RT_TABLE_MAIN
+            },
+            _ => false,
+        };
+        if relevant {
+            return true;
+        }
+        offset += (len + 3) & !3;
+    }
+    false
+}
+
+// The IFA_LOCAL or, failing that, IFA_ADDRESS attribute of an address notification.
+#[cfg(any(all(feature = "netlink", any(target_os = "linux", target_os = "android")), test))]
+fn addr_attribute(family: u8, mut attrs: &[u8]) -> Option<IpAddr> {
+    const AF_INET: u8 = 2;
+    const AF_INET6: u8 = 10;
+    const IFA_ADDRESS: u16 = 1;
+    const IFA_LOCAL: u16 = 2;
+
+    let mut address = None;
+    while attrs.len() >= 4 {
+        let len = NativeEndian::read_u16(attrs) as usize;
+        if len < 4 || len > attrs.len() {
+            break;
+        }
+        let attr_type = NativeEndian::read_u16(&attrs[2..]);
+        let value = &attrs[4..len];
+        let ip = match (family, value.len()) {
+            (AF_INET, 4) => Some(IpAddr::V4(Ipv4Addr::new(value[0], value[1], value[2], value[3]))),
+            (AF_INET6, 16) => {
+                let mut segments = [0u16; 8];
+                for (i, segment) in segments.iter_mut().enumerate() {
+                    *segment = BigEndian::read_u16(&value[2 * i..]);
+                }
+                Some(IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2],
+                                              segments[3], segments[4], segments[5],
+                                              segments[6], segments[7])))
+            },
+            _ => None,
+        };
+        match attr_type {
+            IFA_LOCAL if ip.is_some() => return ip,
+            IFA_ADDRESS => address = ip,
+            _ => (),
+        }
+        attrs = &attrs[cmp::min((len + 3) & !3, attrs.len())..];
+    }
+    address
+}
+
+// Failing to re-gather leaves the context with its old interfaces, which is all that can be
+// done from a runtime job. The next change tries again. Re-gathering searches for IGD gateways,
+// which can hold up a worker for a while, but networks rarely change often enough to matter.
+fn report_change(mc: &MappingContext) {
+    let _ = mc.network_changed();
+}
+
+#[cfg(all(feature = "netlink", any(target_os = "linux", target_os = "android")))]
+#[allow(unsafe_code)]
+mod netlink {
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, RawFd};
+
+    use libc;
+
+    const AF_NETLINK: libc::c_int = 16;
+    const NETLINK_ROUTE: libc::c_int = 0;
+    const RTMGRP_LINK: u32 = 0x1;
+    const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+    const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+    const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+    const RTMGRP_IPV6_ROUTE: u32 = 0x400;
+
+    #[repr(C)]
+    struct SockaddrNl {
+        nl_family: libc::sa_family_t,
+        nl_pad: u16,
+        nl_pid: u32,
+        nl_groups: u32,
+    }
+
+    /// A netlink socket subscribed to link, address and route changes.
+    pub struct NetlinkSocket {
+        fd: libc::c_int,
+    }
+
+    impl NetlinkSocket {
+        /// Open the socket.
+        pub fn new() -> io::Result<NetlinkSocket> {
+            let fd = unsafe { libc::socket(AF_NETLINK, libc::SOCK_RAW, NETLINK_ROUTE) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = NetlinkSocket { fd: fd };
+
+            let addr = SockaddrNl {
+                nl_family: AF_NETLINK as libc::sa_family_t,
+                nl_pad: 0,
+                nl_pid: 0,
+                nl_groups: RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV4_ROUTE |
+                           RTMGRP_IPV6_IFADDR | RTMGRP_IPV6_ROUTE,
+            };
+            let addr_ptr: *const SockaddrNl = &addr;
+            let ret = unsafe {
+                libc::bind(fd,
+                           addr_ptr as *const libc::sockaddr,
+                           mem::size_of::<SockaddrNl>() as libc::socklen_t)
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(socket)
+        }
+
+        /// Read a datagram of notifications into `buf` without waiting. Returns `None` if none
+        /// is waiting.
+        pub fn recv(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+            let ret = unsafe {
+                libc::recv(self.fd,
+                           buf.as_mut_ptr() as *mut libc::c_void,
+                           buf.len(),
+                           libc::MSG_DONTWAIT)
+            };
+            if ret >= 0 {
+                return Ok(Some(ret as usize));
+            }
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock |
+                io::ErrorKind::TimedOut |
+                io::ErrorKind::Interrupted => Ok(None),
+                _ => Err(err),
+            }
+        }
+    }
+
+    impl AsRawFd for NetlinkSocket {
+        fn as_raw_fd(&self) -> RawFd {
+            self.fd
+        }
+    }
+
+    impl Drop for NetlinkSocket {
+        fn drop(&mut self) {
+            let _ = unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use byteorder::{ByteOrder, NativeEndian};
+
+    use event::Event;
+    use mapping_context::MappingContext;
+
+    // Build a netlink message of `msg_type` with `payload`, as the kernel would.
+    fn netlink_msg(msg_type: u16, payload: &[u8]) -> Vec<u8> {
+        let len = 16 + payload.len();
+        let mut msg = vec![0u8; (len + 3) & !3];
+        NativeEndian::write_u32(&mut msg[0..], len as u32);
+        NativeEndian::write_u16(&mut msg[4..], msg_type);
+        msg[16..len].copy_from_slice(payload);
+        msg
+    }
+
+    fn addr_payload(flags: u8, ip: [u8; 4]) -> Vec<u8> {
+        // ifaddrmsg for AF_INET, then an IFA_LOCAL attribute.
+        let mut payload = vec![2, 24, flags, 0, 2, 0, 0, 0];
+        let mut attr = vec![0u8; 4];
+        NativeEndian::write_u16(&mut attr[0..], 8);
+        NativeEndian::write_u16(&mut attr[2..], 2);
+        attr.extend(&ip);
+        payload.extend(attr);
+        payload
+    }
+
+    #[test]
+    fn only_relevant_netlink_notifications_trigger_regathering() {
+        assert!(is_relevant(&netlink_msg(20, &addr_payload(0, [192, 168, 1, 7]))));
+        assert!(!is_relevant(&netlink_msg(20, &addr_payload(0, [127, 0, 0, 2]))));
+        assert!(!is_relevant(&netlink_msg(20, &addr_payload(0x40, [192, 168, 1, 7]))));
+
+        // rtmsg for a default route and a /24, both in the main table.
+        let default_route = [2, 0, 0, 0, 254, 3, 0, 1, 0, 0, 0, 0];
+        let subnet_route = [2, 24, 0, 0, 254, 3, 0, 1, 0, 0, 0, 0];
+        assert!(is_relevant(&netlink_msg(24, &default_route)));
+        assert!(!is_relevant(&netlink_msg(24, &subnet_route)));
+
+        let mut burst = netlink_msg(24, &subnet_route);
+        burst.extend(netlink_msg(21, &addr_payload(0, [10, 0, 0, 3])));
+        assert!(is_relevant(&burst));
+    }
+
+    #[test]
+    fn host_reported_change_reaches_subscribers() {
+        let mc = Arc::new(unwrap_result!(MappingContext::new().result_log()));
+        let events = mc.subscribe();
+        let monitor = NetworkMonitor::driven_by_host(mc.clone());
+        unwrap_result!(monitor.path_changed().result_log());
+        loop {
+            match unwrap_result!(events.try_recv()) {
+                Event::NetworkChanged => break,
+                _ => (),
+            }
+        }
+    }
+}
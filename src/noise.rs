@@ -0,0 +1,732 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::cmp::Ordering;
+use std::io::{self, Read, Write};
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+use sodiumoxide;
+use sodiumoxide::crypto::aead::chacha20poly1305 as aead;
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::scalarmult::curve25519;
+use sodiumoxide::randombytes;
+
+use error_code::{ErrorCategory, ErrorCode};
+use identity::IdentityError;
+use rendezvous_info::PubRendezvousInfo;
+use utils;
+
+// The handshake follows the Noise XX pattern. libsodium's original ChaCha20-Poly1305 is used as
+// the cipher, with the 64-bit little-endian nonce counter placed directly in its 64-bit nonce, so
+// the channel only interoperates with this crate.
+const PROTOCOL_NAME: &'static [u8; 32] = b"Noise_XX_25519_ChaChaPoly_SHA256";
+const PROLOGUE: &'static [u8] = b"nat_traversal secure channel v1";
+
+const DH_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+/// The largest message, including its authentication tag, that can be sent in one frame.
+const MAX_FRAME_LEN: usize = 65535;
+
+/// The most bytes that can be passed to `SecureChannel::send` at once.
+pub const MAX_SECURE_MESSAGE_LEN: usize = MAX_FRAME_LEN - TAG_LEN;
+
+/// A long-term Curve25519 key pair identifying a peer. The public key is shared with peers in
+/// `PubRendezvousInfo`, see `gen_rendezvous_info_with_static_key`. The secret key is zeroed when
+/// this is dropped.
+#[derive(Clone)]
+pub struct StaticKeypair {
+    public: [u8; DH_LEN],
+    secret: [u8; DH_LEN],
+}
+
+// The secret key is deliberately left out so that it can't end up in logs.
+impl fmt::Debug for StaticKeypair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StaticKeypair").field("public", &self.public).finish()
+    }
+}
+
+impl StaticKeypair {
+    /// Generate a new random key pair.
+    pub fn generate() -> StaticKeypair {
+        let (public, secret) = generate_keypair();
+        StaticKeypair {
+            public: public,
+            secret: secret,
+        }
+    }
+
+    /// The public half of the key pair.
+    pub fn public_key(&self) -> [u8; DH_LEN] {
+        self.public
+    }
+}
+
+impl Drop for StaticKeypair {
+    fn drop(&mut self) {
+        utils::zeroize(&mut self.secret);
+    }
+}
+
+quick_error! {
+    /// Errors returned while establishing or using a `SecureChannel`.
+    #[derive(Debug)]
+    pub enum NoiseError {
+        /// IO error on the underlying stream.
+        Io { err: io::Error } {
+            description("IO error on the underlying stream.")
+            display("IO error on the underlying stream: {}", err)
+            cause(err)
+        }
+        /// A message was too long to send in one frame.
+        MessageTooLong { len: usize } {
+            description("A message was too long to send in one frame.")
+            display("A message of {} bytes was too long to send in one frame.", len)
+        }
+        /// A received handshake message was malformed.
+        MalformedHandshake {
+            description("A received handshake message was malformed.")
+        }
+        /// A received message failed authentication.
+        Decrypt {
+            description("A received message failed authentication.")
+        }
+        /// The peer authenticated with a different static key than the one in its rendezvous
+        /// info.
+        UnexpectedStaticKey {
+            description("The peer authenticated with a different static key than the one in its \
+                         rendezvous info.")
+        }
+        /// The peer's rendezvous info doesn't carry a static key.
+        MissingStaticKey {
+            description("The peer's rendezvous info doesn't carry a static key.")
+        }
+        /// Too many messages have been sent or received on the channel.
+        NonceExhausted {
+            description("Too many messages have been sent or received on the channel.")
+        }
+        /// The peer's rendezvous info failed identity verification.
+        Identity { err: IdentityError } {
+            description("The peer's rendezvous info failed identity verification.")
+            display("The peer's rendezvous info failed identity verification: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl From<NoiseError> for io::Error {
+    fn from(e: NoiseError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            NoiseError::Io { err } => err.kind(),
+            NoiseError::MessageTooLong { .. } => io::ErrorKind::InvalidInput,
+            NoiseError::MalformedHandshake => io::ErrorKind::InvalidData,
+            NoiseError::Decrypt => io::ErrorKind::InvalidData,
+            NoiseError::UnexpectedStaticKey => io::ErrorKind::PermissionDenied,
+            NoiseError::MissingStaticKey => io::ErrorKind::InvalidInput,
+            NoiseError::NonceExhausted => io::ErrorKind::Other,
+            NoiseError::Identity { err } => {
+                let err: io::Error = From::from(err);
+                err.kind()
+            },
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for NoiseError {
+    fn code(&self) -> u32 {
+        match *self {
+            NoiseError::Io { .. } => 3001,
+            NoiseError::MessageTooLong { .. } => 3002,
+            NoiseError::MalformedHandshake => 3003,
+            NoiseError::Decrypt => 3004,
+            NoiseError::UnexpectedStaticKey => 3005,
+            NoiseError::MissingStaticKey => 3006,
+            NoiseError::NonceExhausted => 3007,
+            NoiseError::Identity { .. } => 3008,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            NoiseError::Io { .. } => ErrorCategory::Network,
+            NoiseError::MessageTooLong { .. } => ErrorCategory::Configuration,
+            NoiseError::MalformedHandshake => ErrorCategory::Protocol,
+            NoiseError::Decrypt => ErrorCategory::Protocol,
+            NoiseError::UnexpectedStaticKey => ErrorCategory::Protocol,
+            NoiseError::MissingStaticKey => ErrorCategory::Configuration,
+            NoiseError::NonceExhausted => ErrorCategory::Protocol,
+            NoiseError::Identity { ref err } => err.category(),
+        }
+    }
+}
+
+/// An encrypted, authenticated channel over a reliable stream, such as a hole punched tcp
+/// connection, established with a Noise handshake.
+pub struct SecureChannel<S> {
+    stream: S,
+    send: CipherState,
+    recv: CipherState,
+    remote_static_key: [u8; DH_LEN],
+    handshake_hash: [u8; 32],
+}
+
+impl<S> fmt::Debug for SecureChannel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecureChannel")
+         .field("remote_static_key", &self.remote_static_key)
+         .finish()
+    }
+}
+
+impl<S: Read + Write> SecureChannel<S> {
+    /// The static public key the peer authenticated with.
+    pub fn remote_static_key(&self) -> [u8; DH_LEN] {
+        self.remote_static_key
+    }
+
+    /// A value unique to this channel which both peers agree on. It can be used to bind
+    /// application-level authentication to the channel.
+    pub fn handshake_hash(&self) -> [u8; 32] {
+        self.handshake_hash
+    }
+
+    /// Encrypt and send one message.
+    pub fn send(&mut self, msg: &[u8]) -> Result<(), NoiseError> {
+        if msg.len() > MAX_SECURE_MESSAGE_LEN {
+            return Err(NoiseError::MessageTooLong { len: msg.len() });
+        }
+        let frame = try!(self.send.encrypt(&[], msg));
+        write_frame(&mut self.stream, &frame)
+    }
+
+    /// Receive and decrypt one message.
+    pub fn recv(&mut self) -> Result<Vec<u8>, NoiseError> {
+        let frame = try!(read_frame(&mut self.stream));
+        self.recv.decrypt(&[], &frame)
+    }
+
+    /// Get a reference to the underlying stream, eg. to set timeouts on it.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+/// Run a Noise handshake over `stream`, which must be connected to a peer doing the same. Exactly
+/// one side must be the initiator. If `expected_remote_key` is given, the handshake fails unless
+/// the peer authenticates with that key.
+pub fn noise_handshake<S: Read + Write>(mut stream: S,
+                                        initiator: bool,
+                                        local_static: &StaticKeypair,
+                                        expected_remote_key: Option<[u8; DH_LEN]>)
+                                        -> Result<SecureChannel<S>, NoiseError> {
+    let mut hs = HandshakeState::new(local_static);
+    let remote_static = if initiator {
+        // -> e
+        let mut msg = try!(hs.write_e());
+        msg.extend(try!(hs.write_payload()));
+        try!(write_frame(&mut stream, &msg));
+        // <- e, ee, s, es
+        let msg = try!(read_frame(&mut stream));
+        let rest = try!(hs.read_e(&msg));
+        let ee = try!(hs.dh_e_re());
+        hs.symmetric.mix_key(&ee);
+        let rest = try!(hs.read_s(rest));
+        // Don't reveal our static key to a responder we weren't expecting.
+        try!(check_remote_key(hs.rs, expected_remote_key));
+        let es = try!(hs.dh_e_rs());
+        hs.symmetric.mix_key(&es);
+        try!(hs.read_payload(rest));
+        // -> s, se
+        let mut msg = try!(hs.write_s());
+        let se = try!(hs.dh_s_re());
+        hs.symmetric.mix_key(&se);
+        msg.extend(try!(hs.write_payload()));
+        try!(write_frame(&mut stream, &msg));
+        hs.rs
+    } else {
+        // -> e
+        let msg = try!(read_frame(&mut stream));
+        let rest = try!(hs.read_e(&msg));
+        try!(hs.read_payload(rest));
+        // <- e, ee, s, es
+        let mut msg = try!(hs.write_e());
+        let ee = try!(hs.dh_e_re());
+        hs.symmetric.mix_key(&ee);
+        msg.extend(try!(hs.write_s()));
+        let es = try!(hs.dh_s_re());
+        hs.symmetric.mix_key(&es);
+        msg.extend(try!(hs.write_payload()));
+        try!(write_frame(&mut stream, &msg));
+        // -> s, se
+        let msg = try!(read_frame(&mut stream));
+        let rest = try!(hs.read_s(&msg));
+        let se = try!(hs.dh_e_rs());
+        hs.symmetric.mix_key(&se);
+        try!(hs.read_payload(rest));
+        hs.rs
+    };
+    let remote_static = try!(check_remote_key(remote_static, expected_remote_key));
+    let (initiator_cipher, responder_cipher) = hs.symmetric.split();
+    let (send, recv) = if initiator {
+        (initiator_cipher, responder_cipher)
+    } else {
+        (responder_cipher, initiator_cipher)
+    };
+    Ok(SecureChannel {
+        stream: stream,
+        send: send,
+        recv: recv,
+        remote_static_key: remote_static,
+        handshake_hash: hs.symmetric.h,
+    })
+}
+
+/// Establish a `SecureChannel` with a peer over a connection made by hole punching. The peer must
+/// authenticate with the static key in `their_pub_rendezvous_info`. Which side initiates the
+/// handshake is decided by comparing the two static keys, so both peers can call this the same
+/// way. If the info is signed, the signature must be valid.
+pub fn secure_channel<S: Read + Write>(stream: S,
+                                       local_static: &StaticKeypair,
+                                       their_pub_rendezvous_info: &PubRendezvousInfo)
+                                       -> Result<SecureChannel<S>, NoiseError> {
+    if let Some(identity_key) = their_pub_rendezvous_info.identity_key() {
+        if let Err(e) = their_pub_rendezvous_info.verify_identity(&identity_key) {
+            return Err(NoiseError::Identity { err: e });
+        }
+    }
+    let their_key = match their_pub_rendezvous_info.static_key() {
+        Some(their_key) => their_key,
+        None => return Err(NoiseError::MissingStaticKey),
+    };
+    let initiator = local_static.public.cmp(&their_key) == Ordering::Less;
+    noise_handshake(stream, initiator, local_static, Some(their_key))
+}
+
+/// Like `secure_channel` but also requires `their_pub_rendezvous_info` to have been signed by the
+/// identity with public key `expected_identity`. A successful handshake then proves that the
+/// connected party holds a static key vouched for by that identity, so a signalling server can't
+/// connect us to anyone else.
+pub fn secure_channel_with_identity<S: Read + Write>(stream: S,
+                                                     local_static: &StaticKeypair,
+                                                     their_pub_rendezvous_info: &PubRendezvousInfo,
+                                                     expected_identity: &[u8; 32])
+                                                     -> Result<SecureChannel<S>, NoiseError> {
+    if let Err(e) = their_pub_rendezvous_info.verify_identity(expected_identity) {
+        return Err(NoiseError::Identity { err: e });
+    }
+    secure_channel(stream, local_static, their_pub_rendezvous_info)
+}
+
+fn check_remote_key(remote_static: Option<[u8; DH_LEN]>, expected: Option<[u8; DH_LEN]>)
+                    -> Result<[u8; DH_LEN], NoiseError> {
+    let remote_static = match remote_static {
+        Some(remote_static) => remote_static,
+        None => return Err(NoiseError::MalformedHandshake),
+    };
+    match expected {
+        Some(expected) if !utils::constant_time_eq(&expected, &remote_static) => {
+            Err(NoiseError::UnexpectedStaticKey)
+        },
+        _ => Ok(remote_static),
+    }
+}
+
+fn generate_keypair() -> ([u8; DH_LEN], [u8; DH_LEN]) {
+    let _ = sodiumoxide::init();
+    let mut secret = [0u8; DH_LEN];
+    randombytes::randombytes_into(&mut secret);
+    let curve25519::GroupElement(public) = curve25519::scalarmult_base(&curve25519::Scalar(secret));
+    (public, secret)
+}
+
+fn dh(secret: &[u8; DH_LEN], public: &[u8; DH_LEN]) -> [u8; DH_LEN] {
+    let curve25519::GroupElement(shared) =
+        curve25519::scalarmult(&curve25519::Scalar(*secret), &curve25519::GroupElement(*public));
+    shared
+}
+
+fn hmac(key: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    let hmacsha256::Tag(tag) = hmacsha256::authenticate(data, &hmacsha256::Key(*key));
+    tag
+}
+
+// HKDF with SHA-256 producing two outputs, as defined by the Noise specification.
+fn hkdf(chaining_key: &[u8; 32], input_key_material: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut temp_key = hmac(chaining_key, input_key_material);
+    let output_1 = hmac(&temp_key, &[1]);
+    let mut input = [0u8; 33];
+    input[..32].copy_from_slice(&output_1);
+    input[32] = 2;
+    let output_2 = hmac(&temp_key, &input);
+    utils::zeroize(&mut temp_key);
+    (output_1, output_2)
+}
+
+struct CipherState {
+    key: Option<[u8; 32]>,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new(key: Option<[u8; 32]>) -> CipherState {
+        CipherState {
+            key: key,
+            nonce: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Result<aead::Nonce, NoiseError> {
+        if self.nonce == u64::max_value() {
+            return Err(NoiseError::NonceExhausted);
+        }
+        let mut nonce = [0u8; aead::NONCEBYTES];
+        for (i, b) in nonce.iter_mut().enumerate() {
+            *b = (self.nonce >> (8 * i)) as u8;
+        }
+        self.nonce += 1;
+        Ok(aead::Nonce(nonce))
+    }
+
+    fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let key = match self.key {
+            Some(key) => key,
+            None => return Ok(plaintext.to_vec()),
+        };
+        let nonce = try!(self.next_nonce());
+        Ok(aead::seal(plaintext, Some(ad), &nonce, &aead::Key(key)))
+    }
+
+    fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let key = match self.key {
+            Some(key) => key,
+            None => return Ok(ciphertext.to_vec()),
+        };
+        let nonce = try!(self.next_nonce());
+        match aead::open(ciphertext, Some(ad), &nonce, &aead::Key(key)) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(()) => Err(NoiseError::Decrypt),
+        }
+    }
+}
+
+impl Drop for CipherState {
+    fn drop(&mut self) {
+        if let Some(ref mut key) = self.key {
+            utils::zeroize(key);
+        }
+    }
+}
+
+struct SymmetricState {
+    cipher: CipherState,
+    ck: [u8; 32],
+    h: [u8; 32],
+}
+
+impl SymmetricState {
+    fn new() -> SymmetricState {
+        let mut state = SymmetricState {
+            cipher: CipherState::new(None),
+            ck: *PROTOCOL_NAME,
+            h: *PROTOCOL_NAME,
+        };
+        state.mix_hash(PROLOGUE);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut input = Vec::with_capacity(32 + data.len());
+        input.extend_from_slice(&self.h);
+        input.extend_from_slice(data);
+        let sha256::Digest(h) = sha256::hash(&input);
+        self.h = h;
+    }
+
+    fn mix_key(&mut self, input_key_material: &[u8]) {
+        let (ck, key) = hkdf(&self.ck, input_key_material);
+        self.ck = ck;
+        self.cipher = CipherState::new(Some(key));
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let h = self.h;
+        let ciphertext = try!(self.cipher.encrypt(&h, plaintext));
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let h = self.h;
+        let plaintext = try!(self.cipher.decrypt(&h, ciphertext));
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    fn split(&mut self) -> (CipherState, CipherState) {
+        let (key_1, key_2) = hkdf(&self.ck, &[]);
+        utils::zeroize(&mut self.ck);
+        (CipherState::new(Some(key_1)), CipherState::new(Some(key_2)))
+    }
+}
+
+struct HandshakeState<'a> {
+    symmetric: SymmetricState,
+    s: &'a StaticKeypair,
+    e: Option<StaticKeypair>,
+    re: Option<[u8; DH_LEN]>,
+    rs: Option<[u8; DH_LEN]>,
+}
+
+impl<'a> HandshakeState<'a> {
+    fn new(s: &'a StaticKeypair) -> HandshakeState<'a> {
+        HandshakeState {
+            symmetric: SymmetricState::new(),
+            s: s,
+            e: None,
+            re: None,
+            rs: None,
+        }
+    }
+
+    fn write_e(&mut self) -> Result<Vec<u8>, NoiseError> {
+        let e = StaticKeypair::generate();
+        let msg = e.public.to_vec();
+        self.symmetric.mix_hash(&e.public);
+        self.e = Some(e);
+        Ok(msg)
+    }
+
+    fn write_s(&mut self) -> Result<Vec<u8>, NoiseError> {
+        let public = self.s.public;
+        self.symmetric.encrypt_and_hash(&public)
+    }
+
+    fn write_payload(&mut self) -> Result<Vec<u8>, NoiseError> {
+        self.symmetric.encrypt_and_hash(&[])
+    }
+
+    fn read_e<'m>(&mut self, msg: &'m [u8]) -> Result<&'m [u8], NoiseError> {
+        if msg.len() < DH_LEN {
+            return Err(NoiseError::MalformedHandshake);
+        }
+        let mut re = [0u8; DH_LEN];
+        re.copy_from_slice(&msg[..DH_LEN]);
+        self.symmetric.mix_hash(&re);
+        self.re = Some(re);
+        Ok(&msg[DH_LEN..])
+    }
+
+    fn read_s<'m>(&mut self, msg: &'m [u8]) -> Result<&'m [u8], NoiseError> {
+        // Once a key has been mixed in the static key is encrypted and carries a tag.
+        let len = DH_LEN + TAG_LEN;
+        if msg.len() < len {
+            return Err(NoiseError::MalformedHandshake);
+        }
+        let plaintext = try!(self.symmetric.decrypt_and_hash(&msg[..len]));
+        if plaintext.len() != DH_LEN {
+            return Err(NoiseError::MalformedHandshake);
+        }
+        let mut rs = [0u8; DH_LEN];
+        rs.copy_from_slice(&plaintext);
+        self.rs = Some(rs);
+        Ok(&msg[len..])
+    }
+
+    fn read_payload(&mut self, msg: &[u8]) -> Result<(), NoiseError> {
+        // We don't send handshake payloads, and ignore any we're sent.
+        let _ = try!(self.symmetric.decrypt_and_hash(msg));
+        Ok(())
+    }
+
+    fn dh_e_re(&self) -> Result<[u8; DH_LEN], NoiseError> {
+        match (&self.e, self.re) {
+            (&Some(ref e), Some(re)) => Ok(dh(&e.secret, &re)),
+            _ => Err(NoiseError::MalformedHandshake),
+        }
+    }
+
+    fn dh_e_rs(&self) -> Result<[u8; DH_LEN], NoiseError> {
+        match (&self.e, self.rs) {
+            (&Some(ref e), Some(rs)) => Ok(dh(&e.secret, &rs)),
+            _ => Err(NoiseError::MalformedHandshake),
+        }
+    }
+
+    fn dh_s_re(&self) -> Result<[u8; DH_LEN], NoiseError> {
+        match self.re {
+            Some(re) => Ok(dh(&self.s.secret, &re)),
+            None => Err(NoiseError::MalformedHandshake),
+        }
+    }
+}
+
+fn write_frame<W: Write>(stream: &mut W, msg: &[u8]) -> Result<(), NoiseError> {
+    if msg.len() > MAX_FRAME_LEN {
+        return Err(NoiseError::MessageTooLong { len: msg.len() });
+    }
+    let mut frame = Vec::with_capacity(2 + msg.len());
+    frame.extend_from_slice(&[0, 0]);
+    BigEndian::write_u16(&mut frame[..2], msg.len() as u16);
+    frame.extend_from_slice(msg);
+    match stream.write_all(&frame) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(NoiseError::Io { err: e }),
+    }
+}
+
+fn read_frame<R: Read>(stream: &mut R) -> Result<Vec<u8>, NoiseError> {
+    let mut len = [0u8; 2];
+    match stream.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) => return Err(NoiseError::Io { err: e }),
+    };
+    let mut msg = vec![0u8; BigEndian::read_u16(&len) as usize];
+    match stream.read_exact(&mut msg) {
+        Ok(()) => Ok(msg),
+        Err(e) => Err(NoiseError::Io { err: e }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{TcpListener, TcpStream};
+
+    use rendezvous_info::gen_rendezvous_info_with_static_key;
+
+    #[test]
+    fn peers_agree_on_a_channel_bound_to_their_keys() {
+        let listener = unwrap_result!(TcpListener::bind("127.0.0.1:0"));
+        let addr = unwrap_result!(listener.local_addr());
+        let key_0 = StaticKeypair::generate();
+        let key_1 = StaticKeypair::generate();
+        let (_, pub_info_0) = gen_rendezvous_info_with_static_key(Vec::new(), &key_0);
+        let (_, pub_info_1) = gen_rendezvous_info_with_static_key(Vec::new(), &key_1);
+
+        let cloned_key_1 = key_1.clone();
+        let jh = thread!("noise handshake peer 1", move || {
+            let (stream, _) = unwrap_result!(listener.accept());
+            let mut channel = unwrap_result!(secure_channel(stream, &cloned_key_1, &pub_info_0));
+            let msg = unwrap_result!(channel.recv());
+            unwrap_result!(channel.send(&msg));
+            channel.handshake_hash()
+        });
+        let stream = unwrap_result!(TcpStream::connect(addr));
+        let mut channel = unwrap_result!(secure_channel(stream, &key_0, &pub_info_1));
+        assert_eq!(channel.remote_static_key(), key_1.public_key());
+        unwrap_result!(channel.send(b"hello"));
+        assert_eq!(unwrap_result!(channel.recv()), b"hello".to_vec());
+        assert_eq!(unwrap_result!(jh.join()), channel.handshake_hash());
+    }
+
+    #[test]
+    fn initiator_and_responder_complete_the_handshake() {
+        let listener = unwrap_result!(TcpListener::bind("127.0.0.1:0"));
+        let addr = unwrap_result!(listener.local_addr());
+        let initiator_key = StaticKeypair::generate();
+        let responder_key = StaticKeypair::generate();
+        let expected_initiator_key = initiator_key.public_key();
+        let expected_responder_key = responder_key.public_key();
+
+        let jh = thread!("noise handshake responder", move || {
+            let (stream, _) = unwrap_result!(listener.accept());
+            let mut channel = unwrap_result!(noise_handshake(stream,
+                                                             false,
+                                                             &responder_key,
+                                                             Some(expected_initiator_key)));
+            assert_eq!(unwrap_result!(channel.recv()), b"ping".to_vec());
+            unwrap_result!(channel.send(b"pong"));
+            channel.handshake_hash()
+        });
+        let stream = unwrap_result!(TcpStream::connect(addr));
+        let mut channel = unwrap_result!(noise_handshake(stream,
+                                                         true,
+                                                         &initiator_key,
+                                                         Some(expected_responder_key)));
+        assert_eq!(channel.remote_static_key(), expected_responder_key);
+        unwrap_result!(channel.send(b"ping"));
+        assert_eq!(unwrap_result!(channel.recv()), b"pong".to_vec());
+        assert_eq!(unwrap_result!(jh.join()), channel.handshake_hash());
+    }
+
+    #[test]
+    fn signed_infos_verify_only_against_their_identity() {
+        use socket_addr::SocketAddr;
+
+        use identity::{Identity, IdentityError};
+        use mapped_socket_addr::MappedSocketAddr;
+        use rendezvous_info::{self, gen_rendezvous_info, gen_rendezvous_info_with_identity};
+
+        let identity = Identity::generate();
+        let other_identity = Identity::generate();
+        let static_key = StaticKeypair::generate();
+        let (_, info) = gen_rendezvous_info_with_identity(Vec::new(), &static_key, &identity);
+        unwrap_result!(info.verify_identity(&identity.public_key()));
+        match info.verify_identity(&other_identity.public_key()) {
+            Err(IdentityError::WrongIdentity) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // A signalling server swapping in other endpoints breaks the signature.
+        let endpoint = MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!("192.0.2.1:5000".parse())),
+            nat_restricted: true,
+            port_unknown: false,
+            unverified: false,
+        };
+        let tampered = rendezvous_info::with_endpoints(info, vec![endpoint]);
+        match tampered.verify_identity(&identity.public_key()) {
+            Err(IdentityError::BadSignature) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let (_, unsigned) = gen_rendezvous_info(Vec::new());
+        match unsigned.verify_identity(&identity.public_key()) {
+            Err(IdentityError::Unsigned) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn handshake_fails_with_the_wrong_key() {
+        let listener = unwrap_result!(TcpListener::bind("127.0.0.1:0"));
+        let addr = unwrap_result!(listener.local_addr());
+        let key_0 = StaticKeypair::generate();
+        let key_1 = StaticKeypair::generate();
+        let impostor = StaticKeypair::generate();
+        let expected_key_1 = key_1.public_key();
+
+        let _jh = thread!("noise handshake impostor", move || {
+            let (stream, _) = unwrap_result!(listener.accept());
+            let _ = noise_handshake(stream, false, &impostor, None);
+        });
+        let stream = unwrap_result!(TcpStream::connect(addr));
+        match noise_handshake(stream, true, &key_0, Some(expected_key_1)) {
+            Err(NoiseError::UnexpectedStaticKey) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(..) => panic!("Handshake with an impostor succeeded"),
+        }
+    }
+}
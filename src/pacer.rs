@@ -0,0 +1,225 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::net;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use clock::Clock;
+
+/// The rate a `Pacer` won't slow down below, however much loss it sees.
+pub const MIN_PACKETS_PER_SEC: u32 = 10;
+
+/// How long a destination counts as recently probed for `TrafficShaper`'s port spacing.
+const RECENT_DESTINATION_SECS: u64 = 60;
+/// The most recently probed destinations a `TrafficShaper` remembers.
+const MAX_RECENT_DESTINATIONS: usize = 256;
+
+/// A token bucket limiting how fast packets are sent. Some consumer routers start dropping all of
+/// a host's packets once it sends faster than some threshold, so hole punching sends through a
+/// `Pacer` and slows it down whenever packets seem to be getting lost.
+pub struct Pacer {
+    packets_per_sec: u32,
+    burst: u32,
+    // Tokens are counted in millionths of a packet so that small refills aren't rounded away.
+    micro_tokens: u64,
+    refilled_at: Instant,
+}
+
+impl Pacer {
+    /// A pacer allowing `burst` packets at once and `packets_per_sec` on average after that.
+    pub fn new(clock: &Clock, packets_per_sec: u32, burst: u32) -> Pacer {
+        let burst = cmp::max(burst, 1);
+        Pacer {
+            packets_per_sec: cmp::max(packets_per_sec, MIN_PACKETS_PER_SEC),
+            burst: burst,
+            micro_tokens: burst as u64 * 1_000_000,
+            refilled_at: clock.now(),
+        }
+    }
+
+    /// Block on `clock` until `packets` more packets may be sent.
+    pub fn take(&mut self, clock: &Clock, packets: u32) {
+        for _ in 0..packets {
+            self.refill(clock);
+            if self.micro_tokens < 1_000_000 {
+                let wait_micros = (1_000_000 - self.micro_tokens) / self.packets_per_sec as u64;
+                clock.sleep(Duration::new(wait_micros / 1_000_000,
+                                          (wait_micros % 1_000_000) as u32 * 1000 + 1000));
+                self.refill(clock);
+            }
+            self.micro_tokens = self.micro_tokens.saturating_sub(1_000_000);
+        }
+    }
+
+    /// Halve the rate, eg. because sends failed or a whole round of packets went unanswered.
+    pub fn back_off(&mut self) {
+        self.packets_per_sec = cmp::max(self.packets_per_sec / 2, MIN_PACKETS_PER_SEC);
+    }
+
+    fn refill(&mut self, clock: &Clock) {
+        let now = clock.now();
+        if now <= self.refilled_at {
+            return;
+        }
+        let elapsed = now - self.refilled_at;
+        let elapsed_micros = elapsed.as_secs() * 1_000_000 + (elapsed.subsec_nanos() / 1000) as u64;
+        let max = self.burst as u64 * 1_000_000;
+        self.micro_tokens = cmp::min(max, self.micro_tokens +
+                                          elapsed_micros * self.packets_per_sec as u64);
+        self.refilled_at = now;
+    }
+}
+
+/// Limits on the probe packets sent by every traversal attempt sharing a `MappingContext`. Many
+/// sockets punching at once, each to a spread of ports, look like a port scan to intrusion
+/// detection systems, so on networks which have them the total probe rate can be capped and the
+/// first probe to each new destination spaced out.
+pub struct TrafficShaper {
+    state: Mutex<ShaperState>,
+}
+
+struct ShaperState {
+    // `None` when the probe rate isn't capped.
+    pacer: Option<Pacer>,
+    port_spacing: Duration,
+    next_new_destination_at: Option<Instant>,
+    recent_destinations: VecDeque<(net::SocketAddr, Instant)>,
+}
+
+impl TrafficShaper {
+    /// A shaper allowing `probes_per_sec` probes a second in total, or any number if that's zero,
+    /// and `port_spacing` between the first probes to different destinations.
+    pub fn new(clock: &Clock, probes_per_sec: u32, port_spacing: Duration) -> TrafficShaper {
+        TrafficShaper {
+            state: Mutex::new(ShaperState {
+                pacer: new_probe_pacer(clock, probes_per_sec),
+                port_spacing: port_spacing,
+                next_new_destination_at: None,
+                recent_destinations: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Change the limits. Destinations probed so far still count as recently probed.
+    pub fn reconfigure(&self, clock: &Clock, probes_per_sec: u32, port_spacing: Duration) {
+        let mut state = unwrap_result!(self.state.lock());
+        state.pacer = new_probe_pacer(clock, probes_per_sec);
+        state.port_spacing = port_spacing;
+    }
+
+    /// Block on `clock` until a probe may be sent to `dest`. Callers wait their turn, so the
+    /// limits hold across threads.
+    pub fn take(&self, clock: &Clock, dest: &net::SocketAddr) {
+        let mut state = unwrap_result!(self.state.lock());
+        let state = &mut *state;
+        if state.port_spacing > Duration::new(0, 0) {
+            let now = clock.now();
+            let expiry = Duration::from_secs(RECENT_DESTINATION_SECS);
+            while state.recent_destinations.front().map_or(false, |&(_, at)| now - at >= expiry) {
+                let _ = state.recent_destinations.pop_front();
+            }
+            if state.recent_destinations.iter().all(|&(addr, _)| addr != *dest) {
+                if let Some(at) = state.next_new_destination_at {
+                    if at > now {
+                        clock.sleep(at - now);
+                    }
+                }
+                let now = clock.now();
+                state.next_new_destination_at = Some(now + state.port_spacing);
+                if state.recent_destinations.len() >= MAX_RECENT_DESTINATIONS {
+                    let _ = state.recent_destinations.pop_front();
+                }
+                state.recent_destinations.push_back((*dest, now));
+            }
+        }
+        if let Some(ref mut pacer) = state.pacer {
+            pacer.take(clock, 1);
+        }
+    }
+}
+
+fn new_probe_pacer(clock: &Clock, probes_per_sec: u32) -> Option<Pacer> {
+    match probes_per_sec {
+        0 => None,
+        probes_per_sec => Some(Pacer::new(clock, probes_per_sec, 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net;
+    use std::time::Duration;
+
+    use clock::{Clock, MockClock};
+
+    #[test]
+    fn bursts_then_paces() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut pacer = Pacer::new(&clock, 100, 4);
+
+        pacer.take(&clock, 4);
+        assert_eq!(clock.now(), start);
+
+        pacer.take(&clock, 6);
+        let elapsed = clock.now() - start;
+        assert!(elapsed >= Duration::from_millis(60));
+        assert!(elapsed < Duration::from_millis(70));
+    }
+
+    #[test]
+    fn backs_off_to_a_floor() {
+        let clock = MockClock::new();
+        let mut pacer = Pacer::new(&clock, 100, 4);
+        pacer.back_off();
+        assert_eq!(pacer.packets_per_sec, 50);
+        for _ in 0..10 {
+            pacer.back_off();
+        }
+        assert_eq!(pacer.packets_per_sec, MIN_PACKETS_PER_SEC);
+    }
+
+    #[test]
+    fn shaper_spaces_out_new_destinations() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let shaper = TrafficShaper::new(&clock, 0, Duration::from_millis(100));
+        let addr = |s: &str| -> net::SocketAddr { unwrap_result!(s.parse()) };
+
+        shaper.take(&clock, &addr("192.0.2.1:1000"));
+        shaper.take(&clock, &addr("192.0.2.1:1000"));
+        assert_eq!(clock.now(), start);
+        shaper.take(&clock, &addr("192.0.2.1:1001"));
+        shaper.take(&clock, &addr("192.0.2.1:1002"));
+        assert_eq!(clock.now() - start, Duration::from_millis(200));
+
+        shaper.reconfigure(&clock, 10, Duration::new(0, 0));
+        let start = clock.now();
+        for _ in 0..3 {
+            shaper.take(&clock, &addr("192.0.2.1:1003"));
+        }
+        assert!(clock.now() - start >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,590 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::cmp;
+use std::io;
+#[cfg(feature = "tcp")]
+use std::io::{Read, Write};
+use std::net::{IpAddr, UdpSocket};
+#[cfg(feature = "tcp")]
+use std::net::TcpStream;
+use std::time::{Instant, Duration};
+
+use maidsafe_utilities::serialisation::SerialisationError;
+use socket_addr::SocketAddr;
+use sodiumoxide::randombytes;
+
+use error_code::{ErrorCategory, ErrorCode};
+use listener_message;
+use socket_utils::RecvUntil;
+
+/// The status reported by a hole punch server in response to a ping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct ServerStatus {
+    /// How long the server has been running.
+    pub uptime: Duration,
+    /// The version of the simple hole punch server protocol that the server speaks.
+    pub protocol_version: u32,
+    /// How long it took the server to respond.
+    pub round_trip_time: Duration,
+}
+
+/// The latency to a hole punch server measured by `probe_latency`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct LatencyStats {
+    /// How many probes were sent.
+    pub sent: u32,
+    /// How many probes were reflected back before the deadline.
+    pub received: u32,
+    /// The shortest round trip time.
+    pub min_round_trip_time: Duration,
+    /// The mean round trip time.
+    pub mean_round_trip_time: Duration,
+    /// The mean variation in the one-way delay to the server between consecutive probes. This
+    /// doesn't depend on the two clocks agreeing.
+    pub jitter: Duration,
+}
+
+/// Keepalives granted by a hole punch server in answer to `request_keepalive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct ServerKeepalive {
+    /// The address the server saw the request come from, which the keepalives are sent to.
+    pub external_addr: SocketAddr,
+    /// How often the server sends a keepalive.
+    pub interval: Duration,
+    /// How long the server keeps sending them. Call `request_keepalive` again before this runs
+    /// out to keep them coming.
+    pub lease: Duration,
+}
+
+quick_error! {
+    /// Errors returned by `ping_server`, `ping_tcp_server`, `probe_latency` and
+    /// `request_keepalive`.
+    #[derive(Debug)]
+    pub enum PingServerError {
+        /// Error creating a socket to ping the server from.
+        CreateSocket { err: io::Error } {
+            description("Error creating a socket to ping the server from.")
+            display("Error creating a socket to ping the server from: {}", err)
+            cause(err)
+        }
+        /// Error connecting to the server.
+        Connect { err: io::Error } {
+            description("Error connecting to the server.")
+            display("Error connecting to the server: {}", err)
+            cause(err)
+        }
+        /// IO error sending the ping.
+        Send { err: io::Error } {
+            description("IO error sending the ping.")
+            display("IO error sending the ping: {}", err)
+            cause(err)
+        }
+        /// IO error receiving the response.
+        Recv { err: io::Error } {
+            description("IO error receiving the response.")
+            display("IO error receiving the response: {}", err)
+            cause(err)
+        }
+        /// The server did not respond before the deadline.
+        TimedOut {
+            description("The server did not respond before the deadline.")
+        }
+        /// The server's response could not be deserialised.
+        Deserialise { err: SerialisationError } {
+            description("The server's response could not be deserialised.")
+            display("The server's response could not be deserialised: {}", err)
+            cause(err)
+        }
+        /// The server is overloaded and asked us to try again later.
+        Busy {
+            description("The server is overloaded and asked us to try again later.")
+        }
+    }
+}
+
+impl From<PingServerError> for io::Error {
+    fn from(e: PingServerError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            PingServerError::CreateSocket { err } => err.kind(),
+            PingServerError::Connect { err } => err.kind(),
+            PingServerError::Send { err } => err.kind(),
+            PingServerError::Recv { err } => err.kind(),
+            PingServerError::TimedOut => io::ErrorKind::TimedOut,
+            PingServerError::Deserialise { .. } => io::ErrorKind::InvalidData,
+            PingServerError::Busy => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for PingServerError {
+    fn code(&self) -> u32 {
+        match *self {
+            PingServerError::CreateSocket { .. } => 1601,
+            PingServerError::Connect { .. } => 1602,
+            PingServerError::Send { .. } => 1603,
+            PingServerError::Recv { .. } => 1604,
+            PingServerError::TimedOut => 1605,
+            PingServerError::Deserialise { .. } => 1606,
+            PingServerError::Busy => 1607,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            PingServerError::CreateSocket { .. } => ErrorCategory::Network,
+            PingServerError::Connect { .. } => ErrorCategory::Network,
+            PingServerError::Send { .. } => ErrorCategory::Network,
+            PingServerError::Recv { .. } => ErrorCategory::Network,
+            PingServerError::TimedOut => ErrorCategory::Network,
+            PingServerError::Deserialise { .. } => ErrorCategory::Protocol,
+            PingServerError::Busy => ErrorCategory::Network,
+        }
+    }
+}
+
+fn status_from_pong(data: &[u8], sent_at: Instant) -> Result<ServerStatus, PingServerError> {
+    if data == &listener_message::TRY_LATER_MAGIC_CONSTANT[..] {
+        return Err(PingServerError::Busy);
+    }
+    match listener_message::Pong::decode(data) {
+        Ok(pong) => {
+            Ok(ServerStatus {
+                uptime: Duration::from_secs(pong.uptime_secs),
+                protocol_version: pong.protocol_version,
+                round_trip_time: sent_at.elapsed(),
+            })
+        },
+        Err(e) => Err(PingServerError::Deserialise { err: e }),
+    }
+}
+
+/// Check that a `SimpleUdpHolePunchServer` is alive at `addr`. Pings are resent every half a
+/// second until the server responds or `deadline` passes.
+pub fn ping_server(addr: &SocketAddr, deadline: Instant) -> Result<ServerStatus, PingServerError> {
+    let bind_addr = match addr.ip() {
+        IpAddr::V4(..) => "0.0.0.0:0",
+        IpAddr::V6(..) => "[::]:0",
+    };
+    let socket = match UdpSocket::bind(bind_addr) {
+        Ok(socket) => socket,
+        Err(e) => return Err(PingServerError::CreateSocket { err: e }),
+    };
+
+    const MAX_DATAGRAM_SIZE: usize = 256;
+    let mut recv_data = [0u8; MAX_DATAGRAM_SIZE];
+    let mut recv_deadline = Instant::now();
+    while recv_deadline < deadline {
+        recv_deadline = recv_deadline + Duration::from_millis(500);
+        if recv_deadline > deadline {
+            recv_deadline = deadline;
+        }
+        let mut sent_at = Instant::now();
+        // Older servers only understand the bare ping and newer ones may only answer a padded
+        // ping which echoes a cookie, so send both.
+        let mut padded = listener_message::PaddedRequest {
+            magic: listener_message::PING_MAGIC_CONSTANT,
+            cookie: None,
+            nonce: [0u8; listener_message::NONCE_LEN],
+        };
+        let res = socket.send_to(&listener_message::PING_MAGIC_CONSTANT[..], &**addr)
+                        .and_then(|_| socket.send_to(&padded.encode()[..], &**addr));
+        if let Err(e) = res {
+            return Err(PingServerError::Send { err: e });
+        }
+        loop {
+            let (read_size, recv_addr) = match socket.recv_until(&mut recv_data[..], recv_deadline) {
+                Ok(Some(res)) => res,
+                Ok(None) => break,
+                Err(e) => return Err(PingServerError::Recv { err: e }),
+            };
+            if recv_addr != *addr {
+                continue;
+            }
+            if let Some((cookie, _)) = listener_message::decode_cookie(&recv_data[..read_size]) {
+                padded.cookie = Some(cookie);
+                sent_at = Instant::now();
+                if let Err(e) = socket.send_to(&padded.encode()[..], &**addr) {
+                    return Err(PingServerError::Send { err: e });
+                }
+                continue;
+            }
+            return status_from_pong(&recv_data[..read_size], sent_at);
+        }
+    }
+    Err(PingServerError::TimedOut)
+}
+
+/// Measure the latency to a `SimpleUdpHolePunchServer` at `addr` by sending it `probes` latency
+/// probes, `interval` apart, and timing their reflections. Gives up waiting at `deadline` and
+/// returns `TimedOut` if none came back. The server wakes as soon as a probe arrives and sends it
+/// straight back, so the round trip time is the network's. See also
+/// `MappingContext::rank_simple_udp_servers`.
+pub fn probe_latency(addr: &SocketAddr, probes: u32, interval: Duration, deadline: Instant)
+                     -> Result<LatencyStats, PingServerError> {
+    let bind_addr = match addr.ip() {
+        IpAddr::V4(..) => "0.0.0.0:0",
+        IpAddr::V6(..) => "[::]:0",
+    };
+    let socket = match UdpSocket::bind(bind_addr) {
+        Ok(socket) => socket,
+        Err(e) => return Err(PingServerError::CreateSocket { err: e }),
+    };
+
+    // Client timestamps are measured from here. Indexed by sequence number, the time each probe
+    // was sent and, once reflected, when it came back and the server's timestamp.
+    let start = Instant::now();
+    let mut samples: Vec<(u64, Option<(u64, u64)>)> = Vec::new();
+    let mut next_send = start;
+    let mut recv_data = [0u8; listener_message::LATENCY_PROBE_LEN + 1];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        let sent = samples.len() as u32;
+        if sent < probes && now >= next_send {
+            let probe = listener_message::LatencyProbe {
+                sequence: sent,
+                client_timestamp_micros: micros(now - start),
+                server_timestamp_micros: 0,
+            };
+            if let Err(e) = socket.send_to(&probe.encode()[..], &**addr) {
+                return Err(PingServerError::Send { err: e });
+            }
+            samples.push((probe.client_timestamp_micros, None));
+            next_send = next_send + interval;
+            continue;
+        }
+        if sent == probes && samples.iter().all(|&(_, reply)| reply.is_some()) {
+            break;
+        }
+        let recv_deadline = if sent < probes { cmp::min(next_send, deadline) } else { deadline };
+        let (read_size, recv_addr) = match socket.recv_until(&mut recv_data[..], recv_deadline) {
+            Ok(Some(res)) => res,
+            Ok(None) => continue,
+            Err(e) => return Err(PingServerError::Recv { err: e }),
+        };
+        let received_at = micros(Instant::now() - start);
+        if recv_addr != *addr {
+            continue;
+        }
+        let reply = match listener_message::LatencyProbe::decode(&recv_data[..read_size]) {
+            Some(reply) => reply,
+            None if &recv_data[..read_size] == &listener_message::TRY_LATER_MAGIC_CONSTANT[..] => {
+                return Err(PingServerError::Busy);
+            },
+            None => continue,
+        };
+        // Only trust replies which echo the timestamp we sent, not spoofed or mangled ones.
+        if let Some(sample) = samples.get_mut(reply.sequence as usize) {
+            if sample.0 == reply.client_timestamp_micros && sample.1.is_none() {
+                sample.1 = Some((received_at, reply.server_timestamp_micros));
+            }
+        }
+    }
+
+    latency_stats(samples.len() as u32, &samples)
+}
+
+/// Ask the `SimpleUdpHolePunchServer` at `addr` to keep sending small packets to the address it
+/// sees `socket` at, so that the NAT mapping stays open while no other traffic flows. Peers which
+/// publish long-lived rendezvous info for `socket` can use this to stop their endpoints going
+/// stale. Requests are resent every half a second until the server grants them or `deadline`
+/// passes. Whatever reads from `socket` afterwards should drop the keepalives, which
+/// `is_server_keepalive` recognises.
+pub fn request_keepalive(socket: &UdpSocket, addr: &SocketAddr, deadline: Instant)
+                         -> Result<ServerKeepalive, PingServerError> {
+    let mut nonce = [0u8; listener_message::NONCE_LEN];
+    randombytes::randombytes_into(&mut nonce);
+    let mut request = listener_message::PaddedRequest {
+        magic: listener_message::KEEPALIVE_MAGIC_CONSTANT,
+        cookie: None,
+        nonce: nonce,
+    };
+
+    let mut recv_data = [0u8; 256];
+    let mut recv_deadline = Instant::now();
+    while recv_deadline < deadline {
+        recv_deadline = cmp::min(recv_deadline + Duration::from_millis(500), deadline);
+        if let Err(e) = socket.send_to(&request.encode()[..], &**addr) {
+            return Err(PingServerError::Send { err: e });
+        }
+        loop {
+            let res = socket.recv_until(&mut recv_data[..], recv_deadline);
+            let (read_size, recv_addr) = match res {
+                Ok(Some(res)) => res,
+                Ok(None) => break,
+                Err(e) => return Err(PingServerError::Recv { err: e }),
+            };
+            if recv_addr != *addr {
+                continue;
+            }
+            let response = &recv_data[..read_size];
+            if response == &listener_message::TRY_LATER_MAGIC_CONSTANT[..] {
+                return Err(PingServerError::Busy);
+            }
+            if let Some((cookie, echoed_nonce)) = listener_message::decode_cookie(response) {
+                if echoed_nonce == nonce && request.cookie.is_none() {
+                    request.cookie = Some(cookie);
+                    if let Err(e) = socket.send_to(&request.encode()[..], &**addr) {
+                        return Err(PingServerError::Send { err: e });
+                    }
+                }
+                continue;
+            }
+            if let Some(granted) = listener_message::KeepaliveGranted::decode(response) {
+                if granted.nonce == nonce {
+                    return Ok(ServerKeepalive {
+                        external_addr: SocketAddr(granted.external_addr),
+                        interval: Duration::from_secs(granted.interval_secs as u64),
+                        lease: Duration::from_secs(granted.lease_secs as u64),
+                    });
+                }
+            }
+        }
+    }
+    Err(PingServerError::TimedOut)
+}
+
+/// Whether `data`, received from a hole punch server, is one of the keepalives requested with
+/// `request_keepalive`.
+pub fn is_server_keepalive(data: &[u8]) -> bool {
+    data == &listener_message::KEEPALIVE_MAGIC_CONSTANT[..]
+}
+
+fn latency_stats(sent: u32, samples: &[(u64, Option<(u64, u64)>)])
+                 -> Result<LatencyStats, PingServerError> {
+    let mut replies = Vec::new();
+    for &(sent_at, reply) in samples {
+        if let Some((received_at, server_at)) = reply {
+            replies.push((sent_at, received_at, server_at));
+        }
+    }
+    if replies.is_empty() {
+        return Err(PingServerError::TimedOut);
+    }
+    let rtts: Vec<u64> = replies.iter().map(|&(sent_at, received_at, _)| received_at - sent_at)
+                                .collect();
+    let min_rtt = rtts.iter().cloned().min().unwrap_or(0);
+    let mean_rtt = rtts.iter().fold(0, |sum, rtt| sum + rtt) / rtts.len() as u64;
+    // The difference between the server's and our timestamp is the one-way delay plus the unknown
+    // offset between the clocks, which cancels out when comparing consecutive probes.
+    let offsets: Vec<i64> = replies.iter()
+                                   .map(|&(sent, _, server)| server as i64 - sent as i64)
+                                   .collect();
+    let jitter = if offsets.len() < 2 {
+        0
+    } else {
+        let total = offsets.windows(2).fold(0, |sum, w| sum + (w[1] - w[0]).abs() as u64);
+        total / (offsets.len() - 1) as u64
+    };
+    Ok(LatencyStats {
+        sent: sent,
+        received: replies.len() as u32,
+        min_round_trip_time: from_micros(min_rtt),
+        mean_round_trip_time: from_micros(mean_rtt),
+        jitter: from_micros(jitter),
+    })
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + (duration.subsec_nanos() / 1_000) as u64
+}
+
+fn from_micros(micros: u64) -> Duration {
+    Duration::new(micros / 1_000_000, ((micros % 1_000_000) * 1_000) as u32)
+}
+
+/// Check that a `SimpleTcpHolePunchServer` is alive at `addr`.
+#[cfg(feature = "tcp")]
+pub fn ping_tcp_server(addr: &SocketAddr, deadline: Instant) -> Result<ServerStatus, PingServerError> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(PingServerError::TimedOut);
+    }
+    let sent_at = now;
+    let mut stream = match TcpStream::connect(&**addr) {
+        Ok(stream) => stream,
+        Err(e) => return Err(PingServerError::Connect { err: e }),
+    };
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(PingServerError::TimedOut);
+    }
+    match stream.set_read_timeout(Some(deadline - now)) {
+        Ok(()) => (),
+        Err(e) => return Err(PingServerError::Recv { err: e }),
+    };
+    match stream.write_all(&listener_message::PING_MAGIC_CONSTANT[..]) {
+        Ok(()) => (),
+        Err(e) => return Err(PingServerError::Send { err: e }),
+    };
+    const MAX_RESPONSE_SIZE: usize = 256;
+    let mut recv_data = [0u8; MAX_RESPONSE_SIZE];
+    let n = match stream.read(&mut recv_data[..]) {
+        Ok(n) => n,
+        Err(e) => {
+            return match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Err(PingServerError::TimedOut),
+                _ => Err(PingServerError::Recv { err: e }),
+            };
+        },
+    };
+    status_from_pong(&recv_data[..n], sent_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv6Addr, UdpSocket};
+    use std::time::{Instant, Duration};
+
+    use socket_addr::SocketAddr;
+
+    use mapping_context::MappingContext;
+    use simple_udp_hole_punch_server::{SimpleUdpHolePunchServer, SimpleUdpServerLimits};
+    use socket_utils;
+
+    #[test]
+    fn ping_udp_server_over_loopback() {
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new(Box::new(mapping_context),
+                                                                  deadline).result_discard());
+        let addr = unwrap_result!(server.addresses().into_iter().find(|addr| {
+            socket_utils::is_loopback(&addr.ip())
+        }).ok_or("No loopback address"));
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let status = unwrap_result!(ping_server(&addr, deadline));
+        assert_eq!(status.protocol_version, ::listener_message::PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn ping_udp_server_over_ipv6_loopback() {
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new(Box::new(mapping_context),
+                                                                  deadline).result_discard());
+        let ipv6_loopback = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1));
+        let addr = unwrap_result!(server.addresses().into_iter().find(|addr| {
+            addr.ip() == ipv6_loopback
+        }).ok_or("No ipv6 loopback address"));
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let status = unwrap_result!(ping_server(&addr, deadline));
+        assert_eq!(status.protocol_version, ::listener_message::PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn probe_latency_of_cookie_requiring_udp_server_over_loopback() {
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let limits = SimpleUdpServerLimits {
+            require_cookies: true,
+            ..SimpleUdpServerLimits::default()
+        };
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new_with_limits(
+                Box::new(mapping_context), deadline, limits).result_discard());
+        let addr = unwrap_result!(server.addresses().into_iter().find(|addr| {
+            socket_utils::is_loopback(&addr.ip())
+        }).ok_or("No loopback address"));
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let stats = unwrap_result!(probe_latency(&addr, 4, Duration::from_millis(20), deadline));
+        assert_eq!(stats.sent, 4);
+        assert!(stats.received > 0);
+        assert!(stats.min_round_trip_time <= stats.mean_round_trip_time);
+    }
+
+    #[test]
+    fn servers_which_answer_probes_are_ranked_first() {
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new(Box::new(mapping_context),
+                                                                  deadline).result_discard());
+        let addr = unwrap_result!(server.addresses().into_iter().find(|addr| {
+            socket_utils::is_loopback(&addr.ip())
+        }).ok_or("No loopback address"));
+        // Bound but never read from, so its probes go unanswered.
+        let silent = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let silent_addr = SocketAddr(unwrap_result!(silent.local_addr()));
+
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        mc.add_simple_udp_servers(vec![silent_addr, addr]);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let results = mc.rank_simple_udp_servers(2, Duration::from_millis(20), deadline);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].1.is_err());
+        assert!(results[1].1.is_ok());
+        assert_eq!(::mapping_context::simple_udp_servers(&mc), vec![addr, silent_addr]);
+    }
+
+    #[test]
+    fn ping_cookie_requiring_udp_server_over_loopback() {
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let limits = SimpleUdpServerLimits {
+            require_cookies: true,
+            ..SimpleUdpServerLimits::default()
+        };
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new_with_limits(
+                Box::new(mapping_context), deadline, limits).result_discard());
+        let addr = unwrap_result!(server.addresses().into_iter().find(|addr| {
+            socket_utils::is_loopback(&addr.ip())
+        }).ok_or("No loopback address"));
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let status = unwrap_result!(ping_server(&addr, deadline));
+        assert_eq!(status.protocol_version, ::listener_message::PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn udp_server_sends_requested_keepalives() {
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let limits = SimpleUdpServerLimits {
+            require_cookies: true,
+            keepalive_interval: Duration::from_millis(100),
+            ..SimpleUdpServerLimits::default()
+        };
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new_with_limits(
+                Box::new(mapping_context), deadline, limits).result_discard());
+        let addr = unwrap_result!(server.addresses().into_iter().find(|addr| {
+            socket_utils::is_loopback(&addr.ip())
+        }).ok_or("No loopback address"));
+
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let keepalive = unwrap_result!(request_keepalive(&socket, &addr, deadline));
+        assert_eq!(*keepalive.external_addr, unwrap_result!(socket.local_addr()));
+        assert_eq!(keepalive.lease, limits.keepalive_lease);
+
+        unwrap_result!(socket.set_read_timeout(Some(Duration::from_secs(3))));
+        let mut buf = [0u8; 256];
+        let (len, from) = unwrap_result!(socket.recv_from(&mut buf));
+        assert_eq!(SocketAddr(from), addr);
+        assert!(is_server_keepalive(&buf[..len]));
+    }
+}
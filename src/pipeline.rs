@@ -0,0 +1,258 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::net::{self, UdpSocket};
+#[cfg(feature = "tcp")]
+use std::net::TcpStream;
+use std::time::Instant;
+
+use gateway as igd;
+#[cfg(feature = "tcp")]
+use net2;
+use w_result::WResult;
+
+#[cfg(feature = "tcp")]
+use mapped_tcp_socket::{self, MappedTcpSocket, MappedTcpSocketMapError, MappedTcpSocketMapWarning,
+                        TcpPunchHoleError, TcpPunchHoleWarning};
+use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketMapError, MappedUdpSocketMapWarning};
+use mapping_context::{self, MappingContext};
+use punched_udp_socket::{PunchedUdpSocket, UdpPunchHoleError, UdpPunchHoleWarning};
+use rendezvous_info::{PrivRendezvousInfo, PubRendezvousInfo};
+use socket_utils;
+
+/// Finds the external endpoints of sockets.
+///
+/// Implemented by `MappingContext`. Code which takes a `&ExternalAddrDiscovery` rather than
+/// calling `MappedUdpSocket::map` directly can be tested with a fake that doesn't touch the
+/// network.
+pub trait ExternalAddrDiscovery {
+    /// Map a udp socket. See `MappedUdpSocket::map`.
+    fn map_udp(&self, socket: UdpSocket, deadline: Instant)
+               -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketMapError>;
+
+    /// Map a tcp socket. See `MappedTcpSocket::map`.
+    #[cfg(feature = "tcp")]
+    fn map_tcp(&self, socket: net2::TcpBuilder, deadline: Instant)
+               -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketMapError>;
+}
+
+impl ExternalAddrDiscovery for MappingContext {
+    fn map_udp(&self, socket: UdpSocket, deadline: Instant)
+               -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketMapError>
+    {
+        MappedUdpSocket::map(socket, self, deadline)
+    }
+
+    #[cfg(feature = "tcp")]
+    fn map_tcp(&self, socket: net2::TcpBuilder, deadline: Instant)
+               -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketMapError>
+    {
+        MappedTcpSocket::map(socket, self, deadline)
+    }
+}
+
+/// Asks a gateway to forward an external port to a local address.
+///
+/// Implemented by `MappingContext` using the IGD gateways found when the context was created.
+pub trait PortMapper {
+    /// Ask for an external port to be forwarded to `local_addr`. Returns the external address on
+    /// success, or `None` if there is no gateway to ask. If `local_addr` is unspecified the
+    /// gateway of the first interface which has one is used.
+    fn map_port(&self, protocol: igd::PortMappingProtocol, local_addr: net::SocketAddrV4)
+                -> Option<Result<net::SocketAddrV4, igd::AddAnyPortError>>;
+}
+
+impl PortMapper for MappingContext {
+    fn map_port(&self, protocol: igd::PortMappingProtocol, local_addr: net::SocketAddrV4)
+                -> Option<Result<net::SocketAddrV4, igd::AddAnyPortError>>
+    {
+        let unspecified = socket_utils::ipv4_is_unspecified(local_addr.ip());
+        for iface_v4 in mapping_context::interfaces_v4(self) {
+            if !unspecified && iface_v4.addr != *local_addr.ip() {
+                continue;
+            }
+            if let Some(gateway) = iface_v4.gateway {
+                let local_iface_addr = net::SocketAddrV4::new(iface_v4.addr, local_addr.port());
+                return Some(gateway.get_any_address(protocol, local_iface_addr, 0,
+                                                    "rust nat_traversal"));
+            }
+        }
+        None
+    }
+}
+
+/// Punches a hole to a peer given our private and their public rendezvous info.
+///
+/// Implemented by `UdpHolePuncher` and `TcpHolePuncher`.
+pub trait HolePuncher {
+    /// The kind of socket that holes are punched with.
+    type Socket;
+    /// What a successful hole punch produces.
+    type Punched;
+    /// Warnings raised while punching.
+    type Warning;
+    /// Errors raised while punching.
+    type Error;
+
+    /// Punch a hole using `socket`.
+    fn punch_hole(&self,
+                  socket: Self::Socket,
+                  our_priv_rendezvous_info: PrivRendezvousInfo,
+                  their_pub_rendezvous_info: PubRendezvousInfo,
+                  deadline: Instant)
+                  -> WResult<Self::Punched, Self::Warning, Self::Error>;
+}
+
+/// Punches udp holes with `PunchedUdpSocket::punch_hole`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UdpHolePuncher;
+
+impl HolePuncher for UdpHolePuncher {
+    type Socket = UdpSocket;
+    type Punched = PunchedUdpSocket;
+    type Warning = UdpPunchHoleWarning;
+    type Error = UdpPunchHoleError;
+
+    fn punch_hole(&self,
+                  socket: UdpSocket,
+                  our_priv_rendezvous_info: PrivRendezvousInfo,
+                  their_pub_rendezvous_info: PubRendezvousInfo,
+                  deadline: Instant)
+                  -> WResult<PunchedUdpSocket, UdpPunchHoleWarning, UdpPunchHoleError> {
+        PunchedUdpSocket::punch_hole(socket,
+                                     our_priv_rendezvous_info,
+                                     their_pub_rendezvous_info,
+                                     deadline)
+    }
+}
+
+/// Punches tcp holes with `tcp_punch_hole`.
+#[cfg(feature = "tcp")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpHolePuncher;
+
+#[cfg(feature = "tcp")]
+impl HolePuncher for TcpHolePuncher {
+    type Socket = net2::TcpBuilder;
+    type Punched = TcpStream;
+    type Warning = TcpPunchHoleWarning;
+    type Error = TcpPunchHoleError;
+
+    fn punch_hole(&self,
+                  socket: net2::TcpBuilder,
+                  our_priv_rendezvous_info: PrivRendezvousInfo,
+                  their_pub_rendezvous_info: PubRendezvousInfo,
+                  deadline: Instant)
+                  -> WResult<TcpStream, TcpPunchHoleWarning, TcpPunchHoleError> {
+        mapped_tcp_socket::tcp_punch_hole(socket,
+                                          our_priv_rendezvous_info,
+                                          their_pub_rendezvous_info,
+                                          deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{self, UdpSocket};
+    use std::time::{Instant, Duration};
+
+    #[cfg(feature = "tcp")]
+    use net2;
+    use socket_addr::SocketAddr;
+    use w_result::{WResult, WOk};
+
+    use mapped_socket_addr::MappedSocketAddr;
+    #[cfg(feature = "tcp")]
+    use mapped_tcp_socket::{MappedTcpSocket, MappedTcpSocketMapError, MappedTcpSocketMapWarning};
+    use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketMapError, MappedUdpSocketMapWarning};
+    use rendezvous_info::{self, PrivRendezvousInfo, PubRendezvousInfo};
+
+    struct FakeDiscovery {
+        external_addr: net::SocketAddr,
+    }
+
+    impl ExternalAddrDiscovery for FakeDiscovery {
+        fn map_udp(&self, socket: UdpSocket, _deadline: Instant)
+                   -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketMapError>
+        {
+            WOk(MappedUdpSocket {
+                socket: socket,
+                endpoints: vec![MappedSocketAddr {
+                    addr: SocketAddr(self.external_addr),
+                    nat_restricted: true,
+                    port_unknown: false,
+                    unverified: false,
+                }],
+            }, Vec::new())
+        }
+
+        #[cfg(feature = "tcp")]
+        fn map_tcp(&self, socket: net2::TcpBuilder, _deadline: Instant)
+                   -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketMapError>
+        {
+            WOk(MappedTcpSocket {
+                socket: socket,
+                endpoints: Vec::new(),
+            }, Vec::new())
+        }
+    }
+
+    struct FakePuncher;
+
+    impl HolePuncher for FakePuncher {
+        type Socket = UdpSocket;
+        type Punched = net::SocketAddr;
+        type Warning = ();
+        type Error = ();
+
+        fn punch_hole(&self,
+                      _socket: UdpSocket,
+                      _our_priv_rendezvous_info: PrivRendezvousInfo,
+                      their_pub_rendezvous_info: PubRendezvousInfo,
+                      _deadline: Instant)
+                      -> WResult<net::SocketAddr, (), ()> {
+            let (endpoints, _) = rendezvous_info::decompose(their_pub_rendezvous_info);
+            WOk(*endpoints[0].addr, Vec::new())
+        }
+    }
+
+    // The sort of function a downstream application would want to test.
+    fn connect<D, P>(discovery: &D, puncher: &P, socket: UdpSocket) -> net::SocketAddr
+        where D: ExternalAddrDiscovery,
+              P: HolePuncher<Socket = UdpSocket, Punched = net::SocketAddr>
+    {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mapped = unwrap_result!(discovery.map_udp(socket, deadline).result_discard().ok()
+                                                     .ok_or("map_udp failed"));
+        let (priv_info, pub_info) = rendezvous_info::gen_rendezvous_info(mapped.endpoints);
+        unwrap_result!(puncher.punch_hole(mapped.socket, priv_info, pub_info, deadline)
+                              .result_discard().ok().ok_or("punch_hole failed"))
+    }
+
+    #[test]
+    fn fakes_can_stand_in_for_the_pipeline() {
+        let external_addr = unwrap_result!("203.0.113.7:45000".parse());
+        let discovery = FakeDiscovery { external_addr: external_addr };
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        assert_eq!(connect(&discovery, &FakePuncher, socket), external_addr);
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+// Learning which local address a datagram was sent to. A socket bound to the unspecified address
+// receives on all of the host's addresses, and the address a server's response arrives on is the
+// one that the route to that server leaves through. On Linux and Android this uses IP_PKTINFO and
+// IPV6_RECVPKTINFO. Elsewhere the local address is never known.
+
+use std::io;
+use std::net::{IpAddr, UdpSocket};
+use std::time::Instant;
+
+use socket_addr::SocketAddr;
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+use socket_utils::RecvUntil;
+
+/// Ask for the local address of every datagram received on `socket` from now on. Datagrams queued
+/// before this is called may arrive without one.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn enable(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+/// Like `RecvUntil::recv_until` but also returns the local address that the datagram was sent to,
+/// if it's known.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn recv_until(socket: &UdpSocket, buf: &mut [u8], deadline: Instant)
+                  -> io::Result<Option<(usize, SocketAddr, Option<IpAddr>)>> {
+    let res = try!(RecvUntil::recv_until(socket, buf, deadline));
+    Ok(res.map(|(len, addr)| (len, addr, None)))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::linux::{enable, recv_until};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[allow(unsafe_code)]
+mod linux {
+    use std::io;
+    use std::mem;
+    use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::slice;
+    use std::time::Instant;
+
+    use byteorder::{ByteOrder, NativeEndian};
+    use libc;
+    use socket_addr::SocketAddr;
+
+    use socket_utils;
+
+    const IP_PKTINFO: libc::c_int = 8;
+    const IPV6_RECVPKTINFO: libc::c_int = 49;
+    const IPV6_PKTINFO: libc::c_int = 50;
+
+    /// Like `RecvUntil::recv_until` but also returns the local address that the datagram was
+    /// sent to, if it's known.
+    pub fn recv_until(socket: &UdpSocket, buf: &mut [u8], deadline: Instant)
+                      -> io::Result<Option<(usize, SocketAddr, Option<IpAddr>)>> {
+        try!(enable(socket));
+        let res = try!(socket_utils::recv_loop(socket, deadline, |socket| recv(socket, buf)));
+        Ok(res.map(|(len, addr, local_ip)| (len, SocketAddr(addr), local_ip)))
+    }
+
+    /// Ask for the local address of every datagram received on `socket` from now on. Datagrams
+    /// queued before this is called may arrive without one. An ipv6 socket may receive ipv4
+    /// datagrams too, so it asks for both.
+    pub fn enable(socket: &UdpSocket) -> io::Result<()> {
+        let ipv6 = match try!(socket.local_addr()) {
+            net::SocketAddr::V4(..) => false,
+            net::SocketAddr::V6(..) => true,
+        };
+        if ipv6 {
+            try!(set_option(socket, libc::IPPROTO_IPV6, IPV6_RECVPKTINFO));
+            let _ = set_option(socket, libc::IPPROTO_IP, IP_PKTINFO);
+            Ok(())
+        } else {
+            set_option(socket, libc::IPPROTO_IP, IP_PKTINFO)
+        }
+    }
+
+    fn set_option(socket: &UdpSocket, level: libc::c_int, option: libc::c_int) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        let on_ptr: *const libc::c_int = &on;
+        let ret = unsafe {
+            libc::setsockopt(socket.as_raw_fd(),
+                             level,
+                             option,
+                             on_ptr as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn recv(socket: &UdpSocket, buf: &mut [u8])
+            -> io::Result<(usize, net::SocketAddr, Option<IpAddr>)> {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        // u64s so that the control messages are aligned.
+        let mut control = [0u64; 16];
+        let mut iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len() as libc::size_t,
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut storage as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iovec;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let ret = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let addr = try!(from_sockaddr(&storage));
+        let control = unsafe {
+            slice::from_raw_parts(control.as_ptr() as *const u8, msg.msg_controllen as usize)
+        };
+        Ok((ret as usize, addr, local_ip(control)))
+    }
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<net::SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                Ok(net::SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+            },
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                Ok(net::SocketAddr::V6(SocketAddrV6::new(ip,
+                                                         u16::from_be(sin6.sin6_port),
+                                                         sin6.sin6_flowinfo,
+                                                         sin6.sin6_scope_id)))
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown address family")),
+        }
+    }
+
+    fn cmsg_align(len: usize) -> usize {
+        let word = mem::size_of::<usize>();
+        (len + word - 1) & !(word - 1)
+    }
+
+    // Find the local address in the control messages of a received datagram. Each starts with a
+    // `cmsghdr` of a `size_t` length followed by an `int` level and type. `in_pktinfo` holds the
+    // interface index, the local address and the header's destination address, which differs for
+    // broadcasts. `in6_pktinfo` holds the address and then the interface index.
+    pub fn local_ip(control: &[u8]) -> Option<IpAddr> {
+        let word = mem::size_of::<usize>();
+        let header_len = cmsg_align(word + 8);
+        let mut offset = 0;
+        while offset + header_len <= control.len() {
+            let len = match word {
+                8 => NativeEndian::read_u64(&control[offset..]) as usize,
+                _ => NativeEndian::read_u32(&control[offset..]) as usize,
+            };
+            if len < header_len || offset + len > control.len() {
+                break;
+            }
+            let level = NativeEndian::read_i32(&control[offset + word..]);
+            let cmsg_type = NativeEndian::read_i32(&control[offset + word + 4..]);
+            let data = &control[offset + header_len..offset + len];
+            if level == libc::IPPROTO_IP && cmsg_type == IP_PKTINFO && data.len() >= 12 {
+                return Some(IpAddr::V4(Ipv4Addr::new(data[4], data[5], data[6], data[7])));
+            }
+            if level == libc::IPPROTO_IPV6 && cmsg_type == IPV6_PKTINFO && data.len() >= 16 {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&data[..16]);
+                return Some(unmap(Ipv6Addr::from(octets)));
+            }
+            offset += cmsg_align(len);
+        }
+        None
+    }
+
+    // An ipv6 socket reports ipv4 datagrams as sent to an ipv4-mapped address.
+    fn unmap(ip: Ipv6Addr) -> IpAddr {
+        let segments = ip.segments();
+        if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+            if let Some(ipv4) = ip.to_ipv4() {
+                return IpAddr::V4(ipv4);
+            }
+        }
+        IpAddr::V6(ip)
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+    use super::linux::local_ip;
+
+    use std::mem;
+    use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+    use std::time::{Duration, Instant};
+
+    use byteorder::{ByteOrder, NativeEndian};
+    use libc;
+
+    #[test]
+    fn local_ip_is_read_from_pktinfo() {
+        let word = mem::size_of::<usize>();
+        let header_len = (word + 8 + word - 1) & !(word - 1);
+        let mut control = vec![0u8; header_len + 12];
+        match word {
+            8 => NativeEndian::write_u64(&mut control[..], (header_len + 12) as u64),
+            _ => NativeEndian::write_u32(&mut control[..], (header_len + 12) as u32),
+        }
+        NativeEndian::write_i32(&mut control[word..], libc::IPPROTO_IP);
+        NativeEndian::write_i32(&mut control[word + 4..], 8);
+        control[header_len + 4..header_len + 8].copy_from_slice(&[192, 168, 1, 20]);
+        control[header_len + 8..].copy_from_slice(&[255, 255, 255, 255]);
+        assert_eq!(local_ip(&control), Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))));
+        assert_eq!(local_ip(&control[..header_len]), None);
+    }
+
+    #[test]
+    fn datagrams_on_an_unspecified_socket_report_their_local_address() {
+        let receiver = unwrap_result!(UdpSocket::bind("0.0.0.0:0"));
+        let port = unwrap_result!(receiver.local_addr()).port();
+        unwrap_result!(enable(&receiver));
+        let sender = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let _ = unwrap_result!(sender.send_to(b"hello", ("127.0.0.1", port)));
+        let mut buf = [0u8; 16];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let (len, _, ip) = unwrap_result!(recv_until(&receiver, &mut buf, deadline))
+                               .expect("Timed out");
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(ip, Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))));
+    }
+}
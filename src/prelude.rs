@@ -0,0 +1,37 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! The types needed for the usual workflow: create a `MappingContext`, map a socket, swap
+//! rendezvous info with the peer and punch a hole. Import it all with
+//! `use nat_traversal::prelude::*;`.
+//!
+//! Most functions return a `WResult`, which carries warnings along with the result, so it's
+//! re-exported here too.
+
+pub use w_result::{WErr, WOk, WResult};
+
+pub use error_code::{ErrorCategory, ErrorCode};
+pub use event::{Event, EventSender};
+pub use mapped_socket_addr::MappedSocketAddr;
+#[cfg(feature = "tcp")]
+pub use mapped_tcp_socket::{tcp_punch_hole, MappedTcpSocket, MappedTcpSocketMapError,
+                            MappedTcpSocketNewError, TcpPunchBuilder, TcpPunchHoleError};
+pub use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketMapError, MappedUdpSocketNewError};
+pub use mapping_context::{MappingContext, MappingContextNewError};
+pub use punched_udp_socket::{PunchedUdpSocket, UdpPunchBuilder, UdpPunchHoleError};
+pub use rendezvous_info::{gen_rendezvous_info, PrivRendezvousInfo, PubRendezvousInfo};
+pub use timeouts::Timeouts;
@@ -0,0 +1,158 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+
+use mapped_socket_addr::MappedSocketAddr;
+use socket_utils;
+use subnetting::SubnetList;
+
+static REDACT_ADDRS: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Redact addresses in the error and warning messages of this crate, so that they can be logged or
+/// sent off as diagnostics without revealing where the application or its peers are. Applies
+/// process-wide, as messages may be formatted anywhere.
+pub fn set_redact_addrs(redact: bool) {
+    REDACT_ADDRS.store(redact, Ordering::SeqCst);
+}
+
+/// Whether addresses are redacted, see `set_redact_addrs`.
+pub fn redact_addrs() -> bool {
+    REDACT_ADDRS.load(Ordering::SeqCst)
+}
+
+/// Displays an address in messages, or a placeholder if addresses are being redacted.
+pub struct Redacted<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if redact_addrs() {
+            write!(f, "<redacted address>")
+        } else {
+            fmt::Display::fmt(&self.0, f)
+        }
+    }
+}
+
+/// Which kinds of endpoint a `MappingContext` reports for its sockets, and so which end up shared
+/// with peers in rendezvous info. By default every endpoint is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct ExposurePolicy {
+    /// Report loopback addresses, which only a peer on the same host can use.
+    pub share_loopback: bool,
+    /// Report private, link-local and shared addresses, which reveal the local network's
+    /// addressing. Turn this off when the rendezvous info may go to peers outside the LAN.
+    pub share_lan: bool,
+    /// Report Teredo and 6to4 addresses, which reveal the ipv4 address they tunnel through.
+    pub share_tunneled_ipv6: bool,
+    /// Report addresses that servers couldn't confirm, see `MappedSocketAddr::unverified`.
+    pub share_unverified: bool,
+    /// Report addresses whose port is only a guess, see `MappedSocketAddr::port_unknown`.
+    pub share_port_guesses: bool,
+}
+
+impl Default for ExposurePolicy {
+    fn default() -> ExposurePolicy {
+        ExposurePolicy {
+            share_loopback: true,
+            share_lan: true,
+            share_tunneled_ipv6: true,
+            share_unverified: true,
+            share_port_guesses: true,
+        }
+    }
+}
+
+impl ExposurePolicy {
+    /// A policy which only reports confirmed public addresses, for applications whose peers
+    /// mustn't learn anything about the local network.
+    pub fn public_only() -> ExposurePolicy {
+        ExposurePolicy {
+            share_loopback: false,
+            share_lan: false,
+            share_tunneled_ipv6: false,
+            share_unverified: false,
+            share_port_guesses: false,
+        }
+    }
+
+    /// Whether `endpoint` may be reported.
+    pub fn allows(&self, endpoint: &MappedSocketAddr) -> bool {
+        let ip = endpoint.addr.ip();
+        if socket_utils::is_loopback(&ip) {
+            return self.share_loopback;
+        }
+        (self.share_lan || !is_lan(&ip)) &&
+        (self.share_tunneled_ipv6 || endpoint.ipv6_tunnel().is_none()) &&
+        (self.share_unverified || !endpoint.unverified) &&
+        (self.share_port_guesses || !endpoint.port_unknown)
+    }
+
+    /// The endpoints this policy allows, in their original order.
+    pub fn filter(&self, endpoints: Vec<MappedSocketAddr>) -> Vec<MappedSocketAddr> {
+        endpoints.into_iter().filter(|endpoint| self.allows(endpoint)).collect()
+    }
+}
+
+fn is_lan(ip: &IpAddr) -> bool {
+    SubnetList::private().contains(ip) || SubnetList::shared().contains(ip) ||
+    SubnetList::site_local().contains(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use socket_addr::SocketAddr;
+
+    use mapped_socket_addr::MappedSocketAddr;
+
+    fn endpoint(addr: &str, unverified: bool) -> MappedSocketAddr {
+        MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(addr.parse())),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: unverified,
+        }
+    }
+
+    #[test]
+    fn public_only_keeps_confirmed_public_endpoints() {
+        let endpoints = vec![endpoint("127.0.0.1:5000", false),
+                             endpoint("192.168.1.2:5000", false),
+                             endpoint("[2002:c000:221::1]:5000", false),
+                             endpoint("203.0.113.7:5000", true),
+                             endpoint("203.0.113.7:6000", false)];
+        assert_eq!(ExposurePolicy::default().filter(endpoints.clone()), endpoints);
+        assert_eq!(ExposurePolicy::public_only().filter(endpoints),
+                   vec![endpoint("203.0.113.7:6000", false)]);
+    }
+
+    #[test]
+    fn redacted_addresses_are_hidden() {
+        let addr: SocketAddr = SocketAddr(unwrap_result!("203.0.113.7:5000".parse()));
+        assert_eq!(format!("{}", Redacted(addr)), "203.0.113.7:5000");
+        set_redact_addrs(true);
+        assert_eq!(format!("{}", Redacted(addr)), "<redacted address>");
+        set_redact_addrs(false);
+    }
+}
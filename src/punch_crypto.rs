@@ -0,0 +1,170 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::fmt;
+
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use sodiumoxide;
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::secretbox;
+
+use utils;
+
+// The HKDF salt, so that a key derived for hole punching can't be mistaken for one the
+// application derives from the same secret for something else. It's exactly as long as an
+// HMAC-SHA256 key so that it can key the HMAC directly.
+const KEY_CONTEXT: &'static [u8; 32] = b"nat_traversal hole punch salt v2";
+// Prefixed to the HKDF info, so that per-packet keys and subkeys can never coincide.
+const MESSAGE_KEY_LABEL: &'static [u8] = b"message";
+const SUBKEY_LABEL: &'static [u8] = b"subkey";
+
+/// A key for encrypting and authenticating hole punch packets. Both peers derive it from the same
+/// secret, agreed out of band, eg. sent along with the rendezvous info over an already secured
+/// channel. On-path observers then can't read the rendezvous secrets out of the packets or forge
+/// packets which complete the hole punch.
+///
+/// Each packet is sealed with its own key, derived from this one and the packet's random nonce,
+/// so this key is never used directly. It's zeroed when dropped.
+#[derive(Clone)]
+pub struct PunchKey {
+    key: [u8; 32],
+}
+
+impl fmt::Debug for PunchKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PunchKey(<redacted>)")
+    }
+}
+
+// Compared in constant time so that timing doesn't give the key away.
+impl PartialEq for PunchKey {
+    fn eq(&self, other: &PunchKey) -> bool {
+        utils::constant_time_eq(&self.key, &other.key)
+    }
+}
+
+impl Eq for PunchKey {}
+
+// Only so that a `SessionRecord` of a sealed session can be saved and replayed. The key is
+// written as it is, so the record must be kept as safe as the key.
+impl Encodable for PunchKey {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.key.encode(s)
+    }
+}
+
+impl Decodable for PunchKey {
+    fn decode<D: Decoder>(d: &mut D) -> Result<PunchKey, D::Error> {
+        Ok(PunchKey { key: try!(Decodable::decode(d)) })
+    }
+}
+
+impl Drop for PunchKey {
+    fn drop(&mut self) {
+        utils::zeroize(&mut self.key);
+    }
+}
+
+impl PunchKey {
+    /// Derive a key from a secret shared by both peers. The secret should be at least 16 random
+    /// bytes.
+    pub fn derive(shared_secret: &[u8]) -> PunchKey {
+        let _ = sodiumoxide::init();
+        // HKDF-Extract, with the salt as the HMAC key.
+        let hmacsha256::Tag(prk) = hmacsha256::authenticate(shared_secret,
+                                                             &hmacsha256::Key(*KEY_CONTEXT));
+        PunchKey { key: prk }
+    }
+
+    // The key for sealing the packet with nonce `nonce`.
+    fn message_key(&self, nonce: &secretbox::Nonce) -> secretbox::Key {
+        secretbox::Key(expand(&self.key, MESSAGE_KEY_LABEL, &nonce.0))
+    }
+}
+
+// HKDF-Expand with SHA-256, keyed by `prk`, producing a single 32 byte block.
+fn expand(prk: &[u8; 32], label: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut input = Vec::with_capacity(label.len() + info.len() + 1);
+    input.extend_from_slice(label);
+    input.extend_from_slice(info);
+    input.push(1);
+    let hmacsha256::Tag(okm) = hmacsha256::authenticate(&input, &hmacsha256::Key(*prk));
+    utils::zeroize(&mut input);
+    okm
+}
+
+/// Derive a key for some other purpose from `key`, so that the application needn't agree on a
+/// second secret with the peer. Different `context`s give unrelated keys.
+pub fn subkey(key: &PunchKey, context: &[u8]) -> [u8; 32] {
+    expand(&key.key, SUBKEY_LABEL, context)
+}
+
+/// Encrypt `plaintext` under a fresh random nonce. The nonce is prepended to the result.
+pub fn seal(key: &PunchKey, plaintext: &[u8]) -> Vec<u8> {
+    let nonce = secretbox::gen_nonce();
+    let sealed = secretbox::seal(plaintext, &nonce, &key.message_key(&nonce));
+    let mut packet = Vec::with_capacity(secretbox::NONCEBYTES + sealed.len());
+    packet.extend_from_slice(&nonce.0[..]);
+    packet.extend_from_slice(&sealed);
+    packet
+}
+
+/// Decrypt a packet produced by `seal`. Returns `None` if it wasn't sealed with `key` or has been
+/// tampered with.
+pub fn open(key: &PunchKey, packet: &[u8]) -> Option<Vec<u8>> {
+    if packet.len() < secretbox::NONCEBYTES {
+        return None;
+    }
+    let nonce = match secretbox::Nonce::from_slice(&packet[..secretbox::NONCEBYTES]) {
+        Some(nonce) => nonce,
+        None => return None,
+    };
+    secretbox::open(&packet[secretbox::NONCEBYTES..], &nonce, &key.message_key(&nonce)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_same_key_opens_untampered_packets() {
+        let key = PunchKey::derive(b"correct horse battery staple");
+        let other_key = PunchKey::derive(b"incorrect horse battery staple");
+
+        let mut packet = seal(&key, b"punch");
+        assert_eq!(open(&key, &packet), Some(b"punch".to_vec()));
+        assert_eq!(open(&other_key, &packet), None);
+
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        assert_eq!(open(&key, &packet), None);
+        assert_eq!(open(&key, &packet[..4]), None);
+    }
+
+    #[test]
+    fn subkeys_depend_on_the_key_and_context() {
+        let key = PunchKey::derive(b"correct horse battery staple");
+        let other_key = PunchKey::derive(b"incorrect horse battery staple");
+
+        assert_eq!(subkey(&key, b"quic"), subkey(&key.clone(), b"quic"));
+        assert!(subkey(&key, b"quic") != subkey(&key, b"quid"));
+        assert!(subkey(&key, b"quic") != subkey(&other_key, b"quic"));
+    }
+}
@@ -0,0 +1,355 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! The udp hole punching protocol without any sockets, threads or clocks, for event loops that
+//! do their own I/O.
+
+use std::collections::VecDeque;
+use std::net;
+use std::time::{Duration, Instant};
+
+use mapped_socket_addr::MappedSocketAddr;
+use punch_crypto::PunchKey;
+use punched_udp_socket::{self, PacketKind, UdpPunchHoleWarning};
+use rendezvous_info::{self, PrivRendezvousInfo, PubRendezvousInfo, Secrets};
+
+/// How long to wait between the acks sent in reply to the peer's hole punch packet, matching
+/// `PunchedUdpSocket::punch_hole`.
+const ACK_INTERVAL_MS: u64 = 100;
+
+/// Where a `UdpPunchStateMachine` has got to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpPunchStatus {
+    /// Still punching.
+    InProgress,
+    /// The hole is punched and the peer can be reached at this address. Any packets still
+    /// returned by `next_outgoing` should be sent before the socket is used for anything else.
+    Connected(net::SocketAddr),
+    /// The deadline passed without the hole being punched.
+    TimedOut,
+}
+
+// Where the punch has got to, with the details `UdpPunchStatus` leaves out.
+#[derive(Clone, Copy)]
+enum State {
+    Punching { next_send: Instant },
+    // Sending acks to the peer whose hole punch packet arrived first.
+    Acking {
+        peer_addr: net::SocketAddr,
+        acks_sent: u32,
+        next_ack: Instant,
+        // Whether the packet being acked was a nomination.
+        nomination: bool,
+    },
+    Connected(net::SocketAddr),
+    TimedOut,
+}
+
+/// The udp hole punching protocol of `PunchedUdpSocket::punch_hole` as a state machine which
+/// owns no socket, thread or clock, for embedding in event loops and runtimes this crate doesn't
+/// know about, or for driving from a simulator.
+///
+/// The caller sends whatever `next_outgoing` returns from its socket, feeds the packets it
+/// receives to `handle_packet` and calls `handle_timeout` once `poll_timeout` passes, until
+/// `status` is no longer `InProgress`. The current time is passed in with each call.
+///
+/// It doesn't nominate pairs. A peer that does is answered with nomination acks, so the state
+/// machine can play the controlled side.
+pub struct UdpPunchStateMachine {
+    endpoints: Vec<MappedSocketAddr>,
+    our_secrets: Secrets,
+    their_secret: [u8; 4],
+    key: Option<PunchKey>,
+    resend_interval: Duration,
+    acks: u32,
+    deadline: Instant,
+    state: State,
+    // Where to send each packet, the secret it carries, whether it's an ack and whether it's a
+    // nomination. They're only encoded once taken, so that `sealed` can be called after `new`
+    // has queued the first ones.
+    outgoing: VecDeque<(net::SocketAddr, [u8; 4], bool, bool)>,
+    warnings: Vec<UdpPunchHoleWarning>,
+}
+
+impl UdpPunchStateMachine {
+    /// Start punching towards the peer's endpoints. The first hole punch packets are ready to
+    /// send straight away.
+    pub fn new(our_priv_rendezvous_info: PrivRendezvousInfo,
+               their_pub_rendezvous_info: PubRendezvousInfo,
+               now: Instant,
+               deadline: Instant)
+               -> UdpPunchStateMachine {
+        let (endpoints, their_secret) = rendezvous_info::decompose(their_pub_rendezvous_info);
+        let our_secrets = rendezvous_info::get_priv_secrets(our_priv_rendezvous_info);
+        from_secrets(endpoints, our_secrets, their_secret, now, deadline)
+    }
+
+    /// Seal every packet with `key` and ignore packets which aren't sealed with it, as
+    /// `PunchedUdpSocket::punch_hole_sealed` does. Call before sending anything.
+    pub fn sealed(mut self, key: PunchKey) -> UdpPunchStateMachine {
+        self.key = Some(key);
+        self
+    }
+
+    /// Resend hole punch packets this often. Defaults to 600ms.
+    pub fn resend_interval(mut self, interval: Duration) -> UdpPunchStateMachine {
+        self.resend_interval = interval;
+        self
+    }
+
+    /// Send this many acks when the peer's hole punch packet arrives before its ack. Defaults to
+    /// two.
+    pub fn acks(mut self, acks: u32) -> UdpPunchStateMachine {
+        self.acks = acks;
+        self
+    }
+
+    /// Where the punch has got to.
+    pub fn status(&self) -> UdpPunchStatus {
+        match self.state {
+            State::Punching { .. } | State::Acking { .. } => UdpPunchStatus::InProgress,
+            State::Connected(peer_addr) => UdpPunchStatus::Connected(peer_addr),
+            State::TimedOut => UdpPunchStatus::TimedOut,
+        }
+    }
+
+    /// When `handle_timeout` should next be called, or `None` once the punch is over.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        match self.state {
+            State::Punching { next_send } => Some(next_send),
+            State::Acking { next_ack, .. } => Some(next_ack),
+            State::Connected(..) | State::TimedOut => None,
+        }
+    }
+
+    /// Resend hole punch packets or acks if it's time to, or give up once the deadline passes.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        // Like `punch_hole`, once the peer's hole punch packet has arrived all the acks are sent
+        // whatever the deadline.
+        let state = self.state;
+        match state {
+            State::Punching { .. } if now >= self.deadline => self.state = State::TimedOut,
+            State::Punching { next_send } if now >= next_send => {
+                for endpoint in &self.endpoints {
+                    self.outgoing.push_back((*endpoint.addr, self.our_secrets[0], false, false));
+                }
+                self.state = State::Punching { next_send: now + self.resend_interval };
+            },
+            State::Acking { peer_addr, next_ack, nomination, .. } if now >= next_ack => {
+                self.queue_ack(peer_addr, nomination, now);
+            },
+            _ => (),
+        }
+    }
+
+    /// Handle a packet received from `from`. Packets that aren't part of this hole punch are
+    /// noted in `take_warnings`, as `PunchedUdpSocket::punch_hole` would warn about them.
+    pub fn handle_packet(&mut self, from: net::SocketAddr, bytes: &[u8], now: Instant) {
+        let kind = punched_udp_socket::classify_sealed_packet(bytes,
+                                                              self.key.as_ref(),
+                                                              &self.our_secrets,
+                                                              self.their_secret);
+        let punching = match self.state {
+            State::Punching { .. } => true,
+            _ => false,
+        };
+        let nomination = match kind {
+            PacketKind::Nomination | PacketKind::NominationAck => true,
+            _ => false,
+        };
+        match (kind, punching) {
+            (PacketKind::Ack, true) | (PacketKind::NominationAck, true) => {
+                self.state = State::Connected(from);
+                return;
+            },
+            (PacketKind::Punch, true) | (PacketKind::Nomination, true) => {
+                self.state = State::Acking {
+                    peer_addr: from,
+                    acks_sent: 0,
+                    next_ack: now,
+                    nomination: nomination,
+                };
+            },
+            // Once we've settled on a peer address, further packets from the peer change
+            // nothing.
+            (PacketKind::Ack, _) |
+            (PacketKind::Punch, _) |
+            (PacketKind::NominationAck, _) |
+            (PacketKind::Nomination, _) => return,
+            (kind, _) => {
+                if self.warnings.len() < punched_udp_socket::MAX_WARNINGS {
+                    self.warnings.extend(kind.into_warning());
+                }
+                return;
+            },
+        }
+        self.queue_ack(from, nomination, now);
+    }
+
+    /// The next packet to send and where to send it, if any.
+    pub fn next_outgoing(&mut self) -> Option<(net::SocketAddr, Vec<u8>)> {
+        self.outgoing.pop_front().map(|(addr, secret, ack, nomination)| {
+            let data = if nomination {
+                punched_udp_socket::encode_nomination(secret, ack, self.key.as_ref())
+            } else {
+                punched_udp_socket::encode_hole_punch(secret, ack, self.key.as_ref())
+            };
+            (addr, data)
+        })
+    }
+
+    /// Take the warnings raised so far.
+    pub fn take_warnings(&mut self) -> Vec<UdpPunchHoleWarning> {
+        self.warnings.drain(..).collect()
+    }
+
+    fn queue_ack(&mut self, peer_addr: net::SocketAddr, nomination: bool, now: Instant) {
+        self.outgoing.push_back((peer_addr, self.their_secret, true, nomination));
+        let acks_sent = match self.state {
+            State::Acking { acks_sent, .. } => acks_sent + 1,
+            _ => return,
+        };
+        self.state = if acks_sent >= self.acks {
+            State::Connected(peer_addr)
+        } else {
+            State::Acking {
+                peer_addr: peer_addr,
+                acks_sent: acks_sent,
+                next_ack: now + Duration::from_millis(ACK_INTERVAL_MS),
+                nomination: nomination,
+            }
+        };
+    }
+}
+
+/// Start punching with the secrets themselves rather than the rendezvous info holding them, eg.
+/// when replaying a recorded session.
+pub fn from_secrets(endpoints: Vec<MappedSocketAddr>,
+                    our_secrets: Secrets,
+                    their_secret: [u8; 4],
+                    now: Instant,
+                    deadline: Instant)
+                    -> UdpPunchStateMachine {
+    let mut machine = UdpPunchStateMachine {
+        endpoints: endpoints,
+        our_secrets: our_secrets,
+        their_secret: their_secret,
+        key: None,
+        resend_interval: Duration::from_millis(punched_udp_socket::DEFAULT_RESEND_INTERVAL_MS),
+        acks: punched_udp_socket::DEFAULT_ACKS,
+        deadline: deadline,
+        state: State::Punching { next_send: now },
+        outgoing: VecDeque::new(),
+        warnings: Vec::new(),
+    };
+    machine.handle_timeout(now);
+    machine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net;
+    use std::time::{Duration, Instant};
+
+    use socket_addr::SocketAddr;
+
+    use mapped_socket_addr::MappedSocketAddr;
+    use punch_crypto::PunchKey;
+    use rendezvous_info::gen_rendezvous_info;
+
+    fn endpoint(addr: net::SocketAddr) -> MappedSocketAddr {
+        MappedSocketAddr {
+            addr: SocketAddr(addr),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        }
+    }
+
+    // Run two machines against each other, delivering each packet instantly unless `lose` says
+    // otherwise, and return their final statuses.
+    fn run(key: Option<PunchKey>, lose: &Fn(usize) -> bool) -> (UdpPunchStatus, UdpPunchStatus) {
+        let addr_0: net::SocketAddr = unwrap_result!("192.0.2.1:1000".parse());
+        let addr_1: net::SocketAddr = unwrap_result!("198.51.100.1:2000".parse());
+        let (priv_info_0, pub_info_0) = gen_rendezvous_info(vec![endpoint(addr_0)]);
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(vec![endpoint(addr_1)]);
+        let mut now = Instant::now();
+        let deadline = now + Duration::from_secs(10);
+        let seal = |machine: UdpPunchStateMachine| {
+            match key {
+                Some(ref key) => machine.sealed(key.clone()),
+                None => machine,
+            }
+        };
+        let machine_0 = UdpPunchStateMachine::new(priv_info_0, pub_info_1, now, deadline);
+        let machine_1 = UdpPunchStateMachine::new(priv_info_1, pub_info_0, now, deadline);
+        let mut machines = [seal(machine_0), seal(machine_1)];
+        let addrs = [addr_0, addr_1];
+        let mut sent = 0;
+        while machines.iter().any(|m| m.status() == UdpPunchStatus::InProgress) {
+            for i in 0..2 {
+                while let Some((to, packet)) = machines[i].next_outgoing() {
+                    assert_eq!(to, addrs[1 - i]);
+                    sent += 1;
+                    if !lose(sent) {
+                        machines[1 - i].handle_packet(addrs[i], &packet, now);
+                    }
+                }
+            }
+            let next = machines.iter().filter_map(|m| m.poll_timeout()).min();
+            match next {
+                Some(next) => now = next,
+                None => break,
+            }
+            for machine in &mut machines {
+                machine.handle_timeout(now);
+            }
+        }
+        (machines[0].status(), machines[1].status())
+    }
+
+    #[test]
+    fn two_machines_punch_a_hole() {
+        let addr_0 = unwrap_result!("192.0.2.1:1000".parse());
+        let addr_1 = unwrap_result!("198.51.100.1:2000".parse());
+        let connected = (UdpPunchStatus::Connected(addr_1), UdpPunchStatus::Connected(addr_0));
+        assert_eq!(run(None, &|_| false), connected);
+        assert_eq!(run(Some(PunchKey::derive(b"shared out of band")), &|_| false), connected);
+        // The first punches are lost, eg. while the NATs' mappings don't exist yet.
+        assert_eq!(run(None, &|sent| sent <= 3), connected);
+        assert_eq!(run(None, &|_| true), (UdpPunchStatus::TimedOut, UdpPunchStatus::TimedOut));
+    }
+
+    #[test]
+    fn stray_packets_raise_warnings() {
+        let now = Instant::now();
+        let addr: net::SocketAddr = unwrap_result!("192.0.2.1:1000".parse());
+        let (priv_info, _) = gen_rendezvous_info(vec![endpoint(addr)]);
+        let (_, pub_info) = gen_rendezvous_info(vec![endpoint(addr)]);
+        let deadline = now + Duration::from_secs(1);
+        let mut machine = UdpPunchStateMachine::new(priv_info, pub_info, now, deadline);
+        assert_eq!(machine.next_outgoing().map(|(to, _)| to), Some(addr));
+        machine.handle_packet(addr, b"not a hole punch packet", now);
+        assert_eq!(machine.take_warnings().len(), 1);
+        assert_eq!(machine.status(), UdpPunchStatus::InProgress);
+        machine.handle_timeout(deadline);
+        assert_eq!(machine.status(), UdpPunchStatus::TimedOut);
+        assert_eq!(machine.poll_timeout(), None);
+    }
+}
@@ -18,24 +18,454 @@
 //! # `nat_traversal`
 //! NAT traversal utilities.
 
-use maidsafe_utilities::serialisation::{deserialise, SerialisationError, serialise};
+use maidsafe_utilities::serialisation::{deserialise, SerialisationError};
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::Hash;
 use std::io;
-use std::net::UdpSocket;
+use std::net::{self, IpAddr, UdpSocket};
 use std::time::{Instant, Duration};
-use std::thread;
+use std::fmt;
 
 use socket_addr::SocketAddr;
 use w_result::{WResult, WOk, WErr};
 
-use rendezvous_info::{PrivRendezvousInfo, PubRendezvousInfo};
+use cbor::{CborReader, CborWriter};
+use checklist::Checklist;
+use clock::{self, Clock, SystemClock};
+use error_code::{ErrorCategory, ErrorCode};
+use event::{Event, EventSender, Strategy};
+use icmp::IcmpObserver;
+use mapping_context::{self, ConcurrencyLimits, MappingContext};
+use pacer::{Pacer, TrafficShaper};
+use punch_crypto::{self, PunchKey};
+use rendezvous_info::{PrivRendezvousInfo, PubRendezvousInfo, Secrets};
 use rendezvous_info;
-use socket_utils::RecvUntil;
-use mapped_socket_addr::MappedSocketAddr;
+use transport::DatagramTransport;
+use utils;
+use mapped_socket_addr::{CandidateClass, MappedSocketAddr};
+use privacy::Redacted;
+use punch_state_machine::{self, UdpPunchStateMachine, UdpPunchStatus};
+use session_record::{Direction, SessionRecord, SessionRecorder};
+use timeouts::Timeouts;
 
-#[derive(Debug, RustcEncodable, RustcDecodable)]
+#[derive(Debug)]
 struct HolePunch {
     pub secret: [u8; 4],
     pub ack: bool,
+    // Set on the controlling peer's hole punch packets for the pair it nominates, and on the
+    // acks to them.
+    pub nominate: bool,
+}
+
+// A hole punch packet as it was always serialised. The nomination flag is only put on the wire
+// when it's set, so that everything else is encoded exactly like this and peers which don't
+// know about nomination still understand it.
+#[derive(RustcEncodable, RustcDecodable)]
+struct SerialisedHolePunch {
+    secret: [u8; 4],
+    ack: bool,
+}
+
+impl HolePunch {
+    // Encode into `buf` without allocating, exactly as `serialise` would.
+    fn encode_into(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut writer = CborWriter::new(buf);
+        try!(writer.map(if self.nominate { 3 } else { 2 }));
+        try!(writer.text("secret"));
+        try!(writer.array(self.secret.len()));
+        for b in &self.secret {
+            try!(writer.uint(*b as u64));
+        }
+        try!(writer.text("ack"));
+        try!(writer.bool(self.ack));
+        if self.nominate {
+            try!(writer.text("nominate"));
+            try!(writer.bool(true));
+        }
+        Ok(writer.position())
+    }
+
+    // Decode by reading straight out of the receive buffer, falling back to `deserialise` for
+    // anything unusual.
+    fn decode(data: &[u8]) -> Result<HolePunch, SerialisationError> {
+        match decode_hole_punch(data) {
+            Some(hole_punch) => Ok(hole_punch),
+            None => {
+                deserialise::<SerialisedHolePunch>(data).map(|hp| {
+                    HolePunch {
+                        secret: hp.secret,
+                        ack: hp.ack,
+                        nominate: false,
+                    }
+                })
+            },
+        }
+    }
+}
+
+fn decode_hole_punch(data: &[u8]) -> Option<HolePunch> {
+    let mut reader = CborReader::new(data);
+    let fields = match reader.map() {
+        Some(fields) if fields == 2 || fields == 3 => fields,
+        _ => return None,
+    };
+    if reader.key("secret").is_none() || reader.array() != Some(4) {
+        return None;
+    }
+    let mut secret = [0u8; 4];
+    for b in &mut secret {
+        match reader.uint() {
+            Some(value) if value <= 0xff => *b = value as u8,
+            _ => return None,
+        }
+    }
+    if reader.key("ack").is_none() {
+        return None;
+    }
+    let ack = match reader.bool() {
+        Some(ack) => ack,
+        None => return None,
+    };
+    let nominate = if fields == 3 {
+        if reader.key("nominate").is_none() {
+            return None;
+        }
+        match reader.bool() {
+            Some(nominate) => nominate,
+            None => return None,
+        }
+    } else {
+        false
+    };
+    if !reader.is_empty() {
+        return None;
+    }
+    Some(HolePunch {
+        secret: secret,
+        ack: ack,
+        nominate: nominate,
+    })
+}
+
+/// How a received packet affects an in-progress hole punch.
+pub enum PacketKind {
+    /// The peer acknowledged one of our hole punch packets.
+    Ack,
+    /// The peer sent us a hole punch packet which we need to acknowledge.
+    Punch,
+    /// The peer acknowledged the hole punch packet with which we nominated a pair.
+    NominationAck,
+    /// The peer nominated the pair this packet arrived on. It needs acknowledging with a
+    /// nomination ack.
+    Nomination,
+    /// A hole punch packet that doesn't belong to this connection.
+    Unexpected(HolePunchPacketData),
+    /// Something that isn't a hole punch packet.
+    Invalid(SerialisationError),
+    /// A packet that wasn't sealed with our punch key.
+    Unauthenticated,
+}
+
+// Acks may carry any of `our_secrets`, as peers may know us by a secret that has since been
+// rotated.
+fn classify_packet(data: &[u8], our_secrets: &[[u8; 4]], their_secret: [u8; 4]) -> PacketKind {
+    match HolePunch::decode(data) {
+        Ok(hp) => {
+            let ours = our_secrets.iter().any(|s| utils::constant_time_eq(&hp.secret, s));
+            if ours && hp.ack && hp.nominate {
+                PacketKind::NominationAck
+            }
+            else if ours && hp.ack {
+                PacketKind::Ack
+            }
+            else if utils::constant_time_eq(&hp.secret, &their_secret) && hp.nominate {
+                PacketKind::Nomination
+            }
+            else if utils::constant_time_eq(&hp.secret, &their_secret) {
+                PacketKind::Punch
+            }
+            else {
+                PacketKind::Unexpected(HolePunchPacketData { data: hp })
+            }
+        },
+        Err(e) => PacketKind::Invalid(e),
+    }
+}
+
+impl PacketKind {
+    /// The warning to raise for a packet that doesn't advance the hole punch, if any.
+    pub fn into_warning(self) -> Option<UdpPunchHoleWarning> {
+        match self {
+            PacketKind::Ack |
+            PacketKind::Punch |
+            PacketKind::NominationAck |
+            PacketKind::Nomination => None,
+            PacketKind::Unexpected(hole_punch) => {
+                Some(UdpPunchHoleWarning::UnexpectedHolePunchPacket { hole_punch: hole_punch })
+            },
+            PacketKind::Invalid(e) => Some(UdpPunchHoleWarning::InvalidHolePunchPacket { err: e }),
+            PacketKind::Unauthenticated => Some(UdpPunchHoleWarning::UnauthenticatedPacket),
+        }
+    }
+}
+
+/// Encode a hole punch packet, sealed with `key` if there is one.
+pub fn encode_hole_punch(secret: [u8; 4], ack: bool, key: Option<&PunchKey>) -> Vec<u8> {
+    encode(HolePunch {
+        secret: secret,
+        ack: ack,
+        nominate: false,
+    }, key)
+}
+
+/// Encode a hole punch packet which nominates the pair it's sent on, or if `ack` is set one which
+/// acknowledges such a nomination, sealed with `key` if there is one.
+pub fn encode_nomination(secret: [u8; 4], ack: bool, key: Option<&PunchKey>) -> Vec<u8> {
+    encode(HolePunch {
+        secret: secret,
+        ack: ack,
+        nominate: true,
+    }, key)
+}
+
+fn encode(hole_punch: HolePunch, key: Option<&PunchKey>) -> Vec<u8> {
+    let mut buf = [0u8; 128];
+    // Can't fail: the message has a fixed size well under the buffer's.
+    let len = unwrap_result!(hole_punch.encode_into(&mut buf));
+    match key {
+        Some(key) => punch_crypto::seal(key, &buf[..len]),
+        None => buf[..len].to_vec(),
+    }
+}
+
+/// Classify a packet which, if we have a key, should have been sealed with it.
+pub fn classify_sealed_packet(data: &[u8],
+                          key: Option<&PunchKey>,
+                          our_secrets: &[[u8; 4]],
+                          their_secret: [u8; 4]) -> PacketKind {
+    match key {
+        Some(key) => match punch_crypto::open(key, data) {
+            Some(opened) => classify_packet(&opened, our_secrets, their_secret),
+            None => PacketKind::Unauthenticated,
+        },
+        None => classify_packet(data, our_secrets, their_secret),
+    }
+}
+
+/// How often hole punch packets are resent by default.
+pub const DEFAULT_RESEND_INTERVAL_MS: u64 = 600;
+/// How many acks are sent by default when the peer's hole punch packet arrives first.
+pub const DEFAULT_ACKS: u32 = 2;
+/// The most warnings collected about stray packets, so that a malicious peer can't make us
+/// collect them without bound.
+pub const MAX_WARNINGS: usize = 10;
+
+/// Options for udp hole punching. Start with `new`, set whichever options are needed and finish
+/// with `punch_hole`. The `PunchedUdpSocket::punch_hole_*` functions are shorthands for common
+/// combinations.
+#[derive(Clone)]
+pub struct UdpPunchBuilder<'a> {
+    deadline: Instant,
+    events: Option<&'a EventSender>,
+    recorder: Option<&'a SessionRecorder>,
+    clock: Option<&'a Clock>,
+    limits: ConcurrencyLimits,
+    key: Option<&'a PunchKey>,
+    shaper: Option<&'a TrafficShaper>,
+    icmp: Option<&'a IcmpObserver>,
+    resend_interval: Duration,
+    acks: u32,
+    candidate_filter: Option<&'a Fn(&MappedSocketAddr) -> bool>,
+    check_timeout: Option<Duration>,
+    start_at: Option<Instant>,
+    nomination_window: Option<Duration>,
+}
+
+impl<'a> fmt::Debug for UdpPunchBuilder<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UdpPunchBuilder")
+         .field("deadline", &self.deadline)
+         .field("limits", &self.limits)
+         .field("sealed", &self.key.is_some())
+         .field("resend_interval", &self.resend_interval)
+         .field("acks", &self.acks)
+         .field("candidate_filter", &self.candidate_filter.is_some())
+         .field("check_timeout", &self.check_timeout)
+         .field("start_at", &self.start_at)
+         .field("nomination_window", &self.nomination_window)
+         .finish()
+    }
+}
+
+impl<'a> UdpPunchBuilder<'a> {
+    /// Give up on hole punching at `deadline`. Every other option starts at its default.
+    pub fn new(deadline: Instant) -> UdpPunchBuilder<'a> {
+        UdpPunchBuilder {
+            deadline: deadline,
+            events: None,
+            recorder: None,
+            clock: None,
+            limits: ConcurrencyLimits::default(),
+            key: None,
+            shaper: None,
+            icmp: None,
+            resend_interval: Duration::from_millis(DEFAULT_RESEND_INTERVAL_MS),
+            acks: DEFAULT_ACKS,
+            candidate_filter: None,
+            check_timeout: None,
+            start_at: None,
+            nomination_window: None,
+        }
+    }
+
+    /// Give up on hole punching once `timeouts.connect` has passed from now, and on each of the
+    /// peer's endpoints after `timeouts.check`.
+    pub fn with_timeouts(timeouts: &Timeouts) -> UdpPunchBuilder<'a> {
+        UdpPunchBuilder::new(timeouts.connect_deadline()).check_timeout(timeouts.check)
+    }
+
+    /// Report the progress of the hole punching through `events`.
+    pub fn events(mut self, events: &'a EventSender) -> UdpPunchBuilder<'a> {
+        self.events = Some(events);
+        self
+    }
+
+    /// Record every packet sent and received through `recorder`. The resulting `SessionRecord`
+    /// can be saved and later fed to `replay_udp_punch`.
+    pub fn recorder(mut self, recorder: &'a SessionRecorder) -> UdpPunchBuilder<'a> {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Measure the deadline and the delays between resends on `clock` rather than the system
+    /// clock.
+    pub fn clock(mut self, clock: &'a Clock) -> UdpPunchBuilder<'a> {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Send to the peer's endpoints in bursts within `limits`, usually those of the
+    /// `MappingContext` the socket was mapped with.
+    pub fn limits(mut self, limits: ConcurrencyLimits) -> UdpPunchBuilder<'a> {
+        self.limits = limits;
+        self
+    }
+
+    /// Use the events and limits of `mc`, and share its cap on probes with the other traversal
+    /// attempts using it.
+    pub fn in_context(mut self, mc: &'a MappingContext) -> UdpPunchBuilder<'a> {
+        self.events = Some(mapping_context::events(mc));
+        self.limits = mc.concurrency_limits();
+        self.shaper = Some(mapping_context::traffic_shaper(mc));
+        self
+    }
+
+    /// Seal every hole punch packet with `key`, which the peer must use too. Packets not sealed
+    /// with the key are ignored. Use `filter_sealed_udp_hole_punch_packet` to filter out late hole
+    /// punch packets afterwards.
+    pub fn sealed(mut self, key: &'a PunchKey) -> UdpPunchBuilder<'a> {
+        self.key = Some(key);
+        self
+    }
+
+    /// When an endpoint times out, give the reason from any ICMP error that `observer` saw about
+    /// it in the `Event::CheckFailed` raised for it, eg. telling a firewall apart from a missing
+    /// route.
+    pub fn icmp_observer(mut self, observer: &'a IcmpObserver) -> UdpPunchBuilder<'a> {
+        self.icmp = Some(observer);
+        self
+    }
+
+    /// Stop sending to the peer's endpoints once `timeout` has passed without hearing from the
+    /// peer. Packets the peer sends are still accepted until the deadline. By default endpoints
+    /// are tried until the deadline.
+    pub fn check_timeout(mut self, timeout: Duration) -> UdpPunchBuilder<'a> {
+        self.check_timeout = Some(timeout);
+        self
+    }
+
+    /// Resend hole punch packets to the peer's endpoints every `interval`. Defaults to 600ms.
+    pub fn resend_interval(mut self, interval: Duration) -> UdpPunchBuilder<'a> {
+        self.resend_interval = interval;
+        self
+    }
+
+    /// Send `acks` acknowledgements, with a delay in between, when a hole punch packet arrives
+    /// before one of ours is acknowledged. Defaults to 2. At least one is always sent.
+    pub fn acks(mut self, acks: u32) -> UdpPunchBuilder<'a> {
+        self.acks = cmp::max(acks, 1);
+        self
+    }
+
+    /// Hold off sending the first hole punch packets until `start`, measured on the builder's
+    /// clock, eg. the time from `synchronized_punch_start` so that both peers' packets cross.
+    /// Packets the peer sends in the meantime wait on the socket.
+    pub fn start_at(mut self, start: Instant) -> UdpPunchBuilder<'a> {
+        self.start_at = Some(start);
+        self
+    }
+
+    /// Check the peer's endpoints in `Checklist` priority order and, once one answers, keep
+    /// listening for up to `window` for a higher priority one to answer too before connecting
+    /// over the best of them. Without this the first endpoint to answer wins, which can lock in a
+    /// slower reflexive path when the peer is also reachable directly.
+    ///
+    /// The peer with the greater secret is in control: it nominates the best pair whose checks
+    /// were acked and connects once the peer acks the nomination. The other peer connects over
+    /// whichever pair is nominated, so both peers must set this.
+    pub fn nomination_window(mut self, window: Duration) -> UdpPunchBuilder<'a> {
+        self.nomination_window = Some(window);
+        self
+    }
+
+    /// Only try the peer's endpoints for which `filter` returns `true`.
+    pub fn candidate_filter(mut self, filter: &'a Fn(&MappedSocketAddr) -> bool)
+                            -> UdpPunchBuilder<'a> {
+        self.candidate_filter = Some(filter);
+        self
+    }
+
+    /// Punch a udp socket using a mapped socket and the peer's rendezvous info.
+    pub fn punch_hole<S: DatagramTransport>(self,
+                                            socket: S,
+                                            our_priv_rendezvous_info: PrivRendezvousInfo,
+                                            their_pub_rendezvous_info: PubRendezvousInfo)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        PunchedUdpSocket::punch_hole_impl(socket,
+                                          our_priv_rendezvous_info,
+                                          their_pub_rendezvous_info,
+                                          self)
+    }
+
+    /// Punch holes to many peers at once from one socket, eg. when bootstrapping, sharing the
+    /// work of mapping the socket and sending to all of them within one set of limits rather than
+    /// traversing to each peer in turn. Each peer needs info of its own from
+    /// `gen_rendezvous_info`, all made from the socket's endpoints, so that the peers' packets
+    /// can be told apart. Returns the outcome for each peer by `id`, the successful ones
+    /// holding a clone of `socket`. The options apply to every peer, except that no session is
+    /// recorded and warnings about stray packets are dropped.
+    pub fn punch_hole_many<K>(self,
+                              socket: UdpSocket,
+                              peers: Vec<(K, PrivRendezvousInfo, PubRendezvousInfo)>)
+                              -> HashMap<K, Result<PunchedUdpSocket, UdpPunchHoleError>>
+        where K: Hash + Eq
+    {
+        punch_hole_many_impl(socket, peers, self)
+    }
+
+    /// Take the passive side of a one-sided punch: wait on `socket`, which should be reachable
+    /// already (eg. port-forwarded or mapped through UPnP), for a hole punch packet from any
+    /// peer and acknowledge it. The initiating peer punches as usual, using rendezvous info
+    /// carrying this socket's address, which can be generated once and published ahead of time.
+    /// No rendezvous info is needed on this side, so without a key from `sealed` any peer can
+    /// connect.
+    pub fn respond_to_punch<S: DatagramTransport>(self, socket: S)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        PunchedUdpSocket::respond_to_punch_impl(socket, self)
+    }
 }
 
 /// Used for reporting warnings inside `UdpPunchHoleWarning`
@@ -45,11 +475,15 @@ pub struct HolePunchPacketData {
 }
 
 /// A udp socket that has been hole punched.
-pub struct PunchedUdpSocket {
+#[derive(Debug)]
+pub struct PunchedUdpSocket<S = UdpSocket> {
     /// The UDP socket.
-    pub socket: UdpSocket,
+    pub socket: S,
     /// The remote address that this socket is able to send messages to and receive messages from.
     pub peer_addr: SocketAddr,
+    /// Our address that the peer's packets arrived on, if the transport could tell. For a socket
+    /// bound to the unspecified address this is the host candidate that the peer reached.
+    pub local_ip: Option<IpAddr>,
 }
 
 quick_error! {
@@ -85,9 +519,35 @@ quick_error! {
             err: io::Error,
         } {
             description("IO error trying to send a message to one of the peer's potential endpoints.")
-            display("IO error trying to send a message to endpoint {:?}. {}", endpoint, err)
+            display("IO error trying to send a message to endpoint {}. {}",
+                    Redacted(endpoint.addr), err)
             cause(err)
         }
+        /// Received a packet that wasn't sealed with the punch key while hole punching.
+        UnauthenticatedPacket {
+            description("Received a packet that wasn't sealed with the punch key while hole \
+                         punching.")
+        }
+    }
+}
+
+impl ErrorCode for UdpPunchHoleWarning {
+    fn code(&self) -> u32 {
+        match *self {
+            UdpPunchHoleWarning::UnexpectedHolePunchPacket { .. } => 601,
+            UdpPunchHoleWarning::InvalidHolePunchPacket { .. } => 602,
+            UdpPunchHoleWarning::MsgEndpoint { .. } => 603,
+            UdpPunchHoleWarning::UnauthenticatedPacket => 604,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            UdpPunchHoleWarning::UnexpectedHolePunchPacket { .. } => ErrorCategory::Protocol,
+            UdpPunchHoleWarning::InvalidHolePunchPacket { .. } => ErrorCategory::Protocol,
+            UdpPunchHoleWarning::MsgEndpoint { .. } => ErrorCategory::Network,
+            UdpPunchHoleWarning::UnauthenticatedPacket => ErrorCategory::Protocol,
+        }
     }
 }
 
@@ -126,38 +586,205 @@ impl From<UdpPunchHoleError> for io::Error {
     }
 }
 
-impl PunchedUdpSocket {
+impl ErrorCode for UdpPunchHoleError {
+    fn code(&self) -> u32 {
+        match *self {
+            UdpPunchHoleError::TimedOut => 701,
+            UdpPunchHoleError::Io { .. } => 702,
+            UdpPunchHoleError::SendCompleteAck => 703,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            UdpPunchHoleError::TimedOut => ErrorCategory::Network,
+            UdpPunchHoleError::Io { .. } => ErrorCategory::Network,
+            UdpPunchHoleError::SendCompleteAck => ErrorCategory::Network,
+        }
+    }
+}
+
+impl<S: DatagramTransport> PunchedUdpSocket<S> {
     /// Punch a udp socket using a mapped socket and the peer's rendezvous info.
-    pub fn punch_hole(socket: UdpSocket,
+    pub fn punch_hole(socket: S,
                       our_priv_rendezvous_info: PrivRendezvousInfo,
                       their_pub_rendezvous_info: PubRendezvousInfo,
                       deadline: Instant)
-        -> WResult<PunchedUdpSocket, UdpPunchHoleWarning, UdpPunchHoleError>
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        Self::punch_hole_with_events(socket,
+                                     our_priv_rendezvous_info,
+                                     their_pub_rendezvous_info,
+                                     deadline,
+                                     &EventSender::new())
+    }
+
+    /// Like `punch_hole` but reports the progress of the hole punching through `events`.
+    pub fn punch_hole_with_events(socket: S,
+                                  our_priv_rendezvous_info: PrivRendezvousInfo,
+                                  their_pub_rendezvous_info: PubRendezvousInfo,
+                                  deadline: Instant,
+                                  events: &EventSender)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        UdpPunchBuilder::new(deadline)
+            .events(events)
+            .punch_hole(socket, our_priv_rendezvous_info, their_pub_rendezvous_info)
+    }
+
+    /// Like `punch_hole` but records every packet sent and received through `recorder`. The
+    /// resulting `SessionRecord` can be saved and later fed to `replay_udp_punch`.
+    pub fn punch_hole_recorded(socket: S,
+                               our_priv_rendezvous_info: PrivRendezvousInfo,
+                               their_pub_rendezvous_info: PubRendezvousInfo,
+                               deadline: Instant,
+                               recorder: &SessionRecorder)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        UdpPunchBuilder::new(deadline)
+            .recorder(recorder)
+            .punch_hole(socket, our_priv_rendezvous_info, their_pub_rendezvous_info)
+    }
+
+    /// Like `punch_hole` but measures `deadline` and the delays between resends on `clock`.
+    pub fn punch_hole_with_clock(socket: S,
+                                 our_priv_rendezvous_info: PrivRendezvousInfo,
+                                 their_pub_rendezvous_info: PubRendezvousInfo,
+                                 deadline: Instant,
+                                 clock: &Clock)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        UdpPunchBuilder::new(deadline)
+            .clock(clock)
+            .punch_hole(socket, our_priv_rendezvous_info, their_pub_rendezvous_info)
+    }
+
+    /// Like `punch_hole_with_events` but sends to the peer's endpoints in bursts within `limits`,
+    /// usually those of the `MappingContext` the socket was mapped with.
+    pub fn punch_hole_with_limits(socket: S,
+                                  our_priv_rendezvous_info: PrivRendezvousInfo,
+                                  their_pub_rendezvous_info: PubRendezvousInfo,
+                                  deadline: Instant,
+                                  events: &EventSender,
+                                  limits: ConcurrencyLimits)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        UdpPunchBuilder::new(deadline)
+            .events(events)
+            .limits(limits)
+            .punch_hole(socket, our_priv_rendezvous_info, their_pub_rendezvous_info)
+    }
+
+    /// Like `punch_hole_with_events` but seals every hole punch packet with `key`, which the peer
+    /// must use too. Packets not sealed with the key are ignored. Use
+    /// `filter_sealed_udp_hole_punch_packet` to filter out late hole punch packets afterwards.
+    pub fn punch_hole_sealed(socket: S,
+                             our_priv_rendezvous_info: PrivRendezvousInfo,
+                             their_pub_rendezvous_info: PubRendezvousInfo,
+                             deadline: Instant,
+                             events: &EventSender,
+                             key: &PunchKey)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        UdpPunchBuilder::new(deadline)
+            .events(events)
+            .sealed(key)
+            .punch_hole(socket, our_priv_rendezvous_info, their_pub_rendezvous_info)
+    }
+
+    /// Like `punch_hole_with_limits` with the events and limits of `mc`, and sharing its cap on
+    /// probes with the other traversal attempts using it.
+    pub fn punch_hole_in_context(socket: S,
+                                 our_priv_rendezvous_info: PrivRendezvousInfo,
+                                 their_pub_rendezvous_info: PubRendezvousInfo,
+                                 deadline: Instant,
+                                 mc: &MappingContext)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        UdpPunchBuilder::new(deadline)
+            .in_context(mc)
+            .punch_hole(socket, our_priv_rendezvous_info, their_pub_rendezvous_info)
+    }
+
+    /// Like `punch_hole_in_context` but, when an endpoint times out, gives the reason from any
+    /// ICMP error that `observer` saw about it in the `Event::CheckFailed` raised for it, eg.
+    /// telling a firewall apart from a missing route.
+    pub fn punch_hole_observed(socket: S,
+                               our_priv_rendezvous_info: PrivRendezvousInfo,
+                               their_pub_rendezvous_info: PubRendezvousInfo,
+                               deadline: Instant,
+                               mc: &MappingContext,
+                               observer: &IcmpObserver)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        UdpPunchBuilder::new(deadline)
+            .in_context(mc)
+            .icmp_observer(observer)
+            .punch_hole(socket, our_priv_rendezvous_info, their_pub_rendezvous_info)
+    }
+
+    /// Take the passive side of a one-sided punch. See `UdpPunchBuilder::respond_to_punch`.
+    pub fn respond_to_punch(socket: S, deadline: Instant)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        UdpPunchBuilder::new(deadline).respond_to_punch(socket)
+    }
+
+    fn punch_hole_impl(socket: S,
+                       our_priv_rendezvous_info: PrivRendezvousInfo,
+                       their_pub_rendezvous_info: PubRendezvousInfo,
+                       options: UdpPunchBuilder)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
     {
         let mut warnings = Vec::new();
+        let deadline = options.deadline;
+        let no_events = EventSender::new();
+        let events = options.events.unwrap_or(&no_events);
+        let system_clock = SystemClock;
+        let clock = options.clock.unwrap_or(&system_clock);
 
         let (mut endpoints, their_secret)
             = rendezvous_info::decompose(their_pub_rendezvous_info);
-        let our_secret
-            = rendezvous_info::get_priv_secret(our_priv_rendezvous_info);
+        if let Some(filter) = options.candidate_filter {
+            endpoints.retain(|endpoint| filter(endpoint));
+        }
+        let our_endpoints = rendezvous_info::get_priv_endpoints(&our_priv_rendezvous_info);
+        let our_secrets
+            = rendezvous_info::get_priv_secrets(our_priv_rendezvous_info);
+        let our_secret = our_secrets[0];
+        if let Some(recorder) = options.recorder {
+            recorder.record_secrets(our_secret, their_secret);
+            recorder.record_deadline(deadline);
+            if let Some(key) = options.key {
+                recorder.record_key(key);
+            }
+        }
 
         // Cbor seems to serialize into bytes of different sizes and
         // it sometimes exceeded 16 bytes, let's be safe and use 128.
         const MAX_DATAGRAM_SIZE: usize = 128;
 
+        let mut send_buf = [0u8; MAX_DATAGRAM_SIZE];
         let send_data = {
             let hole_punch = HolePunch {
                 secret: our_secret,
                 ack: false,
+                nominate: false,
             };
 
-            serialise(&hole_punch).unwrap()
+            // Can't fail: the message has a fixed size well under `MAX_DATAGRAM_SIZE`.
+            let len = unwrap_result!(hole_punch.encode_into(&mut send_buf));
+            &send_buf[..len]
         };
-
-        assert!(send_data.len() <= MAX_DATAGRAM_SIZE,
-                format!("Data exceed MAX_DATAGRAM_SIZE in blocking_udp_punch_hole: {} > {}",
-                        send_data.len(),
-                        MAX_DATAGRAM_SIZE));
+        let sealed_send_data;
+        let send_data = match options.key {
+            Some(key) => {
+                sealed_send_data = punch_crypto::seal(key, send_data);
+                &sealed_send_data[..]
+            },
+            None => send_data,
+        };
+        let nomination_data = encode_nomination(our_secret, false, options.key);
 
         let mut recv_data = [0u8; MAX_DATAGRAM_SIZE];
 
@@ -205,113 +832,748 @@ impl PunchedUdpSocket {
         // Spend TOTAL_TIMEOUT_MS trying to get their actual address that we can
         // communicate with.
 
-        const DELAY_BETWEEN_RESENDS_MS: u64 = 600;
+        if let Some(start) = options.start_at {
+            let start = cmp::min(start, deadline);
+            let now = clock.now();
+            if start > now {
+                clock.sleep(start - now);
+            }
+        }
 
-        let mut recv_deadline = Instant::now();
-        while recv_deadline < deadline {
-            recv_deadline = recv_deadline + Duration::from_millis(DELAY_BETWEEN_RESENDS_MS);
-            let mut i = 0;
-            while i < endpoints.len() {
-                // TODO(canndrew): How should we handle partial write?
-                let _ = match socket.send_to(&send_data[..], &*endpoints[i].addr) {
-                    Ok(n) => n,
-                    Err(e) => {
-                        warnings.push(UdpPunchHoleWarning::MsgEndpoint {
-                            endpoint: endpoints.swap_remove(i),
-                            err: e,
-                        });
-                        continue;
+        let mut pacer = Pacer::new(clock, options.limits.max_packets_per_sec,
+                                   options.limits.packet_burst);
+        let local_port = socket.local_addr().ok().map(|addr| addr.port());
+        let mut recv_deadline = clock.now();
+        let check_deadline = options.check_timeout.map(|timeout| recv_deadline + timeout);
+        // Both peers pair up the endpoints they advertised, so that they agree on the pairs'
+        // priorities. Info generated without endpoints falls back to the socket's own address.
+        let local_endpoints: Vec<MappedSocketAddr> = if our_endpoints.is_empty() {
+            socket.local_addr().ok().map(MappedSocketAddr::direct).into_iter().collect()
+        } else {
+            our_endpoints
+        };
+        let controlling = our_secret > their_secret;
+        let mut checklist = Checklist::new(&local_endpoints, &endpoints, controlling);
+        // Endpoints whose checks haven't started yet. Each round of resends only goes to the
+        // endpoints whose checks have.
+        let mut pending = match options.nomination_window {
+            Some(..) => {
+                let mut remotes: Vec<MappedSocketAddr> = Vec::new();
+                for pair in checklist.pairs() {
+                    if !remotes.contains(&pair.remote) {
+                        remotes.push(pair.remote.clone());
                     }
-                };
-                i += 1;
+                }
+                remotes
+            },
+            None => interleave_candidate_classes(endpoints),
+        };
+        // When nominating, the latest the controlling side waits for a better pair once one has
+        // answered, and once it has nominated one, the peer's endpoint on it.
+        let mut nominate_by = None;
+        let mut nominated: Option<MappedSocketAddr> = None;
+        let new_check_interval = options.limits.new_check_interval;
+        let first_checks = if new_check_interval == Duration::new(0, 0) {
+            pending.len()
+        } else {
+            cmp::min(pending.len(), 1)
+        };
+        let mut endpoints: Vec<MappedSocketAddr> = pending.drain(..first_checks).collect();
+        for endpoint in &endpoints {
+            events.send(Event::CheckStarted { peer_addr: endpoint.addr });
+        }
+        let mut next_check = recv_deadline + new_check_interval;
+        while recv_deadline < deadline {
+            if let Some(check_deadline) = check_deadline {
+                let checking = !(endpoints.is_empty() && pending.is_empty());
+                if recv_deadline >= check_deadline && checking && nominated.is_none() {
+                    report_timed_out(events, options.icmp, local_port, &endpoints);
+                    report_timed_out(events, options.icmp, local_port, &pending);
+                    endpoints.clear();
+                    pending.clear();
+                }
+            }
+            recv_deadline = recv_deadline + options.resend_interval;
+            // Once a pair is nominated, only the nomination is sent, until the peer acks it.
+            let data = if nominated.is_some() { &nomination_data[..] } else { send_data };
+            let (remaining, mut send_failed) = send_checks(&socket,
+                                                           &endpoints,
+                                                           data,
+                                                           &options,
+                                                           clock,
+                                                           &mut pacer,
+                                                           events,
+                                                           &mut warnings);
+            endpoints = remaining;
+            // Routers which rate limit often fail our sends or silently drop everything, so slow
+            // down if either happens.
+            if send_failed {
+                pacer.back_off();
             }
-            // Keep reading until it's time to send to all endpoints again.
+            let mut received_any = false;
+            // Keep reading until it's time to send to all endpoints again, starting the checks of
+            // further endpoints as they fall due.
             loop {
-                let (read_size, addr) = match socket.recv_until(&mut recv_data[..], recv_deadline) {
+                let now = clock.now();
+                if nominate_by.map_or(false, |by| now >= by) {
+                    nominate_by = None;
+                    nominated = start_nomination(&checklist, &mut endpoints, &mut pending);
+                    // Send the nomination straight away.
+                    recv_deadline = now;
+                    break;
+                }
+                if !pending.is_empty() && now >= next_check {
+                    let endpoint = pending.remove(0);
+                    events.send(Event::CheckStarted { peer_addr: endpoint.addr });
+                    let (started, failed) = send_checks(&socket,
+                                                        &[endpoint],
+                                                        send_data,
+                                                        &options,
+                                                        clock,
+                                                        &mut pacer,
+                                                        events,
+                                                        &mut warnings);
+                    endpoints.extend(started);
+                    send_failed = send_failed || failed;
+                    next_check = now + new_check_interval;
+                }
+                let mut wait_until = if pending.is_empty() {
+                    recv_deadline
+                } else {
+                    cmp::min(recv_deadline, next_check)
+                };
+                if let Some(by) = nominate_by {
+                    wait_until = cmp::min(wait_until, by);
+                }
+                let system_recv_deadline = clock::system_deadline(clock, wait_until);
+                let res = socket.recv_until_with_local_ip(&mut recv_data[..],
+                                                          system_recv_deadline);
+                let (read_size, addr, local_ip) = match res {
                     Ok(Some(x)) => x,
+                    Ok(None) if wait_until < recv_deadline => continue,
                     Ok(None) => break,
                     Err(e) => return WErr(UdpPunchHoleError::Io { err: e }),
                 };
-                match deserialise::<HolePunch>(&recv_data[..read_size]) {
-                    Ok(hp) => {
-                        if hp.secret == our_secret && hp.ack {
+                received_any = true;
+                if let Some(recorder) = options.recorder {
+                    recorder.record_received(addr, &recv_data[..read_size]);
+                }
+                let kind = classify_sealed_packet(&recv_data[..read_size],
+                                                  options.key,
+                                                  &our_secrets,
+                                                  their_secret);
+                let nominating = options.nomination_window.is_some();
+                let nomination = match kind {
+                    PacketKind::Nomination | PacketKind::NominationAck => true,
+                    _ => false,
+                };
+                match kind {
+                    // The controlled side connects over whichever pair the controlling side
+                    // nominates.
+                    PacketKind::Nomination if nominating && !controlling => {
+                        return match send_acks(&socket,
+                                               addr,
+                                               their_secret,
+                                               true,
+                                               &options,
+                                               clock,
+                                               &mut pacer,
+                                               events) {
+                            Ok(()) => {
+                                report_connected(events, addr);
+                                WOk(PunchedUdpSocket {
+                                    socket: socket,
+                                    peer_addr: addr,
+                                    local_ip: local_ip,
+                                }, warnings)
+                            },
+                            Err(e) => WErr(e),
+                        };
+                    },
+                    PacketKind::NominationAck if nominating => {
+                        if nominated.as_ref().map_or(false, |endpoint| endpoint.addr == addr) {
+                            report_connected(events, addr);
                             return WOk(PunchedUdpSocket {
                                 socket: socket,
                                 peer_addr: addr,
+                                local_ip: local_ip,
                             }, warnings);
                         }
-                        if hp.secret == their_secret {
-                            let send_data = {
-                                let hole_punch = HolePunch {
-                                    secret: their_secret,
-                                    ack: true,
-                                };
-
-                                serialise(&hole_punch).unwrap()
-                            };
-
-                            assert!(send_data.len() <= MAX_DATAGRAM_SIZE,
-                                    format!("Data exceed MAX_DATAGRAM_SIZE in blocking_udp_punch_hole: {} > {}",
-                                            send_data.len(),
-                                            MAX_DATAGRAM_SIZE));
-
-                            let mut attempts = 0;
-                            let mut successful_attempts = 0;
-                            let mut error = None;
-                            while attempts < 2 || Instant::now() < deadline {
-                                attempts += 1;
-                                match socket.send_to(&send_data[..], &*addr) {
-                                    Ok(n) => {
-                                        if n == send_data.len() {
-                                            successful_attempts += 1;
-                                            if successful_attempts == 2 {
-                                                break;
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        if error.is_none() {
-                                            error = Some(e);
-                                        }
-                                    }
-                                };
-                                thread::sleep(Duration::from_millis(100));
+                    },
+                    // Only an ack shows that a pair works both ways. The peer's hole punch packets
+                    // are answered but prove nothing, as our acks may never arrive.
+                    PacketKind::Ack if nominating => {
+                        checklist.record_success(*addr, None);
+                        if controlling && nominated.is_none() {
+                            if checklist.is_settled() {
+                                nominate_by = None;
+                                nominated = start_nomination(&checklist,
+                                                             &mut endpoints,
+                                                             &mut pending);
+                                recv_deadline = clock.now();
+                                break;
                             }
-                            if successful_attempts == 0 {
-                                let ret = match error {
-                                    Some(e) => UdpPunchHoleError::Io { err: e },
-                                    None => UdpPunchHoleError::SendCompleteAck,
-                                };
-                                return WErr(ret);
+                            if nominate_by.is_none() {
+                                let now = clock.now();
+                                nominate_by = options.nomination_window.map(|window| now + window);
                             }
-                            else {
-                                return WOk(PunchedUdpSocket {
+                        }
+                    },
+                    PacketKind::Punch | PacketKind::Nomination if nominating => {
+                        if let Err(e) = send_acks(&socket,
+                                                  addr,
+                                                  their_secret,
+                                                  nomination,
+                                                  &options,
+                                                  clock,
+                                                  &mut pacer,
+                                                  events) {
+                            return WErr(e);
+                        }
+                    },
+                    PacketKind::Ack | PacketKind::NominationAck => {
+                        report_connected(events, addr);
+                        return WOk(PunchedUdpSocket {
+                            socket: socket,
+                            peer_addr: addr,
+                            local_ip: local_ip,
+                        }, warnings);
+                    },
+                    PacketKind::Punch | PacketKind::Nomination => {
+                        return match send_acks(&socket,
+                                               addr,
+                                               their_secret,
+                                               nomination,
+                                               &options,
+                                               clock,
+                                               &mut pacer,
+                                               events) {
+                            Ok(()) => {
+                                report_connected(events, addr);
+                                WOk(PunchedUdpSocket {
                                     socket: socket,
                                     peer_addr: addr,
-                                }, warnings);
-                            }
-                        }
+                                    local_ip: local_ip,
+                                }, warnings)
+                            },
+                            Err(e) => WErr(e),
+                        };
+                    },
+                    kind => {
                         // Protect against a malicious peer sending us loads of spurious data.
-                        if warnings.len() < 10 {
-                            warnings.push(UdpPunchHoleWarning::UnexpectedHolePunchPacket {
-                                hole_punch: HolePunchPacketData {
-                                    data: hp,
-                                },
-                            });
+                        if warnings.len() < MAX_WARNINGS {
+                            warnings.extend(kind.into_warning());
                         }
-                    }
-                    Err(e) => {
-                        // Protect against a malicious peer sending us loads of spurious data.
-                        if warnings.len() < 10 {
-                            warnings.push(UdpPunchHoleWarning::InvalidHolePunchPacket {
-                                err: e,
-                            });
+                    },
+                };
+            }
+            if !received_any && !send_failed {
+                pacer.back_off();
+            }
+        }
+        report_timed_out(events, options.icmp, local_port, &endpoints);
+        report_timed_out(events, options.icmp, local_port, &pending);
+        WErr(UdpPunchHoleError::TimedOut)
+    }
+
+    fn respond_to_punch_impl(socket: S, options: UdpPunchBuilder)
+        -> WResult<PunchedUdpSocket<S>, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        let mut warnings = Vec::new();
+        let no_events = EventSender::new();
+        let events = options.events.unwrap_or(&no_events);
+        let system_clock = SystemClock;
+        let clock = options.clock.unwrap_or(&system_clock);
+        let mut pacer = Pacer::new(clock, options.limits.max_packets_per_sec,
+                                   options.limits.packet_burst);
+
+        let mut recv_data = [0u8; 128];
+        loop {
+            let system_deadline = clock::system_deadline(clock, options.deadline);
+            let res = socket.recv_until_with_local_ip(&mut recv_data[..], system_deadline);
+            let (read_size, addr, local_ip) = match res {
+                Ok(Some(x)) => x,
+                Ok(None) => return WErr(UdpPunchHoleError::TimedOut),
+                Err(e) => return WErr(UdpPunchHoleError::Io { err: e }),
+            };
+            if let Some(recorder) = options.recorder {
+                recorder.record_received(addr, &recv_data[..read_size]);
+            }
+            let opened;
+            let data = match options.key {
+                Some(key) => match punch_crypto::open(key, &recv_data[..read_size]) {
+                    Some(data) => {
+                        opened = data;
+                        &opened[..]
+                    },
+                    None => {
+                        if warnings.len() < MAX_WARNINGS {
+                            warnings.push(UdpPunchHoleWarning::UnauthenticatedPacket);
                         }
+                        continue;
+                    },
+                },
+                None => &recv_data[..read_size],
+            };
+            // We don't know the peer's secret, so whoever sends a hole punch packet gets it
+            // echoed back as the ack they're waiting for.
+            let kind = match HolePunch::decode(data) {
+                Ok(ref hp) if !hp.ack => {
+                    events.send(Event::CheckStarted { peer_addr: addr });
+                    return match send_acks(&socket,
+                                           addr,
+                                           hp.secret,
+                                           hp.nominate,
+                                           &options,
+                                           clock,
+                                           &mut pacer,
+                                           events) {
+                        Ok(()) => {
+                            report_connected(events, addr);
+                            WOk(PunchedUdpSocket {
+                                socket: socket,
+                                peer_addr: addr,
+                                local_ip: local_ip,
+                            }, warnings)
+                        },
+                        Err(e) => WErr(e),
+                    };
+                },
+                Ok(hp) => PacketKind::Unexpected(HolePunchPacketData { data: hp }),
+                Err(e) => PacketKind::Invalid(e),
+            };
+            if warnings.len() < MAX_WARNINGS {
+                warnings.extend(kind.into_warning());
+            }
+        }
+    }
+}
+
+// Drive a `UdpPunchStateMachine` for each peer over the shared socket, offering every packet
+// received to each of them. Only the machine whose secrets the packet carries acts on it.
+fn punch_hole_many_impl<K>(socket: UdpSocket,
+                           peers: Vec<(K, PrivRendezvousInfo, PubRendezvousInfo)>,
+                           options: UdpPunchBuilder)
+                           -> HashMap<K, Result<PunchedUdpSocket, UdpPunchHoleError>>
+    where K: Hash + Eq
+{
+    let deadline = options.deadline;
+    let no_events = EventSender::new();
+    let events = options.events.unwrap_or(&no_events);
+    let system_clock = SystemClock;
+    let clock = options.clock.unwrap_or(&system_clock);
+    if let Some(start) = options.start_at {
+        let start = cmp::min(start, deadline);
+        let now = clock.now();
+        if start > now {
+            clock.sleep(start - now);
+        }
+    }
+
+    let now = clock.now();
+    let mut machines = Vec::with_capacity(peers.len());
+    for (id, our_priv_rendezvous_info, mut their_pub_rendezvous_info) in peers {
+        if let Some(filter) = options.candidate_filter {
+            let endpoints = their_pub_rendezvous_info.endpoints()
+                                                     .iter()
+                                                     .filter(|endpoint| filter(endpoint))
+                                                     .cloned()
+                                                     .collect();
+            their_pub_rendezvous_info =
+                rendezvous_info::with_endpoints(their_pub_rendezvous_info, endpoints);
+        }
+        for endpoint in their_pub_rendezvous_info.endpoints() {
+            events.send(Event::CheckStarted { peer_addr: endpoint.addr });
+        }
+        let mut machine = UdpPunchStateMachine::new(our_priv_rendezvous_info,
+                                                    their_pub_rendezvous_info,
+                                                    now,
+                                                    deadline)
+                              .resend_interval(options.resend_interval)
+                              .acks(options.acks);
+        if let Some(key) = options.key {
+            machine = machine.sealed(key.clone());
+        }
+        machines.push((id, machine));
+    }
+
+    let mut pacer = Pacer::new(clock, options.limits.max_packets_per_sec,
+                               options.limits.packet_burst);
+    // Our address that each peer's packets last arrived on.
+    let mut local_ips = HashMap::new();
+    let mut recv_data = [0u8; 128];
+    let mut failure = None;
+    loop {
+        for &mut (_, ref mut machine) in &mut machines {
+            while let Some((addr, packet)) = machine.next_outgoing() {
+                pacer.take(clock, 1);
+                if let Some(shaper) = options.shaper {
+                    shaper.take(clock, &addr);
+                }
+                // A failed send is just another lost packet. The peer times out if they all
+                // fail.
+                let _ = socket.send_to(&packet, addr);
+            }
+        }
+        let next_timeout = machines.iter().filter_map(|&(_, ref machine)| machine.poll_timeout())
+                                   .min();
+        let next_timeout = match next_timeout {
+            Some(next_timeout) => next_timeout,
+            None => break,
+        };
+        let system_deadline = clock::system_deadline(clock, next_timeout);
+        match socket.recv_until_with_local_ip(&mut recv_data[..], system_deadline) {
+            Ok(Some((read_size, addr, local_ip))) => {
+                if let Some(local_ip) = local_ip {
+                    let _ = local_ips.insert(*addr, local_ip);
+                }
+                let now = clock.now();
+                for &mut (_, ref mut machine) in &mut machines {
+                    if machine.status() == UdpPunchStatus::InProgress {
+                        machine.handle_packet(*addr, &recv_data[..read_size], now);
+                        let _ = machine.take_warnings();
                     }
+                }
+            },
+            Ok(None) => (),
+            Err(e) => {
+                failure = Some(e);
+                break;
+            },
+        }
+        let now = clock.now();
+        for &mut (_, ref mut machine) in &mut machines {
+            machine.handle_timeout(now);
+        }
+    }
+
+    let mut outcomes = HashMap::new();
+    for (id, machine) in machines {
+        let outcome = match machine.status() {
+            UdpPunchStatus::Connected(peer_addr) => {
+                report_connected(events, SocketAddr(peer_addr));
+                match socket.try_clone() {
+                    Ok(socket) => {
+                        Ok(PunchedUdpSocket {
+                            socket: socket,
+                            peer_addr: SocketAddr(peer_addr),
+                            local_ip: local_ips.get(&peer_addr).cloned(),
+                        })
+                    },
+                    Err(e) => Err(UdpPunchHoleError::Io { err: e }),
+                }
+            },
+            UdpPunchStatus::TimedOut => Err(UdpPunchHoleError::TimedOut),
+            // Only left in progress if the socket failed.
+            UdpPunchStatus::InProgress => {
+                let err = match failure {
+                    Some(ref e) => io::Error::new(e.kind(), format!("{}", e)),
+                    None => io::Error::new(io::ErrorKind::Other, "hole punching stopped"),
                 };
+                Err(UdpPunchHoleError::Io { err: err })
+            },
+        };
+        let _ = outcomes.insert(id, outcome);
+    }
+    outcomes
+}
+
+// Acknowledge a hole punch packet carrying `secret` from `addr`, sending `options.acks` acks
+// with a delay in between. Nominations get nomination acks. Raises `Event::CheckFailed` if none
+// could be sent.
+fn send_acks<S: DatagramTransport>(socket: &S,
+                                   addr: SocketAddr,
+                                   secret: [u8; 4],
+                                   nomination: bool,
+                                   options: &UdpPunchBuilder,
+                                   clock: &Clock,
+                                   pacer: &mut Pacer,
+                                   events: &EventSender)
+                                   -> Result<(), UdpPunchHoleError> {
+    let send_data = if nomination {
+        encode_nomination(secret, true, options.key)
+    } else {
+        encode_hole_punch(secret, true, options.key)
+    };
+    let mut attempts = 0;
+    let mut successful_attempts = 0;
+    let mut error = None;
+    while attempts < options.acks || clock.now() < options.deadline {
+        attempts += 1;
+        pacer.take(clock, 1);
+        if let Some(shaper) = options.shaper {
+            shaper.take(clock, &addr);
+        }
+        if let Some(recorder) = options.recorder {
+            recorder.record_sent(addr, &send_data[..]);
+        }
+        match socket.send_to(&send_data[..], &*addr) {
+            Ok(n) => {
+                if n == send_data.len() {
+                    successful_attempts += 1;
+                    if successful_attempts == options.acks {
+                        break;
+                    }
+                }
+            }
+            Err(e) => {
+                if error.is_none() {
+                    error = Some(e);
+                }
+            }
+        };
+        clock.sleep(Duration::from_millis(100));
+    }
+    if successful_attempts == 0 {
+        let ret = match error {
+            Some(e) => UdpPunchHoleError::Io { err: e },
+            None => UdpPunchHoleError::SendCompleteAck,
+        };
+        events.send(Event::CheckFailed {
+            peer_addr: addr,
+            reason: format!("{}", ret),
+        });
+        return Err(ret);
+    }
+    Ok(())
+}
+
+// Order endpoints so that host, server reflexive and predicted endpoints take turns, keeping the
+// order within each class, so that paced checks try every kind early on.
+fn interleave_candidate_classes(endpoints: Vec<MappedSocketAddr>) -> Vec<MappedSocketAddr> {
+    let mut classes = [Vec::new(), Vec::new(), Vec::new()];
+    for endpoint in endpoints {
+        let class = match endpoint.candidate_class() {
+            CandidateClass::Host => 0,
+            CandidateClass::Reflexive => 1,
+            CandidateClass::Predicted => 2,
+        };
+        classes[class].push(endpoint);
+    }
+    let longest = classes.iter().map(|class| class.len()).max().unwrap_or(0);
+    let mut interleaved = Vec::new();
+    for i in 0..longest {
+        for class in &classes {
+            if let Some(endpoint) = class.get(i) {
+                interleaved.push(endpoint.clone());
             }
         }
-        WErr(UdpPunchHoleError::TimedOut)
+    }
+    interleaved
+}
+
+// Send hole punch packets to `endpoints` in paced bursts, each in as few system calls as the
+// transport allows. Returns the endpoints sent to and whether any send failed.
+fn send_checks<S: DatagramTransport>(socket: &S,
+                                     endpoints: &[MappedSocketAddr],
+                                     send_data: &[u8],
+                                     options: &UdpPunchBuilder,
+                                     clock: &Clock,
+                                     pacer: &mut Pacer,
+                                     events: &EventSender,
+                                     warnings: &mut Vec<UdpPunchHoleWarning>)
+                                     -> (Vec<MappedSocketAddr>, bool) {
+    if let Some(recorder) = options.recorder {
+        for endpoint in endpoints {
+            recorder.record_sent(endpoint.addr, send_data);
+        }
+    }
+    let mut remaining = Vec::with_capacity(endpoints.len());
+    let mut send_failed = false;
+    let burst_size = cmp::max(cmp::min(options.limits.max_parallel_checks,
+                                       options.limits.packet_burst as usize), 1);
+    for (i, burst) in endpoints.chunks(burst_size).enumerate() {
+        if i > 0 {
+            clock.sleep(options.limits.check_pacing);
+        }
+        pacer.take(clock, burst.len() as u32);
+        if let Some(shaper) = options.shaper {
+            for endpoint in burst {
+                shaper.take(clock, &endpoint.addr);
+            }
+        }
+        let datagrams: Vec<(&[u8], net::SocketAddr)> =
+            burst.iter().map(|endpoint| (send_data, *endpoint.addr)).collect();
+        let results = socket.send_batch(&datagrams);
+        for (endpoint, result) in burst.iter().zip(results) {
+            // TODO(canndrew): How should we handle partial write?
+            match result {
+                Ok(_) => remaining.push(endpoint.clone()),
+                Err(e) => {
+                    send_failed = true;
+                    events.send(Event::CheckFailed {
+                        peer_addr: endpoint.addr,
+                        reason: format!("{}", e),
+                    });
+                    warnings.push(UdpPunchHoleWarning::MsgEndpoint {
+                        endpoint: endpoint.clone(),
+                        err: e,
+                    });
+                },
+            }
+        }
+    }
+    (remaining, send_failed)
+}
+
+impl PunchedUdpSocket<UdpSocket> {
+    /// Connect the socket to `peer_addr` so that `send` and `recv` can be used in place of
+    /// `send_to` and `recv_from`. The OS then drops datagrams from anywhere else, and reports ICMP
+    /// errors about the peer, eg. port unreachable once it has closed its socket, as an error from
+    /// the next `send` or `recv`. Use `is_peer_unreachable` to recognise those.
+    pub fn connect(&self) -> io::Result<()> {
+        self.socket.connect(&*self.peer_addr)
+    }
+
+    /// Send a datagram to the peer. The socket must have been connected with `connect`.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(buf)
+    }
+
+    /// Receive a datagram from the peer, skipping any hole punch packets that arrive late. The
+    /// socket must have been connected with `connect`. Sealed hole punch packets can't be told
+    /// from data without the key, so use `filter_sealed_udp_hole_punch_packet` on what this
+    /// returns if the hole was punched with `punch_hole_sealed`.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = try!(self.socket.recv(buf));
+            if filter_udp_hole_punch_packet(&buf[..len]).is_some() {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+/// Whether an error from `send` or `recv` on a connected `PunchedUdpSocket` means that an ICMP
+/// error came back from the peer, most likely because it has closed its socket or its NAT has
+/// dropped the mapping.
+pub fn is_peer_unreachable(err: &io::Error) -> bool {
+    // Linux and macOS report port unreachable as ECONNREFUSED, Windows as WSAECONNRESET.
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => true,
+        _ => false,
+    }
+}
+
+// Raise `Event::CheckFailed` for endpoints that never answered, with the reason from any ICMP
+// error seen about them.
+fn report_timed_out(events: &EventSender,
+                    icmp: Option<&IcmpObserver>,
+                    local_port: Option<u16>,
+                    endpoints: &[MappedSocketAddr]) {
+    for endpoint in endpoints {
+        let icmp_failure = match (icmp, local_port) {
+            (Some(observer), Some(local_port)) => observer.failure(local_port, &endpoint.addr.0),
+            _ => None,
+        };
+        let reason = match icmp_failure {
+            Some(failure) => format!("{} An ICMP error said {}.", UdpPunchHoleError::TimedOut,
+                                     failure),
+            None => format!("{}", UdpPunchHoleError::TimedOut),
+        };
+        events.send(Event::CheckFailed {
+            peer_addr: endpoint.addr,
+            reason: reason,
+        });
+    }
+}
+
+// Nominate the best pair in `checklist` that answered: from now on only the nomination is sent,
+// to the peer's endpoint on that pair, and the punch completes once the peer acks it.
+fn start_nomination(checklist: &Checklist,
+                    endpoints: &mut Vec<MappedSocketAddr>,
+                    pending: &mut Vec<MappedSocketAddr>)
+                    -> Option<MappedSocketAddr> {
+    let nominated = checklist.nominated().map(|pair| pair.remote.clone());
+    if let Some(ref endpoint) = nominated {
+        *endpoints = vec![endpoint.clone()];
+        pending.clear();
+    }
+    nominated
+}
+
+fn report_connected(events: &EventSender, peer_addr: SocketAddr) {
+    events.send(Event::CheckSucceeded { peer_addr: peer_addr });
+    events.send(Event::Connected {
+        strategy: Strategy::UdpHolePunch,
+        peer_addr: peer_addr,
+    });
+}
+
+/// The outcome of replaying a recorded hole punching session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayOutcome {
+    /// The session connects to the peer at `peer_addr`, `elapsed_ms` milliseconds in.
+    Connected {
+        /// The address of the peer.
+        peer_addr: SocketAddr,
+        /// When the connection was made, relative to the start of the session.
+        elapsed_ms: u64,
+    },
+    /// None of the packets received during the session complete the hole punch.
+    TimedOut,
+}
+
+/// Feed the packets received during a recorded session back through a `UdpPunchStateMachine`.
+/// Time is taken from the record rather than the real clock, so replays are deterministic and can
+/// be used as regression tests for sessions recorded in the field. Packets arriving after the
+/// recorded deadline don't complete the punch, and sealed sessions are opened with the recorded
+/// key.
+pub fn replay_udp_punch(record: &SessionRecord) -> ReplayOutcome {
+    let start = Instant::now();
+    let at = |elapsed_ms| start + Duration::from_millis(elapsed_ms);
+    let deadline_ms = match record.deadline_ms {
+        Some(deadline_ms) => deadline_ms,
+        None => record.packets.last().map_or(0, |packet| packet.elapsed_ms) + 1,
+    };
+    // Nothing is really sent, so the machine needn't know the peer's endpoints.
+    let mut machine = punch_state_machine::from_secrets(Vec::new(),
+                                                        Secrets::new(vec![record.our_secret]),
+                                                        record.their_secret,
+                                                        start,
+                                                        at(deadline_ms));
+    if let Some(ref key) = record.punch_key {
+        machine = machine.sealed(key.clone());
+    }
+
+    let mut now = start;
+    let received = record.packets.iter().filter(|packet| packet.direction == Direction::Received);
+    for packet in received {
+        // Fire the timers that were due before the packet arrived, the deadline among them.
+        let arrival = at(packet.elapsed_ms);
+        while let Some(timeout) = machine.poll_timeout() {
+            if timeout > arrival {
+                break;
+            }
+            now = cmp::max(now, timeout);
+            machine.handle_timeout(now);
+        }
+        if machine.status() != UdpPunchStatus::InProgress {
+            break;
+        }
+        now = arrival;
+        machine.handle_packet(*packet.addr, &packet.data, now);
+        while machine.next_outgoing().is_some() {}
+    }
+    // Once the peer's packet has arrived the remaining acks are sent whatever the deadline, so
+    // run the machine to the end.
+    while let Some(timeout) = machine.poll_timeout() {
+        now = cmp::max(now, timeout);
+        machine.handle_timeout(now);
+        while machine.next_outgoing().is_some() {}
+    }
+
+    match machine.status() {
+        UdpPunchStatus::Connected(peer_addr) => {
+            let elapsed = now - start;
+            ReplayOutcome::Connected {
+                peer_addr: SocketAddr(peer_addr),
+                elapsed_ms: elapsed.as_secs() * 1000 +
+                            (elapsed.subsec_nanos() / 1_000_000) as u64,
+            }
+        },
+        UdpPunchStatus::InProgress |
+        UdpPunchStatus::TimedOut => ReplayOutcome::TimedOut,
     }
 }
 
@@ -322,12 +1584,24 @@ impl PunchedUdpSocket {
 /// hole punching succeeds it's possible that more hole punching packets sent by the remote peer
 /// may yet arrive on the socket. This function can be used to filter out those packets.
 pub fn filter_udp_hole_punch_packet(data: &[u8]) -> Option<&[u8]> {
-    match deserialise::<HolePunch>(data){
+    match HolePunch::decode(data) {
         Ok(_) => None,
         _ => Some(data),
     }
 }
 
+/// Like `filter_udp_hole_punch_packet` but for packets sent by `PunchedUdpSocket::punch_hole_sealed`.
+pub fn filter_sealed_udp_hole_punch_packet<'a>(data: &'a [u8], key: &PunchKey)
+                                               -> Option<&'a [u8]> {
+    match punch_crypto::open(key, data) {
+        Some(opened) => match HolePunch::decode(&opened) {
+            Ok(_) => None,
+            _ => Some(data),
+        },
+        None => Some(data),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::mpsc;
@@ -337,8 +1611,304 @@ mod tests {
 
     use mapping_context::MappingContext;
     use mapped_udp_socket::MappedUdpSocket;
-    use punched_udp_socket::{PunchedUdpSocket, filter_udp_hole_punch_packet};
-    use rendezvous_info::gen_rendezvous_info;
+    use maidsafe_utilities::serialisation::serialise;
+    use socket_addr::SocketAddr;
+
+    use std::net::{self, UdpSocket};
+
+    use clock::{Clock, MockClock};
+    use mapped_socket_addr::MappedSocketAddr;
+    use nat_sim::{NatBehaviour, NatConfig, SimulatedNat};
+    use simple_udp_hole_punch_server::SimpleUdpHolePunchServer;
+    use socket_utils;
+    use transport::DatagramTransport;
+    use event::{Event, EventSender};
+    use punch_crypto::{self, PunchKey};
+    use punched_udp_socket::{HolePunch, PunchedUdpSocket, ReplayOutcome, SerialisedHolePunch,
+                             UdpPunchBuilder, UdpPunchHoleError,
+                             filter_sealed_udp_hole_punch_packet,
+                             filter_udp_hole_punch_packet, interleave_candidate_classes,
+                             is_peer_unreachable, replay_udp_punch};
+    use rendezvous_info::{self, gen_rendezvous_info};
+    use session_record::{Direction, RecordedPacket, SessionRecord};
+
+    #[test]
+    fn hole_punch_codec_matches_serialise() {
+        for &(secret, ack) in &[([1, 2, 3, 4], false), ([0, 23, 24, 255], true)] {
+            let hole_punch = HolePunch {
+                secret: secret,
+                ack: ack,
+                nominate: false,
+            };
+            let serialised = SerialisedHolePunch {
+                secret: secret,
+                ack: ack,
+            };
+            let mut buf = [0u8; 128];
+            let len = unwrap_result!(hole_punch.encode_into(&mut buf));
+            assert_eq!(&buf[..len], &unwrap_result!(serialise(&serialised))[..]);
+            let decoded = unwrap_result!(HolePunch::decode(&buf[..len]));
+            assert_eq!(decoded.secret, secret);
+            assert_eq!(decoded.ack, ack);
+            assert!(!decoded.nominate);
+
+            let nomination = HolePunch {
+                secret: secret,
+                ack: ack,
+                nominate: true,
+            };
+            let len = unwrap_result!(nomination.encode_into(&mut buf));
+            let decoded = unwrap_result!(HolePunch::decode(&buf[..len]));
+            assert_eq!(decoded.secret, secret);
+            assert_eq!(decoded.ack, ack);
+            assert!(decoded.nominate);
+        }
+        assert!(HolePunch::decode(b"garbage").is_err());
+    }
+
+    #[test]
+    fn replay_recorded_session() {
+        let our_secret = [1, 2, 3, 4];
+        let their_secret = [5, 6, 7, 8];
+        let peer_addr = SocketAddr(unwrap_result!("127.0.0.1:5483".parse()));
+        let stranger_addr = SocketAddr(unwrap_result!("127.0.0.1:5484".parse()));
+
+        let stranger_punch = unwrap_result!(serialise(&SerialisedHolePunch {
+            secret: [9, 9, 9, 9],
+            ack: false,
+        }));
+        let peer_punch = unwrap_result!(serialise(&SerialisedHolePunch {
+            secret: their_secret,
+            ack: false,
+        }));
+        let packet = |elapsed_ms, direction, addr, data: &Vec<u8>| {
+            RecordedPacket {
+                elapsed_ms: elapsed_ms,
+                direction: direction,
+                addr: addr,
+                data: data.clone(),
+            }
+        };
+
+        let mut record = SessionRecord {
+            our_secret: our_secret,
+            their_secret: their_secret,
+            punch_key: None,
+            deadline_ms: Some(5000),
+            packets: vec![
+                packet(0, Direction::Received, stranger_addr, &stranger_punch),
+                packet(10, Direction::Received, peer_addr, &b"garbage".to_vec()),
+            ],
+        };
+        assert_eq!(replay_udp_punch(&record), ReplayOutcome::TimedOut);
+
+        record.packets.push(packet(650, Direction::Sent, stranger_addr, &peer_punch));
+        assert_eq!(replay_udp_punch(&record), ReplayOutcome::TimedOut);
+
+        // The peer's hole punch packet is answered with two acks, 100ms apart.
+        let mut late_record = record.clone();
+        record.packets.push(packet(900, Direction::Received, peer_addr, &peer_punch));
+        assert_eq!(replay_udp_punch(&record), ReplayOutcome::Connected {
+            peer_addr: peer_addr,
+            elapsed_ms: 1000,
+        });
+
+        late_record.packets.push(packet(5001, Direction::Received, peer_addr, &peer_punch));
+        assert_eq!(replay_udp_punch(&late_record), ReplayOutcome::TimedOut);
+    }
+
+    #[test]
+    fn replay_sealed_session() {
+        let our_secret = [1, 2, 3, 4];
+        let their_secret = [5, 6, 7, 8];
+        let peer_addr = SocketAddr(unwrap_result!("127.0.0.1:5483".parse()));
+        let key = PunchKey::derive(b"shared out of band");
+        let peer_ack = unwrap_result!(serialise(&SerialisedHolePunch {
+            secret: our_secret,
+            ack: true,
+        }));
+
+        let mut record = SessionRecord {
+            our_secret: our_secret,
+            their_secret: their_secret,
+            punch_key: None,
+            deadline_ms: Some(5000),
+            packets: vec![RecordedPacket {
+                elapsed_ms: 300,
+                direction: Direction::Received,
+                addr: peer_addr,
+                data: punch_crypto::seal(&key, &peer_ack),
+            }],
+        };
+        assert_eq!(replay_udp_punch(&record), ReplayOutcome::TimedOut);
+
+        record.punch_key = Some(key.clone());
+        assert_eq!(replay_udp_punch(&record), ReplayOutcome::Connected {
+            peer_addr: peer_addr,
+            elapsed_ms: 300,
+        });
+    }
+
+    #[test]
+    fn punch_hole_times_out_on_mock_clock() {
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let unreachable = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let endpoint = MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(unreachable.local_addr())),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        };
+        let (priv_info, _) = gen_rendezvous_info(Vec::new());
+        let (_, pub_info) = gen_rendezvous_info(vec![endpoint]);
+
+        // A deadline an hour away on the mock clock has already passed once the clock is
+        // advanced, so punching must give up without waiting in real time.
+        let clock = MockClock::new();
+        let deadline = clock.now() + Duration::from_secs(3600);
+        clock.advance(Duration::from_secs(3601));
+        let start = Instant::now();
+        let res = PunchedUdpSocket::punch_hole_with_clock(socket, priv_info, pub_info, deadline, &clock);
+        match res.result_discard() {
+            Err(UdpPunchHoleError::TimedOut) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Punched a hole to nobody"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn candidate_filter_skips_rejected_endpoints() {
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let peer = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let endpoint = MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(peer.local_addr())),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        };
+        let (priv_info, _) = gen_rendezvous_info(Vec::new());
+        let (_, pub_info) = gen_rendezvous_info(vec![endpoint]);
+
+        let reject_all = |_: &MappedSocketAddr| false;
+        let deadline = Instant::now() + Duration::from_millis(300);
+        let res = UdpPunchBuilder::new(deadline)
+            .candidate_filter(&reject_all)
+            .punch_hole(socket, priv_info, pub_info);
+        match res.result_discard() {
+            Err(UdpPunchHoleError::TimedOut) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Punched a hole to nobody"),
+        }
+        unwrap_result!(peer.set_nonblocking(true));
+        let mut buf = [0u8; 128];
+        assert!(peer.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn check_timeout_stops_sending_to_silent_endpoints() {
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let peer = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let endpoint = MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(peer.local_addr())),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        };
+        let (priv_info, _) = gen_rendezvous_info(Vec::new());
+        let (_, pub_info) = gen_rendezvous_info(vec![endpoint]);
+
+        let events = EventSender::new();
+        let rx = events.subscribe();
+        let deadline = Instant::now() + Duration::from_millis(600);
+        let res = UdpPunchBuilder::new(deadline)
+            .events(&events)
+            .resend_interval(Duration::from_millis(50))
+            .check_timeout(Duration::from_millis(100))
+            .punch_hole(socket, priv_info, pub_info);
+        match res.result_discard() {
+            Err(UdpPunchHoleError::TimedOut) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Punched a hole to a silent peer"),
+        }
+
+        // Resending until the deadline would have sent around a dozen packets.
+        unwrap_result!(peer.set_nonblocking(true));
+        let mut buf = [0u8; 128];
+        let mut received = 0;
+        while peer.recv_from(&mut buf).is_ok() {
+            received += 1;
+        }
+        assert!(received > 0 && received < 6, "received {} packets", received);
+
+        drop(events);
+        let failures = rx.iter()
+                         .filter(|event| match *event {
+                             Event::CheckFailed { .. } => true,
+                             _ => false,
+                         })
+                         .count();
+        assert_eq!(failures, 1);
+    }
+
+    #[test]
+    fn two_peers_udp_hole_punch_through_simulated_nats() {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let server_mc = unwrap_result!(MappingContext::new().result_discard());
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new(Box::new(server_mc),
+                                                                  deadline).result_discard());
+        let server_addr = unwrap_result!(server.addresses().into_iter().find(|addr| {
+            socket_utils::is_loopback(&addr.ip())
+        }).ok_or("No loopback address"));
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        mapping_context.add_simple_udp_servers(Some(server_addr));
+
+        let config = NatConfig::new(NatBehaviour::PortRestrictedCone);
+        let nat_0 = unwrap_result!(SimulatedNat::new(config));
+        let nat_1 = unwrap_result!(SimulatedNat::new(config));
+        let mapped_socket_0 = unwrap_result!(MappedUdpSocket::map(unwrap_result!(nat_0.bind()),
+                                                                  &mapping_context,
+                                                                  deadline).result_discard());
+        let mapped_socket_1 = unwrap_result!(MappedUdpSocket::map(unwrap_result!(nat_1.bind()),
+                                                                  &mapping_context,
+                                                                  deadline).result_discard());
+        assert!(mapped_socket_1.endpoints.iter().any(|e| e.nat_restricted));
+        // The server confirms the address it reports by sending its cookie there.
+        assert!(mapped_socket_0.endpoints.iter().any(|e| e.nat_restricted && !e.unverified));
+
+        let (priv_info_0, pub_info_0) = gen_rendezvous_info(mapped_socket_0.endpoints);
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(mapped_socket_1.endpoints);
+        let socket_0 = mapped_socket_0.socket;
+        let socket_1 = mapped_socket_1.socket;
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let jh = thread!("two_peers_udp_hole_punch_through_simulated_nats punch socket 1", move || {
+            PunchedUdpSocket::punch_hole(socket_1, priv_info_1, pub_info_0, deadline)
+                .result_discard()
+        });
+        let punched_socket_0 = unwrap_result!(PunchedUdpSocket::punch_hole(socket_0,
+                                                                           priv_info_0,
+                                                                           pub_info_1,
+                                                                           deadline)
+                                                  .result_discard());
+        let punched_socket_1 = unwrap_result!(unwrap_result!(jh.join()));
+
+        let data_send = b"through the nat";
+        let _ = unwrap_result!(punched_socket_0.socket.send_to(&data_send[..],
+                                                               &*punched_socket_0.peer_addr));
+        let mut data_recv = [0u8; 1024];
+        loop {
+            let (n, _) = unwrap_result!(unwrap_result!(punched_socket_1.socket
+                .recv_until(&mut data_recv[..], deadline)).ok_or("Timed out"));
+            match filter_udp_hole_punch_packet(&data_recv[..n]) {
+                Some(d) => {
+                    assert_eq!(&data_send[..], d);
+                    break;
+                },
+                None => continue,
+            }
+        }
+    }
 
     #[test]
     fn two_peers_udp_hole_punch_over_loopback() {
@@ -412,5 +1982,279 @@ mod tests {
         unwrap_result!(jh_0.join());
         unwrap_result!(jh_1.join());
     }
-}
 
+    #[test]
+    fn sealed_hole_punch_over_loopback() {
+        let socket_0 = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let socket_1 = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let endpoint = |socket: &UdpSocket| MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(socket.local_addr())),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        };
+        let (priv_info_0, pub_info_0) = gen_rendezvous_info(vec![endpoint(&socket_0)]);
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(vec![endpoint(&socket_1)]);
+        let key = PunchKey::derive(b"shared out of band");
+        let cloned_key = key.clone();
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let jh = thread!("sealed_hole_punch_over_loopback punch socket 1", move || {
+            let res = PunchedUdpSocket::punch_hole_sealed(socket_1,
+                                                          priv_info_1,
+                                                          pub_info_0,
+                                                          deadline,
+                                                          &EventSender::new(),
+                                                          &cloned_key);
+            unwrap_result!(res.result_discard())
+        });
+        let res = PunchedUdpSocket::punch_hole_sealed(socket_0,
+                                                      priv_info_0,
+                                                      pub_info_1,
+                                                      deadline,
+                                                      &EventSender::new(),
+                                                      &key);
+        let punched_socket_0 = unwrap_result!(res.result_discard());
+        let punched_socket_1 = unwrap_result!(jh.join());
+
+        let data_send = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        let mut data_recv = [0u8; 1024];
+        let _ = unwrap_result!(punched_socket_0.socket.send_to(&data_send[..],
+                                                               &*punched_socket_0.peer_addr));
+        loop {
+            let (n, _) = unwrap_result!(punched_socket_1.socket.recv_from(&mut data_recv[..]));
+            assert!(filter_udp_hole_punch_packet(&data_recv[..n]).is_some());
+            if let Some(d) = filter_sealed_udp_hole_punch_packet(&data_recv[..n], &key) {
+                assert_eq!(d, &data_send[..]);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn connected_punched_sockets_notice_the_peer_closing() {
+        let socket_0 = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let socket_1 = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let endpoint = |socket: &UdpSocket| MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(socket.local_addr())),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        };
+        let (priv_info_0, pub_info_0) = gen_rendezvous_info(vec![endpoint(&socket_0)]);
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(vec![endpoint(&socket_1)]);
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let jh = thread!("connected_punched_sockets punch socket 1", move || {
+            let res = PunchedUdpSocket::punch_hole(socket_1, priv_info_1, pub_info_0, deadline);
+            unwrap_result!(res.result_discard())
+        });
+        let res = PunchedUdpSocket::punch_hole(socket_0, priv_info_0, pub_info_1, deadline);
+        let punched_socket_0 = unwrap_result!(res.result_discard());
+        let punched_socket_1 = unwrap_result!(jh.join());
+        unwrap_result!(punched_socket_0.connect());
+        unwrap_result!(punched_socket_1.connect());
+
+        let mut data_recv = [0u8; 1024];
+        assert_eq!(unwrap_result!(punched_socket_0.send(b"connected")), 9);
+        let n = unwrap_result!(punched_socket_1.recv(&mut data_recv));
+        assert_eq!(&data_recv[..n], b"connected");
+
+        // Loopback delivers the port unreachable error straight away, and platforms report it on
+        // the next call after the send that provoked it.
+        drop(punched_socket_1);
+        let timeout = Some(Duration::from_secs(1));
+        unwrap_result!(punched_socket_0.socket.set_read_timeout(timeout));
+        let _ = punched_socket_0.send(b"anyone there?");
+        let err = match punched_socket_0.recv(&mut data_recv) {
+            Ok(n) => panic!("Unexpectedly received {:?}", &data_recv[..n]),
+            Err(e) => e,
+        };
+        if cfg!(any(target_os = "linux", target_os = "windows")) {
+            assert!(is_peer_unreachable(&err), "{}", err);
+        }
+    }
+
+    #[test]
+    fn candidate_classes_take_turns() {
+        let endpoint = |port, nat_restricted, port_unknown| MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(format!("1.2.3.4:{}", port).parse())),
+            nat_restricted: nat_restricted,
+            port_unknown: port_unknown,
+            unverified: false,
+        };
+        let endpoints = vec![endpoint(1, true, false),
+                             endpoint(2, true, false),
+                             endpoint(3, false, true),
+                             endpoint(4, false, false),
+                             endpoint(5, true, false)];
+        let ports: Vec<u16> = interleave_candidate_classes(endpoints)
+            .iter()
+            .map(|endpoint| endpoint.addr.port())
+            .collect();
+        assert_eq!(ports, vec![4, 1, 3, 2, 5]);
+    }
+
+    #[test]
+    fn passive_peer_answers_a_one_sided_punch() {
+        let passive = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let passive_addr = unwrap_result!(passive.local_addr());
+        let initiator = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let initiator_addr = unwrap_result!(initiator.local_addr());
+        let endpoint = MappedSocketAddr {
+            addr: SocketAddr(passive_addr),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        };
+        // Published by the passive peer ahead of time. It never needs the private half.
+        let (_, passive_info) = gen_rendezvous_info(vec![endpoint]);
+        let (initiator_priv_info, _) = gen_rendezvous_info(Vec::new());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let responder = thread!("Passive responder", move || {
+            PunchedUdpSocket::respond_to_punch(passive, deadline).result_discard()
+        });
+        let punched = unwrap_result!(PunchedUdpSocket::punch_hole(initiator,
+                                                                  initiator_priv_info,
+                                                                  passive_info,
+                                                                  deadline).result_discard());
+        assert_eq!(punched.peer_addr, SocketAddr(passive_addr));
+        let responded = unwrap_result!(unwrap_result!(responder.join()));
+        assert_eq!(responded.peer_addr, SocketAddr(initiator_addr));
+    }
+
+    // Stands in for a path to `target` through a NAT: packets from anyone else are passed on to
+    // `target` after `delay`, and packets from `target` go back to whoever last sent something.
+    fn relay(target: net::SocketAddr, delay: Duration, deadline: Instant) -> net::SocketAddr {
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let addr = unwrap_result!(socket.local_addr());
+        let _ = thread!("Relay", move || {
+            let mut buf = [0u8; 1024];
+            let mut client = None;
+            while let Ok(Some((n, from))) = DatagramTransport::recv_until(&socket,
+                                                                          &mut buf[..],
+                                                                          deadline) {
+                if *from == target {
+                    if let Some(client) = client {
+                        let _ = socket.send_to(&buf[..n], client);
+                    }
+                } else {
+                    client = Some(*from);
+                    thread::sleep(delay);
+                    let _ = socket.send_to(&buf[..n], target);
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn nominating_peers_agree_on_the_best_pair() {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let socket_0 = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let addr_0 = unwrap_result!(socket_0.local_addr());
+        let socket_1 = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let addr_1 = unwrap_result!(socket_1.local_addr());
+        let unanswered = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+
+        // Peer 1 is reachable over a reflexive path which answers straight away and a direct one
+        // which only answers after a while. Both peers also advertise an endpoint nobody answers.
+        let reflexive = relay(addr_1, Duration::new(0, 0), deadline);
+        let direct = relay(addr_1, Duration::from_millis(300), deadline);
+        let endpoints_0 = vec![MappedSocketAddr::direct(addr_0),
+                               MappedSocketAddr {
+                                   addr: SocketAddr(unwrap_result!(unanswered.local_addr())),
+                                   nat_restricted: true,
+                                   port_unknown: true,
+                                   unverified: false,
+                               }];
+        let endpoints_1 = vec![MappedSocketAddr {
+                                   addr: SocketAddr(reflexive),
+                                   nat_restricted: true,
+                                   port_unknown: false,
+                                   unverified: false,
+                               },
+                               MappedSocketAddr::direct(direct),
+                               MappedSocketAddr {
+                                   addr: SocketAddr(unwrap_result!(unanswered.local_addr())),
+                                   nat_restricted: true,
+                                   port_unknown: true,
+                                   unverified: false,
+                               }];
+
+        // Peer 0 is put in control, so it's the one choosing between peer 1's paths.
+        let mut infos;
+        loop {
+            infos = (gen_rendezvous_info(endpoints_0.clone()),
+                     gen_rendezvous_info(endpoints_1.clone()));
+            if *rendezvous_info::get_priv_secret((infos.0).0.clone()) >
+               *rendezvous_info::get_priv_secret((infos.1).0.clone()) {
+                break;
+            }
+        }
+        let ((priv_info_0, pub_info_0), (priv_info_1, pub_info_1)) = infos;
+
+        let window = Duration::from_secs(2);
+        let jh = thread!("Controlled peer", move || {
+            UdpPunchBuilder::new(deadline).nomination_window(window)
+                                          .punch_hole(socket_1, priv_info_1, pub_info_0)
+                                          .result_discard()
+        });
+        let punched_0 = unwrap_result!(UdpPunchBuilder::new(deadline)
+                                           .nomination_window(window)
+                                           .punch_hole(socket_0, priv_info_0, pub_info_1)
+                                           .result_discard());
+        let punched_1 = unwrap_result!(unwrap_result!(jh.join()));
+        // The reflexive path answered first but both peers settle on the direct one.
+        assert_eq!(punched_0.peer_addr, SocketAddr(direct));
+        assert_eq!(punched_1.peer_addr, SocketAddr(direct));
+    }
+
+    #[test]
+    fn punch_holes_to_many_peers_from_one_socket() {
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let our_endpoint = MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(socket.local_addr())),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut peers = Vec::new();
+        let mut threads = Vec::new();
+        for id in 0..3 {
+            let peer_socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+            let peer_endpoint = MappedSocketAddr {
+                addr: SocketAddr(unwrap_result!(peer_socket.local_addr())),
+                nat_restricted: false,
+                port_unknown: false,
+                unverified: false,
+            };
+            let (our_priv_info, our_pub_info) = gen_rendezvous_info(vec![our_endpoint.clone()]);
+            let (their_priv_info, their_pub_info) = gen_rendezvous_info(vec![peer_endpoint]);
+            peers.push((id, our_priv_info, their_pub_info));
+            threads.push(thread!("Punching peer", move || {
+                PunchedUdpSocket::punch_hole(peer_socket, their_priv_info, our_pub_info, deadline)
+                    .result_discard()
+                    .map(|punched| punched.peer_addr)
+            }));
+        }
+
+        let outcomes = UdpPunchBuilder::new(deadline).punch_hole_many(socket, peers);
+        assert_eq!(outcomes.len(), 3);
+        let mut peer_addrs = Vec::new();
+        for (id, thread) in threads.into_iter().enumerate() {
+            let their_view = unwrap_result!(unwrap_result!(thread.join()));
+            assert_eq!(their_view, our_endpoint.addr);
+            match outcomes.get(&id) {
+                Some(&Ok(ref punched)) => {
+                    assert!(!peer_addrs.contains(&punched.peer_addr));
+                    peer_addrs.push(punched.peer_addr);
+                },
+                Some(&Err(ref e)) => panic!("Punching peer {} failed: {}", id, e),
+                None => panic!("No outcome for peer {}", id),
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Hole punching between two mapped sockets, once the peers have swapped rendezvous info.
+
+/// Udp hole punching.
+pub mod udp {
+    pub use checklist::{candidate_priority, pair_priority, CandidatePair, Checklist, PairState};
+    pub use failover_udp_socket::FailoverUdpSocket;
+    #[cfg(feature = "stun")]
+    pub use ice::{ice_candidates, ice_lite_connect, ice_priority, IceCandidate, IceCandidateType,
+                  IceCredentials, IceError};
+    pub use icmp::{IcmpFailure, IcmpObserver};
+    pub use loopback::{loopback_udp_rendezvous, LoopbackRendezvousError};
+    pub use pipeline::UdpHolePuncher;
+    pub use punch_crypto::PunchKey;
+    pub use punch_state_machine::{UdpPunchStateMachine, UdpPunchStatus};
+    #[cfg(feature = "quic")]
+    pub use quic::{QuicBootstrap, QuicRole};
+    pub use punched_udp_socket::{PunchedUdpSocket, ReplayOutcome, UdpPunchBuilder,
+                                 UdpPunchHoleError, UdpPunchHoleWarning,
+                                 filter_sealed_udp_hole_punch_packet,
+                                 filter_udp_hole_punch_packet, is_peer_unreachable,
+                                 replay_udp_punch};
+    pub use session_record::{Direction, RecordedPacket, SessionRecord, SessionRecorder};
+}
+
+/// Tcp hole punching.
+#[cfg(feature = "tcp")]
+pub mod tcp {
+    pub use mapped_tcp_socket::{tcp_punch_hole, tcp_punch_hole_with_events, PunchedTcpStream,
+                                TcpPunchBuilder, TcpPunchHoleError, TcpPunchHoleWarning,
+                                TcpPunchTechnique};
+    pub use graceful_tcp_stream::GracefulTcpStream;
+    pub use pipeline::TcpHolePuncher;
+}
@@ -0,0 +1,170 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Handing a punched udp socket over to a QUIC endpoint.
+
+use std::net::{self, UdpSocket};
+
+use punch_crypto::{self, PunchKey};
+use punched_udp_socket::{PunchedUdpSocket, filter_sealed_udp_hole_punch_packet};
+use rendezvous_info::{self, PubRendezvousInfo};
+use utils;
+
+// Mixed into the punch key to derive the address validation token.
+const TOKEN_CONTEXT: &'static [u8] = b"nat_traversal quic address validation token v1";
+
+/// Which end of the QUIC connection a peer plays. QUIC needs one side to dial and the other to
+/// accept, where hole punching is symmetric, so the peers pick from their rendezvous info without
+/// exchanging anything more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuicRole {
+    /// Dial the peer, presenting the address validation token in the Initial packet.
+    Client,
+    /// Accept the peer's connection.
+    Server,
+}
+
+/// A socket punched with `PunchedUdpSocket::punch_hole_sealed`, with what a QUIC endpoint needs to
+/// take it over: which role to play and an address validation token both peers know.
+///
+/// A QUIC server normally makes a client it hasn't seen before prove it can receive at its
+/// address by answering a Retry, which costs a round trip. Hole punching has already proven
+/// that, so the client presents `address_validation_token` in its first Initial packet and the
+/// server accepts it with `validate_token` in place of sending a Retry. The token is derived
+/// from the punch key, so only the two peers know it.
+pub struct QuicBootstrap {
+    socket: UdpSocket,
+    peer_addr: net::SocketAddr,
+    role: QuicRole,
+    token: [u8; 32],
+    key: PunchKey,
+}
+
+impl QuicBootstrap {
+    /// Prepare `punched` for QUIC. `our_info` is the rendezvous info we sent the peer and
+    /// `their_info` the one it sent us, and `key` the key the hole was punched with. The peer
+    /// must pass the same infos the other way round.
+    pub fn new(punched: PunchedUdpSocket,
+               our_info: &PubRendezvousInfo,
+               their_info: &PubRendezvousInfo,
+               key: &PunchKey)
+               -> QuicBootstrap {
+        let our_secret = rendezvous_info::get_pub_secret(our_info);
+        let their_secret = rendezvous_info::get_pub_secret(their_info);
+        // Secrets are random, so they almost never tie, but when they do both peers must still
+        // pick different roles.
+        let ours = (our_secret, format!("{:?}", our_info.endpoints()));
+        let theirs = (their_secret, format!("{:?}", their_info.endpoints()));
+        let (role, client_secret, server_secret) = if ours < theirs {
+            (QuicRole::Client, our_secret, their_secret)
+        } else {
+            (QuicRole::Server, their_secret, our_secret)
+        };
+        let mut context = TOKEN_CONTEXT.to_vec();
+        context.extend_from_slice(&client_secret);
+        context.extend_from_slice(&server_secret);
+        QuicBootstrap {
+            socket: punched.socket,
+            peer_addr: *punched.peer_addr,
+            role: role,
+            token: punch_crypto::subkey(key, &context),
+            key: key.clone(),
+        }
+    }
+
+    /// Which end of the connection to play.
+    pub fn role(&self) -> QuicRole {
+        self.role
+    }
+
+    /// The peer's address, to dial as the client or expect the Initial from as the server.
+    pub fn peer_addr(&self) -> net::SocketAddr {
+        self.peer_addr
+    }
+
+    /// The token for the client to put in its Initial packets.
+    pub fn address_validation_token(&self) -> [u8; 32] {
+        self.token
+    }
+
+    /// Whether the server should treat a client at `from` presenting `token` as already
+    /// validated.
+    pub fn validate_token(&self, from: &net::SocketAddr, token: &[u8]) -> bool {
+        *from == self.peer_addr && utils::constant_time_eq(token, &self.token[..])
+    }
+
+    /// Whether `datagram` is a hole punch packet from the peer that arrived after the punch
+    /// finished, which the QUIC endpoint should drop before trying to parse it.
+    pub fn is_stray_punch_packet(&self, datagram: &[u8]) -> bool {
+        filter_sealed_udp_hole_punch_packet(datagram, &self.key).is_none()
+    }
+
+    /// Hand over the socket. It's left in blocking mode with no timeouts set, so set whatever
+    /// mode the endpoint needs.
+    pub fn into_socket(self) -> UdpSocket {
+        self.socket
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+
+    use socket_addr::SocketAddr;
+
+    use mapped_socket_addr::MappedSocketAddr;
+    use punch_crypto::PunchKey;
+    use punched_udp_socket::{self, PunchedUdpSocket};
+    use rendezvous_info::gen_rendezvous_info;
+
+    fn punched(peer_addr: &str) -> PunchedUdpSocket {
+        PunchedUdpSocket {
+            socket: unwrap_result!(UdpSocket::bind("127.0.0.1:0")),
+            peer_addr: SocketAddr(unwrap_result!(peer_addr.parse())),
+            local_ip: None,
+        }
+    }
+
+    #[test]
+    fn peers_agree_on_roles_and_token() {
+        let endpoint = MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!("192.0.2.1:1000".parse())),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        };
+        let (_, info_0) = gen_rendezvous_info(vec![endpoint.clone()]);
+        let (_, info_1) = gen_rendezvous_info(vec![endpoint]);
+        let key = PunchKey::derive(b"shared out of band");
+        let bootstrap_0 = QuicBootstrap::new(punched("192.0.2.2:2000"), &info_0, &info_1, &key);
+        let bootstrap_1 = QuicBootstrap::new(punched("192.0.2.1:1000"), &info_1, &info_0, &key);
+
+        assert!(bootstrap_0.role() != bootstrap_1.role());
+        let token = bootstrap_0.address_validation_token();
+        assert_eq!(token, bootstrap_1.address_validation_token());
+        assert!(bootstrap_1.validate_token(&unwrap_result!("192.0.2.1:1000".parse()), &token));
+        assert!(!bootstrap_1.validate_token(&unwrap_result!("192.0.2.9:1000".parse()), &token));
+        assert!(!bootstrap_1.validate_token(&unwrap_result!("192.0.2.1:1000".parse()),
+                                            &[0u8; 32]));
+
+        let stray = punched_udp_socket::encode_hole_punch([1, 2, 3, 4], true, Some(&key));
+        assert!(bootstrap_0.is_stray_punch_packet(&stray));
+        assert!(!bootstrap_0.is_stray_punch_packet(b"a QUIC packet"));
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use rand::{ChaChaRng, OsRng, Rng, SeedableRng};
+
+/// A shareable handle to a random number generator.
+///
+/// Everything in this crate which needs randomness (rendezvous secrets, port selection in the NAT
+/// simulator) can be given an `RngHandle` so that tests can use a seeded generator and get
+/// reproducible results. Cloning an `RngHandle` produces a handle to the same generator.
+#[derive(Clone)]
+pub struct RngHandle {
+    rng: Arc<Mutex<Box<Rng + Send>>>,
+}
+
+impl RngHandle {
+    /// Wrap an arbitrary generator.
+    pub fn new<R: Rng + Send + 'static>(rng: R) -> RngHandle {
+        RngHandle { rng: Arc::new(Mutex::new(Box::new(rng))) }
+    }
+
+    /// A handle to the operating system's generator. Fails if the generator can't be opened, eg.
+    /// when `/dev/urandom` is missing from a chroot.
+    pub fn os() -> io::Result<RngHandle> {
+        Ok(RngHandle::new(try!(OsRng::new())))
+    }
+
+    /// A deterministic generator. Handles created with the same seed produce the same sequence.
+    /// Not for use outside of tests.
+    pub fn seeded(seed: &[u32]) -> RngHandle {
+        RngHandle::new(ChaChaRng::from_seed(seed))
+    }
+}
+
+impl fmt::Debug for RngHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RngHandle")
+    }
+}
+
+impl Rng for RngHandle {
+    fn next_u32(&mut self) -> u32 {
+        unwrap_result!(self.rng.lock()).next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        unwrap_result!(self.rng.lock()).next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        unwrap_result!(self.rng.lock()).fill_bytes(dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::Rng;
+
+    #[test]
+    fn seeded_handles_are_reproducible() {
+        let mut a = RngHandle::seeded(&[1, 2, 3]);
+        let mut b = RngHandle::seeded(&[1, 2, 3]);
+        let xs: Vec<u32> = (0..8).map(|_| a.gen()).collect();
+        let ys: Vec<u32> = (0..8).map(|_| b.gen()).collect();
+        assert_eq!(xs, ys);
+
+        // Clones share the underlying generator.
+        let mut c = a.clone();
+        let x: u32 = a.gen();
+        let y: u32 = c.gen();
+        let zs: Vec<u32> = (0..2).map(|_| b.gen()).collect();
+        assert_eq!(vec![x, y], zs);
+    }
+}
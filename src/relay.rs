@@ -0,0 +1,27 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Relaying, for when hole punching fails: TURN, and with the `tcp` feature, tcp splicing.
+
+pub use stun::{decode_channel_data, encode_channel_data};
+pub use turn::{TurnAllocation, TurnCredentials, TurnError, TurnServer, TurnServerConfig,
+               TurnServerNewError, TurnUsage};
+pub use turn_tcp::TurnTcpAllocation;
+#[cfg(feature = "tcp")]
+pub use tcp_race::{RacedTcpStream, TcpRaceBuilder, TcpRaceError};
+#[cfg(feature = "tcp")]
+pub use tcp_splice::{tcp_splice, SpliceToken, TcpSpliceError};
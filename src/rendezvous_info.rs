@@ -18,45 +18,808 @@
 //! # `nat_traversal`
 //! NAT traversal utilities.
 
-use rand;
+use std::cmp;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::fmt;
+use std::io;
+use std::iter::FromIterator;
+use std::mem;
+use std::ops::Deref;
+use std::slice;
+use std::str::FromStr;
+use std::vec;
 
-use mapped_socket_addr::MappedSocketAddr;
+use maidsafe_utilities::serialisation::serialise;
+use rand::Rng;
+use rustc_serialize::hex::{FromHex, ToHex};
+use sodiumoxide;
+use sodiumoxide::randombytes;
 
-/// Info exchanged by both parties before performing a rendezvous connection.
-#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+use clock::Clock;
+use error_code::{ErrorCategory, ErrorCode};
+
+use identity::{self, Identity, IdentityError};
+use mapped_socket_addr::{MappedSocketAddr, ParseMappedSocketAddrError};
+use noise::StaticKeypair;
+use utils;
+
+// Prefixed to the signed contents of a `PubRendezvousInfo` so that the signature can't be passed
+// off as a signature over anything else.
+const SIGNATURE_CONTEXT: &'static str = "nat_traversal rendezvous info v1";
+
+/// Info exchanged by both parties before performing a rendezvous connection. The secret is zeroed
+/// when this is dropped.
+#[derive(Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
 pub struct PubRendezvousInfo {
     /// A vector of all the mapped addresses that the peer can try connecting to.
     endpoints: Vec<MappedSocketAddr>,
     /// Used to identify the peer.
     secret: [u8; 4],
+    /// The peer's static public key, if it takes part in `secure_channel`.
+    static_key: Option<[u8; 32]>,
+    /// The public key of the identity that signed this info.
+    identity_key: Option<[u8; 32]>,
+    /// The identity's signature over the other fields.
+    signature: Option<Vec<u8>>,
+    /// The peer's clock when it sent this info, in milliseconds since the unix epoch.
+    clock_ms: Option<u64>,
+    /// When the peer proposes to start hole punching, in milliseconds since the unix epoch by its
+    /// clock.
+    punch_at_ms: Option<u64>,
+}
+
+impl fmt::Debug for PubRendezvousInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PubRendezvousInfo")
+         .field("endpoints", &self.endpoints)
+         .field("secret", &"<redacted>")
+         .field("static_key", &self.static_key.map(|key| key.to_hex()))
+         .field("identity_key", &self.identity_key.map(|key| key.to_hex()))
+         .field("signed", &self.signature.is_some())
+         .field("clock_ms", &self.clock_ms)
+         .field("punch_at_ms", &self.punch_at_ms)
+         .finish()
+    }
+}
+
+impl Drop for PubRendezvousInfo {
+    fn drop(&mut self) {
+        utils::zeroize(&mut self.secret);
+    }
+}
+
+impl PubRendezvousInfo {
+    /// The mapped addresses that the peer can try connecting to.
+    pub fn endpoints(&self) -> &[MappedSocketAddr] {
+        &self.endpoints
+    }
+
+    /// Add the endpoints of `other` which aren't already in this info. Everything else is kept
+    /// from this info, and it no longer passes `verify_identity` if any endpoints were added.
+    pub fn merge(&mut self, other: &PubRendezvousInfo) {
+        self.extend(other.endpoints.iter().cloned());
+    }
+
+    /// The static public key the peer will authenticate with in `secure_channel`, if any.
+    pub fn static_key(&self) -> Option<[u8; 32]> {
+        self.static_key
+    }
+
+    /// The public key of the identity which signed this info, if it's signed. This isn't
+    /// verified, see `verify_identity`.
+    pub fn identity_key(&self) -> Option<[u8; 32]> {
+        self.identity_key
+    }
+
+    /// Check that this info was signed by the identity with public key `expected` and hasn't been
+    /// modified since, so it really came from the peer we meant to connect to. Infos passed
+    /// through `synthesize_nat64_candidates` no longer verify, so check them beforehand.
+    pub fn verify_identity(&self, expected: &[u8; 32]) -> Result<(), IdentityError> {
+        let (identity_key, signature) = match (self.identity_key, &self.signature) {
+            (Some(identity_key), &Some(ref signature)) => (identity_key, signature),
+            _ => return Err(IdentityError::Unsigned),
+        };
+        if !utils::constant_time_eq(&identity_key, expected) {
+            return Err(IdentityError::WrongIdentity);
+        }
+        identity::verify(&identity_key, &self.signed_contents(), signature)
+    }
+
+    /// Stamp the info with our clock and propose starting the hole punch `delay` from now. Call
+    /// this just before sending the info, and pass what the peer sends back to
+    /// `synchronized_punch_start`. The stamp isn't covered by the identity's signature, so it can
+    /// be added to signed info.
+    pub fn schedule_punch(&mut self, delay: Duration) {
+        self.schedule_punch_from(SystemTime::now(), delay);
+    }
+
+    fn schedule_punch_from(&mut self, now: SystemTime, delay: Duration) {
+        let now_ms = unix_millis(now);
+        self.clock_ms = Some(now_ms);
+        self.punch_at_ms = Some(now_ms + millis(delay));
+    }
+
+    /// The peer's clock minus ours, in milliseconds, going by the stamp from `schedule_punch`.
+    /// This assumes the info took half of `signaling_rtt`, the round trip time of the signaling
+    /// channel, to arrive at `received_at`, so it's only as accurate as the channel is symmetric.
+    /// `None` if the peer didn't stamp the info.
+    pub fn clock_offset_ms(&self, received_at: SystemTime, signaling_rtt: Duration)
+                           -> Option<i64> {
+        self.clock_ms.map(|clock_ms| {
+            let sent_at_ms = unix_millis(received_at) as i64 - (millis(signaling_rtt) / 2) as i64;
+            clock_ms as i64 - sent_at_ms
+        })
+    }
+
+    fn signed_contents(&self) -> Vec<u8> {
+        unwrap_result!(serialise(&(SIGNATURE_CONTEXT,
+                                   &self.endpoints,
+                                   &self.secret,
+                                   &self.static_key,
+                                   &self.identity_key)))
+    }
 }
 
-/// The local half of a `PubRendezvousInfo`.
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl IntoIterator for PubRendezvousInfo {
+    type Item = MappedSocketAddr;
+    type IntoIter = vec::IntoIter<MappedSocketAddr>;
+
+    fn into_iter(self) -> vec::IntoIter<MappedSocketAddr> {
+        decompose(self).0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a PubRendezvousInfo {
+    type Item = &'a MappedSocketAddr;
+    type IntoIter = slice::Iter<'a, MappedSocketAddr>;
+
+    fn into_iter(self) -> slice::Iter<'a, MappedSocketAddr> {
+        self.endpoints.iter()
+    }
+}
+
+// Endpoints whose address is already in the info are skipped, so the first candidate found for an
+// address wins.
+impl Extend<MappedSocketAddr> for PubRendezvousInfo {
+    fn extend<I: IntoIterator<Item = MappedSocketAddr>>(&mut self, iter: I) {
+        for endpoint in iter {
+            if self.endpoints.iter().all(|e| e.addr != endpoint.addr) {
+                self.endpoints.push(endpoint);
+            }
+        }
+    }
+}
+
+// Collecting endpoints generates a fresh pair of infos, like `gen_rendezvous_info`.
+impl FromIterator<MappedSocketAddr> for (PrivRendezvousInfo, PubRendezvousInfo) {
+    fn from_iter<I>(iter: I) -> (PrivRendezvousInfo, PubRendezvousInfo)
+        where I: IntoIterator<Item = MappedSocketAddr>
+    {
+        let (priv_info, mut pub_info) = gen_rendezvous_info(Vec::new());
+        pub_info.extend(iter);
+        (priv_info, pub_info)
+    }
+}
+
+// The first field of the text form of a `PubRendezvousInfo`.
+const TEXT_VERSION: &'static str = "v1";
+
+quick_error! {
+    /// Errors returned when parsing a `PubRendezvousInfo`.
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum ParseRendezvousInfoError {
+        /// The text isn't in a version of the format this crate understands.
+        Version {
+            description("Unsupported rendezvous info format version.")
+        }
+        /// A field which is always present is missing.
+        MissingField { field: &'static str } {
+            description("A required field is missing.")
+            display("Required field \"{}\" is missing.", field)
+        }
+        /// A field is unknown, repeated or has a malformed value.
+        Field { field: String } {
+            description("Malformed field.")
+            display("Malformed field \"{}\".", field)
+        }
+        /// One of the endpoints couldn't be parsed.
+        Endpoint { err: ParseMappedSocketAddrError } {
+            description("Malformed endpoint.")
+            display("Malformed endpoint: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl From<ParseRendezvousInfoError> for io::Error {
+    fn from(e: ParseRendezvousInfoError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e))
+    }
+}
+
+impl ErrorCode for ParseRendezvousInfoError {
+    fn code(&self) -> u32 {
+        match *self {
+            ParseRendezvousInfoError::Version => 3401,
+            ParseRendezvousInfoError::MissingField { .. } => 3402,
+            ParseRendezvousInfoError::Field { .. } => 3403,
+            ParseRendezvousInfoError::Endpoint { .. } => 3404,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        ErrorCategory::Configuration
+    }
+}
+
+// A single line of `;`-separated fields, eg.
+// `v1;secret=0a1b2c3d;endpoints=203.0.113.5:4000!restricted,192.0.2.1:80;sig=..`. The optional
+// fields are left out when they're absent, and the signature is kept so the parsed info can still
+// be checked with `verify_identity`.
+impl fmt::Display for PubRendezvousInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{};secret={};endpoints=", TEXT_VERSION, self.secret.to_hex()));
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, ","));
+            }
+            try!(write!(f, "{}", endpoint));
+        }
+        if let Some(ref static_key) = self.static_key {
+            try!(write!(f, ";key={}", static_key.to_hex()));
+        }
+        if let Some(ref identity_key) = self.identity_key {
+            try!(write!(f, ";id={}", identity_key.to_hex()));
+        }
+        if let Some(ref signature) = self.signature {
+            try!(write!(f, ";sig={}", signature.to_hex()));
+        }
+        if let Some(clock_ms) = self.clock_ms {
+            try!(write!(f, ";clock={}", clock_ms));
+        }
+        if let Some(punch_at_ms) = self.punch_at_ms {
+            try!(write!(f, ";punch_at={}", punch_at_ms));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PubRendezvousInfo {
+    type Err = ParseRendezvousInfoError;
+
+    fn from_str(s: &str) -> Result<PubRendezvousInfo, ParseRendezvousInfoError> {
+        let mut fields = s.trim().split(';');
+        if fields.next() != Some(TEXT_VERSION) {
+            return Err(ParseRendezvousInfoError::Version);
+        }
+
+        let mut secret = None;
+        let mut endpoints = None;
+        let mut static_key = None;
+        let mut identity_key = None;
+        let mut signature = None;
+        let mut clock_ms = None;
+        let mut punch_at_ms = None;
+        for field in fields {
+            let malformed = || ParseRendezvousInfoError::Field { field: field.to_owned() };
+            let mut name_value = field.splitn(2, '=');
+            let name = name_value.next().unwrap_or("");
+            let value = match name_value.next() {
+                Some(value) => value,
+                None => return Err(malformed()),
+            };
+            let first = match name {
+                "secret" => set_once(&mut secret, try!(hex_array_4(value).ok_or_else(&malformed))),
+                "endpoints" => {
+                    let mut parsed = Vec::new();
+                    for endpoint in value.split(',').filter(|e| !e.is_empty()) {
+                        match endpoint.parse() {
+                            Ok(endpoint) => parsed.push(endpoint),
+                            Err(err) => return Err(ParseRendezvousInfoError::Endpoint { err: err }),
+                        }
+                    }
+                    set_once(&mut endpoints, parsed)
+                }
+                "key" => {
+                    set_once(&mut static_key,
+                             try!(hex_array_32(value).ok_or_else(&malformed)))
+                }
+                "id" => {
+                    set_once(&mut identity_key,
+                             try!(hex_array_32(value).ok_or_else(&malformed)))
+                }
+                "sig" => set_once(&mut signature, try!(value.from_hex().map_err(|_| malformed()))),
+                "clock" => set_once(&mut clock_ms, try!(value.parse().map_err(|_| malformed()))),
+                "punch_at" => {
+                    set_once(&mut punch_at_ms, try!(value.parse().map_err(|_| malformed())))
+                }
+                _ => return Err(malformed()),
+            };
+            if !first {
+                return Err(malformed());
+            }
+        }
+
+        Ok(PubRendezvousInfo {
+            endpoints: try!(endpoints.ok_or(ParseRendezvousInfoError::MissingField {
+                field: "endpoints",
+            })),
+            secret: try!(secret.ok_or(ParseRendezvousInfoError::MissingField { field: "secret" })),
+            static_key: static_key,
+            identity_key: identity_key,
+            signature: signature,
+            clock_ms: clock_ms,
+            punch_at_ms: punch_at_ms,
+        })
+    }
+}
+
+// Fill `slot` unless it's already been filled by an earlier, repeated field.
+fn set_once<T>(slot: &mut Option<T>, value: T) -> bool {
+    if slot.is_some() {
+        return false;
+    }
+    *slot = Some(value);
+    true
+}
+
+fn hex_array_4(s: &str) -> Option<[u8; 4]> {
+    let bytes = match s.from_hex() {
+        Ok(ref bytes) if bytes.len() == 4 => bytes.clone(),
+        _ => return None,
+    };
+    let mut array = [0u8; 4];
+    array.copy_from_slice(&bytes);
+    Some(array)
+}
+
+fn hex_array_32(s: &str) -> Option<[u8; 32]> {
+    let bytes = match s.from_hex() {
+        Ok(ref bytes) if bytes.len() == 32 => bytes.clone(),
+        _ => return None,
+    };
+    let mut array = [0u8; 32];
+    array.copy_from_slice(&bytes);
+    Some(array)
+}
+
+/// The local half of a `PubRendezvousInfo`. The secret is zeroed when this is dropped, and
+/// compared in constant time.
+#[derive(Clone, Eq)]
 pub struct PrivRendezvousInfo {
     secret: [u8; 4],
+    /// The endpoints the public half advertises, so that both peers pair up the same endpoints
+    /// when nominating.
+    endpoints: Vec<MappedSocketAddr>,
+    /// The secret of info this replaced, which peers may still be using. See
+    /// `RotatingRendezvousInfo`.
+    previous_secret: Option<[u8; 4]>,
+}
+
+impl fmt::Debug for PrivRendezvousInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PrivRendezvousInfo")
+         .field("secret", &"<redacted>")
+         .field("endpoints", &self.endpoints)
+         .field("previous_secret", &self.previous_secret.as_ref().map(|_| "<redacted>"))
+         .finish()
+    }
+}
+
+impl PartialEq for PrivRendezvousInfo {
+    fn eq(&self, other: &PrivRendezvousInfo) -> bool {
+        // Check every secret whatever the outcome of the others, so that timing doesn't tell
+        // which differed.
+        let secrets_eq = utils::constant_time_eq(&self.secret, &other.secret);
+        let previous_secrets_eq = match (&self.previous_secret, &other.previous_secret) {
+            (&Some(ref ours), &Some(ref theirs)) => utils::constant_time_eq(ours, theirs),
+            (&None, &None) => true,
+            _ => false,
+        };
+        secrets_eq & previous_secrets_eq & (self.endpoints == other.endpoints)
+    }
+}
+
+impl Drop for PrivRendezvousInfo {
+    fn drop(&mut self) {
+        utils::zeroize(&mut self.secret);
+        if let Some(ref mut previous_secret) = self.previous_secret {
+            utils::zeroize(previous_secret);
+        }
+    }
 }
 
 /// Create a `(PrivRendezvousInfo, PubRendezvousInfo)` pair from a list of
-/// mapped socket addresses.
+/// mapped socket addresses. The secret is drawn from libsodium's random number generator, which
+/// unlike `RngHandle::os` can't fail to open.
 pub fn gen_rendezvous_info(endpoints: Vec<MappedSocketAddr>)
                            -> (PrivRendezvousInfo, PubRendezvousInfo) {
-    let secret = rand::random();
+    let _ = sodiumoxide::init();
+    let mut secret = [0u8; 4];
+    randombytes::randombytes_into(&mut secret);
+    rendezvous_info_with_secret(endpoints, secret)
+}
+
+/// Like `gen_rendezvous_info` but draws the secret from `rng`.
+pub fn gen_rendezvous_info_with_rng<R: Rng>(endpoints: Vec<MappedSocketAddr>, rng: &mut R)
+                                            -> (PrivRendezvousInfo, PubRendezvousInfo) {
+    rendezvous_info_with_secret(endpoints, rng.gen())
+}
+
+fn rendezvous_info_with_secret(endpoints: Vec<MappedSocketAddr>, secret: [u8; 4])
+                               -> (PrivRendezvousInfo, PubRendezvousInfo) {
     let priv_info = PrivRendezvousInfo {
         secret: secret,
+        endpoints: endpoints.clone(),
+        previous_secret: None,
     };
     let pub_info = PubRendezvousInfo {
         endpoints: endpoints,
         secret: secret,
+        static_key: None,
+        identity_key: None,
+        signature: None,
+        clock_ms: None,
+        punch_at_ms: None,
     };
     (priv_info, pub_info)
 }
 
-pub fn decompose(info: PubRendezvousInfo) -> (Vec<MappedSocketAddr>, [u8; 4]) {
-    let PubRendezvousInfo { endpoints, secret } = info;
-    (endpoints, secret)
+/// Like `gen_rendezvous_info` but also carries the public half of `static_keypair`, so that the
+/// peer can establish a `SecureChannel` bound to it once a connection has been made.
+pub fn gen_rendezvous_info_with_static_key(endpoints: Vec<MappedSocketAddr>,
+                                           static_keypair: &StaticKeypair)
+                                           -> (PrivRendezvousInfo, PubRendezvousInfo) {
+    let (priv_info, mut pub_info) = gen_rendezvous_info(endpoints);
+    pub_info.static_key = Some(static_keypair.public_key());
+    (priv_info, pub_info)
+}
+
+/// Like `gen_rendezvous_info_with_static_key` but also signs the info with `identity`. A peer
+/// which knows our identity's public key can then check with `verify_identity` that the info it
+/// received came from us, and `secure_channel` proves that whoever it connects to holds the
+/// signed static key.
+pub fn gen_rendezvous_info_with_identity(endpoints: Vec<MappedSocketAddr>,
+                                         static_keypair: &StaticKeypair,
+                                         identity: &Identity)
+                                         -> (PrivRendezvousInfo, PubRendezvousInfo) {
+    let (priv_info, mut pub_info) = gen_rendezvous_info_with_static_key(endpoints, static_keypair);
+    pub_info.identity_key = Some(identity.public_key());
+    pub_info.signature = Some(identity::sign(identity, &pub_info.signed_contents()));
+    (priv_info, pub_info)
+}
+
+/// Rendezvous info for a listener which publishes it for a long time, eg. in a directory. A secret
+/// published for days is effectively public, so the info is regenerated with a new secret every
+/// `period` and must then be republished. Peers still using the previous info can connect for a
+/// further `grace` period. Only udp hole punching accepts the previous secret.
+pub struct RotatingRendezvousInfo<F> {
+    generate: F,
+    period: Duration,
+    grace: Duration,
+    priv_info: PrivRendezvousInfo,
+    pub_info: PubRendezvousInfo,
+    rotated_at: Instant,
+    previous_secret: Option<[u8; 4]>,
 }
 
-pub fn get_priv_secret(info: PrivRendezvousInfo) -> [u8; 4] {
+impl<F> fmt::Debug for RotatingRendezvousInfo<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RotatingRendezvousInfo")
+         .field("period", &self.period)
+         .field("grace", &self.grace)
+         .field("pub_info", &self.pub_info)
+         .finish()
+    }
+}
+
+impl<F> RotatingRendezvousInfo<F>
+    where F: FnMut() -> (PrivRendezvousInfo, PubRendezvousInfo)
+{
+    /// Generate the first info with `generate`, which is called again on each rotation. It
+    /// usually wraps one of the `gen_rendezvous_info` functions.
+    pub fn new(clock: &Clock, period: Duration, grace: Duration, mut generate: F)
+               -> RotatingRendezvousInfo<F> {
+        let (priv_info, pub_info) = generate();
+        RotatingRendezvousInfo {
+            generate: generate,
+            period: period,
+            grace: grace,
+            priv_info: priv_info,
+            pub_info: pub_info,
+            rotated_at: clock.now(),
+            previous_secret: None,
+        }
+    }
+
+    /// The info to publish.
+    pub fn pub_info(&self) -> &PubRendezvousInfo {
+        &self.pub_info
+    }
+
+    /// The info to hole punch with. It accepts the previous secret until the grace period since
+    /// the last rotation has passed.
+    pub fn priv_info(&self, clock: &Clock) -> PrivRendezvousInfo {
+        let mut priv_info = self.priv_info.clone();
+        if clock.now() < self.rotated_at + self.grace {
+            priv_info.previous_secret = self.previous_secret;
+        }
+        priv_info
+    }
+
+    /// Rotate the secret if `period` has passed since the last rotation. Returns whether it was
+    /// rotated, in which case `pub_info` needs republishing.
+    pub fn rotate_if_due(&mut self, clock: &Clock) -> bool {
+        if clock.now() < self.rotated_at + self.period {
+            return false;
+        }
+        self.rotate(clock);
+        true
+    }
+
+    /// Rotate the secret now, eg. because the endpoints changed.
+    pub fn rotate(&mut self, clock: &Clock) {
+        let (priv_info, pub_info) = (self.generate)();
+        if let Some(ref mut previous_secret) = self.previous_secret {
+            utils::zeroize(previous_secret);
+        }
+        self.previous_secret = Some(self.priv_info.secret);
+        self.priv_info = priv_info;
+        self.pub_info = pub_info;
+        self.rotated_at = clock.now();
+    }
+}
+
+impl<F> Drop for RotatingRendezvousInfo<F> {
+    fn drop(&mut self) {
+        if let Some(ref mut previous_secret) = self.previous_secret {
+            utils::zeroize(previous_secret);
+        }
+    }
+}
+
+/// When to start hole punching so that our first packets cross the peer's, given the infos we
+/// and the peer stamped with `PubRendezvousInfo::schedule_punch`: the later of the two proposed
+/// times, with the peer's moved onto our clock. Port restricted cone NATs on both sides only let
+/// the punch through if the packets cross before either NAT gives up on the other's. `received_at`
+/// and `signaling_rtt` are as for `clock_offset_ms`. Pass the result to
+/// `UdpPunchBuilder::start_at`. `None` if either info isn't stamped.
+pub fn synchronized_punch_start(our_info: &PubRendezvousInfo,
+                                their_info: &PubRendezvousInfo,
+                                received_at: SystemTime,
+                                signaling_rtt: Duration)
+                                -> Option<Instant> {
+    let start_ms = match punch_start_ms(our_info, their_info, received_at, signaling_rtt) {
+        Some(start_ms) => start_ms,
+        None => return None,
+    };
+    let now_ms = unix_millis(SystemTime::now());
+    let now = Instant::now();
+    Some(now + Duration::from_millis(start_ms.saturating_sub(now_ms)))
+}
+
+// The start time of `synchronized_punch_start` in milliseconds since the unix epoch by our clock.
+fn punch_start_ms(our_info: &PubRendezvousInfo,
+                  their_info: &PubRendezvousInfo,
+                  received_at: SystemTime,
+                  signaling_rtt: Duration)
+                  -> Option<u64> {
+    let offset_ms = match their_info.clock_offset_ms(received_at, signaling_rtt) {
+        Some(offset_ms) => offset_ms,
+        None => return None,
+    };
+    match (our_info.punch_at_ms, their_info.punch_at_ms) {
+        (Some(ours), Some(theirs)) => {
+            let theirs = cmp::max(theirs as i64 - offset_ms, 0) as u64;
+            Some(cmp::max(ours, theirs))
+        },
+        _ => None,
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    // A clock set before 1970 is too broken to synchronize with.
+    time.duration_since(UNIX_EPOCH).map(millis).unwrap_or(0)
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+pub fn decompose(mut info: PubRendezvousInfo) -> (Vec<MappedSocketAddr>, [u8; 4]) {
+    (mem::replace(&mut info.endpoints, Vec::new()), info.secret)
+}
+
+/// Info with just `endpoints` and `secret`, as older nodes send it.
+pub fn compose(endpoints: Vec<MappedSocketAddr>, secret: [u8; 4]) -> PubRendezvousInfo {
+    PubRendezvousInfo {
+        endpoints: endpoints,
+        secret: secret,
+        static_key: None,
+        identity_key: None,
+        signature: None,
+        clock_ms: None,
+        punch_at_ms: None,
+    }
+}
+
+/// The secret that the peer which sent `info` goes by.
+pub fn get_pub_secret(info: &PubRendezvousInfo) -> [u8; 4] {
     info.secret
 }
+
+/// Replace the endpoints of `info`, keeping everything else.
+pub fn with_endpoints(mut info: PubRendezvousInfo, endpoints: Vec<MappedSocketAddr>)
+                      -> PubRendezvousInfo {
+    info.endpoints = endpoints;
+    info
+}
+
+/// A copy of our secret, zeroed when dropped.
+#[derive(Clone)]
+pub struct Secret([u8; 4]);
+
+impl Deref for Secret {
+    type Target = [u8; 4];
+
+    fn deref(&self) -> &[u8; 4] {
+        &self.0
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        utils::zeroize(&mut self.0);
+    }
+}
+
+/// Copies of the secrets peers may know us by, zeroed when dropped.
+#[derive(Clone)]
+pub struct Secrets(Vec<[u8; 4]>);
+
+impl Secrets {
+    /// Hold `secrets`, which must not be empty. The first is the current one.
+    pub fn new(secrets: Vec<[u8; 4]>) -> Secrets {
+        Secrets(secrets)
+    }
+}
+
+impl Deref for Secrets {
+    type Target = [[u8; 4]];
+
+    fn deref(&self) -> &[[u8; 4]] {
+        &self.0
+    }
+}
+
+impl Drop for Secrets {
+    fn drop(&mut self) {
+        for secret in &mut self.0 {
+            utils::zeroize(secret);
+        }
+    }
+}
+
+pub fn get_priv_secret(info: PrivRendezvousInfo) -> Secret {
+    Secret(info.secret)
+}
+
+/// The endpoints advertised by the public half of `info`.
+pub fn get_priv_endpoints(info: &PrivRendezvousInfo) -> Vec<MappedSocketAddr> {
+    info.endpoints.clone()
+}
+
+/// The secrets that peers may know `info` by: the current one and, while a
+/// `RotatingRendezvousInfo` is in its grace period, the previous one.
+pub fn get_priv_secrets(info: PrivRendezvousInfo) -> Secrets {
+    let mut secrets = Vec::with_capacity(2);
+    secrets.push(info.secret);
+    secrets.extend(info.previous_secret);
+    Secrets(secrets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use clock::MockClock;
+    use identity::Identity;
+    use noise::StaticKeypair;
+
+    #[test]
+    fn rotation_accepts_the_previous_secret_during_the_grace_period() {
+        let clock = MockClock::new();
+        let mut info = RotatingRendezvousInfo::new(&clock,
+                                                   Duration::from_secs(3600),
+                                                   Duration::from_secs(600),
+                                                   || gen_rendezvous_info(Vec::new()));
+        let first_pub_info = info.pub_info().clone();
+        let first_secret = get_priv_secret(info.priv_info(&clock));
+        assert!(!info.rotate_if_due(&clock));
+
+        clock.advance(Duration::from_secs(3600));
+        assert!(info.rotate_if_due(&clock));
+        assert!(*info.pub_info() != first_pub_info);
+        let secrets = get_priv_secrets(info.priv_info(&clock));
+        assert_eq!(secrets.len(), 2);
+        assert_eq!(secrets[1], *first_secret);
+
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(get_priv_secrets(info.priv_info(&clock)).len(), 1);
+    }
+
+    #[test]
+    fn endpoints_can_be_collected_extended_and_merged() {
+        let a: MappedSocketAddr = unwrap_result!("192.0.2.1:1000".parse());
+        let b: MappedSocketAddr = unwrap_result!("192.0.2.2:2000!restricted".parse());
+        let (_, mut info): (PrivRendezvousInfo, PubRendezvousInfo) =
+            vec![a.clone(), a.clone()].into_iter().collect();
+        assert_eq!(info.endpoints(), &[a.clone()][..]);
+
+        let (_, other) = gen_rendezvous_info(vec![b.clone(), a.clone()]);
+        let secret = info.secret;
+        info.merge(&other);
+        assert_eq!(info.secret, secret);
+        assert_eq!((&info).into_iter().collect::<Vec<_>>(), vec![&a, &b]);
+
+        info.extend(vec![unwrap_result!("192.0.2.2:2000".parse())]);
+        assert_eq!(info.into_iter().collect::<Vec<_>>(), vec![a, b]);
+    }
+
+    #[test]
+    fn text_form_round_trips() {
+        let identity = Identity::generate();
+        let endpoints = vec![unwrap_result!("203.0.113.5:4000!restricted".parse()),
+                             unwrap_result!("[2001:db8::1]:4000".parse())];
+        let static_keypair = StaticKeypair::generate();
+        let (_priv_info, pub_info) =
+            gen_rendezvous_info_with_identity(endpoints, &static_keypair, &identity);
+        let text = format!("{}", pub_info);
+        assert!(!text.contains('\n'));
+
+        let parsed: PubRendezvousInfo = unwrap_result!(text.parse());
+        assert_eq!(parsed, pub_info);
+        unwrap_result!(parsed.verify_identity(&identity.public_key()));
+
+        let (_, unsigned) = gen_rendezvous_info(Vec::new());
+        assert_eq!(unwrap_result!(format!("{}", unsigned).parse::<PubRendezvousInfo>()),
+                   unsigned);
+
+        assert_eq!("v2;secret=00000000;endpoints=".parse::<PubRendezvousInfo>(),
+                   Err(ParseRendezvousInfoError::Version));
+        assert_eq!("v1;endpoints=".parse::<PubRendezvousInfo>(),
+                   Err(ParseRendezvousInfoError::MissingField { field: "secret" }));
+        assert!("v1;secret=00;endpoints=".parse::<PubRendezvousInfo>().is_err());
+        assert!("v1;secret=00000000;endpoints=;secret=00000000"
+            .parse::<PubRendezvousInfo>()
+            .is_err());
+    }
+
+    #[test]
+    fn both_peers_agree_when_to_punch() {
+        use super::punch_start_ms;
+
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let at = |ms: u64| -> SystemTime { UNIX_EPOCH + Duration::from_millis(ms) };
+        let rtt = Duration::from_millis(200);
+        // Their clock runs 5 seconds ahead of ours and the signaling channel takes 100ms each way.
+        let (_, mut ours) = gen_rendezvous_info(Vec::new());
+        let (_, mut theirs) = gen_rendezvous_info(Vec::new());
+        ours.schedule_punch_from(at(1_000_000), Duration::from_millis(500));
+        theirs.schedule_punch_from(at(1_005_050), Duration::from_millis(300));
+
+        assert_eq!(theirs.clock_offset_ms(at(1_000_150), rtt), Some(5000));
+        assert_eq!(ours.clock_offset_ms(at(1_005_100), rtt), Some(-5000));
+        // Our proposal is the later one, and they start at the same moment by their clock.
+        assert_eq!(punch_start_ms(&ours, &theirs, at(1_000_150), rtt), Some(1_000_500));
+        assert_eq!(punch_start_ms(&theirs, &ours, at(1_005_100), rtt), Some(1_005_500));
+
+        let text = format!("{}", theirs);
+        assert_eq!(unwrap_result!(text.parse::<PubRendezvousInfo>()), theirs);
+        let (_, unstamped) = gen_rendezvous_info(Vec::new());
+        assert_eq!(punch_start_ms(&ours, &unstamped, at(1_000_150), rtt), None);
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+// Finding the default gateway, which NAT-PMP and PCP clients send their requests to. Interfaces
+// themselves are listed with `get_if_addrs`, which uses getifaddrs on Linux, macOS and the BSDs
+// alike, but routing tables have no portable interface. Linux exposes them in /proc and the BSDs
+// through route(8), whose `get` output is the same on all of them.
+
+use std::io;
+use std::net::Ipv4Addr;
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+          target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd", test))]
+use std::str::FromStr;
+
+/// The ipv4 default gateway, if the machine has one and the platform is supported.
+#[cfg(target_os = "linux")]
+pub fn default_gateway_v4() -> io::Result<Option<Ipv4Addr>> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut table = String::new();
+    let _ = try!(try!(File::open("/proc/net/route")).read_to_string(&mut table));
+    Ok(parse_proc_net_route(&table))
+}
+
+/// The ipv4 default gateway, if the machine has one and the platform is supported.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+          target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd"))]
+pub fn default_gateway_v4() -> io::Result<Option<Ipv4Addr>> {
+    use std::process::Command;
+
+    let output = try!(Command::new("route").args(&["-n", "get", "default"]).output());
+    // route exits with an error when there's no default route.
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(parse_route_get(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// The ipv4 default gateway, if the machine has one and the platform is supported.
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios",
+              target_os = "freebsd", target_os = "dragonfly", target_os = "netbsd",
+              target_os = "openbsd")))]
+pub fn default_gateway_v4() -> io::Result<Option<Ipv4Addr>> {
+    Ok(None)
+}
+
+// Lines of /proc/net/route are "Iface Destination Gateway Flags ..." with the addresses as hex
+// in host byte order. The default route has destination 0 and the RTF_GATEWAY flag.
+#[cfg(any(target_os = "linux", test))]
+fn parse_proc_net_route(table: &str) -> Option<Ipv4Addr> {
+    const RTF_UP: u32 = 0x1;
+    const RTF_GATEWAY: u32 = 0x2;
+
+    for line in table.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        let (destination, gateway, flags) = match (u32::from_str_radix(fields[1], 16),
+                                                   u32::from_str_radix(fields[2], 16),
+                                                   u32::from_str_radix(fields[3], 16)) {
+            (Ok(destination), Ok(gateway), Ok(flags)) => (destination, gateway, flags),
+            _ => continue,
+        };
+        if destination != 0 || flags & (RTF_UP | RTF_GATEWAY) != RTF_UP | RTF_GATEWAY {
+            continue;
+        }
+        let gateway = u32::from_be(gateway);
+        let gateway = Ipv4Addr::new((gateway >> 24) as u8,
+                                    (gateway >> 16) as u8,
+                                    (gateway >> 8) as u8,
+                                    gateway as u8);
+        return Some(gateway);
+    }
+    None
+}
+
+// `route -n get default` prints "name: value" lines, among them "gateway: 192.168.1.1". The
+// gateway is an interface name rather than an address when the default route is a point to
+// point link, in which case there's no gateway to talk to.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+          target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd", test))]
+fn parse_route_get(output: &str) -> Option<Ipv4Addr> {
+    for line in output.lines() {
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) if name.trim() == "gateway" => {
+                return Ipv4Addr::from_str(value.trim()).ok();
+            },
+            _ => (),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn parse_default_routes() {
+        let proc_net_route = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t0000A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+eth0\t00000000\t0100A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+";
+        assert_eq!(parse_proc_net_route(proc_net_route), Some(Ipv4Addr::new(192, 168, 0, 1)));
+        assert_eq!(parse_proc_net_route("Iface\tDestination\tGateway\n"), None);
+
+        let route_get = "   route to: default
+destination: default
+       mask: default
+    gateway: 10.0.1.1
+  interface: en0
+      flags: <UP,GATEWAY,DONE,STATIC,PRCLONING>
+";
+        assert_eq!(parse_route_get(route_get), Some(Ipv4Addr::new(10, 0, 1, 1)));
+        assert_eq!(parse_route_get("    gateway: utun3\n"), None);
+    }
+}
@@ -0,0 +1,653 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+// A small pool of worker threads shared by everything a `MappingContext` runs in the background,
+// so that long-lived servers don't each hold a thread which mostly sits idle. Work is scheduled
+// as jobs to run at an instant, or once one of a set of sockets is readable. A reactor thread
+// waits on the sockets of all the jobs waiting for them at once and hands each job to the workers
+// as soon as one of its sockets is readable, so idle servers don't wake the workers at all. Jobs
+// which need to run again, such as a server reading its socket, wait again before returning.
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::mem;
+use std::panic;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::time::{Duration, Instant};
+use std::fmt;
+
+use maidsafe_utilities::thread::RaiiThreadJoiner;
+
+pub use self::readiness::Pollable;
+
+/// The number of worker threads a `MappingContext` runs.
+pub const DEFAULT_RUNTIME_THREADS: usize = 2;
+
+trait Job: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F: FnOnce() + Send> Job for F {
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+struct Scheduled {
+    at: Instant,
+    // Breaks ties between jobs scheduled for the same instant so they run in the order they were
+    // scheduled.
+    seq: u64,
+    job: Box<Job>,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Scheduled) -> bool {
+        self.at == other.at && self.seq == other.seq
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Scheduled) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    // Reversed, so that the heap pops the earliest job first.
+    fn cmp(&self, other: &Scheduled) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+// A job waiting for one of its sockets to be readable, or for its deadline.
+struct Waiting {
+    fds: Vec<readiness::Fd>,
+    stop_flag: Option<Arc<AtomicBool>>,
+    deadline: Option<Instant>,
+    job: Box<Job>,
+}
+
+impl Waiting {
+    fn is_stopped(&self) -> bool {
+        self.stop_flag.as_ref().map_or(false, |stop_flag| stop_flag.load(AtomicOrdering::SeqCst))
+    }
+}
+
+struct State {
+    jobs: BinaryHeap<Scheduled>,
+    next_seq: u64,
+    // Only the reactor removes jobs from here, so the jobs it's waiting on keep their indices
+    // while others are added.
+    waiting: Vec<Waiting>,
+    shutdown: bool,
+}
+
+impl State {
+    fn push(&mut self, at: Instant, job: Box<Job>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.jobs.push(Scheduled {
+            at: at,
+            seq: seq,
+            job: job,
+        });
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    condvar: Condvar,
+    waker: readiness::Waker,
+}
+
+thread_local! {
+    // The address of the `Shared` of the runtime this thread works for, or zero on threads which
+    // aren't workers.
+    static WORKER_OF: Cell<usize> = Cell::new(0)
+}
+
+fn shared_id(shared: &Shared) -> usize {
+    shared as *const Shared as usize
+}
+
+/// The sockets a job waits on with `RuntimeHandle::when_readable`. Taken before the sockets are
+/// moved into the job.
+#[derive(Debug)]
+pub struct Sockets {
+    fds: Vec<readiness::Fd>,
+    stop_flag: Option<Arc<AtomicBool>>,
+}
+
+impl Sockets {
+    /// Wait on `sockets`.
+    pub fn new(sockets: &[&Pollable]) -> Sockets {
+        Sockets {
+            fds: sockets.iter().map(|socket| readiness::fd(*socket)).collect(),
+            stop_flag: None,
+        }
+    }
+
+    /// Also run the job as soon as `stop_flag` is set with `RuntimeHandle::stop`, so that it can
+    /// drop its sockets.
+    pub fn or_stopped(mut self, stop_flag: &Arc<AtomicBool>) -> Sockets {
+        self.stop_flag = Some(stop_flag.clone());
+        self
+    }
+}
+
+/// A handle for scheduling work onto a `Runtime`. Handles can be cloned and kept by the jobs
+/// themselves. Jobs scheduled after the runtime has been dropped are never run.
+///
+/// Jobs share a few threads so they must not block for long. Sockets should be read in
+/// non-blocking mode until they would block, and the job then run again with `when_readable`,
+/// rather than waited on.
+#[derive(Clone)]
+pub struct RuntimeHandle {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for RuntimeHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = unwrap_result!(self.shared.state.lock());
+        f.debug_struct("RuntimeHandle")
+         .field("pending_jobs", &state.jobs.len())
+         .field("waiting_jobs", &state.waiting.len())
+         .finish()
+    }
+}
+
+impl RuntimeHandle {
+    /// Run `job` as soon as a worker is free.
+    pub fn spawn<F>(&self, job: F)
+        where F: FnOnce() + Send + 'static
+    {
+        self.schedule(Instant::now(), job)
+    }
+
+    /// Run `job` once `at` has passed.
+    pub fn schedule<F>(&self, at: Instant, job: F)
+        where F: FnOnce() + Send + 'static
+    {
+        let mut state = unwrap_result!(self.shared.state.lock());
+        if state.shutdown {
+            return;
+        }
+        state.push(at, Box::new(job));
+        // Wake everyone: the new job may be due before whatever the sleeping workers are waiting
+        // for.
+        self.shared.condvar.notify_all();
+    }
+
+    /// Run `job` as soon as one of `sockets` is readable, or once `deadline` has passed if there
+    /// is one. The sockets must be kept open until the job runs, eg. by moving them into it.
+    pub fn when_readable<F>(&self, sockets: Sockets, deadline: Option<Instant>, job: F)
+        where F: FnOnce() + Send + 'static
+    {
+        let mut state = unwrap_result!(self.shared.state.lock());
+        if state.shutdown {
+            return;
+        }
+        let waiting = Waiting {
+            fds: sockets.fds,
+            stop_flag: sockets.stop_flag,
+            deadline: deadline,
+            job: Box::new(job),
+        };
+        if waiting.is_stopped() {
+            state.push(Instant::now(), waiting.job);
+            self.shared.condvar.notify_all();
+            return;
+        }
+        state.waiting.push(waiting);
+        self.shared.waker.wake();
+    }
+
+    /// Set `stop_flag` and run the jobs waiting on it in `when_readable` now, so that a server
+    /// which has been stopped drops its sockets straight away.
+    pub fn stop(&self, stop_flag: &AtomicBool) {
+        // Set under the lock, so that a job can't miss it between checking it and waiting.
+        let _state = unwrap_result!(self.shared.state.lock());
+        stop_flag.store(true, AtomicOrdering::SeqCst);
+        self.shared.waker.wake();
+    }
+}
+
+/// A fixed set of worker threads which run scheduled jobs. Dropping the runtime stops the
+/// workers, once they've finished the jobs they're running, and discards the jobs not yet run.
+pub struct Runtime {
+    handle: RuntimeHandle,
+    _raii_joiners: Vec<RaiiThreadJoiner>,
+}
+
+impl fmt::Debug for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Runtime")
+         .field("handle", &self.handle)
+         .field("threads", &self._raii_joiners.len())
+         .finish()
+    }
+}
+
+impl Runtime {
+    /// Start a runtime with `num_threads` workers, and a reactor thread for jobs waiting on
+    /// sockets.
+    pub fn new(num_threads: usize) -> Runtime {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                jobs: BinaryHeap::new(),
+                next_seq: 0,
+                waiting: Vec::new(),
+                shutdown: false,
+            }),
+            condvar: Condvar::new(),
+            waker: readiness::Waker::new(),
+        });
+        let mut raii_joiners: Vec<RaiiThreadJoiner> = (0..num_threads).map(|_| {
+            let shared = shared.clone();
+            RaiiThreadJoiner::new(thread!("nat_traversal runtime", move || {
+                work(shared);
+            }))
+        }).collect();
+        let cloned_shared = shared.clone();
+        raii_joiners.push(RaiiThreadJoiner::new(thread!("nat_traversal reactor", move || {
+            react(cloned_shared);
+        })));
+        Runtime {
+            handle: RuntimeHandle { shared: shared },
+            _raii_joiners: raii_joiners,
+        }
+    }
+
+    /// A handle for scheduling work onto the runtime.
+    pub fn handle(&self) -> RuntimeHandle {
+        self.handle.clone()
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        let (jobs, waiting) = {
+            let mut state = unwrap_result!(self.handle.shared.state.lock());
+            state.shutdown = true;
+            (mem::replace(&mut state.jobs, BinaryHeap::new()),
+             mem::replace(&mut state.waiting, Vec::new()))
+        };
+        self.handle.shared.condvar.notify_all();
+        self.handle.shared.waker.wake();
+        // Dropped outside the lock, in case dropping a job tries to schedule another.
+        drop(jobs);
+        drop(waiting);
+        // A job may hold the last reference to whatever owns the runtime, eg. an
+        // `Arc<MappingContext>`, and so drop it from a worker. Joining would then wait on this
+        // very thread, so the workers are left to exit by themselves instead.
+        if WORKER_OF.with(|worker_of| worker_of.get()) == shared_id(&self.handle.shared) {
+            mem::forget(mem::replace(&mut self._raii_joiners, Vec::new()));
+        }
+    }
+}
+
+fn work(shared: Arc<Shared>) {
+    WORKER_OF.with(|worker_of| worker_of.set(shared_id(&shared)));
+    let mut state = unwrap_result!(shared.state.lock());
+    loop {
+        if state.shutdown {
+            return;
+        }
+        let next = state.jobs.peek().map(|scheduled| scheduled.at);
+        let now = Instant::now();
+        match next {
+            None => state = unwrap_result!(shared.condvar.wait(state)),
+            Some(at) if at > now => {
+                state = unwrap_result!(shared.condvar.wait_timeout(state, at - now)).0;
+            },
+            Some(_) => {
+                let scheduled = match state.jobs.pop() {
+                    Some(scheduled) => scheduled,
+                    None => continue,
+                };
+                drop(state);
+                // A panicking job shouldn't take a worker down with it.
+                let job = panic::AssertUnwindSafe(scheduled.job);
+                let _ = panic::catch_unwind(move || job.0.run());
+                state = unwrap_result!(shared.state.lock());
+            },
+        }
+    }
+}
+
+// Wait on the sockets of the jobs in `when_readable`, and move each job to the workers' queue
+// once one of its sockets is readable or its deadline passes.
+fn react(shared: Arc<Shared>) {
+    loop {
+        let (num_waiting, fds, owners, timeout) = {
+            let state = unwrap_result!(shared.state.lock());
+            if state.shutdown {
+                return;
+            }
+            let mut fds = Vec::new();
+            let mut owners = Vec::new();
+            for (index, waiting) in state.waiting.iter().enumerate() {
+                fds.extend(waiting.fds.iter().cloned());
+                owners.extend(waiting.fds.iter().map(|_| index));
+            }
+            let now = Instant::now();
+            let timeout = state.waiting
+                               .iter()
+                               .filter_map(|waiting| waiting.deadline)
+                               .min()
+                               .map(|deadline| {
+                                   if deadline > now {
+                                       deadline - now
+                                   } else {
+                                       Duration::from_millis(0)
+                                   }
+                               });
+            (state.waiting.len(), fds, owners, timeout)
+        };
+        let readable = shared.waker.wait(&fds, timeout);
+
+        let mut state = unwrap_result!(shared.state.lock());
+        if state.shutdown {
+            return;
+        }
+        let mut ready = vec![false; num_waiting];
+        for (&owner, &readable) in owners.iter().zip(&readable) {
+            if readable {
+                ready[owner] = true;
+            }
+        }
+        let now = Instant::now();
+        let waiting = mem::replace(&mut state.waiting, Vec::new());
+        let mut woke_any = false;
+        for (index, waiting) in waiting.into_iter().enumerate() {
+            let due = waiting.deadline.map_or(false, |deadline| deadline <= now);
+            if due || waiting.is_stopped() || ready.get(index).cloned().unwrap_or(false) {
+                state.push(now, waiting.job);
+                woke_any = true;
+            } else {
+                state.waiting.push(waiting);
+            }
+        }
+        if woke_any {
+            shared.condvar.notify_all();
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+mod readiness {
+    use std::cmp;
+    use std::os::unix::io::RawFd;
+    use std::time::Duration;
+
+    use libc;
+
+    pub use std::os::unix::io::AsRawFd as Pollable;
+
+    pub type Fd = RawFd;
+
+    pub fn fd(socket: &Pollable) -> Fd {
+        socket.as_raw_fd()
+    }
+
+    /// A pipe the reactor waits on alongside the sockets, so that it can be woken to wait on
+    /// newly added ones.
+    pub struct Waker {
+        pipe: Option<(RawFd, RawFd)>,
+    }
+
+    /// How long the reactor waits at most if the pipe couldn't be created, since it can't be
+    /// woken.
+    const UNWOKEN_WAIT_MS: u64 = 10;
+
+    impl Waker {
+        pub fn new() -> Waker {
+            let mut fds = [0 as libc::c_int; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+                return Waker { pipe: None };
+            }
+            for &fd in &fds {
+                unsafe {
+                    let flags = libc::fcntl(fd, libc::F_GETFL);
+                    let _ = libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+                    let _ = libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                }
+            }
+            Waker { pipe: Some((fds[0], fds[1])) }
+        }
+
+        pub fn wake(&self) {
+            if let Some((_, write_fd)) = self.pipe {
+                // A full pipe already wakes the reactor, so a failed write doesn't matter.
+                let byte = [0u8];
+                let _ = unsafe {
+                    libc::write(write_fd, byte.as_ptr() as *const libc::c_void, 1)
+                };
+            }
+        }
+
+        /// Wait until one of `fds` is readable, `wake` is called or `timeout` passes. Returns
+        /// whether each of `fds` is readable. Errors and hang-ups count as readable, so that the
+        /// job finds them when it reads.
+        pub fn wait(&self, fds: &[Fd], timeout: Option<Duration>) -> Vec<bool> {
+            let mut pollfds: Vec<libc::pollfd> = Vec::with_capacity(fds.len() + 1);
+            if let Some((read_fd, _)) = self.pipe {
+                pollfds.push(libc::pollfd {
+                    fd: read_fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            }
+            let first_socket = pollfds.len();
+            pollfds.extend(fds.iter().map(|&fd| {
+                libc::pollfd {
+                    fd: fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                }
+            }));
+            let timeout = match self.pipe {
+                Some(..) => timeout,
+                None => {
+                    let unwoken = Duration::from_millis(UNWOKEN_WAIT_MS);
+                    Some(timeout.map_or(unwoken, |timeout| cmp::min(timeout, unwoken)))
+                },
+            };
+            let timeout_ms = match timeout {
+                // Rounded up, so that the reactor doesn't wake just before a deadline.
+                Some(timeout) => {
+                    let ms = timeout.as_secs() * 1000 + (timeout.subsec_nanos() as u64 + 999_999) /
+                             1_000_000;
+                    if ms > libc::c_int::max_value() as u64 {
+                        libc::c_int::max_value()
+                    } else {
+                        ms as libc::c_int
+                    }
+                },
+                None => -1,
+            };
+            let ret = unsafe {
+                libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout_ms)
+            };
+            if ret < 0 {
+                // Interrupted, most likely. The reactor just waits again.
+                return vec![false; fds.len()];
+            }
+            if let Some((read_fd, _)) = self.pipe {
+                // Empty the pipe, so that the next wait doesn't return straight away.
+                let mut buf = [0u8; 64];
+                loop {
+                    let ret = unsafe {
+                        libc::read(read_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+                    };
+                    if ret <= 0 {
+                        break;
+                    }
+                }
+            }
+            pollfds[first_socket..].iter().map(|pollfd| pollfd.revents != 0).collect()
+        }
+    }
+
+    impl Drop for Waker {
+        fn drop(&mut self) {
+            if let Some((read_fd, write_fd)) = self.pipe {
+                let _ = unsafe { libc::close(read_fd) };
+                let _ = unsafe { libc::close(write_fd) };
+            }
+        }
+    }
+}
+
+#[cfg(target_family = "windows")]
+mod readiness {
+    use std::cmp;
+    use std::os::windows::io::RawSocket;
+    use std::thread;
+    use std::time::Duration;
+
+    pub use std::os::windows::io::AsRawSocket as Pollable;
+
+    pub type Fd = RawSocket;
+
+    pub fn fd(socket: &Pollable) -> Fd {
+        socket.as_raw_socket()
+    }
+
+    /// Without ws2_32 there's no waiting on sockets here, so the reactor runs the waiting jobs
+    /// every `CHECK_INTERVAL_MS` to check their sockets themselves.
+    pub struct Waker;
+
+    const CHECK_INTERVAL_MS: u64 = 10;
+
+    impl Waker {
+        pub fn new() -> Waker {
+            Waker
+        }
+
+        pub fn wake(&self) {}
+
+        pub fn wait(&self, fds: &[Fd], timeout: Option<Duration>) -> Vec<bool> {
+            let interval = Duration::from_millis(CHECK_INTERVAL_MS);
+            thread::sleep(timeout.map_or(interval, |timeout| cmp::min(timeout, interval)));
+            vec![true; fds.len()]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn jobs_run_in_schedule_order() {
+        let runtime = Runtime::new(1);
+        let handle = runtime.handle();
+        let (tx, rx) = mpsc::channel();
+        let now = Instant::now();
+        for &(n, delay_ms) in &[(2, 200), (0, 0), (1, 100)] {
+            let tx = tx.clone();
+            handle.schedule(now + Duration::from_millis(delay_ms), move || {
+                unwrap_result!(tx.send(n));
+            });
+        }
+        for n in 0..3 {
+            assert_eq!(unwrap_result!(rx.recv()), n);
+        }
+        assert!(now.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn waiting_jobs_run_once_readable() {
+        let runtime = Runtime::new(1);
+        let handle = runtime.handle();
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let addr = unwrap_result!(socket.local_addr());
+        let (tx, rx) = mpsc::channel();
+        handle.when_readable(Sockets::new(&[&socket]), None, move || {
+            let mut buf = [0u8; 4];
+            unwrap_result!(tx.send(unwrap_result!(socket.recv_from(&mut buf)).0));
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(rx.try_recv().is_err());
+
+        let sender = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let _ = unwrap_result!(sender.send_to(b"ping", addr));
+        assert_eq!(unwrap_result!(rx.recv()), 4);
+    }
+
+    #[test]
+    fn waiting_jobs_run_at_their_deadline_or_when_stopped() {
+        let runtime = Runtime::new(1);
+        let handle = runtime.handle();
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::channel();
+        let now = Instant::now();
+        for &(n, deadline) in &[(0, Some(now + Duration::from_millis(100))), (1, None)] {
+            let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+            let sockets = Sockets::new(&[&socket]).or_stopped(&stop_flag);
+            let tx = tx.clone();
+            handle.when_readable(sockets, deadline, move || {
+                drop(socket);
+                unwrap_result!(tx.send(n));
+            });
+        }
+        assert_eq!(unwrap_result!(rx.recv()), 0);
+        assert!(now.elapsed() >= Duration::from_millis(100));
+        assert!(rx.try_recv().is_err());
+
+        handle.stop(&stop_flag);
+        assert_eq!(unwrap_result!(rx.recv()), 1);
+    }
+
+    #[test]
+    fn jobs_can_drop_their_own_runtime() {
+        let runtime = Runtime::new(1);
+        let handle = runtime.handle();
+        let runtime = Arc::new(Mutex::new(Some(runtime)));
+        let (tx, rx) = mpsc::channel();
+        handle.spawn(move || {
+            let _ = unwrap_result!(runtime.lock()).take();
+            unwrap_result!(tx.send(()));
+        });
+        unwrap_result!(rx.recv());
+    }
+
+    #[test]
+    fn workers_survive_panicking_jobs() {
+        let runtime = Runtime::new(1);
+        let handle = runtime.handle();
+        handle.spawn(|| panic!("Deliberate panic"));
+        let (tx, rx) = mpsc::channel();
+        handle.spawn(move || unwrap_result!(tx.send(())));
+        unwrap_result!(rx.recv());
+    }
+}
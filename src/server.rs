@@ -0,0 +1,37 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! The simple hole punch servers which tell clients their external address, and tools for
+//! running and monitoring them.
+
+pub use control::ControlServer;
+pub use echo_policy::EchoPolicy;
+pub use firewall::{add_firewall_rule, check_firewall, FirewallError, FirewallProtocol,
+                   FirewallVerdict};
+pub use metrics::{render_metrics, write_metrics_textfile, MetricsServer};
+#[cfg(feature = "tcp")]
+pub use ping::ping_tcp_server;
+pub use ping::{is_server_keepalive, ping_server, probe_latency, request_keepalive, LatencyStats,
+               PingServerError, ServerKeepalive, ServerStatus};
+#[cfg(feature = "tcp")]
+pub use simple_tcp_hole_punch_server::{SimpleTcpHolePunchServer, SimpleTcpHolePunchServerNewError};
+pub use simple_udp_hole_punch_server::{SimpleUdpHolePunchServer, SimpleUdpHolePunchServerNewError,
+                                       SimpleUdpServerLimits, SimpleUdpServerStats};
+#[cfg(feature = "tcp")]
+pub use tcp_splice::{TcpSpliceServer, TcpSpliceServerNewError};
+#[cfg(target_family = "unix")]
+pub use socket_activation::{activated_sockets, ActivatedSocket};
@@ -0,0 +1,177 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::io;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::fmt;
+
+use clock::{Clock, SystemClock};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use punch_crypto::PunchKey;
+use socket_addr::SocketAddr;
+
+/// Whether a recorded packet was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub enum Direction {
+    /// We sent the packet.
+    Sent,
+    /// We received the packet.
+    Received,
+}
+
+/// A single packet sent or received during a recorded session.
+#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct RecordedPacket {
+    /// Milliseconds since the start of the session. Replays use this in place of the real clock.
+    pub elapsed_ms: u64,
+    /// Whether we sent or received the packet.
+    pub direction: Direction,
+    /// The address the packet was sent to or received from.
+    pub addr: SocketAddr,
+    /// The contents of the packet.
+    pub data: Vec<u8>,
+}
+
+/// Every protocol interaction of a hole punching session, in the order they happened.
+#[derive(Debug, Clone, Default, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct SessionRecord {
+    /// Our secret for the session.
+    pub our_secret: [u8; 4],
+    /// The peer's secret for the session.
+    pub their_secret: [u8; 4],
+    /// The key the session's packets were sealed with, if they were. Anyone holding the record can
+    /// read and forge the session's packets, so keep it as safe as the key itself.
+    pub punch_key: Option<PunchKey>,
+    /// When the session would have given up, in milliseconds since its start. Replays of records
+    /// without one give up just after the last packet.
+    pub deadline_ms: Option<u64>,
+    /// The packets sent and received.
+    pub packets: Vec<RecordedPacket>,
+}
+
+impl SessionRecord {
+    /// Load a record previously written with `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<SessionRecord> {
+        let mut file = try!(File::open(path));
+        let mut data = Vec::new();
+        let _ = try!(file.read_to_end(&mut data));
+        deserialise(&data).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))
+        })
+    }
+
+    /// Write this record to a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let data = try!(serialise(self).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))
+        }));
+        let mut file = try!(File::create(path));
+        file.write_all(&data)
+    }
+}
+
+/// Records the protocol interactions of a session as they happen. Cloning a `SessionRecorder`
+/// produces a handle to the same record.
+#[derive(Clone)]
+pub struct SessionRecorder {
+    clock: Arc<Clock>,
+    start: Instant,
+    record: Arc<Mutex<SessionRecord>>,
+}
+
+impl fmt::Debug for SessionRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let packets = unwrap_result!(self.record.lock()).packets.len();
+        f.debug_struct("SessionRecorder").field("packets", &packets).finish()
+    }
+}
+
+impl SessionRecorder {
+    /// Start recording a new session. Packet times are measured from now.
+    pub fn new() -> SessionRecorder {
+        SessionRecorder::with_clock(Arc::new(SystemClock))
+    }
+
+    /// Start recording a new session, timing packets with `clock`.
+    pub fn with_clock(clock: Arc<Clock>) -> SessionRecorder {
+        let start = clock.now();
+        SessionRecorder {
+            clock: clock,
+            start: start,
+            record: Arc::new(Mutex::new(SessionRecord::default())),
+        }
+    }
+
+    /// Get a copy of everything recorded so far.
+    pub fn record(&self) -> SessionRecord {
+        unwrap_result!(self.record.lock()).clone()
+    }
+
+    /// Record the secrets used for the session.
+    pub fn record_secrets(&self, our_secret: [u8; 4], their_secret: [u8; 4]) {
+        let mut record = unwrap_result!(self.record.lock());
+        record.our_secret = our_secret;
+        record.their_secret = their_secret;
+    }
+
+    /// Record the key the session's packets are sealed with.
+    pub fn record_key(&self, key: &PunchKey) {
+        unwrap_result!(self.record.lock()).punch_key = Some(key.clone());
+    }
+
+    /// Record when the session gives up.
+    pub fn record_deadline(&self, deadline: Instant) {
+        let deadline_ms = self.elapsed_ms(deadline);
+        unwrap_result!(self.record.lock()).deadline_ms = Some(deadline_ms);
+    }
+
+    /// Record that we sent `data` to `addr`.
+    pub fn record_sent(&self, addr: SocketAddr, data: &[u8]) {
+        self.push(Direction::Sent, addr, data)
+    }
+
+    /// Record that we received `data` from `addr`.
+    pub fn record_received(&self, addr: SocketAddr, data: &[u8]) {
+        self.push(Direction::Received, addr, data)
+    }
+
+    fn push(&self, direction: Direction, addr: SocketAddr, data: &[u8]) {
+        let elapsed_ms = self.elapsed_ms(self.clock.now());
+        let mut record = unwrap_result!(self.record.lock());
+        record.packets.push(RecordedPacket {
+            elapsed_ms: elapsed_ms,
+            direction: direction,
+            addr: addr,
+            data: data.to_vec(),
+        });
+    }
+
+    fn elapsed_ms(&self, instant: Instant) -> u64 {
+        if instant <= self.start {
+            return 0;
+        }
+        let elapsed = instant - self.start;
+        elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64
+    }
+}
@@ -0,0 +1,33 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! What the peers exchange before hole punching, through whatever channel the application has,
+//! and how they authenticate each other and secure the connection afterwards.
+
+#[cfg(feature = "crust_compat")]
+pub use crust_compat::{deserialise_rendezvous_info, serialise_rendezvous_info,
+                       RendezvousWireVersion};
+pub use identity::{Identity, IdentityError};
+#[cfg(feature = "lan")]
+pub use lan_discovery::{add_lan_simple_servers, browse_lan, LanAdvertiser, LanDiscoveryError,
+                        LanService, LAN_PUNCH_SERVER_SERVICE, LAN_RENDEZVOUS_SERVICE};
+pub use noise::{noise_handshake, secure_channel, secure_channel_with_identity, NoiseError,
+                SecureChannel, StaticKeypair, MAX_SECURE_MESSAGE_LEN};
+pub use rendezvous_info::{ParseRendezvousInfoError, PrivRendezvousInfo, PubRendezvousInfo,
+                          RotatingRendezvousInfo, gen_rendezvous_info, gen_rendezvous_info_with_rng,
+                          gen_rendezvous_info_with_identity, gen_rendezvous_info_with_static_key,
+                          synchronized_punch_start};
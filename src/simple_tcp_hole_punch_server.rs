@@ -23,29 +23,45 @@ use std::io::{Read, Write};
 use std::net::{TcpStream, TcpListener};
 use std::time::{Instant, Duration};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::net;
+use std::fmt;
 
-use maidsafe_utilities::serialisation::serialise;
-use maidsafe_utilities::thread::RaiiThreadJoiner;
 use w_result::{WResult, WOk, WErr};
 use socket_addr::SocketAddr;
 
 use listener_message;
 use socket_utils;
+use echo_policy::EchoPolicy;
+use error_code::{ErrorCategory, ErrorCode};
+use event::Event;
+use mapping_context;
 use mapping_context::MappingContext;
 use mapped_tcp_socket::{MappedTcpSocket, MappedTcpSocketNewError, MappedTcpSocketMapWarning};
+use runtime::{RuntimeHandle, Sockets};
+use snapshot::Snapshot;
+use subnetting::IpSubnet;
 
 const TCP_RW_TIMEOUT: u64 = 20;
 
 /// RAII type for a hole punch server which speaks the simple hole punching protocol.
 pub struct SimpleTcpHolePunchServer<T: AsRef<MappingContext>> {
     // TODO(canndrew): Use this to refresh our external addrs.
-    _mapping_context: T,
+    mapping_context: T,
     stop_flag: Arc<AtomicBool>,
     local_addr: net::SocketAddr,
-    _raii_joiner: RaiiThreadJoiner,
-    known_endpoints: Vec<SocketAddr>,
+    known_endpoints: Snapshot<Vec<SocketAddr>>,
+    echo_policy: Arc<Snapshot<EchoPolicy>>,
+    ignored_connections: Arc<AtomicUsize>,
+}
+
+impl<T: AsRef<MappingContext>> fmt::Debug for SimpleTcpHolePunchServer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimpleTcpHolePunchServer")
+         .field("local_addr", &self.local_addr)
+         .field("known_endpoints", &*self.known_endpoints.load())
+         .finish()
+    }
 }
 
 quick_error! {
@@ -69,6 +85,11 @@ quick_error! {
             display("Error getting local address of listening socket: {}", err)
             cause(err)
         }
+        SetNonblocking { err: io::Error } {
+            description("Error putting the listening socket into non-blocking mode.")
+            display("Error putting the listening socket into non-blocking mode: {}", err)
+            cause(err)
+        }
     }
 }
 
@@ -82,14 +103,35 @@ impl From<SimpleTcpHolePunchServerNewError> for io::Error {
             },
             SimpleTcpHolePunchServerNewError::Listen { err } => err.kind(),
             SimpleTcpHolePunchServerNewError::SocketLocalAddr { err } => err.kind(),
+            SimpleTcpHolePunchServerNewError::SetNonblocking { err } => err.kind(),
         };
         io::Error::new(kind, err_str)
     }
 }
 
+impl ErrorCode for SimpleTcpHolePunchServerNewError {
+    fn code(&self) -> u32 {
+        match *self {
+            SimpleTcpHolePunchServerNewError::CreateMappedSocket { .. } => 1501,
+            SimpleTcpHolePunchServerNewError::Listen { .. } => 1502,
+            SimpleTcpHolePunchServerNewError::SocketLocalAddr { .. } => 1503,
+            SimpleTcpHolePunchServerNewError::SetNonblocking { .. } => 1504,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            SimpleTcpHolePunchServerNewError::CreateMappedSocket { ref err, .. } => err.category(),
+            SimpleTcpHolePunchServerNewError::Listen { .. } => ErrorCategory::Network,
+            SimpleTcpHolePunchServerNewError::SocketLocalAddr { .. } => ErrorCategory::Network,
+            SimpleTcpHolePunchServerNewError::SetNonblocking { .. } => ErrorCategory::Network,
+        }
+    }
+}
+
 impl<T: AsRef<MappingContext>> SimpleTcpHolePunchServer<T> {
-    /// Create a new server. This will spawn a background thread which will serve requests until
-    /// the server is dropped.
+    /// Create a new server. Requests are served on the mapping context's runtime until the server
+    /// is dropped.
     pub fn new(mapping_context: T, deadline: Instant)
         -> WResult<SimpleTcpHolePunchServer<T>,
                    MappedTcpSocketMapWarning,
@@ -103,9 +145,6 @@ impl<T: AsRef<MappingContext>> SimpleTcpHolePunchServer<T> {
         };
 
         let tcp_socket = mapped_socket.socket;
-        let stop_flag = Arc::new(AtomicBool::new(false));
-        let cloned_stop_flag = stop_flag.clone();
-
         let tcp_listener = match tcp_socket.listen(128) {
             Ok(tcp_listener) => tcp_listener,
             Err(e) => return WErr(SimpleTcpHolePunchServerNewError::Listen { err: e }),
@@ -132,64 +171,197 @@ impl<T: AsRef<MappingContext>> SimpleTcpHolePunchServer<T> {
                 }
             },
         };
+        match Self::serve_listener(mapping_context, tcp_listener, local_addr,
+                                   unrestricted_endpoints) {
+            Ok(server) => WOk(server, warnings),
+            Err(e) => WErr(e),
+        }
+    }
 
-        let raii_joiner = RaiiThreadJoiner::new(thread!("SimpleTcpHolePunchServer", move || {
-            Self::run(tcp_listener, cloned_stop_flag);
-        }));
+    /// Like `new` but serves on a listener that's already bound, eg. one handed over by the
+    /// service manager (see `activated_sockets`) or by a privileged parent process, so that the
+    /// server can listen on port 3478 without running as root. The listener isn't mapped, so
+    /// `addresses` only holds its local address, if that's a specific non-loopback one, until
+    /// replaced with `set_addresses`.
+    pub fn from_listener(mapping_context: T, tcp_listener: TcpListener)
+        -> Result<SimpleTcpHolePunchServer<T>, SimpleTcpHolePunchServerNewError>
+    {
+        let local_addr = match tcp_listener.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(e) => return Err(SimpleTcpHolePunchServerNewError::SocketLocalAddr { err: e }),
+        };
+        let ip = local_addr.ip();
+        let mut endpoints = Vec::new();
+        if !socket_utils::is_loopback(&ip) && !socket_utils::is_unspecified(&ip) {
+            endpoints.push(SocketAddr(local_addr));
+        }
+        Self::serve_listener(mapping_context, tcp_listener, local_addr, endpoints)
+    }
+
+    fn serve_listener(mapping_context: T,
+                      tcp_listener: TcpListener,
+                      local_addr: net::SocketAddr,
+                      unrestricted_endpoints: Vec<SocketAddr>)
+        -> Result<SimpleTcpHolePunchServer<T>, SimpleTcpHolePunchServerNewError>
+    {
+        if let Err(e) = tcp_listener.set_nonblocking(true) {
+            return Err(SimpleTcpHolePunchServerNewError::SetNonblocking { err: e });
+        }
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let cloned_stop_flag = stop_flag.clone();
+        let echo_policy = Arc::new(Snapshot::new(EchoPolicy::new()));
+        let cloned_echo_policy = echo_policy.clone();
+        let ignored_connections = Arc::new(AtomicUsize::new(0));
+        let cloned_ignored_connections = ignored_connections.clone();
+        let runtime = mapping_context.as_ref().runtime();
+        let cloned_runtime = runtime.clone();
+        runtime.spawn(move || {
+            accept(tcp_listener, Instant::now(), cloned_stop_flag, cloned_echo_policy,
+                   cloned_ignored_connections, cloned_runtime);
+        });
 
         WOk(SimpleTcpHolePunchServer {
-            _mapping_context: mapping_context,
+            mapping_context: mapping_context,
             stop_flag: stop_flag,
-            _raii_joiner: raii_joiner,
             local_addr: local_addr,
-            known_endpoints: unrestricted_endpoints,
-        }, warnings)
-    }
-
-    fn run(tcp_listener: TcpListener,
-           stop_flag: Arc<AtomicBool>) {
-
-        while !stop_flag.load(Ordering::SeqCst) {
-            if let Ok((mut stream, peer_addr)) = tcp_listener.accept() {
-                let _ = thread!("SimpleTcpHolePunchServer::run", move || {
-                    match stream.set_write_timeout(Some(Duration::from_secs(TCP_RW_TIMEOUT))) {
-                        Ok(()) => (),
-                        Err(_) => return,
-                    };
-                    match stream.set_read_timeout(Some(Duration::from_secs(TCP_RW_TIMEOUT))) {
-                        Ok(()) => (),
-                        Err(_) => return,
-                    };
-                    let mut read_buf = [0; 1024];
-                    let bytes_read = match stream.read(&mut read_buf) {
-                        Ok(n) => n,
-                        Err(_) => return,
-                    };
-                    if read_buf[..bytes_read] != listener_message::REQUEST_MAGIC_CONSTANT {
-                        return;
-                    }
-
-                    let resp = listener_message::EchoExternalAddr {
-                        external_addr: SocketAddr(peer_addr),
-                    };
-
-                    let _ = stream.write(&unwrap_result!(serialise(&resp)));
-                });
-            }
-        }
+            known_endpoints: Snapshot::new(unrestricted_endpoints),
+            echo_policy: echo_policy,
+            ignored_connections: ignored_connections,
+        })
     }
 
     /// Get the external addresses of this server to be shared with peers.
     pub fn addresses(&self) -> Vec<SocketAddr> {
-        self.known_endpoints.clone()
+        (*self.known_endpoints.load()).clone()
+    }
+
+    /// Replace the addresses returned by `addresses`, eg. with the public addresses of a load
+    /// balancer in front of the server. The listener is untouched.
+    pub fn set_addresses(&self, addresses: Vec<SocketAddr>) {
+        self.known_endpoints.store(addresses);
+    }
+
+    /// Change which clients the server answers and which addresses it reflects back. Takes effect
+    /// for connections accepted from then on.
+    pub fn set_echo_policy(&self, policy: EchoPolicy) {
+        self.echo_policy.store(policy);
+    }
+
+    /// Stop answering clients in `subnet`, on top of those the echo policy already ignores.
+    /// Their connections are closed as soon as they're accepted, from the next one on.
+    pub fn ignore_clients(&self, subnet: IpSubnet) {
+        self.echo_policy.update(|policy| policy.ignored_clients.insert(subnet));
+    }
+
+    /// How many connections have been closed unanswered because they came from a client the
+    /// echo policy ignores.
+    pub fn ignored_connections(&self) -> usize {
+        self.ignored_connections.load(Ordering::SeqCst)
+    }
+}
+
+// Start serving the connections waiting on the listener then wait for more. Once the server has
+// been stopped the listener is dropped instead.
+fn accept(tcp_listener: TcpListener,
+          start_time: Instant,
+          stop_flag: Arc<AtomicBool>,
+          echo_policy: Arc<Snapshot<EchoPolicy>>,
+          ignored_connections: Arc<AtomicUsize>,
+          runtime: RuntimeHandle) {
+    if stop_flag.load(Ordering::SeqCst) {
+        return;
+    }
+    let policy = echo_policy.load();
+    // Stop at the first error, which is usually `WouldBlock` once there's nothing to accept.
+    while let Ok((stream, peer_addr)) = tcp_listener.accept() {
+        if !policy.answers(&peer_addr.ip()) {
+            let _ = ignored_connections.fetch_add(1, Ordering::SeqCst);
+            continue;
+        }
+        // Accepted sockets inherit non-blocking mode on some platforms but not others.
+        if stream.set_nonblocking(true).is_err() {
+            continue;
+        }
+        let accepted_at = Instant::now();
+        let echoes = policy.echoes(&peer_addr.ip());
+        let cloned_runtime = runtime.clone();
+        runtime.spawn(move || {
+            serve(stream, peer_addr, echoes, start_time, accepted_at, cloned_runtime);
+        });
+    }
+    let sockets = Sockets::new(&[&tcp_listener]).or_stopped(&stop_flag);
+    let cloned_runtime = runtime.clone();
+    runtime.when_readable(sockets, None, move || {
+        accept(tcp_listener, start_time, stop_flag, echo_policy, ignored_connections,
+               cloned_runtime);
+    });
+}
+
+// Answer an accepted connection's request once it arrives, waiting for it until the connection
+// has been open for `TCP_RW_TIMEOUT` seconds. The client's address is only reflected back if
+// `echoes` is set.
+fn serve(mut stream: TcpStream,
+         peer_addr: net::SocketAddr,
+         echoes: bool,
+         start_time: Instant,
+         accepted_at: Instant,
+         runtime: RuntimeHandle) {
+    let mut read_buf = [0; 1024];
+    let bytes_read = match stream.read(&mut read_buf) {
+        Ok(n) => n,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            let timeout_at = accepted_at + Duration::from_secs(TCP_RW_TIMEOUT);
+            if Instant::now() >= timeout_at {
+                return;
+            }
+            let sockets = Sockets::new(&[&stream]);
+            let cloned_runtime = runtime.clone();
+            runtime.when_readable(sockets, Some(timeout_at), move || {
+                serve(stream, peer_addr, echoes, start_time, accepted_at, cloned_runtime);
+            });
+            return;
+        },
+        Err(_) => return,
+    };
+    // The response is small enough that blocking on it is brief.
+    match stream.set_nonblocking(false) {
+        Ok(()) => (),
+        Err(_) => return,
+    };
+    match stream.set_write_timeout(Some(Duration::from_secs(TCP_RW_TIMEOUT))) {
+        Ok(()) => (),
+        Err(_) => return,
+    };
+    if read_buf[..bytes_read] == listener_message::PING_MAGIC_CONSTANT {
+        let resp = listener_message::Pong {
+            uptime_secs: start_time.elapsed().as_secs(),
+            protocol_version: listener_message::PROTOCOL_VERSION,
+        };
+        let mut write_buf = [0; listener_message::MAX_MESSAGE_SIZE];
+        if let Ok(written) = resp.encode_into(&mut write_buf) {
+            let _ = stream.write(&write_buf[..written]);
+        }
+        return;
+    }
+    if read_buf[..bytes_read] != listener_message::REQUEST_MAGIC_CONSTANT || !echoes {
+        return;
+    }
+
+    let resp = listener_message::EchoExternalAddr {
+        external_addr: SocketAddr(peer_addr),
+    };
+
+    let mut write_buf = [0; listener_message::MAX_MESSAGE_SIZE];
+    if let Ok(written) = resp.encode_into(&mut write_buf) {
+        let _ = stream.write(&write_buf[..written]);
     }
 }
 
 impl<T: AsRef<MappingContext>> Drop for SimpleTcpHolePunchServer<T> {
     fn drop(&mut self) {
-        self.stop_flag.store(true, Ordering::SeqCst);
-        // Unblock the acceptor.
-        let _ = TcpStream::connect(self.local_addr);
+        self.mapping_context.as_ref().runtime().stop(&self.stop_flag);
+        let events = mapping_context::events(self.mapping_context.as_ref());
+        events.send(Event::Closed { local_addr: SocketAddr(self.local_addr) });
     }
 }
 
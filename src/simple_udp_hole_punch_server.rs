@@ -18,31 +18,208 @@
 //! # `nat_traversal`
 //! NAT traversal utilities.
 
+use std::fs::{self, File};
 use std::io;
-use std::net::UdpSocket;
+use std::io::{Read, Write};
+use std::net::{self, IpAddr, UdpSocket};
+use std::path::{Path, PathBuf};
 use std::time::{Instant, Duration};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::fmt;
 
-use maidsafe_utilities::serialisation::serialise;
-use maidsafe_utilities::thread::RaiiThreadJoiner;
+use byteorder::{BigEndian, ByteOrder};
+use rustc_serialize::json;
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::randombytes;
 use w_result::{WResult, WOk, WErr};
 
 use socket_addr::SocketAddr;
+use batch;
 use listener_message;
 
+use echo_policy::EchoPolicy;
+use error_code::{ErrorCategory, ErrorCode};
+use event::Event;
+use ipv6_selection;
+use mapping_context;
 use mapping_context::MappingContext;
 use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketNewError, MappedUdpSocketMapWarning};
+use runtime::{Pollable, RuntimeHandle, Sockets};
+use snapshot::Snapshot;
+use socket_utils;
+use subnetting::{IpSubnet, SubnetList};
+use utils;
 
-const UDP_READ_TIMEOUT_SECS: u64 = 2;
+/// How often persisted stats are written out.
+const STATS_SAVE_INTERVAL_SECS: u64 = 60;
+/// The most requests read from the socket at once.
+const MAX_BATCH_SIZE: usize = 32;
+/// Requests are either four bytes or padded to `PADDED_REQUEST_LEN` so anything longer is
+/// truncated to this and ignored.
+const REQUEST_BUF_SIZE: usize = 2 * listener_message::PADDED_REQUEST_LEN;
+/// How long the cookies handed out by the server stay valid. Cookies from the previous period are
+/// still accepted so that a client which got one just before the period ended has time to echo it.
+const COOKIE_PERIOD_SECS: u64 = 30;
+
+/// Limits on the memory and time a `SimpleUdpHolePunchServer` spends on requests, so that a flood
+/// of requests can't starve the rest of the runtime or exhaust memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct SimpleUdpServerLimits {
+    /// The bytes of buffers the server may use for requests and responses. This bounds how many
+    /// requests are read from the socket at once.
+    pub memory_budget: usize,
+    /// The most requests answered each time the server is woken by its sockets, before it lets
+    /// other jobs on the runtime have a turn. The server is woken again straight away while
+    /// requests are still waiting.
+    pub max_requests_per_poll: usize,
+    /// Once `max_requests_per_poll` requests have been answered, up to this many of the oldest
+    /// waiting requests are shed by replying "try later". Anything beyond that is left to the OS,
+    /// which drops new requests once the socket's receive buffer is full.
+    pub max_shed_per_poll: usize,
+    /// Only answer padded requests which echo a cookie the server handed out, and ignore the old
+    /// four byte requests. A cookie is only sent in reply to a padded request, so the server never
+    /// sends more bytes than it received and can't be used to amplify a flood at a spoofed
+    /// address. Leave this off while clients of older versions of this crate still need serving.
+    pub require_cookies: bool,
+    /// The most clients, counted by address, that the server sends keepalives to on their
+    /// request. Further requests are answered with "try later". Zero turns keepalives off.
+    pub max_keepalives: usize,
+    /// How often a keepalive is sent to each client that asked for them. This should be shorter
+    /// than the time NATs keep an idle udp mapping open, which is as little as 30 seconds for
+    /// some.
+    pub keepalive_interval: Duration,
+    /// How long keepalives are sent for after each request, so that clients which have gone away
+    /// stop getting them.
+    pub keepalive_lease: Duration,
+}
+
+impl Default for SimpleUdpServerLimits {
+    fn default() -> SimpleUdpServerLimits {
+        SimpleUdpServerLimits {
+            memory_budget: 16 * 1024,
+            max_requests_per_poll: 256,
+            max_shed_per_poll: 1024,
+            require_cookies: false,
+            max_keepalives: 1024,
+            keepalive_interval: Duration::from_secs(20),
+            keepalive_lease: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Counts of the requests handled by a `SimpleUdpHolePunchServer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, RustcEncodable, RustcDecodable)]
+pub struct SimpleUdpServerStats {
+    /// Requests that were answered.
+    pub served_requests: usize,
+    /// Requests that were answered with "try later" because the server was overloaded.
+    pub shed_requests: usize,
+    /// Requests that were dropped because they came from a client the echo policy ignores.
+    pub ignored_requests: usize,
+}
+
+impl SimpleUdpServerStats {
+    /// Load stats previously written with `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<SimpleUdpServerStats> {
+        let mut file = try!(File::open(path));
+        let mut contents = String::new();
+        let _ = try!(file.read_to_string(&mut contents));
+        json::decode(&contents).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))
+        })
+    }
+
+    /// Write these stats to a file. They're written to a temporary file first and moved into
+    /// place, so a crash part way through leaves the previous stats intact.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let contents = try!(json::encode(self).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))
+        }));
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        {
+            let mut file = try!(File::create(&tmp_path));
+            try!(file.write_all(contents.as_bytes()));
+            try!(file.sync_all());
+        }
+        fs::rename(&tmp_path, path)
+    }
+
+    fn plus(&self, other: &SimpleUdpServerStats) -> SimpleUdpServerStats {
+        SimpleUdpServerStats {
+            served_requests: self.served_requests + other.served_requests,
+            shed_requests: self.shed_requests + other.shed_requests,
+            ignored_requests: self.ignored_requests + other.ignored_requests,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    served_requests: AtomicUsize,
+    shed_requests: AtomicUsize,
+    ignored_requests: AtomicUsize,
+    // Set by `persist_stats`.
+    stats_file: Mutex<Option<StatsFile>>,
+}
+
+// Where a server's cumulative stats are kept, and the stats of its previous runs.
+struct StatsFile {
+    path: PathBuf,
+    previous_runs: SimpleUdpServerStats,
+}
+
+impl Counters {
+    fn session_stats(&self) -> SimpleUdpServerStats {
+        SimpleUdpServerStats {
+            served_requests: self.served_requests.load(Ordering::SeqCst),
+            shed_requests: self.shed_requests.load(Ordering::SeqCst),
+            ignored_requests: self.ignored_requests.load(Ordering::SeqCst),
+        }
+    }
+
+    fn cumulative_stats(&self) -> SimpleUdpServerStats {
+        let session = self.session_stats();
+        match *unwrap_result!(self.stats_file.lock()) {
+            Some(ref stats_file) => stats_file.previous_runs.plus(&session),
+            None => session,
+        }
+    }
+
+    // Write the cumulative stats to the stats file, if there is one.
+    fn save_stats(&self) -> io::Result<()> {
+        let session = self.session_stats();
+        match *unwrap_result!(self.stats_file.lock()) {
+            Some(ref stats_file) => stats_file.previous_runs.plus(&session).save(&stats_file.path),
+            None => Ok(()),
+        }
+    }
+}
 
 /// RAII type for a hole punch server which speaks the simple hole punching protocol.
 pub struct SimpleUdpHolePunchServer<T: AsRef<MappingContext>> {
     // TODO(canndrew): Use this to refresh our external addrs.
-    _mapping_context: T,
+    mapping_context: T,
     stop_flag: Arc<AtomicBool>,
-    _raii_joiner: RaiiThreadJoiner,
-    known_endpoints: Vec<SocketAddr>,
+    draining: Arc<AtomicBool>,
+    local_addr: Option<SocketAddr>,
+    known_endpoints: Snapshot<Vec<SocketAddr>>,
+    counters: Arc<Counters>,
+    limits: Arc<Snapshot<SimpleUdpServerLimits>>,
+    echo_policy: Arc<Snapshot<EchoPolicy>>,
+}
+
+impl<T: AsRef<MappingContext>> fmt::Debug for SimpleUdpHolePunchServer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimpleUdpHolePunchServer")
+         .field("local_addr", &self.local_addr)
+         .field("known_endpoints", &*self.known_endpoints.load())
+         .finish()
+    }
 }
 
 quick_error! {
@@ -56,12 +233,12 @@ quick_error! {
             display("Error creating a mapped udp socket to listen on: {}", err)
             cause(err)
         }
-        /// Error setting the timeout on the server's listening socket.
-        SetSocketTimeout {
+        /// Error putting the server's listening socket into non-blocking mode.
+        SetNonblocking {
             err: io::Error
         } {
-            description("Error setting the timeout on the server's listening socket.")
-            display("Error setting the timeout on the server's listening socket: {}.", err)
+            description("Error putting the server's listening socket into non-blocking mode.")
+            display("Error putting the server's listening socket into non-blocking mode: {}.", err)
             cause(err)
         }
     }
@@ -75,19 +252,47 @@ impl From<SimpleUdpHolePunchServerNewError> for io::Error {
                 let err: io::Error = From::from(err);
                 err.kind()
             },
-            SimpleUdpHolePunchServerNewError::SetSocketTimeout { err } => err.kind(),
+            SimpleUdpHolePunchServerNewError::SetNonblocking { err } => err.kind(),
         };
         io::Error::new(kind, err_str)
     }
 }
 
+impl ErrorCode for SimpleUdpHolePunchServerNewError {
+    fn code(&self) -> u32 {
+        match *self {
+            SimpleUdpHolePunchServerNewError::CreateMappedSocket { .. } => 1401,
+            // 1402 was `SetSocketTimeout`, which went away when the server stopped blocking in
+            // `recv_from`. It stays retired.
+            SimpleUdpHolePunchServerNewError::SetNonblocking { .. } => 1403,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            SimpleUdpHolePunchServerNewError::CreateMappedSocket { ref err, .. } => err.category(),
+            SimpleUdpHolePunchServerNewError::SetNonblocking { .. } => ErrorCategory::Network,
+        }
+    }
+}
+
 impl<T: AsRef<MappingContext>> SimpleUdpHolePunchServer<T> {
-    /// Create a new server. This will spawn a background thread which will serve requests until
-    /// the server is dropped.
+    /// Create a new server. Requests are served on the mapping context's runtime until the server
+    /// is dropped.
     pub fn new(mapping_context: T, deadline: Instant)
         -> WResult<SimpleUdpHolePunchServer<T>,
                    MappedUdpSocketMapWarning,
                    SimpleUdpHolePunchServerNewError>
+    {
+        Self::new_with_limits(mapping_context, deadline, SimpleUdpServerLimits::default())
+    }
+
+    /// Like `new` but with limits on the resources spent serving requests other than the
+    /// defaults.
+    pub fn new_with_limits(mapping_context: T, deadline: Instant, limits: SimpleUdpServerLimits)
+        -> WResult<SimpleUdpHolePunchServer<T>,
+                   MappedUdpSocketMapWarning,
+                   SimpleUdpHolePunchServerNewError>
     {
         let (mapped_socket, warnings) = match MappedUdpSocket::new(mapping_context.as_ref(), deadline) {
             WOk(mapped_socket, warnings) => (mapped_socket, warnings),
@@ -95,64 +300,826 @@ impl<T: AsRef<MappingContext>> SimpleUdpHolePunchServer<T> {
                 return WErr(SimpleUdpHolePunchServerNewError::CreateMappedSocket { err: e });
             }
         };
+        Self::serve_mapped(mapping_context, mapped_socket, warnings, limits)
+    }
+
+    /// Like `new` but serves on a socket that's already bound, eg. one handed over by the
+    /// service manager (see `activated_sockets`) or by a privileged parent process, so that the
+    /// server can listen on port 3478 without running as root.
+    pub fn from_socket(mapping_context: T, socket: UdpSocket, deadline: Instant)
+        -> WResult<SimpleUdpHolePunchServer<T>,
+                   MappedUdpSocketMapWarning,
+                   SimpleUdpHolePunchServerNewError>
+    {
+        Self::from_socket_with_limits(mapping_context,
+                                      socket,
+                                      deadline,
+                                      SimpleUdpServerLimits::default())
+    }
+
+    /// Like `from_socket` but with limits on the resources spent serving requests other than the
+    /// defaults.
+    pub fn from_socket_with_limits(mapping_context: T,
+                                   socket: UdpSocket,
+                                   deadline: Instant,
+                                   limits: SimpleUdpServerLimits)
+        -> WResult<SimpleUdpHolePunchServer<T>,
+                   MappedUdpSocketMapWarning,
+                   SimpleUdpHolePunchServerNewError>
+    {
+        let (mapped_socket, warnings) = match MappedUdpSocket::map(socket,
+                                                                   mapping_context.as_ref(),
+                                                                   deadline) {
+            WOk(mapped_socket, warnings) => (mapped_socket, warnings),
+            WErr(e) => {
+                let err = MappedUdpSocketNewError::MapSocket { err: e };
+                return WErr(SimpleUdpHolePunchServerNewError::CreateMappedSocket { err: err });
+            }
+        };
+        Self::serve_mapped(mapping_context, mapped_socket, warnings, limits)
+    }
 
+    fn serve_mapped(mapping_context: T,
+                    mapped_socket: MappedUdpSocket,
+                    warnings: Vec<MappedUdpSocketMapWarning>,
+                    limits: SimpleUdpServerLimits)
+        -> WResult<SimpleUdpHolePunchServer<T>,
+                   MappedUdpSocketMapWarning,
+                   SimpleUdpHolePunchServerNewError>
+    {
         let udp_socket = mapped_socket.socket;
+        let local_addr = udp_socket.local_addr().ok().map(SocketAddr);
         let stop_flag = Arc::new(AtomicBool::new(false));
         let cloned_stop_flag = stop_flag.clone();
+        let draining = Arc::new(AtomicBool::new(false));
 
-        match udp_socket.set_read_timeout(Some(Duration::from_secs(UDP_READ_TIMEOUT_SECS))) {
+        match udp_socket.set_nonblocking(true) {
             Ok(()) => (),
             Err(e) => {
-                return WErr(SimpleUdpHolePunchServerNewError::SetSocketTimeout { err: e })
+                return WErr(SimpleUdpHolePunchServerNewError::SetNonblocking { err: e })
             }
         };
 
-        let raii_joiner = RaiiThreadJoiner::new(thread!("SimpleUdpHolePunchServer", move || {
-            Self::run(udp_socket, cloned_stop_flag);
-        }));
+        let secondary_socket = bind_secondary_socket(&local_addr);
+        let other_family_socket = bind_other_family_socket(&local_addr);
+        let other_family_addr = other_family_socket.as_ref().and_then(|s| s.local_addr().ok());
+        let counters = Arc::new(Counters::default());
+        let configured_limits = Arc::new(Snapshot::new(limits));
+        let echo_policy = Arc::new(Snapshot::new(EchoPolicy::new()));
+        let mut cookie_secret = [0u8; 32];
+        randombytes::randombytes_into(&mut cookie_secret);
+        let server = Server {
+            udp_socket: udp_socket,
+            secondary_socket: secondary_socket,
+            other_family_socket: other_family_socket,
+            buffers: Buffers::new(&limits),
+            limits: limits,
+            configured_limits: configured_limits.clone(),
+            counters: counters.clone(),
+            echo_policy: echo_policy.clone(),
+            start_time: Instant::now(),
+            stats_saved_at: Instant::now(),
+            cookie_secret: cookie_secret,
+            stop_flag: cloned_stop_flag,
+            draining: draining.clone(),
+            keepalives: HashMap::new(),
+        };
+        let runtime = mapping_context.as_ref().runtime();
+        let cloned_runtime = runtime.clone();
+        runtime.spawn(move || serve(server, cloned_runtime));
 
-        let unrestricted_endpoints = mapped_socket.endpoints.into_iter().filter_map(|msa| {
-            match msa.nat_restricted {
-                false => Some(msa.addr),
-                true => None,
-            }
-        }).collect();
+        let mut unrestricted_endpoints: Vec<SocketAddr> =
+            mapped_socket.endpoints.into_iter().filter_map(|msa| {
+                match msa.nat_restricted {
+                    false => Some(msa.addr),
+                    true => None,
+                }
+            }).collect();
+        if let Some(other_family_addr) = other_family_addr {
+            unrestricted_endpoints.extend(other_family_endpoints(mapping_context.as_ref(),
+                                                                 &other_family_addr));
+        }
         WOk(SimpleUdpHolePunchServer {
-            _mapping_context: mapping_context,
+            mapping_context: mapping_context,
             stop_flag: stop_flag,
-            _raii_joiner: raii_joiner,
-            known_endpoints: unrestricted_endpoints,
+            draining: draining,
+            local_addr: local_addr,
+            known_endpoints: Snapshot::new(unrestricted_endpoints),
+            counters: counters,
+            limits: configured_limits,
+            echo_policy: echo_policy,
         }, warnings)
     }
 
-    fn run(udp_socket: UdpSocket,
-           stop_flag: Arc<AtomicBool>) {
-        let mut read_buf = [0; 1024];
+    /// Get the external addresses of this server to be shared with peers.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        (*self.known_endpoints.load()).clone()
+    }
+
+    /// A handle to the runtime the server is served on, for running related work alongside it.
+    pub fn runtime(&self) -> RuntimeHandle {
+        self.mapping_context.as_ref().runtime()
+    }
+
+    /// Replace the addresses returned by `addresses`, eg. with the public addresses of a load
+    /// balancer in front of the server. The socket is untouched.
+    pub fn set_addresses(&self, addresses: Vec<SocketAddr>) {
+        self.known_endpoints.store(addresses);
+    }
+
+    /// The limits on the resources spent serving requests.
+    pub fn limits(&self) -> SimpleUdpServerLimits {
+        *self.limits.load()
+    }
+
+    /// Change the limits on the resources spent serving requests without dropping the socket, so
+    /// clients mid-way through mapping aren't disturbed. Takes effect from the next request.
+    pub fn set_limits(&self, limits: SimpleUdpServerLimits) {
+        self.limits.store(limits);
+    }
+
+    /// Change which clients the server answers and which addresses it reflects back. Takes effect
+    /// from the next batch of requests.
+    pub fn set_echo_policy(&self, policy: EchoPolicy) {
+        self.echo_policy.store(policy);
+    }
+
+    /// Stop answering clients in `subnet`, on top of those the echo policy already ignores, eg. to
+    /// cut off a client flooding the server. Their requests are dropped without a reply from the
+    /// next batch on and counted in the stats' `ignored_requests`.
+    pub fn ignore_clients(&self, subnet: IpSubnet) {
+        self.echo_policy.update(|policy| policy.ignored_clients.insert(subnet));
+    }
+
+    /// Answer clients in `subnet` again after `ignore_clients`. Returns whether `subnet` was
+    /// ignored, exactly as given.
+    pub fn unignore_clients(&self, subnet: &IpSubnet) -> bool {
+        self.echo_policy.update(|policy| policy.ignored_clients.remove(subnet))
+    }
+
+    /// The subnets of clients the server ignores.
+    pub fn ignored_clients(&self) -> SubnetList {
+        self.echo_policy.load().ignored_clients.clone()
+    }
+
+    /// While draining, every request is answered with "try later" so that clients move on to
+    /// other servers, eg. before this one is taken down for maintenance. Requests are counted as
+    /// shed.
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::SeqCst);
+    }
+
+    /// Whether the server is draining.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// How many requests the server has answered and shed since it was created.
+    pub fn stats(&self) -> SimpleUdpServerStats {
+        self.counters.session_stats()
+    }
+
+    /// How many requests the server has answered and shed over all its runs, counting the stats
+    /// loaded by `persist_stats`. The same as `stats` if the stats aren't persisted.
+    pub fn cumulative_stats(&self) -> SimpleUdpServerStats {
+        self.counters.cumulative_stats()
+    }
+
+    /// Keep the cumulative stats in the file at `path` so that they survive restarts. The stats
+    /// of previous runs are loaded from the file if it exists, and the file is rewritten every
+    /// minute and when the server is dropped.
+    pub fn persist_stats(&self, path: PathBuf) -> io::Result<()> {
+        let previous_runs = match SimpleUdpServerStats::load(&path) {
+            Ok(stats) => stats,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => SimpleUdpServerStats::default(),
+            Err(e) => return Err(e),
+        };
+        *unwrap_result!(self.counters.stats_file.lock()) = Some(StatsFile {
+            path: path,
+            previous_runs: previous_runs,
+        });
+        self.counters.save_stats()
+    }
+}
+
+// Everything the server needs from one wakeup to the next.
+struct Server {
+    udp_socket: UdpSocket,
+    // Bound to the unspecified address of the other family, so that clients of both families can
+    // use the server.
+    other_family_socket: Option<UdpSocket>,
+    // Bound to another port of the same address, and only used to answer the second request of a
+    // mapping behaviour check.
+    secondary_socket: Option<UdpSocket>,
+    buffers: Buffers,
+    limits: SimpleUdpServerLimits,
+    // Replaced by `set_limits`. Copied into `limits` on each wakeup.
+    configured_limits: Arc<Snapshot<SimpleUdpServerLimits>>,
+    counters: Arc<Counters>,
+    echo_policy: Arc<Snapshot<EchoPolicy>>,
+    start_time: Instant,
+    stats_saved_at: Instant,
+    // Cookies are derived from this and the client's address, so the server doesn't need to
+    // remember who it handed them to.
+    cookie_secret: [u8; 32],
+    stop_flag: Arc<AtomicBool>,
+    draining: Arc<AtomicBool>,
+    // The clients that asked for keepalives, keyed by the address they're sent to.
+    keepalives: HashMap<net::SocketAddr, Keepalive>,
+}
+
+// Keepalives for one client, sent from the socket its request arrived on.
+struct Keepalive {
+    other_family: bool,
+    next_send: Instant,
+    expires: Instant,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        utils::zeroize(&mut self.cookie_secret);
+    }
+}
+
+// Buffers for a batch of requests and their responses, kept from one wakeup to the next so that
+// serving doesn't allocate.
+struct Buffers {
+    read: Vec<Vec<u8>>,
+    write: Vec<Vec<u8>>,
+    responses: Vec<(usize, net::SocketAddr)>,
+}
+
+impl Buffers {
+    fn new(limits: &SimpleUdpServerLimits) -> Buffers {
+        let per_request = REQUEST_BUF_SIZE + listener_message::MAX_MESSAGE_SIZE;
+        let batch_size = cmp::max(cmp::min(limits.memory_budget / per_request, MAX_BATCH_SIZE), 1);
+        Buffers {
+            read: vec![vec![0; REQUEST_BUF_SIZE]; batch_size],
+            write: vec![vec![0; listener_message::MAX_MESSAGE_SIZE]; batch_size],
+            responses: Vec::with_capacity(batch_size),
+        }
+    }
+}
 
-        while !stop_flag.load(Ordering::SeqCst) {
-            if let Ok((bytes_read, peer_addr)) = udp_socket.recv_from(&mut read_buf) {
-                if read_buf[..bytes_read] != listener_message::REQUEST_MAGIC_CONSTANT {
-                    continue;
+// Answer the requests waiting on the sockets then wait for more. Once the server has been stopped
+// the sockets are dropped instead.
+fn serve(mut server: Server, runtime: RuntimeHandle) {
+    if server.stop_flag.load(Ordering::SeqCst) {
+        return;
+    }
+    let limits = *server.configured_limits.load();
+    if limits.memory_budget != server.limits.memory_budget {
+        server.buffers = Buffers::new(&limits);
+    }
+    server.limits = limits;
+    // Ignored requests count towards the limits, so that a flood from an ignored client can't
+    // keep the server reading forever, but not towards the requests served or shed.
+    let ignored_before = server.counters.ignored_requests.load(Ordering::SeqCst);
+    // Draining servers answer nothing, so everything waiting is shed.
+    let draining = server.draining.load(Ordering::SeqCst);
+    let mut served = 0;
+    for &other_family in &[false, true] {
+        while !draining && served < server.limits.max_requests_per_poll {
+            match answer_batch(&mut server, other_family) {
+                Some(n) => served += n,
+                None => break,
+            }
+        }
+    }
+    while !draining && served < server.limits.max_requests_per_poll {
+        match answer_secondary_batch(&mut server) {
+            Some(n) => served += n,
+            None => break,
+        }
+    }
+    let ignored_serving = server.counters.ignored_requests.load(Ordering::SeqCst);
+    let _ = server.counters
+                  .served_requests
+                  .fetch_add(served - (ignored_serving - ignored_before), Ordering::SeqCst);
+    let mut shed = 0;
+    if draining || served >= server.limits.max_requests_per_poll {
+        // There may be more waiting than we can answer. The oldest requests have been waiting
+        // longest and their clients have likely resent them already, so shed those.
+        for &other_family in &[false, true] {
+            while shed < server.limits.max_shed_per_poll {
+                match shed_batch(&mut server, other_family) {
+                    Some(n) => shed += n,
+                    None => break,
                 }
+            }
+        }
+        let ignored_shedding = server.counters.ignored_requests.load(Ordering::SeqCst);
+        let _ = server.counters
+                      .shed_requests
+                      .fetch_add(shed - (ignored_shedding - ignored_serving), Ordering::SeqCst);
+    }
+    send_keepalives(&mut server);
+    if server.stats_saved_at.elapsed() >= Duration::from_secs(STATS_SAVE_INTERVAL_SECS) {
+        // A failed write is retried next time round, and the counts are still in memory.
+        let _ = server.counters.save_stats();
+        server.stats_saved_at = Instant::now();
+    }
+    let cloned_runtime = runtime.clone();
+    if served >= server.limits.max_requests_per_poll {
+        // More may be waiting. Let other jobs run first, then carry on.
+        runtime.spawn(move || serve(server, cloned_runtime));
+        return;
+    }
+    // Wake for the next request, or else for the next keepalive or saving the stats.
+    let mut deadline = server.stats_saved_at + Duration::from_secs(STATS_SAVE_INTERVAL_SECS);
+    if let Some(next_send) = server.keepalives.values().map(|keepalive| keepalive.next_send).min() {
+        deadline = cmp::min(deadline, next_send);
+    }
+    let sockets = {
+        let mut sockets: Vec<&Pollable> = vec![&server.udp_socket];
+        if let Some(ref socket) = server.other_family_socket {
+            sockets.push(socket);
+        }
+        if let Some(ref socket) = server.secondary_socket {
+            sockets.push(socket);
+        }
+        Sockets::new(&sockets).or_stopped(&server.stop_flag)
+    };
+    runtime.when_readable(sockets, Some(deadline), move || serve(server, cloned_runtime));
+}
 
+// Read a batch of requests from the main socket, or the socket of the other address family, and
+// answer them. Returns the number of requests read, or `None` on the first error, which is usually
+// `WouldBlock` once the socket has been drained.
+fn answer_batch(server: &mut Server, other_family: bool) -> Option<usize> {
+    let socket = match (other_family, &server.other_family_socket) {
+        (false, _) => &server.udp_socket,
+        (true, &Some(ref socket)) => socket,
+        (true, &None) => return None,
+    };
+    let buffers = &mut server.buffers;
+    let received = match batch::recv_batch(socket, &mut buffers.read) {
+        Ok(received) => received,
+        Err(..) => return None,
+    };
+    buffers.responses.clear();
+    let period = server.start_time.elapsed().as_secs() / COOKIE_PERIOD_SECS;
+    let echo_policy = server.echo_policy.load();
+    let mut ignored = 0;
+    for (read_buf, &(bytes_read, peer_addr)) in buffers.read.iter().zip(&received) {
+        if !echo_policy.answers(&peer_addr.ip()) {
+            ignored += 1;
+            continue;
+        }
+        let request = &read_buf[..bytes_read];
+        // Latency probes are reflected as they are, padding and all, so they can't amplify
+        // anything and need no cookie. They're sent straight back rather than with the rest of
+        // the batch so the round trip doesn't include the time spent answering other requests.
+        if let Some(mut probe) = listener_message::LatencyProbe::decode(request) {
+            probe.server_timestamp_micros = micros(server.start_time.elapsed());
+            let _ = socket.send_to(&probe.encode()[..], peer_addr);
+            continue;
+        }
+        if let Some(request) = listener_message::MappingBehaviourRequest::decode(request) {
+            if echo_policy.echoes(&peer_addr.ip()) {
+                let report =
+                    mapping_behaviour_report(&server.secondary_socket, &request, peer_addr);
+                let num_responses = buffers.responses.len();
+                buffers.write[num_responses][..report.len()].copy_from_slice(&report[..]);
+                buffers.responses.push((report.len(), peer_addr));
+            }
+            continue;
+        }
+        // The nonce is `None` for the old four byte requests.
+        let mut magic = [0u8; 4];
+        let mut nonce = None;
+        let has_cookie = match listener_message::PaddedRequest::decode(request) {
+            Some(padded) => {
+                magic = padded.magic;
+                nonce = Some(padded.nonce);
+                match padded.cookie {
+                    Some(ref cookie) => cookie_is_valid(&server.cookie_secret, &peer_addr,
+                                                        period, cookie),
+                    None => false,
+                }
+            },
+            None if request.len() == magic.len() && !server.limits.require_cookies => {
+                magic.copy_from_slice(request);
+                true
+            },
+            None => continue,
+        };
+        if magic != listener_message::PING_MAGIC_CONSTANT &&
+           magic != listener_message::REQUEST_MAGIC_CONSTANT &&
+           magic != listener_message::KEEPALIVE_MAGIC_CONSTANT {
+            continue;
+        }
+        // Keepalives are sent unasked for until the lease runs out, so they're only granted to
+        // clients which have echoed a cookie and so proved the address is theirs.
+        if magic == listener_message::KEEPALIVE_MAGIC_CONSTANT && nonce.is_none() {
+            continue;
+        }
+        if has_cookie && magic != listener_message::PING_MAGIC_CONSTANT &&
+           !echo_policy.echoes(&peer_addr.ip()) {
+            continue;
+        }
+        let num_responses = buffers.responses.len();
+        let write_buf = &mut buffers.write[num_responses][..];
+        let encoded = match (has_cookie, nonce) {
+            (false, Some(ref nonce)) => {
+                let cookie = make_cookie(&server.cookie_secret, &peer_addr, period);
+                let reply = listener_message::encode_cookie(&cookie, nonce);
+                write_buf[..reply.len()].copy_from_slice(&reply[..]);
+                Ok(reply.len())
+            },
+            (_, Some(nonce)) if magic == listener_message::KEEPALIVE_MAGIC_CONSTANT => {
+                let granted = grant_keepalive(&mut server.keepalives,
+                                              &server.limits,
+                                              peer_addr,
+                                              other_family);
+                if granted {
+                    let reply = listener_message::KeepaliveGranted {
+                        nonce: nonce,
+                        external_addr: reflected_addr(peer_addr),
+                        interval_secs: server.limits.keepalive_interval.as_secs() as u32,
+                        lease_secs: server.limits.keepalive_lease.as_secs() as u32,
+                    }.encode();
+                    write_buf[..reply.len()].copy_from_slice(&reply[..]);
+                    Ok(reply.len())
+                } else {
+                    let reply = listener_message::TRY_LATER_MAGIC_CONSTANT;
+                    write_buf[..reply.len()].copy_from_slice(&reply[..]);
+                    Ok(reply.len())
+                }
+            },
+            _ if magic == listener_message::PING_MAGIC_CONSTANT => {
+                let resp = listener_message::Pong {
+                    uptime_secs: server.start_time.elapsed().as_secs(),
+                    protocol_version: listener_message::PROTOCOL_VERSION,
+                };
+                resp.encode_into(write_buf)
+            },
+            (_, Some(nonce)) => {
+                let resp = listener_message::ConfirmedExternalAddr {
+                    nonce: nonce,
+                    external_addr: SocketAddr(reflected_addr(peer_addr)),
+                };
+                resp.encode_into(write_buf)
+            },
+            (_, None) => {
                 let resp = listener_message::EchoExternalAddr {
-                    external_addr: SocketAddr(peer_addr.clone()),
+                    external_addr: SocketAddr(reflected_addr(peer_addr)),
                 };
+                resp.encode_into(write_buf)
+            },
+        };
+        // Responses only echo the peer's own address back, so there's nothing that shouldn't fit,
+        // but a server mustn't fall over if something does.
+        let written = match encoded {
+            Ok(written) => written,
+            Err(_) => continue,
+        };
+        buffers.responses.push((written, peer_addr));
+    }
+    let datagrams: Vec<(&[u8], net::SocketAddr)> =
+        buffers.write
+               .iter()
+               .zip(&buffers.responses)
+               .map(|(write_buf, &(written, peer_addr))| (&write_buf[..written], peer_addr))
+               .collect();
+    let _ = batch::send_batch(socket, &datagrams);
+    let _ = server.counters.ignored_requests.fetch_add(ignored, Ordering::SeqCst);
+    Some(received.len())
+}
 
-                let _ = udp_socket.send_to(&unwrap_result!(serialise(&resp)),
-                                           peer_addr);
-            }
+// Start or renew keepalives to `peer_addr`, unless the server already sends as many as it may.
+fn grant_keepalive(keepalives: &mut HashMap<net::SocketAddr, Keepalive>,
+                   limits: &SimpleUdpServerLimits,
+                   peer_addr: net::SocketAddr,
+                   other_family: bool)
+                   -> bool {
+    let now = Instant::now();
+    let expires = now + limits.keepalive_lease;
+    if let Some(keepalive) = keepalives.get_mut(&peer_addr) {
+        keepalive.other_family = other_family;
+        keepalive.expires = expires;
+        return true;
+    }
+    if keepalives.len() >= limits.max_keepalives {
+        return false;
+    }
+    let _ = keepalives.insert(peer_addr, Keepalive {
+        other_family: other_family,
+        next_send: now + limits.keepalive_interval,
+        expires: expires,
+    });
+    true
+}
+
+// Send the keepalives that are due and forget the clients whose leases have run out.
+fn send_keepalives(server: &mut Server) {
+    if server.keepalives.is_empty() {
+        return;
+    }
+    let now = Instant::now();
+    let mut expired = Vec::new();
+    for (peer_addr, keepalive) in &mut server.keepalives {
+        if keepalive.expires <= now {
+            expired.push(*peer_addr);
+            continue;
+        }
+        if keepalive.next_send > now {
+            continue;
         }
+        let socket = match (keepalive.other_family, &server.other_family_socket) {
+            (true, &Some(ref socket)) => socket,
+            _ => &server.udp_socket,
+        };
+        let _ = socket.send_to(&listener_message::KEEPALIVE_MAGIC_CONSTANT[..], peer_addr);
+        keepalive.next_send = now + server.limits.keepalive_interval;
+    }
+    for peer_addr in expired {
+        let _ = server.keepalives.remove(&peer_addr);
     }
+}
 
-    /// Get the external addresses of this server to be shared with peers.
-    pub fn addresses(&self) -> Vec<SocketAddr> {
-        self.known_endpoints.clone()
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + (duration.subsec_nanos() / 1_000) as u64
+}
+
+// Read a batch of requests from the secondary socket and answer the mapping behaviour requests
+// among them. Returns the number of requests read, or `None` once the socket has been drained or
+// if there is no secondary socket.
+fn answer_secondary_batch(server: &mut Server) -> Option<usize> {
+    let socket = match server.secondary_socket {
+        Some(ref socket) => socket,
+        None => return None,
+    };
+    let received = match batch::recv_batch(socket, &mut server.buffers.read) {
+        Ok(received) => received,
+        Err(..) => return None,
+    };
+    let echo_policy = server.echo_policy.load();
+    let mut reports = Vec::new();
+    let mut ignored = 0;
+    for (read_buf, &(bytes_read, peer_addr)) in server.buffers.read.iter().zip(&received) {
+        if !echo_policy.answers(&peer_addr.ip()) {
+            ignored += 1;
+            continue;
+        }
+        if !echo_policy.echoes(&peer_addr.ip()) {
+            continue;
+        }
+        let request = &read_buf[..bytes_read];
+        let request = match listener_message::MappingBehaviourRequest::decode(request) {
+            Some(request) => request,
+            None => continue,
+        };
+        let report = mapping_behaviour_report(&server.secondary_socket, &request, peer_addr);
+        reports.push((report, peer_addr));
     }
+    let datagrams: Vec<(&[u8], net::SocketAddr)> =
+        reports.iter().map(|&(ref report, peer_addr)| (&report[..], peer_addr)).collect();
+    let _ = batch::send_batch(socket, &datagrams);
+    let _ = server.counters.ignored_requests.fetch_add(ignored, Ordering::SeqCst);
+    Some(received.len())
+}
+
+// Tell `peer_addr` where we saw it and, if the request carries the address our main port saw,
+// whether that's the same.
+fn mapping_behaviour_report(secondary_socket: &Option<UdpSocket>,
+                            request: &listener_message::MappingBehaviourRequest,
+                            peer_addr: net::SocketAddr)
+                            -> [u8; listener_message::MAPPING_BEHAVIOUR_REPORT_LEN] {
+    let secondary_port = match *secondary_socket {
+        Some(ref socket) => socket.local_addr().map(|addr| addr.port()).unwrap_or(0),
+        None => 0,
+    };
+    let external_addr = reflected_addr(peer_addr);
+    let verdict = match request.first_addr {
+        None => listener_message::MAPPING_VERDICT_NONE,
+        Some(first_addr) if first_addr == external_addr => listener_message::MAPPING_VERDICT_SAME,
+        Some(_) => listener_message::MAPPING_VERDICT_DIFFERENT,
+    };
+    let report = listener_message::MappingBehaviourReport {
+        nonce: request.nonce,
+        external_addr: external_addr,
+        secondary_port: secondary_port,
+        verdict: verdict,
+    };
+    report.encode()
+}
+
+// The address to tell a client we saw it at: its ipv4 address if a dual-stack socket saw it at an
+// ipv4-mapped one, so that clients see the address in the family they sent from.
+fn reflected_addr(peer_addr: net::SocketAddr) -> net::SocketAddr {
+    match (peer_addr, socket_utils::unmap_ipv4(&peer_addr.ip())) {
+        (net::SocketAddr::V6(..), IpAddr::V4(ip)) => {
+            net::SocketAddr::V4(net::SocketAddrV4::new(ip, peer_addr.port()))
+        },
+        _ => peer_addr,
+    }
+}
+
+// Bind a socket of the other address family to `local_addr`'s port, so that clients of both
+// families can use the server, or to any port if that's taken.
+fn bind_other_family_socket(local_addr: &Option<SocketAddr>) -> Option<UdpSocket> {
+    let local_addr = match *local_addr {
+        Some(ref local_addr) => local_addr,
+        None => return None,
+    };
+    let unspecified = match local_addr.ip() {
+        IpAddr::V4(..) => IpAddr::V6(net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+        IpAddr::V6(..) => IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0)),
+    };
+    let socket = match UdpSocket::bind((unspecified, local_addr.port())) {
+        Ok(socket) => socket,
+        Err(_) => {
+            match UdpSocket::bind((unspecified, 0)) {
+                Ok(socket) => socket,
+                Err(_) => return None,
+            }
+        },
+    };
+    match socket.set_nonblocking(true) {
+        Ok(()) => Some(socket),
+        Err(_) => None,
+    }
+}
+
+// The addresses clients can reach the other family's socket at: the loopback address and the
+// addresses of the interfaces, global ones only for ipv6.
+fn other_family_endpoints(mc: &MappingContext, other_family_addr: &net::SocketAddr)
+                          -> Vec<SocketAddr> {
+    let port = other_family_addr.port();
+    let ips: Vec<IpAddr> = match *other_family_addr {
+        net::SocketAddr::V4(..) => {
+            let loopback = IpAddr::V4(net::Ipv4Addr::new(127, 0, 0, 1));
+            let interface_ips = mapping_context::interfaces_v4(mc).into_iter()
+                                                                  .map(|i| IpAddr::V4(i.addr));
+            Some(loopback).into_iter().chain(interface_ips).collect()
+        },
+        net::SocketAddr::V6(..) => {
+            let loopback = IpAddr::V6(net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1));
+            let interface_ips = mapping_context::interfaces_v6(mc)
+                                    .into_iter()
+                                    .filter(|i| ipv6_selection::is_global(&i.addr))
+                                    .map(|i| IpAddr::V6(i.addr));
+            Some(loopback).into_iter().chain(interface_ips).collect()
+        },
+    };
+    ips.into_iter().map(|ip| SocketAddr(net::SocketAddr::new(ip, port))).collect()
+}
+
+// Bind a socket to another port of the address the server listens on.
+fn bind_secondary_socket(local_addr: &Option<SocketAddr>) -> Option<UdpSocket> {
+    let local_addr = match *local_addr {
+        Some(ref local_addr) => local_addr,
+        None => return None,
+    };
+    let socket = match UdpSocket::bind((local_addr.ip(), 0)) {
+        Ok(socket) => socket,
+        Err(_) => return None,
+    };
+    match socket.set_nonblocking(true) {
+        Ok(()) => Some(socket),
+        Err(_) => None,
+    }
+}
+
+// Derive the cookie handed to `peer_addr` during `period`.
+fn make_cookie(secret: &[u8; 32], peer_addr: &net::SocketAddr, period: u64)
+               -> [u8; listener_message::COOKIE_LEN] {
+    let mut input = [0u8; 1 + 8 + 16 + 2];
+    match *peer_addr {
+        net::SocketAddr::V4(ref addr) => {
+            input[0] = 4;
+            input[9..13].copy_from_slice(&addr.ip().octets()[..]);
+        },
+        net::SocketAddr::V6(ref addr) => {
+            input[0] = 6;
+            input[9..25].copy_from_slice(&addr.ip().octets()[..]);
+        },
+    }
+    BigEndian::write_u64(&mut input[1..9], period);
+    BigEndian::write_u16(&mut input[25..], peer_addr.port());
+    let hmacsha256::Tag(tag) = hmacsha256::authenticate(&input[..], &hmacsha256::Key(*secret));
+    let mut cookie = [0u8; listener_message::COOKIE_LEN];
+    cookie.copy_from_slice(&tag[..listener_message::COOKIE_LEN]);
+    cookie
+}
+
+// Check a cookie echoed by `peer_addr` was handed out during this period or the one before.
+fn cookie_is_valid(secret: &[u8; 32],
+                   peer_addr: &net::SocketAddr,
+                   period: u64,
+                   cookie: &[u8; listener_message::COOKIE_LEN])
+                   -> bool {
+    let current = make_cookie(secret, peer_addr, period);
+    if utils::constant_time_eq(&current[..], &cookie[..]) {
+        return true;
+    }
+    if period == 0 {
+        return false;
+    }
+    let previous = make_cookie(secret, peer_addr, period - 1);
+    utils::constant_time_eq(&previous[..], &cookie[..])
+}
+
+// Read a batch of requests and tell their senders to try later. Returns the number of requests
+// read, or `None` once the socket has been drained.
+fn shed_batch(server: &mut Server, other_family: bool) -> Option<usize> {
+    let socket = match (other_family, &server.other_family_socket) {
+        (false, _) => &server.udp_socket,
+        (true, &Some(ref socket)) => socket,
+        (true, &None) => return None,
+    };
+    let received = match batch::recv_batch(socket, &mut server.buffers.read) {
+        Ok(received) => received,
+        Err(..) => return None,
+    };
+    // The reply is four bytes, so don't reply to anything shorter. Ignored clients aren't even
+    // told to try later.
+    let try_later = &listener_message::TRY_LATER_MAGIC_CONSTANT[..];
+    let echo_policy = server.echo_policy.load();
+    let (answered, ignored): (Vec<_>, Vec<_>) =
+        received.iter().partition(|&&(_, peer_addr)| echo_policy.answers(&peer_addr.ip()));
+    let datagrams: Vec<(&[u8], net::SocketAddr)> =
+        answered.iter()
+                .filter(|&&&(bytes_read, _)| bytes_read >= try_later.len())
+                .map(|&&(_, peer_addr)| (try_later, peer_addr))
+                .collect();
+    let _ = batch::send_batch(socket, &datagrams);
+    let _ = server.counters.ignored_requests.fetch_add(ignored.len(), Ordering::SeqCst);
+    Some(received.len())
 }
 
 impl<T: AsRef<MappingContext>> Drop for SimpleUdpHolePunchServer<T> {
     fn drop(&mut self) {
-        self.stop_flag.store(true, Ordering::SeqCst);
+        self.mapping_context.as_ref().runtime().stop(&self.stop_flag);
+        let _ = self.counters.save_stats();
+        if let Some(local_addr) = self.local_addr {
+            let events = mapping_context::events(self.mapping_context.as_ref());
+            events.send(Event::Closed { local_addr: local_addr });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    use rand;
+
+    use mapping_context::MappingContext;
+    use ping::ping_server;
+    use socket_utils;
+
+    #[test]
+    fn persisted_stats_accumulate_across_runs() {
+        let path = env::temp_dir().join(format!("nat_traversal_stats_{}.json",
+                                                rand::random::<u64>()));
+        let mut previous_runs = 0;
+        for _ in 0..2 {
+            let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+            let deadline = Instant::now() + Duration::from_secs(3);
+            let server = unwrap_result!(SimpleUdpHolePunchServer::new(Box::new(mapping_context),
+                                                                      deadline)
+                                            .result_discard());
+            unwrap_result!(server.persist_stats(path.clone()));
+            let addr = unwrap_result!(server.addresses().into_iter().find(|addr| {
+                socket_utils::is_loopback(&addr.ip())
+            }).ok_or("No loopback address"));
+            let deadline = Instant::now() + Duration::from_secs(3);
+            let _ = unwrap_result!(ping_server(&addr, deadline));
+
+            let stats = server.stats();
+            assert!(stats.served_requests > 0);
+            let cumulative = server.cumulative_stats().served_requests;
+            assert!(cumulative >= previous_runs + stats.served_requests);
+            previous_runs = cumulative;
+        }
+        let saved = unwrap_result!(SimpleUdpServerStats::load(&path));
+        assert!(saved.served_requests >= 2);
+        unwrap_result!(fs::remove_file(&path));
+    }
+
+    #[test]
+    fn ignored_clients_are_dropped_and_counted() {
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let server = unwrap_result!(SimpleUdpHolePunchServer::new(Box::new(mapping_context),
+                                                                  deadline)
+                                        .result_discard());
+        let addr = unwrap_result!(server.addresses().into_iter().find(|addr| {
+            socket_utils::is_loopback(&addr.ip())
+        }).ok_or("No loopback address"));
+        server.ignore_clients(unwrap_result!("127.0.0.0/8".parse()));
+        server.ignore_clients(unwrap_result!("::1/128".parse()));
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        assert!(ping_server(&addr, deadline).is_err());
+        let stats = server.stats();
+        assert_eq!(stats.served_requests, 0);
+        assert!(stats.ignored_requests > 0);
     }
 }
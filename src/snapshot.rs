@@ -0,0 +1,94 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A value which is read far more often than it's written, such as a server list. Readers take an
+/// `Arc` to the current version and never wait for a writer to finish building the next one:
+/// writers copy the value, modify the copy, and swap it in. The lock guarding the swap is only
+/// ever held long enough to clone or replace an `Arc`.
+pub struct Snapshot<T> {
+    current: RwLock<Arc<T>>,
+    // Serialises writers so that concurrent updates don't overwrite each other.
+    writer: Mutex<()>,
+}
+
+impl<T: Clone> Snapshot<T> {
+    /// A snapshot holding `value`.
+    pub fn new(value: T) -> Snapshot<T> {
+        Snapshot {
+            current: RwLock::new(Arc::new(value)),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The current version of the value. It stays valid, if out of date, however long it's kept.
+    pub fn load(&self) -> Arc<T> {
+        unwrap_result!(self.current.read()).clone()
+    }
+
+    /// Replace the value.
+    pub fn store(&self, value: T) {
+        let _writer = unwrap_result!(self.writer.lock());
+        *unwrap_result!(self.current.write()) = Arc::new(value);
+    }
+
+    /// Modify a copy of the value with `f` and make it the current version. Returns what `f`
+    /// returns.
+    pub fn update<R, F>(&self, f: F) -> R
+        where F: FnOnce(&mut T) -> R
+    {
+        let _writer = unwrap_result!(self.writer.lock());
+        let mut value = (*self.load()).clone();
+        let ret = f(&mut value);
+        *unwrap_result!(self.current.write()) = Arc::new(value);
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn updates_are_not_lost_and_old_snapshots_stay_valid() {
+        let snapshot = Arc::new(Snapshot::new(Vec::new()));
+        let before = snapshot.load();
+
+        let writers: Vec<_> = (0..4).map(|i| {
+            let snapshot = snapshot.clone();
+            thread!("snapshot writer", move || {
+                for j in 0..100 {
+                    snapshot.update(|v| v.push(i * 100 + j));
+                }
+            })
+        }).collect();
+        for writer in writers {
+            unwrap_result!(writer.join());
+        }
+
+        assert!(before.is_empty());
+        let mut after = (*snapshot.load()).clone();
+        after.sort();
+        assert_eq!(after, (0..400).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Taking over sockets bound by a service manager, as systemd does for socket activated services,
+//! so that a server can listen on a privileged port without running as root.
+
+use std::env;
+use std::io;
+use std::mem;
+use std::net::{TcpListener, UdpSocket};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use libc;
+
+// The first descriptor passed by the service manager. 0, 1 and 2 are stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket handed over by the service manager.
+#[derive(Debug)]
+pub enum ActivatedSocket {
+    /// A udp socket, for a `SimpleUdpHolePunchServer`.
+    Udp(UdpSocket),
+    /// A listening tcp socket, for a `SimpleTcpHolePunchServer`.
+    Tcp(TcpListener),
+}
+
+/// Take the sockets passed to this process with the `LISTEN_FDS` protocol used by systemd's
+/// socket activation, in the order they're listed in the socket unit. Returns no sockets if none
+/// were passed to this process, eg. because it wasn't socket activated.
+///
+/// The environment variables are cleared so that child processes don't take the sockets too, and
+/// the sockets are marked close-on-exec. This should only be called once, and before any other
+/// code opens files or sockets.
+#[allow(unsafe_code)]
+pub fn activated_sockets() -> io::Result<Vec<ActivatedSocket>> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<libc::pid_t>().ok());
+    let num_fds = env::var("LISTEN_FDS").ok().and_then(|num_fds| num_fds.parse::<RawFd>().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let num_fds = match (pid, num_fds) {
+        (Some(pid), Some(num_fds)) if pid == unsafe { libc::getpid() } => num_fds,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut sockets = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + num_fds {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = match try!(socket_type(fd)) {
+            libc::SOCK_DGRAM => ActivatedSocket::Udp(unsafe { UdpSocket::from_raw_fd(fd) }),
+            libc::SOCK_STREAM => ActivatedSocket::Tcp(unsafe { TcpListener::from_raw_fd(fd) }),
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("Passed descriptor {} isn't a udp or tcp \
+                                                   socket",
+                                                  fd)));
+            },
+        };
+        // A unix domain socket has the right type but no internet address.
+        let has_inet_addr = match socket {
+            ActivatedSocket::Udp(ref socket) => socket.local_addr().is_ok(),
+            ActivatedSocket::Tcp(ref listener) => listener.local_addr().is_ok(),
+        };
+        if !has_inet_addr {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Passed descriptor {} isn't bound to an internet \
+                                               address",
+                                              fd)));
+        }
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+#[allow(unsafe_code)]
+fn socket_type(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut socket_type: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(fd,
+                         libc::SOL_SOCKET,
+                         libc::SO_TYPE,
+                         &mut socket_type as *mut libc::c_int as *mut libc::c_void,
+                         &mut len)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket_type)
+}
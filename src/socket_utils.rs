@@ -16,14 +16,25 @@
 // relating to use of the SAFE Network Software.
 
 use std::io;
-use std::net::{TcpStream, UdpSocket, IpAddr, Ipv4Addr, Ipv6Addr};
-use std::net;
+use std::net::{UdpSocket, IpAddr, Ipv4Addr, Ipv6Addr};
+#[cfg(feature = "tcp")]
+use std::net::{self, TcpStream};
 use std::time::Instant;
-#[cfg(target_family = "windows")]
+#[cfg(any(all(feature = "tcp", target_family = "windows"), target_os = "macos",
+          target_os = "ios"))]
 use std::mem;
 use socket_addr::SocketAddr;
 use std::io::ErrorKind;
+#[cfg(feature = "tcp")]
 use net2;
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+use std::os::unix::io::AsRawFd;
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+use libc;
+#[cfg(all(feature = "tcp", target_family = "windows"))]
+use winapi;
+#[cfg(all(feature = "tcp", target_family = "windows"))]
+use ws2_32;
 
 /// A self interruptable receive trait that allows a timed-out period to be defined
 pub trait RecvUntil {
@@ -39,43 +50,53 @@ impl RecvUntil for UdpSocket {
                   buf: &mut [u8],
                   deadline: Instant)
                   -> io::Result<Option<(usize, SocketAddr)>> {
-        let old_timeout = try!(self.read_timeout());
-        loop {
-            let current_time = Instant::now();
-            if current_time >= deadline {
-                try!(self.set_read_timeout(old_timeout));
-                return Ok(None);
-            }
-            {
-                let timeout = deadline - current_time;
-                try!(self.set_read_timeout(Some(timeout)));
-            }
+        let res = try!(recv_loop(self, deadline, |socket| socket.recv_from(buf)));
+        Ok(res.map(|(bytes_len, addr)| (bytes_len, SocketAddr(addr))))
+    }
+}
+
+/// Call `recv` on `socket` until it returns something or `deadline` passes, with the socket's
+/// read timeout set to the time remaining. Returns `None` on timeout.
+pub fn recv_loop<T, F>(socket: &UdpSocket, deadline: Instant, mut recv: F)
+                       -> io::Result<Option<T>>
+    where F: FnMut(&UdpSocket) -> io::Result<T>
+{
+    let old_timeout = try!(socket.read_timeout());
+    loop {
+        let current_time = Instant::now();
+        if current_time >= deadline {
+            try!(socket.set_read_timeout(old_timeout));
+            return Ok(None);
+        }
+        {
+            let timeout = deadline - current_time;
+            try!(socket.set_read_timeout(Some(timeout)));
+        }
 
-            match self.recv_from(buf) {
-                Ok((bytes_len, addr)) => {
-                    try!(self.set_read_timeout(old_timeout));
-                    return Ok(Some((bytes_len, SocketAddr(addr))));
-                },
-                Err(e) => {
-                    match e.kind() {
-                        ErrorKind::TimedOut | ErrorKind::WouldBlock => {
-                            try!(self.set_read_timeout(old_timeout));
-                            return Ok(None);
-                        },
-                        ErrorKind::Interrupted => (),
-                        // On Windows, when we send a packet to an endpoint
-                        // which is not being listened on, the system responds
-                        // with an ICMP packet "ICMP port unreachable".
-                        // We do not care about this silly behavior, so we just
-                        // ignore it.
-                        // See here for more info:
-                        // https://bobobobo.wordpress.com/2009/05/17/udp-an-existing-connection-was-forcibly-closed-by-the-remote-host/
-                        ErrorKind::ConnectionReset => (),
-                        _ => {
-                            try!(self.set_read_timeout(old_timeout));
-                            return Err(e);
-                        },
-                    }
+        match recv(socket) {
+            Ok(res) => {
+                try!(socket.set_read_timeout(old_timeout));
+                return Ok(Some(res));
+            },
+            Err(e) => {
+                match e.kind() {
+                    ErrorKind::TimedOut | ErrorKind::WouldBlock => {
+                        try!(socket.set_read_timeout(old_timeout));
+                        return Ok(None);
+                    },
+                    ErrorKind::Interrupted => (),
+                    // On Windows, when we send a packet to an endpoint
+                    // which is not being listened on, the system responds
+                    // with an ICMP packet "ICMP port unreachable".
+                    // We do not care about this silly behavior, so we just
+                    // ignore it.
+                    // See here for more info:
+                    // https://bobobobo.wordpress.com/2009/05/17/udp-an-existing-connection-was-forcibly-closed-by-the-remote-host/
+                    ErrorKind::ConnectionReset => (),
+                    _ => {
+                        try!(socket.set_read_timeout(old_timeout));
+                        return Err(e);
+                    },
                 }
             }
         }
@@ -92,6 +113,7 @@ pub fn ipv6_is_unspecified(addr: &Ipv6Addr) -> bool {
     addr.segments() == [0, 0, 0, 0, 0, 0, 0, 0]
 }
 
+#[cfg(feature = "tcp")]
 pub fn ipv4_unspecified_to_loopback(addr: Ipv4Addr) -> Ipv4Addr {
     if ipv4_is_unspecified(&addr) {
         Ipv4Addr::new(127, 0, 0, 1)
@@ -101,6 +123,7 @@ pub fn ipv4_unspecified_to_loopback(addr: Ipv4Addr) -> Ipv4Addr {
     }
 }
 
+#[cfg(feature = "tcp")]
 pub fn ipv6_unspecified_to_loopback(addr: Ipv6Addr) -> Ipv6Addr {
     if ipv6_is_unspecified(&addr) {
         Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)
@@ -110,6 +133,7 @@ pub fn ipv6_unspecified_to_loopback(addr: Ipv6Addr) -> Ipv6Addr {
     }
 }
 
+#[cfg(feature = "tcp")]
 pub fn ip_unspecified_to_loopback(addr: IpAddr) -> IpAddr {
     match addr {
         IpAddr::V4(addr_v4) => IpAddr::V4(ipv4_unspecified_to_loopback(addr_v4)),
@@ -127,6 +151,28 @@ pub fn ipv6_is_loopback(addr: &Ipv6Addr) -> bool {
     addr.segments() == [0, 0, 0, 0, 0, 0, 0, 1]
 }
 
+// TODO(canndrew): Remove this once SocketAddr::is_ipv6 is stable
+#[cfg(feature = "tcp")]
+pub fn is_ipv6(addr: &::std::net::SocketAddr) -> bool {
+    match *addr {
+        ::std::net::SocketAddr::V4(..) => false,
+        ::std::net::SocketAddr::V6(..) => true,
+    }
+}
+
+/// Dual-stack sockets see ipv4 peers at ipv4-mapped ipv6 addresses. Returns the ipv4 address for
+/// those and `addr` otherwise.
+pub fn unmap_ipv4(addr: &IpAddr) -> IpAddr {
+    if let IpAddr::V6(ref addr_v6) = *addr {
+        let segments = addr_v6.segments();
+        if segments[..5].iter().all(|s| *s == 0) && segments[5] == 0xffff {
+            return IpAddr::V4(Ipv4Addr::new((segments[6] >> 8) as u8, segments[6] as u8,
+                                            (segments[7] >> 8) as u8, segments[7] as u8));
+        }
+    }
+    *addr
+}
+
 pub fn is_loopback(addr: &IpAddr) -> bool {
     match *addr {
         IpAddr::V4(ref addr_v4) => ipv4_is_loopback(addr_v4),
@@ -134,21 +180,75 @@ pub fn is_loopback(addr: &IpAddr) -> bool {
     }
 }
 
-#[cfg(target_family = "unix")]
+/// Whether `addr` is `0.0.0.0` or `::`, which a socket binds to to receive on every interface.
+pub fn is_unspecified(addr: &IpAddr) -> bool {
+    match *addr {
+        IpAddr::V4(ref addr_v4) => addr_v4.octets() == [0; 4],
+        IpAddr::V6(ref addr_v6) => addr_v6.segments() == [0; 8],
+    }
+}
+
+#[cfg(all(feature = "tcp", target_family = "unix"))]
 pub fn enable_so_reuseport(sock: &net2::TcpBuilder) -> io::Result<()> {
     use net2::unix::UnixTcpBuilderExt;
     let _ = try!(sock.reuse_port(true));
     Ok(())
 }
 
-#[cfg(target_family = "windows")]
-pub fn enable_so_reuseport(_sock: &net2::TcpBuilder) -> io::Result<()> {
-    Ok(())
+// Windows has no SO_REUSEPORT. SO_REUSEADDR alone lets sockets share a port there, much as both
+// options together do elsewhere, but it can't be combined with SO_EXCLUSIVEADDRUSE, which
+// hardened configurations may turn on by default. So make sure that's off.
+#[cfg(all(feature = "tcp", target_family = "windows"))]
+pub fn enable_so_reuseport(sock: &net2::TcpBuilder) -> io::Result<()> {
+    set_exclusive_addr_use(sock, false)
+}
+
+#[cfg(all(feature = "tcp", target_family = "windows"))]
+const SOL_SOCKET: winapi::c_int = 0xffff;
+#[cfg(all(feature = "tcp", target_family = "windows"))]
+const SO_REUSEADDR: winapi::c_int = 0x0004;
+#[cfg(all(feature = "tcp", target_family = "windows"))]
+const SO_EXCLUSIVEADDRUSE: winapi::c_int = !SO_REUSEADDR;
+// Returned by bind on Windows when another socket holds the address with SO_EXCLUSIVEADDRUSE.
+#[cfg(all(feature = "tcp", target_family = "windows"))]
+const WSAEACCES: i32 = 10013;
+
+/// Set or clear SO_EXCLUSIVEADDRUSE, which stops any other socket binding the same address.
+#[cfg(all(feature = "tcp", target_family = "windows"))]
+#[allow(unsafe_code)]
+pub fn set_exclusive_addr_use(sock: &net2::TcpBuilder, exclusive: bool) -> io::Result<()> {
+    use std::os::windows::io::AsRawSocket;
+    let value = exclusive as winapi::c_int;
+    let value_ptr: *const winapi::c_int = &value;
+    let ret = unsafe {
+        ws2_32::setsockopt(sock.as_raw_socket() as winapi::SOCKET,
+                           SOL_SOCKET,
+                           SO_EXCLUSIVEADDRUSE,
+                           value_ptr as *const winapi::c_char,
+                           mem::size_of::<winapi::c_int>() as winapi::c_int)
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(unsafe { ws2_32::WSAGetLastError() }))
+    }
+}
+
+/// Whether binding failed because another socket holds the address exclusively, so it can't be
+/// shared however this socket is set up.
+#[cfg(all(feature = "tcp", target_family = "windows"))]
+pub fn is_exclusive_bind_error(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(WSAEACCES)
+}
+
+#[cfg(all(feature = "tcp", target_family = "unix"))]
+pub fn is_exclusive_bind_error(_err: &io::Error) -> bool {
+    false
 }
 
 // TODO(canndrew): This function should be deprecated once this issue
 // (https://github.com/rust-lang-nursery/net2-rs/issues/26) is resolved.
-#[cfg(target_family = "unix")]
+#[cfg(all(feature = "tcp", target_family = "unix"))]
 #[allow(unsafe_code)]
 pub fn tcp_builder_local_addr(sock: &net2::TcpBuilder) -> io::Result<net::SocketAddr> {
     use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
@@ -159,7 +259,7 @@ pub fn tcp_builder_local_addr(sock: &net2::TcpBuilder) -> io::Result<net::Socket
     ret
 }
 
-#[cfg(target_family = "windows")]
+#[cfg(all(feature = "tcp", target_family = "windows"))]
 #[allow(unsafe_code)]
 pub fn tcp_builder_local_addr(sock: &net2::TcpBuilder) -> io::Result<net::SocketAddr> {
     use std::os::windows::io::{AsRawSocket, FromRawSocket};
@@ -170,3 +270,194 @@ pub fn tcp_builder_local_addr(sock: &net2::TcpBuilder) -> io::Result<net::Socket
     ret
 }
 
+
+// Pinning sockets to a network interface, so that everything sent through them leaves through
+// that interface whatever the routing table says. Linux has SO_BINDTODEVICE, which takes the
+// interface's name and needs CAP_NET_RAW before Linux 5.7. macOS and iOS have IP_BOUND_IF and
+// IPV6_BOUND_IF, which take its index. Other platforms can't pin sockets.
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_BINDTODEVICE: i32 = 25;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const IP_BOUND_IF: i32 = 25;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const IPV6_BOUND_IF: i32 = 125;
+#[cfg(all(feature = "tcp", any(target_os = "linux", target_os = "android", target_os = "macos",
+                               target_os = "ios")))]
+const IFNAMSIZ: usize = 16;
+
+/// Pin `sock`, an ipv6 socket if `ipv6`, to the interface named `if_name`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[allow(unsafe_code)]
+pub fn bind_to_interface<S: AsRawFd>(sock: &S, if_name: &str, _ipv6: bool) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(sock.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         SO_BINDTODEVICE,
+                         if_name.as_ptr() as *const libc::c_void,
+                         if_name.len() as libc::socklen_t)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The name of the interface that `sock` is pinned to, if any.
+#[cfg(all(feature = "tcp", any(target_os = "linux", target_os = "android")))]
+#[allow(unsafe_code)]
+pub fn bound_interface<S: AsRawFd>(sock: &S, _ipv6: bool) -> io::Result<Option<String>> {
+    let mut name = [0u8; IFNAMSIZ];
+    let mut len = name.len() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(sock.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         SO_BINDTODEVICE,
+                         name.as_mut_ptr() as *mut libc::c_void,
+                         &mut len)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(interface_name(&name[..len as usize]))
+}
+
+/// Pin `sock`, an ipv6 socket if `ipv6`, to the interface named `if_name`.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[allow(unsafe_code)]
+pub fn bind_to_interface<S: AsRawFd>(sock: &S, if_name: &str, ipv6: bool) -> io::Result<()> {
+    use std::ffi::CString;
+
+    let c_name = match CString::new(if_name) {
+        Ok(c_name) => c_name,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+    };
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (level, option) = if ipv6 {
+        (libc::IPPROTO_IPV6, IPV6_BOUND_IF)
+    } else {
+        (libc::IPPROTO_IP, IP_BOUND_IF)
+    };
+    let index_ptr: *const libc::c_uint = &index;
+    let ret = unsafe {
+        libc::setsockopt(sock.as_raw_fd(),
+                         level,
+                         option,
+                         index_ptr as *const libc::c_void,
+                         mem::size_of::<libc::c_uint>() as libc::socklen_t)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The name of the interface that `sock` is pinned to, if any.
+#[cfg(all(feature = "tcp", any(target_os = "macos", target_os = "ios")))]
+#[allow(unsafe_code)]
+pub fn bound_interface<S: AsRawFd>(sock: &S, ipv6: bool) -> io::Result<Option<String>> {
+    extern "C" {
+        fn if_indextoname(ifindex: libc::c_uint, ifname: *mut libc::c_char) -> *mut libc::c_char;
+    }
+
+    let (level, option) = if ipv6 {
+        (libc::IPPROTO_IPV6, IPV6_BOUND_IF)
+    } else {
+        (libc::IPPROTO_IP, IP_BOUND_IF)
+    };
+    let mut index: libc::c_uint = 0;
+    let mut len = mem::size_of::<libc::c_uint>() as libc::socklen_t;
+    let index_ptr: *mut libc::c_uint = &mut index;
+    let ret = unsafe {
+        libc::getsockopt(sock.as_raw_fd(), level, option, index_ptr as *mut libc::c_void, &mut len)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if index == 0 {
+        return Ok(None);
+    }
+    let mut name = [0u8; IFNAMSIZ];
+    let ret = unsafe { if_indextoname(index, name.as_mut_ptr() as *mut libc::c_char) };
+    if ret.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(interface_name(&name))
+}
+
+/// Pin `sock`, an ipv6 socket if `ipv6`, to the interface named `if_name`. Always fails as
+/// sockets can't be pinned on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos",
+              target_os = "ios")))]
+pub fn bind_to_interface<S>(_sock: &S, _if_name: &str, _ipv6: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other,
+                       "Sockets can't be pinned to an interface on this platform"))
+}
+
+/// The name of the interface that `sock` is pinned to, if any.
+#[cfg(all(feature = "tcp", not(any(target_os = "linux", target_os = "android",
+                                   target_os = "macos", target_os = "ios"))))]
+pub fn bound_interface<S>(_sock: &S, _ipv6: bool) -> io::Result<Option<String>> {
+    Ok(None)
+}
+
+// A nul-terminated interface name, or `None` if it's empty.
+#[cfg(all(feature = "tcp", any(target_os = "linux", target_os = "android", target_os = "macos",
+                               target_os = "ios")))]
+fn interface_name(name: &[u8]) -> Option<String> {
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    match len {
+        0 => None,
+        _ => Some(String::from_utf8_lossy(&name[..len]).into_owned()),
+    }
+}
+
+// MPTCP sockets are made with a protocol of their own and then act as tcp sockets, falling back
+// to plain tcp when the peer doesn't speak it. Only Linux has them so far.
+#[cfg(all(feature = "tcp", target_os = "linux"))]
+const IPPROTO_MPTCP: libc::c_int = 262;
+#[cfg(all(feature = "tcp", target_os = "linux"))]
+const TCP_IS_MPTCP: libc::c_int = 43;
+
+/// Create an unbound MPTCP socket, an ipv6 one if `ipv6`.
+#[cfg(all(feature = "tcp", target_os = "linux"))]
+#[allow(unsafe_code)]
+pub fn new_mptcp_socket(ipv6: bool) -> io::Result<net2::TcpBuilder> {
+    use std::os::unix::io::FromRawFd;
+    let domain = if ipv6 { libc::AF_INET6 } else { libc::AF_INET };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, IPPROTO_MPTCP) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { net2::TcpBuilder::from_raw_fd(fd) })
+}
+
+#[cfg(all(feature = "tcp", not(target_os = "linux")))]
+pub fn new_mptcp_socket(_ipv6: bool) -> io::Result<net2::TcpBuilder> {
+    Err(io::Error::new(ErrorKind::Other, "MPTCP is not supported on this platform"))
+}
+
+/// Whether `stream` negotiated MPTCP with the peer, rather than falling back to plain tcp or never
+/// trying. Kernels too old to say are taken not to have.
+#[cfg(all(feature = "tcp", target_os = "linux"))]
+#[allow(unsafe_code)]
+pub fn is_mptcp(stream: &TcpStream) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = ::std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(),
+                         libc::IPPROTO_TCP,
+                         TCP_IS_MPTCP,
+                         &mut value as *mut libc::c_int as *mut libc::c_void,
+                         &mut len)
+    };
+    ret == 0 && value != 0
+}
+
+#[cfg(all(feature = "tcp", not(target_os = "linux")))]
+pub fn is_mptcp(_stream: &TcpStream) -> bool {
+    false
+}
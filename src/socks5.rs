@@ -0,0 +1,485 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Instant;
+
+use byteorder::{BigEndian, ByteOrder};
+use socket_addr::SocketAddr;
+
+use error_code::{ErrorCategory, ErrorCode};
+use event::{Event, Strategy, push_endpoint};
+use mapping_context;
+use mapping_context::MappingContext;
+use mapped_socket_addr::MappedSocketAddr;
+use mapped_udp_socket::MappedUdpSocket;
+use socket_utils;
+use socket_utils::RecvUntil;
+use transport::DatagramTransport;
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_USERNAME_PASSWORD: u8 = 2;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+const COMMAND_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+/// The largest header the proxy can put in front of a relayed datagram.
+const MAX_UDP_HEADER_SIZE: usize = 4 + 1 + 255 + 2;
+
+quick_error! {
+    /// Errors returned when associating with a SOCKS5 proxy.
+    #[derive(Debug)]
+    pub enum Socks5Error {
+        /// No SOCKS5 proxy has been configured on the `MappingContext`.
+        NoProxy {
+            description("No SOCKS5 proxy has been configured")
+        }
+        /// Error connecting to the proxy.
+        Connect { err: io::Error } {
+            description("Error connecting to the SOCKS5 proxy")
+            display("Error connecting to the SOCKS5 proxy: {}", err)
+            cause(err)
+        }
+        /// Error binding the local udp socket.
+        BindUdp { err: io::Error } {
+            description("Error binding a udp socket for the SOCKS5 association")
+            display("Error binding a udp socket for the SOCKS5 association: {}", err)
+            cause(err)
+        }
+        /// IO error talking to the proxy.
+        Io { err: io::Error } {
+            description("IO error talking to the SOCKS5 proxy")
+            display("IO error talking to the SOCKS5 proxy: {}", err)
+            cause(err)
+        }
+        /// The deadline passed before the association was established.
+        TimedOut {
+            description("Timed out associating with the SOCKS5 proxy")
+        }
+        /// The proxy doesn't accept any of the authentication methods we offered.
+        NoAcceptableMethod {
+            description("The SOCKS5 proxy doesn't accept any of our authentication methods")
+        }
+        /// The username or password is longer than 255 bytes.
+        CredentialsTooLong {
+            description("SOCKS5 usernames and passwords are limited to 255 bytes")
+        }
+        /// The proxy rejected our username and password.
+        AuthFailed {
+            description("The SOCKS5 proxy rejected our username and password")
+        }
+        /// The proxy refused the UDP ASSOCIATE request.
+        Rejected { reply: u8 } {
+            description("The SOCKS5 proxy refused the UDP ASSOCIATE request")
+            display("The SOCKS5 proxy refused the UDP ASSOCIATE request with reply code {}", reply)
+        }
+        /// The proxy sent something that isn't valid SOCKS5.
+        BadReply {
+            description("The SOCKS5 proxy sent an invalid reply")
+        }
+    }
+}
+
+impl From<Socks5Error> for io::Error {
+    fn from(e: Socks5Error) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            Socks5Error::NoProxy => io::ErrorKind::NotFound,
+            Socks5Error::Connect { err } => err.kind(),
+            Socks5Error::BindUdp { err } => err.kind(),
+            Socks5Error::Io { err } => err.kind(),
+            Socks5Error::TimedOut => io::ErrorKind::TimedOut,
+            Socks5Error::NoAcceptableMethod => io::ErrorKind::PermissionDenied,
+            Socks5Error::CredentialsTooLong => io::ErrorKind::InvalidInput,
+            Socks5Error::AuthFailed => io::ErrorKind::PermissionDenied,
+            Socks5Error::Rejected { .. } => io::ErrorKind::ConnectionRefused,
+            Socks5Error::BadReply => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for Socks5Error {
+    fn code(&self) -> u32 {
+        match *self {
+            Socks5Error::NoProxy => 2401,
+            Socks5Error::Connect { .. } => 2402,
+            Socks5Error::BindUdp { .. } => 2403,
+            Socks5Error::Io { .. } => 2404,
+            Socks5Error::TimedOut => 2405,
+            Socks5Error::NoAcceptableMethod => 2406,
+            Socks5Error::CredentialsTooLong => 2407,
+            Socks5Error::AuthFailed => 2408,
+            Socks5Error::Rejected { .. } => 2409,
+            Socks5Error::BadReply => 2410,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            Socks5Error::NoProxy => ErrorCategory::Configuration,
+            Socks5Error::Connect { .. } => ErrorCategory::Network,
+            Socks5Error::BindUdp { .. } => ErrorCategory::Network,
+            Socks5Error::Io { .. } => ErrorCategory::Network,
+            Socks5Error::TimedOut => ErrorCategory::Network,
+            Socks5Error::NoAcceptableMethod => ErrorCategory::Configuration,
+            Socks5Error::CredentialsTooLong => ErrorCategory::Configuration,
+            Socks5Error::AuthFailed => ErrorCategory::Configuration,
+            Socks5Error::Rejected { .. } => ErrorCategory::Network,
+            Socks5Error::BadReply => ErrorCategory::Protocol,
+        }
+    }
+}
+
+/// A SOCKS5 proxy to relay udp through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    /// The address of the proxy's tcp control port.
+    pub addr: SocketAddr,
+    /// The username and password to authenticate with, if the proxy requires them.
+    pub credentials: Option<(String, String)>,
+}
+
+/// A udp socket whose traffic is relayed through a SOCKS5 proxy's UDP ASSOCIATE. Datagrams are
+/// wrapped in and unwrapped from the SOCKS5 udp header transparently. The association lasts as
+/// long as this socket.
+#[derive(Debug)]
+pub struct Socks5UdpSocket {
+    // The proxy ends the association when this is closed.
+    _control: TcpStream,
+    socket: UdpSocket,
+    relay_addr: net::SocketAddr,
+}
+
+fn io_err(e: io::Error) -> Socks5Error {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Socks5Error::TimedOut,
+        _ => Socks5Error::Io { err: e },
+    }
+}
+
+fn write_addr(buf: &mut Vec<u8>, addr: &net::SocketAddr) {
+    match *addr {
+        net::SocketAddr::V4(ref addr_v4) => {
+            buf.push(ATYP_IPV4);
+            buf.extend(&addr_v4.ip().octets()[..]);
+        },
+        net::SocketAddr::V6(ref addr_v6) => {
+            buf.push(ATYP_IPV6);
+            for segment in &addr_v6.ip().segments() {
+                let mut bytes = [0u8; 2];
+                BigEndian::write_u16(&mut bytes, *segment);
+                buf.extend(&bytes[..]);
+            }
+        },
+    }
+    let mut port = [0u8; 2];
+    BigEndian::write_u16(&mut port, addr.port());
+    buf.extend(&port[..]);
+}
+
+// Read an address in SOCKS5 format from the start of `buf`. Returns the address and its encoded
+// length, or `None` if `buf` doesn't start with a complete address.
+fn read_addr(buf: &[u8]) -> Option<(net::SocketAddr, usize)> {
+    let (ip, len) = match buf.first() {
+        Some(&ATYP_IPV4) if buf.len() >= 7 => {
+            (IpAddr::V4(Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4])), 5)
+        },
+        Some(&ATYP_IPV6) if buf.len() >= 19 => {
+            let mut segments = [0u16; 8];
+            for (i, segment) in segments.iter_mut().enumerate() {
+                *segment = BigEndian::read_u16(&buf[1 + 2 * i..3 + 2 * i]);
+            }
+            (IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                      segments[4], segments[5], segments[6], segments[7])), 17)
+        },
+        Some(&ATYP_DOMAIN) if buf.len() >= 2 && buf.len() >= 4 + buf[1] as usize => {
+            let domain_len = buf[1] as usize;
+            let domain = match ::std::str::from_utf8(&buf[2..2 + domain_len]) {
+                Ok(domain) => domain,
+                Err(_) => return None,
+            };
+            let port = BigEndian::read_u16(&buf[2 + domain_len..4 + domain_len]);
+            let addr = match (domain, port).to_socket_addrs().ok().and_then(|mut a| a.next()) {
+                Some(addr) => addr,
+                None => return None,
+            };
+            return Some((addr, 4 + domain_len));
+        },
+        _ => return None,
+    };
+    let port = BigEndian::read_u16(&buf[len..len + 2]);
+    Some((net::SocketAddr::new(ip, port), len + 2))
+}
+
+impl Socks5UdpSocket {
+    /// Connect to `proxy` and ask it to relay udp for us.
+    pub fn associate(proxy: &Socks5Proxy, deadline: Instant) -> Result<Socks5UdpSocket, Socks5Error> {
+        let mut control = match TcpStream::connect(&*proxy.addr) {
+            Ok(control) => control,
+            Err(e) => return Err(Socks5Error::Connect { err: e }),
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Socks5Error::TimedOut);
+        }
+        let timeout = Some(deadline - now);
+        match control.set_read_timeout(timeout).and_then(|()| control.set_write_timeout(timeout)) {
+            Ok(()) => (),
+            Err(e) => return Err(Socks5Error::Io { err: e }),
+        };
+
+        // Negotiate an authentication method.
+        let greeting = match proxy.credentials {
+            Some(..) => vec![SOCKS_VERSION, 2, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD],
+            None => vec![SOCKS_VERSION, 1, METHOD_NO_AUTH],
+        };
+        try!(control.write_all(&greeting).map_err(io_err));
+        let mut choice = [0u8; 2];
+        try!(control.read_exact(&mut choice).map_err(io_err));
+        match (choice[0], choice[1], &proxy.credentials) {
+            (SOCKS_VERSION, METHOD_NO_AUTH, _) => (),
+            (SOCKS_VERSION, METHOD_USERNAME_PASSWORD, &Some((ref username, ref password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(Socks5Error::CredentialsTooLong);
+                }
+                let mut auth = vec![1, username.len() as u8];
+                auth.extend(username.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend(password.as_bytes());
+                try!(control.write_all(&auth).map_err(io_err));
+                let mut status = [0u8; 2];
+                try!(control.read_exact(&mut status).map_err(io_err));
+                if status[1] != 0 {
+                    return Err(Socks5Error::AuthFailed);
+                }
+            },
+            (SOCKS_VERSION, METHOD_NONE_ACCEPTABLE, _) => {
+                return Err(Socks5Error::NoAcceptableMethod);
+            },
+            _ => return Err(Socks5Error::BadReply),
+        }
+
+        // Bind the udp socket we'll send from on the interface we reach the proxy through and
+        // tell the proxy where our datagrams will come from.
+        let control_local_addr = try!(control.local_addr().map_err(io_err));
+        let socket = match UdpSocket::bind((control_local_addr.ip(), 0)) {
+            Ok(socket) => socket,
+            Err(e) => return Err(Socks5Error::BindUdp { err: e }),
+        };
+        let socket_addr = match socket.local_addr() {
+            Ok(addr) => addr,
+            Err(e) => return Err(Socks5Error::BindUdp { err: e }),
+        };
+        let mut request = vec![SOCKS_VERSION, COMMAND_UDP_ASSOCIATE, 0];
+        write_addr(&mut request, &socket_addr);
+        try!(control.write_all(&request).map_err(io_err));
+
+        let mut reply = [0u8; 3 + 1 + 255 + 2];
+        try!(control.read_exact(&mut reply[..5]).map_err(io_err));
+        if reply[0] != SOCKS_VERSION {
+            return Err(Socks5Error::BadReply);
+        }
+        if reply[1] != 0 {
+            return Err(Socks5Error::Rejected { reply: reply[1] });
+        }
+        let addr_len = match reply[3] {
+            ATYP_IPV4 => 1 + 4 + 2,
+            ATYP_IPV6 => 1 + 16 + 2,
+            ATYP_DOMAIN => 1 + 1 + reply[4] as usize + 2,
+            _ => return Err(Socks5Error::BadReply),
+        };
+        try!(control.read_exact(&mut reply[5..3 + addr_len]).map_err(io_err));
+        let mut relay_addr = match read_addr(&reply[3..3 + addr_len]) {
+            Some((addr, _)) => addr,
+            None => return Err(Socks5Error::BadReply),
+        };
+        // Proxies may answer with an unspecified address meaning "the address you reached me on".
+        let unspecified = match relay_addr.ip() {
+            IpAddr::V4(ip) => socket_utils::ipv4_is_unspecified(&ip),
+            IpAddr::V6(ip) => socket_utils::ipv6_is_unspecified(&ip),
+        };
+        if unspecified {
+            relay_addr = net::SocketAddr::new(proxy.addr.ip(), relay_addr.port());
+        }
+
+        Ok(Socks5UdpSocket {
+            _control: control,
+            socket: socket,
+            relay_addr: relay_addr,
+        })
+    }
+
+    /// The address of the proxy's udp relay for this association.
+    pub fn relay_addr(&self) -> net::SocketAddr {
+        self.relay_addr
+    }
+}
+
+impl DatagramTransport for Socks5UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: &net::SocketAddr) -> io::Result<usize> {
+        // RSV and FRAG are zero. We never fragment.
+        let mut packet = Vec::with_capacity(MAX_UDP_HEADER_SIZE + buf.len());
+        packet.extend(&[0, 0, 0]);
+        write_addr(&mut packet, addr);
+        packet.extend(buf);
+        let _ = try!(self.socket.send_to(&packet, self.relay_addr));
+        Ok(buf.len())
+    }
+
+    fn recv_until(&self, buf: &mut [u8], deadline: Instant)
+                  -> io::Result<Option<(usize, SocketAddr)>> {
+        let mut packet = vec![0u8; MAX_UDP_HEADER_SIZE + buf.len()];
+        loop {
+            let (len, from) = match try!(RecvUntil::recv_until(&self.socket, &mut packet,
+                                                                deadline)) {
+                Some(res) => res,
+                None => return Ok(None),
+            };
+            // Drop anything which didn't come through the relay, and fragments, which we don't
+            // support reassembling.
+            if *from != self.relay_addr || len < 4 || packet[2] != 0 {
+                continue;
+            }
+            let (addr, addr_len) = match read_addr(&packet[3..len]) {
+                Some(res) => res,
+                None => continue,
+            };
+            let payload = &packet[3 + addr_len..len];
+            let n = ::std::cmp::min(payload.len(), buf.len());
+            buf[..n].copy_from_slice(&payload[..n]);
+            return Ok(Some((n, SocketAddr(addr))));
+        }
+    }
+
+    /// Peers see datagrams as coming from the relay so that's our local address as far as
+    /// traversal is concerned.
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        Ok(self.relay_addr)
+    }
+}
+
+/// Associate with the SOCKS5 proxy configured on `mc` and return a socket whose only endpoint is
+/// the proxy's relay. The socket can be hole punched with `PunchedUdpSocket::punch_hole` like any
+/// other `DatagramTransport`.
+pub fn map_socks5_udp(mc: &MappingContext, deadline: Instant)
+                      -> Result<MappedUdpSocket<Socks5UdpSocket>, Socks5Error>
+{
+    let proxy = match mapping_context::socks5_proxy(mc) {
+        Some(proxy) => proxy,
+        None => return Err(Socks5Error::NoProxy),
+    };
+    let events = mapping_context::events(mc);
+    events.send(Event::StrategyChanged { strategy: Strategy::Socks5 });
+    let socket = match Socks5UdpSocket::associate(&proxy, deadline) {
+        Ok(socket) => socket,
+        Err(e) => {
+            mc.record_strategy_result(Strategy::Socks5, false);
+            return Err(e);
+        },
+    };
+    mc.record_strategy_result(Strategy::Socks5, true);
+    events.send(Event::GatheringStarted { local_addr: SocketAddr(socket.relay_addr()) });
+
+    let mut endpoints = Vec::new();
+    push_endpoint(&mut endpoints, events, Strategy::Socks5, MappedSocketAddr {
+        addr: SocketAddr(socket.relay_addr()),
+        // Proxies usually only relay datagrams from addresses we've sent to.
+        nat_restricted: true,
+        port_unknown: false,
+        unverified: false,
+    });
+    let endpoints = mapping_context::exposed_endpoints(mc, endpoints);
+    Ok(MappedUdpSocket {
+        socket: socket,
+        endpoints: endpoints,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net;
+    use std::net::{TcpListener, UdpSocket};
+    use std::time::{Instant, Duration};
+
+    use socket_addr::SocketAddr;
+
+    use transport::DatagramTransport;
+
+    // A proxy which accepts one UDP ASSOCIATE without authentication and echoes the first
+    // datagram it's asked to relay straight back, as if the destination had replied.
+    fn fake_proxy() -> net::SocketAddr {
+        let listener = unwrap_result!(TcpListener::bind("127.0.0.1:0"));
+        let addr = unwrap_result!(listener.local_addr());
+        let _ = thread!("fake_socks5_proxy", move || {
+            let (mut control, _) = unwrap_result!(listener.accept());
+            let mut greeting = [0u8; 3];
+            unwrap_result!(control.read_exact(&mut greeting));
+            assert_eq!(greeting, [5, 1, 0]);
+            unwrap_result!(control.write_all(&[5, 0]));
+
+            let mut request = [0u8; 10];
+            unwrap_result!(control.read_exact(&mut request));
+            assert_eq!(&request[..4], &[5, 3, 0, 1]);
+            let relay = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+            let relay_port = unwrap_result!(relay.local_addr()).port();
+            // Reply with an unspecified address, meaning the proxy's own.
+            unwrap_result!(control.write_all(&[5, 0, 0, 1, 0, 0, 0, 0,
+                                               (relay_port >> 8) as u8, relay_port as u8]));
+
+            let mut buf = [0u8; 1024];
+            let (len, client) = unwrap_result!(relay.recv_from(&mut buf));
+            let _ = unwrap_result!(relay.send_to(&buf[..len], client));
+            // Keep the association open until the client hangs up.
+            let _ = control.read(&mut buf);
+        });
+        addr
+    }
+
+    #[test]
+    fn udp_is_relayed_through_the_proxy() {
+        let proxy = Socks5Proxy {
+            addr: SocketAddr(fake_proxy()),
+            credentials: None,
+        };
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let socket = unwrap_result!(Socks5UdpSocket::associate(&proxy, deadline));
+        assert_eq!(socket.relay_addr().ip(), proxy.addr.ip());
+        assert_eq!(unwrap_result!(socket.local_addr()), socket.relay_addr());
+
+        let peer: net::SocketAddr = unwrap_result!("192.0.2.1:9".parse());
+        assert_eq!(unwrap_result!(socket.send_to(b"hello", &peer)), 5);
+        let mut buf = [0u8; 16];
+        let (len, from) = match unwrap_result!(socket.recv_until(&mut buf, deadline)) {
+            Some(res) => res,
+            None => panic!("Timed out waiting for the relayed datagram"),
+        };
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(*from, peer);
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::io;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::path::Path;
+
+use rustc_serialize::json;
+
+use event::Strategy;
+
+/// The number of outcomes remembered for each strategy.
+const MAX_OUTCOMES: usize = 16;
+
+/// If this many of the most recent attempts at a strategy have failed (and none have succeeded)
+/// then the strategy is considered to be consistently failing.
+const FAILURES_BEFORE_SKIPPING: usize = 3;
+
+/// A consistently failing strategy is given another chance after being skipped this many times,
+/// in case the network has changed.
+const SKIPS_BEFORE_RETRY: u32 = 8;
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+struct StrategyRecord {
+    strategy: Strategy,
+    // Oldest first.
+    outcomes: Vec<bool>,
+    skipped: u32,
+}
+
+/// A small history of which strategies have succeeded or failed on the current network.
+#[derive(Debug, Clone, Default, RustcEncodable, RustcDecodable)]
+pub struct StrategyHistory {
+    records: Vec<StrategyRecord>,
+}
+
+impl StrategyHistory {
+    /// Create an empty history.
+    pub fn new() -> StrategyHistory {
+        StrategyHistory::default()
+    }
+
+    /// Load a history previously written with `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<StrategyHistory> {
+        let mut file = try!(File::open(path));
+        let mut contents = String::new();
+        let _ = try!(file.read_to_string(&mut contents));
+        json::decode(&contents).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))
+        })
+    }
+
+    /// Write this history to a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let contents = try!(json::encode(self).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))
+        }));
+        let mut file = try!(File::create(path));
+        file.write_all(contents.as_bytes())
+    }
+
+    /// Record whether an attempt to use `strategy` succeeded.
+    pub fn record(&mut self, strategy: Strategy, succeeded: bool) {
+        if let Some(record) = self.records.iter_mut().find(|r| r.strategy == strategy) {
+            record.outcomes.push(succeeded);
+            record.skipped = 0;
+            if record.outcomes.len() > MAX_OUTCOMES {
+                let _ = record.outcomes.remove(0);
+            }
+            return;
+        }
+        self.records.push(StrategyRecord {
+            strategy: strategy,
+            outcomes: vec![succeeded],
+            skipped: 0,
+        });
+    }
+
+    /// The fraction of the remembered attempts at `strategy` which succeeded, or `None` if we have
+    /// never tried it.
+    pub fn success_rate(&self, strategy: Strategy) -> Option<f64> {
+        self.records.iter().find(|r| r.strategy == strategy).map(|r| {
+            let successes = r.outcomes.iter().filter(|s| **s).count();
+            successes as f64 / r.outcomes.len() as f64
+        })
+    }
+
+    /// Returns `true` if the last few attempts at `strategy` have all failed. Strategies which are
+    /// consistently failing are skipped when mapping sockets.
+    pub fn is_consistently_failing(&self, strategy: Strategy) -> bool {
+        match self.records.iter().find(|r| r.strategy == strategy) {
+            Some(r) => {
+                r.outcomes.len() >= FAILURES_BEFORE_SKIPPING &&
+                r.outcomes.iter().rev().take(FAILURES_BEFORE_SKIPPING).all(|s| !*s)
+            },
+            None => false,
+        }
+    }
+
+    /// Decide whether to skip `strategy` for the current operation. Consistently failing
+    /// strategies are skipped, except that every so often they are retried.
+    pub fn should_skip(&mut self, strategy: Strategy) -> bool {
+        if !self.is_consistently_failing(strategy) {
+            return false;
+        }
+        match self.records.iter_mut().find(|r| r.strategy == strategy) {
+            Some(record) => {
+                record.skipped += 1;
+                record.skipped % SKIPS_BEFORE_RETRY != 0
+            },
+            None => false,
+        }
+    }
+
+    /// The strategy with the best success rate that isn't consistently failing, or `None` if no
+    /// strategy has succeeded yet.
+    pub fn recommended_strategy(&self) -> Option<Strategy> {
+        let mut best = None;
+        for record in &self.records {
+            if self.is_consistently_failing(record.strategy) {
+                continue;
+            }
+            let rate = match self.success_rate(record.strategy) {
+                Some(rate) if rate > 0.0 => rate,
+                _ => continue,
+            };
+            best = match best {
+                Some((_, best_rate)) if best_rate >= rate => best,
+                _ => Some((record.strategy, rate)),
+            };
+        }
+        best.map(|(strategy, _)| strategy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use event::Strategy;
+
+    #[test]
+    fn consistently_failing_strategies_are_not_recommended() {
+        let mut history = StrategyHistory::new();
+        assert_eq!(history.recommended_strategy(), None);
+
+        for _ in 0..3 {
+            history.record(Strategy::Igd, false);
+        }
+        history.record(Strategy::SimpleServer, false);
+        history.record(Strategy::SimpleServer, true);
+
+        assert!(history.is_consistently_failing(Strategy::Igd));
+        assert!(!history.is_consistently_failing(Strategy::SimpleServer));
+        assert_eq!(history.recommended_strategy(), Some(Strategy::SimpleServer));
+
+        assert!((0..7).all(|_| history.should_skip(Strategy::Igd)));
+        assert!(!history.should_skip(Strategy::Igd));
+
+        history.record(Strategy::Igd, true);
+        assert!(!history.is_consistently_failing(Strategy::Igd));
+        assert_eq!(history.success_rate(Strategy::Igd), Some(0.25));
+    }
+}
@@ -0,0 +1,958 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::io;
+use std::net;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use rand;
+
+use error_code::{ErrorCategory, ErrorCode};
+use sha1;
+use transport::DatagramTransport;
+use utils;
+
+/// The magic cookie which distinguishes RFC 5389 STUN messages.
+pub const MAGIC_COOKIE: u32 = 0x2112a442;
+/// The Binding method.
+pub const METHOD_BINDING: u16 = 0x001;
+/// The TURN Allocate method.
+pub const METHOD_ALLOCATE: u16 = 0x003;
+/// The TURN Refresh method.
+pub const METHOD_REFRESH: u16 = 0x004;
+/// The TURN Send method.
+pub const METHOD_SEND: u16 = 0x006;
+/// The TURN Data method.
+pub const METHOD_DATA: u16 = 0x007;
+/// The TURN CreatePermission method.
+pub const METHOD_CREATE_PERMISSION: u16 = 0x008;
+/// The TURN ChannelBind method.
+pub const METHOD_CHANNEL_BIND: u16 = 0x009;
+/// The TURN-TCP Connect method.
+pub const METHOD_CONNECT: u16 = 0x00a;
+/// The TURN-TCP ConnectionBind method.
+pub const METHOD_CONNECTION_BIND: u16 = 0x00b;
+/// The TURN-TCP ConnectionAttempt method.
+pub const METHOD_CONNECTION_ATTEMPT: u16 = 0x00c;
+
+const HEADER_SIZE: usize = 20;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_USERNAME: u16 = 0x0006;
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_CHANNEL_NUMBER: u16 = 0x000c;
+const ATTR_LIFETIME: u16 = 0x000d;
+const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+const ATTR_DATA: u16 = 0x0013;
+const ATTR_REALM: u16 = 0x0014;
+const ATTR_NONCE: u16 = 0x0015;
+const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+const ATTR_CONNECTION_ID: u16 = 0x002a;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_PRIORITY: u16 = 0x0024;
+const ATTR_USE_CANDIDATE: u16 = 0x0025;
+const ATTR_SOFTWARE: u16 = 0x8022;
+const ATTR_ALTERNATE_SERVER: u16 = 0x8023;
+const ATTR_FINGERPRINT: u16 = 0x8028;
+const ATTR_ICE_CONTROLLED: u16 = 0x8029;
+const ATTR_ICE_CONTROLLING: u16 = 0x802a;
+const FINGERPRINT_XOR: u32 = 0x5354554e;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+/// The initial retransmission timeout for requests, from RFC 5389.
+const INITIAL_RTO_MS: u64 = 500;
+/// The number of times a request is sent before giving up.
+const MAX_REQUEST_ATTEMPTS: u32 = 7;
+/// How many ALTERNATE-SERVER redirects we follow before giving up.
+const MAX_REDIRECTS: u32 = 3;
+/// The error code a server uses to redirect us to an ALTERNATE-SERVER.
+const ERROR_TRY_ALTERNATE: u16 = 300;
+const SOFTWARE: &'static str = "nat_traversal";
+
+quick_error! {
+    /// Errors returned when decoding a STUN message.
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum StunError {
+        /// The datagram is not a STUN message.
+        NotStun {
+            description("The datagram is not a STUN message")
+        }
+        /// The message is truncated or an attribute is malformed.
+        Malformed {
+            description("Malformed STUN message")
+        }
+        /// The FINGERPRINT attribute doesn't match the message.
+        BadFingerprint {
+            description("The STUN message's FINGERPRINT doesn't match its contents")
+        }
+    }
+}
+
+impl From<StunError> for io::Error {
+    fn from(e: StunError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))
+    }
+}
+
+impl ErrorCode for StunError {
+    fn code(&self) -> u32 {
+        match *self {
+            StunError::NotStun => 2501,
+            StunError::Malformed => 2502,
+            StunError::BadFingerprint => 2503,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        ErrorCategory::Protocol
+    }
+}
+
+quick_error! {
+    /// Errors returned by `query_stun_server`.
+    #[derive(Debug)]
+    pub enum StunQueryError {
+        /// IO error sending or receiving.
+        Io { err: io::Error } {
+            description("IO error talking to the STUN server")
+            display("IO error talking to the STUN server: {}", err)
+            cause(err)
+        }
+        /// The server didn't respond before the deadline or the retransmissions ran out.
+        TimedOut {
+            description("Timed out waiting for the STUN server to respond")
+        }
+        /// The server sent an error response.
+        ErrorResponse { code: u16, reason: String } {
+            description("The STUN server sent an error response")
+            display("The STUN server sent an error response: {} {}", code, reason)
+        }
+        /// The server kept redirecting us with ALTERNATE-SERVER.
+        TooManyRedirects {
+            description("The STUN server redirected us too many times")
+        }
+        /// The response contained attributes which we're required to understand but don't.
+        UnknownAttributes { attr_types: Vec<u16> } {
+            description("The STUN response contained unknown comprehension-required attributes")
+            display("The STUN response contained unknown comprehension-required attributes: \
+                     {:?}", attr_types)
+        }
+        /// The success response didn't say which address the request came from.
+        NoMappedAddress {
+            description("The STUN response didn't contain a mapped address")
+        }
+    }
+}
+
+impl From<StunQueryError> for io::Error {
+    fn from(e: StunQueryError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            StunQueryError::Io { err } => err.kind(),
+            StunQueryError::TimedOut => io::ErrorKind::TimedOut,
+            StunQueryError::ErrorResponse { .. } |
+            StunQueryError::TooManyRedirects => io::ErrorKind::ConnectionRefused,
+            StunQueryError::UnknownAttributes { .. } |
+            StunQueryError::NoMappedAddress => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for StunQueryError {
+    fn code(&self) -> u32 {
+        match *self {
+            StunQueryError::Io { .. } => 2701,
+            StunQueryError::TimedOut => 2702,
+            StunQueryError::ErrorResponse { .. } => 2703,
+            StunQueryError::TooManyRedirects => 2704,
+            StunQueryError::UnknownAttributes { .. } => 2705,
+            StunQueryError::NoMappedAddress => 2706,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            StunQueryError::Io { .. } |
+            StunQueryError::TimedOut => ErrorCategory::Network,
+            StunQueryError::ErrorResponse { .. } |
+            StunQueryError::TooManyRedirects |
+            StunQueryError::UnknownAttributes { .. } |
+            StunQueryError::NoMappedAddress => ErrorCategory::Protocol,
+        }
+    }
+}
+
+/// The class of a STUN message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StunClass {
+    /// A request, which expects a response.
+    Request,
+    /// An indication, which doesn't.
+    Indication,
+    /// A success response.
+    SuccessResponse,
+    /// An error response.
+    ErrorResponse,
+}
+
+/// A STUN attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StunAttribute {
+    /// MAPPED-ADDRESS, from RFC 3489 servers.
+    MappedAddress(net::SocketAddr),
+    /// XOR-MAPPED-ADDRESS: the address the server saw the request come from.
+    XorMappedAddress(net::SocketAddr),
+    /// USERNAME.
+    Username(String),
+    /// ERROR-CODE.
+    ErrorCode {
+        /// The error code, eg. 487 for a role conflict.
+        code: u16,
+        /// A human readable reason phrase.
+        reason: String,
+    },
+    /// SOFTWARE: a description of the agent sending the message.
+    Software(String),
+    /// ALTERNATE-SERVER: the server to retry a request with after a 300 error response.
+    AlternateServer(net::SocketAddr),
+    /// ICE PRIORITY.
+    Priority(u32),
+    /// ICE USE-CANDIDATE.
+    UseCandidate,
+    /// ICE-CONTROLLED with its tie-breaker.
+    IceControlled(u64),
+    /// ICE-CONTROLLING with its tie-breaker.
+    IceControlling(u64),
+    /// TURN CHANNEL-NUMBER.
+    ChannelNumber(u16),
+    /// TURN LIFETIME, in seconds.
+    Lifetime(u32),
+    /// TURN XOR-PEER-ADDRESS.
+    XorPeerAddress(net::SocketAddr),
+    /// TURN DATA.
+    Data(Vec<u8>),
+    /// REALM, for long-term credentials.
+    Realm(String),
+    /// NONCE, for long-term credentials.
+    Nonce(String),
+    /// TURN XOR-RELAYED-ADDRESS.
+    XorRelayedAddress(net::SocketAddr),
+    /// TURN REQUESTED-TRANSPORT, an IP protocol number. 17 for udp, 6 for tcp.
+    RequestedTransport(u8),
+    /// TURN-TCP CONNECTION-ID.
+    ConnectionId(u32),
+    /// Any attribute we don't interpret.
+    Unknown {
+        /// The attribute type.
+        attr_type: u16,
+        /// The raw value.
+        value: Vec<u8>,
+    },
+}
+
+/// A STUN message. MESSAGE-INTEGRITY and FINGERPRINT are handled when encoding and decoding
+/// rather than appearing in `attributes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StunMessage {
+    /// The class of the message.
+    pub class: StunClass,
+    /// The method, eg. `METHOD_BINDING`.
+    pub method: u16,
+    /// The transaction id which matches responses to requests.
+    pub transaction_id: [u8; 12],
+    /// The message's attributes, in order.
+    pub attributes: Vec<StunAttribute>,
+}
+
+/// A decoded STUN message along with what's needed to check its MESSAGE-INTEGRITY.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedStunMessage {
+    /// The message.
+    pub message: StunMessage,
+    // The message up to MESSAGE-INTEGRITY and the HMAC it carried, if it had one.
+    integrity: Option<(Vec<u8>, [u8; 20])>,
+}
+
+impl DecodedStunMessage {
+    /// Whether the message carried a MESSAGE-INTEGRITY attribute.
+    pub fn has_integrity(&self) -> bool {
+        self.integrity.is_some()
+    }
+
+    /// Check the MESSAGE-INTEGRITY attribute against `key`, eg. an ICE password. Returns `false`
+    /// if the message doesn't have one.
+    pub fn verify_integrity(&self, key: &[u8]) -> bool {
+        match self.integrity {
+            Some((ref covered, ref hmac)) => {
+                let expected = sha1::hmac_sha1(key, covered);
+                utils::constant_time_eq(&expected[..], &hmac[..])
+            },
+            None => false,
+        }
+    }
+}
+
+/// CRC-32 as used by STUN's FINGERPRINT (the ISO-HDLC / zlib polynomial).
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Frame `data` as TURN ChannelData on `channel`.
+pub fn encode_channel_data(channel: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + data.len() + 3);
+    push_u16(&mut buf, channel);
+    push_u16(&mut buf, data.len() as u16);
+    buf.extend(data);
+    // Padding is only required over stream transports but is harmless over udp.
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+    buf
+}
+
+/// Split TURN ChannelData into its channel number and payload. Returns `None` if `buf` isn't
+/// ChannelData.
+pub fn decode_channel_data(buf: &[u8]) -> Option<(u16, &[u8])> {
+    if buf.len() < 4 || buf[0] & 0xc0 != 0x40 {
+        return None;
+    }
+    let len = BigEndian::read_u16(&buf[2..4]) as usize;
+    if buf.len() < 4 + len {
+        return None;
+    }
+    Some((BigEndian::read_u16(&buf[..2]), &buf[4..4 + len]))
+}
+
+/// Whether `buf` looks like a STUN message. Useful for demultiplexing STUN from other traffic on
+/// the same socket.
+pub fn is_stun(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_SIZE && buf[0] & 0xc0 == 0 &&
+    BigEndian::read_u32(&buf[4..8]) == MAGIC_COOKIE
+}
+
+fn message_type(class: StunClass, method: u16) -> u16 {
+    let class_bits = match class {
+        StunClass::Request => 0x0000,
+        StunClass::Indication => 0x0010,
+        StunClass::SuccessResponse => 0x0100,
+        StunClass::ErrorResponse => 0x0110,
+    };
+    (method & 0x000f) | ((method & 0x0070) << 1) | ((method & 0x0f80) << 2) | class_bits
+}
+
+fn split_message_type(message_type: u16) -> (StunClass, u16) {
+    let class = match message_type & 0x0110 {
+        0x0000 => StunClass::Request,
+        0x0010 => StunClass::Indication,
+        0x0100 => StunClass::SuccessResponse,
+        _ => StunClass::ErrorResponse,
+    };
+    let method = (message_type & 0x000f) | ((message_type >> 1) & 0x0070) |
+                 ((message_type >> 2) & 0x0f80);
+    (class, method)
+}
+
+fn push_u16(buf: &mut Vec<u8>, n: u16) {
+    let mut bytes = [0u8; 2];
+    BigEndian::write_u16(&mut bytes, n);
+    buf.extend(&bytes[..]);
+}
+
+fn push_u32(buf: &mut Vec<u8>, n: u32) {
+    let mut bytes = [0u8; 4];
+    BigEndian::write_u32(&mut bytes, n);
+    buf.extend(&bytes[..]);
+}
+
+fn push_attribute(buf: &mut Vec<u8>, attr_type: u16, value: &[u8]) {
+    push_u16(buf, attr_type);
+    push_u16(buf, value.len() as u16);
+    buf.extend(value);
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+// XOR an address with the magic cookie and transaction id, as for XOR-MAPPED-ADDRESS. The
+// operation is its own inverse.
+fn xor_addr(addr: &net::SocketAddr, transaction_id: &[u8; 12]) -> net::SocketAddr {
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let mut mask = [0u8; 16];
+    BigEndian::write_u32(&mut mask[..4], MAGIC_COOKIE);
+    mask[4..].copy_from_slice(transaction_id);
+    match *addr {
+        net::SocketAddr::V4(ref addr_v4) => {
+            let o = addr_v4.ip().octets();
+            let ip = Ipv4Addr::new(o[0] ^ mask[0], o[1] ^ mask[1], o[2] ^ mask[2], o[3] ^ mask[3]);
+            net::SocketAddr::new(IpAddr::V4(ip), port)
+        },
+        net::SocketAddr::V6(ref addr_v6) => {
+            let mut segments = addr_v6.ip().segments();
+            for (i, segment) in segments.iter_mut().enumerate() {
+                *segment ^= BigEndian::read_u16(&mask[2 * i..2 * i + 2]);
+            }
+            let ip = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                   segments[4], segments[5], segments[6], segments[7]);
+            net::SocketAddr::new(IpAddr::V6(ip), port)
+        },
+    }
+}
+
+fn encode_addr(addr: &net::SocketAddr) -> Vec<u8> {
+    let mut value = Vec::with_capacity(20);
+    match *addr {
+        net::SocketAddr::V4(ref addr_v4) => {
+            value.push(0);
+            value.push(FAMILY_IPV4);
+            push_u16(&mut value, addr.port());
+            value.extend(&addr_v4.ip().octets()[..]);
+        },
+        net::SocketAddr::V6(ref addr_v6) => {
+            value.push(0);
+            value.push(FAMILY_IPV6);
+            push_u16(&mut value, addr.port());
+            for segment in &addr_v6.ip().segments() {
+                push_u16(&mut value, *segment);
+            }
+        },
+    }
+    value
+}
+
+fn decode_addr(value: &[u8]) -> Result<net::SocketAddr, StunError> {
+    if value.len() < 4 {
+        return Err(StunError::Malformed);
+    }
+    let port = BigEndian::read_u16(&value[2..4]);
+    match (value[1], value.len()) {
+        (FAMILY_IPV4, 8) => {
+            let ip = Ipv4Addr::new(value[4], value[5], value[6], value[7]);
+            Ok(net::SocketAddr::new(IpAddr::V4(ip), port))
+        },
+        (FAMILY_IPV6, 20) => {
+            let mut segments = [0u16; 8];
+            for (i, segment) in segments.iter_mut().enumerate() {
+                *segment = BigEndian::read_u16(&value[4 + 2 * i..6 + 2 * i]);
+            }
+            let ip = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                   segments[4], segments[5], segments[6], segments[7]);
+            Ok(net::SocketAddr::new(IpAddr::V6(ip), port))
+        },
+        _ => Err(StunError::Malformed),
+    }
+}
+
+fn string_value(value: &[u8]) -> Result<String, StunError> {
+    match String::from_utf8(value.to_vec()) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(StunError::Malformed),
+    }
+}
+
+impl StunMessage {
+    /// Create a message with no attributes.
+    pub fn new(class: StunClass, method: u16, transaction_id: [u8; 12]) -> StunMessage {
+        StunMessage {
+            class: class,
+            method: method,
+            transaction_id: transaction_id,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Encode the message. If `integrity_key` is given a MESSAGE-INTEGRITY attribute is added
+    /// and if `fingerprint` is set a FINGERPRINT attribute is added after it.
+    pub fn encode(&self, integrity_key: Option<&[u8]>, fingerprint: bool) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(128);
+        push_u16(&mut buf, message_type(self.class, self.method));
+        push_u16(&mut buf, 0);
+        push_u32(&mut buf, MAGIC_COOKIE);
+        buf.extend(&self.transaction_id[..]);
+
+        for attribute in &self.attributes {
+            match *attribute {
+                StunAttribute::MappedAddress(ref addr) => {
+                    push_attribute(&mut buf, ATTR_MAPPED_ADDRESS, &encode_addr(addr));
+                },
+                StunAttribute::XorMappedAddress(ref addr) => {
+                    let xored = xor_addr(addr, &self.transaction_id);
+                    push_attribute(&mut buf, ATTR_XOR_MAPPED_ADDRESS, &encode_addr(&xored));
+                },
+                StunAttribute::Username(ref username) => {
+                    push_attribute(&mut buf, ATTR_USERNAME, username.as_bytes());
+                },
+                StunAttribute::ErrorCode { code, ref reason } => {
+                    let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+                    value.extend(reason.as_bytes());
+                    push_attribute(&mut buf, ATTR_ERROR_CODE, &value);
+                },
+                StunAttribute::Software(ref software) => {
+                    push_attribute(&mut buf, ATTR_SOFTWARE, software.as_bytes());
+                },
+                StunAttribute::AlternateServer(ref addr) => {
+                    push_attribute(&mut buf, ATTR_ALTERNATE_SERVER, &encode_addr(addr));
+                },
+                StunAttribute::Priority(priority) => {
+                    let mut value = [0u8; 4];
+                    BigEndian::write_u32(&mut value, priority);
+                    push_attribute(&mut buf, ATTR_PRIORITY, &value);
+                },
+                StunAttribute::UseCandidate => push_attribute(&mut buf, ATTR_USE_CANDIDATE, &[]),
+                StunAttribute::IceControlled(tie_breaker) => {
+                    let mut value = [0u8; 8];
+                    BigEndian::write_u64(&mut value, tie_breaker);
+                    push_attribute(&mut buf, ATTR_ICE_CONTROLLED, &value);
+                },
+                StunAttribute::IceControlling(tie_breaker) => {
+                    let mut value = [0u8; 8];
+                    BigEndian::write_u64(&mut value, tie_breaker);
+                    push_attribute(&mut buf, ATTR_ICE_CONTROLLING, &value);
+                },
+                StunAttribute::ChannelNumber(channel) => {
+                    let mut value = [0u8; 4];
+                    BigEndian::write_u16(&mut value[..2], channel);
+                    push_attribute(&mut buf, ATTR_CHANNEL_NUMBER, &value);
+                },
+                StunAttribute::Lifetime(lifetime) => {
+                    let mut value = [0u8; 4];
+                    BigEndian::write_u32(&mut value, lifetime);
+                    push_attribute(&mut buf, ATTR_LIFETIME, &value);
+                },
+                StunAttribute::XorPeerAddress(ref addr) => {
+                    let xored = xor_addr(addr, &self.transaction_id);
+                    push_attribute(&mut buf, ATTR_XOR_PEER_ADDRESS, &encode_addr(&xored));
+                },
+                StunAttribute::Data(ref data) => push_attribute(&mut buf, ATTR_DATA, data),
+                StunAttribute::Realm(ref realm) => {
+                    push_attribute(&mut buf, ATTR_REALM, realm.as_bytes());
+                },
+                StunAttribute::Nonce(ref nonce) => {
+                    push_attribute(&mut buf, ATTR_NONCE, nonce.as_bytes());
+                },
+                StunAttribute::XorRelayedAddress(ref addr) => {
+                    let xored = xor_addr(addr, &self.transaction_id);
+                    push_attribute(&mut buf, ATTR_XOR_RELAYED_ADDRESS, &encode_addr(&xored));
+                },
+                StunAttribute::RequestedTransport(protocol) => {
+                    push_attribute(&mut buf, ATTR_REQUESTED_TRANSPORT, &[protocol, 0, 0, 0]);
+                },
+                StunAttribute::ConnectionId(connection_id) => {
+                    let mut value = [0u8; 4];
+                    BigEndian::write_u32(&mut value, connection_id);
+                    push_attribute(&mut buf, ATTR_CONNECTION_ID, &value);
+                },
+                StunAttribute::Unknown { attr_type, ref value } => {
+                    push_attribute(&mut buf, attr_type, value);
+                },
+            }
+        }
+
+        // The length field must cover each of these attributes when it is computed.
+        if let Some(key) = integrity_key {
+            let len = (buf.len() - HEADER_SIZE + 24) as u16;
+            BigEndian::write_u16(&mut buf[2..4], len);
+            let hmac = sha1::hmac_sha1(key, &buf);
+            push_attribute(&mut buf, ATTR_MESSAGE_INTEGRITY, &hmac);
+        }
+        if fingerprint {
+            let len = (buf.len() - HEADER_SIZE + 8) as u16;
+            BigEndian::write_u16(&mut buf[2..4], len);
+            let crc = crc32(&buf) ^ FINGERPRINT_XOR;
+            let mut value = [0u8; 4];
+            BigEndian::write_u32(&mut value, crc);
+            push_attribute(&mut buf, ATTR_FINGERPRINT, &value);
+        }
+        let len = (buf.len() - HEADER_SIZE) as u16;
+        BigEndian::write_u16(&mut buf[2..4], len);
+        buf
+    }
+
+    /// Decode a message, checking its FINGERPRINT if it has one.
+    pub fn decode(buf: &[u8]) -> Result<DecodedStunMessage, StunError> {
+        if !is_stun(buf) {
+            return Err(StunError::NotStun);
+        }
+        let len = BigEndian::read_u16(&buf[2..4]) as usize;
+        if len % 4 != 0 || buf.len() < HEADER_SIZE + len {
+            return Err(StunError::Malformed);
+        }
+        let buf = &buf[..HEADER_SIZE + len];
+        let (class, method) = split_message_type(BigEndian::read_u16(&buf[0..2]));
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&buf[8..20]);
+
+        let mut message = StunMessage::new(class, method, transaction_id);
+        let mut integrity = None;
+        let mut pos = HEADER_SIZE;
+        while pos < buf.len() {
+            if pos + 4 > buf.len() {
+                return Err(StunError::Malformed);
+            }
+            let attr_type = BigEndian::read_u16(&buf[pos..pos + 2]);
+            let attr_len = BigEndian::read_u16(&buf[pos + 2..pos + 4]) as usize;
+            let value_start = pos + 4;
+            let value_end = value_start + attr_len;
+            if value_end > buf.len() {
+                return Err(StunError::Malformed);
+            }
+            let value = &buf[value_start..value_end];
+            let next = value_start + (attr_len + 3) / 4 * 4;
+
+            match attr_type {
+                ATTR_FINGERPRINT => {
+                    if attr_len != 4 {
+                        return Err(StunError::Malformed);
+                    }
+                    // The CRC covers the message up to here with the length as sent.
+                    let expected = crc32(&buf[..pos]) ^ FINGERPRINT_XOR;
+                    if BigEndian::read_u32(value) != expected {
+                        return Err(StunError::BadFingerprint);
+                    }
+                    // FINGERPRINT is always last.
+                    break;
+                },
+                _ if integrity.is_some() => {
+                    // Only FINGERPRINT may follow MESSAGE-INTEGRITY. Ignore anything else.
+                },
+                ATTR_MESSAGE_INTEGRITY => {
+                    if attr_len != 20 {
+                        return Err(StunError::Malformed);
+                    }
+                    // The HMAC covers the message up to here with the length adjusted to end
+                    // just after MESSAGE-INTEGRITY.
+                    let mut covered = buf[..pos].to_vec();
+                    BigEndian::write_u16(&mut covered[2..4], (value_end - HEADER_SIZE) as u16);
+                    let mut hmac = [0u8; 20];
+                    hmac.copy_from_slice(value);
+                    integrity = Some((covered, hmac));
+                },
+                _ => {
+                    let attribute = try!(decode_attribute(attr_type, value, &transaction_id));
+                    message.attributes.push(attribute);
+                },
+            }
+            pos = next;
+        }
+        Ok(DecodedStunMessage {
+            message: message,
+            integrity: integrity,
+        })
+    }
+
+    /// The first attribute for which `f` returns `Some`.
+    pub fn find<T, F>(&self, f: F) -> Option<T>
+        where F: FnMut(&StunAttribute) -> Option<T>
+    {
+        self.attributes.iter().filter_map(f).next()
+    }
+}
+
+fn decode_attribute(attr_type: u16, value: &[u8], transaction_id: &[u8; 12])
+                    -> Result<StunAttribute, StunError>
+{
+    Ok(match attr_type {
+        ATTR_MAPPED_ADDRESS => StunAttribute::MappedAddress(try!(decode_addr(value))),
+        ATTR_XOR_MAPPED_ADDRESS => {
+            StunAttribute::XorMappedAddress(xor_addr(&try!(decode_addr(value)), transaction_id))
+        },
+        ATTR_USERNAME => StunAttribute::Username(try!(string_value(value))),
+        ATTR_ERROR_CODE => {
+            if value.len() < 4 {
+                return Err(StunError::Malformed);
+            }
+            StunAttribute::ErrorCode {
+                code: (value[2] & 0x07) as u16 * 100 + value[3] as u16,
+                reason: try!(string_value(&value[4..])),
+            }
+        },
+        ATTR_SOFTWARE => StunAttribute::Software(try!(string_value(value))),
+        ATTR_ALTERNATE_SERVER => StunAttribute::AlternateServer(try!(decode_addr(value))),
+        ATTR_PRIORITY if value.len() == 4 => StunAttribute::Priority(BigEndian::read_u32(value)),
+        ATTR_USE_CANDIDATE => StunAttribute::UseCandidate,
+        ATTR_ICE_CONTROLLED if value.len() == 8 => {
+            StunAttribute::IceControlled(BigEndian::read_u64(value))
+        },
+        ATTR_ICE_CONTROLLING if value.len() == 8 => {
+            StunAttribute::IceControlling(BigEndian::read_u64(value))
+        },
+        ATTR_CHANNEL_NUMBER if value.len() == 4 => {
+            StunAttribute::ChannelNumber(BigEndian::read_u16(&value[..2]))
+        },
+        ATTR_LIFETIME if value.len() == 4 => StunAttribute::Lifetime(BigEndian::read_u32(value)),
+        ATTR_XOR_PEER_ADDRESS => {
+            StunAttribute::XorPeerAddress(xor_addr(&try!(decode_addr(value)), transaction_id))
+        },
+        ATTR_DATA => StunAttribute::Data(value.to_vec()),
+        ATTR_REALM => StunAttribute::Realm(try!(string_value(value))),
+        ATTR_NONCE => StunAttribute::Nonce(try!(string_value(value))),
+        ATTR_XOR_RELAYED_ADDRESS => {
+            StunAttribute::XorRelayedAddress(xor_addr(&try!(decode_addr(value)), transaction_id))
+        },
+        ATTR_REQUESTED_TRANSPORT if value.len() == 4 => StunAttribute::RequestedTransport(value[0]),
+        ATTR_CONNECTION_ID if value.len() == 4 => {
+            StunAttribute::ConnectionId(BigEndian::read_u32(value))
+        },
+        ATTR_PRIORITY | ATTR_ICE_CONTROLLED | ATTR_ICE_CONTROLLING | ATTR_CHANNEL_NUMBER |
+        ATTR_LIFETIME | ATTR_REQUESTED_TRANSPORT | ATTR_CONNECTION_ID => {
+            return Err(StunError::Malformed);
+        },
+        _ => {
+            StunAttribute::Unknown {
+                attr_type: attr_type,
+                value: value.to_vec(),
+            }
+        },
+    })
+}
+
+/// Ask the STUN server at `server` which address `socket`'s traffic appears to come from.
+///
+/// The Binding request carries SOFTWARE and FINGERPRINT attributes since some public servers
+/// drop requests without them. Requests are retransmitted as RFC 5389 describes, ALTERNATE-SERVER
+/// redirects are followed and XOR-MAPPED-ADDRESS is preferred over MAPPED-ADDRESS. Datagrams
+/// which aren't the response we're waiting for, including responses with a bad FINGERPRINT, are
+/// ignored.
+pub fn query_stun_server<S>(socket: &S, server: &net::SocketAddr, deadline: Instant)
+                            -> Result<net::SocketAddr, StunQueryError>
+    where S: DatagramTransport
+{
+    let mut server = *server;
+    let mut redirects = 0;
+    'redirect: loop {
+        let mut request = StunMessage::new(StunClass::Request, METHOD_BINDING, rand::random());
+        request.attributes.push(StunAttribute::Software(SOFTWARE.to_owned()));
+        let encoded = request.encode(None, true);
+
+        let mut rto = Duration::from_millis(INITIAL_RTO_MS);
+        let mut recv_buf = [0u8; 1500];
+        for _ in 0..MAX_REQUEST_ATTEMPTS {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(StunQueryError::TimedOut);
+            }
+            if let Err(e) = socket.send_to(&encoded, &server) {
+                return Err(StunQueryError::Io { err: e });
+            }
+            let recv_deadline = ::std::cmp::min(now + rto, deadline);
+            rto = rto * 2;
+            loop {
+                let (len, from) = match socket.recv_until(&mut recv_buf, recv_deadline) {
+                    Ok(Some(res)) => res,
+                    Ok(None) => break,
+                    Err(e) => return Err(StunQueryError::Io { err: e }),
+                };
+                if *from != server {
+                    continue;
+                }
+                let response = match StunMessage::decode(&recv_buf[..len]) {
+                    Ok(decoded) => decoded.message,
+                    Err(_) => continue,
+                };
+                if response.transaction_id != request.transaction_id ||
+                   response.method != METHOD_BINDING {
+                    continue;
+                }
+                match response.class {
+                    StunClass::SuccessResponse => return mapped_address(&response),
+                    StunClass::ErrorResponse => {
+                        let (code, reason) = response.find(|attr| match *attr {
+                            StunAttribute::ErrorCode { code, ref reason } => {
+                                Some((code, reason.clone()))
+                            },
+                            _ => None,
+                        }).unwrap_or((0, String::new()));
+                        let alternate = response.find(|attr| match *attr {
+                            StunAttribute::AlternateServer(addr) => Some(addr),
+                            _ => None,
+                        });
+                        match alternate {
+                            Some(alternate) if code == ERROR_TRY_ALTERNATE => {
+                                redirects += 1;
+                                if redirects > MAX_REDIRECTS {
+                                    return Err(StunQueryError::TooManyRedirects);
+                                }
+                                server = alternate;
+                                continue 'redirect;
+                            },
+                            _ => {
+                                return Err(StunQueryError::ErrorResponse {
+                                    code: code,
+                                    reason: reason,
+                                });
+                            },
+                        }
+                    },
+                    StunClass::Request | StunClass::Indication => (),
+                }
+            }
+        }
+        return Err(StunQueryError::TimedOut);
+    }
+}
+
+fn mapped_address(response: &StunMessage) -> Result<net::SocketAddr, StunQueryError> {
+    // Attributes below 0x8000 are comprehension-required so a response containing ones we don't
+    // understand has to be treated as a failure.
+    let unknown: Vec<u16> = response.attributes
+                                    .iter()
+                                    .filter_map(|attr| match *attr {
+                                        StunAttribute::Unknown { attr_type, .. }
+                                            if attr_type < 0x8000 => Some(attr_type),
+                                        _ => None,
+                                    })
+                                    .collect();
+    if !unknown.is_empty() {
+        return Err(StunQueryError::UnknownAttributes { attr_types: unknown });
+    }
+    let xor_mapped = response.find(|attr| match *attr {
+        StunAttribute::XorMappedAddress(addr) => Some(addr),
+        _ => None,
+    });
+    let mapped = response.find(|attr| match *attr {
+        StunAttribute::MappedAddress(addr) => Some(addr),
+        _ => None,
+    });
+    match xor_mapped.or(mapped) {
+        Some(addr) => Ok(addr),
+        None => Err(StunQueryError::NoMappedAddress),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, is_stun, query_stun_server, StunAttribute, StunClass, StunError,
+                StunMessage, StunQueryError, METHOD_BINDING};
+
+    use std::net;
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn messages_round_trip_with_integrity_and_fingerprint() {
+        let mut request = StunMessage::new(StunClass::Request, METHOD_BINDING, [7; 12]);
+        request.attributes.push(StunAttribute::Username("remote:local".to_owned()));
+        request.attributes.push(StunAttribute::Priority(0x6e0001ff));
+        request.attributes.push(StunAttribute::IceControlling(0x932ff9b151263b36));
+        request.attributes.push(StunAttribute::UseCandidate);
+        let encoded = request.encode(Some(&b"password"[..]), true);
+        assert!(is_stun(&encoded));
+
+        let decoded = unwrap_result!(StunMessage::decode(&encoded));
+        assert_eq!(decoded.message, request);
+        assert!(decoded.verify_integrity(b"password"));
+        assert!(!decoded.verify_integrity(b"wrong password"));
+
+        let mut tampered = encoded.clone();
+        tampered[30] ^= 1;
+        assert_eq!(StunMessage::decode(&tampered), Err(StunError::BadFingerprint));
+
+        let mut response = StunMessage::new(StunClass::SuccessResponse, METHOD_BINDING, [9; 12]);
+        response.attributes.push(StunAttribute::XorMappedAddress(
+            unwrap_result!("[2001:db8::1]:32853".parse())));
+        response.attributes.push(StunAttribute::XorMappedAddress(
+            unwrap_result!("192.0.2.1:32853".parse())));
+        let decoded = unwrap_result!(StunMessage::decode(&response.encode(None, false)));
+        assert_eq!(decoded.message, response);
+        assert!(!decoded.has_integrity());
+    }
+
+    // A server which redirects the first request to `alternate` then, from `alternate`, answers
+    // with both a MAPPED-ADDRESS and an XOR-MAPPED-ADDRESS as strict RFC 5389 servers may.
+    fn fake_servers() -> net::SocketAddr {
+        let primary = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let alternate = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let primary_addr = unwrap_result!(primary.local_addr());
+        let alternate_addr = unwrap_result!(alternate.local_addr());
+        let _ = thread!("fake_stun_servers", move || {
+            let mut buf = [0u8; 1500];
+            let (len, from) = unwrap_result!(primary.recv_from(&mut buf));
+            let request = unwrap_result!(StunMessage::decode(&buf[..len])).message;
+            assert!(request.attributes.iter().any(|attr| match *attr {
+                StunAttribute::Software(..) => true,
+                _ => false,
+            }));
+            let mut redirect = StunMessage::new(StunClass::ErrorResponse,
+                                                METHOD_BINDING,
+                                                request.transaction_id);
+            redirect.attributes.push(StunAttribute::ErrorCode {
+                code: 300,
+                reason: "Try Alternate".to_owned(),
+            });
+            redirect.attributes.push(StunAttribute::AlternateServer(alternate_addr));
+            let _ = unwrap_result!(primary.send_to(&redirect.encode(None, true), from));
+
+            let (len, from) = unwrap_result!(alternate.recv_from(&mut buf));
+            let request = unwrap_result!(StunMessage::decode(&buf[..len])).message;
+            let mut response = StunMessage::new(StunClass::SuccessResponse,
+                                                METHOD_BINDING,
+                                                request.transaction_id);
+            response.attributes.push(StunAttribute::MappedAddress(
+                unwrap_result!("192.0.2.1:1".parse())));
+            response.attributes.push(StunAttribute::XorMappedAddress(from));
+            let _ = unwrap_result!(alternate.send_to(&response.encode(None, true), from));
+
+            // A server we don't understand the response of.
+            let (len, from) = unwrap_result!(primary.recv_from(&mut buf));
+            let request = unwrap_result!(StunMessage::decode(&buf[..len])).message;
+            let mut response = StunMessage::new(StunClass::SuccessResponse,
+                                                METHOD_BINDING,
+                                                request.transaction_id);
+            response.attributes.push(StunAttribute::Unknown {
+                attr_type: 0x7fff,
+                value: Vec::new(),
+            });
+            let _ = unwrap_result!(primary.send_to(&response.encode(None, true), from));
+        });
+        primary_addr
+    }
+
+    #[test]
+    fn query_follows_alternate_server() {
+        let server = fake_servers();
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let mapped = unwrap_result!(query_stun_server(&socket, &server, deadline));
+        assert_eq!(mapped, unwrap_result!(socket.local_addr()));
+
+        match query_stun_server(&socket, &server, deadline) {
+            Err(StunQueryError::UnknownAttributes { attr_types }) => {
+                assert_eq!(attr_types, vec![0x7fff])
+            },
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,497 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Ip subnets and lists of them, eg. for recognising private or tunneled addresses.
+
+// This module deliberately depends on nothing but the address types from `std::net` and the
+// crate's error traits. It doesn't open sockets, spawn threads or touch igd, so it can be lifted
+// out and used where the rest of the crate can't be compiled.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::slice;
+use std::str::FromStr;
+
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+
+use error_code::{ErrorCategory, ErrorCode};
+
+quick_error! {
+    /// Errors returned when creating or parsing a subnet.
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum SubnetError {
+        /// The prefix length is longer than the address.
+        PrefixTooLong { prefix_len: u8, max: u8 } {
+            description("The prefix length is longer than the address.")
+            display("The prefix length {} is longer than the maximum of {}.", prefix_len, max)
+        }
+        /// The string is not in `address/prefix_len` form.
+        Parse { s: String } {
+            description("The string is not in address/prefix_len form.")
+            display("\"{}\" is not in address/prefix_len form.", s)
+        }
+    }
+}
+
+impl From<SubnetError> for io::Error {
+    fn from(e: SubnetError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e))
+    }
+}
+
+impl ErrorCode for SubnetError {
+    fn code(&self) -> u32 {
+        match *self {
+            SubnetError::PrefixTooLong { .. } => 1801,
+            SubnetError::Parse { .. } => 1802,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        ErrorCategory::Configuration
+    }
+}
+
+// Keep the first `prefix_len` bits of `bytes` and zero the rest.
+fn mask_bytes(bytes: &mut [u8], prefix_len: u8) {
+    let prefix_len = prefix_len as usize;
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let bit = i * 8;
+        if bit + 8 <= prefix_len {
+            continue;
+        }
+        if bit >= prefix_len {
+            *byte = 0;
+        } else {
+            *byte &= 0xffu8 << (8 - (prefix_len - bit));
+        }
+    }
+}
+
+fn ipv6_to_bytes(addr: &Ipv6Addr) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    for (i, segment) in addr.segments().iter().enumerate() {
+        bytes[2 * i] = (*segment >> 8) as u8;
+        bytes[2 * i + 1] = *segment as u8;
+    }
+    bytes
+}
+
+fn ipv6_from_bytes(bytes: &[u8; 16]) -> Ipv6Addr {
+    let mut segments = [0u16; 8];
+    for (i, segment) in segments.iter_mut().enumerate() {
+        *segment = ((bytes[2 * i] as u16) << 8) | bytes[2 * i + 1] as u16;
+    }
+    Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                  segments[4], segments[5], segments[6], segments[7])
+}
+
+fn split_cidr(s: &str) -> Result<(&str, u8), SubnetError> {
+    let mut parts = s.splitn(2, '/');
+    let addr = parts.next().unwrap_or("");
+    match parts.next().and_then(|p| p.parse().ok()) {
+        Some(prefix_len) => Ok((addr, prefix_len)),
+        None => Err(SubnetError::Parse { s: s.to_owned() }),
+    }
+}
+
+/// An IPv4 subnet, eg. `192.168.0.0/16`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv4Subnet {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Subnet {
+    /// Create the subnet containing `addr` with a prefix of `prefix_len` bits. Host bits of
+    /// `addr` are cleared.
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Result<Ipv4Subnet, SubnetError> {
+        if prefix_len > 32 {
+            return Err(SubnetError::PrefixTooLong { prefix_len: prefix_len, max: 32 });
+        }
+        let mut octets = addr.octets();
+        mask_bytes(&mut octets, prefix_len);
+        Ok(Ipv4Subnet {
+            addr: Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]),
+            prefix_len: prefix_len,
+        })
+    }
+
+    /// The network address of the subnet.
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    /// The number of bits in the prefix.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `addr` is in this subnet.
+    pub fn contains(&self, addr: &Ipv4Addr) -> bool {
+        let mut octets = addr.octets();
+        mask_bytes(&mut octets, self.prefix_len);
+        octets == self.addr.octets()
+    }
+
+    /// Whether every address of `other` is in this subnet.
+    pub fn contains_subnet(&self, other: &Ipv4Subnet) -> bool {
+        other.prefix_len >= self.prefix_len && self.contains(&other.addr)
+    }
+}
+
+impl fmt::Display for Ipv4Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for Ipv4Subnet {
+    type Err = SubnetError;
+
+    fn from_str(s: &str) -> Result<Ipv4Subnet, SubnetError> {
+        let (addr, prefix_len) = try!(split_cidr(s));
+        match addr.parse() {
+            Ok(addr) => Ipv4Subnet::new(addr, prefix_len),
+            Err(_) => Err(SubnetError::Parse { s: s.to_owned() }),
+        }
+    }
+}
+
+/// An IPv6 subnet, eg. `fc00::/7`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv6Subnet {
+    addr: Ipv6Addr,
+    prefix_len: u8,
+}
+
+impl Ipv6Subnet {
+    /// Create the subnet containing `addr` with a prefix of `prefix_len` bits. Host bits of
+    /// `addr` are cleared.
+    pub fn new(addr: Ipv6Addr, prefix_len: u8) -> Result<Ipv6Subnet, SubnetError> {
+        if prefix_len > 128 {
+            return Err(SubnetError::PrefixTooLong { prefix_len: prefix_len, max: 128 });
+        }
+        let mut bytes = ipv6_to_bytes(&addr);
+        mask_bytes(&mut bytes, prefix_len);
+        Ok(Ipv6Subnet {
+            addr: ipv6_from_bytes(&bytes),
+            prefix_len: prefix_len,
+        })
+    }
+
+    /// The network address of the subnet.
+    pub fn addr(&self) -> Ipv6Addr {
+        self.addr
+    }
+
+    /// The number of bits in the prefix.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `addr` is in this subnet.
+    pub fn contains(&self, addr: &Ipv6Addr) -> bool {
+        let mut bytes = ipv6_to_bytes(addr);
+        mask_bytes(&mut bytes, self.prefix_len);
+        bytes == ipv6_to_bytes(&self.addr)
+    }
+
+    /// Whether every address of `other` is in this subnet.
+    pub fn contains_subnet(&self, other: &Ipv6Subnet) -> bool {
+        other.prefix_len >= self.prefix_len && self.contains(&other.addr)
+    }
+}
+
+impl fmt::Display for Ipv6Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for Ipv6Subnet {
+    type Err = SubnetError;
+
+    fn from_str(s: &str) -> Result<Ipv6Subnet, SubnetError> {
+        let (addr, prefix_len) = try!(split_cidr(s));
+        match addr.parse() {
+            Ok(addr) => Ipv6Subnet::new(addr, prefix_len),
+            Err(_) => Err(SubnetError::Parse { s: s.to_owned() }),
+        }
+    }
+}
+
+/// An IPv4 or IPv6 subnet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpSubnet {
+    /// An IPv4 subnet.
+    V4(Ipv4Subnet),
+    /// An IPv6 subnet.
+    V6(Ipv6Subnet),
+}
+
+impl IpSubnet {
+    /// Whether `addr` is in this subnet. IPv4 addresses are never in IPv6 subnets and vice versa.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (*self, *addr) {
+            (IpSubnet::V4(ref subnet), IpAddr::V4(ref addr)) => subnet.contains(addr),
+            (IpSubnet::V6(ref subnet), IpAddr::V6(ref addr)) => subnet.contains(addr),
+            _ => false,
+        }
+    }
+
+    /// Whether every address of `other` is in this subnet.
+    pub fn contains_subnet(&self, other: &IpSubnet) -> bool {
+        match (*self, *other) {
+            (IpSubnet::V4(ref subnet), IpSubnet::V4(ref other)) => subnet.contains_subnet(other),
+            (IpSubnet::V6(ref subnet), IpSubnet::V6(ref other)) => subnet.contains_subnet(other),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IpSubnet::V4(ref subnet) => fmt::Display::fmt(subnet, f),
+            IpSubnet::V6(ref subnet) => fmt::Display::fmt(subnet, f),
+        }
+    }
+}
+
+impl FromStr for IpSubnet {
+    type Err = SubnetError;
+
+    fn from_str(s: &str) -> Result<IpSubnet, SubnetError> {
+        if s.contains(':') {
+            Ok(IpSubnet::V6(try!(s.parse())))
+        } else {
+            Ok(IpSubnet::V4(try!(s.parse())))
+        }
+    }
+}
+
+// Subnets are encoded as their usual notation so that they read naturally in config files.
+impl Encodable for IpSubnet {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_string())
+    }
+}
+
+impl Decodable for IpSubnet {
+    fn decode<D: Decoder>(d: &mut D) -> Result<IpSubnet, D::Error> {
+        let subnet = try!(d.read_str());
+        subnet.parse().map_err(|e: SubnetError| d.error(&e.to_string()))
+    }
+}
+
+/// A collection of subnets with no redundant entries. Inserting a subnet which is already covered
+/// does nothing and inserting a subnet which covers existing entries replaces them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubnetList {
+    subnets: Vec<IpSubnet>,
+}
+
+impl SubnetList {
+    /// Create an empty list.
+    pub fn new() -> SubnetList {
+        SubnetList { subnets: Vec::new() }
+    }
+
+    /// The private and link-local address ranges: `10.0.0.0/8`, `172.16.0.0/12`,
+    /// `192.168.0.0/16`, `169.254.0.0/16`, `fc00::/7` and `fe80::/10`.
+    pub fn private() -> SubnetList {
+        let mut list = SubnetList::new();
+        for s in &["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "169.254.0.0/16",
+                   "fc00::/7", "fe80::/10"] {
+            list.insert(unwrap_result!(s.parse()));
+        }
+        list
+    }
+
+    /// The shared address space used by carrier-grade NATs, `100.64.0.0/10`.
+    pub fn shared() -> SubnetList {
+        let mut list = SubnetList::new();
+        list.insert(unwrap_result!("100.64.0.0/10".parse()));
+        list
+    }
+
+    /// The deprecated ipv6 site-local range, `fec0::/10`, which some sites still use internally.
+    pub fn site_local() -> SubnetList {
+        let mut list = SubnetList::new();
+        list.insert(unwrap_result!("fec0::/10".parse()));
+        list
+    }
+
+    /// Add every subnet of `other` to the list.
+    pub fn extend(&mut self, other: &SubnetList) {
+        for subnet in other.iter() {
+            self.insert(*subnet);
+        }
+    }
+
+    /// Add a subnet to the list.
+    pub fn insert(&mut self, subnet: IpSubnet) {
+        if self.subnets.iter().any(|s| s.contains_subnet(&subnet)) {
+            return;
+        }
+        self.subnets.retain(|s| !subnet.contains_subnet(s));
+        self.subnets.push(subnet);
+    }
+
+    /// Remove a subnet from the list. Only the exact subnet is removed, not any it covers or is
+    /// covered by. Returns whether it was in the list.
+    pub fn remove(&mut self, subnet: &IpSubnet) -> bool {
+        let len = self.subnets.len();
+        self.subnets.retain(|s| s != subnet);
+        self.subnets.len() != len
+    }
+
+    /// Whether `addr` is in any subnet in the list.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        self.subnets.iter().any(|s| s.contains(addr))
+    }
+
+    /// The number of subnets in the list.
+    pub fn len(&self) -> usize {
+        self.subnets.len()
+    }
+
+    /// Whether the list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.subnets.is_empty()
+    }
+
+    /// Iterate over the subnets in the list.
+    pub fn iter(&self) -> slice::Iter<IpSubnet> {
+        self.subnets.iter()
+    }
+}
+
+impl Encodable for SubnetList {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.subnets.encode(s)
+    }
+}
+
+// Decoding goes through `insert` so that a hand-written list with redundant entries is tidied up.
+impl Decodable for SubnetList {
+    fn decode<D: Decoder>(d: &mut D) -> Result<SubnetList, D::Error> {
+        let subnets: Vec<IpSubnet> = try!(Decodable::decode(d));
+        let mut list = SubnetList::new();
+        for subnet in subnets {
+            list.insert(subnet);
+        }
+        Ok(list)
+    }
+}
+
+/// A kind of ipv6 address which is tunneled over ipv4. Such addresses often blackhole traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ipv6Tunnel {
+    /// A Teredo address, in `2001::/32`.
+    Teredo,
+    /// A 6to4 address, in `2002::/16`.
+    SixToFour,
+}
+
+impl Ipv6Tunnel {
+    /// The kind of tunnel `addr` belongs to, if any.
+    pub fn classify(addr: &Ipv6Addr) -> Option<Ipv6Tunnel> {
+        let teredo = Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0);
+        let teredo = unwrap_result!(Ipv6Subnet::new(teredo, 32));
+        let six_to_four = Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0);
+        let six_to_four = unwrap_result!(Ipv6Subnet::new(six_to_four, 16));
+        if teredo.contains(addr) {
+            Some(Ipv6Tunnel::Teredo)
+        } else if six_to_four.contains(addr) {
+            Some(Ipv6Tunnel::SixToFour)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::IpAddr;
+
+    use rustc_serialize::json;
+
+    fn ip(s: &str) -> IpAddr {
+        unwrap_result!(s.parse())
+    }
+
+    #[test]
+    fn subnets_parse_mask_and_contain() {
+        let subnet: Ipv4Subnet = unwrap_result!("192.168.17.5/20".parse());
+        assert_eq!(format!("{}", subnet), "192.168.16.0/20");
+        assert!(IpSubnet::V4(subnet).contains(&ip("192.168.31.255")));
+        assert!(!IpSubnet::V4(subnet).contains(&ip("192.168.32.0")));
+
+        let subnet: IpSubnet = unwrap_result!("2001:db8:abcd::1/33".parse());
+        assert_eq!(format!("{}", subnet), "2001:db8:8000::/33");
+        assert!(subnet.contains(&ip("2001:db8:ffff::")));
+        assert!(!subnet.contains(&ip("2001:db8:7fff::")));
+        assert!(!subnet.contains(&ip("10.0.0.1")));
+
+        assert_eq!("10.0.0.0/33".parse::<IpSubnet>(),
+                   Err(SubnetError::PrefixTooLong { prefix_len: 33, max: 32 }));
+        assert!("10.0.0.0".parse::<IpSubnet>().is_err());
+    }
+
+    #[test]
+    fn subnet_list_drops_redundant_entries() {
+        let mut list = SubnetList::new();
+        list.insert(unwrap_result!("10.1.0.0/16".parse()));
+        list.insert(unwrap_result!("10.2.0.0/16".parse()));
+        assert_eq!(list.len(), 2);
+        list.insert(unwrap_result!("10.0.0.0/8".parse()));
+        assert_eq!(list.len(), 1);
+        list.insert(unwrap_result!("10.3.0.0/16".parse()));
+        assert_eq!(list.len(), 1);
+
+        let private = SubnetList::private();
+        assert!(private.contains(&ip("172.20.1.1")));
+        assert!(private.contains(&ip("fd00::1")));
+        assert!(!private.contains(&ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn subnet_lists_encode_as_subnet_notation() {
+        let mut list = SubnetList::new();
+        list.insert(unwrap_result!("10.0.0.0/8".parse()));
+        list.insert(unwrap_result!("fd00::/8".parse()));
+        let encoded = unwrap_result!(json::encode(&list));
+        assert_eq!(encoded, r#"["10.0.0.0/8","fd00::/8"]"#);
+
+        let decoded: SubnetList = unwrap_result!(json::decode(r#"["10.1.0.0/16","10.0.0.0/8"]"#));
+        assert_eq!(decoded.len(), 1);
+        assert!(json::decode::<SubnetList>(r#"["10.0.0.0/33"]"#).is_err());
+    }
+
+    #[test]
+    fn tunneled_ipv6_is_classified() {
+        let classify = |s: &str| Ipv6Tunnel::classify(&unwrap_result!(s.parse()));
+        assert_eq!(classify("2001:0:4136:e378:8000:63bf:3fff:fdd2"), Some(Ipv6Tunnel::Teredo));
+        assert_eq!(classify("2002:c000:221::1"), Some(Ipv6Tunnel::SixToFour));
+        assert_eq!(classify("2001:db8::1"), None);
+    }
+}
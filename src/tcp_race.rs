@@ -0,0 +1,543 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Racing a direct tcp punch against a relayed tcp splice. Traffic starts on whichever comes up
+//! first and, if the relay won, moves to the direct stream once that's up too, so a slow punch
+//! doesn't saddle the whole session with the relay's latency.
+//!
+//! What's written to the relay is framed, each frame a two byte big-endian length followed by
+//! that many bytes of data. A peer moves over by writing an empty frame, the cutover marker, to
+//! the relay, shutting down its writing half of the relayed stream and writing to the direct one
+//! from then on. A peer which reads the marker follows as soon as it has drained the relay to its
+//! end, whether or not its own direct stream came up within its cutover window, so the two never
+//! end up on different paths. Reading drains the relayed stream to its end before reading the
+//! direct one, so the peer's bytes arrive in the order they were sent whichever of the two got
+//! there first.
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+use socket_addr::SocketAddr;
+use w_result::{WErr, WOk, WResult};
+
+use error_code::{ErrorCategory, ErrorCode};
+use mapped_tcp_socket::{PunchedTcpStream, TcpPunchHoleError, TcpPunchHoleWarning,
+                        TcpPunchTechnique};
+use tcp_splice::{tcp_splice, SpliceToken, TcpSpliceError};
+
+quick_error! {
+    /// Error returned by `TcpRaceBuilder::race`.
+    #[derive(Debug)]
+    pub enum TcpRaceError {
+        /// Neither the direct punch nor the relay came up.
+        AllFailed { direct: TcpPunchHoleError, relay: TcpSpliceError } {
+            description("Neither the direct punch nor the relay came up.")
+            display("Neither the direct punch nor the relay came up. Punching failed with: {}. \
+                     Relaying failed with: {}.", direct, relay)
+            cause(direct)
+        }
+        /// IO error while racing, eg. a thread racing one of the paths panicked.
+        Io { err: io::Error } {
+            description("IO error while racing.")
+            display("IO error while racing: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl From<TcpRaceError> for io::Error {
+    fn from(e: TcpRaceError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            TcpRaceError::AllFailed { .. } => io::ErrorKind::NotConnected,
+            TcpRaceError::Io { err } => return err,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for TcpRaceError {
+    fn code(&self) -> u32 {
+        match *self {
+            TcpRaceError::AllFailed { .. } => 3801,
+            TcpRaceError::Io { .. } => 3802,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            TcpRaceError::AllFailed { .. } => ErrorCategory::Network,
+            TcpRaceError::Io { .. } => ErrorCategory::Network,
+        }
+    }
+}
+
+/// Races a direct tcp punch against a tcp splice through a relay. Both peers must race, with the
+/// same relay, token and cutover window.
+#[derive(Debug)]
+pub struct TcpRaceBuilder {
+    relay: SocketAddr,
+    token: SpliceToken,
+    deadline: Instant,
+    cutover_window: Option<Duration>,
+}
+
+impl TcpRaceBuilder {
+    /// Splice through `relay` with `token`, giving up on the relay at `deadline`. Move over from
+    /// the relay whenever the direct punch comes up.
+    pub fn new(relay: &SocketAddr, token: &SpliceToken, deadline: Instant) -> TcpRaceBuilder {
+        TcpRaceBuilder {
+            relay: *relay,
+            token: *token,
+            deadline: deadline,
+            cutover_window: None,
+        }
+    }
+
+    /// Only move over from the relay if the direct punch comes up within `window` of the relay
+    /// winning. A direct stream that comes up later is only kept in case the peer moves over, and
+    /// otherwise the session stays relayed. A zero window never moves over first.
+    pub fn cutover_window(mut self, window: Duration) -> TcpRaceBuilder {
+        self.cutover_window = Some(window);
+        self
+    }
+
+    /// Start the race and return once either path is up. `direct` performs the punch, for
+    /// instance with `TcpPunchBuilder::punch_hole_detailed`, and runs on its own thread.
+    pub fn race<F>(self, direct: F) -> Result<RacedTcpStream, TcpRaceError>
+        where F: FnOnce() -> WResult<PunchedTcpStream, TcpPunchHoleWarning, TcpPunchHoleError>,
+              F: Send + 'static
+    {
+        let (tx, rx) = mpsc::channel();
+        let direct_tx = tx.clone();
+        let _ = thread!("TcpRace direct", move || {
+            let _ = direct_tx.send(Arrival::Direct(direct()));
+        });
+        let relay = self.relay;
+        let token = self.token;
+        let deadline = self.deadline;
+        let _ = thread!("TcpRace relay", move || {
+            let _ = tx.send(Arrival::Relay(tcp_splice(&relay, &token, deadline)));
+        });
+
+        let mut stream = RacedTcpStream {
+            arrivals: rx,
+            direct: None,
+            direct_pending: true,
+            relay: None,
+            relay_pending: true,
+            relay_write_closed: false,
+            relay_frame_left: 0,
+            peer_moved: false,
+            standby: None,
+            cutover_window: self.cutover_window,
+            cutover_deadline: None,
+        };
+        let mut direct_err = None;
+        let mut relay_err = None;
+        loop {
+            let arrival = match stream.arrivals.recv() {
+                Ok(arrival) => arrival,
+                // Both threads have gone without a word, so one of them panicked.
+                Err(_) => {
+                    let err = io::Error::new(io::ErrorKind::Other, "A racing thread panicked");
+                    return Err(TcpRaceError::Io { err: err });
+                },
+            };
+            match arrival {
+                Arrival::Direct(WErr(e)) => {
+                    stream.direct_pending = false;
+                    direct_err = Some(e);
+                },
+                Arrival::Relay(Err(e)) => {
+                    stream.relay_pending = false;
+                    relay_err = Some(e);
+                },
+                arrival => {
+                    stream.arrive(arrival);
+                    return Ok(stream);
+                },
+            }
+            match (direct_err, relay_err) {
+                (Some(direct), Some(relay)) => {
+                    return Err(TcpRaceError::AllFailed {
+                        direct: direct,
+                        relay: relay,
+                    });
+                },
+                (direct, relay) => {
+                    direct_err = direct;
+                    relay_err = relay;
+                },
+            }
+        }
+    }
+}
+
+// The most data written to the relay in one frame.
+const MAX_FRAME_LEN: usize = 0xffff;
+// An empty frame tells the peer we've moved over to the direct stream.
+const CUTOVER_MARKER: [u8; 2] = [0, 0];
+
+enum Arrival {
+    Direct(WResult<PunchedTcpStream, TcpPunchHoleWarning, TcpPunchHoleError>),
+    Relay(Result<PunchedTcpStream, TcpSpliceError>),
+}
+
+/// A stream to the peer over whichever of the direct and relayed paths is current. It moves
+/// over to the direct path by itself as it's read from and written to.
+pub struct RacedTcpStream {
+    arrivals: Receiver<Arrival>,
+    direct: Option<PunchedTcpStream>,
+    direct_pending: bool,
+    relay: Option<PunchedTcpStream>,
+    relay_pending: bool,
+    relay_write_closed: bool,
+    // How much of the frame being read from the relay is still to come.
+    relay_frame_left: usize,
+    // Whether we've read the peer's cutover marker.
+    peer_moved: bool,
+    // A direct stream which came up after the cutover window, kept in case the peer moves over.
+    standby: Option<PunchedTcpStream>,
+    cutover_window: Option<Duration>,
+    cutover_deadline: Option<Instant>,
+}
+
+impl fmt::Debug for RacedTcpStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RacedTcpStream")
+         .field("direct", &self.direct)
+         .field("direct_pending", &self.direct_pending)
+         .field("relay", &self.relay)
+         .field("relay_pending", &self.relay_pending)
+         .field("relay_write_closed", &self.relay_write_closed)
+         .field("peer_moved", &self.peer_moved)
+         .field("standby", &self.standby)
+         .finish()
+    }
+}
+
+impl RacedTcpStream {
+    /// How the stream we're writing to was made. `TcpPunchTechnique::Relayed` until we've moved
+    /// over to the direct stream.
+    pub fn technique(&self) -> TcpPunchTechnique {
+        match self.direct {
+            Some(ref direct) => direct.technique,
+            None => TcpPunchTechnique::Relayed,
+        }
+    }
+
+    /// The direct stream, if we've moved over to it.
+    pub fn direct(&self) -> Option<&PunchedTcpStream> {
+        self.direct.as_ref()
+    }
+
+    /// The relayed stream, if the relay came up and the peer hasn't finished with it.
+    pub fn relayed(&self) -> Option<&PunchedTcpStream> {
+        self.relay.as_ref()
+    }
+
+    fn arrive(&mut self, arrival: Arrival) {
+        match arrival {
+            Arrival::Direct(WOk(punched, _)) => {
+                self.direct_pending = false;
+                let too_late = match self.cutover_deadline {
+                    Some(cutover_deadline) => Instant::now() >= cutover_deadline,
+                    None => false,
+                };
+                if self.relay.is_some() && too_late {
+                    self.standby = Some(punched);
+                    return;
+                }
+                self.direct = Some(punched);
+                self.close_relay_write();
+            },
+            Arrival::Direct(WErr(_)) => self.direct_pending = false,
+            Arrival::Relay(Ok(punched)) => {
+                self.relay_pending = false;
+                self.relay = Some(punched);
+                if self.direct.is_some() {
+                    self.close_relay_write();
+                } else if let Some(window) = self.cutover_window {
+                    self.cutover_deadline = Some(Instant::now() + window);
+                }
+            },
+            Arrival::Relay(Err(_)) => self.relay_pending = false,
+        }
+    }
+
+    fn close_relay_write(&mut self) {
+        if let Some(ref mut relay) = self.relay {
+            if !self.relay_write_closed {
+                // If the marker doesn't make it the relay is broken and the peer reads an error
+                // rather than the end of the stream.
+                let _ = relay.stream.write_all(&CUTOVER_MARKER);
+                let _ = relay.stream.shutdown(Shutdown::Write);
+                self.relay_write_closed = true;
+            }
+        }
+    }
+
+    // Move over to the direct stream after the peer has, even if ours came up too late for us
+    // to move first.
+    fn follow(&mut self) {
+        if self.standby.is_none() {
+            self.wait_for_direct();
+        }
+        if let Some(standby) = self.standby.take() {
+            self.direct = Some(standby);
+            self.close_relay_write();
+        }
+    }
+
+    // Read the peer's data from the relay, returning `0` at the end of the relayed stream.
+    fn read_relay(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let relay = match self.relay {
+            Some(ref mut relay) => relay,
+            None => return Ok(0),
+        };
+        while self.relay_frame_left == 0 {
+            let mut header = [0u8; 2];
+            let mut got = 0;
+            while got < header.len() {
+                match try!(relay.stream.read(&mut header[got..])) {
+                    0 if got == 0 => return Ok(0),
+                    0 => {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                  "Relayed stream ended mid-frame"))
+                    },
+                    n => got += n,
+                }
+            }
+            if header == CUTOVER_MARKER {
+                self.peer_moved = true;
+            }
+            self.relay_frame_left = ((header[0] as usize) << 8) | header[1] as usize;
+        }
+        let len = cmp::min(buf.len(), self.relay_frame_left);
+        let n = try!(relay.stream.read(&mut buf[..len]));
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "Relayed stream ended mid-frame"));
+        }
+        self.relay_frame_left -= n;
+        Ok(n)
+    }
+
+    fn poll(&mut self) {
+        while let Ok(arrival) = self.arrivals.try_recv() {
+            self.arrive(arrival);
+        }
+    }
+
+    fn wait_for_relay(&mut self) {
+        while self.relay_pending {
+            match self.arrivals.recv() {
+                Ok(arrival) => self.arrive(arrival),
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn wait_for_direct(&mut self) {
+        while self.direct_pending {
+            match self.arrivals.recv() {
+                Ok(arrival) => self.arrive(arrival),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl Read for RacedTcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.poll();
+        // The peer may have started on the relay even though we didn't, so its first bytes could
+        // still be on the way through it.
+        if self.direct.is_some() {
+            self.wait_for_relay();
+        }
+        let n = try!(self.read_relay(buf));
+        if n > 0 {
+            return Ok(n);
+        }
+        if self.relay.is_some() {
+            // The peer has finished with the relay. Without the marker it's closed the stream.
+            if !self.peer_moved {
+                return Ok(0);
+            }
+            if self.direct.is_none() {
+                self.follow();
+            }
+            if self.direct.is_none() {
+                return Err(io::Error::new(io::ErrorKind::NotConnected,
+                                          "The peer moved over to a direct stream we don't have"));
+            }
+            self.relay = None;
+        }
+        match self.direct {
+            Some(ref mut direct) => direct.stream.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Write for RacedTcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.poll();
+        if let Some(ref mut direct) = self.direct {
+            return direct.stream.write(buf);
+        }
+        match self.relay {
+            Some(ref mut relay) => {
+                // An empty frame would be read as the cutover marker.
+                if buf.is_empty() {
+                    return Ok(0);
+                }
+                let len = cmp::min(buf.len(), MAX_FRAME_LEN);
+                let mut frame = Vec::with_capacity(2 + len);
+                frame.push((len >> 8) as u8);
+                frame.push(len as u8);
+                frame.extend_from_slice(&buf[..len]);
+                try!(relay.stream.write_all(&frame));
+                Ok(len)
+            },
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "No path to the peer")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(ref mut relay) = self.relay {
+            if !self.relay_write_closed {
+                try!(relay.stream.flush());
+            }
+        }
+        match self.direct {
+            Some(ref mut direct) => direct.stream.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use socket_addr::SocketAddr;
+    use w_result::WOk;
+
+    use mapped_tcp_socket::{PunchedTcpStream, TcpPunchTechnique};
+    use tcp_splice::{SpliceToken, TcpSpliceServer};
+
+    fn punched(stream: TcpStream, technique: TcpPunchTechnique) -> PunchedTcpStream {
+        PunchedTcpStream {
+            local_addr: SocketAddr(unwrap_result!(stream.local_addr())),
+            peer_addr: SocketAddr(unwrap_result!(stream.peer_addr())),
+            stream: stream,
+            technique: technique,
+            mptcp: false,
+        }
+    }
+
+    #[test]
+    fn relayed_traffic_moves_over_to_a_late_direct_stream_in_order() {
+        let server = unwrap_result!(TcpSpliceServer::bind(&unwrap_result!("127.0.0.1:0".parse())));
+        let relay = server.local_addr();
+        let token = SpliceToken::random();
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        let listener = unwrap_result!(TcpListener::bind("127.0.0.1:0"));
+        let connected = unwrap_result!(TcpStream::connect(unwrap_result!(listener.local_addr())));
+        let (accepted, _) = unwrap_result!(listener.accept());
+
+        let peer = thread!("relayed_traffic_moves_over", move || {
+            let mut stream = unwrap_result!(TcpRaceBuilder::new(&relay, &token, deadline)
+                                                .race(move || {
+                thread::sleep(Duration::from_millis(300));
+                WOk(punched(connected, TcpPunchTechnique::Connected), Vec::new())
+            }));
+            assert_eq!(stream.technique(), TcpPunchTechnique::Relayed);
+            unwrap_result!(stream.write_all(b"relayed "));
+            thread::sleep(Duration::from_millis(600));
+            unwrap_result!(stream.write_all(b"direct"));
+            assert_eq!(stream.technique(), TcpPunchTechnique::Connected);
+        });
+
+        let mut stream = unwrap_result!(TcpRaceBuilder::new(&relay, &token, deadline)
+                                            .race(move || {
+            thread::sleep(Duration::from_millis(300));
+            WOk(punched(accepted, TcpPunchTechnique::Accepted), Vec::new())
+        }));
+        let mut data = Vec::new();
+        let _ = unwrap_result!(stream.read_to_end(&mut data));
+        assert_eq!(data, b"relayed direct");
+        assert_eq!(stream.technique(), TcpPunchTechnique::Accepted);
+        assert!(stream.relayed().is_none());
+        unwrap_result!(peer.join());
+    }
+
+    #[test]
+    fn a_peer_past_its_cutover_window_follows_the_other_over() {
+        let server = unwrap_result!(TcpSpliceServer::bind(&unwrap_result!("127.0.0.1:0".parse())));
+        let relay = server.local_addr();
+        let token = SpliceToken::random();
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        let listener = unwrap_result!(TcpListener::bind("127.0.0.1:0"));
+        let connected = unwrap_result!(TcpStream::connect(unwrap_result!(listener.local_addr())));
+        let (accepted, _) = unwrap_result!(listener.accept());
+
+        let peer = thread!("a_peer_past_its_cutover_window", move || {
+            let mut stream = unwrap_result!(TcpRaceBuilder::new(&relay, &token, deadline)
+                                                .cutover_window(Duration::from_secs(0))
+                                                .race(move || {
+                thread::sleep(Duration::from_millis(300));
+                WOk(punched(connected, TcpPunchTechnique::Connected), Vec::new())
+            }));
+            let mut data = [0u8; 6];
+            unwrap_result!(stream.read_exact(&mut data));
+            assert_eq!(&data, b"direct");
+            assert_eq!(stream.technique(), TcpPunchTechnique::Connected);
+            unwrap_result!(stream.write_all(b"back"));
+        });
+
+        let mut stream = unwrap_result!(TcpRaceBuilder::new(&relay, &token, deadline)
+                                            .race(move || {
+            thread::sleep(Duration::from_millis(300));
+            WOk(punched(accepted, TcpPunchTechnique::Accepted), Vec::new())
+        }));
+        thread::sleep(Duration::from_millis(600));
+        unwrap_result!(stream.write_all(b"direct"));
+        assert_eq!(stream.technique(), TcpPunchTechnique::Accepted);
+        let mut data = Vec::new();
+        let _ = unwrap_result!(stream.read_to_end(&mut data));
+        assert_eq!(data, b"back");
+        unwrap_result!(peer.join());
+    }
+}
@@ -0,0 +1,418 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Relaying tcp by splicing. Both peers connect out to a relay, which pairs their connections up
+//! by a token they agreed on and copies bytes between them. Unlike TURN over tcp there's no
+//! allocation or permission to manage, and firewalls that only let outbound tcp through are no
+//! obstacle.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rand;
+use socket_addr::SocketAddr;
+
+use error_code::{ErrorCategory, ErrorCode};
+use listener_message::{self, SPLICE_MAGIC_CONSTANT, SPLICE_REQUEST_LEN, SPLICE_TOKEN_LEN};
+use mapped_tcp_socket::{PunchedTcpStream, TcpPunchTechnique};
+use socket_utils;
+
+/// How long the relay waits for a connection's splice request.
+const SPLICE_REQUEST_TIMEOUT_SECS: u64 = 20;
+/// How long the relay holds a connection waiting for the peer's.
+const SPLICE_WAIT_SECS: u64 = 60;
+/// The most connections the relay holds waiting for their peers'.
+const MAX_WAITING_SPLICES: usize = 1024;
+
+/// Pairs up the two peers' connections to a splice relay. One peer generates it and sends it to
+/// the other along with its rendezvous info. Anyone who knows it can take the peer's place, so
+/// it should only go to the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpliceToken(pub [u8; SPLICE_TOKEN_LEN]);
+
+impl SpliceToken {
+    /// Generate a new random token.
+    pub fn random() -> SpliceToken {
+        SpliceToken(rand::random())
+    }
+}
+
+quick_error! {
+    /// Errors returned by `tcp_splice`.
+    #[derive(Debug)]
+    pub enum TcpSpliceError {
+        /// Error connecting to the relay.
+        Connect { err: io::Error } {
+            description("Error connecting to the relay.")
+            display("Error connecting to the relay: {}", err)
+            cause(err)
+        }
+        /// IO error talking to the relay.
+        Io { err: io::Error } {
+            description("IO error talking to the relay.")
+            display("IO error talking to the relay: {}", err)
+            cause(err)
+        }
+        /// The peer didn't connect to the relay before the deadline.
+        TimedOut {
+            description("The peer didn't connect to the relay before the deadline.")
+        }
+        /// The relay is overloaded and asked us to try again later.
+        Busy {
+            description("The relay is overloaded and asked us to try again later.")
+        }
+        /// The relay sent something other than a splice confirmation.
+        InvalidResponse {
+            description("The relay sent something other than a splice confirmation.")
+        }
+    }
+}
+
+impl From<TcpSpliceError> for io::Error {
+    fn from(e: TcpSpliceError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            TcpSpliceError::Connect { err } => err.kind(),
+            TcpSpliceError::Io { err } => err.kind(),
+            TcpSpliceError::TimedOut => io::ErrorKind::TimedOut,
+            TcpSpliceError::Busy => io::ErrorKind::Other,
+            TcpSpliceError::InvalidResponse => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for TcpSpliceError {
+    fn code(&self) -> u32 {
+        match *self {
+            TcpSpliceError::Connect { .. } => 3601,
+            TcpSpliceError::Io { .. } => 3602,
+            TcpSpliceError::TimedOut => 3603,
+            TcpSpliceError::Busy => 3604,
+            TcpSpliceError::InvalidResponse => 3605,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            TcpSpliceError::Connect { .. } => ErrorCategory::Network,
+            TcpSpliceError::Io { .. } => ErrorCategory::Network,
+            TcpSpliceError::TimedOut => ErrorCategory::Network,
+            TcpSpliceError::Busy => ErrorCategory::Network,
+            TcpSpliceError::InvalidResponse => ErrorCategory::Protocol,
+        }
+    }
+}
+
+/// Connect to the splice relay at `relay` and wait until `deadline` for it to splice our
+/// connection to the peer's, made with the same `token`. The stream returned carries the peer's
+/// data like any other, with `TcpPunchTechnique::Relayed` and the relay's address as its peer
+/// address.
+pub fn tcp_splice(relay: &SocketAddr, token: &SpliceToken, deadline: Instant)
+                  -> Result<PunchedTcpStream, TcpSpliceError> {
+    let mut stream = match TcpStream::connect(&**relay) {
+        Ok(stream) => stream,
+        Err(e) => return Err(TcpSpliceError::Connect { err: e }),
+    };
+    let mut request = [0u8; SPLICE_REQUEST_LEN];
+    request[..4].copy_from_slice(&SPLICE_MAGIC_CONSTANT[..]);
+    request[4..].copy_from_slice(&token.0[..]);
+    if let Err(e) = stream.write_all(&request[..]) {
+        return Err(TcpSpliceError::Io { err: e });
+    }
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(TcpSpliceError::TimedOut);
+    }
+    if let Err(e) = stream.set_read_timeout(Some(deadline - now)) {
+        return Err(TcpSpliceError::Io { err: e });
+    }
+    let mut response = [0u8; 4];
+    match stream.read_exact(&mut response[..]) {
+        Ok(()) => (),
+        Err(e) => {
+            return match e.kind() {
+                io::ErrorKind::TimedOut |
+                io::ErrorKind::WouldBlock => Err(TcpSpliceError::TimedOut),
+                _ => Err(TcpSpliceError::Io { err: e }),
+            };
+        },
+    }
+    if response == listener_message::TRY_LATER_MAGIC_CONSTANT {
+        return Err(TcpSpliceError::Busy);
+    }
+    if response != SPLICE_MAGIC_CONSTANT {
+        return Err(TcpSpliceError::InvalidResponse);
+    }
+    if let Err(e) = stream.set_read_timeout(None) {
+        return Err(TcpSpliceError::Io { err: e });
+    }
+    let local_addr = match stream.local_addr() {
+        Ok(local_addr) => local_addr,
+        Err(e) => return Err(TcpSpliceError::Io { err: e }),
+    };
+    Ok(PunchedTcpStream {
+        stream: stream,
+        technique: TcpPunchTechnique::Relayed,
+        local_addr: SocketAddr(local_addr),
+        peer_addr: *relay,
+        mptcp: false,
+    })
+}
+
+quick_error! {
+    /// Errors returned by `TcpSpliceServer::bind` and `TcpSpliceServer::from_listener`.
+    #[derive(Debug)]
+    pub enum TcpSpliceServerNewError {
+        /// Error binding the listener.
+        Bind { err: io::Error } {
+            description("Error binding the listener.")
+            display("Error binding the listener: {}", err)
+            cause(err)
+        }
+        /// Error getting the local address of the listener.
+        SocketLocalAddr { err: io::Error } {
+            description("Error getting the local address of the listener.")
+            display("Error getting the local address of the listener: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl From<TcpSpliceServerNewError> for io::Error {
+    fn from(e: TcpSpliceServerNewError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            TcpSpliceServerNewError::Bind { err } => err.kind(),
+            TcpSpliceServerNewError::SocketLocalAddr { err } => err.kind(),
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for TcpSpliceServerNewError {
+    fn code(&self) -> u32 {
+        match *self {
+            TcpSpliceServerNewError::Bind { .. } => 3701,
+            TcpSpliceServerNewError::SocketLocalAddr { .. } => 3702,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            TcpSpliceServerNewError::Bind { .. } => ErrorCategory::Configuration,
+            TcpSpliceServerNewError::SocketLocalAddr { .. } => ErrorCategory::Network,
+        }
+    }
+}
+
+type WaitingSplices = Arc<Mutex<HashMap<SpliceToken, (TcpStream, Instant)>>>;
+
+/// RAII type for a relay which splices together connections made with the same `SpliceToken`.
+/// Connections already spliced carry on after it's dropped.
+pub struct TcpSpliceServer {
+    stop_flag: Arc<AtomicBool>,
+    local_addr: net::SocketAddr,
+    spliced: Arc<AtomicUsize>,
+}
+
+impl fmt::Debug for TcpSpliceServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TcpSpliceServer")
+         .field("local_addr", &self.local_addr)
+         .field("spliced", &self.spliced_connections())
+         .finish()
+    }
+}
+
+impl TcpSpliceServer {
+    /// Listen for connections to splice on `addr`.
+    pub fn bind(addr: &net::SocketAddr) -> Result<TcpSpliceServer, TcpSpliceServerNewError> {
+        match TcpListener::bind(addr) {
+            Ok(listener) => TcpSpliceServer::from_listener(listener),
+            Err(e) => Err(TcpSpliceServerNewError::Bind { err: e }),
+        }
+    }
+
+    /// Like `bind` but serves on a listener that's already bound.
+    pub fn from_listener(listener: TcpListener)
+                         -> Result<TcpSpliceServer, TcpSpliceServerNewError> {
+        let local_addr = match listener.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(e) => return Err(TcpSpliceServerNewError::SocketLocalAddr { err: e }),
+        };
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let cloned_stop_flag = stop_flag.clone();
+        let spliced = Arc::new(AtomicUsize::new(0));
+        let cloned_spliced = spliced.clone();
+        let _ = thread!("TcpSpliceServer accept", move || {
+            let waiting: WaitingSplices = Arc::new(Mutex::new(HashMap::new()));
+            for stream in listener.incoming() {
+                if cloned_stop_flag.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let waiting = waiting.clone();
+                let spliced = cloned_spliced.clone();
+                let _ = thread!("TcpSpliceServer request", move || {
+                    serve(stream, waiting, spliced);
+                });
+            }
+        });
+        Ok(TcpSpliceServer {
+            stop_flag: stop_flag,
+            local_addr: local_addr,
+            spliced: spliced,
+        })
+    }
+
+    /// The address the relay listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        SocketAddr(self.local_addr)
+    }
+
+    /// How many pairs of connections have been spliced.
+    pub fn spliced_connections(&self) -> usize {
+        self.spliced.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for TcpSpliceServer {
+    fn drop(&mut self) {
+        self.stop_flag.store(true, Ordering::SeqCst);
+        // Wake the acceptor up so that it notices.
+        let addr = net::SocketAddr::new(socket_utils::ip_unspecified_to_loopback(self.local_addr
+                                                                                     .ip()),
+                                        self.local_addr.port());
+        let _ = TcpStream::connect(&addr);
+    }
+}
+
+// Read a connection's splice request, then either splice it to the connection waiting with the
+// same token or leave it waiting in turn.
+fn serve(mut stream: TcpStream, waiting: WaitingSplices, spliced: Arc<AtomicUsize>) {
+    let timeout = Duration::from_secs(SPLICE_REQUEST_TIMEOUT_SECS);
+    if stream.set_read_timeout(Some(timeout)).is_err() {
+        return;
+    }
+    let mut request = [0u8; SPLICE_REQUEST_LEN];
+    if stream.read_exact(&mut request[..]).is_err() || request[..4] != SPLICE_MAGIC_CONSTANT {
+        return;
+    }
+    let mut token = [0u8; SPLICE_TOKEN_LEN];
+    token.copy_from_slice(&request[4..]);
+    let token = SpliceToken(token);
+
+    let partner = {
+        let mut waiting = unwrap_result!(waiting.lock());
+        let now = Instant::now();
+        let wait = Duration::from_secs(SPLICE_WAIT_SECS);
+        let expired: Vec<SpliceToken> = waiting.iter()
+                                               .filter(|&(_, &(_, since))| now - since >= wait)
+                                               .map(|(token, _)| *token)
+                                               .collect();
+        for token in expired {
+            let _ = waiting.remove(&token);
+        }
+        match waiting.remove(&token) {
+            Some((partner, _)) => partner,
+            None => {
+                if waiting.len() >= MAX_WAITING_SPLICES {
+                    let _ = stream.write_all(&listener_message::TRY_LATER_MAGIC_CONSTANT[..]);
+                    return;
+                }
+                let _ = waiting.insert(token, (stream, now));
+                return;
+            },
+        }
+    };
+    splice(stream, partner, &spliced);
+}
+
+// Tell both connections they've been spliced then copy between them until both sides finish.
+fn splice(mut stream: TcpStream, mut partner: TcpStream, spliced: &AtomicUsize) {
+    if stream.set_read_timeout(None).is_err() || partner.set_read_timeout(None).is_err() {
+        return;
+    }
+    if stream.write_all(&SPLICE_MAGIC_CONSTANT[..]).is_err() ||
+       partner.write_all(&SPLICE_MAGIC_CONSTANT[..]).is_err() {
+        return;
+    }
+    let (stream_clone, partner_clone) = match (stream.try_clone(), partner.try_clone()) {
+        (Ok(stream_clone), Ok(partner_clone)) => (stream_clone, partner_clone),
+        _ => return,
+    };
+    let _ = spliced.fetch_add(1, Ordering::SeqCst);
+    let _ = thread!("TcpSpliceServer copy", move || copy_until_closed(stream, partner_clone));
+    copy_until_closed(partner, stream_clone);
+}
+
+// Copy `from` into `to` until `from` closes, then pass the half-close on.
+fn copy_until_closed(mut from: TcpStream, mut to: TcpStream) {
+    let _ = io::copy(&mut from, &mut to);
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::time::{Duration, Instant};
+
+    use mapped_tcp_socket::TcpPunchTechnique;
+
+    #[test]
+    fn relay_splices_peers_with_the_same_token() {
+        let server = unwrap_result!(TcpSpliceServer::bind(&unwrap_result!("127.0.0.1:0".parse())));
+        let relay = server.local_addr();
+        let token = SpliceToken::random();
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        let peer = thread!("relay_splices_peers_with_the_same_token", move || {
+            let mut punched = unwrap_result!(tcp_splice(&relay, &token, deadline));
+            unwrap_result!(punched.stream.write_all(b"hello"));
+            unwrap_result!(punched.stream.shutdown(::std::net::Shutdown::Write));
+        });
+        let mut punched = unwrap_result!(tcp_splice(&relay, &token, deadline));
+        assert_eq!(punched.technique, TcpPunchTechnique::Relayed);
+        assert_eq!(punched.peer_addr, relay);
+        let mut data = Vec::new();
+        let _ = unwrap_result!(punched.stream.read_to_end(&mut data));
+        assert_eq!(data, b"hello");
+        unwrap_result!(peer.join());
+        assert_eq!(server.spliced_connections(), 1);
+    }
+
+    #[test]
+    fn unpaired_connection_times_out() {
+        let server = unwrap_result!(TcpSpliceServer::bind(&unwrap_result!("127.0.0.1:0".parse())));
+        let deadline = Instant::now() + Duration::from_millis(300);
+        match tcp_splice(&server.local_addr(), &SpliceToken::random(), deadline) {
+            Err(TcpSpliceError::TimedOut) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|punched| punched.peer_addr)),
+        }
+    }
+}
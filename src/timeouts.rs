@@ -0,0 +1,106 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// How long each stage of traversal may take. Every stage is bounded, so the worst case time to
+/// connect is roughly `gather` plus `connect`.
+///
+/// Set the timeouts used when mapping sockets with `MappingContext::set_timeouts` and pass them to
+/// `UdpPunchBuilder::with_timeouts` or `TcpPunchBuilder::with_timeouts` for hole punching. Like
+/// the crate's other configuration types they can be read from an application's config file with
+/// `rustc_serialize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct Timeouts {
+    /// How long gathering our endpoints, ie. mapping a socket, takes at most. See
+    /// `gather_deadline`.
+    pub gather: Duration,
+    /// How long a single server, such as an IGD gateway, STUN or http echo server or a tcp
+    /// simple server, is waited on before moving on to the next.
+    pub server_query: Duration,
+    /// How long a single peer endpoint is tried before it's given up on.
+    pub check: Duration,
+    /// How long hole punching takes at most. See `connect_deadline`.
+    pub connect: Duration,
+    /// How often keepalives are sent on a punched connection once it's quiet, or `None` to not
+    /// send any.
+    pub keepalive: Option<Duration>,
+}
+
+impl Timeouts {
+    /// When gathering should give up if it starts now.
+    pub fn gather_deadline(&self) -> Instant {
+        Instant::now() + self.gather
+    }
+
+    /// When hole punching should give up if it starts now.
+    pub fn connect_deadline(&self) -> Instant {
+        Instant::now() + self.connect
+    }
+
+    /// When a query to a single server starting now should give up, without going past
+    /// `deadline`.
+    pub fn server_query_deadline(&self, deadline: Instant) -> Instant {
+        cmp::min(Instant::now() + self.server_query, deadline)
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            gather: Duration::from_secs(3),
+            server_query: Duration::from_secs(1),
+            check: Duration::from_secs(5),
+            connect: Duration::from_secs(10),
+            keepalive: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use rustc_serialize::json;
+
+    #[test]
+    fn timeouts_survive_a_json_round_trip() {
+        let timeouts = Timeouts {
+            keepalive: Some(Duration::from_millis(15500)),
+            ..Timeouts::default()
+        };
+        let encoded = unwrap_result!(json::encode(&timeouts));
+        let decoded: Timeouts = unwrap_result!(json::decode(&encoded));
+        assert_eq!(decoded, timeouts);
+    }
+
+    #[test]
+    fn server_query_deadline_is_capped() {
+        let timeouts = Timeouts::default();
+        let soon = Instant::now() + Duration::from_millis(100);
+        assert_eq!(timeouts.server_query_deadline(soon), soon);
+
+        let later = Instant::now() + Duration::from_secs(60);
+        assert!(timeouts.server_query_deadline(later) < later);
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::io;
+use std::net::{self, IpAddr, UdpSocket};
+use std::time::Instant;
+
+use socket_addr::SocketAddr;
+
+use batch;
+use pktinfo;
+use socket_utils::RecvUntil;
+
+/// An unreliable datagram socket.
+///
+/// `MappedUdpSocket::map` and `PunchedUdpSocket::punch_hole` are generic over this trait so that
+/// traversal can be run over sockets other than a plain `UdpSocket`, such as an encrypted tunnel
+/// or a test harness socket.
+pub trait DatagramTransport {
+    /// Send a datagram to `addr`. Returns the number of bytes sent.
+    fn send_to(&self, buf: &[u8], addr: &net::SocketAddr) -> io::Result<usize>;
+
+    /// Receive a datagram, blocking until one arrives or `deadline` passes. Returns the number of
+    /// bytes read and the sender's address, or `None` on timeout.
+    fn recv_until(&self, buf: &mut [u8], deadline: Instant)
+                  -> io::Result<Option<(usize, SocketAddr)>>;
+
+    /// Like `recv_until` but also returns the local address that the datagram was sent to, which
+    /// for a transport bound to the unspecified address tells which of the host's addresses the
+    /// sender reached. Transports which can't tell return `None` for it, as the default does.
+    fn recv_until_with_local_ip(&self, buf: &mut [u8], deadline: Instant)
+                                -> io::Result<Option<(usize, SocketAddr, Option<IpAddr>)>> {
+        let res = try!(self.recv_until(buf, deadline));
+        Ok(res.map(|(len, addr)| (len, addr, None)))
+    }
+
+    /// The local address that the transport is bound to.
+    fn local_addr(&self) -> io::Result<net::SocketAddr>;
+
+    /// Send each of `datagrams` to its address. Returns the outcome of each send, in order.
+    /// Transports which can send several datagrams per system call should override this.
+    fn send_batch(&self, datagrams: &[(&[u8], net::SocketAddr)]) -> Vec<io::Result<usize>> {
+        datagrams.iter().map(|&(buf, ref addr)| self.send_to(buf, addr)).collect()
+    }
+}
+
+impl DatagramTransport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: &net::SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_until(&self, buf: &mut [u8], deadline: Instant)
+                  -> io::Result<Option<(usize, SocketAddr)>> {
+        RecvUntil::recv_until(self, buf, deadline)
+    }
+
+    fn recv_until_with_local_ip(&self, buf: &mut [u8], deadline: Instant)
+                                -> io::Result<Option<(usize, SocketAddr, Option<IpAddr>)>> {
+        pktinfo::recv_until(self, buf, deadline)
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn send_batch(&self, datagrams: &[(&[u8], net::SocketAddr)]) -> Vec<io::Result<usize>> {
+        batch::send_batch(self, datagrams)
+    }
+}
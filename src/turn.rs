@@ -0,0 +1,1627 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::net;
+use std::net::{IpAddr, TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::fmt;
+
+use rand;
+use socket_addr::SocketAddr;
+
+use error_code::{ErrorCategory, ErrorCode};
+use mapping_context::MappingContext;
+use md5;
+use runtime::{RuntimeHandle, Sockets};
+use stun;
+use stun::{DecodedStunMessage, StunAttribute, StunClass, StunMessage};
+use transport::DatagramTransport;
+use turn_tcp;
+use turn_tcp::TcpRelays;
+
+/// The IP protocol number of udp.
+const TRANSPORT_UDP: u8 = 17;
+pub const DEFAULT_LIFETIME_SECS: u64 = 600;
+pub const PERMISSION_LIFETIME_SECS: u64 = 300;
+const CHANNEL_LIFETIME_SECS: u64 = 600;
+const FIRST_CHANNEL: u16 = 0x4000;
+const LAST_CHANNEL: u16 = 0x7ffe;
+pub const SERVER_READ_TIMEOUT_MS: u64 = 500;
+/// The most datagrams read from one socket each time it's readable, so that a busy allocation
+/// can't starve the rest of the runtime.
+const MAX_DATAGRAMS_PER_POLL: usize = 64;
+const INITIAL_RTO_MS: u64 = 500;
+const MAX_REQUEST_ATTEMPTS: u32 = 7;
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+quick_error! {
+    /// Errors returned by `TurnAllocation`.
+    #[derive(Debug)]
+    pub enum TurnError {
+        /// IO error talking to the relay server.
+        Io { err: io::Error } {
+            description("IO error talking to the TURN server")
+            display("IO error talking to the TURN server: {}", err)
+            cause(err)
+        }
+        /// The server didn't respond in time.
+        TimedOut {
+            description("Timed out waiting for the TURN server to respond")
+        }
+        /// The server rejected our request.
+        Rejected { code: u16, reason: String } {
+            description("The TURN server rejected our request")
+            display("The TURN server rejected our request: {} {}", code, reason)
+        }
+        /// The server's response was missing attributes or failed its integrity check.
+        BadResponse {
+            description("The TURN server sent an invalid response")
+        }
+        /// Every channel number has been bound.
+        NoFreeChannels {
+            description("No channel numbers are left to bind")
+        }
+    }
+}
+
+impl From<TurnError> for io::Error {
+    fn from(e: TurnError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            TurnError::Io { err } => err.kind(),
+            TurnError::TimedOut => io::ErrorKind::TimedOut,
+            TurnError::Rejected { .. } => io::ErrorKind::ConnectionRefused,
+            TurnError::BadResponse => io::ErrorKind::InvalidData,
+            TurnError::NoFreeChannels => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for TurnError {
+    fn code(&self) -> u32 {
+        match *self {
+            TurnError::Io { .. } => 2801,
+            TurnError::TimedOut => 2802,
+            TurnError::Rejected { .. } => 2803,
+            TurnError::BadResponse => 2804,
+            TurnError::NoFreeChannels => 2805,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match *self {
+            TurnError::Io { .. } |
+            TurnError::TimedOut => ErrorCategory::Network,
+            TurnError::Rejected { .. } |
+            TurnError::BadResponse => ErrorCategory::Protocol,
+            TurnError::NoFreeChannels => ErrorCategory::Configuration,
+        }
+    }
+}
+
+quick_error! {
+    /// Errors returned by `TurnServer::new`.
+    #[derive(Debug)]
+    pub enum TurnServerNewError {
+        /// Error binding the server's socket.
+        Bind { err: io::Error } {
+            description("Error binding the TURN server's socket")
+            display("Error binding the TURN server's socket: {}", err)
+            cause(err)
+        }
+        /// Error listening for tcp clients on the server's port.
+        Listen { err: io::Error } {
+            description("Error listening for tcp connections to the TURN server")
+            display("Error listening for tcp connections to the TURN server: {}", err)
+            cause(err)
+        }
+        /// Error putting the server's socket or tcp listener into non-blocking mode.
+        SetNonblocking { err: io::Error } {
+            description("Error putting the TURN server's sockets into non-blocking mode")
+            display("Error putting the TURN server's sockets into non-blocking mode: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl From<TurnServerNewError> for io::Error {
+    fn from(e: TurnServerNewError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            TurnServerNewError::Bind { err } |
+            TurnServerNewError::Listen { err } |
+            TurnServerNewError::SetNonblocking { err } => err.kind(),
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl ErrorCode for TurnServerNewError {
+    fn code(&self) -> u32 {
+        match *self {
+            TurnServerNewError::Bind { .. } => 2901,
+            // 2902 was `SetSocketTimeout`, from when the server blocked in `recv_from` on a
+            // thread of its own. It stays retired.
+            TurnServerNewError::Listen { .. } => 2903,
+            TurnServerNewError::SetNonblocking { .. } => 2904,
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        ErrorCategory::Network
+    }
+}
+
+/// A username and password for a TURN server's long-term credentials.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TurnCredentials {
+    /// The username.
+    pub username: String,
+    /// The password.
+    pub password: String,
+}
+
+// The MESSAGE-INTEGRITY key for long-term credentials.
+fn long_term_key(username: &str, realm: &str, password: &str) -> [u8; 16] {
+    md5::md5(format!("{}:{}:{}", username, realm, password).as_bytes())
+}
+
+/// The code and reason of an error response.
+pub fn error_code(message: &StunMessage) -> (u16, String) {
+    message.find(|attr| match *attr {
+               StunAttribute::ErrorCode { code, ref reason } => Some((code, reason.clone())),
+               _ => None,
+           })
+           .unwrap_or((0, String::new()))
+}
+
+/// The client side of the long-term credential mechanism, shared by the udp and tcp clients.
+pub struct LongTermAuth {
+    username: String,
+    realm: String,
+    nonce: String,
+    key: [u8; 16],
+}
+
+impl LongTermAuth {
+    /// Take the realm and nonce from the server's 401 response to an unauthenticated request.
+    pub fn from_challenge(challenge: &StunMessage, credentials: &TurnCredentials)
+                          -> Result<LongTermAuth, TurnError>
+    {
+        let (code, reason) = error_code(challenge);
+        if challenge.class != StunClass::ErrorResponse || code != 401 {
+            return Err(TurnError::Rejected {
+                code: code,
+                reason: reason,
+            });
+        }
+        let realm = challenge.find(|attr| match *attr {
+            StunAttribute::Realm(ref realm) => Some(realm.clone()),
+            _ => None,
+        });
+        let nonce = challenge.find(|attr| match *attr {
+            StunAttribute::Nonce(ref nonce) => Some(nonce.clone()),
+            _ => None,
+        });
+        match (realm, nonce) {
+            (Some(realm), Some(nonce)) => {
+                Ok(LongTermAuth {
+                    key: long_term_key(&credentials.username, &realm, &credentials.password),
+                    username: credentials.username.clone(),
+                    realm: realm,
+                    nonce: nonce,
+                })
+            },
+            _ => Err(TurnError::BadResponse),
+        }
+    }
+
+    /// Encode a signed request. Returns the request and its transaction id.
+    pub fn request(&self, method: u16, attributes: Vec<StunAttribute>) -> (Vec<u8>, [u8; 12]) {
+        let mut request = StunMessage::new(StunClass::Request, method, rand::random());
+        request.attributes = attributes;
+        request.attributes.push(StunAttribute::Username(self.username.clone()));
+        request.attributes.push(StunAttribute::Realm(self.realm.clone()));
+        request.attributes.push(StunAttribute::Nonce(self.nonce.clone()));
+        (request.encode(Some(&self.key[..]), true), request.transaction_id)
+    }
+
+    /// Interpret the response to a signed request. Returns `None` if the server says our nonce
+    /// is stale, in which case the request should be signed again and resent. That's only
+    /// allowed once, which the caller tracks with `retried`.
+    pub fn check_response(&mut self, decoded: &DecodedStunMessage, retried: bool)
+                          -> Result<Option<StunMessage>, TurnError>
+    {
+        let response = &decoded.message;
+        if response.class == StunClass::SuccessResponse {
+            if !decoded.verify_integrity(&self.key) {
+                return Err(TurnError::BadResponse);
+            }
+            return Ok(Some(response.clone()));
+        }
+        let (code, reason) = error_code(response);
+        let nonce = response.find(|attr| match *attr {
+            StunAttribute::Nonce(ref nonce) => Some(nonce.clone()),
+            _ => None,
+        });
+        match nonce {
+            Some(nonce) if code == 438 && !retried => {
+                self.nonce = nonce;
+                Ok(None)
+            },
+            _ => {
+                Err(TurnError::Rejected {
+                    code: code,
+                    reason: reason,
+                })
+            },
+        }
+    }
+}
+
+/// Call `f` with the number of udp allocations in total and held by `username`, holding the lock
+/// on them so that no udp allocation is made meanwhile. The udp half takes the tcp half's lock
+/// while holding its own, so `f` may take that too.
+pub fn with_allocation_counts<R, F>(state: &ServerState, username: &str, f: F) -> R
+    where F: FnOnce(usize, usize) -> R
+{
+    let allocations = unwrap_result!(state.allocations.lock());
+    let user_allocations = allocations.values().filter(|a| a.username == username).count();
+    f(allocations.len(), user_allocations)
+}
+
+/// The relayed and mapped addresses from a successful Allocate response.
+pub fn allocated_addrs(response: &StunMessage)
+                       -> Result<(net::SocketAddr, net::SocketAddr), TurnError>
+{
+    let relayed_addr = response.find(|attr| match *attr {
+        StunAttribute::XorRelayedAddress(addr) => Some(addr),
+        _ => None,
+    });
+    let mapped_addr = response.find(|attr| match *attr {
+        StunAttribute::XorMappedAddress(addr) => Some(addr),
+        _ => None,
+    });
+    match (relayed_addr, mapped_addr) {
+        (Some(relayed_addr), Some(mapped_addr)) => Ok((relayed_addr, mapped_addr)),
+        _ => Err(TurnError::BadResponse),
+    }
+}
+
+// Send `request` to `server` until a response with the same transaction id arrives, doubling the
+// retransmission timeout each time. Any other traffic is discarded.
+fn transaction<S>(socket: &S,
+                  server: &net::SocketAddr,
+                  request: &[u8],
+                  transaction_id: &[u8; 12],
+                  deadline: Instant)
+                  -> Result<DecodedStunMessage, TurnError>
+    where S: DatagramTransport
+{
+    let mut rto = Duration::from_millis(INITIAL_RTO_MS);
+    let mut recv_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    for _ in 0..MAX_REQUEST_ATTEMPTS {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        if let Err(e) = socket.send_to(request, server) {
+            return Err(TurnError::Io { err: e });
+        }
+        let recv_deadline = cmp::min(now + rto, deadline);
+        rto = rto * 2;
+        loop {
+            let (len, from) = match socket.recv_until(&mut recv_buf, recv_deadline) {
+                Ok(Some(res)) => res,
+                Ok(None) => break,
+                Err(e) => return Err(TurnError::Io { err: e }),
+            };
+            if *from != *server {
+                continue;
+            }
+            if let Ok(decoded) = StunMessage::decode(&recv_buf[..len]) {
+                let is_response = match decoded.message.class {
+                    StunClass::SuccessResponse | StunClass::ErrorResponse => true,
+                    StunClass::Request | StunClass::Indication => false,
+                };
+                if is_response && decoded.message.transaction_id == *transaction_id {
+                    return Ok(decoded);
+                }
+            }
+        }
+    }
+    Err(TurnError::TimedOut)
+}
+
+/// An allocation on a TURN server. Datagrams sent through it are relayed from the allocation's
+/// relayed address, so peers can reach us there even when hole punching fails.
+///
+/// Peers need a permission, see `create_permission`, before the server will relay traffic to or
+/// from them. Binding a channel with `bind_channel` makes relaying cheaper.
+pub struct TurnAllocation<S: DatagramTransport = UdpSocket> {
+    socket: S,
+    server: net::SocketAddr,
+    auth: LongTermAuth,
+    relayed_addr: net::SocketAddr,
+    mapped_addr: net::SocketAddr,
+    lifetime: Duration,
+    channels: HashMap<net::SocketAddr, u16>,
+    next_channel: u16,
+}
+
+impl<S: DatagramTransport> fmt::Debug for TurnAllocation<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TurnAllocation")
+         .field("server", &self.server)
+         .field("relayed_addr", &self.relayed_addr)
+         .field("mapped_addr", &self.mapped_addr)
+         .field("lifetime", &self.lifetime)
+         .finish()
+    }
+}
+
+impl<S: DatagramTransport> TurnAllocation<S> {
+    /// Allocate a relayed address on the TURN server at `server`.
+    pub fn allocate(socket: S,
+                    server: &net::SocketAddr,
+                    credentials: &TurnCredentials,
+                    deadline: Instant)
+                    -> Result<TurnAllocation<S>, TurnError>
+    {
+        // The first request is unauthenticated. The server answers with the realm and a nonce to
+        // authenticate with.
+        let mut request = StunMessage::new(StunClass::Request,
+                                           stun::METHOD_ALLOCATE,
+                                           rand::random());
+        request.attributes.push(StunAttribute::RequestedTransport(TRANSPORT_UDP));
+        let challenge = try!(transaction(&socket,
+                                         server,
+                                         &request.encode(None, true),
+                                         &request.transaction_id,
+                                         deadline)).message;
+
+        let mut allocation = TurnAllocation {
+            auth: try!(LongTermAuth::from_challenge(&challenge, credentials)),
+            socket: socket,
+            server: *server,
+            relayed_addr: *server,
+            mapped_addr: *server,
+            lifetime: Duration::from_secs(0),
+            channels: HashMap::new(),
+            next_channel: FIRST_CHANNEL,
+        };
+        let attributes = vec![StunAttribute::RequestedTransport(TRANSPORT_UDP)];
+        let response = try!(allocation.request(stun::METHOD_ALLOCATE, attributes, deadline));
+        let (relayed_addr, mapped_addr) = try!(allocated_addrs(&response));
+        allocation.relayed_addr = relayed_addr;
+        allocation.mapped_addr = mapped_addr;
+        allocation.lifetime = lifetime_of(&response);
+        Ok(allocation)
+    }
+
+    /// The address on the server which peers send to in order to reach us.
+    pub fn relayed_addr(&self) -> net::SocketAddr {
+        self.relayed_addr
+    }
+
+    /// Our address as seen by the server.
+    pub fn mapped_addr(&self) -> net::SocketAddr {
+        self.mapped_addr
+    }
+
+    /// How long the allocation lasts from when it was made or last refreshed.
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Extend the allocation's lifetime.
+    pub fn refresh(&mut self, deadline: Instant) -> Result<(), TurnError> {
+        let lifetime = DEFAULT_LIFETIME_SECS as u32;
+        let response = try!(self.request(stun::METHOD_REFRESH,
+                                         vec![StunAttribute::Lifetime(lifetime)],
+                                         deadline));
+        self.lifetime = lifetime_of(&response);
+        Ok(())
+    }
+
+    /// Allow traffic to and from `peer`'s ip address to be relayed. Permissions last five
+    /// minutes and need to be renewed by calling this again.
+    pub fn create_permission(&mut self, peer: &net::SocketAddr, deadline: Instant)
+                             -> Result<(), TurnError>
+    {
+        let _ = try!(self.request(stun::METHOD_CREATE_PERMISSION,
+                                  vec![StunAttribute::XorPeerAddress(*peer)],
+                                  deadline));
+        Ok(())
+    }
+
+    /// Bind a channel to `peer` so that datagrams to and from it carry a four byte header rather
+    /// than a full STUN indication. This also creates a permission for the peer. Bindings last
+    /// ten minutes and need to be renewed by calling this again.
+    pub fn bind_channel(&mut self, peer: &net::SocketAddr, deadline: Instant)
+                        -> Result<u16, TurnError>
+    {
+        let channel = match self.channels.get(peer) {
+            Some(channel) => *channel,
+            None => {
+                if self.next_channel > LAST_CHANNEL {
+                    return Err(TurnError::NoFreeChannels);
+                }
+                self.next_channel
+            },
+        };
+        let _ = try!(self.request(stun::METHOD_CHANNEL_BIND,
+                                  vec![StunAttribute::ChannelNumber(channel),
+                                       StunAttribute::XorPeerAddress(*peer)],
+                                  deadline));
+        if channel == self.next_channel {
+            self.next_channel += 1;
+        }
+        let _ = self.channels.insert(*peer, channel);
+        Ok(channel)
+    }
+
+    fn request(&mut self, method: u16, attributes: Vec<StunAttribute>, deadline: Instant)
+               -> Result<StunMessage, TurnError>
+    {
+        let mut retried = false;
+        loop {
+            let (request, transaction_id) = self.auth.request(method, attributes.clone());
+            let decoded = try!(transaction(&self.socket,
+                                           &self.server,
+                                           &request,
+                                           &transaction_id,
+                                           deadline));
+            match try!(self.auth.check_response(&decoded, retried)) {
+                Some(response) => return Ok(response),
+                None => retried = true,
+            }
+        }
+    }
+}
+
+/// The LIFETIME of a response to an Allocate or Refresh request.
+pub fn lifetime_of(response: &StunMessage) -> Duration {
+    let secs = response.find(|attr| match *attr {
+        StunAttribute::Lifetime(secs) => Some(secs as u64),
+        _ => None,
+    });
+    Duration::from_secs(secs.unwrap_or(DEFAULT_LIFETIME_SECS))
+}
+
+impl<S: DatagramTransport> DatagramTransport for TurnAllocation<S> {
+    fn send_to(&self, buf: &[u8], addr: &net::SocketAddr) -> io::Result<usize> {
+        let datagram = match self.channels.get(addr) {
+            Some(channel) => stun::encode_channel_data(*channel, buf),
+            None => {
+                let mut indication = StunMessage::new(StunClass::Indication,
+                                                      stun::METHOD_SEND,
+                                                      rand::random());
+                indication.attributes.push(StunAttribute::XorPeerAddress(*addr));
+                indication.attributes.push(StunAttribute::Data(buf.to_vec()));
+                indication.encode(None, false)
+            },
+        };
+        let _ = try!(self.socket.send_to(&datagram, &self.server));
+        Ok(buf.len())
+    }
+
+    fn recv_until(&self, buf: &mut [u8], deadline: Instant)
+                  -> io::Result<Option<(usize, SocketAddr)>> {
+        let mut recv_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, from) = match try!(self.socket.recv_until(&mut recv_buf, deadline)) {
+                Some(res) => res,
+                None => return Ok(None),
+            };
+            if *from != self.server {
+                continue;
+            }
+            let datagram = &recv_buf[..len];
+            if let Some((channel, data)) = stun::decode_channel_data(datagram) {
+                let peer = self.channels.iter().find(|&(_, c)| *c == channel).map(|(p, _)| *p);
+                if let Some(peer) = peer {
+                    let n = cmp::min(buf.len(), data.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    return Ok(Some((n, SocketAddr(peer))));
+                }
+                continue;
+            }
+            let message = match StunMessage::decode(datagram) {
+                Ok(decoded) => decoded.message,
+                Err(_) => continue,
+            };
+            if message.class != StunClass::Indication || message.method != stun::METHOD_DATA {
+                continue;
+            }
+            let peer = message.find(|attr| match *attr {
+                StunAttribute::XorPeerAddress(addr) => Some(addr),
+                _ => None,
+            });
+            let data = message.find(|attr| match *attr {
+                StunAttribute::Data(ref data) => Some(data.clone()),
+                _ => None,
+            });
+            if let (Some(peer), Some(data)) = (peer, data) {
+                let n = cmp::min(buf.len(), data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                return Ok(Some((n, SocketAddr(peer))));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        Ok(self.relayed_addr)
+    }
+}
+
+impl<S: DatagramTransport> Drop for TurnAllocation<S> {
+    fn drop(&mut self) {
+        // Release the allocation rather than leaving it to expire. There's nobody to report a
+        // failure to so don't wait for the response.
+        let attributes = vec![StunAttribute::Lifetime(0)];
+        let (request, _) = self.auth.request(stun::METHOD_REFRESH, attributes);
+        let _ = self.socket.send_to(&request, &self.server);
+    }
+}
+
+/// Configuration for a `TurnServer`.
+#[derive(Debug, Clone)]
+pub struct TurnServerConfig {
+    /// The address the server listens for clients on.
+    pub bind_addr: net::SocketAddr,
+    /// The ip to allocate relayed addresses on. This must be an address of a local interface
+    /// that peers can reach.
+    pub relay_ip: IpAddr,
+    /// The realm for long-term credentials.
+    pub realm: String,
+    /// Usernames and their passwords.
+    pub users: HashMap<String, String>,
+    /// The most allocations the server holds at once.
+    pub max_allocations: usize,
+    /// The most allocations any one user can hold at once.
+    pub max_allocations_per_user: usize,
+    /// The longest lifetime a client can ask for.
+    pub max_lifetime: Duration,
+    /// The longest an allocation lasts, however often it's refreshed.
+    pub max_allocation_age: Option<Duration>,
+    /// The most bytes relayed, in both directions, for any one udp allocation.
+    pub max_bytes_per_allocation: Option<u64>,
+    /// The most bytes relayed for any one user over all their allocations, udp and tcp, since the
+    /// server started. A udp allocation is deleted as soon as it's sent something it has no
+    /// quota left for, and a tcp connection closed, so that clients aren't left sending into
+    /// the void. The allocation's next request then fails with 486 Allocation Quota Reached, as
+    /// does a Refresh once the quota is used up, and a user who is out of quota can't allocate.
+    pub max_bytes_per_user: Option<u64>,
+}
+
+impl TurnServerConfig {
+    /// A configuration with no users which relays on `bind_addr`'s ip.
+    pub fn new(bind_addr: net::SocketAddr, realm: String) -> TurnServerConfig {
+        TurnServerConfig {
+            bind_addr: bind_addr,
+            relay_ip: bind_addr.ip(),
+            realm: realm,
+            users: HashMap::new(),
+            max_allocations: 1000,
+            max_allocations_per_user: 10,
+            max_lifetime: Duration::from_secs(3600),
+            max_allocation_age: None,
+            max_bytes_per_allocation: None,
+            max_bytes_per_user: None,
+        }
+    }
+
+    /// Add a user who may allocate relayed addresses.
+    pub fn add_user(&mut self, username: String, password: String) {
+        let _ = self.users.insert(username, password);
+    }
+}
+
+/// A user's use of a `TurnServer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TurnUsage {
+    /// The bytes relayed for the user since the server started, counting both directions and
+    /// both udp and tcp.
+    pub bytes_relayed: u64,
+    /// The allocations the user holds now.
+    pub allocations: usize,
+}
+
+// A client's allocation on the server, keyed by the client's address.
+struct Allocation {
+    username: String,
+    relay: Arc<UdpSocket>,
+    relayed_addr: net::SocketAddr,
+    created: Instant,
+    expires: Instant,
+    bytes_relayed: u64,
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<u16, (net::SocketAddr, Instant)>,
+    // Stops the job relaying from `relay` once the allocation is removed.
+    relay_stop_flag: Arc<AtomicBool>,
+    runtime: RuntimeHandle,
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.runtime.stop(&self.relay_stop_flag);
+    }
+}
+
+impl Allocation {
+    // Count `len` bytes relayed for this allocation and its user. Returns false, counting
+    // nothing, if that would take either over its quota.
+    fn charge(&mut self, accounts: &Accounts, len: usize) -> bool {
+        let len = len as u64;
+        if let Some(max_bytes) = accounts.max_bytes_per_allocation {
+            if self.bytes_relayed + len > max_bytes {
+                return false;
+            }
+        }
+        if !accounts.charge_user(&self.username, len) {
+            return false;
+        }
+        self.bytes_relayed += len;
+        true
+    }
+
+    fn permitted(&self, ip: &IpAddr, now: Instant) -> bool {
+        match self.permissions.get(ip) {
+            Some(expires) => *expires > now,
+            None => false,
+        }
+    }
+
+    fn channel_peer(&self, channel: u16, now: Instant) -> Option<net::SocketAddr> {
+        match self.channels.get(&channel) {
+            Some(&(peer, expires)) if expires > now => Some(peer),
+            _ => None,
+        }
+    }
+
+    fn peer_channel(&self, peer: &net::SocketAddr, now: Instant) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|&(_, &(p, expires))| p == *peer && expires > now)
+            .map(|(channel, _)| *channel)
+    }
+}
+
+type Allocations = Arc<Mutex<HashMap<net::SocketAddr, Allocation>>>;
+
+/// State shared by the server's udp and tcp halves.
+pub struct ServerState {
+    /// The server's configuration.
+    pub config: TurnServerConfig,
+    /// The nonce clients must authenticate with.
+    pub nonce: String,
+    socket: Arc<UdpSocket>,
+    allocations: Allocations,
+    /// Allocations made over tcp and their connections.
+    pub tcp: TcpRelays,
+    /// The bytes relayed for each user.
+    pub accounts: Arc<Accounts>,
+}
+
+/// The bytes relayed for each user, and the quotas they're held to.
+pub struct Accounts {
+    max_bytes_per_allocation: Option<u64>,
+    max_bytes_per_user: Option<u64>,
+    bytes_per_user: Mutex<HashMap<String, u64>>,
+    // The clients whose udp allocations were deleted for going over quota, until the allocations
+    // would have expired.
+    out_of_quota: Mutex<HashMap<net::SocketAddr, Instant>>,
+}
+
+impl Accounts {
+    fn new(config: &TurnServerConfig) -> Accounts {
+        Accounts {
+            max_bytes_per_allocation: config.max_bytes_per_allocation,
+            max_bytes_per_user: config.max_bytes_per_user,
+            bytes_per_user: Mutex::new(HashMap::new()),
+            out_of_quota: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count `len` bytes relayed for `username`. Returns false, counting nothing, if that would
+    /// take the user over their quota.
+    pub fn charge_user(&self, username: &str, len: u64) -> bool {
+        let mut bytes_per_user = unwrap_result!(self.bytes_per_user.lock());
+        let bytes = bytes_per_user.entry(username.to_owned()).or_insert(0);
+        if let Some(max_bytes) = self.max_bytes_per_user {
+            if *bytes + len > max_bytes {
+                return false;
+            }
+        }
+        *bytes += len;
+        true
+    }
+
+    /// Whether `username` has used up their quota.
+    pub fn exhausted(&self, username: &str) -> bool {
+        let max_bytes = match self.max_bytes_per_user {
+            Some(max_bytes) => max_bytes,
+            None => return false,
+        };
+        let bytes_per_user = unwrap_result!(self.bytes_per_user.lock());
+        bytes_per_user.get(username).map_or(false, |bytes| *bytes >= max_bytes)
+    }
+}
+
+/// The lifetime to grant a Refresh or Allocate request for an allocation made at `created`, so
+/// that the allocation doesn't outlive `max_allocation_age`.
+pub fn capped_lifetime(state: &ServerState, created: Instant, requested: Duration) -> Duration {
+    match state.config.max_allocation_age {
+        Some(max_age) => {
+            let age = created.elapsed();
+            if age >= max_age {
+                Duration::from_secs(0)
+            } else {
+                cmp::min(requested, max_age - age)
+            }
+        },
+        None => requested,
+    }
+}
+
+/// A TURN relay server. Udp is relayed as in RFC 5766 and tcp connections as in RFC 6062, with
+/// clients connecting over tcp to the same port the server listens for udp on. Clients
+/// authenticate with long-term credentials and are limited by the quotas in the
+/// `TurnServerConfig`. The server's sockets and relayed addresses are served on the runtime of a
+/// `MappingContext`, and tcp connections each on a thread of their own, until it's dropped.
+pub struct TurnServer {
+    state: Arc<ServerState>,
+    stop_flag: Arc<AtomicBool>,
+    local_addr: net::SocketAddr,
+    runtime: RuntimeHandle,
+}
+
+impl fmt::Debug for TurnServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TurnServer").field("local_addr", &self.local_addr).finish()
+    }
+}
+
+impl TurnServer {
+    /// Start a server on `mc`'s runtime. `mc` must outlive the server.
+    pub fn new(mc: &MappingContext, config: TurnServerConfig)
+               -> Result<TurnServer, TurnServerNewError>
+    {
+        let socket = match UdpSocket::bind(config.bind_addr) {
+            Ok(socket) => socket,
+            Err(e) => return Err(TurnServerNewError::Bind { err: e }),
+        };
+        let local_addr = match socket.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(e) => return Err(TurnServerNewError::Bind { err: e }),
+        };
+        if let Err(e) = socket.set_nonblocking(true) {
+            return Err(TurnServerNewError::SetNonblocking { err: e });
+        }
+        let listener = match TcpListener::bind(local_addr) {
+            Ok(listener) => listener,
+            Err(e) => return Err(TurnServerNewError::Listen { err: e }),
+        };
+        if let Err(e) = listener.set_nonblocking(true) {
+            return Err(TurnServerNewError::SetNonblocking { err: e });
+        }
+
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let cloned_stop_flag = stop_flag.clone();
+        let tcp_stop_flag = stop_flag.clone();
+        let accounts = Arc::new(Accounts::new(&config));
+        let state = Arc::new(ServerState {
+            config: config,
+            nonce: format!("{:016x}", rand::random::<u64>()),
+            socket: Arc::new(socket),
+            allocations: Arc::new(Mutex::new(HashMap::new())),
+            tcp: TcpRelays::new(),
+            accounts: accounts,
+        });
+        let tcp_state = state.clone();
+        let run_state = state.clone();
+        let runtime = mc.runtime();
+        let cloned_runtime = runtime.clone();
+        runtime.spawn(move || {
+            serve(run_state, cloned_stop_flag, vec![0u8; MAX_DATAGRAM_SIZE], cloned_runtime);
+        });
+        let cloned_runtime = runtime.clone();
+        runtime.spawn(move || {
+            turn_tcp::serve(tcp_state, listener, tcp_stop_flag, cloned_runtime);
+        });
+        Ok(TurnServer {
+            state: state,
+            stop_flag: stop_flag,
+            local_addr: local_addr,
+            runtime: runtime,
+        })
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+
+    /// How much each user who has relayed anything or holds an allocation has used the server.
+    pub fn usage(&self) -> HashMap<String, TurnUsage> {
+        let mut usage: HashMap<String, TurnUsage> = HashMap::new();
+        let bytes_per_user = unwrap_result!(self.state.accounts.bytes_per_user.lock()).clone();
+        for (username, bytes) in bytes_per_user {
+            usage.entry(username).or_insert_with(TurnUsage::default).bytes_relayed = bytes;
+        }
+        let usernames: Vec<String> = {
+            let allocations = unwrap_result!(self.state.allocations.lock());
+            allocations.values().map(|a| a.username.clone()).collect()
+        };
+        for username in usernames.into_iter().chain(turn_tcp::usernames(&self.state.tcp)) {
+            usage.entry(username).or_insert_with(TurnUsage::default).allocations += 1;
+        }
+        usage
+    }
+
+}
+
+impl Drop for TurnServer {
+    fn drop(&mut self) {
+        self.runtime.stop(&self.stop_flag);
+    }
+}
+
+// Answer the datagrams waiting on the server's socket then wait for more, or for the next
+// allocation to expire. Once the server has been stopped its allocations are dropped instead, and
+// with them the jobs relaying from their relayed addresses.
+fn serve(state: Arc<ServerState>,
+         stop_flag: Arc<AtomicBool>,
+         mut read_buf: Vec<u8>,
+         runtime: RuntimeHandle) {
+    if stop_flag.load(Ordering::SeqCst) {
+        unwrap_result!(state.allocations.lock()).clear();
+        return;
+    }
+    expire_allocations(&state.allocations, &state.accounts);
+    let mut received = 0;
+    while received < MAX_DATAGRAMS_PER_POLL {
+        // Stop at the first error, which is usually `WouldBlock` once the socket has been
+        // drained.
+        let (len, client) = match state.socket.recv_from(&mut read_buf) {
+            Ok(res) => res,
+            Err(_) => break,
+        };
+        received += 1;
+        let datagram = &read_buf[..len];
+        if let Some((channel, data)) = stun::decode_channel_data(datagram) {
+            relay_channel_data(&state, client, channel, data);
+            continue;
+        }
+        let decoded = match StunMessage::decode(datagram) {
+            Ok(decoded) => decoded,
+            Err(_) => continue,
+        };
+        if let Some(response) = handle_message(&state, client, &decoded, &runtime) {
+            let _ = state.socket.send_to(&response, client);
+        }
+    }
+    let cloned_runtime = runtime.clone();
+    if received >= MAX_DATAGRAMS_PER_POLL {
+        runtime.spawn(move || serve(state, stop_flag, read_buf, cloned_runtime));
+        return;
+    }
+    let next_expiry = unwrap_result!(state.allocations.lock()).values().map(|a| a.expires).min();
+    let sockets = Sockets::new(&[&*state.socket]).or_stopped(&stop_flag);
+    runtime.when_readable(sockets, next_expiry, move || {
+        serve(state, stop_flag, read_buf, cloned_runtime);
+    });
+}
+
+fn expire_allocations(allocations: &Allocations, accounts: &Accounts) {
+    let now = Instant::now();
+    unwrap_result!(accounts.out_of_quota.lock()).retain(|_, expires| *expires > now);
+    let mut allocations = unwrap_result!(allocations.lock());
+    let expired: Vec<net::SocketAddr> = allocations.iter()
+                                                   .filter(|&(_, a)| a.expires <= now)
+                                                   .map(|(client, _)| *client)
+                                                   .collect();
+    for client in expired {
+        let _ = allocations.remove(&client);
+    }
+    for allocation in allocations.values_mut() {
+        let permissions = allocation.permissions
+                                    .drain()
+                                    .filter(|&(_, expires)| expires > now)
+                                    .collect();
+        allocation.permissions = permissions;
+        let channels = allocation.channels
+                                 .drain()
+                                 .filter(|&(_, (_, expires))| expires > now)
+                                 .collect();
+        allocation.channels = channels;
+    }
+}
+
+// Delete the client's allocation now that it can't relay any more, rather than going on taking
+// data only to drop it, and remember why so that its next request is answered with 486.
+fn end_for_quota(allocations: &mut HashMap<net::SocketAddr, Allocation>,
+                 accounts: &Accounts,
+                 client: net::SocketAddr) {
+    if let Some(allocation) = allocations.remove(&client) {
+        let _ = unwrap_result!(accounts.out_of_quota.lock()).insert(client, allocation.expires);
+    }
+}
+
+fn relay_channel_data(state: &ServerState, client: net::SocketAddr, channel: u16, data: &[u8]) {
+    let now = Instant::now();
+    let mut allocations = unwrap_result!(state.allocations.lock());
+    let over_quota = match allocations.get_mut(&client) {
+        Some(allocation) => {
+            match allocation.channel_peer(channel, now) {
+                Some(peer) if allocation.permitted(&peer.ip(), now) => {
+                    if allocation.charge(&state.accounts, data.len()) {
+                        let _ = allocation.relay.send_to(data, peer);
+                        false
+                    } else {
+                        true
+                    }
+                },
+                _ => false,
+            }
+        },
+        None => false,
+    };
+    if over_quota {
+        end_for_quota(&mut allocations, &state.accounts, client);
+    }
+}
+
+// Everything needed to relay from an allocation's relayed address to its client, from one wakeup
+// to the next.
+struct Relay {
+    relay: Arc<UdpSocket>,
+    stop_flag: Arc<AtomicBool>,
+    relayed_addr: net::SocketAddr,
+    client: net::SocketAddr,
+    socket: Arc<UdpSocket>,
+    allocations: Allocations,
+    accounts: Arc<Accounts>,
+    read_buf: Vec<u8>,
+}
+
+// Forward the datagrams waiting on an allocation's relay socket to its client then wait for more.
+// Once the allocation goes away the relay socket is dropped instead.
+fn relay_from_peers(mut relay: Relay, runtime: RuntimeHandle) {
+    let mut received = 0;
+    while received < MAX_DATAGRAMS_PER_POLL {
+        let res = relay.relay.recv_from(&mut relay.read_buf);
+        let datagram = {
+            let mut allocations = unwrap_result!(relay.allocations.lock());
+            let charged = {
+                let allocation = match allocations.get_mut(&relay.client) {
+                    Some(allocation) if allocation.relayed_addr == relay.relayed_addr => {
+                        allocation
+                    },
+                    _ => return,
+                };
+                // Stop at the first error, which is usually `WouldBlock` once the socket has
+                // been drained.
+                let (len, peer) = match res {
+                    Ok(res) => res,
+                    Err(_) => break,
+                };
+                received += 1;
+                let now = Instant::now();
+                if !allocation.permitted(&peer.ip(), now) {
+                    continue;
+                }
+                if allocation.charge(&relay.accounts, len) {
+                    Some((len, peer, allocation.peer_channel(&peer, now)))
+                } else {
+                    None
+                }
+            };
+            let (len, peer, channel) = match charged {
+                Some(charged) => charged,
+                None => {
+                    end_for_quota(&mut allocations, &relay.accounts, relay.client);
+                    return;
+                },
+            };
+            let data = &relay.read_buf[..len];
+            match channel {
+                Some(channel) => stun::encode_channel_data(channel, data),
+                None => {
+                    let mut indication = StunMessage::new(StunClass::Indication,
+                                                          stun::METHOD_DATA,
+                                                          rand::random());
+                    indication.attributes.push(StunAttribute::XorPeerAddress(peer));
+                    indication.attributes.push(StunAttribute::Data(data.to_vec()));
+                    indication.encode(None, false)
+                },
+            }
+        };
+        let _ = relay.socket.send_to(&datagram, relay.client);
+    }
+    let cloned_runtime = runtime.clone();
+    if received >= MAX_DATAGRAMS_PER_POLL {
+        runtime.spawn(move || relay_from_peers(relay, cloned_runtime));
+        return;
+    }
+    let sockets = Sockets::new(&[&*relay.relay]).or_stopped(&relay.stop_flag);
+    runtime.when_readable(sockets, None, move || relay_from_peers(relay, cloned_runtime));
+}
+
+/// An error response to `request`.
+pub fn error_response(request: &StunMessage, code: u16, reason: &str) -> StunMessage {
+    let mut response = StunMessage::new(StunClass::ErrorResponse,
+                                        request.method,
+                                        request.transaction_id);
+    response.attributes.push(StunAttribute::ErrorCode {
+        code: code,
+        reason: reason.to_owned(),
+    });
+    response
+}
+
+fn handle_message(state: &ServerState,
+                  client: net::SocketAddr,
+                  decoded: &DecodedStunMessage,
+                  runtime: &RuntimeHandle)
+                  -> Option<Vec<u8>> {
+    let message = &decoded.message;
+    match (message.class, message.method) {
+        (StunClass::Request, stun::METHOD_BINDING) => {
+            let mut response = StunMessage::new(StunClass::SuccessResponse,
+                                                stun::METHOD_BINDING,
+                                                message.transaction_id);
+            response.attributes.push(StunAttribute::XorMappedAddress(client));
+            Some(response.encode(None, true))
+        },
+        (StunClass::Indication, stun::METHOD_SEND) => {
+            relay_send_indication(state, client, message);
+            None
+        },
+        (StunClass::Request, method) => {
+            let (username, key) = match authenticate(state, decoded) {
+                Ok(res) => res,
+                Err(response) => return Some(response),
+            };
+            let result = match method {
+                stun::METHOD_ALLOCATE => allocate(state, client, &username, message, runtime),
+                stun::METHOD_REFRESH => refresh(state, client, &username, message),
+                stun::METHOD_CREATE_PERMISSION => {
+                    create_permission(state, client, &username, message)
+                },
+                stun::METHOD_CHANNEL_BIND => channel_bind(state, client, &username, message),
+                _ => Err((400, "Bad Request")),
+            };
+            Some(signed_response(message, result, &key))
+        },
+        _ => None,
+    }
+}
+
+/// Encode the response to an authenticated request.
+pub fn signed_response(request: &StunMessage, result: HandlerResult, key: &[u8; 16]) -> Vec<u8> {
+    let response = match result {
+        Ok(attributes) => {
+            let mut response = StunMessage::new(StunClass::SuccessResponse,
+                                                request.method,
+                                                request.transaction_id);
+            response.attributes = attributes;
+            response
+        },
+        Err((code, reason)) => error_response(request, code, reason),
+    };
+    response.encode(Some(&key[..]), true)
+}
+
+/// Check a request's long-term credentials. Returns the username and the key to sign the
+/// response with, or the error response to send.
+pub fn authenticate(state: &ServerState, decoded: &DecodedStunMessage)
+                -> Result<(String, [u8; 16]), Vec<u8>> {
+    let message = &decoded.message;
+    let challenge = |code, reason| {
+        let mut response = error_response(message, code, reason);
+        response.attributes.push(StunAttribute::Realm(state.config.realm.clone()));
+        response.attributes.push(StunAttribute::Nonce(state.nonce.clone()));
+        response.encode(None, true)
+    };
+    let username = message.find(|attr| match *attr {
+        StunAttribute::Username(ref username) => Some(username.clone()),
+        _ => None,
+    });
+    let nonce = message.find(|attr| match *attr {
+        StunAttribute::Nonce(ref nonce) => Some(nonce.clone()),
+        _ => None,
+    });
+    let (username, nonce) = match (username, nonce) {
+        (Some(username), Some(nonce)) => (username, nonce),
+        _ => return Err(challenge(401, "Unauthorized")),
+    };
+    if !decoded.has_integrity() {
+        return Err(challenge(401, "Unauthorized"));
+    }
+    if nonce != state.nonce {
+        return Err(challenge(438, "Stale Nonce"));
+    }
+    let key = match state.config.users.get(&username) {
+        Some(password) => long_term_key(&username, &state.config.realm, password),
+        None => return Err(challenge(401, "Unauthorized")),
+    };
+    if !decoded.verify_integrity(&key) {
+        return Err(challenge(401, "Unauthorized"));
+    }
+    Ok((username, key))
+}
+
+/// The attributes of a success response or the code and reason of an error response.
+pub type HandlerResult = Result<Vec<StunAttribute>, (u16, &'static str)>;
+
+/// The lifetime a Refresh or Allocate request asks for, capped by the configuration.
+pub fn requested_lifetime(state: &ServerState, message: &StunMessage) -> Duration {
+    let requested = message.find(|attr| match *attr {
+        StunAttribute::Lifetime(secs) => Some(Duration::from_secs(secs as u64)),
+        _ => None,
+    });
+    cmp::min(requested.unwrap_or(Duration::from_secs(DEFAULT_LIFETIME_SECS)),
+             state.config.max_lifetime)
+}
+
+fn allocate(state: &ServerState,
+            client: net::SocketAddr,
+            username: &str,
+            message: &StunMessage,
+            runtime: &RuntimeHandle)
+            -> HandlerResult {
+    let mut allocations = unwrap_result!(state.allocations.lock());
+    if allocations.contains_key(&client) {
+        return Err((437, "Allocation Mismatch"));
+    }
+    let transport = message.find(|attr| match *attr {
+        StunAttribute::RequestedTransport(protocol) => Some(protocol),
+        _ => None,
+    });
+    match transport {
+        Some(TRANSPORT_UDP) => (),
+        Some(_) => return Err((442, "Unsupported Transport Protocol")),
+        None => return Err((400, "Bad Request")),
+    }
+    let user_allocations = allocations.values().filter(|a| a.username == username).count();
+    let (tcp_allocations, tcp_user_allocations) = turn_tcp::allocation_counts(&state.tcp, username);
+    if allocations.len() + tcp_allocations >= state.config.max_allocations ||
+       user_allocations + tcp_user_allocations >= state.config.max_allocations_per_user ||
+       state.accounts.exhausted(username) {
+        return Err((486, "Allocation Quota Reached"));
+    }
+
+    let relay = match UdpSocket::bind(net::SocketAddr::new(state.config.relay_ip, 0)) {
+        Ok(relay) => relay,
+        Err(_) => return Err((508, "Insufficient Capacity")),
+    };
+    let relayed_addr = match relay.local_addr() {
+        Ok(relayed_addr) => relayed_addr,
+        Err(_) => return Err((508, "Insufficient Capacity")),
+    };
+    if relay.set_nonblocking(true).is_err() {
+        return Err((508, "Insufficient Capacity"));
+    }
+    let relay = Arc::new(relay);
+    let relay_stop_flag = Arc::new(AtomicBool::new(false));
+    let created = Instant::now();
+    let lifetime = capped_lifetime(state, created, requested_lifetime(state, message));
+    let _ = allocations.insert(client, Allocation {
+        username: username.to_owned(),
+        relay: relay.clone(),
+        relayed_addr: relayed_addr,
+        created: created,
+        expires: created + lifetime,
+        bytes_relayed: 0,
+        permissions: HashMap::new(),
+        channels: HashMap::new(),
+        relay_stop_flag: relay_stop_flag.clone(),
+        runtime: runtime.clone(),
+    });
+    let _ = unwrap_result!(state.accounts.out_of_quota.lock()).remove(&client);
+
+    let relay = Relay {
+        relay: relay,
+        stop_flag: relay_stop_flag,
+        relayed_addr: relayed_addr,
+        client: client,
+        socket: state.socket.clone(),
+        allocations: state.allocations.clone(),
+        accounts: state.accounts.clone(),
+        read_buf: vec![0u8; MAX_DATAGRAM_SIZE],
+    };
+    let cloned_runtime = runtime.clone();
+    runtime.spawn(move || relay_from_peers(relay, cloned_runtime));
+    Ok(vec![StunAttribute::XorRelayedAddress(relayed_addr),
+            StunAttribute::Lifetime(lifetime.as_secs() as u32),
+            StunAttribute::XorMappedAddress(client)])
+}
+
+// The client's allocation. Requests about an allocation must use the credentials it was made
+// with, as in section 4 of RFC 5766, so that a user can't touch another user's allocation from
+// the same address. A client whose allocation went over quota hears so once.
+fn allocation_of<'a>(allocations: &'a mut HashMap<net::SocketAddr, Allocation>,
+                     accounts: &Accounts,
+                     client: net::SocketAddr,
+                     username: &str)
+                     -> Result<&'a mut Allocation, (u16, &'static str)> {
+    match allocations.get_mut(&client) {
+        Some(allocation) => {
+            if allocation.username != username {
+                return Err((441, "Wrong Credentials"));
+            }
+            Ok(allocation)
+        },
+        None => {
+            match unwrap_result!(accounts.out_of_quota.lock()).remove(&client) {
+                Some(_) => Err((486, "Allocation Quota Reached")),
+                None => Err((437, "Allocation Mismatch")),
+            }
+        },
+    }
+}
+
+fn refresh(state: &ServerState,
+           client: net::SocketAddr,
+           username: &str,
+           message: &StunMessage)
+           -> HandlerResult {
+    let mut allocations = unwrap_result!(state.allocations.lock());
+    let (created, exhausted) = {
+        let allocation = try!(allocation_of(&mut allocations, &state.accounts, client, username));
+        let exhausted = match state.config.max_bytes_per_allocation {
+            Some(max_bytes) => allocation.bytes_relayed >= max_bytes,
+            None => false,
+        };
+        (allocation.created, exhausted || state.accounts.exhausted(&allocation.username))
+    };
+    let lifetime = capped_lifetime(state, created, requested_lifetime(state, message));
+    // An allocation which can't relay any more is deleted, so that the client hears why.
+    if exhausted {
+        let _ = allocations.remove(&client);
+        return Err((486, "Allocation Quota Reached"));
+    }
+    if lifetime.as_secs() == 0 {
+        let _ = allocations.remove(&client);
+    } else if let Some(allocation) = allocations.get_mut(&client) {
+        allocation.expires = Instant::now() + lifetime;
+    }
+    Ok(vec![StunAttribute::Lifetime(lifetime.as_secs() as u32)])
+}
+
+fn create_permission(state: &ServerState,
+                     client: net::SocketAddr,
+                     username: &str,
+                     message: &StunMessage)
+                     -> HandlerResult {
+    let mut allocations = unwrap_result!(state.allocations.lock());
+    let allocation = try!(allocation_of(&mut allocations, &state.accounts, client, username));
+    let peers: Vec<net::SocketAddr> = message.attributes
+                                             .iter()
+                                             .filter_map(|attr| match *attr {
+                                                 StunAttribute::XorPeerAddress(addr) => Some(addr),
+                                                 _ => None,
+                                             })
+                                             .collect();
+    if peers.is_empty() {
+        return Err((400, "Bad Request"));
+    }
+    let expires = Instant::now() + Duration::from_secs(PERMISSION_LIFETIME_SECS);
+    for peer in peers {
+        let _ = allocation.permissions.insert(peer.ip(), expires);
+    }
+    Ok(Vec::new())
+}
+
+fn channel_bind(state: &ServerState,
+                client: net::SocketAddr,
+                username: &str,
+                message: &StunMessage)
+                -> HandlerResult {
+    let mut allocations = unwrap_result!(state.allocations.lock());
+    let allocation = try!(allocation_of(&mut allocations, &state.accounts, client, username));
+    let channel = message.find(|attr| match *attr {
+        StunAttribute::ChannelNumber(channel) => Some(channel),
+        _ => None,
+    });
+    let peer = message.find(|attr| match *attr {
+        StunAttribute::XorPeerAddress(addr) => Some(addr),
+        _ => None,
+    });
+    let (channel, peer) = match (channel, peer) {
+        (Some(channel), Some(peer)) if channel >= FIRST_CHANNEL && channel <= LAST_CHANNEL => {
+            (channel, peer)
+        },
+        _ => return Err((400, "Bad Request")),
+    };
+    // A channel can't be rebound to a different peer, nor a peer to a different channel, while
+    // the binding lasts.
+    let now = Instant::now();
+    let channel_conflict = match allocation.channel_peer(channel, now) {
+        Some(bound_peer) => bound_peer != peer,
+        None => false,
+    };
+    let peer_conflict = match allocation.peer_channel(&peer, now) {
+        Some(bound_channel) => bound_channel != channel,
+        None => false,
+    };
+    if channel_conflict || peer_conflict {
+        return Err((400, "Bad Request"));
+    }
+    let _ = allocation.channels
+                      .insert(channel, (peer, now + Duration::from_secs(CHANNEL_LIFETIME_SECS)));
+    let _ = allocation.permissions
+                      .insert(peer.ip(), now + Duration::from_secs(PERMISSION_LIFETIME_SECS));
+    Ok(Vec::new())
+}
+
+fn relay_send_indication(state: &ServerState, client: net::SocketAddr, message: &StunMessage) {
+    let peer = message.find(|attr| match *attr {
+        StunAttribute::XorPeerAddress(addr) => Some(addr),
+        _ => None,
+    });
+    let data = message.find(|attr| match *attr {
+        StunAttribute::Data(ref data) => Some(data.clone()),
+        _ => None,
+    });
+    if let (Some(peer), Some(data)) = (peer, data) {
+        let mut allocations = unwrap_result!(state.allocations.lock());
+        let over_quota = match allocations.get_mut(&client) {
+            Some(allocation) if allocation.permitted(&peer.ip(), Instant::now()) => {
+                if allocation.charge(&state.accounts, data.len()) {
+                    let _ = allocation.relay.send_to(&data, peer);
+                    false
+                } else {
+                    true
+                }
+            },
+            _ => false,
+        };
+        if over_quota {
+            end_for_quota(&mut allocations, &state.accounts, client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::transaction;
+
+    use std::net;
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use mapping_context::MappingContext;
+    use rand;
+    use stun;
+    use stun::{StunClass, StunMessage};
+    use transport::DatagramTransport;
+
+    // The server is served on the context's runtime, so the context is returned too.
+    fn start_server(max_allocations_per_user: usize) -> (MappingContext, TurnServer) {
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        let mut config = TurnServerConfig::new(unwrap_result!("127.0.0.1:0".parse()),
+                                               "example.org".to_owned());
+        config.add_user("alice".to_owned(), "hunter2".to_owned());
+        config.max_allocations_per_user = max_allocations_per_user;
+        let server = unwrap_result!(TurnServer::new(&mc, config));
+        (mc, server)
+    }
+
+    fn credentials(password: &str) -> TurnCredentials {
+        TurnCredentials {
+            username: "alice".to_owned(),
+            password: password.to_owned(),
+        }
+    }
+
+    fn recv(socket: &UdpSocket) -> (Vec<u8>, net::SocketAddr) {
+        let mut buf = [0u8; 64];
+        let (len, from) = unwrap_result!(socket.recv_from(&mut buf));
+        (buf[..len].to_vec(), from)
+    }
+
+    #[test]
+    fn relays_through_indications_and_channels() {
+        let (_mc, server) = start_server(10);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let mut allocation = unwrap_result!(TurnAllocation::allocate(socket,
+                                                                     &server.local_addr(),
+                                                                     &credentials("hunter2"),
+                                                                     deadline));
+        let relayed_addr = allocation.relayed_addr();
+        assert_eq!(unwrap_result!(allocation.local_addr()), relayed_addr);
+
+        let peer = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        unwrap_result!(peer.set_read_timeout(Some(Duration::from_secs(5))));
+        let peer_addr = unwrap_result!(peer.local_addr());
+        unwrap_result!(allocation.create_permission(&peer_addr, deadline));
+
+        assert_eq!(unwrap_result!(allocation.send_to(b"hello", &peer_addr)), 5);
+        assert_eq!(recv(&peer), (b"hello".to_vec(), relayed_addr));
+        let _ = unwrap_result!(peer.send_to(b"world", relayed_addr));
+        let mut buf = [0u8; 64];
+        match unwrap_result!(allocation.recv_until(&mut buf, deadline)) {
+            Some((len, from)) => {
+                assert_eq!(&buf[..len], b"world");
+                assert_eq!(*from, peer_addr);
+            },
+            None => panic!("Timed out waiting for a relayed datagram"),
+        }
+
+        let channel = unwrap_result!(allocation.bind_channel(&peer_addr, deadline));
+        assert_eq!(channel, 0x4000);
+        assert_eq!(unwrap_result!(allocation.send_to(b"over a channel", &peer_addr)), 14);
+        assert_eq!(recv(&peer), (b"over a channel".to_vec(), relayed_addr));
+        let _ = unwrap_result!(peer.send_to(b"and back", relayed_addr));
+        match unwrap_result!(allocation.recv_until(&mut buf, deadline)) {
+            Some((len, from)) => {
+                assert_eq!(&buf[..len], b"and back");
+                assert_eq!(*from, peer_addr);
+            },
+            None => panic!("Timed out waiting for a relayed datagram"),
+        }
+    }
+
+    #[test]
+    fn enforces_credentials_and_quotas() {
+        let (_mc, server) = start_server(1);
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let server_addr = server.local_addr();
+        match TurnAllocation::allocate(socket, &server_addr, &credentials("wrong"), deadline) {
+            Err(TurnError::Rejected { code: 401, .. }) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|a| a.relayed_addr())),
+        }
+
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let _allocation = unwrap_result!(TurnAllocation::allocate(socket,
+                                                                  &server_addr,
+                                                                  &credentials("hunter2"),
+                                                                  deadline));
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        match TurnAllocation::allocate(socket, &server_addr, &credentials("hunter2"), deadline) {
+            Err(TurnError::Rejected { code: 486, .. }) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|a| a.relayed_addr())),
+        }
+    }
+
+    #[test]
+    fn rejects_requests_with_another_users_credentials() {
+        let mut config = TurnServerConfig::new(unwrap_result!("127.0.0.1:0".parse()),
+                                               "example.org".to_owned());
+        config.add_user("alice".to_owned(), "hunter2".to_owned());
+        config.add_user("mallory".to_owned(), "letmein".to_owned());
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        let server = unwrap_result!(TurnServer::new(&mc, config));
+        let server_addr = server.local_addr();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let mallory_socket = unwrap_result!(socket.try_clone());
+        let _allocation = unwrap_result!(TurnAllocation::allocate(socket,
+                                                                  &server_addr,
+                                                                  &credentials("hunter2"),
+                                                                  deadline));
+
+        // Mallory sends from the address of alice's allocation, but with her own credentials.
+        let request = StunMessage::new(StunClass::Request, stun::METHOD_REFRESH, rand::random());
+        let challenge = unwrap_result!(transaction(&mallory_socket,
+                                                   &server_addr,
+                                                   &request.encode(None, true),
+                                                   &request.transaction_id,
+                                                   deadline));
+        let mallory = TurnCredentials {
+            username: "mallory".to_owned(),
+            password: "letmein".to_owned(),
+        };
+        let auth = unwrap_result!(LongTermAuth::from_challenge(&challenge.message, &mallory));
+        for &method in &[stun::METHOD_REFRESH,
+                         stun::METHOD_CREATE_PERMISSION,
+                         stun::METHOD_CHANNEL_BIND] {
+            let (request, transaction_id) = auth.request(method, Vec::new());
+            let response = unwrap_result!(transaction(&mallory_socket,
+                                                      &server_addr,
+                                                      &request,
+                                                      &transaction_id,
+                                                      deadline));
+            assert_eq!(error_code(&response.message).0, 441);
+        }
+        assert_eq!(server.usage().get("alice").map(|usage| usage.allocations), Some(1));
+    }
+
+    #[test]
+    fn stops_relaying_once_the_byte_quota_is_used() {
+        let mut config = TurnServerConfig::new(unwrap_result!("127.0.0.1:0".parse()),
+                                               "example.org".to_owned());
+        config.add_user("alice".to_owned(), "hunter2".to_owned());
+        config.max_bytes_per_user = Some(10);
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        let server = unwrap_result!(TurnServer::new(&mc, config));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let mut allocation = unwrap_result!(TurnAllocation::allocate(socket,
+                                                                     &server.local_addr(),
+                                                                     &credentials("hunter2"),
+                                                                     deadline));
+        let relayed_addr = allocation.relayed_addr();
+        let peer = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        unwrap_result!(peer.set_read_timeout(Some(Duration::from_secs(5))));
+        let peer_addr = unwrap_result!(peer.local_addr());
+        unwrap_result!(allocation.create_permission(&peer_addr, deadline));
+
+        let _ = unwrap_result!(allocation.send_to(b"hello", &peer_addr));
+        assert_eq!(recv(&peer), (b"hello".to_vec(), relayed_addr));
+        let _ = unwrap_result!(peer.send_to(b"world", relayed_addr));
+        let mut buf = [0u8; 64];
+        assert!(unwrap_result!(allocation.recv_until(&mut buf, deadline)).is_some());
+
+        let usage = server.usage();
+        assert_eq!(usage.get("alice"),
+                   Some(&TurnUsage {
+                       bytes_relayed: 10,
+                       allocations: 1,
+                   }));
+        match allocation.refresh(deadline) {
+            Err(TurnError::Rejected { code: 486, .. }) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn ends_the_allocation_once_it_goes_over_quota() {
+        let mut config = TurnServerConfig::new(unwrap_result!("127.0.0.1:0".parse()),
+                                               "example.org".to_owned());
+        config.add_user("alice".to_owned(), "hunter2".to_owned());
+        config.max_bytes_per_allocation = Some(8);
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        let server = unwrap_result!(TurnServer::new(&mc, config));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let mut allocation = unwrap_result!(TurnAllocation::allocate(socket,
+                                                                     &server.local_addr(),
+                                                                     &credentials("hunter2"),
+                                                                     deadline));
+        let peer = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        unwrap_result!(peer.set_read_timeout(Some(Duration::from_secs(5))));
+        let peer_addr = unwrap_result!(peer.local_addr());
+        unwrap_result!(allocation.create_permission(&peer_addr, deadline));
+
+        let _ = unwrap_result!(allocation.send_to(b"hello", &peer_addr));
+        let _ = unwrap_result!(allocation.send_to(b"world", &peer_addr));
+        assert_eq!(recv(&peer).0, b"hello".to_vec());
+        // The second send went over quota, so the allocation is gone and the client told why.
+        match allocation.refresh(deadline) {
+            Err(TurnError::Rejected { code: 486, .. }) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(server.usage().get("alice").map(|usage| usage.allocations), Some(0));
+    }
+}
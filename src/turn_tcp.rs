@@ -0,0 +1,996 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::net;
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+use rand;
+
+use runtime::{RuntimeHandle, Sockets};
+use stun;
+use stun::{DecodedStunMessage, StunAttribute, StunClass, StunMessage};
+use turn;
+use turn::{Accounts, HandlerResult, LongTermAuth, ServerState, TurnCredentials, TurnError};
+
+/// The IP protocol number of tcp.
+const TRANSPORT_TCP: u8 = 6;
+/// How long a peer connection waits for the client to bind it, from RFC 6062.
+const CONNECTION_BIND_TIMEOUT_SECS: u64 = 30;
+/// How long a write to a client's control connection may block. Connection attempts are announced
+/// from the runtime, so a client which stops reading mustn't hold up a worker for long. It's
+/// disconnected instead.
+const CONTROL_WRITE_TIMEOUT_MS: u64 = 1000;
+const STUN_HEADER_SIZE: usize = 20;
+
+// Splits a stream into STUN messages, which are framed by their own length fields. Bytes are
+// buffered across reads so a timeout part way through a message doesn't lose our place.
+#[derive(Default)]
+struct StunFramer {
+    buf: Vec<u8>,
+}
+
+impl StunFramer {
+    fn next_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.buf.len() < STUN_HEADER_SIZE {
+            return Ok(None);
+        }
+        if !stun::is_stun(&self.buf) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected a STUN message"));
+        }
+        let len = STUN_HEADER_SIZE + BigEndian::read_u16(&self.buf[2..4]) as usize;
+        if self.buf.len() < len {
+            return Ok(None);
+        }
+        let rest = self.buf.split_off(len);
+        Ok(Some(mem::replace(&mut self.buf, rest)))
+    }
+
+    // Read until a whole message has arrived or `deadline` passes.
+    fn read_message(&mut self, stream: &mut TcpStream, deadline: Instant)
+                    -> io::Result<Option<Vec<u8>>> {
+        let mut read_buf = [0u8; 4096];
+        loop {
+            if let Some(message) = try!(self.next_message()) {
+                return Ok(Some(message));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            try!(stream.set_read_timeout(Some(deadline - now)));
+            match stream.read(&mut read_buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              "The connection was closed"));
+                },
+                Ok(n) => self.buf.extend(&read_buf[..n]),
+                Err(e) => {
+                    match e.kind() {
+                        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => return Ok(None),
+                        io::ErrorKind::Interrupted => (),
+                        _ => return Err(e),
+                    }
+                },
+            }
+        }
+    }
+}
+
+fn io_error(err: io::Error) -> TurnError {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => TurnError::TimedOut,
+        _ => TurnError::Io { err: err },
+    }
+}
+
+// Send a request over a control connection and wait for its response. ConnectionAttempt
+// indications which arrive in the meantime are queued in `attempts`.
+fn transaction(stream: &mut TcpStream,
+               framer: &mut StunFramer,
+               attempts: &mut VecDeque<(u32, net::SocketAddr)>,
+               request: &[u8],
+               transaction_id: &[u8; 12],
+               deadline: Instant)
+               -> Result<DecodedStunMessage, TurnError> {
+    if let Err(e) = stream.write_all(request) {
+        return Err(io_error(e));
+    }
+    loop {
+        let message = match framer.read_message(stream, deadline) {
+            Ok(Some(message)) => message,
+            Ok(None) => return Err(TurnError::TimedOut),
+            Err(e) => return Err(io_error(e)),
+        };
+        let decoded = match StunMessage::decode(&message) {
+            Ok(decoded) => decoded,
+            Err(_) => return Err(TurnError::BadResponse),
+        };
+        match decoded.message.class {
+            StunClass::SuccessResponse | StunClass::ErrorResponse => {
+                if decoded.message.transaction_id == *transaction_id {
+                    return Ok(decoded);
+                }
+            },
+            StunClass::Indication => {
+                if let Some(attempt) = connection_attempt(&decoded.message) {
+                    attempts.push_back(attempt);
+                }
+            },
+            StunClass::Request => (),
+        }
+    }
+}
+
+fn connection_attempt(message: &StunMessage) -> Option<(u32, net::SocketAddr)> {
+    if message.method != stun::METHOD_CONNECTION_ATTEMPT {
+        return None;
+    }
+    let connection_id = message.find(|attr| match *attr {
+        StunAttribute::ConnectionId(connection_id) => Some(connection_id),
+        _ => None,
+    });
+    let peer = message.find(|attr| match *attr {
+        StunAttribute::XorPeerAddress(addr) => Some(addr),
+        _ => None,
+    });
+    match (connection_id, peer) {
+        (Some(connection_id), Some(peer)) => Some((connection_id, peer)),
+        _ => None,
+    }
+}
+
+/// A tcp allocation on a TURN server (RFC 6062). Connections to and from peers are relayed
+/// through the allocation's relayed address and handed back as ordinary `TcpStream`s.
+///
+/// The allocation lasts until it expires or this is dropped, closing the control connection.
+/// Relayed connections outlive it.
+pub struct TurnTcpAllocation {
+    control: TcpStream,
+    framer: StunFramer,
+    server: net::SocketAddr,
+    auth: LongTermAuth,
+    relayed_addr: net::SocketAddr,
+    mapped_addr: net::SocketAddr,
+    lifetime: Duration,
+    attempts: VecDeque<(u32, net::SocketAddr)>,
+}
+
+impl fmt::Debug for TurnTcpAllocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TurnTcpAllocation")
+         .field("server", &self.server)
+         .field("relayed_addr", &self.relayed_addr)
+         .field("mapped_addr", &self.mapped_addr)
+         .field("lifetime", &self.lifetime)
+         .finish()
+    }
+}
+
+impl TurnTcpAllocation {
+    /// Connect to the TURN server at `server` and allocate a relayed tcp address.
+    pub fn allocate(server: &net::SocketAddr,
+                    credentials: &TurnCredentials,
+                    deadline: Instant)
+                    -> Result<TurnTcpAllocation, TurnError>
+    {
+        let mut control = match TcpStream::connect(server) {
+            Ok(control) => control,
+            Err(e) => return Err(io_error(e)),
+        };
+        let mut framer = StunFramer::default();
+        let mut attempts = VecDeque::new();
+        let mut request = StunMessage::new(StunClass::Request,
+                                           stun::METHOD_ALLOCATE,
+                                           rand::random());
+        request.attributes.push(StunAttribute::RequestedTransport(TRANSPORT_TCP));
+        let challenge = try!(transaction(&mut control,
+                                         &mut framer,
+                                         &mut attempts,
+                                         &request.encode(None, true),
+                                         &request.transaction_id,
+                                         deadline)).message;
+
+        let mut allocation = TurnTcpAllocation {
+            auth: try!(LongTermAuth::from_challenge(&challenge, credentials)),
+            control: control,
+            framer: framer,
+            server: *server,
+            relayed_addr: *server,
+            mapped_addr: *server,
+            lifetime: Duration::from_secs(0),
+            attempts: attempts,
+        };
+        let attributes = vec![StunAttribute::RequestedTransport(TRANSPORT_TCP)];
+        let response = try!(allocation.request(stun::METHOD_ALLOCATE, attributes, deadline));
+        let (relayed_addr, mapped_addr) = try!(turn::allocated_addrs(&response));
+        allocation.relayed_addr = relayed_addr;
+        allocation.mapped_addr = mapped_addr;
+        allocation.lifetime = turn::lifetime_of(&response);
+        Ok(allocation)
+    }
+
+    /// The address on the server which peers connect to in order to reach us.
+    pub fn relayed_addr(&self) -> net::SocketAddr {
+        self.relayed_addr
+    }
+
+    /// Our address as seen by the server.
+    pub fn mapped_addr(&self) -> net::SocketAddr {
+        self.mapped_addr
+    }
+
+    /// How long the allocation lasts from when it was made or last refreshed.
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Extend the allocation's lifetime.
+    pub fn refresh(&mut self, deadline: Instant) -> Result<(), TurnError> {
+        let lifetime = turn::DEFAULT_LIFETIME_SECS as u32;
+        let response = try!(self.request(stun::METHOD_REFRESH,
+                                         vec![StunAttribute::Lifetime(lifetime)],
+                                         deadline));
+        self.lifetime = turn::lifetime_of(&response);
+        Ok(())
+    }
+
+    /// Allow connections to and from `peer`'s ip address to be relayed. Permissions last five
+    /// minutes and need to be renewed by calling this again.
+    pub fn create_permission(&mut self, peer: &net::SocketAddr, deadline: Instant)
+                             -> Result<(), TurnError>
+    {
+        let _ = try!(self.request(stun::METHOD_CREATE_PERMISSION,
+                                  vec![StunAttribute::XorPeerAddress(*peer)],
+                                  deadline));
+        Ok(())
+    }
+
+    /// Have the server connect to `peer`, which needs a permission, and return a stream
+    /// relayed to it.
+    pub fn connect(&mut self, peer: &net::SocketAddr, deadline: Instant)
+                   -> Result<TcpStream, TurnError>
+    {
+        let response = try!(self.request(stun::METHOD_CONNECT,
+                                         vec![StunAttribute::XorPeerAddress(*peer)],
+                                         deadline));
+        let connection_id = response.find(|attr| match *attr {
+            StunAttribute::ConnectionId(connection_id) => Some(connection_id),
+            _ => None,
+        });
+        match connection_id {
+            Some(connection_id) => self.bind_connection(connection_id, deadline),
+            None => Err(TurnError::BadResponse),
+        }
+    }
+
+    /// Wait for a permitted peer to connect to the relayed address. Returns a stream relayed to
+    /// the peer and the peer's address.
+    pub fn accept(&mut self, deadline: Instant)
+                  -> Result<(TcpStream, net::SocketAddr), TurnError>
+    {
+        loop {
+            if let Some((connection_id, peer)) = self.attempts.pop_front() {
+                let stream = try!(self.bind_connection(connection_id, deadline));
+                return Ok((stream, peer));
+            }
+            let message = match self.framer.read_message(&mut self.control, deadline) {
+                Ok(Some(message)) => message,
+                Ok(None) => return Err(TurnError::TimedOut),
+                Err(e) => return Err(io_error(e)),
+            };
+            if let Ok(decoded) = StunMessage::decode(&message) {
+                if decoded.message.class == StunClass::Indication {
+                    if let Some(attempt) = connection_attempt(&decoded.message) {
+                        self.attempts.push_back(attempt);
+                    }
+                }
+            }
+        }
+    }
+
+    // Open a data connection to the server and bind it to the peer connection identified by
+    // `connection_id`. Once bound, the data connection carries the peer's bytes verbatim.
+    fn bind_connection(&mut self, connection_id: u32, deadline: Instant)
+                       -> Result<TcpStream, TurnError>
+    {
+        let mut stream = match TcpStream::connect(self.server) {
+            Ok(stream) => stream,
+            Err(e) => return Err(io_error(e)),
+        };
+        let mut retried = false;
+        loop {
+            let attributes = vec![StunAttribute::ConnectionId(connection_id)];
+            let (request, transaction_id) = self.auth.request(stun::METHOD_CONNECTION_BIND,
+                                                              attributes);
+            if let Err(e) = stream.write_all(&request) {
+                return Err(io_error(e));
+            }
+            // Read exactly one message. Anything after it belongs to the peer.
+            let response = match read_one_message(&mut stream, deadline) {
+                Ok(response) => response,
+                Err(e) => return Err(io_error(e)),
+            };
+            let decoded = match StunMessage::decode(&response) {
+                Ok(decoded) => decoded,
+                Err(_) => return Err(TurnError::BadResponse),
+            };
+            if decoded.message.transaction_id != transaction_id {
+                return Err(TurnError::BadResponse);
+            }
+            match try!(self.auth.check_response(&decoded, retried)) {
+                Some(_) => {
+                    if let Err(e) = stream.set_read_timeout(None) {
+                        return Err(io_error(e));
+                    }
+                    return Ok(stream);
+                },
+                None => retried = true,
+            }
+        }
+    }
+
+    fn request(&mut self, method: u16, attributes: Vec<StunAttribute>, deadline: Instant)
+               -> Result<StunMessage, TurnError>
+    {
+        let mut retried = false;
+        loop {
+            let (request, transaction_id) = self.auth.request(method, attributes.clone());
+            let decoded = try!(transaction(&mut self.control,
+                                           &mut self.framer,
+                                           &mut self.attempts,
+                                           &request,
+                                           &transaction_id,
+                                           deadline));
+            match try!(self.auth.check_response(&decoded, retried)) {
+                Some(response) => return Ok(response),
+                None => retried = true,
+            }
+        }
+    }
+}
+
+fn read_exact_until(stream: &mut TcpStream, buf: &mut [u8], deadline: Instant) -> io::Result<()> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out"));
+    }
+    try!(stream.set_read_timeout(Some(deadline - now)));
+    stream.read_exact(buf)
+}
+
+fn read_one_message(stream: &mut TcpStream, deadline: Instant) -> io::Result<Vec<u8>> {
+    let mut message = vec![0u8; STUN_HEADER_SIZE];
+    try!(read_exact_until(stream, &mut message, deadline));
+    if !stun::is_stun(&message) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected a STUN message"));
+    }
+    let len = BigEndian::read_u16(&message[2..4]) as usize;
+    message.resize(STUN_HEADER_SIZE + len, 0);
+    try!(read_exact_until(stream, &mut message[STUN_HEADER_SIZE..], deadline));
+    Ok(message)
+}
+
+// A tcp allocation on the server, keyed by the address of the client's control connection.
+struct TcpAllocation {
+    username: String,
+    relayed_addr: net::SocketAddr,
+    created: Instant,
+    expires: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+    control: Arc<Mutex<TcpStream>>,
+    // Stops the job accepting peers on the relayed address once the allocation is removed.
+    acceptor_stop_flag: Arc<AtomicBool>,
+    runtime: RuntimeHandle,
+}
+
+impl Drop for TcpAllocation {
+    fn drop(&mut self) {
+        self.runtime.stop(&self.acceptor_stop_flag);
+    }
+}
+
+impl TcpAllocation {
+    fn permitted(&self, ip: &IpAddr, now: Instant) -> bool {
+        match self.permissions.get(ip) {
+            Some(expires) => *expires > now,
+            None => false,
+        }
+    }
+}
+
+// A connection to or from a peer waiting for the client to bind a data connection to it.
+struct PendingConnection {
+    username: String,
+    stream: TcpStream,
+    expires: Instant,
+}
+
+/// The server's tcp allocations and the peer connections waiting to be bound.
+pub struct TcpRelays {
+    allocations: Mutex<HashMap<net::SocketAddr, TcpAllocation>>,
+    pending: Mutex<HashMap<u32, PendingConnection>>,
+}
+
+impl TcpRelays {
+    /// No allocations.
+    pub fn new() -> TcpRelays {
+        TcpRelays {
+            allocations: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// The number of tcp allocations in total and held by `username`.
+pub fn allocation_counts(relays: &TcpRelays, username: &str) -> (usize, usize) {
+    let allocations = unwrap_result!(relays.allocations.lock());
+    let user_allocations = allocations.values().filter(|a| a.username == username).count();
+    (allocations.len(), user_allocations)
+}
+
+/// The users holding each tcp allocation.
+pub fn usernames(relays: &TcpRelays) -> Vec<String> {
+    let allocations = unwrap_result!(relays.allocations.lock());
+    allocations.values().map(|a| a.username.clone()).collect()
+}
+
+/// Start serving the tcp clients of a `TurnServer` waiting on its non-blocking listener, then wait
+/// for more. Once `stop_flag` is set the listener is dropped instead. Each client is served on a
+/// thread of its own since relayed connections block.
+pub fn serve(state: Arc<ServerState>,
+             listener: TcpListener,
+             stop_flag: Arc<AtomicBool>,
+             runtime: RuntimeHandle) {
+    if stop_flag.load(Ordering::SeqCst) {
+        return;
+    }
+    // Stop at the first error, which is usually `WouldBlock` once there's nothing to accept.
+    while let Ok((stream, _)) = listener.accept() {
+        // Accepted sockets inherit non-blocking mode on some platforms but not others.
+        if stream.set_nonblocking(false).is_err() {
+            continue;
+        }
+        let state = state.clone();
+        let cloned_runtime = runtime.clone();
+        let _ = thread!("TurnServerTcpClient", move || {
+            serve_connection(state, stream, cloned_runtime);
+        });
+    }
+    let sockets = Sockets::new(&[&listener]).or_stopped(&stop_flag);
+    let cloned_runtime = runtime.clone();
+    runtime.when_readable(sockets, None, move || {
+        serve(state, listener, stop_flag, cloned_runtime);
+    });
+}
+
+// Serve one connection from a client. This is either a control connection carrying Allocate and
+// the requests that follow it, or a data connection which is spliced to a peer once it's bound.
+fn serve_connection(state: Arc<ServerState>, mut stream: TcpStream, runtime: RuntimeHandle) {
+    let client = match stream.peer_addr() {
+        Ok(client) => client,
+        Err(_) => return,
+    };
+    if stream.set_write_timeout(Some(Duration::from_millis(CONTROL_WRITE_TIMEOUT_MS))).is_err() {
+        return;
+    }
+    let control = match stream.try_clone() {
+        Ok(control) => Arc::new(Mutex::new(control)),
+        Err(_) => return,
+    };
+    let mut framer = StunFramer::default();
+    loop {
+        let deadline = Instant::now() + Duration::from_millis(turn::SERVER_READ_TIMEOUT_MS);
+        let message = match framer.read_message(&mut stream, deadline) {
+            Ok(Some(message)) => message,
+            Ok(None) => {
+                if allocation_expired(&state, client) {
+                    break;
+                }
+                continue;
+            },
+            Err(_) => break,
+        };
+        let decoded = match StunMessage::decode(&message) {
+            Ok(decoded) => decoded,
+            Err(_) => break,
+        };
+        if decoded.message.class != StunClass::Request {
+            continue;
+        }
+        let (username, key) = match turn::authenticate(&state, &decoded) {
+            Ok(res) => res,
+            Err(response) => {
+                if unwrap_result!(control.lock()).write_all(&response).is_err() {
+                    break;
+                }
+                continue;
+            },
+        };
+        let request = &decoded.message;
+        if request.method == stun::METHOD_CONNECTION_BIND {
+            match connection_bind(&state, &username, request) {
+                Ok(peer_stream) => {
+                    let response = turn::signed_response(request, Ok(Vec::new()), &key);
+                    if unwrap_result!(control.lock()).write_all(&response).is_ok() {
+                        splice(stream, peer_stream, framer.buf, &state.accounts, username);
+                    }
+                    return;
+                },
+                Err(err) => {
+                    let response = turn::signed_response(request, Err(err), &key);
+                    if unwrap_result!(control.lock()).write_all(&response).is_err() {
+                        break;
+                    }
+                    continue;
+                },
+            }
+        }
+        let result = match request.method {
+            stun::METHOD_ALLOCATE => {
+                allocate(&state, client, &username, request, &control, &runtime)
+            },
+            stun::METHOD_REFRESH => refresh(&state, client, &username, request),
+            stun::METHOD_CREATE_PERMISSION => {
+                create_permission(&state, client, &username, request)
+            },
+            stun::METHOD_CONNECT => connect(&state, client, &username, request),
+            _ => Err((400, "Bad Request")),
+        };
+        let response = turn::signed_response(request, result, &key);
+        if unwrap_result!(control.lock()).write_all(&response).is_err() {
+            break;
+        }
+    }
+    // Closing the control connection deletes the allocation.
+    remove_allocation(&state, client);
+}
+
+fn allocation_expired(state: &ServerState, client: net::SocketAddr) -> bool {
+    let allocations = unwrap_result!(state.tcp.allocations.lock());
+    match allocations.get(&client) {
+        Some(allocation) => allocation.expires <= Instant::now(),
+        None => false,
+    }
+}
+
+// Removing the allocation stops the job accepting peers on its relayed address.
+fn remove_allocation(state: &ServerState, client: net::SocketAddr) {
+    let _ = unwrap_result!(state.tcp.allocations.lock()).remove(&client);
+}
+
+fn allocate(state: &Arc<ServerState>,
+            client: net::SocketAddr,
+            username: &str,
+            request: &StunMessage,
+            control: &Arc<Mutex<TcpStream>>,
+            runtime: &RuntimeHandle)
+            -> HandlerResult {
+    let transport = request.find(|attr| match *attr {
+        StunAttribute::RequestedTransport(protocol) => Some(protocol),
+        _ => None,
+    });
+    match transport {
+        Some(TRANSPORT_TCP) => (),
+        Some(_) => return Err((442, "Unsupported Transport Protocol")),
+        None => return Err((400, "Bad Request")),
+    }
+    // Check the limits and insert the allocation holding the udp half's lock as well as ours, so
+    // that allocations made over udp and tcp at once can't both slip under them. The locks are
+    // taken in the same order as the udp half takes them.
+    let allocated: Result<_, (u16, &'static str)> =
+        turn::with_allocation_counts(state, username, |udp_allocations, udp_user_allocations| {
+            let mut allocations = unwrap_result!(state.tcp.allocations.lock());
+            if allocations.contains_key(&client) {
+                return Err((437, "Allocation Mismatch"));
+            }
+            let user_allocations = allocations.values().filter(|a| a.username == username).count();
+            if udp_allocations + allocations.len() >= state.config.max_allocations ||
+               udp_user_allocations + user_allocations >= state.config.max_allocations_per_user ||
+               state.accounts.exhausted(username) {
+                return Err((486, "Allocation Quota Reached"));
+            }
+
+            let listener = match TcpListener::bind(net::SocketAddr::new(state.config.relay_ip, 0)) {
+                Ok(listener) => listener,
+                Err(_) => return Err((508, "Insufficient Capacity")),
+            };
+            let relayed_addr = match listener.local_addr() {
+                Ok(relayed_addr) => relayed_addr,
+                Err(_) => return Err((508, "Insufficient Capacity")),
+            };
+            if listener.set_nonblocking(true).is_err() {
+                return Err((508, "Insufficient Capacity"));
+            }
+            let acceptor_stop_flag = Arc::new(AtomicBool::new(false));
+            let created = Instant::now();
+            let requested = turn::requested_lifetime(state, request);
+            let lifetime = turn::capped_lifetime(state, created, requested);
+            let _ = allocations.insert(client, TcpAllocation {
+                username: username.to_owned(),
+                relayed_addr: relayed_addr,
+                created: created,
+                expires: created + lifetime,
+                permissions: HashMap::new(),
+                control: control.clone(),
+                acceptor_stop_flag: acceptor_stop_flag.clone(),
+                runtime: runtime.clone(),
+            });
+            Ok((listener, relayed_addr, lifetime, acceptor_stop_flag))
+        });
+    let (listener, relayed_addr, lifetime, acceptor_stop_flag) = try!(allocated);
+
+    let cloned_state = state.clone();
+    let cloned_runtime = runtime.clone();
+    runtime.spawn(move || {
+        accept_peers(cloned_state, listener, client, relayed_addr, acceptor_stop_flag,
+                     cloned_runtime);
+    });
+    Ok(vec![StunAttribute::XorRelayedAddress(relayed_addr),
+            StunAttribute::Lifetime(lifetime.as_secs() as u32),
+            StunAttribute::XorMappedAddress(client)])
+}
+
+// Accept the connections from peers waiting on an allocation's relayed address and tell its
+// client about them, then wait for more. Once the allocation goes away the listener is dropped
+// instead.
+fn accept_peers(state: Arc<ServerState>,
+                listener: TcpListener,
+                client: net::SocketAddr,
+                relayed_addr: net::SocketAddr,
+                stop_flag: Arc<AtomicBool>,
+                runtime: RuntimeHandle) {
+    if stop_flag.load(Ordering::SeqCst) {
+        return;
+    }
+    // Stop at the first error, which is usually `WouldBlock` once there's nothing to accept.
+    while let Ok((peer_stream, peer)) = listener.accept() {
+        // Accepted sockets inherit non-blocking mode on some platforms but not others.
+        if peer_stream.set_nonblocking(false).is_err() {
+            continue;
+        }
+        let (username, control) = {
+            let allocations = unwrap_result!(state.tcp.allocations.lock());
+            match allocations.get(&client) {
+                Some(allocation) if allocation.relayed_addr == relayed_addr => {
+                    if !allocation.permitted(&peer.ip(), Instant::now()) {
+                        continue;
+                    }
+                    (allocation.username.clone(), allocation.control.clone())
+                },
+                _ => return,
+            }
+        };
+        let connection_id = add_pending(&state, username, peer_stream);
+        let mut indication = StunMessage::new(StunClass::Indication,
+                                              stun::METHOD_CONNECTION_ATTEMPT,
+                                              rand::random());
+        indication.attributes.push(StunAttribute::ConnectionId(connection_id));
+        indication.attributes.push(StunAttribute::XorPeerAddress(peer));
+        let mut control = unwrap_result!(control.lock());
+        if control.write_all(&indication.encode(None, true)).is_err() {
+            // The client isn't reading. Closing the control connection deletes the allocation.
+            let _ = control.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+    let sockets = Sockets::new(&[&listener]).or_stopped(&stop_flag);
+    let cloned_runtime = runtime.clone();
+    runtime.when_readable(sockets, None, move || {
+        accept_peers(state, listener, client, relayed_addr, stop_flag, cloned_runtime);
+    });
+}
+
+fn add_pending(state: &ServerState, username: String, stream: TcpStream) -> u32 {
+    let now = Instant::now();
+    let mut pending = unwrap_result!(state.tcp.pending.lock());
+    let expired: Vec<u32> = pending.iter()
+                                   .filter(|&(_, p)| p.expires <= now)
+                                   .map(|(connection_id, _)| *connection_id)
+                                   .collect();
+    for connection_id in expired {
+        let _ = pending.remove(&connection_id);
+    }
+    let mut connection_id = rand::random();
+    while pending.contains_key(&connection_id) {
+        connection_id = rand::random();
+    }
+    let _ = pending.insert(connection_id, PendingConnection {
+        username: username,
+        stream: stream,
+        expires: now + Duration::from_secs(CONNECTION_BIND_TIMEOUT_SECS),
+    });
+    connection_id
+}
+
+// The client's allocation, which must have been made with the same credentials as the request,
+// as for udp allocations.
+fn allocation_of<'a>(allocations: &'a mut HashMap<net::SocketAddr, TcpAllocation>,
+                     client: net::SocketAddr,
+                     username: &str)
+                     -> Result<&'a mut TcpAllocation, (u16, &'static str)> {
+    match allocations.get_mut(&client) {
+        Some(allocation) => {
+            if allocation.username != username {
+                return Err((441, "Wrong Credentials"));
+            }
+            Ok(allocation)
+        },
+        None => Err((437, "Allocation Mismatch")),
+    }
+}
+
+fn refresh(state: &ServerState,
+           client: net::SocketAddr,
+           username: &str,
+           request: &StunMessage)
+           -> HandlerResult {
+    let mut allocations = unwrap_result!(state.tcp.allocations.lock());
+    let (created, exhausted) = {
+        let allocation = try!(allocation_of(&mut allocations, client, username));
+        (allocation.created, state.accounts.exhausted(&allocation.username))
+    };
+    let lifetime = turn::capped_lifetime(state, created, turn::requested_lifetime(state, request));
+    if exhausted {
+        // Expiring the allocation closes the control connection once the client has the answer.
+        if let Some(allocation) = allocations.get_mut(&client) {
+            allocation.expires = Instant::now();
+        }
+        return Err((486, "Allocation Quota Reached"));
+    }
+    if let Some(allocation) = allocations.get_mut(&client) {
+        // A zero lifetime expires the allocation, which closes the control connection.
+        allocation.expires = Instant::now() + lifetime;
+    }
+    Ok(vec![StunAttribute::Lifetime(lifetime.as_secs() as u32)])
+}
+
+fn create_permission(state: &ServerState,
+                     client: net::SocketAddr,
+                     username: &str,
+                     request: &StunMessage)
+                     -> HandlerResult {
+    let mut allocations = unwrap_result!(state.tcp.allocations.lock());
+    let allocation = try!(allocation_of(&mut allocations, client, username));
+    let peers: Vec<net::SocketAddr> = request.attributes
+                                             .iter()
+                                             .filter_map(|attr| match *attr {
+                                                 StunAttribute::XorPeerAddress(addr) => Some(addr),
+                                                 _ => None,
+                                             })
+                                             .collect();
+    if peers.is_empty() {
+        return Err((400, "Bad Request"));
+    }
+    let now = Instant::now();
+    let expires = now + Duration::from_secs(turn::PERMISSION_LIFETIME_SECS);
+    for peer in peers {
+        let _ = allocation.permissions.insert(peer.ip(), expires);
+    }
+    let expired: Vec<IpAddr> = allocation.permissions
+                                         .iter()
+                                         .filter(|&(_, expires)| *expires <= now)
+                                         .map(|(ip, _)| *ip)
+                                         .collect();
+    for ip in expired {
+        let _ = allocation.permissions.remove(&ip);
+    }
+    Ok(Vec::new())
+}
+
+fn connect(state: &ServerState,
+           client: net::SocketAddr,
+           username: &str,
+           request: &StunMessage)
+           -> HandlerResult {
+    let peer = request.find(|attr| match *attr {
+        StunAttribute::XorPeerAddress(addr) => Some(addr),
+        _ => None,
+    });
+    let peer = match peer {
+        Some(peer) => peer,
+        None => return Err((400, "Bad Request")),
+    };
+    {
+        let mut allocations = unwrap_result!(state.tcp.allocations.lock());
+        let allocation = try!(allocation_of(&mut allocations, client, username));
+        if !allocation.permitted(&peer.ip(), Instant::now()) {
+            return Err((403, "Forbidden"));
+        }
+    }
+    let stream = match TcpStream::connect(peer) {
+        Ok(stream) => stream,
+        Err(_) => return Err((447, "Connection Timeout or Failure")),
+    };
+    let connection_id = add_pending(state, username.to_owned(), stream);
+    Ok(vec![StunAttribute::ConnectionId(connection_id)])
+}
+
+fn connection_bind(state: &ServerState, username: &str, request: &StunMessage)
+                   -> Result<TcpStream, (u16, &'static str)> {
+    let connection_id = request.find(|attr| match *attr {
+        StunAttribute::ConnectionId(connection_id) => Some(connection_id),
+        _ => None,
+    });
+    let connection_id = match connection_id {
+        Some(connection_id) => connection_id,
+        None => return Err((400, "Bad Request")),
+    };
+    let mut pending = unwrap_result!(state.tcp.pending.lock());
+    let owned = match pending.get(&connection_id) {
+        Some(connection) => connection.username == username,
+        None => false,
+    };
+    if !owned {
+        return Err((400, "Bad Request"));
+    }
+    match pending.remove(&connection_id) {
+        Some(ref connection) if connection.expires <= Instant::now() => Err((400, "Bad Request")),
+        Some(connection) => Ok(connection.stream),
+        None => Err((400, "Bad Request")),
+    }
+}
+
+// Copy bytes both ways between a bound data connection and its peer until either side closes or
+// the user's quota is used up.
+fn splice(client: TcpStream,
+          peer: TcpStream,
+          leftover: Vec<u8>,
+          accounts: &Arc<Accounts>,
+          username: String) {
+    let (mut client_reader, mut client_writer) = match client.try_clone() {
+        Ok(client_writer) => (client, client_writer),
+        Err(_) => return,
+    };
+    let (mut peer_reader, mut peer_writer) = match peer.try_clone() {
+        Ok(peer_writer) => (peer, peer_writer),
+        Err(_) => return,
+    };
+    if client_reader.set_read_timeout(None).is_err() ||
+       !accounts.charge_user(&username, leftover.len() as u64) ||
+       peer_writer.write_all(&leftover).is_err() {
+        return;
+    }
+    let cloned_accounts = accounts.clone();
+    let cloned_username = username.clone();
+    let _ = thread!("TurnServerTcpSplice", move || {
+        copy_metered(&mut client_reader, &mut peer_writer, &cloned_accounts, &cloned_username);
+    });
+    copy_metered(&mut peer_reader, &mut client_writer, accounts, &username);
+}
+
+// Copy from `reader` to `writer` until `reader` closes, then pass the close on. The bytes are
+// counted against `username`'s quota, and both connections are closed once it's used up.
+fn copy_metered(reader: &mut TcpStream,
+                writer: &mut TcpStream,
+                accounts: &Accounts,
+                username: &str) {
+    let mut buf = [0u8; 8192];
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        if !accounts.charge_user(username, len as u64) {
+            let _ = reader.shutdown(Shutdown::Both);
+            let _ = writer.shutdown(Shutdown::Both);
+            return;
+        }
+        if writer.write_all(&buf[..len]).is_err() {
+            break;
+        }
+    }
+    let _ = writer.shutdown(Shutdown::Write);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use mapping_context::MappingContext;
+    use turn::{TurnCredentials, TurnServer, TurnServerConfig};
+
+    fn read_all(stream: &mut TcpStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        let _ = unwrap_result!(stream.read_to_end(&mut data));
+        data
+    }
+
+    #[test]
+    fn relays_tcp_connections_both_ways() {
+        let mut config = TurnServerConfig::new(unwrap_result!("127.0.0.1:0".parse()),
+                                               "example.org".to_owned());
+        config.add_user("alice".to_owned(), "hunter2".to_owned());
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        let server = unwrap_result!(TurnServer::new(&mc, config));
+        let credentials = TurnCredentials {
+            username: "alice".to_owned(),
+            password: "hunter2".to_owned(),
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut allocation = unwrap_result!(TurnTcpAllocation::allocate(&server.local_addr(),
+                                                                        &credentials,
+                                                                        deadline));
+        let relayed_addr = allocation.relayed_addr();
+
+        // Outgoing: the server connects to the peer on our behalf.
+        let peer_listener = unwrap_result!(TcpListener::bind("127.0.0.1:0"));
+        let peer_addr = unwrap_result!(peer_listener.local_addr());
+        unwrap_result!(allocation.create_permission(&peer_addr, deadline));
+        let mut stream = unwrap_result!(allocation.connect(&peer_addr, deadline));
+        let (mut peer_stream, _) = unwrap_result!(peer_listener.accept());
+        unwrap_result!(stream.write_all(b"hello"));
+        unwrap_result!(stream.shutdown(Shutdown::Write));
+        assert_eq!(read_all(&mut peer_stream), b"hello");
+        unwrap_result!(peer_stream.write_all(b"world"));
+        drop(peer_stream);
+        assert_eq!(read_all(&mut stream), b"world");
+
+        // Incoming: the peer connects to our relayed address.
+        let _ = thread!("turn_tcp_peer", move || {
+            let mut peer_stream = unwrap_result!(TcpStream::connect(relayed_addr));
+            unwrap_result!(peer_stream.write_all(b"incoming"));
+        });
+        let (mut stream, from) = unwrap_result!(allocation.accept(deadline));
+        assert_eq!(from.ip(), peer_addr.ip());
+        assert_eq!(read_all(&mut stream), b"incoming");
+    }
+
+    #[test]
+    fn closes_the_relayed_address_with_the_allocation() {
+        let mut config = TurnServerConfig::new(unwrap_result!("127.0.0.1:0".parse()),
+                                               "example.org".to_owned());
+        config.add_user("alice".to_owned(), "hunter2".to_owned());
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        let server = unwrap_result!(TurnServer::new(&mc, config));
+        let credentials = TurnCredentials {
+            username: "alice".to_owned(),
+            password: "hunter2".to_owned(),
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let allocation = unwrap_result!(TurnTcpAllocation::allocate(&server.local_addr(),
+                                                                    &credentials,
+                                                                    deadline));
+        let relayed_addr = allocation.relayed_addr();
+        assert!(TcpStream::connect(relayed_addr).is_ok());
+
+        // Closing the control connection deletes the allocation, and with it the listener.
+        drop(allocation);
+        while TcpStream::connect(relayed_addr).is_ok() {
+            assert!(Instant::now() < deadline, "The relayed address is still listening");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::fmt;
+use std::ptr;
 
 pub struct DisplaySlice<'a, T: 'a>(pub &'static str, pub &'a [T]);
 
@@ -16,3 +17,36 @@ impl<'a, T> fmt::Display for DisplaySlice<'a, T>
     }
 }
 
+/// Compare two byte strings in time that depends only on their lengths, not on where they first
+/// differ, so that secrets can't be guessed byte by byte from response times.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Overwrite secret material with zeroes before it's freed.
+#[allow(unsafe_code)]
+pub fn zeroize(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // Volatile so that the compiler can't drop the writes as dead stores.
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_compares_contents_and_lengths() {
+        assert!(constant_time_eq(b"abcd", b"abcd"));
+        assert!(!constant_time_eq(b"abcd", b"abce"));
+        assert!(!constant_time_eq(b"abcd", b"abc"));
+
+        let mut secret = [1u8, 2, 3, 4];
+        zeroize(&mut secret);
+        assert_eq!(secret, [0; 4]);
+    }
+}
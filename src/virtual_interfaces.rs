@@ -0,0 +1,143 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! # `nat_traversal`
+//! NAT traversal utilities.
+
+// Recognising interfaces that don't lead onto a physical network: VPN tunnels and the bridges
+// that containers and virtual machines hang off. Their addresses are only reachable from the same
+// host or the same VPN, so advertising them to remote peers just gives them more endpoints to
+// time out on. Interfaces are recognised by their names' well known prefixes, and on Linux
+// tun/tap devices are also recognised whatever they're called.
+
+use std::net::IpAddr;
+
+use mapped_socket_addr::MappedSocketAddr;
+
+/// What to do with endpoints on virtual interfaces, such as VPN tunnels and Docker bridges, when
+/// mapping sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub enum VirtualInterfacePolicy {
+    /// Don't report them at all.
+    Exclude,
+    /// Report them after every other endpoint, so peers try them last.
+    Deprioritize,
+    /// Treat them like any other endpoint.
+    Include,
+}
+
+impl Default for VirtualInterfacePolicy {
+    fn default() -> VirtualInterfacePolicy {
+        VirtualInterfacePolicy::Deprioritize
+    }
+}
+
+impl VirtualInterfacePolicy {
+    /// Apply the policy to `endpoints`, where `virtual_ips` are the addresses of the host's
+    /// virtual interfaces.
+    pub fn apply(&self, endpoints: Vec<MappedSocketAddr>, virtual_ips: &[IpAddr])
+                 -> Vec<MappedSocketAddr> {
+        if *self == VirtualInterfacePolicy::Include {
+            return endpoints;
+        }
+        let (on_virtual, mut others): (Vec<_>, Vec<_>) = endpoints.into_iter().partition(|e| {
+            virtual_ips.contains(&e.addr.ip())
+        });
+        if *self == VirtualInterfacePolicy::Deprioritize {
+            others.extend(on_virtual);
+        }
+        others
+    }
+}
+
+// Name prefixes of tunnels (OpenVPN, WireGuard, macOS's utun, Tailscale, ZeroTier) and bridges
+// (Docker, libvirt, LXC/LXD, podman, Kubernetes networking, VirtualBox and VMware host-only
+// networks). ppp interfaces are left out as they're usually the real uplink.
+const VIRTUAL_PREFIXES: &'static [&'static str] = &["tun", "tap", "wg", "utun", "ipsec",
+                                                     "tailscale", "zt", "docker", "br-", "veth",
+                                                     "virbr", "lxcbr", "lxdbr", "podman", "cni",
+                                                     "flannel", "cali", "vboxnet", "vmnet"];
+
+// Substrings of the friendly names Windows gives virtual adapters.
+const VIRTUAL_WINDOWS_NAMES: &'static [&'static str] = &["vethernet", "virtualbox", "vmware",
+                                                         "tap-windows", "wireguard", "hyper-v"];
+
+/// Whether the interface named `name` is a virtual one.
+pub fn is_virtual(name: &str) -> bool {
+    let lower = name.to_lowercase();
+    VIRTUAL_PREFIXES.iter().any(|prefix| lower.starts_with(prefix)) ||
+    VIRTUAL_WINDOWS_NAMES.iter().any(|part| lower.contains(part)) || is_tun_device(name)
+}
+
+// tun and tap devices have a tun_flags attribute in sysfs.
+#[cfg(target_os = "linux")]
+fn is_tun_device(name: &str) -> bool {
+    use std::path::Path;
+
+    !name.contains('/') && Path::new("/sys/class/net").join(name).join("tun_flags").exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_tun_device(_name: &str) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use mapped_socket_addr::MappedSocketAddr;
+    use socket_addr::SocketAddr;
+
+    fn endpoint(addr: &str) -> MappedSocketAddr {
+        MappedSocketAddr {
+            addr: SocketAddr(unwrap_result!(addr.parse())),
+            nat_restricted: false,
+            port_unknown: false,
+            unverified: false,
+        }
+    }
+
+    #[test]
+    fn virtual_endpoints_follow_the_policy() {
+        assert!(is_virtual("docker0"));
+        assert!(is_virtual("br-3f2a9c1d0e4b"));
+        assert!(is_virtual("utun2"));
+        assert!(is_virtual("vEthernet (WSL)"));
+        assert!(!is_virtual("eth0"));
+        assert!(!is_virtual("en0"));
+        assert!(!is_virtual("ppp0"));
+
+        let endpoints = vec![endpoint("172.17.0.1:5000"),
+                             endpoint("192.168.1.20:5000"),
+                             endpoint("203.0.113.7:5000")];
+        let virtual_ips = [unwrap_result!(IpAddr::from_str("172.17.0.1"))];
+        let addrs = |endpoints: Vec<MappedSocketAddr>| -> Vec<String> {
+            endpoints.iter().map(|e| format!("{}", e.addr.ip())).collect()
+        };
+        assert_eq!(addrs(VirtualInterfacePolicy::Exclude.apply(endpoints.clone(), &virtual_ips)),
+                   vec!["192.168.1.20", "203.0.113.7"]);
+        assert_eq!(addrs(VirtualInterfacePolicy::Deprioritize.apply(endpoints.clone(),
+                                                                    &virtual_ips)),
+                   vec!["192.168.1.20", "203.0.113.7", "172.17.0.1"]);
+        assert_eq!(addrs(VirtualInterfacePolicy::Include.apply(endpoints, &virtual_ips)),
+                   vec!["172.17.0.1", "192.168.1.20", "203.0.113.7"]);
+    }
+}